@@ -12,14 +12,21 @@
 
 use anyhow::{anyhow, Context, Result};
 use arc_swap::ArcSwap;
+use std::collections::BTreeSet;
 use std::fmt;
 use std::fmt::Debug;
+use std::fs::File;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
+use hypervisor::kvm::KVM_FDS;
 use migration::{migration::Migratable, MigrationManager};
-use util::byte_code::ByteCode;
+use util::aio::Iovec;
+use util::bitmap::Bitmap;
+use util::byte_code::{ByteCode, Endian, SwapBytes};
 use util::test_helper::is_test_enabled;
+use util::unix::host_page_size;
 
 use crate::{
     AddressRange, AddressSpaceError, FlatRange, GuestAddress, Listener, ListenerReqType, Region,
@@ -59,8 +66,151 @@ impl Default for RegionCache {
     }
 }
 
+/// Granularity of `TranslationCache` entries, which is 4KiB guest page.
+const TRANSLATION_PAGE_SHIFT: u64 = 12;
+/// Number of entries in `TranslationCache`.
+const TRANSLATION_CACHE_ENTRIES: usize = 32;
+
+#[derive(Clone, Copy)]
+struct TranslationEntry {
+    /// Guest page frame number, `u64::MAX` means the entry is empty.
+    page: u64,
+    /// Host address of the start of guest page.
+    host_addr: u64,
+    /// Bytes from the start of guest page to the end of the Ram flat range.
+    len: u64,
+}
+
+impl Default for TranslationEntry {
+    fn default() -> Self {
+        TranslationEntry {
+            page: u64::MAX,
+            host_addr: 0,
+            len: 0,
+        }
+    }
+}
+
+/// Direct-mapped cache of guest page to host address translations. It is owned by one
+/// user, such as a virtqueue, so lookups neither walk the flat view nor take any lock.
+///
+/// Invalidation rules:
+/// * Each topology update of `AddressSpace` bumps its generation, the cache drops all
+///   entries on the next lookup which sees a different generation.
+/// * Only Ram regions are cached, ranges in other regions always miss.
+/// * One entry covers its page up to the end of the flat range containing it, ranges
+///   crossing the end of the flat range always miss.
+/// * Users can call `invalidate` to drop the entries by themselves, e.g. when reset.
+#[derive(Clone, Copy, Default)]
+pub struct TranslationCache {
+    /// Generation of `AddressSpace` which the entries belong to.
+    generation: u64,
+    entries: [TranslationEntry; TRANSLATION_CACHE_ENTRIES],
+}
+
+impl TranslationCache {
+    /// Drop all cached translations.
+    pub fn invalidate(&mut self) {
+        self.entries = [TranslationEntry::default(); TRANSLATION_CACHE_ENTRIES];
+    }
+
+    /// Drop all cached translations if the topology of `mem` changed since the last
+    /// lookup, return `true` if so.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - Address space which the translations belong to.
+    pub fn sync_generation(&mut self, mem: &AddressSpace) -> bool {
+        let generation = mem.generation();
+        if generation == self.generation {
+            return false;
+        }
+        self.invalidate();
+        self.generation = generation;
+        true
+    }
+
+    /// Translate guest range `[addr, addr + len)` to host address, return `None` if the
+    /// range is not inside one Ram flat range.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - Address space which the range belongs to.
+    /// * `addr` - Guest address.
+    /// * `len` - Length of the range.
+    pub fn translate(&mut self, mem: &AddressSpace, addr: GuestAddress, len: u64) -> Option<u64> {
+        self.sync_generation(mem);
+        let page = addr.raw_value() >> TRANSLATION_PAGE_SHIFT;
+        let offset = addr.raw_value() - (page << TRANSLATION_PAGE_SHIFT);
+        let slot = (page % TRANSLATION_CACHE_ENTRIES as u64) as usize;
+        let mut entry = self.entries[slot];
+        if entry.page != page {
+            let cache = mem.get_region_cache(addr)?;
+            if cache.reg_type != RegionType::Ram {
+                return None;
+            }
+            let page_start = page << TRANSLATION_PAGE_SHIFT;
+            if page_start < cache.start {
+                // The page is shared with other flat range, translate without caching.
+                if len > cache.end - addr.raw_value() {
+                    return None;
+                }
+                return Some(cache.host_base + addr.raw_value() - cache.start);
+            }
+            entry = TranslationEntry {
+                page,
+                host_addr: cache.host_base + page_start - cache.start,
+                len: cache.end - page_start,
+            };
+            self.entries[slot] = entry;
+        }
+        if len > entry.len - offset {
+            return None;
+        }
+        Some(entry.host_addr + offset)
+    }
+}
+
 type ListenerObj = Arc<Mutex<dyn Listener>>;
 
+/// RAM mapped shared from a file, which can be mapped by another process, such as
+/// vhost-user backend.
+#[derive(Clone, Debug)]
+pub struct SharedMemRegion {
+    /// Guest address of the memory.
+    pub guest_addr: u64,
+    /// Size of the memory.
+    pub size: u64,
+    /// Host address of the memory.
+    pub host_addr: u64,
+    /// File backing the memory.
+    pub file: Arc<File>,
+    /// Offset of the memory in file.
+    pub offset: u64,
+}
+
+/// Dirty pages which are not synced by `sync_dirty_bitmap` yet.
+#[derive(Default)]
+struct DirtyLog {
+    /// Whether dirty page tracking is started.
+    enabled: AtomicBool,
+    /// Frame numbers (guest address / host page size) of pages written by VMM,
+    /// or collected from KVM but out of the synced range.
+    pages: Mutex<BTreeSet<u64>>,
+}
+
+impl DirtyLog {
+    fn mark(&self, addr: u64, count: u64) {
+        if count == 0 || !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let page_size = host_page_size();
+        let first = addr / page_size;
+        let last = addr.saturating_add(count - 1) / page_size;
+        self.pages.lock().unwrap().extend(first..=last);
+    }
+}
+
 /// Address Space of memory.
 #[derive(Clone)]
 pub struct AddressSpace {
@@ -73,6 +223,10 @@ pub struct AddressSpace {
     listeners: Arc<Mutex<Vec<ListenerObj>>>,
     /// The current layout of ioeventfds, which is compared with new ones in topology-update stage.
     ioeventfds: Arc<Mutex<Vec<RegionIoEventFd>>>,
+    /// Dirty pages logged since dirty page tracking started.
+    dirty_log: Arc<DirtyLog>,
+    /// Generation of `flat_view`, bumped every time the topology is updated.
+    generation: Arc<AtomicU64>,
 }
 
 impl fmt::Debug for AddressSpace {
@@ -97,6 +251,8 @@ impl AddressSpace {
             flat_view: Arc::new(ArcSwap::new(Arc::new(FlatView::default()))),
             listeners: Arc::new(Mutex::new(Vec::new())),
             ioeventfds: Arc::new(Mutex::new(Vec::new())),
+            dirty_log: Arc::new(DirtyLog::default()),
+            generation: Arc::new(AtomicU64::new(0)),
         });
 
         root.set_belonged_address_space(&space);
@@ -389,6 +545,113 @@ impl AddressSpace {
         }
     }
 
+    /// Translate guest range `[addr, addr + len)` to host iovecs, the range may straddle
+    /// multiple Ram regions, host-contiguous parts are merged into one iovec. Writes
+    /// through the iovecs are not logged as dirty pages, just like `write_object_direct`.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address.
+    /// * `len` - Length of the range.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if any part of the range isn't backed by Ram region, such as the
+    /// holes and IO regions.
+    pub fn get_host_iovec(&self, addr: GuestAddress, len: u64) -> Result<Vec<Iovec>> {
+        let view = self.flat_view.load();
+        let mut iovecs: Vec<Iovec> = Vec::new();
+        let mut start = addr;
+        let mut remain = len;
+        while remain > 0 {
+            let fr = view
+                .find_flatrange(start)
+                .with_context(|| anyhow!(AddressSpaceError::RegionNotFound(start.raw_value())))?;
+            let region_type = fr.owner.region_type();
+            if region_type != RegionType::Ram && region_type != RegionType::RamDevice {
+                return Err(anyhow!(AddressSpaceError::RegionType(region_type)));
+            }
+            let offset = start.offset_from(fr.addr_range.base);
+            let host_addr = fr.owner.get_host_address().unwrap() + fr.offset_in_region + offset;
+            let size = remain.min(fr.addr_range.size - offset);
+            match iovecs.last_mut() {
+                Some(last) if last.iov_base + last.iov_len == host_addr => last.iov_len += size,
+                _ => iovecs.push(Iovec::new(host_addr, size)),
+            }
+            start = start
+                .checked_add(size)
+                .ok_or_else(|| anyhow!(AddressSpaceError::Overflow(start.raw_value())))?;
+            remain -= size;
+        }
+        Ok(iovecs)
+    }
+
+    /// Release the host pages backing guest range `[addr, addr + len)`, so that the
+    /// memory is returned to host and reads back as zero (or the file content for
+    /// privately mapped files) afterwards. Shared mappings are discarded with
+    /// `MADV_REMOVE` to punch a hole in the backing file, others with `MADV_DONTNEED`.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address of the range, aligned to the page size of region.
+    /// * `len` - Length of the range, aligned to the page size of region.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the range is not inside one Ram region, is not page aligned,
+    /// or madvise fails.
+    pub fn discard_range(&self, addr: GuestAddress, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let view = self.flat_view.load();
+        let fr = view
+            .find_flatrange(addr)
+            .with_context(|| anyhow!(AddressSpaceError::RegionNotFound(addr.raw_value())))?;
+        let region_type = fr.owner.region_type();
+        if region_type != RegionType::Ram {
+            return Err(anyhow!(AddressSpaceError::RegionType(region_type)));
+        }
+        let offset = addr.offset_from(fr.addr_range.base);
+        if len > fr.addr_range.size - offset {
+            return Err(anyhow!(AddressSpaceError::DiscardCrossRegion(
+                addr.raw_value(),
+                len
+            )));
+        }
+        let page_size = fr
+            .owner
+            .get_region_page_size()
+            .unwrap_or_else(host_page_size);
+        let host_addr = fr.owner.get_host_address().unwrap() + fr.offset_in_region + offset;
+        if host_addr % page_size != 0 || len % page_size != 0 {
+            return Err(anyhow!(AddressSpaceError::DiscardUnaligned(
+                addr.raw_value(),
+                len,
+                page_size
+            )));
+        }
+
+        let advice = if fr.owner.is_share() {
+            libc::MADV_REMOVE
+        } else {
+            libc::MADV_DONTNEED
+        };
+        let ret = unsafe { libc::madvise(host_addr as *mut libc::c_void, len as usize, advice) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!(
+                    "Failed to discard range (0x{:X}, 0x{:X})",
+                    addr.raw_value(),
+                    len
+                )
+            });
+        }
+        // The content of discarded pages changed to zero, migration has to resend them.
+        self.dirty_log.mark(addr.raw_value(), len);
+        Ok(())
+    }
+
     /// Check if the GuestAddress is in one of Ram region.
     ///
     /// # Arguments
@@ -403,6 +666,27 @@ impl AddressSpace {
         })
     }
 
+    /// Check if range `[addr, addr + size)` overlaps with any Ram region.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address.
+    /// * `size` - Size of the range.
+    pub fn memory_overlaps(&self, addr: GuestAddress, size: u64) -> bool {
+        let end = addr.raw_value().saturating_add(size);
+        self.flat_view.load().0.iter().any(|fr| {
+            fr.owner.region_type() == RegionType::Ram
+                && fr.addr_range.base.raw_value() < end
+                && addr.raw_value() < fr.addr_range.end_addr().raw_value()
+        })
+    }
+
+    /// Get the generation of topology, which changes every time the topology is updated.
+    /// Users caching translations compare it to tell whether the cache is stale.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
     pub fn get_region_cache(&self, addr: GuestAddress) -> Option<RegionCache> {
         let view = &self.flat_view.load();
         if let Some(range) = view.find_flatrange(addr) {
@@ -432,6 +716,17 @@ impl AddressSpace {
             .map_or(GuestAddress(0), |fr| fr.addr_range.end_addr())
     }
 
+    /// Return ranges of all Ram regions visible in AddressSpace, in address order.
+    pub fn ram_ranges(&self) -> Vec<AddressRange> {
+        self.flat_view
+            .load()
+            .0
+            .iter()
+            .filter(|fr| fr.owner.region_type() == RegionType::Ram)
+            .map(|fr| fr.addr_range)
+            .collect()
+    }
+
     /// Read memory segment to `dst`.
     ///
     /// # Arguments
@@ -485,43 +780,42 @@ impl AddressSpace {
 
         let region_base = fr.addr_range.base.unchecked_sub(fr.offset_in_region);
         let offset_in_region = fr.offset_in_region + offset;
-        
+        if fr.owner.region_type() == RegionType::Ram {
+            self.dirty_log.mark(addr.raw_value(), count);
+        }
+
         if is_test_enabled() {
-            // println!("[[ here in address_space, wirte function, successfully test_enabled ]] ");
-            // println!("region_base: 0x{:X}, offset_in_region: 0x{:X}, count: 0x{:X}\n", 
-                // region_base.raw_value(), offset_in_region, count);
+            // Ioeventfds are emulated in userspace for test, which runs without KVM.
+            let mut buf = Vec::new();
+            src.read_to_end(&mut buf)
+                .with_context(|| "Failed to read data to write")?;
+            let mut data = [0_u8; 8];
+            let len = buf.len().min(data.len());
+            data[..len].copy_from_slice(&buf[..len]);
+            let data = u64::from_le_bytes(data);
+
             for evtfd in self.ioeventfds.lock().unwrap().iter() {
                 if addr != evtfd.addr_range.base || count != evtfd.addr_range.size {
                     continue;
                 }
-                if !evtfd.data_match {
-                    evtfd.fd.write(1).unwrap();
-                    println!("[[ address-write, return Ok(()) ]]");
+                if !evtfd.data_match || evtfd.data == data {
+                    evtfd
+                        .fd
+                        .write(1)
+                        .with_context(|| "Failed to write ioeventfd")?;
                     return Ok(());
                 }
-
-                let mut buf = Vec::new();
-                src.read_to_end(&mut buf).unwrap();
-
-                if buf.len() <= 8 {
-                    let data = u64::from_bytes(buf.as_slice()).unwrap();
-                    if *data == evtfd.data {
-                        evtfd.fd.write(1).unwrap();
-                        println!("[[ address-write, return Ok(())-2 ]]");
-                        return Ok(());
-                    }
-                }
-
-                return fr.owner
-                    .write(&mut buf.as_slice(), region_base, offset_in_region, count)
-                    .with_context(||
-                        format!(
-                            "Failed to write region, region base 0x{:X}, offset in region 0x{:X}, size 0x{:X}",
-                            region_base.raw_value(),
-                            offset_in_region,
-                            count
-                        ));
             }
+
+            return fr.owner
+                .write(&mut buf.as_slice(), region_base, offset_in_region, count)
+                .with_context(||
+                    format!(
+                        "Failed to write region, region base 0x{:X}, offset in region 0x{:X}, size 0x{:X}",
+                        region_base.raw_value(),
+                        offset_in_region,
+                        count
+                    ));
         }
 
         fr.owner
@@ -588,6 +882,37 @@ impl AddressSpace {
         Ok(obj)
     }
 
+    /// Write an object to memory in byte order `endian`, e.g. the little endian
+    /// structures of virtio.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The object that will be written to the memory.
+    /// * `addr` - The start guest address where the object will be written to.
+    /// * `endian` - Byte order of the object in memory.
+    pub fn write_object_endian<T: SwapBytes>(
+        &self,
+        data: &T,
+        addr: GuestAddress,
+        endian: Endian,
+    ) -> Result<()> {
+        self.write_object(&data.to_endian(endian), addr)
+    }
+
+    /// Read an object from memory in byte order `endian`, and convert it to host byte order.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The start guest address where the data will be read from.
+    /// * `endian` - Byte order of the object in memory.
+    pub fn read_object_endian<T: SwapBytes>(
+        &self,
+        addr: GuestAddress,
+        endian: Endian,
+    ) -> Result<T> {
+        Ok(self.read_object::<T>(addr)?.to_endian(endian))
+    }
+
     /// Read some data from memory to form an object via host address.
     ///
     /// # Arguments
@@ -608,6 +933,116 @@ impl AddressSpace {
         Ok(obj)
     }
 
+    /// Start logging pages written by guest and VMM. RAM regions added after this
+    /// are logged as well. Writes through host address, such as `write_object_direct`,
+    /// are not logged.
+    pub fn start_dirty_log(&self) -> Result<()> {
+        self.dirty_log.pages.lock().unwrap().clear();
+        self.dirty_log.enabled.store(true, Ordering::SeqCst);
+        KVM_FDS
+            .load()
+            .start_dirty_log()
+            .with_context(|| "Failed to start dirty log in KVM")
+    }
+
+    /// Stop logging dirty pages and drop the ones not synced.
+    pub fn stop_dirty_log(&self) -> Result<()> {
+        self.dirty_log.enabled.store(false, Ordering::SeqCst);
+        self.dirty_log.pages.lock().unwrap().clear();
+        KVM_FDS
+            .load()
+            .stop_dirty_log()
+            .with_context(|| "Failed to stop dirty log in KVM")
+    }
+
+    /// Get all RAM of this address space mapped shared from a file.
+    pub fn shared_mem_regions(&self) -> Vec<SharedMemRegion> {
+        self.flat_view
+            .load()
+            .0
+            .iter()
+            .filter(|fr| fr.owner.region_type() == RegionType::Ram && fr.owner.is_share())
+            .filter_map(|fr| {
+                let fb = fr.owner.get_file_backend()?;
+                Some(SharedMemRegion {
+                    guest_addr: fr.addr_range.base.raw_value(),
+                    size: fr.addr_range.size,
+                    host_addr: fr.owner.get_host_address()? + fr.offset_in_region,
+                    file: fb.file,
+                    offset: fb.offset + fr.offset_in_region,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the pages in `range` written since logging started or the last sync of
+    /// them, and clear their dirty state. Bit `n` of the returned bitmap stands for
+    /// the `n`th page counted from the page containing `range.base`.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - Guest address range to sync.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if dirty log is not started, or fail to get dirty log from KVM.
+    pub fn sync_dirty_bitmap(&self, range: AddressRange) -> Result<Bitmap<u64>> {
+        if !self.dirty_log.enabled.load(Ordering::SeqCst) {
+            return Err(anyhow!(AddressSpaceError::DirtyLogNotStarted));
+        }
+        let end = range
+            .base
+            .raw_value()
+            .checked_add(range.size)
+            .filter(|_| range.size != 0)
+            .with_context(|| anyhow!(AddressSpaceError::Overflow(range.base.raw_value())))?;
+        let page_size = host_page_size();
+        let first = range.base.raw_value() / page_size;
+        let last = (end - 1) / page_size;
+
+        let mut locked_pages = self.dirty_log.pages.lock().unwrap();
+        // KVM clears the whole slot, so all dirty pages of the slot are collected and
+        // the ones out of range are kept for later sync.
+        let kvm_fds = KVM_FDS.load();
+        let slots: Vec<_> = kvm_fds
+            .get_mem_slots()
+            .lock()
+            .unwrap()
+            .values()
+            .copied()
+            .collect();
+        for slot in slots {
+            let slot_base = slot.guest_phys_addr;
+            if slot_base >= end || slot_base + slot.memory_size <= range.base.raw_value() {
+                continue;
+            }
+            // Skip slots backing other address space.
+            if self.get_host_address(GuestAddress(slot_base)) != Some(slot.userspace_addr) {
+                continue;
+            }
+            let bitmap = kvm_fds.get_dirty_log(slot.slot, slot.memory_size)?;
+            for (idx, word) in bitmap.iter().enumerate() {
+                let mut word = *word;
+                while word != 0 {
+                    let bit = u64::from(word.trailing_zeros());
+                    word &= word - 1;
+                    locked_pages.insert(slot_base / page_size + idx as u64 * 64 + bit);
+                }
+            }
+        }
+
+        let mut synced = locked_pages.split_off(&first);
+        let mut rest = synced.split_off(&(last + 1));
+        locked_pages.append(&mut rest);
+        drop(locked_pages);
+
+        let mut dirty_bitmap = Bitmap::<u64>::new(((last - first) / 64 + 1) as usize);
+        for page in synced {
+            dirty_bitmap.set((page - first) as usize)?;
+        }
+        Ok(dirty_bitmap)
+    }
+
     /// Update the topology of memory.
     pub fn update_topology(&self) -> Result<()> {
         let old_fv = self.flat_view.load();
@@ -624,6 +1059,7 @@ impl AddressSpace {
             .with_context(|| "Failed to update topology (second pass)")?;
 
         self.flat_view.store(Arc::new(new_fv));
+        self.generation.fetch_add(1, Ordering::SeqCst);
         self.update_ioeventfds()
             .with_context(|| "Failed to generate and update ioeventfds")?;
         Ok(())
@@ -632,10 +1068,11 @@ impl AddressSpace {
 
 #[cfg(test)]
 mod test {
+    use serial_test::serial;
     use vmm_sys_util::eventfd::EventFd;
 
     use super::*;
-    use crate::{HostMemMapping, RegionOps};
+    use crate::{FileBackend, HostMemMapping, RegionOps};
 
     #[derive(Default, Clone)]
     struct TestListener {
@@ -1046,10 +1483,21 @@ mod test {
             space.memory_end_address(),
             ram2.start_address().unchecked_add(ram2.size())
         );
+        assert_eq!(
+            space.ram_ranges(),
+            vec![
+                AddressRange::new(GuestAddress(0), 1000),
+                AddressRange::new(GuestAddress(2000), 1000)
+            ]
+        );
         assert!(space.address_in_memory(GuestAddress(0), 0));
         assert_eq!(space.address_in_memory(GuestAddress(1000), 0), false);
         assert_eq!(space.address_in_memory(GuestAddress(1500), 0), false);
         assert!(space.address_in_memory(GuestAddress(2900), 0));
+        assert!(space.memory_overlaps(GuestAddress(900), 200));
+        assert!(space.memory_overlaps(GuestAddress(1500), 1000));
+        assert_eq!(space.memory_overlaps(GuestAddress(1000), 1000), false);
+        assert_eq!(space.memory_overlaps(GuestAddress(3000), u64::MAX), false);
 
         assert_eq!(
             space.get_host_address(GuestAddress(500)),
@@ -1081,6 +1529,13 @@ mod test {
         assert_eq!(space.address_in_memory(GuestAddress(1500), 0), false);
         assert_eq!(space.address_in_memory(GuestAddress(2400), 0), false);
         assert!(space.address_in_memory(GuestAddress(2900), 0));
+        assert_eq!(
+            space.ram_ranges(),
+            vec![
+                AddressRange::new(GuestAddress(0), 1000),
+                AddressRange::new(GuestAddress(2500), 500)
+            ]
+        );
 
         assert_eq!(
             space.get_host_address(GuestAddress(500)),
@@ -1110,4 +1565,362 @@ mod test {
         assert_eq!(data1, 10000);
         assert!(space.write_object(&data, GuestAddress(993)).is_err());
     }
+
+    #[derive(Clone, Copy, Default, Debug, PartialEq)]
+    #[repr(C, packed)]
+    struct PackedHeader {
+        kind: u16,
+        len: u32,
+    }
+
+    impl ByteCode for PackedHeader {}
+
+    impl SwapBytes for PackedHeader {
+        fn swap_bytes(self) -> Self {
+            PackedHeader {
+                kind: self.kind.swap_bytes(),
+                len: self.len.swap_bytes(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_and_read_object_endian() {
+        let root = Region::init_container_region(0x1000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x1000, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+
+        let header = PackedHeader {
+            kind: 0x0102,
+            len: 0x0304_0506,
+        };
+        space
+            .write_object_endian(&header, GuestAddress(0x10), Endian::Big)
+            .unwrap();
+        let mut bytes = [0_u8; 6];
+        space
+            .read(&mut bytes.as_mut(), GuestAddress(0x10), 6)
+            .unwrap();
+        assert_eq!(bytes, [0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let read: PackedHeader = space
+            .read_object_endian(GuestAddress(0x10), Endian::Big)
+            .unwrap();
+        assert_eq!(read, header);
+
+        space
+            .write_object_endian(&header, GuestAddress(0x20), Endian::Little)
+            .unwrap();
+        space
+            .read(&mut bytes.as_mut(), GuestAddress(0x20), 6)
+            .unwrap();
+        assert_eq!(bytes, [0x02, 0x01, 0x06, 0x05, 0x04, 0x03]);
+        let read: PackedHeader = space
+            .read_object_endian(GuestAddress(0x20), Endian::Little)
+            .unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn test_get_host_iovec() {
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x1000, None, false, false, false).unwrap(),
+        );
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x1000, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram1.clone()), 0)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram2.clone()), 0x1000)
+            .unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        root.add_subregion(Region::init_io_region(0x1000, default_ops), 0x3000)
+            .unwrap();
+
+        // Buffer straddling two Ram regions is split into two iovecs.
+        let iovecs = space.get_host_iovec(GuestAddress(0xff0), 0x20).unwrap();
+        assert_eq!(iovecs.len(), 2);
+        assert_eq!(
+            (iovecs[0].iov_base, iovecs[0].iov_len),
+            (ram1.host_address() + 0xff0, 0x10)
+        );
+        assert_eq!(
+            (iovecs[1].iov_base, iovecs[1].iov_len),
+            (ram2.host_address(), 0x10)
+        );
+        let data = [0x5a_u8; 0x20];
+        util::aio::iov_from_buf_direct(&iovecs, &data).unwrap();
+        let mut buf = [0_u8; 0x10];
+        space
+            .read(&mut buf.as_mut(), GuestAddress(0xff0), 0x10)
+            .unwrap();
+        assert_eq!(buf, [0x5a; 0x10]);
+        space
+            .read(&mut buf.as_mut(), GuestAddress(0x1000), 0x10)
+            .unwrap();
+        assert_eq!(buf, [0x5a; 0x10]);
+
+        let iovecs = space.get_host_iovec(GuestAddress(0x100), 0x100).unwrap();
+        assert_eq!(iovecs.len(), 1);
+        assert_eq!(iovecs[0].iov_base, ram1.host_address() + 0x100);
+        assert!(space
+            .get_host_iovec(GuestAddress(0x100), 0)
+            .unwrap()
+            .is_empty());
+
+        // Holes and IO regions are not backed by host memory.
+        assert!(space.get_host_iovec(GuestAddress(0x1ff0), 0x20).is_err());
+        assert!(space.get_host_iovec(GuestAddress(0x3000), 0x10).is_err());
+    }
+
+    #[test]
+    fn test_translation_cache() {
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x2000, None, false, false, false).unwrap(),
+        );
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x1000, None, false, false, false).unwrap(),
+        );
+        let region1 = Region::init_ram_region(ram1.clone());
+        root.add_subregion(region1.clone(), 0).unwrap();
+        root.add_subregion(Region::init_ram_region(ram2.clone()), 0x2000)
+            .unwrap();
+
+        let mut cache = TranslationCache::default();
+        assert_eq!(
+            cache.translate(&space, GuestAddress(0x10), 0x10),
+            Some(ram1.host_address() + 0x10)
+        );
+        // Entry of a page covers the rest of its flat range.
+        assert_eq!(
+            cache.translate(&space, GuestAddress(0x20), 0x1fe0),
+            Some(ram1.host_address() + 0x20)
+        );
+        assert_eq!(
+            cache.translate(&space, GuestAddress(0x2010), 0x10),
+            Some(ram2.host_address() + 0x10)
+        );
+        // Ranges crossing flat ranges and holes miss.
+        assert_eq!(cache.translate(&space, GuestAddress(0x1ff0), 0x20), None);
+        assert_eq!(cache.translate(&space, GuestAddress(0x3000), 0x10), None);
+
+        // Topology update invalidates cached translations.
+        let generation = space.generation();
+        root.delete_subregion(&region1).unwrap();
+        assert_ne!(space.generation(), generation);
+        assert_eq!(cache.translate(&space, GuestAddress(0x10), 0x10), None);
+        let ram3 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x1000, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram3.clone()), 0)
+            .unwrap();
+        assert_eq!(
+            cache.translate(&space, GuestAddress(0x10), 0x10),
+            Some(ram3.host_address() + 0x10)
+        );
+        assert!(!cache.sync_generation(&space));
+    }
+
+    /// Get Rss in KiB of the host mapping starting at `host_addr` from /proc/self/smaps.
+    fn mapping_rss(host_addr: u64) -> u64 {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let start = format!("{:x}-", host_addr);
+        smaps
+            .lines()
+            .skip_while(|line| !line.starts_with(&start))
+            .find_map(|line| line.strip_prefix("Rss:"))
+            .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_discard_range() {
+        let page_size = host_page_size();
+        let ram_size = 64 * page_size;
+        let root = Region::init_container_region(4 * ram_size);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, ram_size, None, false, false, false)
+                .unwrap(),
+        );
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, ram_size, None, false, false, false)
+                .unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram1.clone()), 0)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram2), ram_size)
+            .unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        root.add_subregion(Region::init_io_region(ram_size, default_ops), 3 * ram_size)
+            .unwrap();
+
+        let data = vec![0xa5_u8; ram_size as usize];
+        space
+            .write(&mut data.as_slice(), GuestAddress(0), ram_size)
+            .unwrap();
+        let rss_before = mapping_rss(ram1.host_address());
+        assert!(rss_before >= ram_size / 1024);
+
+        // Discarding the second half of ram1 returns the pages to host.
+        let half = ram_size / 2;
+        space.discard_range(GuestAddress(half), half).unwrap();
+        let rss_after = mapping_rss(ram1.host_address());
+        assert!(rss_before - rss_after >= half / 1024);
+        let mut buf = vec![0_u8; half as usize];
+        space
+            .read(&mut buf.as_mut_slice(), GuestAddress(half), half)
+            .unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        space
+            .read(&mut buf.as_mut_slice(), GuestAddress(0), half)
+            .unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+
+        // Crossing region boundaries, holes, IO regions and unaligned ranges are rejected.
+        assert!(space
+            .discard_range(GuestAddress(ram_size - page_size), 2 * page_size)
+            .is_err());
+        assert!(space
+            .discard_range(GuestAddress(2 * ram_size), page_size)
+            .is_err());
+        assert!(space
+            .discard_range(GuestAddress(3 * ram_size), page_size)
+            .is_err());
+        assert!(space.discard_range(GuestAddress(8), page_size).is_err());
+        assert!(space.discard_range(GuestAddress(0), page_size - 8).is_err());
+    }
+
+    #[test]
+    fn test_discard_range_shared_file() {
+        let page_size = host_page_size();
+        let ram_size = 16 * page_size;
+        let file = FileBackend::new_memfd("discard-test", ram_size, false).unwrap();
+        let root = Region::init_container_region(ram_size);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(0),
+                None,
+                ram_size,
+                Some(file),
+                false,
+                true,
+                false,
+            )
+            .unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+
+        let data: u64 = 0xdead_beef;
+        space.write_object(&data, GuestAddress(page_size)).unwrap();
+        space.discard_range(GuestAddress(0), ram_size).unwrap();
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(page_size)).unwrap(),
+            0
+        );
+    }
+
+    fn write_page(space: &AddressSpace, page: u64) {
+        let data: u64 = 0xdead_beef;
+        space
+            .write_object(&data, GuestAddress(page * host_page_size() + 8))
+            .unwrap();
+    }
+
+    fn dirty_pages(space: &AddressSpace, first: u64, count: u64) -> Vec<u64> {
+        let page_size = host_page_size();
+        let range = AddressRange::new(GuestAddress(first * page_size), count * page_size);
+        let bitmap = space.sync_dirty_bitmap(range).unwrap();
+        (0..count)
+            .filter(|n| bitmap.contain(*n as usize).unwrap())
+            .map(|n| first + n)
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_mem_regions() {
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x1000, None, false, true, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram1), 0)
+            .unwrap();
+        let f_back = FileBackend::new_memfd("test_mem", 0x2000, true).unwrap();
+        let mut f_back2 = f_back.clone();
+        f_back2.offset = 0x1000;
+        let addr = GuestAddress(0x1000);
+        let ram2 = Arc::new(
+            HostMemMapping::new(addr, None, 0x1000, Some(f_back), false, true, false).unwrap(),
+        );
+        let ram3 = Arc::new(
+            HostMemMapping::new(addr, None, 0x1000, Some(f_back2), false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram2.clone()), 0x1000)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram3), 0x2000)
+            .unwrap();
+
+        // Anonymous RAM and private file mapping aren't shared.
+        let regions = space.shared_mem_regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].guest_addr, 0x1000);
+        assert_eq!(regions[0].size, 0x1000);
+        assert_eq!(regions[0].host_addr, ram2.host_address());
+        assert_eq!(regions[0].offset, 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_dirty_log() {
+        let page_size = host_page_size();
+        let root = Region::init_container_region(8 * page_size);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let size = 4 * page_size;
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, size, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram1), 0)
+            .unwrap();
+        let range = AddressRange::new(GuestAddress(0), 8 * page_size);
+        assert!(space.sync_dirty_bitmap(range).is_err());
+
+        // Writes before logging started are not logged.
+        write_page(&space, 2);
+        space.start_dirty_log().unwrap();
+        assert!(dirty_pages(&space, 0, 8).is_empty());
+
+        // Region added after logging started.
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(size), None, size, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram2), size)
+            .unwrap();
+        write_page(&space, 5);
+        assert_eq!(dirty_pages(&space, 0, 8), vec![5]);
+        assert!(dirty_pages(&space, 0, 8).is_empty());
+
+        // Dirty pages out of the synced range are kept.
+        write_page(&space, 1);
+        write_page(&space, 6);
+        assert_eq!(dirty_pages(&space, 4, 4), vec![6]);
+        assert_eq!(dirty_pages(&space, 0, 8), vec![1]);
+
+        space.stop_dirty_log().unwrap();
+        write_page(&space, 3);
+        assert!(space.sync_dirty_bitmap(range).is_err());
+    }
 }
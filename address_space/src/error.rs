@@ -50,4 +50,10 @@ pub enum AddressSpaceError {
     KvmSlotOverlap { add: (u64, u64), exist: (u64, u64) },
     #[error("Invalid offset: offset 0x{0:X}, data length 0x{1:X}, region size 0x{2:X}")]
     InvalidOffset(u64, u64, u64),
+    #[error("Dirty log is not started")]
+    DirtyLogNotStarted,
+    #[error("Discard range (0x{0:X}, 0x{1:X}) crosses the boundary of Ram region")]
+    DiscardCrossRegion(u64, u64),
+    #[error("Discard range (0x{0:X}, 0x{1:X}) is not aligned to page size 0x{2:X}")]
+    DiscardUnaligned(u64, u64, u64),
 }
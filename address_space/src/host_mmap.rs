@@ -15,13 +15,15 @@ use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
 use anyhow::{bail, Context, Result};
-use log::{error, info};
+use log::{error, info, warn};
 use machine_manager::config::{HostMemPolicy, MachineMemConfig, MemZoneConfig};
+use machine_manager::temp_cleaner::TempCleaner;
 use util::{
     syscall::mbind,
-    unix::{do_mmap, host_page_size},
+    unix::{do_mmap, host_page_size, HUGETLBFS_MAGIC},
 };
 
 use crate::{AddressRange, GuestAddress};
@@ -31,6 +33,8 @@ const MAX_PREALLOC_THREAD: u8 = 16;
 const MPOL_MF_STRICT: u32 = 1;
 /// Move pages owned by this process to conform to mapping.
 const MPOL_MF_MOVE: u32 = 2;
+/// Populate page tables writable, which is supported since linux 5.14.
+const MADV_POPULATE_WRITE: libc::c_int = 23;
 
 /// FileBackend represents backend-file of `HostMemMapping`.
 #[derive(Clone, Debug)]
@@ -92,6 +96,9 @@ impl FileBackend {
                     fs_path,
                     std::io::Error::last_os_error()
                 );
+                // The name is filled by `mkstemp`, remove it when VM exits.
+                let path = unsafe { std::ffi::CString::from_raw(fs_cstr) };
+                TempCleaner::add_path(path.to_string_lossy().into_owned());
             }
             unsafe { File::from_raw_fd(raw_fd) }
         } else {
@@ -121,14 +128,30 @@ impl FileBackend {
         // Safe because struct `statfs` only contains plain-data-type field,
         // and set to all-zero will not cause any undefined behavior.
         let mut fstat: libc::statfs = unsafe { std::mem::zeroed() };
-        unsafe { libc::fstatfs(file.as_raw_fd(), &mut fstat) };
+        if unsafe { libc::fstatfs(file.as_raw_fd(), &mut fstat) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to get file system of {}", file_path));
+        }
         info!(
             "Using memory backing file, the page size is {}",
             fstat.f_bsize
         );
+        let page_size = fstat.f_bsize as u64;
+        let hugetlbfs = fstat.f_type as i64 == HUGETLBFS_MAGIC;
+        if hugetlbfs && file_len % page_size != 0 {
+            bail!(
+                "Memory size 0x{:X} is not aligned to hugepage size 0x{:X} of {}",
+                file_len,
+                page_size,
+                file_path
+            );
+        }
 
         let old_file_len = file.metadata().unwrap().len();
         if old_file_len == 0 {
+            if hugetlbfs {
+                check_hugepage_pool(page_size, file_len)?;
+            }
             file.set_len(file_len)
                 .with_context(|| format!("Failed to set length of file: {}", file_path))?;
         } else if old_file_len < file_len {
@@ -142,7 +165,78 @@ impl FileBackend {
         Ok(FileBackend {
             file: Arc::new(file),
             offset: 0_u64,
-            page_size: fstat.f_bsize as u64,
+            page_size,
+        })
+    }
+}
+
+/// Check whether there are enough free hugepages in host to back memory.
+///
+/// # Arguments
+///
+/// * `page_size` - Size of hugepage, such as 2M or 1G.
+/// * `size` - Size of memory.
+fn check_hugepage_pool(page_size: u64, size: u64) -> Result<()> {
+    let path = format!(
+        "/sys/kernel/mm/hugepages/hugepages-{}kB/free_hugepages",
+        page_size >> 10
+    );
+    let free_pages = match std::fs::read_to_string(&path) {
+        Ok(free) => free
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("Invalid number of free hugepages in {}", path))?,
+        Err(e) => {
+            warn!("Failed to read {}: {}, skip checking hugepages", path, e);
+            return Ok(());
+        }
+    };
+    let nr_pages = size / page_size;
+    if free_pages < nr_pages {
+        bail!(
+            "Hugepage pool is too small: {} pages of {}kB are needed, but only {} are free",
+            nr_pages,
+            page_size >> 10,
+            free_pages
+        );
+    }
+    Ok(())
+}
+
+impl FileBackend {
+    /// Construct a new FileBackend with memfd.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of memfd, which is shown in `/proc/<pid>/fd`.
+    /// * `file_len` - The size of file.
+    /// * `seal` - Seal the size of memfd, so it can't be changed by other process.
+    pub fn new_memfd(name: &str, file_len: u64, seal: bool) -> Result<FileBackend> {
+        let name = std::ffi::CString::new(name).with_context(|| "Invalid name of memfd")?;
+        let mut flags = libc::MFD_CLOEXEC;
+        if seal {
+            flags |= libc::MFD_ALLOW_SEALING;
+        }
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) } as RawFd;
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| "Failed to create memfd");
+        }
+
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(file_len)
+            .with_context(|| "Failed to set the length of memfd that backs memory")?;
+        if seal {
+            let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+            if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| "Failed to seal memfd");
+            }
+        }
+
+        Ok(FileBackend {
+            file: Arc::new(file),
+            offset: 0,
+            page_size: host_page_size(),
         })
     }
 }
@@ -166,7 +260,7 @@ fn max_nr_threads(nr_vcpus: u8) -> u8 {
 /// # Arguments
 ///
 /// * `start` - The start host address of memory segment.
-/// * `page_size` - Size of host page.
+/// * `page_size` - Size of page backing the memory.
 /// * `nr_pages` - Number of pages.
 fn touch_pages(start: u64, page_size: u64, nr_pages: u64) {
     let mut addr = start;
@@ -184,16 +278,50 @@ fn touch_pages(start: u64, page_size: u64, nr_pages: u64) {
     }
 }
 
-/// Pre-alloc memory for virtual machine.
+/// Pre-alloc memory for virtual machine, with `MADV_POPULATE_WRITE` if host supports,
+/// otherwise with threads touching pages.
 ///
 /// # Arguments
 ///
 /// * `host_addr` - The start host address to pre allocate.
 /// * `size` - Size of memory.
-/// * `nr_vcpus` - Number of vcpus.
-fn mem_prealloc(host_addr: u64, size: u64, nr_vcpus: u8) {
-    let page_size = host_page_size();
-    let threads = max_nr_threads(nr_vcpus);
+/// * `page_size` - Size of page backing the memory, touching one byte allocates a page.
+/// * `threads` - Number of threads touching pages.
+fn mem_prealloc(host_addr: u64, size: u64, page_size: u64, threads: u8) -> Result<()> {
+    let start = Instant::now();
+    let ret = unsafe {
+        libc::madvise(
+            host_addr as *mut libc::c_void,
+            size as usize,
+            MADV_POPULATE_WRITE,
+        )
+    };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            // Touching pages would get SIGBUS, e.g. hugepages are exhausted.
+            return Err(err).with_context(|| "Failed to prealloc memory");
+        }
+        touch_pages_parallel(host_addr, size, page_size, threads);
+    }
+    info!(
+        "Preallocated 0x{:X} bytes of memory in {:?}",
+        size,
+        start.elapsed()
+    );
+    Ok(())
+}
+
+/// Touch pages with threads in parallel.
+///
+/// # Arguments
+///
+/// * `host_addr` - The start host address to pre allocate.
+/// * `size` - Size of memory.
+/// * `page_size` - Size of page backing the memory.
+/// * `threads` - Number of threads touching pages.
+fn touch_pages_parallel(host_addr: u64, size: u64, page_size: u64, threads: u8) {
+    let threads = threads.max(1);
     let nr_pages = (size + page_size - 1) / page_size;
     let pages_per_thread = nr_pages / (threads as u64);
     let left = nr_pages % (threads as u64);
@@ -231,8 +359,16 @@ pub fn create_host_mmaps(
     nr_vcpus: u8,
 ) -> Result<Vec<Arc<HostMemMapping>>> {
     let mut f_back: Option<FileBackend> = None;
+    let mut mem_share = mem_config.mem_share;
 
-    if let Some(path) = &mem_config.mem_path {
+    if let Some(backend) = &mem_config.mem_backend {
+        let file_len = ranges.iter().fold(0, |acc, x| acc + x.1);
+        f_back = Some(
+            FileBackend::new_memfd(&backend.id, file_len, backend.seal)
+                .with_context(|| format!("Failed to create memory backend {}", backend.id))?,
+        );
+        mem_share = backend.share;
+    } else if let Some(path) = &mem_config.mem_path {
         let file_len = ranges.iter().fold(0, |acc, x| acc + x.1);
         f_back = Some(
             FileBackend::new_mem(path, file_len)
@@ -240,24 +376,8 @@ pub fn create_host_mmaps(
         );
     } else if mem_config.mem_share {
         let file_len = ranges.iter().fold(0, |acc, x| acc + x.1);
-        let anon_mem_name = String::from("stratovirt_anon_mem");
-
-        let anon_fd =
-            unsafe { libc::syscall(libc::SYS_memfd_create, anon_mem_name.as_ptr(), 0) } as RawFd;
-        if anon_fd < 0 {
-            return Err(std::io::Error::last_os_error()).with_context(|| "Failed to create memfd");
-        }
-
-        let anon_file = unsafe { File::from_raw_fd(anon_fd) };
-        anon_file
-            .set_len(file_len)
-            .with_context(|| "Failed to set the length of anonymous file that backs memory")?;
-
-        f_back = Some(FileBackend {
-            file: Arc::new(anon_file),
-            offset: 0,
-            page_size: host_page_size(),
-        });
+        let anon_mem_name = "stratovirt_anon_mem";
+        f_back = Some(FileBackend::new_memfd(anon_mem_name, file_len, false)?);
     }
 
     let backend = f_back.as_ref();
@@ -266,11 +386,17 @@ pub fn create_host_mmaps(
         mem_config.mem_size,
         backend.map_or(0, |fb| fb.offset),
         false,
-        mem_config.mem_share,
+        mem_share,
         mem_config.dump_guest_core,
-    )?;
+        mem_config.mem_reserve,
+    )
+    .with_context(|| format!("Failed to map 0x{:X} bytes of memory", mem_config.mem_size))?;
     if mem_config.mem_prealloc {
-        mem_prealloc(host_addr, mem_config.mem_size, nr_vcpus);
+        let page_size = backend.map_or(0, |fb| fb.page_size).max(host_page_size());
+        let threads = mem_config
+            .prealloc_threads
+            .unwrap_or_else(|| max_nr_threads(nr_vcpus));
+        mem_prealloc(host_addr, mem_config.mem_size, page_size, threads)?;
     }
     let mut mappings = Vec::new();
     for range in ranges.iter() {
@@ -280,7 +406,7 @@ pub fn create_host_mmaps(
             range.1,
             f_back.clone(),
             mem_config.dump_guest_core,
-            mem_config.mem_share,
+            mem_share,
             false,
         )?));
         host_addr += range.1;
@@ -355,6 +481,8 @@ pub struct HostMemMapping {
     host_addr: *mut u8,
     /// Represents file and offset-in-file that backs this mapping.
     file_back: Option<FileBackend>,
+    /// Whether the memory is mapped shared.
+    is_share: bool,
 }
 
 // Send and Sync is not auto-implemented for raw pointer type
@@ -395,6 +523,7 @@ impl HostMemMapping {
                 read_only,
                 is_share,
                 dump_guest_core,
+                true,
             )?
         };
 
@@ -405,9 +534,49 @@ impl HostMemMapping {
             },
             host_addr: host_addr as *mut u8,
             file_back,
+            is_share,
         })
     }
 
+    /// Construct a new HostMemMapping of image file, e.g. the firmware of flash.
+    /// Read-only image is mapped private, so that the file is never modified.
+    /// Writable image is mapped shared, so that writes are persisted to the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_addr` - Base GPA.
+    /// * `file` - Opened image file.
+    /// * `read_only` - The image is read only or not.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * size of the file is zero or not aligned with host page size.
+    /// * fail to mmap the file.
+    pub fn new_rom_file(guest_addr: GuestAddress, file: File, read_only: bool) -> Result<Self> {
+        let size = file
+            .metadata()
+            .with_context(|| "Failed to get metadata of image file")?
+            .len();
+        if size == 0 || size % host_page_size() != 0 {
+            bail!(
+                "Size 0x{:X} of image file is not a multiple of host page size 0x{:X}",
+                size,
+                host_page_size()
+            );
+        }
+        let file_back = FileBackend::new_common(file);
+        Self::new(
+            guest_addr,
+            None,
+            size,
+            Some(file_back),
+            false,
+            !read_only,
+            read_only,
+        )
+    }
+
     /// Get size of mapped memory.
     pub fn size(&self) -> u64 {
         self.address_range.size
@@ -429,6 +598,65 @@ impl HostMemMapping {
     pub fn file_backend(&self) -> Option<FileBackend> {
         self.file_back.clone()
     }
+
+    /// Whether the memory is mapped shared.
+    pub fn is_share(&self) -> bool {
+        self.is_share
+    }
+
+    /// Mark the memory mergeable by KSM (kernel samepage merging).
+    pub fn set_mergeable(&self) -> Result<()> {
+        let ret = unsafe {
+            libc::madvise(
+                self.host_addr as *mut libc::c_void,
+                self.size() as usize,
+                libc::MADV_MERGEABLE,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| "Failed to mark memory mergeable");
+        }
+        Ok(())
+    }
+
+    /// Pre-alloc the whole mapping with `threads` threads at most.
+    pub fn prealloc(&self, threads: u8) -> Result<()> {
+        let page_size = self
+            .file_back
+            .as_ref()
+            .map_or(0, |fb| fb.page_size)
+            .max(host_page_size());
+        mem_prealloc(
+            self.host_address(),
+            self.size(),
+            page_size,
+            threads.min(MAX_PREALLOC_THREAD),
+        )
+    }
+
+    /// Bind the memory to host numa nodes `nodes`, which are sorted in ascending order.
+    pub fn bind_host_nodes(&self, nodes: &[u32]) -> Result<()> {
+        let mut max_node = match nodes.last() {
+            Some(node) => *node as usize,
+            None => return Ok(()),
+        };
+        let mut nmask = vec![0_u64; max_node / 64 + 1];
+        for node in nodes.iter() {
+            nmask[(*node / 64) as usize] |= 1_u64 << (*node % 64);
+        }
+        // The same as `set_host_memory_policy`, the last node is cut off by mbind() otherwise.
+        max_node += 1;
+        mbind(
+            self.host_address(),
+            self.size(),
+            HostMemPolicy::Bind as u32,
+            nmask,
+            max_node as u64,
+            MPOL_MF_STRICT | MPOL_MF_MOVE,
+        )
+        .with_context(|| "Failed to call mbind")
+    }
 }
 
 impl Drop for HostMemMapping {
@@ -446,6 +674,7 @@ impl Drop for HostMemMapping {
 #[cfg(test)]
 mod test {
     use super::*;
+    use machine_manager::config::MemFdBackendConfig;
     use std::io::{Read, Seek, SeekFrom, Write};
     use vmm_sys_util::tempfile::TempFile;
 
@@ -560,6 +789,10 @@ mod test {
             mem_share: false,
             mem_prealloc: false,
             mem_zones: None,
+            mem_backend: None,
+            prealloc_threads: None,
+            mem_reserve: true,
+            hotplug: None,
         };
 
         let host_mmaps = create_host_mmaps(&addr_ranges, &mem_config, 1).unwrap();
@@ -586,15 +819,75 @@ mod test {
         assert_eq!(total_mem_size, total_mmaps_size);
     }
 
+    #[test]
+    fn test_rom_file_mapping() {
+        let page_size = host_page_size();
+        let tmp = TempFile::new().unwrap();
+        let mut file = tmp.as_file().try_clone().unwrap();
+        // Empty or unaligned image is rejected.
+        assert!(
+            HostMemMapping::new_rom_file(GuestAddress(0), file.try_clone().unwrap(), true).is_err()
+        );
+        file.write_all(&[0x5a; 16]).unwrap();
+        assert!(
+            HostMemMapping::new_rom_file(GuestAddress(0), file.try_clone().unwrap(), true).is_err()
+        );
+        file.set_len(page_size).unwrap();
+
+        let rom =
+            HostMemMapping::new_rom_file(GuestAddress(0x1000), file.try_clone().unwrap(), true)
+                .unwrap();
+        assert_eq!(rom.size(), page_size);
+        assert!(!rom.is_share());
+        let slice = unsafe { std::slice::from_raw_parts(rom.host_address() as *const u8, 16) };
+        assert_eq!(slice, [0x5a; 16]);
+
+        // Writes of writable image reach the file.
+        let flash =
+            HostMemMapping::new_rom_file(GuestAddress(0x1000), file.try_clone().unwrap(), false)
+                .unwrap();
+        assert!(flash.is_share());
+        unsafe { *(flash.host_address() as *mut u8) = 0xa5 };
+        let mut buf = [0_u8; 2];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xa5, 0x5a]);
+    }
+
+    #[test]
+    fn test_create_host_mmaps_memfd() {
+        let addr_ranges = [(0x0, 0x10_0000), (0x100000, 0x10_0000)];
+        let mem_config = MachineMemConfig {
+            mem_size: 0x20_0000,
+            mem_backend: Some(MemFdBackendConfig {
+                id: "mem0".to_string(),
+                size: 0x20_0000,
+                share: true,
+                seal: true,
+            }),
+            ..Default::default()
+        };
+
+        let host_mmaps = create_host_mmaps(&addr_ranges, &mem_config, 1).unwrap();
+        assert!(host_mmaps[0].is_share());
+        let f_back = host_mmaps[1].file_backend().unwrap();
+        assert_eq!(f_back.offset, 0x10_0000);
+        // Size of memfd is sealed.
+        assert_eq!(f_back.file.metadata().unwrap().len(), 0x20_0000);
+        assert!(f_back.file.set_len(0x40_0000).is_err());
+    }
+
     #[test]
     fn test_memory_prealloc() {
         // Mmap and prealloc with anonymous memory.
-        let host_addr = do_mmap(&None, 0x20_0000, 0, false, false, false).unwrap();
+        let host_addr = do_mmap(&None, 0x20_0000, 0, false, false, false, true).unwrap();
         // Check the thread number equals to minimum value.
         assert_eq!(max_nr_threads(1), 1);
         // The max threads limit is 16, or the number of host CPUs, it will never be 20.
         assert_ne!(max_nr_threads(20), 20);
-        mem_prealloc(host_addr, 0x20_0000, 20);
+        mem_prealloc(host_addr, 0x20_0000, host_page_size(), max_nr_threads(20)).unwrap();
+        // Touch pages with threads, in case `MADV_POPULATE_WRITE` is supported.
+        touch_pages_parallel(host_addr, 0x20_0000, host_page_size(), 3);
 
         // Mmap and prealloc with file backend.
         let file_path = String::from("back_mem_test");
@@ -607,8 +900,18 @@ mod test {
             false,
             true,
             false,
+            true,
         )
         .unwrap();
-        mem_prealloc(host_addr, 0x10_0000, 2);
+        mem_prealloc(host_addr, 0x10_0000, f_back.page_size, 2).unwrap();
+    }
+
+    #[test]
+    fn test_check_hugepage_pool() {
+        // Hugepage pool of host is never large enough, or there is no such pool.
+        let page_size = 0x20_0000;
+        let ret = check_hugepage_pool(page_size, u64::MAX / page_size * page_size);
+        let path = "/sys/kernel/mm/hugepages/hugepages-2048kB/free_hugepages";
+        assert_eq!(ret.is_err(), std::path::Path::new(path).exists());
     }
 }
@@ -14,7 +14,7 @@ use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
 use hypervisor::kvm::KVM_FDS;
-use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_READONLY};
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY};
 use kvm_ioctls::{IoEventAddress, NoDatamatch};
 use log::{debug, warn};
 use util::{num_ops::round_down, unix::host_page_size};
@@ -253,6 +253,9 @@ impl KvmMemoryListener {
         let mut flags = 0_u32;
         if flat_range.owner.get_rom_device_romd().unwrap_or(false) {
             flags |= KVM_MEM_READONLY;
+        } else if KVM_FDS.load().dirty_log_enabled() {
+            // Region added after dirty page tracking started.
+            flags |= KVM_MEM_LOG_DIRTY_PAGES;
         }
         let kvm_region = kvm_userspace_memory_region {
             slot: slot_idx | (self.as_id.load(Ordering::SeqCst) << 16),
@@ -427,6 +427,11 @@ impl Region {
         self.mem_mapping.as_ref().and_then(|r| r.file_backend())
     }
 
+    /// Whether this region is backed by host-memory mapped shared.
+    pub fn is_share(&self) -> bool {
+        self.mem_mapping.as_ref().map_or(false, |r| r.is_share())
+    }
+
     /// Get the region file backend page size.
     pub fn get_region_page_size(&self) -> Option<u64> {
         self.mem_mapping
@@ -38,8 +38,20 @@ pub enum BootLoaderError {
     DTBOverflow(u64),
     #[error("Failed to load kernel image {0} to memory {1}.")]
     KernelOverflow(u64, u64),
-    #[error("Failed to load initrd image {0} to memory {1}.")]
+    #[error("Initrd image of {0} bytes doesn't fit in {1} bytes of guest memory left by kernel and dtb.")]
     InitrdOverflow(u64, u64),
+    #[error("Failed to place dtb at 0x{0:x}, guest memory ends at 0x{1:x}.")]
+    DtbOverflow(u64, u64),
+    #[error("Invalid ELF kernel image: {0}.")]
+    ElfInvalid(String),
+    #[error("ELF kernel image is built for machine {0}, expected RISC-V (243).")]
+    ElfMachine(u16),
+    #[error("Segments of ELF kernel image overlap at guest address 0x{0:x}.")]
+    ElfOverlap(u64),
+    #[error("Firmware ending at 0x{0:x} overlaps fw_dynamic info below kernel at 0x{1:x}.")]
+    BiosOverflow(u64, u64),
+    #[error("Failed to open firmware image")]
+    BootLoaderOpenBios,
     #[error("Failed to open kernel image")]
     BootLoaderOpenKernel,
     #[error("Failed to open initrd image")]
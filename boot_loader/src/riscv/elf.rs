@@ -0,0 +1,350 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::BootLoaderError;
+use anyhow::{anyhow, Result};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const ELF64_EHDR_SIZE: usize = 64;
+const ELF64_PHDR_SIZE: usize = 56;
+/// Upper bound of program headers, vmlinux only has a few of them.
+const MAX_PHDRS: u16 = 64;
+
+/// Loadable segment of ELF kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfSegment {
+    /// Offset of the segment content in file.
+    pub offset: u64,
+    /// Guest physical address the segment is loaded to.
+    pub addr: u64,
+    /// Virtual address the segment is linked at.
+    pub vaddr: u64,
+    /// Size of the content in file, the rest of segment is zeroed.
+    pub file_size: u64,
+    /// Size of the segment in guest memory.
+    pub mem_size: u64,
+}
+
+/// Kernel image in ELF format, e.g. vmlinux.
+#[derive(Debug)]
+pub struct ElfKernel {
+    /// Guest physical address of entry point.
+    pub entry: u64,
+    /// PT_LOAD segments sorted by guest address, which don't overlap.
+    pub segments: Vec<ElfSegment>,
+}
+
+impl ElfKernel {
+    /// Start of guest memory occupied by kernel.
+    pub fn start(&self) -> u64 {
+        self.segments[0].addr
+    }
+
+    /// End of guest memory occupied by kernel.
+    pub fn end(&self) -> u64 {
+        let last = self.segments.last().unwrap();
+        last.addr + last.mem_size
+    }
+}
+
+fn invalid(reason: &str) -> anyhow::Error {
+    anyhow!(BootLoaderError::ElfInvalid(reason.to_string()))
+}
+
+fn le_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0_u8; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Whether the image starts with ELF magic. Image is rewound to start.
+pub fn is_elf<R: Read + Seek>(image: &mut R) -> Result<bool> {
+    let mut magic = [0_u8; 4];
+    image.seek(SeekFrom::Start(0))?;
+    // Image shorter than magic isn't ELF.
+    let ret = image.read_exact(&mut magic).is_ok() && magic == ELF_MAGIC;
+    image.seek(SeekFrom::Start(0))?;
+    Ok(ret)
+}
+
+/// Parse the headers of riscv64 ELF kernel.
+///
+/// Physical addresses of segments below `mem_start` are taken as offsets into guest
+/// memory, which is how vmlinux is linked, others are taken as they are.
+///
+/// # Errors
+///
+/// Image is not a little-endian ELF64 for RISC-V, has no loadable segment, has
+/// overlapping segments or its entry is out of the segments.
+pub fn parse_elf<R: Read + Seek>(image: &mut R, mem_start: u64) -> Result<ElfKernel> {
+    let file_size = image.seek(SeekFrom::End(0))?;
+    let mut ehdr = [0_u8; ELF64_EHDR_SIZE];
+    image.seek(SeekFrom::Start(0))?;
+    image
+        .read_exact(&mut ehdr)
+        .map_err(|_| invalid("truncated ELF header"))?;
+    if ehdr[0..4] != ELF_MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    if ehdr[4] != ELFCLASS64 || ehdr[5] != ELFDATA2LSB {
+        return Err(invalid("not a little-endian ELF64"));
+    }
+    let machine = le_u16(&ehdr[18..20]);
+    if machine != EM_RISCV {
+        return Err(anyhow!(BootLoaderError::ElfMachine(machine)));
+    }
+    let entry = le_u64(&ehdr[24..32]);
+    let phoff = le_u64(&ehdr[32..40]);
+    let phentsize = le_u16(&ehdr[54..56]);
+    let phnum = le_u16(&ehdr[56..58]);
+    if phentsize as usize != ELF64_PHDR_SIZE || phnum > MAX_PHDRS {
+        return Err(invalid("bad program header table"));
+    }
+
+    let mut segments = Vec::new();
+    let mut phdr = [0_u8; ELF64_PHDR_SIZE];
+    for index in 0..phnum as u64 {
+        let offset = phoff
+            .checked_add(index * ELF64_PHDR_SIZE as u64)
+            .ok_or_else(|| invalid("bad program header table"))?;
+        image.seek(SeekFrom::Start(offset))?;
+        image
+            .read_exact(&mut phdr)
+            .map_err(|_| invalid("truncated program header"))?;
+        if le_u32(&phdr[0..4]) != PT_LOAD {
+            continue;
+        }
+        let seg = ElfSegment {
+            offset: le_u64(&phdr[8..16]),
+            vaddr: le_u64(&phdr[16..24]),
+            addr: le_u64(&phdr[24..32]),
+            file_size: le_u64(&phdr[32..40]),
+            mem_size: le_u64(&phdr[40..48]),
+        };
+        if seg.mem_size == 0 {
+            continue;
+        }
+        if seg.file_size > seg.mem_size {
+            return Err(invalid("segment file size exceeds memory size"));
+        }
+        if seg
+            .offset
+            .checked_add(seg.file_size)
+            .filter(|end| *end <= file_size)
+            .is_none()
+        {
+            return Err(invalid("segment out of file"));
+        }
+        let addr = if seg.addr < mem_start {
+            mem_start.checked_add(seg.addr)
+        } else {
+            Some(seg.addr)
+        };
+        match addr.filter(|addr| addr.checked_add(seg.mem_size).is_some()) {
+            Some(addr) => segments.push(ElfSegment { addr, ..seg }),
+            None => return Err(invalid("segment address overflow")),
+        }
+    }
+    if segments.is_empty() {
+        return Err(invalid("no loadable segment"));
+    }
+
+    segments.sort_by_key(|seg| seg.addr);
+    for pair in segments.windows(2) {
+        if pair[0].addr + pair[0].mem_size > pair[1].addr {
+            return Err(anyhow!(BootLoaderError::ElfOverlap(pair[1].addr)));
+        }
+    }
+    let entry = segments
+        .iter()
+        .find(|seg| entry >= seg.vaddr && entry - seg.vaddr < seg.mem_size)
+        .map(|seg| seg.addr + (entry - seg.vaddr))
+        .ok_or_else(|| invalid("entry out of loadable segments"))?;
+
+    Ok(ElfKernel { entry, segments })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const MEM_START: u64 = 0x8000_0000;
+    const VADDR_BASE: u64 = 0xffff_ffff_8000_0000;
+
+    /// Segment of ELF fixture: virtual address, physical address, content and size
+    /// in memory.
+    pub struct FixtureSegment<'a> {
+        pub vaddr: u64,
+        pub paddr: u64,
+        pub data: &'a [u8],
+        pub mem_size: u64,
+    }
+
+    /// Build a minimal ELF64 image with a PT_LOAD program header for each segment.
+    pub fn elf_fixture(machine: u16, entry: u64, segments: &[FixtureSegment]) -> Vec<u8> {
+        let phoff = ELF64_EHDR_SIZE;
+        let mut data_off = phoff + ELF64_PHDR_SIZE * segments.len();
+
+        let mut image = vec![0_u8; ELF64_EHDR_SIZE];
+        image[0..4].copy_from_slice(&ELF_MAGIC);
+        image[4] = ELFCLASS64;
+        image[5] = ELFDATA2LSB;
+        image[6] = 1;
+        image[16..18].copy_from_slice(&2_u16.to_le_bytes());
+        image[18..20].copy_from_slice(&machine.to_le_bytes());
+        image[20..24].copy_from_slice(&1_u32.to_le_bytes());
+        image[24..32].copy_from_slice(&entry.to_le_bytes());
+        image[32..40].copy_from_slice(&(phoff as u64).to_le_bytes());
+        image[52..54].copy_from_slice(&(ELF64_EHDR_SIZE as u16).to_le_bytes());
+        image[54..56].copy_from_slice(&(ELF64_PHDR_SIZE as u16).to_le_bytes());
+        image[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        for seg in segments {
+            let mut phdr = vec![0_u8; ELF64_PHDR_SIZE];
+            phdr[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            phdr[4..8].copy_from_slice(&7_u32.to_le_bytes());
+            phdr[8..16].copy_from_slice(&(data_off as u64).to_le_bytes());
+            phdr[16..24].copy_from_slice(&seg.vaddr.to_le_bytes());
+            phdr[24..32].copy_from_slice(&seg.paddr.to_le_bytes());
+            phdr[32..40].copy_from_slice(&(seg.data.len() as u64).to_le_bytes());
+            phdr[40..48].copy_from_slice(&seg.mem_size.to_le_bytes());
+            image.extend_from_slice(&phdr);
+            data_off += seg.data.len();
+        }
+        for seg in segments {
+            image.extend_from_slice(seg.data);
+        }
+        image
+    }
+
+    fn segment(vaddr: u64, paddr: u64, data: &[u8], mem_size: u64) -> FixtureSegment {
+        FixtureSegment {
+            vaddr,
+            paddr,
+            data,
+            mem_size,
+        }
+    }
+
+    #[test]
+    fn test_parse_elf() {
+        assert!(!is_elf(&mut Cursor::new(b"MZ\0\0Image".to_vec())).unwrap());
+        assert!(!is_elf(&mut Cursor::new(vec![0x7f])).unwrap());
+
+        // Linked like vmlinux, physical addresses are offsets into guest memory.
+        let image = elf_fixture(
+            EM_RISCV,
+            VADDR_BASE + 0x20_0008,
+            &[
+                segment(VADDR_BASE + 0x20_0000, 0x20_0000, &[0xaa; 16], 0x1000),
+                segment(VADDR_BASE, 0, &[0x13, 0, 0, 0], 4),
+            ],
+        );
+        let mut cursor = Cursor::new(image);
+        assert!(is_elf(&mut cursor).unwrap());
+        let elf = parse_elf(&mut cursor, MEM_START).unwrap();
+        assert_eq!(elf.segments.len(), 2);
+        assert_eq!(elf.segments[0].addr, MEM_START);
+        assert_eq!(elf.segments[1].addr, MEM_START + 0x20_0000);
+        assert_eq!(elf.segments[1].file_size, 16);
+        assert_eq!(elf.start(), MEM_START);
+        assert_eq!(elf.end(), MEM_START + 0x20_1000);
+        assert_eq!(elf.entry, MEM_START + 0x20_0008);
+
+        // Physical addresses in guest memory are taken as they are.
+        let image = elf_fixture(
+            EM_RISCV,
+            MEM_START + 0x20_0000,
+            &[segment(
+                MEM_START + 0x20_0000,
+                MEM_START + 0x20_0000,
+                &[0; 8],
+                8,
+            )],
+        );
+        let elf = parse_elf(&mut Cursor::new(image), MEM_START).unwrap();
+        assert_eq!(elf.entry, MEM_START + 0x20_0000);
+        assert_eq!(elf.start(), MEM_START + 0x20_0000);
+    }
+
+    #[test]
+    fn test_parse_elf_invalid() {
+        let parse = |machine: u16, entry: u64, segments: &[FixtureSegment]| {
+            parse_elf(
+                &mut Cursor::new(elf_fixture(machine, entry, segments)),
+                MEM_START,
+            )
+        };
+
+        // Built for aarch64.
+        let err = parse(183, VADDR_BASE, &[segment(VADDR_BASE, 0, &[0; 4], 4)]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BootLoaderError>(),
+            Some(BootLoaderError::ElfMachine(183))
+        ));
+
+        // Bss of the first segment runs into the second one.
+        let err = parse(
+            EM_RISCV,
+            VADDR_BASE,
+            &[
+                segment(VADDR_BASE, 0, &[0; 4], 0x2000),
+                segment(VADDR_BASE + 0x1000, 0x1000, &[0; 4], 4),
+            ],
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BootLoaderError>(),
+            Some(BootLoaderError::ElfOverlap(addr)) if *addr == MEM_START + 0x1000
+        ));
+
+        // Entry out of segments.
+        assert!(parse(
+            EM_RISCV,
+            VADDR_BASE + 4,
+            &[segment(VADDR_BASE, 0, &[0; 4], 4)]
+        )
+        .is_err());
+        // No loadable segment.
+        assert!(parse(EM_RISCV, VADDR_BASE, &[]).is_err());
+        // Content larger than segment.
+        assert!(parse(EM_RISCV, VADDR_BASE, &[segment(VADDR_BASE, 0, &[0; 8], 4)]).is_err());
+
+        // Truncated image.
+        let mut image = elf_fixture(
+            EM_RISCV,
+            VADDR_BASE,
+            &[segment(VADDR_BASE, 0, &[0; 16], 16)],
+        );
+        image.truncate(image.len() - 8);
+        assert!(parse_elf(&mut Cursor::new(image), MEM_START).is_err());
+    }
+}
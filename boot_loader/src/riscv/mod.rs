@@ -16,9 +16,11 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod elf;
+
 use std::fs::File;
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use crate::error::BootLoaderError;
@@ -27,9 +29,22 @@ use anyhow::{anyhow, Context, Result};
 use devices::legacy::{error::LegacyError as FwcfgErrorKind, FwCfgEntryType, FwCfgOps};
 use log::info;
 use util::byte_code::ByteCode;
+use util::device_tree::FDT_MAX_SIZE;
+use util::num_ops::{round_down, round_up};
+
+use elf::{is_elf, parse_elf, ElfKernel};
 
 const RISCV64_KERNEL_OFFSET: u64 = 0x20_0000;
 const SZ_4M: u64 = 0x00400000;
+/// Alignment of initrd, and of dtb when it follows initrd.
+const INITRD_ALIGN: u64 = 0x1000;
+/// Size of the page right below kernel which holds fw_dynamic info.
+const FW_DYNAMIC_INFO_AREA: u64 = 0x1000;
+/// "OSBI" in little endian.
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942_534f;
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+/// Privilege mode of the stage after firmware, S-mode.
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
 
 /// Boot loader config used for riscv.
 #[derive(Default, Debug)]
@@ -40,6 +55,9 @@ pub struct RISCVBootLoaderConfig {
     pub initrd: Option<PathBuf>,
     /// Start address of guest memory.
     pub mem_start: u64,
+    /// Path of OpenSBI fw_dynamic firmware, which is loaded at the start of guest memory
+    /// and booted instead of kernel.
+    pub bios: Option<PathBuf>,
 }
 
 /// The start address for `kernel image`, `initrd image` and `dtb` in guest memory.
@@ -52,19 +70,132 @@ pub struct RISCVBootLoader {
     pub initrd_size: u64,
     /// Start address for `dtb` in guest memory.
     pub dtb_start: u64,
+    /// Start address for fw_dynamic info in guest memory, passed to firmware in `a2`.
+    pub fw_dynamic_start: Option<u64>,
+}
+
+/// Boot information passed from the previous booting stage to OpenSBI fw_dynamic
+/// firmware, see `struct fw_dynamic_info` of OpenSBI.
+#[repr(C)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+struct FwDynamicInfo {
+    magic: u64,
+    version: u64,
+    /// Address the firmware jumps to, which is kernel entry.
+    next_addr: u64,
+    /// Privilege mode of the next stage.
+    next_mode: u64,
+    options: u64,
+    /// Hart which does the cold boot.
+    boot_hart: u64,
+}
+
+impl ByteCode for FwDynamicInfo {}
+
+impl FwDynamicInfo {
+    fn new(next_addr: u64) -> Self {
+        FwDynamicInfo {
+            magic: FW_DYNAMIC_INFO_MAGIC,
+            version: FW_DYNAMIC_INFO_VERSION,
+            next_addr,
+            next_mode: FW_DYNAMIC_INFO_NEXT_MODE_S,
+            options: 0,
+            boot_hart: 0,
+        }
+    }
+}
+
+/// Guest addresses of kernel, initrd and dtb.
+#[derive(Debug, PartialEq, Eq)]
+struct BootLayout {
+    /// Start address of initrd, 0 if there is no initrd.
+    initrd_start: u64,
+    dtb_start: u64,
+}
+
+/// Place initrd and dtb around kernel occupying `[kernel_start, kernel_end)` of guest
+/// memory ending at `mem_end`.
+///
+/// Dtb is placed 4M after the kernel, initrd at the top of memory aligned down to 4K.
+/// If memory is too small to keep initrd clear of dtb, initrd follows the kernel and
+/// dtb follows initrd, both aligned up to 4K.
+fn boot_layout(
+    kernel_start: u64,
+    kernel_end: u64,
+    mem_end: u64,
+    initrd_size: u64,
+) -> Result<BootLayout> {
+    if kernel_end > mem_end {
+        return Err(anyhow!(BootLoaderError::KernelOverflow(
+            kernel_start,
+            kernel_end - kernel_start
+        )));
+    }
+
+    let dtb_size = FDT_MAX_SIZE as u64;
+    let dtb_start = kernel_end + SZ_4M;
+    if initrd_size == 0 {
+        if dtb_start + dtb_size > mem_end {
+            return Err(anyhow!(BootLoaderError::DtbOverflow(dtb_start, mem_end)));
+        }
+        return Ok(BootLayout {
+            initrd_start: 0,
+            dtb_start,
+        });
+    }
+
+    let top_start = mem_end
+        .checked_sub(initrd_size)
+        .and_then(|addr| round_down(addr, INITRD_ALIGN));
+    if let Some(initrd_start) = top_start.filter(|addr| *addr >= dtb_start + dtb_size) {
+        return Ok(BootLayout {
+            initrd_start,
+            dtb_start,
+        });
+    }
+
+    let initrd_start = round_up(kernel_end, INITRD_ALIGN).unwrap();
+    let dtb_start = initrd_start
+        .checked_add(initrd_size)
+        .and_then(|end| round_up(end, INITRD_ALIGN))
+        .filter(|addr| addr + dtb_size <= mem_end);
+    match dtb_start {
+        Some(dtb_start) => Ok(BootLayout {
+            initrd_start,
+            dtb_start,
+        }),
+        None => Err(anyhow!(BootLoaderError::InitrdOverflow(
+            initrd_size,
+            mem_end.saturating_sub(initrd_start + dtb_size)
+        ))),
+    }
+}
+
+/// Start address of `Image` kernel when firmware of `fw_size` bytes is loaded at
+/// `mem_start`: the 2M offset, moved up in 2M steps if firmware and fw_dynamic info
+/// don't fit below it.
+fn bios_kernel_start(mem_start: u64, fw_size: u64) -> u64 {
+    let fw_end = mem_start + fw_size + FW_DYNAMIC_INFO_AREA;
+    round_up(fw_end, RISCV64_KERNEL_OFFSET).unwrap()
+}
+
+/// Place fw_dynamic info in the page right below the kernel, which must be clear of
+/// firmware ending at `fw_end`.
+fn fw_dynamic_start(fw_end: u64, kernel_start: u64) -> Result<u64> {
+    kernel_start
+        .checked_sub(FW_DYNAMIC_INFO_AREA)
+        .filter(|start| *start >= fw_end)
+        .ok_or_else(|| anyhow!(BootLoaderError::BiosOverflow(fw_end, kernel_start)))
 }
 
 fn load_kernel(
     fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>,
+    mut kernel_image: File,
+    elf: Option<&ElfKernel>,
     kernel_start: u64,
-    kernel_path: &Path,
+    kernel_size: u64,
     sys_mem: &Arc<AddressSpace>,
-) -> Result<u64> {
-    let mut kernel_image =
-        File::open(kernel_path).with_context(|| anyhow!(BootLoaderError::BootLoaderOpenKernel))?;
-    let kernel_size = kernel_image.metadata().unwrap().len();
-    let kernel_end = kernel_start + kernel_size;
-
+) -> Result<()> {
     if let Some(fw_cfg) = fwcfg {
         let mut kernel_data = Vec::new();
         kernel_image.read_to_end(&mut kernel_data)?;
@@ -78,49 +209,43 @@ fn load_kernel(
         lock_dev
             .add_data_entry(FwCfgEntryType::KernelData, kernel_data)
             .with_context(|| anyhow!(FwcfgErrorKind::AddEntryErr("KernelData".to_string())))?;
-    } else {
-        if sys_mem
-            .memory_end_address()
-            .raw_value()
-            .checked_sub(kernel_end)
-            .is_none()
-        {
-            return Err(anyhow!(BootLoaderError::KernelOverflow(
-                kernel_start,
-                kernel_size
-            )));
+    } else if let Some(elf) = elf {
+        for seg in elf.segments.iter() {
+            kernel_image.seek(SeekFrom::Start(seg.offset))?;
+            sys_mem
+                .write(
+                    &mut (&mut kernel_image).take(seg.file_size),
+                    GuestAddress(seg.addr),
+                    seg.file_size,
+                )
+                .with_context(|| "Fail to write kernel segment to guest memory")?;
+            let zero_size = seg.mem_size - seg.file_size;
+            if zero_size == 0 {
+                continue;
+            }
+            sys_mem
+                .write(
+                    &mut std::io::repeat(0).take(zero_size),
+                    GuestAddress(seg.addr + seg.file_size),
+                    zero_size,
+                )
+                .with_context(|| "Fail to clear kernel segment in guest memory")?;
         }
+    } else {
         sys_mem
             .write(&mut kernel_image, GuestAddress(kernel_start), kernel_size)
             .with_context(|| "Fail to write kernel to guest memory")?;
     }
-    Ok(kernel_end)
+    Ok(())
 }
 
 fn load_initrd(
     fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>,
-    initrd_path: &Path,
+    mut initrd_image: File,
+    initrd_start: u64,
+    initrd_size: u64,
     sys_mem: &Arc<AddressSpace>,
-    kernel_end: u64,
-) -> Result<(u64, u64)> {
-    let mut initrd_image =
-        File::open(initrd_path).with_context(|| anyhow!(BootLoaderError::BootLoaderOpenInitrd))?;
-    let initrd_size = initrd_image.metadata().unwrap().len();
-
-    let initrd_start = if let Some(addr) = sys_mem
-        .memory_end_address()
-        .raw_value()
-        .checked_sub(initrd_size)
-        .filter(|addr| addr >= &kernel_end)
-    {
-        addr
-    } else {
-        return Err(anyhow!(BootLoaderError::InitrdOverflow(
-            kernel_end,
-            initrd_size
-        )));
-    };
-
+) -> Result<()> {
     if let Some(fw_cfg) = fwcfg {
         let mut initrd_data = Vec::new();
         initrd_image.read_to_end(&mut initrd_data)?;
@@ -145,8 +270,7 @@ fn load_initrd(
             .write(&mut initrd_image, GuestAddress(initrd_start), initrd_size)
             .with_context(|| "Fail to write initrd to guest memory")?;
     }
-
-    Ok((initrd_start, initrd_size))
+    Ok(())
 }
 
 /// Load linux kernel and other boot source to Guest Memory.
@@ -156,10 +280,11 @@ fn load_initrd(
 /// 1. Prepare for linux kernel boot env, return guest memory layout.
 /// 2. According guest memory layout, load linux kernel to guest memory.
 /// 3. According guest memory layout, load initrd image to guest memory.
+/// 4. If there is firmware, load it and fw_dynamic info to guest memory.
 ///
 /// # Arguments
 ///
-/// * `config` - boot source config, contains kernel, initrd.
+/// * `config` - boot source config, contains kernel, initrd and firmware.
 /// * `sys_mem` - guest memory.
 ///
 /// # Errors
@@ -172,37 +297,329 @@ pub fn load_linux(
     fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>,
 ) -> Result<RISCVBootLoader> {
     // The memory layout is as follow:
-    // 1. kernel address: memory start + RISCV64_KERNEL_OFFSET
+    // 1. kernel address: memory start + RISCV64_KERNEL_OFFSET for Image, or the
+    //    physical addresses of segments for ELF
     // 2. dtb address: kernel end + SZ_4M
-    // 3. initrd address: memory end - inird_size
-    let kernel_start = config.mem_start + RISCV64_KERNEL_OFFSET;
-    let boot_pc = if fwcfg.is_some() { 0 } else { kernel_start };
+    // 3. initrd address: memory end - inird_size, aligned down to 4K
+    // Initrd and dtb follow the kernel instead if memory is small, see `boot_layout`.
+    // With firmware, it's placed at memory start, fw_dynamic info in the page below the
+    // kernel, and `Image` kernel is moved up in 2M steps if firmware is larger than 2M.
+    let bios_image = match &config.bios {
+        Some(bios_path) => Some(
+            File::open(bios_path).with_context(|| anyhow!(BootLoaderError::BootLoaderOpenBios))?,
+        ),
+        None => None,
+    };
+    let bios_size = match &bios_image {
+        Some(image) => image.metadata()?.len(),
+        None => 0,
+    };
+    let kernel_path = config.kernel.as_ref().unwrap();
+    let mut kernel_image =
+        File::open(kernel_path).with_context(|| anyhow!(BootLoaderError::BootLoaderOpenKernel))?;
+    let kernel_size = kernel_image.metadata()?.len();
+    let elf = if is_elf(&mut kernel_image)? {
+        Some(parse_elf(&mut kernel_image, config.mem_start)?)
+    } else {
+        None
+    };
+    let (kernel_start, kernel_end) = match &elf {
+        Some(elf) => (elf.start(), elf.end()),
+        None => {
+            let start = match &bios_image {
+                Some(_) => bios_kernel_start(config.mem_start, bios_size),
+                None => config.mem_start + RISCV64_KERNEL_OFFSET,
+            };
+            (start, start + kernel_size)
+        }
+    };
+    let kernel_entry = elf.as_ref().map_or(kernel_start, |elf| elf.entry);
+    let initrd_image = match &config.initrd {
+        Some(initrd_path) => Some(
+            File::open(initrd_path)
+                .with_context(|| anyhow!(BootLoaderError::BootLoaderOpenInitrd))?,
+        ),
+        None => {
+            info!("No initrd image file.");
+            None
+        }
+    };
+    let initrd_size = match &initrd_image {
+        Some(image) => image.metadata()?.len(),
+        None => 0,
+    };
+
+    // Check the placement before touching guest memory.
+    let mem_end = sys_mem.memory_end_address().raw_value();
+    let layout = boot_layout(kernel_start, kernel_end, mem_end, initrd_size)?;
+    let fw_dynamic_start = match &bios_image {
+        Some(_) => Some(fw_dynamic_start(
+            config.mem_start + bios_size,
+            kernel_start,
+        )?),
+        None => None,
+    };
+
+    let boot_pc = match &bios_image {
+        _ if fwcfg.is_some() => 0,
+        Some(_) => {
+            info!("Boot from firmware at 0x{:x}", config.mem_start);
+            config.mem_start
+        }
+        None => kernel_entry,
+    };
 
-    let kernel_end = load_kernel(
+    load_kernel(
         fwcfg,
+        kernel_image,
+        elf.as_ref(),
         kernel_start,
-        config.kernel.as_ref().unwrap(),
+        kernel_size,
         sys_mem,
     )
     .with_context(|| "Fail to load kernel")?;
-
-    let dtb_addr = kernel_end + SZ_4M;
-
-    let mut initrd_start = 0_u64;
-    let mut initrd_size = 0_u64;
-    if config.initrd.is_some() {
-        let initrd_tuple = load_initrd(fwcfg, config.initrd.as_ref().unwrap(), sys_mem, kernel_end)
+    if let Some(image) = initrd_image {
+        load_initrd(fwcfg, image, layout.initrd_start, initrd_size, sys_mem)
             .with_context(|| "Fail to load initrd")?;
-        initrd_start = initrd_tuple.0;
-        initrd_size = initrd_tuple.1;
-    } else {
-        info!("No initrd image file.");
+    }
+    if let (Some(mut image), Some(info_start)) = (bios_image, fw_dynamic_start) {
+        sys_mem
+            .write(&mut image, GuestAddress(config.mem_start), bios_size)
+            .with_context(|| "Fail to write firmware to guest memory")?;
+        sys_mem
+            .write_object(&FwDynamicInfo::new(kernel_entry), GuestAddress(info_start))
+            .with_context(|| "Fail to write fw_dynamic info to guest memory")?;
     }
 
     Ok(RISCVBootLoader {
         boot_pc,
-        initrd_start,
+        initrd_start: layout.initrd_start,
         initrd_size,
-        dtb_start: dtb_addr,
+        dtb_start: layout.dtb_start,
+        fw_dynamic_start,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use address_space::{HostMemMapping, Region};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::elf::test::{elf_fixture, FixtureSegment};
+    use super::*;
+
+    const MEM_START: u64 = 0x8000_0000;
+    const SZ_1M: u64 = 0x10_0000;
+    const KERNEL_START: u64 = MEM_START + RISCV64_KERNEL_OFFSET;
+
+    fn image_layout(mem_end: u64, kernel_size: u64, initrd_size: u64) -> Result<BootLayout> {
+        boot_layout(
+            KERNEL_START,
+            KERNEL_START + kernel_size,
+            mem_end,
+            initrd_size,
+        )
+    }
+
+    #[test]
+    fn test_boot_layout_initrd_at_top() {
+        let mem_end = MEM_START + 512 * SZ_1M;
+        let layout = image_layout(mem_end, 20 * SZ_1M + 3, 8 * SZ_1M + 5).unwrap();
+        let kernel_end = KERNEL_START + 20 * SZ_1M + 3;
+        assert_eq!(layout.dtb_start, kernel_end + SZ_4M);
+        assert_eq!(layout.initrd_start % INITRD_ALIGN, 0);
+        assert!(layout.initrd_start + 8 * SZ_1M + 5 <= mem_end);
+        assert!(mem_end - layout.initrd_start < 8 * SZ_1M + 5 + INITRD_ALIGN);
+
+        // No initrd.
+        let layout = image_layout(mem_end, 20 * SZ_1M, 0).unwrap();
+        assert_eq!(layout.initrd_start, 0);
+    }
+
+    #[test]
+    fn test_boot_layout_small_memory() {
+        // Initrd at the top of memory would overlap dtb placed 4M after the kernel.
+        let kernel_size = 6 * SZ_1M + 1;
+        let initrd_size = 3 * SZ_1M;
+        let mem_end = MEM_START + RISCV64_KERNEL_OFFSET + kernel_size + 6 * SZ_1M;
+        let layout = image_layout(mem_end, kernel_size, initrd_size).unwrap();
+        let kernel_end = KERNEL_START + kernel_size;
+        assert_eq!(
+            layout.initrd_start,
+            round_up(kernel_end, INITRD_ALIGN).unwrap()
+        );
+        assert_eq!(layout.dtb_start % INITRD_ALIGN, 0);
+        assert!(layout.dtb_start >= layout.initrd_start + initrd_size);
+        assert!(layout.dtb_start + FDT_MAX_SIZE as u64 <= mem_end);
+    }
+
+    #[test]
+    fn test_boot_layout_overflow() {
+        let kernel_size = 6 * SZ_1M;
+        let mem_end = MEM_START + RISCV64_KERNEL_OFFSET + kernel_size + 2 * SZ_1M;
+        // Neither at the top of memory nor after the kernel.
+        let err = image_layout(mem_end, kernel_size, 2 * SZ_1M).unwrap_err();
+        match err.downcast_ref::<BootLoaderError>() {
+            Some(BootLoaderError::InitrdOverflow(size, available)) => {
+                assert_eq!(*size, 2 * SZ_1M);
+                assert_eq!(*available, 2 * SZ_1M - FDT_MAX_SIZE as u64);
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        // Dtb placed 4M after the kernel is out of memory.
+        assert!(image_layout(mem_end, kernel_size, 0).is_err());
+        // Kernel is out of memory.
+        assert!(image_layout(mem_end, 9 * SZ_1M, 0).is_err());
+    }
+
+    fn sys_mem_init(size: u64) -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_mem = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(MEM_START),
+                None,
+                size,
+                None,
+                false,
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        sys_mem
+            .root()
+            .add_subregion(Region::init_ram_region(host_mmap), MEM_START)
+            .unwrap();
+        sys_mem
+    }
+
+    #[test]
+    fn test_load_elf_kernel() {
+        let sys_mem = sys_mem_init(64 * SZ_1M);
+        let vaddr = 0xffff_ffff_8020_0000;
+        let image = elf_fixture(
+            243,
+            vaddr + 4,
+            &[FixtureSegment {
+                vaddr,
+                paddr: RISCV64_KERNEL_OFFSET,
+                data: &[0x13, 0, 0, 0, 0x6f, 0, 0, 0],
+                mem_size: 0x1000,
+            }],
+        );
+        let kernel = TempFile::new().unwrap();
+        kernel.as_file().write_all(&image).unwrap();
+        // Stale data where bss of the segment is placed.
+        sys_mem
+            .write_object(&u32::MAX, GuestAddress(KERNEL_START + 0x10))
+            .unwrap();
+
+        let config = RISCVBootLoaderConfig {
+            kernel: Some(kernel.as_path().to_path_buf()),
+            initrd: None,
+            mem_start: MEM_START,
+            bios: None,
+        };
+        let boot = load_linux(&config, &sys_mem, None).unwrap();
+        assert_eq!(boot.boot_pc, KERNEL_START + 4);
+        assert!(boot.fw_dynamic_start.is_none());
+        assert_eq!(boot.dtb_start, KERNEL_START + 0x1000 + SZ_4M);
+        let word: u32 = sys_mem.read_object(GuestAddress(KERNEL_START + 4)).unwrap();
+        assert_eq!(word, 0x6f);
+        let word: u32 = sys_mem
+            .read_object(GuestAddress(KERNEL_START + 0x10))
+            .unwrap();
+        assert_eq!(word, 0);
+
+        // Segment out of guest memory.
+        let image = elf_fixture(
+            243,
+            vaddr,
+            &[FixtureSegment {
+                vaddr,
+                paddr: 64 * SZ_1M,
+                data: &[0x13, 0, 0, 0],
+                mem_size: 4,
+            }],
+        );
+        let kernel = TempFile::new().unwrap();
+        kernel.as_file().write_all(&image).unwrap();
+        let config = RISCVBootLoaderConfig {
+            kernel: Some(kernel.as_path().to_path_buf()),
+            ..config
+        };
+        assert!(load_linux(&config, &sys_mem, None).is_err());
+    }
+
+    #[test]
+    fn test_load_bios() {
+        let sys_mem = sys_mem_init(64 * SZ_1M);
+        let firmware = [0x6f_u8, 0, 0, 0];
+        let bios = TempFile::new().unwrap();
+        bios.as_file().write_all(&firmware).unwrap();
+        let kernel = TempFile::new().unwrap();
+        kernel.as_file().write_all(&[0x13, 0, 0, 0]).unwrap();
+
+        let config = RISCVBootLoaderConfig {
+            kernel: Some(kernel.as_path().to_path_buf()),
+            initrd: None,
+            mem_start: MEM_START,
+            bios: Some(bios.as_path().to_path_buf()),
+        };
+        let boot = load_linux(&config, &sys_mem, None).unwrap();
+        assert_eq!(boot.boot_pc, MEM_START);
+        assert_eq!(boot.dtb_start, KERNEL_START + 4 + SZ_4M);
+        let info_start = KERNEL_START - FW_DYNAMIC_INFO_AREA;
+        assert_eq!(boot.fw_dynamic_start, Some(info_start));
+        let info: FwDynamicInfo = sys_mem.read_object(GuestAddress(info_start)).unwrap();
+        assert_eq!(info, FwDynamicInfo::new(KERNEL_START));
+        assert_eq!(info.magic, FW_DYNAMIC_INFO_MAGIC);
+        let word: u32 = sys_mem.read_object(GuestAddress(MEM_START)).unwrap();
+        assert_eq!(word, 0x6f);
+        let word: u32 = sys_mem.read_object(GuestAddress(KERNEL_START)).unwrap();
+        assert_eq!(word, 0x13);
+
+        // Kernel is moved up to the next 2M for firmware larger than 2M.
+        bios.as_file().set_len(RISCV64_KERNEL_OFFSET).unwrap();
+        let boot = load_linux(&config, &sys_mem, None).unwrap();
+        let kernel_start = MEM_START + 2 * RISCV64_KERNEL_OFFSET;
+        assert_eq!(
+            boot.fw_dynamic_start,
+            Some(kernel_start - FW_DYNAMIC_INFO_AREA)
+        );
+        let info: FwDynamicInfo = sys_mem
+            .read_object(GuestAddress(kernel_start - FW_DYNAMIC_INFO_AREA))
+            .unwrap();
+        assert_eq!(info.next_addr, kernel_start);
+
+        // ELF kernel is not moved, firmware overlaps fw_dynamic info below it.
+        let vaddr = 0xffff_ffff_8020_0000;
+        let image = elf_fixture(
+            243,
+            vaddr,
+            &[FixtureSegment {
+                vaddr,
+                paddr: RISCV64_KERNEL_OFFSET,
+                data: &[0x13, 0, 0, 0],
+                mem_size: 4,
+            }],
+        );
+        let kernel = TempFile::new().unwrap();
+        kernel.as_file().write_all(&image).unwrap();
+        let config = RISCVBootLoaderConfig {
+            kernel: Some(kernel.as_path().to_path_buf()),
+            ..config
+        };
+        let err = load_linux(&config, &sys_mem, None).unwrap_err();
+        match err.downcast_ref::<BootLoaderError>() {
+            Some(BootLoaderError::BiosOverflow(fw_end, start)) => {
+                assert_eq!(*fw_end, KERNEL_START);
+                assert_eq!(*start, KERNEL_START);
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+}
@@ -20,7 +20,8 @@
 mod riscv;
 
 pub mod error;
-use anyhow::{anyhow, Context, Result};
+mod stats;
+use anyhow::{anyhow, bail, Context, Result};
 pub use error::CpuError;
 use machine_manager::qmp::qmp_schema;
 #[cfg(target_arch = "riscv64")]
@@ -28,22 +29,36 @@ pub use riscv::RISCVCPUBootConfig as CPUBootConfig;
 #[cfg(target_arch = "riscv64")]
 pub use riscv::RISCVCPUCaps as CPUCaps;
 #[cfg(target_arch = "riscv64")]
+pub use riscv::RISCVCPUFeatures as CPUFeatures;
+#[cfg(target_arch = "riscv64")]
+pub use riscv::RISCVCPUSnapshot as CPUSnapshot;
+#[cfg(target_arch = "riscv64")]
 pub use riscv::RISCVCPUState as ArchCPU;
 #[cfg(target_arch = "riscv64")]
 pub use riscv::RISCVCPUTopology as CPUTopology;
+#[cfg(target_arch = "riscv64")]
+pub use riscv::GDB_NUM_CORE_REGS;
+pub use stats::CpuStats;
 
 use std::cell::RefCell;
+use std::os::unix::thread::JoinHandleExt;
 use std::sync::atomic::{fence, AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, Condvar, Mutex, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use kvm_bindings::{kvm_guest_debug, KVM_GUESTDBG_ENABLE};
 use kvm_ioctls::{VcpuExit, VcpuFd};
 use libc::{c_int, c_void, siginfo_t};
 use log::{error, info, warn};
 use machine_manager::event;
 use machine_manager::machine::MachineInterface;
+#[cfg(target_arch = "riscv64")]
+use machine_manager::signal_handler::set_vm_exit_code;
 use machine_manager::{qmp::qmp_schema as schema, qmp::QmpChannel};
+use util::boot_time::{record_boot_milestone, BootMilestone};
+use util::seccomp::{install_thread_filter, SeccompThread};
+use util::syscall::{get_thread_affinity, set_thread_affinity};
 use vmm_sys_util::signal::{register_signal_handler, Killable};
 
 // SIGRTMIN = 34 (GNU, in MUSL is 35) and SIGRTMAX = 64  in linux, VCPU signal
@@ -89,6 +104,7 @@ pub trait CPUInterface {
         &self,
         boot: &CPUBootConfig,
         topology: &CPUTopology,
+        config: &CPUFeatures,
     ) -> Result<()>;
 
     /// Start `CPU` thread and run virtual CPU in kvm.
@@ -150,6 +166,16 @@ pub struct CPU {
     boot_state: Arc<Mutex<ArchCPU>>,
     /// Sync the pause state of vCPU in kvm and userspace.
     pause_signal: Arc<AtomicBool>,
+    /// Runtime statistics accumulated in vcpu thread.
+    stats: Arc<CpuStats>,
+    /// Host cpu which the vcpu thread is pinned to.
+    affinity: Mutex<Option<usize>>,
+    /// Line buffer of what guest prints through SBI console.
+    #[cfg(target_arch = "riscv64")]
+    sbi_console: Mutex<riscv::SbiConsole>,
+    /// Steal time reported to guest.
+    #[cfg(target_arch = "riscv64")]
+    steal_time: Mutex<riscv::StealTime>,
 }
 
 impl CPU {
@@ -178,11 +204,20 @@ impl CPU {
             caps: CPUCaps::init_capabilities(),
             boot_state: Arc::new(Mutex::new(ArchCPU::default())),
             pause_signal: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(CpuStats::default()),
+            affinity: Mutex::new(None),
+            #[cfg(target_arch = "riscv64")]
+            sbi_console: Mutex::new(riscv::SbiConsole::default()),
+            #[cfg(target_arch = "riscv64")]
+            steal_time: Mutex::new(riscv::StealTime::default()),
         }
     }
 
     pub fn set_to_boot_state(&self) {
         self.arch_cpu.lock().unwrap().set(&self.boot_state);
+        // Guest sets steal time again once it boots.
+        #[cfg(target_arch = "riscv64")]
+        self.steal_time.lock().unwrap().reset(&self.fd);
     }
 
     /// Get this `CPU`'s ID.
@@ -205,6 +240,21 @@ impl CPU {
         &self.arch_cpu
     }
 
+    /// Get the virtual machine this `CPU` is attached to.
+    pub fn vm(&self) -> Weak<Mutex<dyn MachineInterface + Send + Sync>> {
+        self.vm.clone()
+    }
+
+    /// Get this `CPU`'s architecture-special property set for booting.
+    pub fn boot_state(&self) -> &Arc<Mutex<ArchCPU>> {
+        &self.boot_state
+    }
+
+    /// Get this `CPU`'s runtime statistics.
+    pub fn stats(&self) -> &Arc<CpuStats> {
+        &self.stats
+    }
+
     /// Set task the `CPU` to handle.
     fn set_task(&self, task: Option<thread::JoinHandle<()>>) {
         let mut data = self.task.lock().unwrap();
@@ -221,6 +271,132 @@ impl CPU {
     fn set_tid(&self) {
         *self.tid.lock().unwrap() = Some(util::unix::gettid());
     }
+
+    /// Pin the thread of `CPU` to host cpu `host_cpu` once it's started.
+    pub fn set_affinity(&self, host_cpu: usize) {
+        *self.affinity.lock().unwrap() = Some(host_cpu);
+    }
+
+    /// Get host cpus which the thread of `CPU` is allowed to run on, `None` if the
+    /// thread is not started.
+    pub fn host_cpus(&self) -> Option<Vec<usize>> {
+        let tid = (*self.tid.lock().unwrap())?;
+        match get_thread_affinity(tid as libc::pid_t) {
+            Ok(cpus) => Some(cpus),
+            Err(e) => {
+                error!("Failed to get affinity of vcpu{}: {:?}", self.id, e);
+                None
+            }
+        }
+    }
+
+    /// Make guest `ebreak` exit to userspace instead of trapping into guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `enable` - Enable or disable guest debugging.
+    pub fn set_guest_debug(&self, enable: bool) -> Result<()> {
+        let debug = kvm_guest_debug {
+            control: if enable { KVM_GUESTDBG_ENABLE } else { 0 },
+            ..Default::default()
+        };
+        self.fd
+            .set_guest_debug(&debug)
+            .with_context(|| format!("Failed to set guest debug for CPU {}", self.id))?;
+        Ok(())
+    }
+
+    /// Pause this `CPU` at the guest breakpoint it hit, and report it to debugger
+    /// which will stop the other vcpus.
+    fn debug_stop(&self, vm: &Arc<Mutex<dyn MachineInterface + Send + Sync>>) -> Result<()> {
+        let (cpu_state, _) = &*self.state;
+        let mut state = cpu_state.lock().unwrap();
+        match *state {
+            CpuLifecycleState::Stopping | CpuLifecycleState::Stopped => return Ok(()),
+            CpuLifecycleState::Running => {
+                *state = CpuLifecycleState::Paused;
+                #[cfg(target_arch = "riscv64")]
+                self.arch_cpu.lock().unwrap().save_timer(&self.fd)?;
+            }
+            // Paused by others, who has saved guest time.
+            _ => {}
+        }
+        self.pause_signal.store(true, Ordering::SeqCst);
+        drop(state);
+
+        if !vm.lock().unwrap().debug_exit(self.id) {
+            bail!("Vcpu{} hit a breakpoint without debugger", self.id);
+        }
+        Ok(())
+    }
+
+    /// Log lines printed by guest through SBI console, and report guest panic once
+    /// the kernel panic message shows up.
+    #[cfg(target_arch = "riscv64")]
+    fn sbi_console_putchar(&self, vm: &Arc<Mutex<dyn MachineInterface + Send + Sync>>, ch: u8) {
+        let line = match self.sbi_console.lock().unwrap().putchar(ch) {
+            Some(line) => line,
+            None => return,
+        };
+        info!("Vcpu{} sbi console: {}", self.id, line);
+        if riscv::is_kernel_panic(&line) {
+            vm.lock().unwrap().guest_panicked(line.trim());
+        }
+    }
+
+    /// Handle `call` of SBI debug console, returns the value to guest in `a1` or the
+    /// SBI error. What guest writes is also checked for kernel panic.
+    #[cfg(target_arch = "riscv64")]
+    fn sbi_debug_console(
+        &self,
+        vm: &Arc<Mutex<dyn MachineInterface + Send + Sync>>,
+        call: riscv::SbiDebugConsoleCall,
+    ) -> std::result::Result<u64, i64> {
+        let ret = match call {
+            riscv::SbiDebugConsoleCall::Write { addr, len } => {
+                let written = vm.lock().unwrap().sbi_console_write(addr, len);
+                written.map(|data| {
+                    for ch in &data {
+                        self.sbi_console_putchar(vm, *ch);
+                    }
+                    data.len() as u64
+                })
+            }
+            riscv::SbiDebugConsoleCall::Read { addr, len } => {
+                vm.lock().unwrap().sbi_console_read(addr, len)
+            }
+            riscv::SbiDebugConsoleCall::WriteByte(byte) => {
+                let written = vm.lock().unwrap().sbi_console_write_byte(byte);
+                self.sbi_console_putchar(vm, byte);
+                written.map(|_| 0)
+            }
+        };
+        ret.map_err(riscv::sbi_console_error)
+    }
+
+    /// Handle STA call of guest setting the shared memory of steal time at guest
+    /// physical address `shmem`, or disabling it if none.
+    #[cfg(target_arch = "riscv64")]
+    fn sbi_steal_time(
+        &self,
+        vm: &Arc<Mutex<dyn MachineInterface + Send + Sync>>,
+        shmem: Option<u64>,
+    ) -> std::result::Result<(), i64> {
+        if !self.steal_time.lock().unwrap().is_emulated() {
+            return Err(riscv::SBI_ERR_NOT_SUPPORTED);
+        }
+        let hva = match shmem {
+            Some(addr) => Some(
+                vm.lock()
+                    .unwrap()
+                    .guest_ram_host_address(addr, riscv::SBI_STA_SHMEM_SIZE)
+                    .ok_or(riscv::SBI_ERR_INVALID_ADDRESS)?,
+            ),
+            None => None,
+        };
+        self.steal_time.lock().unwrap().set_shmem(hva, self.tid());
+        Ok(())
+    }
 }
 
 impl CPUInterface for CPU {
@@ -228,7 +404,7 @@ impl CPUInterface for CPU {
         &self,
         boot: &CPUBootConfig,
         topology: &CPUTopology,
-        #[cfg(target_arch = "aarch64")] config: &CPUFeatures,
+        config: &CPUFeatures,
     ) -> Result<()> {
         trace_cpu_boot_config(boot);
         let (cpu_state, _) = &*self.state;
@@ -242,12 +418,7 @@ impl CPUInterface for CPU {
         self.arch_cpu
             .lock()
             .unwrap()
-            .set_boot_config(
-                &self.fd,
-                boot,
-                #[cfg(target_arch = "aarch64")]
-                config,
-            )
+            .set_boot_config(&self.fd, boot, config)
             .with_context(|| "Failed to realize arch cpu")?;
 
         self.arch_cpu
@@ -257,6 +428,11 @@ impl CPUInterface for CPU {
             .with_context(|| "Failed to realize arch cpu")?;
 
         self.boot_state.lock().unwrap().set(&self.arch_cpu);
+        #[cfg(target_arch = "riscv64")]
+        self.steal_time
+            .lock()
+            .unwrap()
+            .realize(&self.fd, config.steal_time);
         Ok(())
     }
 
@@ -268,6 +444,9 @@ impl CPUInterface for CPU {
             return Ok(());
         }
 
+        #[cfg(target_arch = "riscv64")]
+        self.arch_cpu.lock().unwrap().restore_timer(&self.fd)?;
+
         *cpu_state = CpuLifecycleState::Running;
         self.pause_signal.store(false, Ordering::SeqCst);
         drop(cpu_state);
@@ -304,7 +483,17 @@ impl CPUInterface for CPU {
                 }
             })
             .with_context(|| format!("Failed to create thread for CPU {}/KVM", local_cpu.id()))?;
+        let thread = handle.as_pthread_t();
         local_cpu.set_task(Some(handle));
+        if let Some(host_cpu) = *local_cpu.affinity.lock().unwrap() {
+            set_thread_affinity(thread, host_cpu).with_context(|| {
+                format!(
+                    "Failed to pin vcpu{} to host cpu {}",
+                    local_cpu.id(),
+                    host_cpu
+                )
+            })?;
+        }
         Ok(())
     }
 
@@ -338,7 +527,8 @@ impl CPUInterface for CPU {
         let task = self.task.lock().unwrap();
         let (cpu_state, cvar) = &*self.state;
 
-        if *cpu_state.lock().unwrap() == CpuLifecycleState::Running {
+        let was_running = *cpu_state.lock().unwrap() == CpuLifecycleState::Running;
+        if was_running {
             *cpu_state.lock().unwrap() = CpuLifecycleState::Paused;
             cvar.notify_one()
         }
@@ -362,6 +552,12 @@ impl CPUInterface for CPU {
             }
         }
 
+        // Guest time keeps going in kvm, freeze it until resumed.
+        #[cfg(target_arch = "riscv64")]
+        if was_running {
+            self.arch_cpu.lock().unwrap().save_timer(&self.fd)?;
+        }
+
         Ok(())
     }
 
@@ -406,6 +602,7 @@ impl CPUInterface for CPU {
             let shutdown_msg = schema::Shutdown {
                 guest: true,
                 reason: "guest-shutdown".to_string(),
+                resumable: None,
             };
             event!(Shutdown; shutdown_msg);
         }
@@ -414,13 +611,14 @@ impl CPUInterface for CPU {
     }
 
     fn guest_reset(&self) -> Result<()> {
-        if let Some(vm) = self.vm.upgrade() {
-            vm.lock().unwrap().reset();
+        let reset = if let Some(vm) = self.vm.upgrade() {
+            vm.lock().unwrap().reset()
         } else {
             return Err(anyhow!(CpuError::NoMachineInterface));
-        }
+        };
 
-        if QmpChannel::is_connected() {
+        // Machine may turn reset into shutdown, which reports its own event.
+        if reset && QmpChannel::is_connected() {
             let reset_msg = schema::Reset { guest: true };
             event!(Reset; reset_msg);
         }
@@ -435,7 +633,27 @@ impl CPUInterface for CPU {
             return Err(anyhow!(CpuError::NoMachineInterface));
         };
 
-        match self.fd.run() {
+        record_boot_milestone(BootMilestone::FirstVcpuEntry);
+        #[cfg(target_arch = "riscv64")]
+        self.steal_time.lock().unwrap().update(self.tid());
+        let entry = Instant::now();
+        let run = self.fd.run();
+        let exit = Instant::now();
+        let kind = stats::ExitKind::from_run(&run);
+        let ret = self.handle_vcpu_exit(&vm, run);
+        self.stats.account(entry, exit, kind);
+        ret
+    }
+}
+
+impl CPU {
+    /// Handle the exit of `KVM_RUN`, return false if vcpu should stop running.
+    fn handle_vcpu_exit(
+        &self,
+        vm: &Arc<Mutex<dyn MachineInterface + Send + Sync>>,
+        run: std::result::Result<VcpuExit, kvm_ioctls::Error>,
+    ) -> Result<bool> {
+        match run {
             Ok(run) => match run {
                 VcpuExit::MmioRead(addr, data) => {
                     vm.lock().unwrap().mmio_read(addr, data);
@@ -452,6 +670,18 @@ impl CPUInterface for CPU {
                             "Vcpu{} received an KVM_SYSTEM_EVENT_SHUTDOWN signal",
                             self.id()
                         );
+                        // KVM passes the reason of SBI system reset in flags.
+                        #[cfg(target_arch = "riscv64")]
+                        if riscv::is_system_failure(flags)
+                            && vm.lock().unwrap().guest_panicked("sbi-system-failure")
+                        {
+                            return Ok(true);
+                        }
+                        if vm.lock().unwrap().pause_on_shutdown() {
+                            return Ok(true);
+                        }
+                        #[cfg(target_arch = "riscv64")]
+                        set_vm_exit_code(riscv::shutdown_exit_code(flags));
                         self.guest_shutdown()
                             .with_context(|| "Some error occurred in guest shutdown")?;
                     } else if event == kvm_bindings::KVM_SYSTEM_EVENT_RESET {
@@ -462,6 +692,14 @@ impl CPUInterface for CPU {
                         self.guest_reset()
                             .with_context(|| "Some error occurred in guest reset")?;
                         return Ok(true);
+                    } else if event == kvm_bindings::KVM_SYSTEM_EVENT_CRASH {
+                        info!(
+                            "Vcpu{} received an KVM_SYSTEM_EVENT_CRASH signal",
+                            self.id()
+                        );
+                        if vm.lock().unwrap().guest_panicked("kvm-system-crash") {
+                            return Ok(true);
+                        }
                     } else {
                         error!(
                             "Vcpu{} received unexpected system event with type 0x{:x}, flags 0x{:x}",
@@ -473,6 +711,72 @@ impl CPUInterface for CPU {
 
                     return Ok(false);
                 }
+                #[cfg(target_arch = "riscv64")]
+                VcpuExit::RiscvSbi(sbi)
+                    if sbi.extension_id == riscv::SBI_EXT_0_1_CONSOLE_PUTCHAR =>
+                {
+                    self.sbi_console_putchar(vm, sbi.args[0] as u8);
+                    sbi.ret[0] = 0;
+                }
+                #[cfg(target_arch = "riscv64")]
+                VcpuExit::RiscvSbi(sbi) if sbi.extension_id == riscv::SBI_EXT_DBCN => {
+                    let ret = riscv::sbi_debug_console(sbi.function_id, &sbi.args)
+                        .and_then(|call| self.sbi_debug_console(vm, call));
+                    match ret {
+                        Ok(value) => {
+                            sbi.ret[0] = 0;
+                            sbi.ret[1] = value;
+                        }
+                        Err(error) => {
+                            sbi.ret[0] = error as u64;
+                            sbi.ret[1] = 0;
+                        }
+                    }
+                }
+                #[cfg(target_arch = "riscv64")]
+                VcpuExit::RiscvSbi(sbi) if sbi.extension_id == riscv::SBI_EXT_STA => {
+                    let ret = riscv::sbi_steal_time(sbi.function_id, &sbi.args)
+                        .and_then(|shmem| self.sbi_steal_time(vm, shmem));
+                    sbi.ret[0] = match ret {
+                        Ok(()) => 0,
+                        Err(error) => error as u64,
+                    };
+                    sbi.ret[1] = 0;
+                }
+                #[cfg(target_arch = "riscv64")]
+                VcpuExit::RiscvSbi(sbi) => {
+                    match riscv::sbi_system_reset(sbi.extension_id, sbi.function_id, &sbi.args) {
+                        Ok(riscv::SbiSystemReset::Shutdown(reason)) => {
+                            info!("Vcpu{} received SBI system shutdown", self.id());
+                            if riscv::is_system_failure(reason)
+                                && vm.lock().unwrap().guest_panicked("sbi-system-failure")
+                            {
+                                return Ok(true);
+                            }
+                            if vm.lock().unwrap().pause_on_shutdown() {
+                                return Ok(true);
+                            }
+                            set_vm_exit_code(riscv::shutdown_exit_code(reason));
+                            self.guest_shutdown()
+                                .with_context(|| "Some error occurred in guest shutdown")?;
+                            return Ok(false);
+                        }
+                        Ok(riscv::SbiSystemReset::Reboot) => {
+                            info!("Vcpu{} received SBI system reboot", self.id());
+                            self.guest_reset()
+                                .with_context(|| "Some error occurred in guest reset")?;
+                        }
+                        Err(error) => {
+                            sbi.ret[0] = error as u64;
+                            sbi.ret[1] = 0;
+                        }
+                    }
+                }
+                VcpuExit::Debug(_) => {
+                    info!("Vcpu{} received KVM_EXIT_DEBUG signal", self.id());
+                    self.debug_stop(vm)
+                        .with_context(|| "Some error occurred in debug stop")?;
+                }
                 VcpuExit::FailEntry(reason, cpuid) => {
                     info!(
                         "Vcpu{} received KVM_EXIT_FAIL_ENTRY signal. the vcpu could not be run due to unknown reasons({})",
@@ -626,9 +930,19 @@ impl CPUThreadWorker {
             .reset()
             .with_context(|| "Failed to reset for cpu register state")?;
 
+        // Filter is installed before waiting for the others, but its failure is
+        // reported after that, so that they aren't blocked.
+        let seccomp_ret = install_thread_filter(SeccompThread::Vcpu);
+
         // Wait for all vcpu to complete the running
         // environment initialization.
         thread_barrier.wait();
+        seccomp_ret.with_context(|| {
+            format!(
+                "Failed to install seccomp filter of vcpu{}",
+                self.thread_cpu.id
+            )
+        })?;
 
         info!("vcpu{} start running", self.thread_cpu.id);
         while let Ok(true) = self.ready_for_running() {
@@ -726,6 +1040,29 @@ impl CpuTopology {
         mask[vcpu_id]
     }
 
+    /// Set online mask for a cpu, see `get_mask`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_id` - ID of vcpu.
+    /// * `mask` - `1` if vcpu is online, `0` if offline.
+    pub fn set_mask(&self, vcpu_id: usize, mask: u8) {
+        let mut online_mask = self.online_mask.lock().unwrap();
+        online_mask[vcpu_id] = mask;
+    }
+
+    /// Find the offline vcpu at `socket-id`, `core-id` and `thread-id` reported
+    /// by QMP. Vcpus of different dies and clusters share the same position,
+    /// the one with the lowest ID is returned.
+    pub fn find_offline_vcpu(&self, socket_id: u8, core_id: u8, thread_id: u8) -> Option<u8> {
+        (0..self.max_cpus).find(|&vcpu_id| {
+            let (socketid, _dieid, _clusterid, coreid, threadid) =
+                self.get_topo_item(vcpu_id as usize);
+            self.get_mask(vcpu_id as usize) == 0
+                && (socketid, coreid, threadid) == (socket_id, core_id, thread_id)
+        })
+    }
+
     /// Get single cpu topology for vcpu, return this vcpu's `socket-id`,
     /// `core-id` and `thread-id`.
     ///
@@ -768,3 +1105,27 @@ fn capture_boot_signal(addr: u64, data: &[u8]) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_offline_vcpu() {
+        // 2 sockets * 2 cores * 2 threads, 3 vcpus online.
+        let topo = CpuTopology::new(3, 2, 1, 1, 2, 2, 8);
+        assert_eq!(topo.find_offline_vcpu(0, 1, 1), Some(3));
+        assert_eq!(topo.find_offline_vcpu(1, 0, 1), Some(5));
+        // Online vcpu and position out of topology.
+        assert_eq!(topo.find_offline_vcpu(0, 1, 0), None);
+        assert_eq!(topo.find_offline_vcpu(0, 2, 0), None);
+
+        topo.set_mask(3, 1);
+        assert_eq!(topo.get_mask(3), 1);
+        assert_eq!(topo.find_offline_vcpu(0, 1, 1), None);
+
+        // Vcpus of the second cluster share positions with the first one.
+        let topo = CpuTopology::new(2, 1, 1, 2, 2, 1, 4);
+        assert_eq!(topo.find_offline_vcpu(0, 0, 0), Some(2));
+    }
+}
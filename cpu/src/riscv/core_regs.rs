@@ -15,7 +15,8 @@ use std::mem::size_of;
 
 use kvm_bindings::{
     kvm_riscv_config, kvm_riscv_core, kvm_riscv_timer, user_regs_struct, KVM_REG_RISCV,
-    KVM_REG_RISCV_CONFIG, KVM_REG_RISCV_CORE, KVM_REG_RISCV_TIMER, KVM_REG_SIZE_U64,
+    KVM_REG_RISCV_CONFIG, KVM_REG_RISCV_CORE, KVM_REG_RISCV_CSR, KVM_REG_RISCV_FP_D,
+    KVM_REG_RISCV_FP_F, KVM_REG_RISCV_TIMER, KVM_REG_SIZE_U32, KVM_REG_SIZE_U64,
 };
 use kvm_ioctls::VcpuFd;
 use util::offset_of;
@@ -237,6 +238,89 @@ impl Into<u64> for RISCVCoreRegs {
     }
 }
 
+/// Returns the register id of the `index`th field of `user_regs_struct`, where
+/// `pc` is 0 and general register xN is N.
+pub fn core_reg_id(index: usize) -> u64 {
+    KVM_REG_RISCV as u64
+        | KVM_REG_SIZE_U64 as u64
+        | u64::from(KVM_REG_RISCV_CORE)
+        | index as u64
+}
+
+/// Number of registers in `kvm_riscv_core`: pc, x1-x31 and mode.
+const NR_CORE_REGS: u64 = 33;
+/// Number of registers in `kvm_riscv_csr`, sstatus to scounteren.
+const NR_CSR_REGS: u64 = 10;
+/// Number of FP registers f0-f31, which are followed by fcsr.
+const NR_FP_REGS: u64 = 32;
+
+/// Returns ids of registers read by `KVM_GET_ONE_REG` for snapshot: core registers,
+/// CSRs and FP registers of the widest FP extension the vcpu has. Timer registers
+/// are not included, as their frozen values are kept when vcpu is paused.
+///
+/// # Arguments
+///
+/// * `has_f` - The vcpu has single-precision FP extension.
+/// * `has_d` - The vcpu has double-precision FP extension.
+pub fn snapshot_reg_ids(has_f: bool, has_d: bool) -> Vec<u64> {
+    let reg_id = |reg_type: u32, size: u64, index: u64| {
+        KVM_REG_RISCV as u64 | size | u64::from(reg_type) | index
+    };
+    let mut ids: Vec<u64> = (0..NR_CORE_REGS).map(|i| core_reg_id(i as usize)).collect();
+    ids.extend((0..NR_CSR_REGS).map(|i| reg_id(KVM_REG_RISCV_CSR, KVM_REG_SIZE_U64, i)));
+    let fp = if has_d {
+        Some((KVM_REG_RISCV_FP_D, KVM_REG_SIZE_U64))
+    } else if has_f {
+        Some((KVM_REG_RISCV_FP_F, KVM_REG_SIZE_U32))
+    } else {
+        None
+    };
+    if let Some((fp_type, size)) = fp {
+        ids.extend((0..NR_FP_REGS).map(|i| reg_id(fp_type, size, i)));
+        ids.push(reg_id(fp_type, KVM_REG_SIZE_U32, NR_FP_REGS));
+    }
+    ids
+}
+
+/// Returns timer registers as pairs of register id and value, in the order they must
+/// be written: time, compare and state.
+///
+/// # Arguments
+///
+/// * `timer_regs` - Timer registers of the vcpu.
+pub fn timer_reg_values(timer_regs: &kvm_riscv_timer) -> Vec<(u64, u64)> {
+    vec![
+        (RISCVTimerRegs::TIME.into(), timer_regs.time),
+        (RISCVTimerRegs::COMPARE.into(), timer_regs.compare),
+        (RISCVTimerRegs::STATE.into(), timer_regs.state),
+    ]
+}
+
+/// Returns the values of registers `ids` as pairs of register id and value.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `ids` - Ids of registers, see `snapshot_reg_ids`.
+pub fn get_regs(vcpu_fd: &VcpuFd, ids: &[u64]) -> Result<Vec<(u64, u64)>> {
+    ids.iter()
+        .map(|id| Ok((*id, vcpu_fd.get_one_reg(*id)? as u64)))
+        .collect()
+}
+
+/// Sets registers given as pairs of register id and value, in their order.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `regs` - Registers returned by `get_regs` or `timer_reg_values`.
+pub fn set_regs(vcpu_fd: &VcpuFd, regs: &[(u64, u64)]) -> Result<()> {
+    for (id, value) in regs {
+        vcpu_fd.set_one_reg(*id, u128::from(*value))?;
+    }
+    Ok(())
+}
+
 /// RISCV cpu time register.
 /// See: https://elixir.bootlin.com/linux/v6.0/source/arch/riscv/include/uapi/asm/kvm.h#L78
 pub enum RISCVTimerRegs {
@@ -271,6 +355,59 @@ impl Into<u64> for RISCVTimerRegs {
     }
 }
 
+/// SBI extension registers, which are not in kvm-bindings yet.
+/// See: https://elixir.bootlin.com/linux/v6.8/source/arch/riscv/include/uapi/asm/kvm.h#L257
+const KVM_REG_RISCV_SBI_EXT: u64 = 0x08 << 24;
+const KVM_REG_RISCV_SBI_SINGLE: u64 = 0;
+/// Debug console extension, disabled by KVM unless userspace enables it.
+pub const KVM_RISCV_SBI_EXT_DBCN: u64 = 9;
+/// Steal-time accounting extension, enabled by KVM which handles it in kernel.
+pub const KVM_RISCV_SBI_EXT_STA: u64 = 10;
+/// State of SBI extensions kept by KVM.
+/// See: https://elixir.bootlin.com/linux/v6.8/source/arch/riscv/include/uapi/asm/kvm.h#L272
+const KVM_REG_RISCV_SBI_STATE: u64 = 0x09 << 24;
+const KVM_REG_RISCV_SBI_STA: u64 = 0;
+const KVM_REG_RISCV_SBI_STA_SHMEM_LO: u64 = 0;
+const KVM_REG_RISCV_SBI_STA_SHMEM_HI: u64 = 1;
+
+/// Enables or disables SBI extension `ext_id` of the vcpu, whose calls are forwarded
+/// to userspace or handled by KVM once enabled.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `ext_id` - id of the extension defined by KVM, e.g. `KVM_RISCV_SBI_EXT_DBCN`.
+/// * `enabled` - Enable or disable the extension.
+pub fn set_sbi_ext(vcpu_fd: &VcpuFd, ext_id: u64, enabled: bool) -> Result<()> {
+    let reg_id = KVM_REG_RISCV as u64
+        | KVM_REG_SIZE_U64 as u64
+        | KVM_REG_RISCV_SBI_EXT
+        | KVM_REG_RISCV_SBI_SINGLE
+        | ext_id;
+    vcpu_fd.set_one_reg(reg_id, u128::from(enabled))
+}
+
+/// Disables the shared memory of steal time set by guest to KVM, which KVM doesn't
+/// drop when vcpu registers are reset.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+pub fn reset_sta_shmem(vcpu_fd: &VcpuFd) -> Result<()> {
+    for index in [
+        KVM_REG_RISCV_SBI_STA_SHMEM_LO,
+        KVM_REG_RISCV_SBI_STA_SHMEM_HI,
+    ] {
+        let reg_id = KVM_REG_RISCV as u64
+            | KVM_REG_SIZE_U64 as u64
+            | KVM_REG_RISCV_SBI_STATE
+            | KVM_REG_RISCV_SBI_STA
+            | index;
+        vcpu_fd.set_one_reg(reg_id, u128::from(u64::MAX))?;
+    }
+    Ok(())
+}
+
 /// Returns the vcpu's current `config_register`.
 ///
 /// The register state is gotten from `KVM_GET_ONE_REG` api in KVM.
@@ -286,6 +423,19 @@ pub fn get_config_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_config> {
     Ok(config_regs)
 }
 
+/// Sets the vcpu's current `config_register`, which KVM only allows before the vcpu
+/// runs. Extensions which KVM can't enable or disable are silently kept as they are.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `config_regs` - kvm_riscv_config state to be written.
+pub fn set_config_regs(vcpu_fd: &VcpuFd, config_regs: kvm_riscv_config) -> Result<()> {
+    vcpu_fd.set_one_reg(RISCVConfigRegs::ISA.into(), config_regs.isa as u128)?;
+
+    Ok(())
+}
+
 /// Sets the vcpu's current "core_register"
 ///
 /// The register state is gotten from `KVM_SET_ONE_REG` api in KVM.
@@ -298,10 +448,32 @@ pub fn set_core_regs(vcpu_fd: &VcpuFd, core_regs: kvm_riscv_core) -> Result<()>
     vcpu_fd.set_one_reg(RISCVCoreRegs::PC.into(), core_regs.regs.pc as u128)?;
     vcpu_fd.set_one_reg(RISCVCoreRegs::A0.into(), core_regs.regs.a0 as u128)?;
     vcpu_fd.set_one_reg(RISCVCoreRegs::A1.into(), core_regs.regs.a1 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::A2.into(), core_regs.regs.a2 as u128)?;
 
     Ok(())
 }
 
+/// Returns the value of a single core register, see `core_reg_id`.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `index` - index of the register in `user_regs_struct`.
+pub fn get_core_reg(vcpu_fd: &VcpuFd, index: usize) -> Result<u64> {
+    Ok(vcpu_fd.get_one_reg(core_reg_id(index))? as u64)
+}
+
+/// Sets the value of a single core register, see `core_reg_id`.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `index` - index of the register in `user_regs_struct`.
+/// * `value` - value to be written.
+pub fn set_core_reg(vcpu_fd: &VcpuFd, index: usize, value: u64) -> Result<()> {
+    vcpu_fd.set_one_reg(core_reg_id(index), value as u128)
+}
+
 /// Returns the vcpu's current `timer_register`.
 ///
 /// The register state is gotten from `KVM_GET_ONE_REG` api in KVM.
@@ -322,3 +494,17 @@ pub fn get_timer_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_timer> {
 
     Ok(timer_regs)
 }
+
+/// Sets the vcpu's guest time, which moves the time base of vcpu timer.
+///
+/// The register state is written by `KVM_SET_ONE_REG` api in KVM.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `timer_regs` - kvm_riscv_timer state to be written.
+pub fn set_timer_regs(vcpu_fd: &VcpuFd, timer_regs: kvm_riscv_timer) -> Result<()> {
+    vcpu_fd.set_one_reg(RISCVTimerRegs::TIME.into(), timer_regs.time as u128)?;
+
+    Ok(())
+}
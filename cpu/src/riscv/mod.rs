@@ -13,17 +13,33 @@
 
 pub mod caps;
 mod core_regs;
+mod sbi;
+mod steal_time;
 
 pub use self::caps::RISCVCPUCaps;
+pub use self::sbi::{
+    is_kernel_panic, is_system_failure, sbi_console_error, sbi_debug_console, sbi_steal_time,
+    sbi_system_reset, shutdown_exit_code, SbiConsole, SbiDebugConsoleCall, SbiSystemReset,
+    SBI_ERR_INVALID_ADDRESS, SBI_ERR_NOT_SUPPORTED, SBI_EXT_0_1_CONSOLE_PUTCHAR, SBI_EXT_DBCN,
+    SBI_EXT_STA, SBI_STA_SHMEM_SIZE,
+};
+pub use self::steal_time::StealTime;
 use kvm_bindings::{
     kvm_mp_state, kvm_riscv_config, kvm_riscv_core, kvm_riscv_timer, KVM_MP_STATE_RUNNABLE,
     KVM_MP_STATE_STOPPED,
 };
 use kvm_ioctls::VcpuFd;
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
-use self::core_regs::{get_config_regs, get_timer_regs, set_core_regs};
-use anyhow::{Context, Result};
+use self::core_regs::{
+    get_config_regs, get_core_reg, get_regs, get_timer_regs, set_config_regs, set_core_reg,
+    set_core_regs, set_regs, set_sbi_ext, set_timer_regs, snapshot_reg_ids, timer_reg_values,
+    KVM_RISCV_SBI_EXT_DBCN,
+};
+use anyhow::{bail, Context, Result};
+use log::warn;
+use machine_manager::config::CpuConfig;
 
 use migration::{
     DeviceStateDesc, FieldDesc,
@@ -31,11 +47,68 @@ use migration::{
 use migration_derive::{ByteCode, Desc};
 use util::byte_code::ByteCode;
 
+/// Number of registers in gdb's riscv core feature: x0-x31 followed by pc.
+pub const GDB_NUM_CORE_REGS: usize = 33;
+/// Number of pc register in gdb's riscv core feature.
+const GDB_REG_PC: usize = 32;
+
 /// RISCV CPU booting configure information
 #[derive(Default, Copy, Clone, Debug)]
 pub struct RISCVCPUBootConfig {
     pub fdt_addr: u64,
     pub boot_pc: u64,
+    /// Address of OpenSBI fw_dynamic info if booting from firmware.
+    pub fw_dynamic_addr: Option<u64>,
+}
+
+/// ISA extensions and SBI features of vcpus given by `-cpu`.
+#[derive(Clone, Debug, Default)]
+pub struct RISCVCPUFeatures {
+    /// Single-letter extensions turned on or off, others are left as KVM provides.
+    pub isa_ext: BTreeMap<char, bool>,
+    /// Report steal time to guest by SBI STA extension.
+    pub steal_time: bool,
+}
+
+impl From<&CpuConfig> for RISCVCPUFeatures {
+    fn from(config: &CpuConfig) -> Self {
+        RISCVCPUFeatures {
+            isa_ext: config.isa_ext.clone(),
+            steal_time: config.steal_time,
+        }
+    }
+}
+
+impl RISCVCPUFeatures {
+    /// Bit of single-letter extension `ext` in the ISA config register.
+    pub fn isa_bit(ext: char) -> u64 {
+        1 << (ext as u8 - b'a')
+    }
+
+    /// Apply the extensions to ISA config register `isa` given by KVM.
+    fn apply(&self, isa: u64) -> u64 {
+        self.isa_ext.iter().fold(isa, |isa, (ext, enabled)| {
+            if *enabled {
+                isa | Self::isa_bit(*ext)
+            } else {
+                isa & !Self::isa_bit(*ext)
+            }
+        })
+    }
+
+    /// Check the extensions against ISA config register `isa` accepted by KVM.
+    fn check(&self, isa: u64) -> Result<()> {
+        for (ext, enabled) in self.isa_ext.iter() {
+            let present = isa & Self::isa_bit(*ext) != 0;
+            if *enabled && !present {
+                bail!("ISA extension {} is not supported by host KVM", ext);
+            }
+            if !*enabled && present {
+                bail!("ISA extension {} can't be disabled by host KVM", ext);
+            }
+        }
+        Ok(())
+    }
 }
 
 #[allow(dead_code)]
@@ -56,6 +129,15 @@ impl RISCVCPUTopology {
     }
 }
 
+/// Registers of a vcpu kept in snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RISCVCPUSnapshot {
+    /// Multiprocessing state, harts not started by guest yet are stopped.
+    pub mp_state: u32,
+    /// Pairs of register id of `KVM_GET_ONE_REG` and value.
+    pub regs: Vec<(u64, u64)>,
+}
+
 /// RISCV CPU architect information
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
@@ -111,13 +193,33 @@ impl RISCVCPUState {
     ///
     /// * `vcpu_fd` - Vcpu file descriptor in kvm.
     /// * `boot_config` - Boot message from boot_loader.
+    /// * `features` - ISA extensions given by `-cpu`.
     pub fn set_boot_config(
         &mut self,
         vcpu_fd: &Arc<VcpuFd>,
         boot_config: &RISCVCPUBootConfig,
+        features: &RISCVCPUFeatures,
     ) -> Result<()> {
         self.config_regs = get_config_regs(vcpu_fd)?;
+        if !features.isa_ext.is_empty() {
+            self.config_regs.isa = features.apply(self.config_regs.isa);
+            set_config_regs(vcpu_fd, self.config_regs).with_context(|| {
+                format!("Failed to set ISA extensions for CPU {}", self.apic_id)
+            })?;
+            // KVM ignores extensions which it can't change, check what's in effect.
+            self.config_regs = get_config_regs(vcpu_fd)?;
+            features
+                .check(self.config_regs.isa)
+                .with_context(|| format!("Invalid -cpu for CPU {}", self.apic_id))?;
+        }
         self.timer_regs = get_timer_regs(vcpu_fd)?;
+        // Older kernels have no DBCN, guest falls back to legacy console then.
+        if let Err(e) = set_sbi_ext(vcpu_fd, KVM_RISCV_SBI_EXT_DBCN, true) {
+            warn!(
+                "Failed to enable SBI debug console for CPU {}: {:?}",
+                self.apic_id, e
+            );
+        }
 
         self.set_core_reg(boot_config);
 
@@ -147,6 +249,35 @@ impl RISCVCPUState {
         Ok(())
     }
 
+    /// Save the guest time when vcpu is paused.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    pub fn save_timer(&mut self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
+        self.timer_regs = get_timer_regs(vcpu_fd)
+            .with_context(|| format!("Failed to get timer register for CPU {}", self.apic_id))?;
+        Ok(())
+    }
+
+    /// Restore the guest time saved by `save_timer`, so that guest time doesn't
+    /// jump over the pause.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    pub fn restore_timer(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
+        set_timer_regs(vcpu_fd, self.timer_regs)
+            .with_context(|| format!("Failed to set timer register for CPU {}", self.apic_id))?;
+        Ok(())
+    }
+
+    /// Set the guest time to be restored on resume, for vcpus created while
+    /// vm is paused.
+    pub fn set_saved_timer(&mut self, timer_regs: kvm_riscv_timer) {
+        self.timer_regs = timer_regs;
+    }
+
     /// Get config_regs value.
     pub fn config_regs(&self) -> kvm_riscv_config {
         self.config_regs
@@ -171,11 +302,229 @@ impl RISCVCPUState {
         if self.apic_id == 0 {
             self.core_regs.regs.a1 = boot_config.fdt_addr;
             self.core_regs.regs.pc = boot_config.boot_pc;
+            // Firmware entry of OpenSBI fw_dynamic takes boot info in a2.
+            if let Some(fw_dynamic_addr) = boot_config.fw_dynamic_addr {
+                self.core_regs.regs.a2 = fw_dynamic_addr;
+            }
+        }
+    }
+
+    /// Read a register numbered as in gdb's riscv core feature.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    /// * `reg` - Register number, x0-x31 are 0-31 and pc is 32.
+    pub fn get_gdb_reg(&self, vcpu_fd: &Arc<VcpuFd>, reg: usize) -> Result<u64> {
+        let value = match reg {
+            // x0 is hardwired to zero.
+            0 => 0,
+            1..=31 => get_core_reg(vcpu_fd, reg)?,
+            GDB_REG_PC => get_core_reg(vcpu_fd, 0)?,
+            _ => bail!("Invalid register {} for CPU {}", reg, self.apic_id),
+        };
+        Ok(value)
+    }
+
+    /// Write a register numbered as in gdb's riscv core feature.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    /// * `reg` - Register number, x0-x31 are 0-31 and pc is 32.
+    /// * `value` - Value to be written, writes to x0 are ignored.
+    pub fn set_gdb_reg(&self, vcpu_fd: &Arc<VcpuFd>, reg: usize, value: u64) -> Result<()> {
+        match reg {
+            0 => {}
+            1..=31 => set_core_reg(vcpu_fd, reg, value)?,
+            GDB_REG_PC => set_core_reg(vcpu_fd, 0, value)?,
+            _ => bail!("Invalid register {} for CPU {}", reg, self.apic_id),
         }
+        Ok(())
+    }
+
+    /// Read pc and x1-x31 laid out as `user_regs_struct`, which is the register set
+    /// of ELF core notes.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    pub fn get_user_regs(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<[u64; 32]> {
+        let mut regs = [0_u64; 32];
+        for (index, reg) in regs.iter_mut().enumerate() {
+            *reg = get_core_reg(vcpu_fd, index)
+                .with_context(|| format!("Failed to get registers for CPU {}", self.apic_id))?;
+        }
+        Ok(regs)
+    }
+
+    /// Read registers kept in snapshot. Timer registers are the ones saved when vcpu
+    /// was paused, so that guest time doesn't include the time spent in pause.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    pub fn save_regs(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<RISCVCPUSnapshot> {
+        let isa = self.config_regs.isa;
+        let ids = snapshot_reg_ids(
+            isa & RISCVCPUFeatures::isa_bit('f') != 0,
+            isa & RISCVCPUFeatures::isa_bit('d') != 0,
+        );
+        let mut regs = get_regs(vcpu_fd, &ids)
+            .with_context(|| format!("Failed to get registers for CPU {}", self.apic_id))?;
+        regs.extend(timer_reg_values(&self.timer_regs));
+        let mp_state = vcpu_fd
+            .get_mp_state()
+            .with_context(|| format!("Failed to get mpstate for CPU {}", self.apic_id))?;
+        Ok(RISCVCPUSnapshot {
+            mp_state: mp_state.mp_state,
+            regs,
+        })
+    }
+
+    /// Write registers read by `save_regs` to the paused vcpu. The restored guest time
+    /// is kept to be set again on resume.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    /// * `snapshot` - Registers read by `save_regs`.
+    pub fn restore_regs(
+        &mut self,
+        vcpu_fd: &Arc<VcpuFd>,
+        snapshot: &RISCVCPUSnapshot,
+    ) -> Result<()> {
+        set_regs(vcpu_fd, &snapshot.regs)
+            .with_context(|| format!("Failed to set registers for CPU {}", self.apic_id))?;
+        vcpu_fd
+            .set_mp_state(kvm_mp_state {
+                mp_state: snapshot.mp_state,
+            })
+            .with_context(|| format!("Failed to set mpstate for CPU {}", self.apic_id))?;
+        self.save_timer(vcpu_fd)
     }
 
     /// Get the length of registers.
     pub fn get_xlen(&self) -> u64 {
         self.xlen
     }
+
+    /// Get the ISA extensions of vcpu, bit `n` stands for the `n`th letter.
+    pub fn get_isa(&self) -> u64 {
+        self.config_regs.isa
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::core_regs::{core_reg_id, RISCVCoreRegs};
+    use super::*;
+
+    #[test]
+    fn test_core_reg_id() {
+        let pc: u64 = RISCVCoreRegs::PC.into();
+        let ra: u64 = RISCVCoreRegs::RA.into();
+        let a0: u64 = RISCVCoreRegs::A0.into();
+        let t6: u64 = RISCVCoreRegs::T6.into();
+        assert_eq!(core_reg_id(0), pc);
+        assert_eq!(core_reg_id(1), ra);
+        assert_eq!(core_reg_id(10), a0);
+        assert_eq!(core_reg_id(31), t6);
+    }
+
+    #[test]
+    fn test_snapshot_reg_ids() {
+        use kvm_bindings::{KVM_REG_SIZE_MASK, KVM_REG_SIZE_U32, KVM_REG_SIZE_U64};
+
+        // pc, x1-x31, mode and CSRs.
+        let ids = snapshot_reg_ids(false, false);
+        assert_eq!(ids.len(), 43);
+        assert_eq!(ids[0], core_reg_id(0));
+        assert_eq!(ids[32], core_reg_id(32));
+
+        // f0-f31 are as wide as the widest FP extension, fcsr is always 32 bits.
+        let ids = snapshot_reg_ids(true, true);
+        assert_eq!(ids.len(), 76);
+        assert_eq!(ids[43] & KVM_REG_SIZE_MASK, KVM_REG_SIZE_U64);
+        assert_eq!(ids[75] & KVM_REG_SIZE_MASK, KVM_REG_SIZE_U32);
+        let ids = snapshot_reg_ids(true, false);
+        assert_eq!(ids.len(), 76);
+        assert_eq!(ids[43] & KVM_REG_SIZE_MASK, KVM_REG_SIZE_U32);
+
+        // Timer registers are written in order: time, compare and state.
+        let timer_regs = kvm_riscv_timer {
+            frequency: 10_000_000,
+            time: 100,
+            compare: 200,
+            state: 1,
+        };
+        let values: Vec<u64> = timer_reg_values(&timer_regs)
+            .iter()
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(values, vec![100, 200, 1]);
+    }
+
+    #[test]
+    fn test_isa_features() {
+        // rv64imafdc
+        let isa = "imafdc"
+            .chars()
+            .fold(0, |isa, ext| isa | RISCVCPUFeatures::isa_bit(ext));
+        let mut features = RISCVCPUFeatures::default();
+        assert_eq!(features.apply(isa), isa);
+
+        features.isa_ext.insert('c', false);
+        features.isa_ext.insert('v', true);
+        let applied = features.apply(isa);
+        assert_eq!(applied & RISCVCPUFeatures::isa_bit('c'), 0);
+        assert_ne!(applied & RISCVCPUFeatures::isa_bit('v'), 0);
+        assert!(features.check(applied).is_ok());
+
+        // KVM keeps c and doesn't provide v.
+        let err = features.check(isa).unwrap_err().to_string();
+        assert!(err.contains("ISA extension c"));
+        features.isa_ext.remove(&'c');
+        let err = features.check(isa).unwrap_err().to_string();
+        assert!(err.contains("ISA extension v is not supported"));
+    }
+
+    #[test]
+    fn test_set_core_reg() {
+        // Boot kernel directly.
+        let boot_config = RISCVCPUBootConfig {
+            fdt_addr: 0x8260_0000,
+            boot_pc: 0x8020_0000,
+            fw_dynamic_addr: None,
+        };
+        let mut cpu_state = RISCVCPUState::new(0);
+        cpu_state.set_core_reg(&boot_config);
+        let regs = cpu_state.core_regs().regs;
+        assert_eq!(regs.pc, 0x8020_0000);
+        assert_eq!(regs.a0, 0);
+        assert_eq!(regs.a1, 0x8260_0000);
+        assert_eq!(regs.a2, 0);
+
+        // Boot firmware at memory start.
+        let boot_config = RISCVCPUBootConfig {
+            boot_pc: 0x8000_0000,
+            fw_dynamic_addr: Some(0x801f_f000),
+            ..boot_config
+        };
+        let mut cpu_state = RISCVCPUState::new(0);
+        cpu_state.set_core_reg(&boot_config);
+        let regs = cpu_state.core_regs().regs;
+        assert_eq!(regs.pc, 0x8000_0000);
+        assert_eq!(regs.a0, 0);
+        assert_eq!(regs.a1, 0x8260_0000);
+        assert_eq!(regs.a2, 0x801f_f000);
+
+        // Secondary harts only get their hartid.
+        let mut cpu_state = RISCVCPUState::new(1);
+        cpu_state.set_core_reg(&boot_config);
+        let regs = cpu_state.core_regs().regs;
+        assert_eq!(regs.pc, 0);
+        assert_eq!(regs.a0, 1);
+        assert_eq!(regs.a2, 0);
+    }
 }
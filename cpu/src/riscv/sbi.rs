@@ -0,0 +1,343 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use machine_manager::machine::SbiConsoleError;
+use machine_manager::signal_handler::VM_EXIT_GUEST_FAILURE;
+
+/// Legacy console putchar extension, whose `a0` is the byte to print.
+pub const SBI_EXT_0_1_CONSOLE_PUTCHAR: u64 = 0x1;
+
+/// Debug Console Extension, "DBCN".
+pub const SBI_EXT_DBCN: u64 = 0x4442_434E;
+const SBI_EXT_DBCN_CONSOLE_WRITE: u64 = 0;
+const SBI_EXT_DBCN_CONSOLE_READ: u64 = 1;
+const SBI_EXT_DBCN_CONSOLE_WRITE_BYTE: u64 = 2;
+
+/// Steal-time Accounting Extension, "STA".
+pub const SBI_EXT_STA: u64 = 0x0053_5441;
+const SBI_EXT_STA_STEAL_TIME_SET_SHMEM: u64 = 0;
+/// Size and alignment of the shared memory of STA.
+pub const SBI_STA_SHMEM_SIZE: u64 = 64;
+
+/// System Reset Extension, "SRST".
+const SBI_EXT_SRST: u64 = 0x5352_5354;
+const SBI_EXT_SRST_RESET: u64 = 0;
+
+const SBI_SRST_RESET_TYPE_SHUTDOWN: u64 = 0;
+const SBI_SRST_RESET_TYPE_COLD_REBOOT: u64 = 1;
+const SBI_SRST_RESET_TYPE_WARM_REBOOT: u64 = 2;
+/// Start of vendor or platform specific reset types.
+const SBI_SRST_RESET_TYPE_VENDOR: u64 = 0xf000_0000;
+
+const SBI_SRST_RESET_REASON_NONE: u64 = 0;
+const SBI_SRST_RESET_REASON_SYSFAIL: u64 = 1;
+/// Start of SBI implementation specific reset reasons, followed by vendor specific ones.
+const SBI_SRST_RESET_REASON_IMPL: u64 = 0xe000_0000;
+
+/// Lines of SBI console longer than it are split.
+const SBI_CONSOLE_LINE_MAX: usize = 1024;
+/// Linux prints it to all consoles when kernel panics.
+const KERNEL_PANIC_MARKER: &str = "Kernel panic - not syncing";
+
+pub const SBI_ERR_FAILED: i64 = -1;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+
+/// System reset requested by guest through SBI.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SbiSystemReset {
+    /// Power off, with the reason given by guest.
+    Shutdown(u64),
+    /// Cold or warm reboot, both reset the whole machine.
+    Reboot,
+}
+
+/// Decode an SBI call forwarded to userspace by KVM.
+///
+/// Only system reset of SRST is handled, other calls fail with the SBI error returned
+/// to guest in `a0`.
+pub fn sbi_system_reset(
+    extension_id: u64,
+    function_id: u64,
+    args: &[u64],
+) -> std::result::Result<SbiSystemReset, i64> {
+    if extension_id != SBI_EXT_SRST || function_id != SBI_EXT_SRST_RESET {
+        return Err(SBI_ERR_NOT_SUPPORTED);
+    }
+
+    let reason = args[1] & u64::from(u32::MAX);
+    if reason > SBI_SRST_RESET_REASON_SYSFAIL && reason < SBI_SRST_RESET_REASON_IMPL {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    match args[0] & u64::from(u32::MAX) {
+        SBI_SRST_RESET_TYPE_SHUTDOWN => Ok(SbiSystemReset::Shutdown(reason)),
+        SBI_SRST_RESET_TYPE_COLD_REBOOT | SBI_SRST_RESET_TYPE_WARM_REBOOT => {
+            Ok(SbiSystemReset::Reboot)
+        }
+        reset_type if reset_type >= SBI_SRST_RESET_TYPE_VENDOR => Err(SBI_ERR_NOT_SUPPORTED),
+        _ => Err(SBI_ERR_INVALID_PARAM),
+    }
+}
+
+/// Debug console call made by guest through DBCN.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SbiDebugConsoleCall {
+    /// Write `len` bytes at guest physical address `addr`.
+    Write { addr: u64, len: u64 },
+    /// Read at most `len` bytes to guest physical address `addr`.
+    Read { addr: u64, len: u64 },
+    /// Write one byte.
+    WriteByte(u8),
+}
+
+/// Decode a DBCN call, whose buffer address is split into `a1` and `a2`.
+pub fn sbi_debug_console(
+    function_id: u64,
+    args: &[u64],
+) -> std::result::Result<SbiDebugConsoleCall, i64> {
+    match function_id {
+        SBI_EXT_DBCN_CONSOLE_WRITE | SBI_EXT_DBCN_CONSOLE_READ => {
+            // Physical address of rv64 never exceeds 64 bits.
+            if args[2] != 0 {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+            let (addr, len) = (args[1], args[0]);
+            if function_id == SBI_EXT_DBCN_CONSOLE_WRITE {
+                Ok(SbiDebugConsoleCall::Write { addr, len })
+            } else {
+                Ok(SbiDebugConsoleCall::Read { addr, len })
+            }
+        }
+        SBI_EXT_DBCN_CONSOLE_WRITE_BYTE => Ok(SbiDebugConsoleCall::WriteByte(args[0] as u8)),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Decode a STA call, returns the guest physical address of the shared memory, or
+/// none if guest disables steal time by all-ones address.
+pub fn sbi_steal_time(function_id: u64, args: &[u64]) -> std::result::Result<Option<u64>, i64> {
+    if function_id != SBI_EXT_STA_STEAL_TIME_SET_SHMEM {
+        return Err(SBI_ERR_NOT_SUPPORTED);
+    }
+    let (addr_lo, addr_hi, flags) = (args[0], args[1], args[2]);
+    if flags != 0 {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    if addr_lo == u64::MAX && addr_hi == u64::MAX {
+        return Ok(None);
+    }
+    if addr_lo % SBI_STA_SHMEM_SIZE != 0 {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    // Physical address of rv64 never exceeds 64 bits.
+    if addr_hi != 0 {
+        return Err(SBI_ERR_INVALID_ADDRESS);
+    }
+    Ok(Some(addr_lo))
+}
+
+/// SBI error returned to guest for failed debug console call.
+pub fn sbi_console_error(error: SbiConsoleError) -> i64 {
+    match error {
+        SbiConsoleError::NotSupported => SBI_ERR_NOT_SUPPORTED,
+        SbiConsoleError::InvalidParam => SBI_ERR_INVALID_PARAM,
+        SbiConsoleError::Failed => SBI_ERR_FAILED,
+    }
+}
+
+/// Whether guest shuts down by SRST `reason` because of system failure, e.g. panic.
+pub fn is_system_failure(reason: u64) -> bool {
+    reason == SBI_SRST_RESET_REASON_SYSFAIL
+}
+
+/// Line buffer of SBI console, by which guest kernel panic is detected.
+#[derive(Default)]
+pub struct SbiConsole {
+    line: Vec<u8>,
+}
+
+impl SbiConsole {
+    /// Buffer `ch` printed by guest, return the line once it's complete.
+    pub fn putchar(&mut self, ch: u8) -> Option<String> {
+        match ch {
+            b'\n' => {}
+            b'\r' => return None,
+            _ => {
+                self.line.push(ch);
+                if self.line.len() < SBI_CONSOLE_LINE_MAX {
+                    return None;
+                }
+            }
+        }
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        Some(line)
+    }
+}
+
+/// Whether `line` printed by guest is the kernel panic message.
+pub fn is_kernel_panic(line: &str) -> bool {
+    line.contains(KERNEL_PANIC_MARKER)
+}
+
+/// Process exit code for guest shutdown with SRST `reason`.
+pub fn shutdown_exit_code(reason: u64) -> i32 {
+    match reason {
+        SBI_SRST_RESET_REASON_SYSFAIL => VM_EXIT_GUEST_FAILURE,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sbi_system_reset() {
+        let reset = |args: &[u64]| sbi_system_reset(SBI_EXT_SRST, SBI_EXT_SRST_RESET, args);
+
+        assert_eq!(
+            reset(&[SBI_SRST_RESET_TYPE_SHUTDOWN, SBI_SRST_RESET_REASON_NONE]),
+            Ok(SbiSystemReset::Shutdown(SBI_SRST_RESET_REASON_NONE))
+        );
+        assert_eq!(
+            reset(&[SBI_SRST_RESET_TYPE_SHUTDOWN, SBI_SRST_RESET_REASON_SYSFAIL]),
+            Ok(SbiSystemReset::Shutdown(SBI_SRST_RESET_REASON_SYSFAIL))
+        );
+        assert_eq!(
+            reset(&[SBI_SRST_RESET_TYPE_COLD_REBOOT, 0]),
+            Ok(SbiSystemReset::Reboot)
+        );
+        assert_eq!(
+            reset(&[SBI_SRST_RESET_TYPE_WARM_REBOOT, 0]),
+            Ok(SbiSystemReset::Reboot)
+        );
+        // Upper 32 bits are ignored.
+        assert_eq!(
+            reset(&[1 << 32 | SBI_SRST_RESET_TYPE_SHUTDOWN, 1 << 32]),
+            Ok(SbiSystemReset::Shutdown(SBI_SRST_RESET_REASON_NONE))
+        );
+        // Implementation specific reasons.
+        assert_eq!(
+            reset(&[SBI_SRST_RESET_TYPE_SHUTDOWN, SBI_SRST_RESET_REASON_IMPL]),
+            Ok(SbiSystemReset::Shutdown(SBI_SRST_RESET_REASON_IMPL))
+        );
+
+        // Reserved reset type and reason.
+        assert_eq!(reset(&[3, 0]), Err(SBI_ERR_INVALID_PARAM));
+        assert_eq!(reset(&[0, 2]), Err(SBI_ERR_INVALID_PARAM));
+        assert_eq!(
+            reset(&[SBI_SRST_RESET_TYPE_VENDOR, 0]),
+            Err(SBI_ERR_NOT_SUPPORTED)
+        );
+
+        // Other extensions, e.g. legacy console putchar.
+        assert_eq!(
+            sbi_system_reset(0x1, 0, &[0x41, 0]),
+            Err(SBI_ERR_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            sbi_system_reset(SBI_EXT_SRST, 1, &[0, 0]),
+            Err(SBI_ERR_NOT_SUPPORTED)
+        );
+    }
+
+    #[test]
+    fn test_sbi_debug_console() {
+        assert_eq!(
+            sbi_debug_console(SBI_EXT_DBCN_CONSOLE_WRITE, &[16, 0x8000_1000, 0]),
+            Ok(SbiDebugConsoleCall::Write {
+                addr: 0x8000_1000,
+                len: 16
+            })
+        );
+        assert_eq!(
+            sbi_debug_console(SBI_EXT_DBCN_CONSOLE_READ, &[8, 0x8000_2000, 0]),
+            Ok(SbiDebugConsoleCall::Read {
+                addr: 0x8000_2000,
+                len: 8
+            })
+        );
+        // Only the low 8 bits are written.
+        assert_eq!(
+            sbi_debug_console(SBI_EXT_DBCN_CONSOLE_WRITE_BYTE, &[0x141, 0, 0]),
+            Ok(SbiDebugConsoleCall::WriteByte(0x41))
+        );
+        assert_eq!(
+            sbi_debug_console(SBI_EXT_DBCN_CONSOLE_WRITE, &[16, 0, 1]),
+            Err(SBI_ERR_INVALID_PARAM)
+        );
+        assert_eq!(sbi_debug_console(3, &[0, 0, 0]), Err(SBI_ERR_NOT_SUPPORTED));
+
+        assert_eq!(
+            sbi_console_error(SbiConsoleError::NotSupported),
+            SBI_ERR_NOT_SUPPORTED
+        );
+        assert_eq!(
+            sbi_console_error(SbiConsoleError::InvalidParam),
+            SBI_ERR_INVALID_PARAM
+        );
+        assert_eq!(sbi_console_error(SbiConsoleError::Failed), SBI_ERR_FAILED);
+    }
+
+    #[test]
+    fn test_sbi_steal_time() {
+        let set_shmem = |args: &[u64]| sbi_steal_time(SBI_EXT_STA_STEAL_TIME_SET_SHMEM, args);
+
+        assert_eq!(set_shmem(&[0x8000_1040, 0, 0]), Ok(Some(0x8000_1040)));
+        assert_eq!(set_shmem(&[u64::MAX, u64::MAX, 0]), Ok(None));
+        // Misaligned, above 64 bits, and reserved flags.
+        assert_eq!(set_shmem(&[0x8000_1020, 0, 0]), Err(SBI_ERR_INVALID_PARAM));
+        assert_eq!(
+            set_shmem(&[0x8000_1040, 1, 0]),
+            Err(SBI_ERR_INVALID_ADDRESS)
+        );
+        assert_eq!(set_shmem(&[0x8000_1040, 0, 1]), Err(SBI_ERR_INVALID_PARAM));
+        assert_eq!(sbi_steal_time(1, &[0, 0, 0]), Err(SBI_ERR_NOT_SUPPORTED));
+    }
+
+    #[test]
+    fn test_shutdown_exit_code() {
+        assert_eq!(shutdown_exit_code(SBI_SRST_RESET_REASON_NONE), 0);
+        assert_eq!(
+            shutdown_exit_code(SBI_SRST_RESET_REASON_SYSFAIL),
+            VM_EXIT_GUEST_FAILURE
+        );
+        assert_eq!(shutdown_exit_code(SBI_SRST_RESET_REASON_IMPL), 0);
+    }
+
+    #[test]
+    fn test_sbi_console() {
+        let mut console = SbiConsole::default();
+        let mut lines = Vec::new();
+        for ch in b"[    1.0] Kernel panic - not syncing: VFS\r\nok\n" {
+            if let Some(line) = console.putchar(*ch) {
+                lines.push(line);
+            }
+        }
+        assert_eq!(lines, ["[    1.0] Kernel panic - not syncing: VFS", "ok"]);
+        assert!(is_kernel_panic(&lines[0]));
+        assert!(!is_kernel_panic(&lines[1]));
+
+        // Long line is split.
+        for _ in 0..SBI_CONSOLE_LINE_MAX - 1 {
+            assert!(console.putchar(b'a').is_none());
+        }
+        assert_eq!(console.putchar(b'a').unwrap().len(), SBI_CONSOLE_LINE_MAX);
+        assert_eq!(console.putchar(b'\n').unwrap(), "");
+
+        assert!(is_system_failure(SBI_SRST_RESET_REASON_SYSFAIL));
+        assert!(!is_system_failure(SBI_SRST_RESET_REASON_NONE));
+    }
+}
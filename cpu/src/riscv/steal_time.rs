@@ -0,0 +1,200 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+use kvm_ioctls::VcpuFd;
+use log::{error, info};
+
+use super::core_regs::{reset_sta_shmem, set_sbi_ext, KVM_RISCV_SBI_EXT_STA};
+use crate::stats::thread_run_delay_ns;
+
+/// Steal time is updated at most once an interval, as reading host scheduling stats
+/// costs syscalls on every vcpu entry otherwise.
+const STEAL_TIME_UPDATE_INTERVAL: Duration = Duration::from_millis(10);
+
+// Fields of the shared memory of STA, which is little-endian.
+const STA_SEQUENCE: u64 = 0;
+const STA_FLAGS: u64 = 4;
+const STA_STEAL: u64 = 8;
+const STA_PREEMPTED: u64 = 16;
+
+/// Steal time of one vcpu reported to guest by SBI STA extension.
+///
+/// KVM which supports STA handles it in kernel. Otherwise calls forwarded to
+/// userspace are handled here, and the shared memory is updated before vcpu enters
+/// guest, by the time the vcpu thread waits on host run queues.
+#[derive(Default)]
+pub struct StealTime {
+    /// Turned on by `-cpu steal-time=on`.
+    enabled: bool,
+    /// STA is handled by KVM.
+    in_kvm: bool,
+    /// Host address of the shared memory set by guest.
+    shmem: Option<u64>,
+    /// Run delay of the vcpu thread when steal time was last updated.
+    last_run_delay: u64,
+    /// Steal time reported to guest, in nanoseconds.
+    steal: u64,
+    last_update: Option<Instant>,
+}
+
+impl StealTime {
+    /// Turn on STA of KVM if `enabled`, or turn it off so that guest doesn't see it.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    /// * `enabled` - Whether steal time is given by `-cpu`.
+    pub fn realize(&mut self, vcpu_fd: &VcpuFd, enabled: bool) {
+        self.enabled = enabled;
+        self.in_kvm = set_sbi_ext(vcpu_fd, KVM_RISCV_SBI_EXT_STA, enabled).is_ok() && enabled;
+        if enabled && !self.in_kvm {
+            info!("KVM has no SBI STA, steal time is accounted in userspace");
+        }
+    }
+
+    /// Whether STA calls forwarded to userspace are handled.
+    pub fn is_emulated(&self) -> bool {
+        self.enabled && !self.in_kvm
+    }
+
+    /// Set the shared memory at host address `shmem`, or disable steal time if none.
+    ///
+    /// # Arguments
+    ///
+    /// * `shmem` - Host address of the shared memory, which is `SBI_STA_SHMEM_SIZE`
+    ///   bytes of guest RAM.
+    /// * `tid` - Thread id of the vcpu.
+    pub fn set_shmem(&mut self, shmem: Option<u64>, tid: u64) {
+        self.shmem = shmem;
+        self.steal = 0;
+        self.last_update = None;
+        self.last_run_delay = thread_run_delay_ns(tid).unwrap_or(0);
+        if let Some(shmem) = shmem {
+            // SAFETY: shmem is aligned guest RAM which stays mapped.
+            unsafe {
+                field::<AtomicU32>(shmem, STA_SEQUENCE).store(0, Ordering::Release);
+                field::<AtomicU32>(shmem, STA_FLAGS).store(0, Ordering::Release);
+            }
+            self.write_steal(shmem);
+        }
+    }
+
+    /// Update steal time before vcpu enters guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `tid` - Thread id of the vcpu.
+    pub fn update(&mut self, tid: u64) {
+        if self.shmem.is_none()
+            || self
+                .last_update
+                .map_or(false, |last| last.elapsed() < STEAL_TIME_UPDATE_INTERVAL)
+        {
+            return;
+        }
+        match thread_run_delay_ns(tid) {
+            Some(run_delay) => self.account(run_delay),
+            None => {
+                error!(
+                    "Failed to read run delay of vcpu thread {}, steal time stops",
+                    tid
+                );
+                self.shmem = None;
+            }
+        }
+    }
+
+    /// Drop the shared memory set by guest, which is done when vcpu is reset.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    pub fn reset(&mut self, vcpu_fd: &VcpuFd) {
+        self.shmem = None;
+        if self.in_kvm {
+            if let Err(e) = reset_sta_shmem(vcpu_fd) {
+                error!("Failed to reset steal time of KVM: {:?}", e);
+            }
+        }
+    }
+
+    fn account(&mut self, run_delay: u64) {
+        self.steal += run_delay.saturating_sub(self.last_run_delay);
+        self.last_run_delay = run_delay;
+        self.last_update = Some(Instant::now());
+        if let Some(shmem) = self.shmem {
+            self.write_steal(shmem);
+        }
+    }
+
+    /// Write steal time, during which the sequence is odd so guest retries reading.
+    fn write_steal(&self, shmem: u64) {
+        // SAFETY: shmem is aligned guest RAM which stays mapped.
+        unsafe {
+            let sequence = field::<AtomicU32>(shmem, STA_SEQUENCE);
+            let seq = u32::from_le(sequence.load(Ordering::Acquire));
+            sequence.store(seq.wrapping_add(1).to_le(), Ordering::Release);
+            field::<AtomicU64>(shmem, STA_STEAL).store(self.steal.to_le(), Ordering::Release);
+            field::<AtomicU8>(shmem, STA_PREEMPTED).store(0, Ordering::Release);
+            sequence.store(seq.wrapping_add(2).to_le(), Ordering::Release);
+        }
+    }
+}
+
+/// # Safety
+///
+/// `shmem` must point to the shared memory of STA.
+unsafe fn field<'a, T>(shmem: u64, offset: u64) -> &'a T {
+    &*((shmem + offset) as *const T)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[repr(C, align(64))]
+    #[derive(Default)]
+    struct Shmem([u64; 8]);
+
+    #[test]
+    fn test_steal_time_account() {
+        let mut buf = Shmem::default();
+        let shmem = buf.0.as_mut_ptr() as u64;
+        let mut steal_time = StealTime {
+            shmem: Some(shmem),
+            ..Default::default()
+        };
+        steal_time.write_steal(shmem);
+        // Sequence is odd during the write, and even after it.
+        assert_eq!(buf.0[0] & 0xffff_ffff, 2);
+        assert_eq!(buf.0[1], 0);
+
+        steal_time.last_run_delay = 1000;
+        steal_time.account(1500);
+        assert_eq!(buf.0[0] & 0xffff_ffff, 4);
+        assert_eq!(buf.0[1], 500);
+        assert!(steal_time.last_update.is_some());
+        // Updates within the interval are skipped.
+        steal_time.update(0);
+        assert_eq!(buf.0[1], 500);
+
+        steal_time.account(1200);
+        assert_eq!(buf.0[1], 500);
+        steal_time.account(2000);
+        assert_eq!(buf.0[1], 1300);
+        assert_eq!(buf.0[2] & 0xff, 0);
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use kvm_ioctls::VcpuExit;
+use machine_manager::qmp::qmp_schema;
+
+/// Reason why vcpu exits from `KVM_RUN`, by which exits are counted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExitKind {
+    /// Guest accesses emulated device.
+    Mmio,
+    /// Guest calls SBI which is handled in userspace.
+    Sbi,
+    /// Vcpu is kicked out by signal, e.g. to be paused.
+    Interrupt,
+    Other,
+}
+
+impl ExitKind {
+    pub fn from_run(run: &std::result::Result<VcpuExit, kvm_ioctls::Error>) -> Self {
+        match run {
+            Ok(VcpuExit::MmioRead(..)) | Ok(VcpuExit::MmioWrite(..)) => ExitKind::Mmio,
+            #[cfg(target_arch = "riscv64")]
+            Ok(VcpuExit::RiscvSbi(_)) => ExitKind::Sbi,
+            Ok(VcpuExit::Intr) => ExitKind::Interrupt,
+            Err(e) if e.errno() == libc::EINTR || e.errno() == libc::EAGAIN => ExitKind::Interrupt,
+            _ => ExitKind::Other,
+        }
+    }
+}
+
+/// Time the thread `tid` of this process has waited on host run queues, which is
+/// the steal time of a vcpu thread, in nanoseconds. None if the host kernel doesn't
+/// account it.
+pub fn thread_run_delay_ns(tid: u64) -> Option<u64> {
+    let schedstat = std::fs::read_to_string(format!("/proc/self/task/{}/schedstat", tid)).ok()?;
+    parse_run_delay(&schedstat)
+}
+
+/// Parse run delay from schedstat, which is "<run time> <run delay> <timeslices>".
+fn parse_run_delay(schedstat: &str) -> Option<u64> {
+    schedstat.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Runtime statistics of one vcpu.
+///
+/// Counters are only accumulated by the vcpu thread itself, so relaxed atomics are
+/// enough, and they are snapshotted when queried. Instructions retired by guest are
+/// not reported, as kvm on riscv does not expose them. Steal time is read from host
+/// scheduling stats of the vcpu thread when queried.
+#[derive(Default)]
+pub struct CpuStats {
+    /// Time spent in `KVM_RUN`, including guest idle in `wfi`, in nanoseconds.
+    guest_time_ns: AtomicU64,
+    /// Time spent handling exits in userspace, in nanoseconds.
+    exit_time_ns: AtomicU64,
+    mmio_exits: AtomicU64,
+    sbi_exits: AtomicU64,
+    interrupt_exits: AtomicU64,
+    other_exits: AtomicU64,
+    /// Run delay of the vcpu thread when counters were zeroed, in nanoseconds.
+    steal_base_ns: AtomicU64,
+}
+
+impl CpuStats {
+    /// Account one round of `KVM_RUN`.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - When vcpu enters guest.
+    /// * `exit` - When vcpu exits to userspace.
+    /// * `kind` - Reason of the exit.
+    pub fn account(&self, entry: Instant, exit: Instant, kind: ExitKind) {
+        let guest_ns = exit.duration_since(entry).as_nanos() as u64;
+        let exit_ns = exit.elapsed().as_nanos() as u64;
+        self.guest_time_ns.fetch_add(guest_ns, Ordering::Relaxed);
+        self.exit_time_ns.fetch_add(exit_ns, Ordering::Relaxed);
+
+        let counter = match kind {
+            ExitKind::Mmio => &self.mmio_exits,
+            ExitKind::Sbi => &self.sbi_exits,
+            ExitKind::Interrupt => &self.interrupt_exits,
+            ExitKind::Other => &self.other_exits,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Zero all counters, vcpu should be paused.
+    ///
+    /// # Arguments
+    ///
+    /// * `thread_id` - Thread id of the vcpu.
+    pub fn reset(&self, thread_id: u64) {
+        for counter in [
+            &self.guest_time_ns,
+            &self.exit_time_ns,
+            &self.mmio_exits,
+            &self.sbi_exits,
+            &self.interrupt_exits,
+            &self.other_exits,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.steal_base_ns.store(
+            thread_run_delay_ns(thread_id).unwrap_or(0),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Take a snapshot of counters for QMP.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu_index` - Index of the vcpu.
+    /// * `thread_id` - Thread id of the vcpu.
+    pub fn snapshot(&self, cpu_index: u8, thread_id: u64) -> qmp_schema::VcpuStats {
+        qmp_schema::VcpuStats {
+            cpu_index: cpu_index as isize,
+            thread_id: thread_id as isize,
+            guest_time_ns: self.guest_time_ns.load(Ordering::Relaxed),
+            exit_time_ns: self.exit_time_ns.load(Ordering::Relaxed),
+            mmio_exits: self.mmio_exits.load(Ordering::Relaxed),
+            sbi_exits: self.sbi_exits.load(Ordering::Relaxed),
+            interrupt_exits: self.interrupt_exits.load(Ordering::Relaxed),
+            other_exits: self.other_exits.load(Ordering::Relaxed),
+            steal_time_ns: thread_run_delay_ns(thread_id)
+                .unwrap_or(0)
+                .saturating_sub(self.steal_base_ns.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_cpu_stats() {
+        let stats = CpuStats::default();
+        let entry = Instant::now();
+        let exit = entry + Duration::from_micros(10);
+        stats.account(entry, exit, ExitKind::Mmio);
+        stats.account(entry, exit, ExitKind::Mmio);
+        stats.account(entry, exit, ExitKind::Interrupt);
+
+        let snap = stats.snapshot(1, 100);
+        assert_eq!(snap.cpu_index, 1);
+        assert_eq!(snap.thread_id, 100);
+        assert_eq!(snap.guest_time_ns, 30_000);
+        assert_eq!(snap.mmio_exits, 2);
+        assert_eq!(snap.sbi_exits, 0);
+        assert_eq!(snap.interrupt_exits, 1);
+        assert_eq!(snap.other_exits, 0);
+
+        stats.reset(100);
+        let snap = stats.snapshot(1, 100);
+        assert_eq!(snap.guest_time_ns, 0);
+        assert_eq!(snap.exit_time_ns, 0);
+        assert_eq!(snap.mmio_exits, 0);
+        assert_eq!(snap.interrupt_exits, 0);
+    }
+
+    #[test]
+    fn test_thread_run_delay() {
+        assert_eq!(parse_run_delay("1234 5678 9\n"), Some(5678));
+        assert_eq!(parse_run_delay("1234"), None);
+        assert_eq!(parse_run_delay("1234 abc 9"), None);
+
+        let tid = util::unix::gettid();
+        if let Some(before) = thread_run_delay_ns(tid) {
+            let after = thread_run_delay_ns(tid).unwrap();
+            assert!(after >= before);
+        }
+        assert_eq!(thread_run_delay_ns(0), None);
+    }
+}
@@ -24,5 +24,7 @@ pub use riscv::PLICConfig as InterruptControllerConfig;
 pub use riscv::InterruptController;
 #[cfg(target_arch = "riscv64")]
 pub use riscv::plic::MAX_DEVICES;
+#[cfg(target_arch = "riscv64")]
+pub use riscv::clint::{Clint, ClintIrqLines, CLINT_REGION_SIZE};
 
 
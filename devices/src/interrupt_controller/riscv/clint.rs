@@ -0,0 +1,515 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use address_space::GuestAddress;
+use anyhow::{bail, Context, Result};
+use log::debug;
+use sysbus::{begin_fdt_node, AccessResult, SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use util::device_tree::{self, FdtBuilder};
+
+use crate::timer::DeviceTimer;
+
+/// Registers of CLINT, refer to the ACLINT specification of RISC-V.
+const MSIP_BASE: u64 = 0x0;
+const MTIMECMP_BASE: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+/// Size of the register region of CLINT.
+pub const CLINT_REGION_SIZE: u64 = 0x1_0000;
+/// Max number of harts served by CLINT, limited by the space of msip registers.
+const MAX_HARTS: usize = 4095;
+
+/// Bit of machine software interrupt in `mip`.
+pub const MIP_MSIP: u32 = 1 << 3;
+/// Bit of machine timer interrupt in `mip`.
+pub const MIP_MTIP: u32 = 1 << 7;
+
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+/// Callback notified with the hart index and its pending `mip` bits driven by CLINT.
+pub type ClintIrqLines = Arc<dyn Fn(usize, u32) + Send + Sync>;
+
+/// Read `data.len()` bytes at `offset` of 64-bit register `reg`, which allows 32-bit
+/// accesses to the low and high halves.
+fn read_reg64(reg: u64, offset: u64, data: &mut [u8]) -> AccessResult {
+    let bytes = reg.to_le_bytes();
+    match (offset, data.len()) {
+        (0, 8) | (0, 4) | (4, 4) => {
+            let start = offset as usize;
+            data.copy_from_slice(&bytes[start..start + data.len()]);
+            AccessResult::Ok
+        }
+        _ => AccessResult::UnsupportedSize,
+    }
+}
+
+/// Update 64-bit register `reg` with `data` written at `offset`, return `None` if the
+/// size of access is not supported.
+fn write_reg64(reg: u64, offset: u64, data: &[u8]) -> Option<u64> {
+    let mut bytes = reg.to_le_bytes();
+    match (offset, data.len()) {
+        (0, 8) | (0, 4) | (4, 4) => {
+            let start = offset as usize;
+            bytes[start..start + data.len()].copy_from_slice(data);
+            Some(u64::from_le_bytes(bytes))
+        }
+        _ => None,
+    }
+}
+
+/// Time base of `mtime`, which counts at `frequency` since `start`.
+#[derive(Clone, Copy)]
+struct MtimeClock {
+    frequency: u64,
+    start: Instant,
+    /// Value of `mtime` at `start`.
+    offset: u64,
+    /// Value of `mtime` while vm is paused.
+    frozen: Option<u64>,
+}
+
+impl MtimeClock {
+    fn now(&self) -> u64 {
+        if let Some(frozen) = self.frozen {
+            return frozen;
+        }
+        let ticks =
+            self.start.elapsed().as_nanos() * self.frequency as u128 / NANOSECONDS_PER_SECOND;
+        (ticks as u64).wrapping_add(self.offset)
+    }
+
+    /// Nanoseconds until `mtime` reaches `deadline`.
+    fn ns_until(&self, deadline: u64) -> u64 {
+        let ticks = deadline.saturating_sub(self.now()) as u128;
+        let ns =
+            (ticks * NANOSECONDS_PER_SECOND + self.frequency as u128 - 1) / self.frequency as u128;
+        ns.min(u64::MAX as u128) as u64
+    }
+
+    /// Set `mtime` to `val`, it keeps counting from `val` unless frozen.
+    fn set(&mut self, val: u64) {
+        if self.frozen.is_some() {
+            self.frozen = Some(val);
+        } else {
+            self.start = Instant::now();
+            self.offset = val;
+        }
+    }
+}
+
+struct HartState {
+    msip: bool,
+    mtimecmp: u64,
+    /// Pending `mip` bits last notified.
+    mip: u32,
+    timer: DeviceTimer,
+}
+
+impl Default for HartState {
+    fn default() -> Self {
+        HartState {
+            msip: false,
+            mtimecmp: u64::MAX,
+            mip: 0,
+            timer: DeviceTimer::default(),
+        }
+    }
+}
+
+/// State shared between CLINT and the timers armed in main loop.
+struct ClintState {
+    clock: MtimeClock,
+    harts: Vec<HartState>,
+    lines: Option<ClintIrqLines>,
+}
+
+impl ClintState {
+    /// Update the pending `mip` bits of `hart` and notify the interrupt line on change.
+    fn update_irq(&mut self, hart: usize) {
+        let state = &mut self.harts[hart];
+        let mut mip = 0;
+        if state.msip {
+            mip |= MIP_MSIP;
+        }
+        if self.clock.now() >= state.mtimecmp {
+            mip |= MIP_MTIP;
+        }
+        if mip == state.mip {
+            return;
+        }
+        state.mip = mip;
+        debug!("CLINT hart {} pending interrupts 0x{:x}", hart, mip);
+        if let Some(lines) = &self.lines {
+            lines(hart, mip);
+        }
+    }
+}
+
+/// Re-arm the timer of `hart` according to its `mtimecmp`, timers armed before are
+/// dropped when they fire.
+fn arm_timer(state: &Arc<Mutex<ClintState>>, hart: usize) {
+    let mut locked_state = state.lock().unwrap();
+    locked_state.update_irq(hart);
+    let mtimecmp = locked_state.harts[hart].mtimecmp;
+    if locked_state.harts[hart].mip & MIP_MTIP != 0
+        || mtimecmp == u64::MAX
+        || locked_state.clock.frozen.is_some()
+    {
+        locked_state.harts[hart].timer.cancel();
+        return;
+    }
+    let delay = locked_state.clock.ns_until(mtimecmp);
+
+    let weak_state: Weak<Mutex<ClintState>> = Arc::downgrade(state);
+    let func = move || {
+        if let Some(state) = weak_state.upgrade() {
+            if state.lock().unwrap().harts[hart].timer.is_current() {
+                arm_timer(&state, hart);
+            }
+        }
+    };
+    locked_state.harts[hart]
+        .timer
+        .arm(Duration::from_nanos(delay), func);
+}
+
+/// Core local interruptor, provides per-hart machine software interrupt (msip) and
+/// machine timer (mtime, mtimecmp), the timers are driven by main loop.
+pub struct Clint {
+    state: Arc<Mutex<ClintState>>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl Clint {
+    /// Create CLINT for `nr_harts` harts with `mtime` counting at `frequency` Hz.
+    ///
+    /// # Arguments
+    ///
+    /// * `nr_harts` - Number of harts.
+    /// * `frequency` - Timebase frequency, the same as `timebase-frequency` of cpus.
+    /// * `lines` - Interrupt lines of harts, `None` if they are not connected.
+    pub fn new(nr_harts: usize, frequency: u64, lines: Option<ClintIrqLines>) -> Result<Self> {
+        if nr_harts == 0 || nr_harts > MAX_HARTS {
+            bail!("CLINT supports 1 to {} harts, got {}", MAX_HARTS, nr_harts);
+        }
+        if frequency == 0 {
+            bail!("Timebase frequency of CLINT must not be zero");
+        }
+        Ok(Clint {
+            state: Arc::new(Mutex::new(ClintState {
+                clock: MtimeClock {
+                    frequency,
+                    start: Instant::now(),
+                    offset: 0,
+                    frozen: None,
+                },
+                harts: (0..nr_harts).map(|_| HartState::default()).collect(),
+                lines,
+            })),
+            res: SysRes::default(),
+        })
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.res.region_base = region_base;
+        self.res.region_size = region_size;
+        self.res.irq = -1;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach CLINT device")?;
+        Ok(dev)
+    }
+
+    /// Get the pending `mip` bits of `hart` driven by CLINT.
+    pub fn pending(&self, hart: usize) -> u32 {
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.update_irq(hart);
+        locked_state.harts[hart].mip
+    }
+
+    fn nr_harts(&self) -> u64 {
+        self.state.lock().unwrap().harts.len() as u64
+    }
+}
+
+impl SysBusDevOps for Clint {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        let nr_harts = self.nr_harts();
+        let locked_state = self.state.lock().unwrap();
+        if offset >= MSIP_BASE && offset < MSIP_BASE + nr_harts * 4 {
+            if data.len() != 4 || offset % 4 != 0 {
+                return AccessResult::UnsupportedSize;
+            }
+            let hart = ((offset - MSIP_BASE) / 4) as usize;
+            data.copy_from_slice(&(locked_state.harts[hart].msip as u32).to_le_bytes());
+            AccessResult::Ok
+        } else if offset >= MTIMECMP_BASE && offset < MTIMECMP_BASE + nr_harts * 8 {
+            let hart = ((offset - MTIMECMP_BASE) / 8) as usize;
+            read_reg64(locked_state.harts[hart].mtimecmp, offset % 8, data)
+        } else if (MTIME..MTIME + 8).contains(&offset) {
+            read_reg64(locked_state.clock.now(), offset - MTIME, data)
+        } else {
+            AccessResult::BadOffset
+        }
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        let nr_harts = self.nr_harts();
+        if offset >= MSIP_BASE && offset < MSIP_BASE + nr_harts * 4 {
+            if data.len() != 4 || offset % 4 != 0 {
+                return AccessResult::UnsupportedSize;
+            }
+            let hart = ((offset - MSIP_BASE) / 4) as usize;
+            let mut locked_state = self.state.lock().unwrap();
+            // Only bit 0 of msip is writable.
+            locked_state.harts[hart].msip = data[0] & 1 != 0;
+            locked_state.update_irq(hart);
+            AccessResult::Ok
+        } else if offset >= MTIMECMP_BASE && offset < MTIMECMP_BASE + nr_harts * 8 {
+            let hart = ((offset - MTIMECMP_BASE) / 8) as usize;
+            let mut locked_state = self.state.lock().unwrap();
+            let mtimecmp = locked_state.harts[hart].mtimecmp;
+            match write_reg64(mtimecmp, offset % 8, data) {
+                Some(val) => locked_state.harts[hart].mtimecmp = val,
+                None => return AccessResult::UnsupportedSize,
+            }
+            drop(locked_state);
+            arm_timer(&self.state, hart);
+            AccessResult::Ok
+        } else if (MTIME..MTIME + 8).contains(&offset) {
+            let mut locked_state = self.state.lock().unwrap();
+            let now = locked_state.clock.now();
+            let val = match write_reg64(now, offset - MTIME, data) {
+                Some(val) => val,
+                None => return AccessResult::UnsupportedSize,
+            };
+            locked_state.clock.set(val);
+            drop(locked_state);
+            // Deadlines of all harts move with mtime.
+            for hart in 0..nr_harts as usize {
+                arm_timer(&self.state, hart);
+            }
+            AccessResult::Ok
+        } else {
+            AccessResult::BadOffset
+        }
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::Clint
+    }
+
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        let node_dep = match begin_fdt_node(parent, SysBusDevType::Clint, &self.res)? {
+            Some(node_dep) => node_dep,
+            None => return Ok(()),
+        };
+        // Machine software interrupt (3) and machine timer interrupt (7) of every hart.
+        let mut irq_cells = Vec::new();
+        for hart in 0..self.nr_harts() as u32 {
+            let intc = device_tree::INCT_PHANDLE_START + hart;
+            irq_cells.extend_from_slice(&[intc, 3, intc, 7]);
+        }
+        parent.set_property_array_u32("interrupts-extended", &irq_cells)?;
+        parent.end_node(node_dep)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let nr_harts = self.nr_harts() as usize;
+        let mut locked_state = self.state.lock().unwrap();
+        for hart in 0..nr_harts {
+            let state = &mut locked_state.harts[hart];
+            state.msip = false;
+            state.mtimecmp = u64::MAX;
+            // Drop the armed timer.
+            state.timer.cancel();
+            locked_state.update_irq(hart);
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        let mut locked_state = self.state.lock().unwrap();
+        if locked_state.clock.frozen.is_none() {
+            locked_state.clock.frozen = Some(locked_state.clock.now());
+        }
+        // Drop the armed timers, they are re-armed on resume.
+        for state in locked_state.harts.iter() {
+            state.timer.cancel();
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let mut locked_state = self.state.lock().unwrap();
+        // Continue from the time when vm is paused.
+        if let Some(mtime) = locked_state.clock.frozen.take() {
+            locked_state.clock.set(mtime);
+        }
+        drop(locked_state);
+        for hart in 0..self.nr_harts() as usize {
+            arm_timer(&self.state, hart);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use machine_manager::event_loop::EventLoop;
+
+    use super::*;
+
+    fn write_u32(clint: &mut Clint, offset: u64, val: u32) -> AccessResult {
+        clint.write(&val.to_le_bytes(), GuestAddress(0), offset)
+    }
+
+    fn read_u32(clint: &mut Clint, offset: u64) -> u32 {
+        let mut data = [0_u8; 4];
+        assert_eq!(
+            clint.read(&mut data, GuestAddress(0), offset),
+            AccessResult::Ok
+        );
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_clint_msip() {
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let cloned_notified = notified.clone();
+        let lines: ClintIrqLines = Arc::new(move |hart: usize, mip: u32| {
+            cloned_notified.lock().unwrap().push((hart, mip));
+        });
+        let mut clint = Clint::new(2, 10_000_000, Some(lines)).unwrap();
+
+        assert_eq!(write_u32(&mut clint, MSIP_BASE + 4, 0xff), AccessResult::Ok);
+        assert_eq!(read_u32(&mut clint, MSIP_BASE + 4), 1);
+        assert_eq!(read_u32(&mut clint, MSIP_BASE), 0);
+        assert_eq!(clint.pending(1), MIP_MSIP);
+        assert_eq!(write_u32(&mut clint, MSIP_BASE + 4, 0), AccessResult::Ok);
+        assert_eq!(*notified.lock().unwrap(), vec![(1, MIP_MSIP), (1, 0)]);
+
+        // Registers of absent harts and unsupported sizes.
+        assert_eq!(
+            write_u32(&mut clint, MSIP_BASE + 8, 1),
+            AccessResult::BadOffset
+        );
+        assert_eq!(
+            clint.write(&[1_u8], GuestAddress(0), MSIP_BASE),
+            AccessResult::UnsupportedSize
+        );
+
+        assert_eq!(write_u32(&mut clint, MSIP_BASE, 1), AccessResult::Ok);
+        clint.reset().unwrap();
+        assert_eq!(read_u32(&mut clint, MSIP_BASE), 0);
+        assert_eq!(clint.pending(0), 0);
+    }
+
+    #[test]
+    fn test_clint_mtime() {
+        EventLoop::object_init(&None).unwrap();
+        let mut clint = Clint::new(1, 10_000_000, None).unwrap();
+
+        // mtimecmp is all-ones after reset, accessed by 32-bit halves.
+        assert_eq!(read_u32(&mut clint, MTIMECMP_BASE), u32::MAX);
+        assert_eq!(read_u32(&mut clint, MTIMECMP_BASE + 4), u32::MAX);
+
+        // Write mtime by halves and read it back as a whole.
+        assert_eq!(write_u32(&mut clint, MTIME + 4, 0x1), AccessResult::Ok);
+        assert_eq!(write_u32(&mut clint, MTIME, 0), AccessResult::Ok);
+        let mut data = [0_u8; 8];
+        assert_eq!(
+            clint.read(&mut data, GuestAddress(0), MTIME),
+            AccessResult::Ok
+        );
+        let mtime = u64::from_le_bytes(data);
+        assert!(mtime >= 0x1_0000_0000);
+        assert!(mtime < 0x1_0000_0000 + 10_000_000);
+        assert!(read_u32(&mut clint, MTIME + 4) >= 1);
+        assert_eq!(
+            clint.read(&mut [0_u8; 2], GuestAddress(0), MTIME),
+            AccessResult::UnsupportedSize
+        );
+
+        // Timer interrupt is pending once mtime passes mtimecmp.
+        assert_eq!(
+            write_u32(&mut clint, MTIMECMP_BASE + 4, 0x1),
+            AccessResult::Ok
+        );
+        assert_eq!(write_u32(&mut clint, MTIMECMP_BASE, 0), AccessResult::Ok);
+        assert_eq!(clint.pending(0), MIP_MTIP);
+        clint.reset().unwrap();
+        assert_eq!(clint.pending(0), 0);
+        let mut data = [0_u8; 8];
+        assert_eq!(
+            clint.read(&mut data, GuestAddress(0), MTIMECMP_BASE),
+            AccessResult::Ok
+        );
+        assert_eq!(u64::from_le_bytes(data), u64::MAX);
+    }
+
+    #[test]
+    fn test_clint_pause() {
+        EventLoop::object_init(&None).unwrap();
+        let mut clint = Clint::new(1, 10_000_000, None).unwrap();
+        let read_mtime = |clint: &mut Clint| {
+            let mut data = [0_u8; 8];
+            assert_eq!(
+                clint.read(&mut data, GuestAddress(0), MTIME),
+                AccessResult::Ok
+            );
+            u64::from_le_bytes(data)
+        };
+
+        // mtime stands still while paused, pausing twice doesn't move it.
+        clint.pause().unwrap();
+        let paused = read_mtime(&mut clint);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        clint.pause().unwrap();
+        assert_eq!(read_mtime(&mut clint), paused);
+
+        // Timer doesn't expire while paused.
+        assert_eq!(
+            write_u32(&mut clint, MTIMECMP_BASE, paused as u32 + 1),
+            AccessResult::Ok
+        );
+        assert_eq!(
+            write_u32(&mut clint, MTIMECMP_BASE + 4, (paused >> 32) as u32),
+            AccessResult::Ok
+        );
+        assert_eq!(clint.pending(0), 0);
+
+        // Continue counting from the paused value.
+        clint.resume().unwrap();
+        let resumed = read_mtime(&mut clint);
+        assert!(resumed >= paused);
+        assert!(resumed < paused + 100_000);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert_eq!(clint.pending(0), MIP_MTIP);
+    }
+}
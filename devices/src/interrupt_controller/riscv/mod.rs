@@ -11,12 +11,22 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+pub mod clint;
 pub mod plic;
+pub use clint::Clint;
 pub use plic::PLIC;
 
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use sysbus::SysBus;
+use sysbus::{IrqMode, IrqRouter, SysBus};
+use hypervisor::kvm::KVM_FDS;
 use kvm_ioctls::VcpuFd;
+use log::error;
+use machine_manager::event_loop::EventLoop;
+use util::loop_context::{read_fd, EventNotifier, NotifierCallback, NotifierOperation};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
 use anyhow::{anyhow, Context, Result};
 
 /// PLIC version type.
@@ -38,9 +48,19 @@ pub trait PLICDevice {
     where
         Self: Sized;
     
-    fn kvm_irq_line(&self, irq: u8, level: u8) -> Result<()>;
+    fn kvm_irq_line(&mut self, irq: u8, level: u8) -> Result<()>;
 
-    fn kvm_irq_trigger(&self, irq: u8) -> Result<()>;
+    fn kvm_irq_trigger(&mut self, irq: u8) -> Result<()>;
+
+    /// Set level of `irq` driven by `source`, the line is asserted while any
+    /// of its sources asserts it.
+    fn set_irq_level(&mut self, irq: u8, source: u64, level: u8) -> Result<()>;
+
+    /// Drive contexts of `hart` through `vcpu_fd`, for vcpus hot-added after realize.
+    fn set_hart_vcpu(&mut self, hart: u32, vcpu_fd: Arc<VcpuFd>) -> Result<()>;
+
+    /// Whether the irqchip is owned by kvm, which is the only one irqfd delivers to.
+    fn in_kernel(&self) -> bool;
 }
 
 /// A wrapper around creating and using a interrupt controller.
@@ -64,6 +84,9 @@ impl InterruptController {
                 }
             },
         };
+        sysbus.set_irq_router(Arc::new(PLICIrqRouter {
+            plic: intc.plic.clone(),
+        }));
         Ok(intc)
     }
 
@@ -77,5 +100,83 @@ impl InterruptController {
         Ok(())
     }
 
+    pub fn set_irq_level(&self, irq: u8, source: u64, level: u8) -> Result<()> {
+        self.plic.lock().unwrap().set_irq_level(irq, source, level)?;
+        Ok(())
+    }
+
+    pub fn set_hart_vcpu(&self, hart: u32, vcpu_fd: Arc<VcpuFd>) -> Result<()> {
+        self.plic.lock().unwrap().set_hart_vcpu(hart, vcpu_fd)
+    }
+
+}
+
+/// Deliver interrupt eventfds of sysbus devices to PLIC.
+///
+/// The eventfd is bound to the gsi as irqfd if kvm owns the irqchip and supports
+/// irqfd, otherwise it is polled in main loop and injected through PLIC by userspace.
+struct PLICIrqRouter {
+    plic: Arc<Mutex<dyn PLICDevice + std::marker::Send + std::marker::Sync>>,
 }
 
+impl IrqRouter for PLICIrqRouter {
+    fn irqfd_supported(&self) -> bool {
+        // Irqfd injects into kvm's irqchip, which never sees the PLIC emulated in
+        // userspace.
+        self.plic.lock().unwrap().in_kernel() && KVM_FDS.load().irqfd_supported()
+    }
+
+    fn register_irqfd(&self, evt: &EventFd, irq: i32) -> Result<()> {
+        KVM_FDS.load().register_irqfd(evt, irq as u32)
+    }
+
+    fn register_polling(&self, evt: &EventFd, irq: i32) -> Result<()> {
+        let plic = self.plic.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(e) = plic.lock().unwrap().kvm_irq_trigger(irq as u8) {
+                error!("Failed to inject irq {} by PLIC: {:?}", irq, e);
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        );
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| format!("Failed to poll interrupt eventfd of irq {}", irq))
+    }
+
+    fn unregister(&self, evt: &EventFd, irq: i32, mode: IrqMode) -> Result<()> {
+        match mode {
+            IrqMode::Irqfd => KVM_FDS.load().unregister_irqfd(evt, irq as u32),
+            IrqMode::Polling => {
+                let notifier = EventNotifier::new(
+                    NotifierOperation::Delete,
+                    evt.as_raw_fd(),
+                    None,
+                    EventSet::IN,
+                    Vec::new(),
+                );
+                EventLoop::update_event(vec![notifier], None).with_context(|| {
+                    format!("Failed to stop polling interrupt eventfd of irq {}", irq)
+                })
+            }
+            IrqMode::None => Ok(()),
+        }
+    }
+
+    fn trigger_irq(&self, irq: i32) -> Result<()> {
+        self.plic.lock().unwrap().kvm_irq_trigger(irq as u8)
+    }
+
+    fn set_irq_level(&self, irq: i32, source: u64, level: bool) -> Result<()> {
+        self.plic
+            .lock()
+            .unwrap()
+            .set_irq_level(irq as u8, source, level as u8)
+    }
+}
@@ -11,21 +11,32 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex};
 
-use anyhow::{anyhow, Context, Result};
-use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use super::{PLICConfig, PLICDevice};
 use address_space::GuestAddress;
+use anyhow::{anyhow, bail, Context, Result};
 use kvm_ioctls::VcpuFd;
-use super::{PLICConfig, PLICDevice};
-use log::{debug, error};
+use log::error;
+use sysbus::{
+    begin_fdt_node, decode_state, encode_state, AccessResult, StateReader, SysBus, SysBusDevOps,
+    SysBusDevType, SysRes,
+};
+use util::device_tree::{self, FdtBuilder};
 
 pub const MAX_DEVICES: u32 = 1024;
-const MAX_CONTEXTS: u32 = 15872; 
+const MAX_CONTEXTS: u32 = 15872;
+/// Number of 32-bit words of per-source bitmaps.
+const IRQ_WORDS: usize = (MAX_DEVICES / 32) as usize;
+
+/// Priority registers start at offset 0.
+const PRIORITY_PER_ID: u32 = 4;
+/// Priority is 3 bits wide, a source of priority 0 never interrupts.
+const MAX_PRIORITY: u8 = 7;
+
+const PENDING_BASE: u32 = 0x1000;
 
-const PRIORITY_BASE: u32 = 0;
-const PRIORITY_PER_ID: u32 =4;
- 
 const ENABLE_BASE: u32 = 0x2000;
 const ENABLE_PER_HART: u32 = 0x80;
 
@@ -34,48 +45,99 @@ const CONTEXT_PER_HART: u32 = 0x1000;
 const CONTEXT_THRESHOLD: u32 = 0;
 const CONTEXT_CLAIM: u32 = 4;
 
-const REG_SIZE: u32 = 0x0100_0000; 
+const REG_SIZE: u32 = 0x0100_0000;
+
+/// Version of PLIC state saved by `state_bytes`.
+const PLIC_STATE_VERSION: u32 = 1;
 
+/// Copy the low bytes of 32-bit register `val` to `data`.
+fn read_reg32(val: u32, data: &mut [u8]) {
+    let bytes = val.to_le_bytes();
+    let len = data.len().min(4);
+    data[..len].copy_from_slice(&bytes[..len]);
+}
+
+/// Value written to 32-bit register by `data`, the missing high bytes are zero.
+fn write_reg32(data: &[u8]) -> u32 {
+    let mut bytes = [0_u8; 4];
+    let len = data.len().min(4);
+    bytes[..len].copy_from_slice(&data[..len]);
+    u32::from_le_bytes(bytes)
+}
 
-#[derive(Clone,Debug)]
-struct PLICContext{
+/// Interrupt target of a hart in one privilege mode.
+#[derive(Clone, Debug)]
+struct PLICContext {
     num: u32,
+    /// Only sources with priority above threshold are delivered.
     irq_priority_threshold: u8,
-    vcpu_fd: Arc<VcpuFd>,
-    irq_enable: [u32; (MAX_DEVICES/32) as usize],
-    irq_pending: [u32; (MAX_DEVICES/32) as usize],
-    irq_pending_priority: [u8; MAX_DEVICES as usize],
-    irq_claimed: [u32; (MAX_DEVICES/32) as usize],
-    irq_autoclear: [u32; (MAX_DEVICES/32) as usize],
+    /// Vcpu whose external interrupt is driven by the context.
+    vcpu_fd: Option<Arc<VcpuFd>>,
+    irq_enable: [u32; IRQ_WORDS],
 }
 
-
 impl PLICContext {
-    fn new(vcpu_fd:Arc<VcpuFd>) -> Self {
+    fn new(num: u32, vcpu_fd: Option<Arc<VcpuFd>>) -> Self {
         Self {
-           num: 0,
-           irq_priority_threshold: 0,
-           vcpu_fd: vcpu_fd,
-           irq_enable: [0; (MAX_DEVICES/32) as usize],
-           irq_pending: [0; (MAX_DEVICES/32) as usize],
-           irq_pending_priority: [0; MAX_DEVICES as usize],
-           irq_claimed: [0; (MAX_DEVICES/32) as usize],
-           irq_autoclear: [0; (MAX_DEVICES/32) as usize]
+            num,
+            irq_priority_threshold: 0,
+            vcpu_fd,
+            irq_enable: [0; IRQ_WORDS],
         }
     }
 }
 
+/// Devices asserting level-triggered IRQ lines.
+///
+/// A line shared by several devices is the OR of levels of all sharers, it stays
+/// asserted until every sharer deasserts it.
+#[derive(Default)]
+struct IrqLineSources {
+    /// Sources currently asserting each line.
+    asserted: BTreeMap<u8, BTreeSet<u64>>,
+}
+
+impl IrqLineSources {
+    /// Update level of `source` on line `irq`, return the resulting level of the line.
+    fn set_level(&mut self, irq: u8, source: u64, level: u8) -> u8 {
+        if level != 0 {
+            self.asserted.entry(irq).or_default().insert(source);
+            return 1;
+        }
+        if let Some(sources) = self.asserted.get_mut(&irq) {
+            sources.remove(&source);
+            if !sources.is_empty() {
+                return 1;
+            }
+            self.asserted.remove(&irq);
+        }
+        0
+    }
+}
+
+/// Each hart has two contexts, the even one for M-mode and the odd one for S-mode.
+fn is_smode_context(num: u32) -> bool {
+    num % 2 == 1
+}
 
 pub struct PLIC {
     ready: bool,
     num_irq: u32,
-    num_irq_word:u32,
-    max_prio:u32,
+    num_irq_word: u32,
 
-    num_context:u32,
-    contexts:Vec<Arc<Mutex<PLICContext>>>,
+    num_context: u32,
+    contexts: Vec<PLICContext>,
 
     irq_priority: [u8; MAX_DEVICES as usize],
+    /// Sources pending in gateways, shared by all contexts.
+    irq_pending: [u32; IRQ_WORDS],
+    /// Sources claimed and not completed yet, they are not presented to any context.
+    irq_claimed: [u32; IRQ_WORDS],
+    /// Levels of level-triggered sources, an asserted source becomes pending again
+    /// once it is completed.
+    irq_level: [u32; IRQ_WORDS],
+    /// Sources of level-triggered IRQ lines.
+    line_sources: IrqLineSources,
     /// System resource.
     res: SysRes,
 }
@@ -85,54 +147,73 @@ impl PLICDevice for PLIC {
         PLIC {
             ready: false,
             num_irq: MAX_DEVICES,
-            num_irq_word: MAX_DEVICES,
-            max_prio: (1 << PRIORITY_PER_ID) - 1,
-            num_context: MAX_CONTEXTS,
-            contexts: Vec::<Arc<Mutex<PLICContext>>>::new(),
+            num_irq_word: MAX_DEVICES / 32,
+            num_context: 0,
+            contexts: Vec::new(),
             irq_priority: [0; MAX_DEVICES as usize],
-            /// System resource.
+            irq_pending: [0; IRQ_WORDS],
+            irq_claimed: [0; IRQ_WORDS],
+            irq_level: [0; IRQ_WORDS],
+            line_sources: IrqLineSources::default(),
             res: SysRes::default(),
         }
-        
     }
 
-    fn kvm_irq_line(&self, irq: u8, level: u8) -> Result<()> {
-        self.plic_irq_trig(irq, level, false)?;
-        Ok(())
+    fn kvm_irq_line(&mut self, irq: u8, level: u8) -> Result<()> {
+        self.plic_irq_trig(irq, level, false)
     }
 
-    fn kvm_irq_trigger(&self, irq: u8) -> Result<()> {
-        self.plic_irq_trig(irq, 1, true)?;
-        Ok(())
+    fn kvm_irq_trigger(&mut self, irq: u8) -> Result<()> {
+        self.plic_irq_trig(irq, 1, true)
+    }
+
+    fn set_irq_level(&mut self, irq: u8, source: u64, level: u8) -> Result<()> {
+        let level = self.line_sources.set_level(irq, source, level);
+        self.plic_irq_trig(irq, level, false)
+    }
+
+    fn set_hart_vcpu(&mut self, hart: u32, vcpu_fd: Arc<VcpuFd>) -> Result<()> {
+        let cntx = hart as usize * 2;
+        if cntx >= self.contexts.len() {
+            return Err(anyhow!(
+                "PLIC serves {} harts, hart {} is out of range",
+                self.num_context / 2,
+                hart
+            ));
+        }
+        for context in self.contexts[cntx..cntx + 2].iter_mut() {
+            context.vcpu_fd = Some(vcpu_fd.clone());
+        }
+        self.context_irq_update(cntx + 1)
+    }
+
+    fn in_kernel(&self) -> bool {
+        false
     }
 }
 
 impl PLIC {
-    pub fn realize(mut self, 
+    pub fn realize(
+        mut self,
         vcpu_fds: Vec<Arc<VcpuFd>>,
         sysbus: &mut SysBus,
         plic_conf: &PLICConfig,
-    ) -> Result<Arc<Mutex<Self>>> {    
-
-        self.num_irq_word = self.num_irq / 32;
-        if self.num_irq_word * 32 < self.num_irq{
-            self.num_irq_word += 1;
+    ) -> Result<Arc<Mutex<Self>>> {
+        if plic_conf.vcpu_count * 2 > MAX_CONTEXTS || vcpu_fds.len() > plic_conf.vcpu_count as usize
+        {
+            return Err(anyhow!(
+                "PLIC can't serve {} vcpus with {} harts",
+                vcpu_fds.len(),
+                plic_conf.vcpu_count
+            ));
         }
+        // Harts without vcpu yet are connected when their vcpus are hot-added.
+        let mut harts: Vec<Option<Arc<VcpuFd>>> = vcpu_fds.into_iter().map(Some).collect();
+        harts.resize(plic_conf.vcpu_count as usize, None);
+        self.init_contexts(harts);
 
-        self.num_context = plic_conf.vcpu_count * 2;
-        
-        let mut contexts = Vec::<Arc<Mutex<PLICContext>>>::new();
-        for i in 0..self.num_context {
-            let vcpu_fd = vcpu_fds[(i / 2) as usize].clone();
-            let mut context = PLICContext::new(vcpu_fd);
-            context.num = i;
-            contexts.push(Arc::new(Mutex::new(context)));
-        }
-        self.contexts = contexts;
-       
         let region_base = plic_conf.region_base;
         let region_size = plic_conf.region_size;
-
         if let Some(res) = self.get_sys_resource() {
             res.region_base = region_base;
             res.region_size = region_size;
@@ -141,288 +222,267 @@ impl PLIC {
 
         self.ready = true;
         let dev = Arc::new(Mutex::new(self));
-        sysbus.attach_device(&dev, region_base, region_size).with_context(|| "Failed to attach device")?;
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach device")?;
 
         Ok(dev)
     }
 
-    fn context_best_pending_irq(&self, context: &Arc<Mutex<PLICContext>>) -> Result<u32> {
-        let mut best_irq_prio = 0;
-        let mut best_irq:u32 = 0;
-        let mut i = 0;
-        while i < self.num_irq_word {
-            let context= context.lock().unwrap();
-            if context.irq_pending[i as usize] == 0 {
-                i += 1;
-                continue;
-            }
+    /// Create M-mode and S-mode contexts for each hart, whose external interrupt
+    /// is driven through the vcpu given in `harts`.
+    fn init_contexts(&mut self, harts: Vec<Option<Arc<VcpuFd>>>) {
+        self.num_context = harts.len() as u32 * 2;
+        self.contexts = (0..self.num_context)
+            .map(|num| PLICContext::new(num, harts[(num / 2) as usize].clone()))
+            .collect();
+    }
 
-            let mut j = 0;
-            while j < 32 {
-                let irq = i * 32 + j;
-                if (self.num_irq <= irq) ||
-                ((context.irq_pending[i as usize] & (1 << j) ) == 0) ||
-                ((context.irq_claimed[i as usize] & (1 << j)) != 0) {
-                    j += 1;
-                    continue;
-                }
-                if (best_irq == 0) || (best_irq_prio < context.irq_pending_priority[irq as usize]) {
+    /// Find the pending source with the highest priority above the threshold among
+    /// sources enabled for context `cntx`, ties are broken by the lowest id. Return 0
+    /// if there is none.
+    fn context_best_pending_irq(&self, cntx: usize) -> u32 {
+        let context = &self.contexts[cntx];
+        let mut best_irq = 0;
+        let mut best_irq_prio = context.irq_priority_threshold;
+        for word in 0..self.num_irq_word as usize {
+            let mut irqs =
+                self.irq_pending[word] & !self.irq_claimed[word] & context.irq_enable[word];
+            while irqs != 0 {
+                let irq = word as u32 * 32 + irqs.trailing_zeros();
+                irqs &= irqs - 1;
+                let irq_prio = self.irq_priority[irq as usize];
+                if irq_prio > best_irq_prio {
                     best_irq = irq;
-                    best_irq_prio = context.irq_pending_priority[irq as usize];
+                    best_irq_prio = irq_prio;
                 }
-                j += 1;
             }
-            i += 1;
         }
-
-        Ok(best_irq)
+        best_irq
     }
 
-    fn context_irq_update(&self, context: &Arc<Mutex<PLICContext>>) -> Result<()> {
-        let vcpu_fd = context.lock().unwrap().vcpu_fd.clone();
-        let best_irq = self.context_best_pending_irq(context)?;
-        if best_irq > 0 {
-            vcpu_fd.set_interrupt();
-        } 
-        else {
-            vcpu_fd.unset_interrupt();
+    /// Raise or clear external interrupt of the hart served by context `cntx`.
+    fn context_irq_update(&self, cntx: usize) -> Result<()> {
+        let context = &self.contexts[cntx];
+        // Guest runs in S-mode, M-mode contexts must not touch external interrupt of hart.
+        if !is_smode_context(context.num) {
+            return Ok(());
+        }
+        let vcpu_fd = match &context.vcpu_fd {
+            Some(vcpu_fd) => vcpu_fd,
+            None => return Ok(()),
         };
-        
-        Ok(())
+        if self.context_best_pending_irq(cntx) > 0 {
+            vcpu_fd.set_interrupt()
+        } else {
+            vcpu_fd.unset_interrupt()
+        }
+        .with_context(|| format!("Failed to update external interrupt of context {}", cntx))
     }
 
-    pub fn plic_irq_trig(&self, irq: u8, level: u8, edge: bool) -> Result<()> {
-        let mut irq_marked = false;
-        
-        if !self.ready {return Ok(());}
+    /// Update all contexts after the state of sources changed.
+    fn irq_update(&self) -> Result<()> {
+        for cntx in 0..self.contexts.len() {
+            self.context_irq_update(cntx)?;
+        }
+        Ok(())
+    }
 
-        let irq_prio = self.irq_priority[irq as usize];
+    /// Deliver `irq` to gateway. An edge raises the pending bit, which is kept until
+    /// the source is claimed. A level source is pending while asserted and not claimed.
+    pub fn plic_irq_trig(&mut self, irq: u8, level: u8, edge: bool) -> Result<()> {
+        if !self.ready || irq == 0 || u32::from(irq) >= self.num_irq {
+            return Ok(());
+        }
         let irq_word = (irq / 32) as usize;
         let irq_mask = 1 << (irq % 32);
 
-        let mut i = 0;
-        while i < self.num_context {
-            let mut context = self.contexts[i as usize].lock().unwrap();
-            if (context.irq_enable[irq_word] & irq_mask) != 0 {
-                if level != 0 {
-                    context.irq_pending[irq_word] |= irq_mask;
-                    context.irq_pending_priority[irq as usize] = irq_prio;
-                    if edge{
-                        context.irq_autoclear[irq_word] |= irq_mask;
-                    }
-                }
-                else {
-                    context.irq_pending[irq_word] &= !irq_mask;
-                    context.irq_pending_priority[irq as usize] = 0;
-                    context.irq_claimed[irq_word] &= !irq_mask;
-                    context.irq_autoclear[irq_word] &= !irq_mask;
-                }
-                self.context_irq_update(&Arc::new(Mutex::new(context.clone())))?;
-                irq_marked = true;
+        if edge {
+            if level != 0 {
+                self.irq_pending[irq_word] |= irq_mask;
             }
-            if irq_marked {break;}
-            i += 1;
+        } else if level != 0 {
+            self.irq_level[irq_word] |= irq_mask;
+            if self.irq_claimed[irq_word] & irq_mask == 0 {
+                self.irq_pending[irq_word] |= irq_mask;
+            }
+        } else {
+            self.irq_level[irq_word] &= !irq_mask;
+            self.irq_pending[irq_word] &= !irq_mask;
         }
-
-        Ok(())
+        self.irq_update()
     }
 
-    fn context_irq_claim(&self, context: &Arc<Mutex<PLICContext>>) -> Result<u32> {
-        let vcpu_fd = context.lock().unwrap().vcpu_fd.clone();
-        let best_irq = self.context_best_pending_irq(context)?;
-        let best_irq_word = best_irq / 32;
-        let best_irq_mask = 1 << (best_irq % 32);
-
-        vcpu_fd.unset_interrupt();
-
-        let mut context = context.lock().unwrap();
+    /// Claim the best pending source of context `cntx`, which is not presented again
+    /// until it is completed.
+    fn context_irq_claim(&mut self, cntx: usize) -> Result<u32> {
+        let best_irq = self.context_best_pending_irq(cntx);
         if best_irq > 0 {
-            if (context.irq_autoclear[best_irq_word as usize] & best_irq_mask) != 0 {
-                context.irq_pending[best_irq_word as usize] &= !best_irq_mask;
-                context.irq_pending_priority[best_irq as usize] = 0;
-                context.irq_claimed[best_irq_word as usize] &= !best_irq_mask;
-                context.irq_autoclear[best_irq_word as usize] &= !best_irq_mask;
-            }
-            else {
-                context.irq_claimed[best_irq_word as usize] |= best_irq_mask;
-            }
+            let irq_word = (best_irq / 32) as usize;
+            let irq_mask = 1 << (best_irq % 32);
+            self.irq_pending[irq_word] &= !irq_mask;
+            self.irq_claimed[irq_word] |= irq_mask;
         }
-
-        self.context_irq_update(&Arc::new(Mutex::new(context.clone())))?;
+        self.irq_update()?;
         Ok(best_irq)
     }
 
-    fn priority_read(&self, offset: u32, data: &mut [u8]) -> Result<()> {
-        let irq:u32 = offset >> 2;
-        if (irq == 0) || (irq >= self.num_irq)  {return Ok(());}
-        data[0] = self.irq_priority[irq as usize];
-        Ok(())
+    /// Complete `irq` claimed before, completions of sources not enabled for context
+    /// `cntx` are ignored.
+    fn context_irq_complete(&mut self, cntx: usize, irq: u32) -> Result<()> {
+        if irq == 0 || irq >= self.num_irq {
+            return Ok(());
+        }
+        let irq_word = (irq / 32) as usize;
+        let irq_mask = 1 << (irq % 32);
+        if self.contexts[cntx].irq_enable[irq_word] & irq_mask == 0
+            || self.irq_claimed[irq_word] & irq_mask == 0
+        {
+            return Ok(());
+        }
+        self.irq_claimed[irq_word] &= !irq_mask;
+        if self.irq_level[irq_word] & irq_mask != 0 {
+            self.irq_pending[irq_word] |= irq_mask;
+        }
+        self.irq_update()
     }
 
-    fn priority_write(&mut self, offset: u32, data: &[u8]) -> Result<()> {
-        let irq:u32 = offset >> 2;
-        if (irq == 0) || (irq >= self.num_irq)  {return Ok(());}
-        let mut val= data[0];
-        val &= (1 << PRIORITY_PER_ID) - 1;
-        self.irq_priority[irq as usize] = val ;
-        Ok(())
+    fn priority_read(&self, offset: u32, data: &mut [u8]) {
+        let irq = offset / PRIORITY_PER_ID;
+        let val = if irq == 0 || irq >= self.num_irq {
+            0
+        } else {
+            self.irq_priority[irq as usize]
+        };
+        read_reg32(u32::from(val), data);
     }
 
-    fn context_enable_read(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &mut [u8]) -> Result<()> {
-        let irq_word:u32 = offset >> 2;
-        if self.num_irq_word < irq_word   {return Ok(());}
-        data[0] = context.lock().unwrap().irq_enable[irq_word as usize] as u8;
-        Ok(())
+    fn priority_write(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        let irq = offset / PRIORITY_PER_ID;
+        if irq == 0 || irq >= self.num_irq {
+            return Ok(());
+        }
+        self.irq_priority[irq as usize] = write_reg32(data).min(u32::from(MAX_PRIORITY)) as u8;
+        self.irq_update()
     }
 
-    fn context_enable_write(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &[u8]) -> Result<()> {
-        let irq_word:u32 = offset >> 2;
-
-        if self.num_irq_word < irq_word  {return Ok(());}
+    /// Bits of the pending array, which is read-only.
+    fn pending_read(&self, offset: u32, data: &mut [u8]) {
+        let irq_word = offset / 4;
+        let val = if irq_word < self.num_irq_word {
+            self.irq_pending[irq_word as usize]
+        } else {
+            0
+        };
+        read_reg32(val, data);
+    }
 
-        let mut context = context.lock().unwrap();
+    fn context_enable_read(&self, cntx: usize, offset: u32, data: &mut [u8]) {
+        let irq_word = offset / 4;
+        let val = if irq_word < self.num_irq_word {
+            self.contexts[cntx].irq_enable[irq_word as usize]
+        } else {
+            0
+        };
+        read_reg32(val, data);
+    }
 
-        let old_val:u32 = context.irq_enable[irq_word as usize];
-        let mut new_val= data[0] as u32;
+    fn context_enable_write(&mut self, cntx: usize, offset: u32, data: &[u8]) -> Result<()> {
+        let irq_word = offset / 4;
+        if irq_word >= self.num_irq_word {
+            return Ok(());
+        }
+        let mut val = write_reg32(data);
+        // Source 0 doesn't exist.
         if irq_word == 0 {
-            new_val &= !0x1;
-        }
-        context.irq_enable[irq_word as usize] = new_val;
-
-        let xor_val:u32 = old_val ^ new_val;
-        let mut i = 0;
-        while i < 32 {
-            let irq = irq_word * 32 + i;
-            let irq_mask:u32 = 1 << i;
-            let irq_prio:u8 = self.irq_priority[irq as usize];
-            if (xor_val & irq_mask) == 0 {
-                i += 1;
-                continue;
-            }
-            if (new_val & irq_mask) == 0{
-                context.irq_pending[irq_word as usize] &= !irq_mask;
-                context.irq_pending_priority[irq as usize] = 0;
-                context.irq_claimed[irq_word as usize] &= !irq_mask;
-            }
-            i += 1;
+            val &= !0x1;
         }
+        self.contexts[cntx].irq_enable[irq_word as usize] = val;
+        self.context_irq_update(cntx)
+    }
 
-        self.context_irq_update(&Arc::new(Mutex::new(context.clone())))?;
+    fn context_read(&mut self, cntx: usize, offset: u32, data: &mut [u8]) -> Result<()> {
+        let val = match offset {
+            CONTEXT_THRESHOLD => u32::from(self.contexts[cntx].irq_priority_threshold),
+            CONTEXT_CLAIM => self.context_irq_claim(cntx)?,
+            _ => 0,
+        };
+        read_reg32(val, data);
         Ok(())
     }
 
-    fn context_read(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &mut [u8]) -> Result<()> {
+    fn context_write(&mut self, cntx: usize, offset: u32, data: &[u8]) -> Result<()> {
         match offset {
-            CONTEXT_THRESHOLD=> {
-                data[0] = context.lock().unwrap().irq_priority_threshold;
-            } 
-            CONTEXT_CLAIM => {
-                data[0] = self.context_irq_claim(context).unwrap() as u8;
+            CONTEXT_THRESHOLD => {
+                let val = write_reg32(data).min(u32::from(MAX_PRIORITY));
+                self.contexts[cntx].irq_priority_threshold = val as u8;
+                self.context_irq_update(cntx)
             }
-            _ => ()
+            CONTEXT_CLAIM => self.context_irq_complete(cntx, write_reg32(data)),
+            _ => Ok(()),
         }
-        Ok(())
     }
- 
-    fn context_write(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &[u8]) -> Result<()> {
-        let mut irq_update = false;
-        match offset {
-            CONTEXT_THRESHOLD => {
-                let mut val= data[0] as u32;
-                val &= (1 << PRIORITY_PER_ID) - 1;
-                if val <= self.max_prio {
-                    context.lock().unwrap().irq_priority_threshold = val as u8;
-                }
-                else {
-                    irq_update = true;
-                }
+}
+
+impl SysBusDevOps for PLIC {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        let addr = offset as u32 & !0x3;
+        if addr < PENDING_BASE {
+            self.priority_read(addr, data);
+        } else if addr < ENABLE_BASE {
+            self.pending_read(addr - PENDING_BASE, data);
+        } else if addr < CONTEXT_BASE {
+            let cntx = (addr - ENABLE_BASE) / ENABLE_PER_HART;
+            if cntx >= self.num_context {
+                read_reg32(0, data);
+                return AccessResult::Ok;
             }
-            CONTEXT_CLAIM =>{
-                let val= data[0] as u32;
-                let irq_word = val / 32;
-                let irq_mask = 1 << (val % 32);
-                if (val < self.num_irq) && 
-                    ((context.lock().unwrap().irq_enable[irq_word as usize] & irq_mask) != 0) {
-                            context.lock().unwrap().irq_claimed[irq_word as usize] &= !irq_mask;
-                            irq_update = true;
-                }
+            let offset = addr - ENABLE_BASE - cntx * ENABLE_PER_HART;
+            self.context_enable_read(cntx as usize, offset, data);
+        } else if addr < REG_SIZE {
+            let cntx = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
+            if cntx >= self.num_context {
+                read_reg32(0, data);
+                return AccessResult::Ok;
             }
-            _ =>{
-                irq_update = true;
+            let offset = addr - CONTEXT_BASE - cntx * CONTEXT_PER_HART;
+            if let Err(e) = self.context_read(cntx as usize, offset, data) {
+                error!("Failed to read context {}: {:?}", cntx, e);
+                return AccessResult::Failed;
             }
         }
-        if irq_update {
-            self.context_irq_update(context)?;
-        }
-        Ok(())
+        AccessResult::Ok
     }
 
-}
-
-impl SysBusDevOps for PLIC {
-    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
-        let mut addr = offset as u32;
-        addr &= !0x3;
-        if PRIORITY_BASE <= addr && addr < ENABLE_BASE {
-            if self.priority_read(addr, data).is_err() {
-                error!("Failed to read priority register");
-                return false;
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        let addr = offset as u32 & !0x3;
+        let ret = if addr < PENDING_BASE {
+            self.priority_write(addr, data)
+        } else if addr < ENABLE_BASE {
+            // Pending bits are read-only.
+            Ok(())
+        } else if addr < CONTEXT_BASE {
+            let cntx = (addr - ENABLE_BASE) / ENABLE_PER_HART;
+            if cntx >= self.num_context {
+                return AccessResult::Ok;
             }
-        }
-        else if ENABLE_BASE <= addr && addr < CONTEXT_BASE {
-            let cntx:u32 = (addr - ENABLE_BASE) / ENABLE_PER_HART;
-            addr -= cntx * ENABLE_PER_HART + ENABLE_BASE;
-            if cntx < self.num_context  {
-                if self.context_enable_read(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to read enable register");
-                    return false;
-                }
-            } 
-        }
-        else if CONTEXT_BASE <= addr && addr < REG_SIZE {
-            let cntx:u32 = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
-            addr -= cntx * CONTEXT_PER_HART + CONTEXT_BASE;
-            if cntx < self.num_context {
-               if self.context_read(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to read context");
-                    return false;
-               }
-            } 
-        }
-        
-        true
-    }
-
-    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
-        let mut addr = offset as u32;
-        addr &= !0x3;
-        if PRIORITY_BASE <= addr && addr < ENABLE_BASE {
-            if self.priority_write(addr, data).is_err() {
-                error!("Failed to write priority register");
-                return false;
+            let offset = addr - ENABLE_BASE - cntx * ENABLE_PER_HART;
+            self.context_enable_write(cntx as usize, offset, data)
+        } else if addr < REG_SIZE {
+            let cntx = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
+            if cntx >= self.num_context {
+                return AccessResult::Ok;
             }
+            let offset = addr - CONTEXT_BASE - cntx * CONTEXT_PER_HART;
+            self.context_write(cntx as usize, offset, data)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = ret {
+            error!("Failed to write PLIC register 0x{:x}: {:?}", addr, e);
+            return AccessResult::Failed;
         }
-        else if ENABLE_BASE <= addr && addr < CONTEXT_BASE {
-            let cntx:u32 = (addr - ENABLE_BASE) / ENABLE_PER_HART;
-            addr -= cntx * ENABLE_PER_HART + ENABLE_BASE;
-            if cntx < self.num_context {
-                if self.context_enable_write(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to write enable register");
-                    return false;
-                }
-            } 
-        }
-        else if CONTEXT_BASE <= addr && addr < REG_SIZE {
-            let cntx:u32 = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
-            addr -= cntx * CONTEXT_PER_HART + CONTEXT_BASE;
-            if cntx < self.num_context {
-                if self.context_write(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to write context");
-                    return false;
-                }
-            } 
-        }
-        true
+        AccessResult::Ok
     }
 
     fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
@@ -432,5 +492,344 @@ impl SysBusDevOps for PLIC {
     fn get_type(&self) -> SysBusDevType {
         SysBusDevType::Plic
     }
+
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        let mut state = Vec::new();
+        state.extend_from_slice(&self.num_context.to_le_bytes());
+        state.extend_from_slice(&self.irq_priority);
+        for words in [&self.irq_pending, &self.irq_claimed, &self.irq_level] {
+            words
+                .iter()
+                .for_each(|word| state.extend_from_slice(&word.to_le_bytes()));
+        }
+        for context in self.contexts.iter() {
+            state.push(context.irq_priority_threshold);
+            context
+                .irq_enable
+                .iter()
+                .for_each(|word| state.extend_from_slice(&word.to_le_bytes()));
+        }
+        let asserted = &self.line_sources.asserted;
+        state.extend_from_slice(&(asserted.len() as u32).to_le_bytes());
+        for (irq, sources) in asserted.iter() {
+            state.push(*irq);
+            state.extend_from_slice(&(sources.len() as u32).to_le_bytes());
+            sources
+                .iter()
+                .for_each(|source| state.extend_from_slice(&source.to_le_bytes()));
+        }
+        Ok(encode_state(PLIC_STATE_VERSION, &state))
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let (_, payload) = decode_state(data, PLIC_STATE_VERSION)?;
+        let mut reader = StateReader::new(payload);
+        let num_context = reader.read_u32()?;
+        if num_context != self.num_context {
+            bail!(
+                "PLIC state has {} contexts, expected {}",
+                num_context,
+                self.num_context
+            );
+        }
+        // Parse all of the state before touching the device.
+        let priority = reader.read_bytes(MAX_DEVICES as usize)?;
+        let mut bitmaps = [[0_u32; IRQ_WORDS]; 3];
+        for word in bitmaps.iter_mut().flatten() {
+            *word = reader.read_u32()?;
+        }
+        let mut contexts = Vec::with_capacity(self.contexts.len());
+        for _ in 0..self.contexts.len() {
+            let threshold = reader.read_bytes(1)?[0];
+            let mut enable = [0_u32; IRQ_WORDS];
+            for word in enable.iter_mut() {
+                *word = reader.read_u32()?;
+            }
+            contexts.push((threshold, enable));
+        }
+        let mut line_sources = IrqLineSources::default();
+        for _ in 0..reader.read_u32()? {
+            let irq = reader.read_bytes(1)?[0];
+            let sources = line_sources.asserted.entry(irq).or_default();
+            for _ in 0..reader.read_u32()? {
+                sources.insert(reader.read_u64()?);
+            }
+        }
+        if !reader.is_empty() {
+            bail!("Invalid PLIC state size {}", payload.len());
+        }
+
+        self.irq_priority.copy_from_slice(priority);
+        self.irq_pending = bitmaps[0];
+        self.irq_claimed = bitmaps[1];
+        self.irq_level = bitmaps[2];
+        for (context, (threshold, enable)) in self.contexts.iter_mut().zip(contexts) {
+            context.irq_priority_threshold = threshold;
+            context.irq_enable = enable;
+        }
+        self.line_sources = line_sources;
+        self.irq_update()
+    }
+
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        let node_dep = match begin_fdt_node(parent, SysBusDevType::Plic, &self.res)? {
+            Some(node_dep) => node_dep,
+            None => return Ok(()),
+        };
+        parent.set_property("interrupt-controller", &Vec::new())?;
+        parent.set_property_u32("#interrupt-cells", 0x1)?;
+        parent.set_property_u32("phandle", device_tree::PLIC_PHANDLE)?;
+        parent.set_property_u32("riscv,ndev", MAX_DEVICES - 1)?;
+
+        // M-mode contexts are not used by guest.
+        let mut irq_cells = Vec::new();
+        for hart in 0..self.num_context / 2 {
+            let intc = device_tree::INCT_PHANDLE_START + hart;
+            irq_cells.extend_from_slice(&[intc, 0xffff_ffff, intc, 9]);
+        }
+        parent.set_property_array_u32("interrupts-extended", &irq_cells)?;
+        parent.end_node(node_dep)?;
+        Ok(())
+    }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// S-mode contexts of hart 0 and hart 1.
+    const HART0: u32 = 1;
+    const HART1: u32 = 3;
+
+    fn create_plic(nr_harts: usize) -> PLIC {
+        let mut plic = PLIC::new();
+        plic.init_contexts(vec![None; nr_harts]);
+        plic.ready = true;
+        plic
+    }
+
+    fn write_reg(plic: &mut PLIC, offset: u32, val: u32) {
+        assert_eq!(
+            plic.write(&val.to_le_bytes(), GuestAddress(0), offset as u64),
+            AccessResult::Ok
+        );
+    }
+
+    fn read_reg(plic: &mut PLIC, offset: u32) -> u32 {
+        let mut data = [0_u8; 4];
+        assert_eq!(
+            plic.read(&mut data, GuestAddress(0), offset as u64),
+            AccessResult::Ok
+        );
+        u32::from_le_bytes(data)
+    }
+
+    fn set_priority(plic: &mut PLIC, irq: u32, priority: u32) {
+        write_reg(plic, irq * PRIORITY_PER_ID, priority);
+    }
+
+    fn set_enable(plic: &mut PLIC, context: u32, irq_word: u32, val: u32) {
+        write_reg(
+            plic,
+            ENABLE_BASE + context * ENABLE_PER_HART + irq_word * 4,
+            val,
+        );
+    }
+
+    fn context_reg(context: u32, reg: u32) -> u32 {
+        CONTEXT_BASE + context * CONTEXT_PER_HART + reg
+    }
+
+    fn claim(plic: &mut PLIC, context: u32) -> u32 {
+        read_reg(plic, context_reg(context, CONTEXT_CLAIM))
+    }
+
+    fn complete(plic: &mut PLIC, context: u32, irq: u32) {
+        write_reg(plic, context_reg(context, CONTEXT_CLAIM), irq);
+    }
+
+    #[test]
+    fn test_plic_priority() {
+        let mut plic = create_plic(1);
+
+        // Priority of source 0 is hardwired to zero, others are 3 bits wide.
+        set_priority(&mut plic, 0, 5);
+        assert_eq!(read_reg(&mut plic, 0), 0);
+        set_priority(&mut plic, 1, 0xff);
+        assert_eq!(read_reg(&mut plic, PRIORITY_PER_ID), 7);
+
+        // Sources pending at the same time are claimed from the highest priority,
+        // ties are broken by the lowest id.
+        set_priority(&mut plic, 3, 1);
+        set_priority(&mut plic, 5, 3);
+        set_priority(&mut plic, 7, 3);
+        set_enable(&mut plic, HART0, 0, 1 << 3 | 1 << 5 | 1 << 7);
+        for irq in [3, 5, 7] {
+            plic.kvm_irq_line(irq, 1).unwrap();
+        }
+        assert_eq!(read_reg(&mut plic, PENDING_BASE), 1 << 3 | 1 << 5 | 1 << 7);
+        assert_eq!(claim(&mut plic, HART0), 5);
+        assert_eq!(claim(&mut plic, HART0), 7);
+        assert_eq!(claim(&mut plic, HART0), 3);
+        assert_eq!(claim(&mut plic, HART0), 0);
+        assert_eq!(read_reg(&mut plic, PENDING_BASE), 0);
+        for irq in [3, 5, 7] {
+            plic.kvm_irq_line(irq as u8, 0).unwrap();
+            complete(&mut plic, HART0, irq);
+        }
+
+        // Source of priority 0 never interrupts.
+        set_priority(&mut plic, 3, 0);
+        plic.kvm_irq_line(3, 1).unwrap();
+        assert_eq!(claim(&mut plic, HART0), 0);
+        plic.kvm_irq_line(3, 0).unwrap();
+    }
+
+    #[test]
+    fn test_plic_state() {
+        let mut plic = create_plic(2);
+        set_priority(&mut plic, 2, 3);
+        set_priority(&mut plic, 33, 1);
+        set_enable(&mut plic, HART1, 0, 1 << 2);
+        set_enable(&mut plic, HART1, 1, 1 << 1);
+        write_reg(&mut plic, context_reg(HART1, CONTEXT_THRESHOLD), 1);
+        plic.set_irq_level(2, 0x1000, 1).unwrap();
+        plic.kvm_irq_trigger(33).unwrap();
+        assert_eq!(claim(&mut plic, HART1), 2);
+        let state = plic.state_bytes().unwrap();
+
+        // Claimed source stays claimed, and the pending one is delivered after restore.
+        let mut restored = create_plic(2);
+        restored.restore_state(&state).unwrap();
+        assert_eq!(read_reg(&mut restored, 2 * PRIORITY_PER_ID), 3);
+        assert_eq!(
+            read_reg(&mut restored, context_reg(HART1, CONTEXT_THRESHOLD)),
+            1
+        );
+        assert_eq!(claim(&mut restored, HART1), 0);
+        set_priority(&mut restored, 33, 2);
+        assert_eq!(claim(&mut restored, HART1), 33);
+
+        // Level of the shared line is kept, completed source is pending again.
+        complete(&mut restored, HART1, 2);
+        assert_eq!(claim(&mut restored, HART1), 2);
+        complete(&mut restored, HART1, 2);
+        restored.set_irq_level(2, 0x1000, 0).unwrap();
+        assert_eq!(claim(&mut restored, HART1), 0);
+
+        // Contexts must match, truncated state is refused without changing the device.
+        assert!(create_plic(1).restore_state(&state).is_err());
+        let (_, payload) = decode_state(&state, PLIC_STATE_VERSION).unwrap();
+        let truncated = encode_state(PLIC_STATE_VERSION, &payload[..payload.len() - 1]);
+        let mut plic = create_plic(2);
+        assert!(plic.restore_state(&truncated).is_err());
+        assert_eq!(read_reg(&mut plic, 2 * PRIORITY_PER_ID), 0);
+    }
+
+    #[test]
+    fn test_plic_threshold() {
+        let mut plic = create_plic(2);
+        set_priority(&mut plic, 2, 2);
+        set_priority(&mut plic, 4, 5);
+        set_enable(&mut plic, HART0, 0, 1 << 2 | 1 << 4);
+        set_enable(&mut plic, HART1, 0, 1 << 2 | 1 << 4);
+
+        // Threshold is 3 bits wide.
+        write_reg(&mut plic, context_reg(HART0, CONTEXT_THRESHOLD), 0xff);
+        assert_eq!(
+            read_reg(&mut plic, context_reg(HART0, CONTEXT_THRESHOLD)),
+            7
+        );
+
+        // Sources not above the threshold are masked for the context only.
+        write_reg(&mut plic, context_reg(HART0, CONTEXT_THRESHOLD), 2);
+        plic.kvm_irq_line(2, 1).unwrap();
+        plic.kvm_irq_line(4, 1).unwrap();
+        assert_eq!(claim(&mut plic, HART0), 4);
+        assert_eq!(claim(&mut plic, HART0), 0);
+        write_reg(&mut plic, context_reg(HART0, CONTEXT_THRESHOLD), 1);
+        assert_eq!(claim(&mut plic, HART0), 2);
+
+        write_reg(&mut plic, context_reg(HART0, CONTEXT_THRESHOLD), 7);
+        complete(&mut plic, HART0, 2);
+        complete(&mut plic, HART0, 4);
+        assert_eq!(claim(&mut plic, HART0), 0);
+        assert_eq!(claim(&mut plic, HART1), 4);
+        assert_eq!(claim(&mut plic, HART1), 2);
+    }
+
+    #[test]
+    fn test_plic_claim_complete() {
+        let mut plic = create_plic(2);
+        set_priority(&mut plic, 4, 1);
+        set_priority(&mut plic, 33, 1);
+        set_enable(&mut plic, HART0, 0, 1 << 4);
+        set_enable(&mut plic, HART0, 1, 1 << 1);
+
+        // A claimed level source is not presented again until completed, and it
+        // is pending again on completion while still asserted.
+        plic.kvm_irq_line(4, 1).unwrap();
+        assert_eq!(claim(&mut plic, HART0), 4);
+        assert_eq!(claim(&mut plic, HART0), 0);
+        complete(&mut plic, HART0, 4);
+        assert_eq!(claim(&mut plic, HART0), 4);
+
+        // Completion from context without the source enabled is ignored.
+        complete(&mut plic, HART1, 4);
+        assert_eq!(claim(&mut plic, HART0), 0);
+
+        // Deasserted before completion, the source isn't pending any more.
+        plic.kvm_irq_line(4, 0).unwrap();
+        complete(&mut plic, HART0, 4);
+        assert_eq!(claim(&mut plic, HART0), 0);
+
+        // Edge of claimed source is latched and presented after completion.
+        plic.kvm_irq_trigger(33).unwrap();
+        assert_eq!(read_reg(&mut plic, PENDING_BASE + 4), 1 << 1);
+        assert_eq!(claim(&mut plic, HART0), 33);
+        plic.kvm_irq_trigger(33).unwrap();
+        assert_eq!(claim(&mut plic, HART0), 0);
+        complete(&mut plic, HART0, 33);
+        assert_eq!(claim(&mut plic, HART0), 33);
+        complete(&mut plic, HART0, 33);
+        assert_eq!(claim(&mut plic, HART0), 0);
+
+        // Source disabled after it is raised stays pending until enabled again.
+        plic.kvm_irq_trigger(33).unwrap();
+        set_enable(&mut plic, HART0, 1, 0);
+        assert_eq!(
+            read_reg(&mut plic, ENABLE_BASE + HART0 * ENABLE_PER_HART + 4),
+            0
+        );
+        assert_eq!(claim(&mut plic, HART0), 0);
+        set_enable(&mut plic, HART0, 1, 1 << 1);
+        assert_eq!(claim(&mut plic, HART0), 33);
+    }
+
+    #[test]
+    fn test_context_mode() {
+        // Hart 0 owns contexts 0 (M-mode) and 1 (S-mode), hart 3 owns 6 and 7.
+        assert!(!is_smode_context(0));
+        assert!(is_smode_context(1));
+        assert!(!is_smode_context(6));
+        assert!(is_smode_context(7));
+    }
+
+    #[test]
+    fn test_shared_line_level() {
+        let mut sources = IrqLineSources::default();
+        assert_eq!(sources.set_level(5, 0x1000, 1), 1);
+        assert_eq!(sources.set_level(5, 0x2000, 1), 1);
+        // Line stays asserted until every sharer deasserts.
+        assert_eq!(sources.set_level(5, 0x1000, 0), 1);
+        assert_eq!(sources.set_level(5, 0x1000, 0), 1);
+        assert_eq!(sources.set_level(5, 0x2000, 0), 0);
+        assert!(sources.asserted.is_empty());
+
+        // Lines are independent of each other.
+        assert_eq!(sources.set_level(5, 0x1000, 1), 1);
+        assert_eq!(sources.set_level(6, 0x1000, 0), 0);
+        assert_eq!(sources.set_level(6, 0x2000, 1), 1);
+        assert_eq!(sources.set_level(5, 0x1000, 0), 0);
+    }
+}
@@ -10,9 +10,11 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeMap;
 use std::fs::{read_link, File, OpenOptions};
-use std::io::{Stdin, Stdout};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::io::{Sink, Stdin, Stdout};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -24,6 +26,7 @@ use log::{error, info};
 use machine_manager::machine::{PathInfo, PTY_PATH};
 use machine_manager::{
     config::{ChardevConfig, ChardevType},
+    qmp::qmp_schema::ChardevInfo,
     temp_cleaner::TempCleaner,
 };
 use util::loop_context::{
@@ -48,8 +51,8 @@ pub struct Chardev {
     pub id: String,
     /// Type of backend device.
     pub backend: ChardevType,
-    /// UnixListener for socket-type chardev.
-    pub listener: Option<UnixListener>,
+    /// Listener for server socket-type chardev.
+    pub listener: Option<ChardevListener>,
     /// Chardev input.
     pub input: Option<Arc<Mutex<dyn CommunicatInInterface>>>,
     /// Chardev output.
@@ -62,6 +65,23 @@ pub struct Chardev {
     receive: ReceFn,
     /// Return the remain space size of receiver buffer.
     get_remain_space_size: Option<Arc<dyn Fn() -> usize + Send + Sync>>,
+    /// Path of the slave of pty-type chardev.
+    pty_path: Option<PathBuf>,
+}
+
+/// Listener of server socket-type chardev.
+pub enum ChardevListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl AsRawFd for ChardevListener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ChardevListener::Unix(listener) => listener.as_raw_fd(),
+            ChardevListener::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
 }
 
 impl Chardev {
@@ -76,6 +96,7 @@ impl Chardev {
             deactivated: false,
             receive: None,
             get_remain_space_size: None,
+            pty_path: None,
         }
     }
 
@@ -95,6 +116,7 @@ impl Chardev {
                     label: self.id.clone(),
                 };
                 PTY_PATH.lock().unwrap().push(path_info);
+                self.pty_path = Some(path);
                 // Safe because `master_arc` is the only one owner for the file descriptor.
                 let master_arc = unsafe { Arc::new(Mutex::new(File::from_raw_fd(master))) };
                 self.input = Some(master_arc.clone());
@@ -102,41 +124,134 @@ impl Chardev {
             }
             ChardevType::Socket {
                 path,
-                server,
-                nowait,
+                server: false,
+                ..
             } => {
-                if !*server || !*nowait {
-                    bail!(
-                        "Argument \'server\' and \'nowait\' are both required for chardev \'{}\'",
-                        path
-                    );
-                }
+                let stream = UnixStream::connect(path).with_context(|| {
+                    format!("Failed to connect socket for chardev, path:{}", path)
+                })?;
+                self.set_stream(stream);
+            }
+            ChardevType::Socket { path, nowait, .. } => {
+                let (path, nowait) = (path.clone(), *nowait);
                 let sock = UnixListener::bind(path.clone())
                     .with_context(|| format!("Failed to bind socket for chardev, path:{}", path))?;
-                self.listener = Some(sock);
                 // add file to temporary pool, so it could be cleaned when vm exit.
                 TempCleaner::add_path(path.clone());
-                limit_permission(path).with_context(|| {
+                limit_permission(&path).with_context(|| {
                     format!(
                         "Failed to change file permission for chardev, path:{}",
                         path
                     )
                 })?;
+                if !nowait {
+                    info!("Chardev {} waits for client on {}", self.id, path);
+                    let (stream, _) = sock.accept()?;
+                    self.set_stream(stream);
+                }
+                self.listener = Some(ChardevListener::Unix(sock));
+            }
+            ChardevType::TcpSocket {
+                host,
+                port,
+                server: false,
+                ..
+            } => {
+                let stream = TcpStream::connect((host.as_str(), *port)).with_context(|| {
+                    format!("Failed to connect socket for chardev, {}:{}", host, port)
+                })?;
+                self.set_stream(stream);
+            }
+            ChardevType::TcpSocket {
+                host, port, nowait, ..
+            } => {
+                let (host, port, nowait) = (host.clone(), *port, *nowait);
+                let sock = TcpListener::bind((host.as_str(), port)).with_context(|| {
+                    format!("Failed to bind socket for chardev, {}:{}", host, port)
+                })?;
+                if !nowait {
+                    info!("Chardev {} waits for client on {}:{}", self.id, host, port);
+                    let (stream, _) = sock.accept()?;
+                    self.set_stream(stream);
+                }
+                self.listener = Some(ChardevListener::Tcp(sock));
             }
             ChardevType::File(path) => {
                 let file = Arc::new(Mutex::new(
                     OpenOptions::new()
                         .read(true)
-                        .write(true)
+                        .append(true)
                         .create(true)
-                        .open(path)?,
+                        .open(path)
+                        .with_context(|| {
+                            format!("Failed to open file for chardev, path:{}", path)
+                        })?,
                 ));
                 self.output = Some(file);
             }
+            ChardevType::Null => {
+                self.output = Some(Arc::new(Mutex::new(std::io::sink())));
+            }
         };
         Ok(())
     }
 
+    /// Use the connected `stream` as input and output, return its fd.
+    fn set_stream<T>(&mut self, stream: T) -> RawFd
+    where
+        T: 'static + CommunicatInInterface + CommunicatOutInterface,
+    {
+        let stream_fd = stream.as_raw_fd();
+        let stream_arc = Arc::new(Mutex::new(stream));
+        self.stream_fd = Some(stream_fd);
+        self.input = Some(stream_arc.clone());
+        self.output = Some(stream_arc);
+        stream_fd
+    }
+
+    /// Accept a client of the listener, return the fd of its stream.
+    fn accept(&mut self) -> Result<RawFd> {
+        let stream_fd = match self.listener.as_ref() {
+            Some(ChardevListener::Unix(listener)) => {
+                let (stream, _) = listener.accept()?;
+                self.set_stream(stream)
+            }
+            Some(ChardevListener::Tcp(listener)) => {
+                let (stream, _) = listener.accept()?;
+                self.set_stream(stream)
+            }
+            None => bail!("Chardev {} is not a server", self.id),
+        };
+        Ok(stream_fd)
+    }
+
+    /// Path of the slave of pty-type chardev.
+    pub fn pty_path(&self) -> Option<String> {
+        self.pty_path
+            .as_ref()
+            .map(|path| path.to_string_lossy().to_string())
+    }
+
+    /// Describe the backend as `filename` of `query-chardev`.
+    pub fn filename(&self) -> String {
+        let server = |server: bool| if server { ",server=on" } else { "" };
+        match &self.backend {
+            ChardevType::Stdio => "stdio".to_string(),
+            ChardevType::Pty => format!("pty:{}", self.pty_path().unwrap_or_default()),
+            ChardevType::Socket {
+                path, server: s, ..
+            } => format!("unix:{}{}", path, server(*s)),
+            ChardevType::TcpSocket {
+                host,
+                port,
+                server: s,
+                ..
+            } => format!("tcp:{}:{}{}", host, port, server(*s)),
+            ChardevType::File(path) => format!("file:{}", path),
+            ChardevType::Null => "null".to_string(),
+        }
+    }
+
     pub fn set_input_callback<T: 'static + InputReceiver>(&mut self, dev: &Arc<Mutex<T>>) {
         let cloned_dev = dev.clone();
         self.receive = Some(Arc::new(move |data: &[u8]| {
@@ -224,60 +339,74 @@ fn get_notifier_handler(
             }
             None
         }),
-        ChardevType::Socket { .. } => Rc::new(move |_, _| {
+        ChardevType::Socket { .. } | ChardevType::TcpSocket { .. } => Rc::new(move |_, _| {
             let mut locked_chardev = chardev.lock().unwrap();
             if locked_chardev.deactivated {
                 return None;
             }
-            let (stream, _) = locked_chardev.listener.as_ref().unwrap().accept().unwrap();
             let listener_fd = locked_chardev.listener.as_ref().unwrap().as_raw_fd();
-            let stream_fd = stream.as_raw_fd();
-            locked_chardev.stream_fd = Some(stream_fd);
-            let stream_arc = Arc::new(Mutex::new(stream));
-            locked_chardev.input = Some(stream_arc.clone());
-            locked_chardev.output = Some(stream_arc);
-
-            let cloned_chardev = chardev.clone();
-            let inner_handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
-                let mut locked_chardev = cloned_chardev.lock().unwrap();
-                if event == EventSet::IN {
-                    if locked_chardev.deactivated {
-                        return None;
-                    }
-                    let buff_size = locked_chardev.get_remain_space_size.as_ref().unwrap()();
-                    let mut buffer = vec![0_u8; buff_size];
-                    if let Some(input) = locked_chardev.input.clone() {
-                        if let Ok(index) = input.lock().unwrap().chr_read_raw(&mut buffer) {
-                            locked_chardev.receive.as_ref().unwrap()(&mut buffer[..index]);
-                        } else {
-                            error!("Failed to read input data");
-                        }
-                    } else {
-                        error!("Failed to get chardev input fd");
-                    }
-                    None
-                } else if event & EventSet::HANG_UP == EventSet::HANG_UP {
-                    // Always allow disconnect even if has deactivated.
-                    locked_chardev.input = None;
-                    locked_chardev.output = None;
-                    locked_chardev.stream_fd = None;
-                    Some(gen_delete_notifiers(&[stream_fd]))
-                } else {
-                    None
+            let stream_fd = match locked_chardev.accept() {
+                Ok(fd) => fd,
+                Err(e) => {
+                    error!("Failed to accept client of chardev: {:?}", e);
+                    return None;
                 }
-            });
-            Some(vec![EventNotifier::new(
-                NotifierOperation::AddShared,
+            };
+            drop(locked_chardev);
+            Some(vec![stream_notifier(
+                chardev.clone(),
                 stream_fd,
                 Some(listener_fd),
-                EventSet::IN | EventSet::HANG_UP,
-                vec![inner_handler],
             )])
         }),
-        ChardevType::File(_) => Rc::new(move |_, _| None),
+        ChardevType::File(_) | ChardevType::Null => Rc::new(move |_, _| None),
     }
 }
 
+/// Notifier of the connected stream of socket-type chardev, the listener is parked
+/// until the stream hangs up.
+fn stream_notifier(
+    chardev: Arc<Mutex<Chardev>>,
+    stream_fd: RawFd,
+    listener_fd: Option<RawFd>,
+) -> EventNotifier {
+    let handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
+        let mut locked_chardev = chardev.lock().unwrap();
+        if event == EventSet::IN {
+            if locked_chardev.deactivated {
+                return None;
+            }
+            let buff_size = locked_chardev.get_remain_space_size.as_ref().unwrap()();
+            let mut buffer = vec![0_u8; buff_size];
+            if let Some(input) = locked_chardev.input.clone() {
+                if let Ok(index) = input.lock().unwrap().chr_read_raw(&mut buffer) {
+                    locked_chardev.receive.as_ref().unwrap()(&mut buffer[..index]);
+                } else {
+                    error!("Failed to read input data");
+                }
+            } else {
+                error!("Failed to get chardev input fd");
+            }
+            None
+        } else if event & EventSet::HANG_UP == EventSet::HANG_UP {
+            // Always allow disconnect even if has deactivated.
+            locked_chardev.input = None;
+            locked_chardev.output = None;
+            locked_chardev.stream_fd = None;
+            Some(gen_delete_notifiers(&[stream_fd]))
+        } else {
+            None
+        }
+    });
+    EventNotifier::new(
+        NotifierOperation::AddShared,
+        stream_fd,
+        listener_fd,
+        EventSet::IN | EventSet::HANG_UP,
+        vec![handler],
+    )
+}
+
 impl EventNotifierHelper for Chardev {
     fn internal_notifiers(chardev: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut notifiers = Vec::new();
@@ -295,15 +424,17 @@ impl EventNotifierHelper for Chardev {
                     ));
                 }
             }
-            ChardevType::Socket { .. } => {
-                if chardev.lock().unwrap().stream_fd.is_some() {
-                    notifiers.push(EventNotifier::new(
-                        NotifierOperation::Resume,
-                        chardev.lock().unwrap().stream_fd.unwrap(),
-                        None,
-                        EventSet::IN | EventSet::HANG_UP,
-                        Vec::new(),
-                    ));
+            ChardevType::Socket { .. } | ChardevType::TcpSocket { .. } => {
+                // The stream is connected already if it's a client or waited for client.
+                let stream_fd = chardev.lock().unwrap().stream_fd;
+                if let Some(stream_fd) = stream_fd {
+                    let listener_fd = chardev
+                        .lock()
+                        .unwrap()
+                        .listener
+                        .as_ref()
+                        .map(|listener| listener.as_raw_fd());
+                    notifiers.push(stream_notifier(cloned_chardev, stream_fd, listener_fd));
                 } else if let Some(listener) = chardev.lock().unwrap().listener.as_ref() {
                     notifiers.push(EventNotifier::new(
                         NotifierOperation::AddShared,
@@ -314,7 +445,7 @@ impl EventNotifierHelper for Chardev {
                     ));
                 }
             }
-            ChardevType::File(_) => (),
+            ChardevType::File(_) | ChardevType::Null => (),
         }
         notifiers
     }
@@ -337,9 +468,168 @@ pub trait CommunicatInInterface: std::marker::Send + std::os::unix::io::AsRawFd
 pub trait CommunicatOutInterface: std::io::Write + std::marker::Send {}
 
 impl CommunicatInInterface for UnixStream {}
+impl CommunicatInInterface for TcpStream {}
 impl CommunicatInInterface for File {}
 impl CommunicatInInterface for Stdin {}
 
 impl CommunicatOutInterface for UnixStream {}
+impl CommunicatOutInterface for TcpStream {}
 impl CommunicatOutInterface for File {}
 impl CommunicatOutInterface for Stdout {}
+impl CommunicatOutInterface for Sink {}
+
+/// A chardev registered in `ChardevRegistry`.
+struct RegisteredChardev {
+    chardev: Arc<Mutex<Chardev>>,
+    /// Id of the device using the chardev.
+    frontend: Option<String>,
+}
+
+/// Character devices of vm keyed by id, each of them is used by at most one device.
+#[derive(Default)]
+pub struct ChardevRegistry {
+    chardevs: BTreeMap<String, RegisteredChardev>,
+}
+
+impl ChardevRegistry {
+    /// Check whether chardev `id` is registered or not.
+    pub fn contains(&self, id: &str) -> bool {
+        self.chardevs.contains_key(id)
+    }
+
+    /// Register a realized chardev.
+    ///
+    /// # Arguments
+    ///
+    /// * `chardev` - The chardev to register.
+    /// * `frontend` - Id of the device using the chardev, `None` if it's unused.
+    pub fn register(&mut self, chardev: Arc<Mutex<Chardev>>, frontend: Option<&str>) -> Result<()> {
+        let id = chardev.lock().unwrap().id.clone();
+        if self.contains(&id) {
+            bail!("Chardev {} already exists", id);
+        }
+        self.chardevs.insert(
+            id,
+            RegisteredChardev {
+                chardev,
+                frontend: frontend.map(String::from),
+            },
+        );
+        Ok(())
+    }
+
+    /// Unregister an unused chardev and close it.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the chardev.
+    pub fn unregister(&mut self, id: &str) -> Result<()> {
+        match self.chardevs.get(id) {
+            None => bail!("Chardev {} not found", id),
+            Some(RegisteredChardev {
+                frontend: Some(frontend),
+                ..
+            }) => bail!("Chardev {} is busy, it's used by device {}", id, frontend),
+            Some(_) => (),
+        }
+        let registered = self.chardevs.remove(id).unwrap();
+        let locked_chardev = registered.chardev.lock().unwrap();
+        match &locked_chardev.backend {
+            ChardevType::Socket {
+                path, server: true, ..
+            } => {
+                if let Err(e) = std::fs::remove_file(path) {
+                    error!("Failed to remove socket of chardev {}: {:?}", id, e);
+                }
+            }
+            ChardevType::Pty => PTY_PATH.lock().unwrap().retain(|info| info.label != id),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Information of registered chardevs for `query-chardev`.
+    pub fn query(&self) -> Vec<ChardevInfo> {
+        self.chardevs
+            .iter()
+            .map(|(id, registered)| ChardevInfo {
+                open: registered.frontend.is_some(),
+                filename: registered.chardev.lock().unwrap().filename(),
+                label: id.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    fn null_chardev(id: &str) -> Arc<Mutex<Chardev>> {
+        let mut chardev = Chardev::new(ChardevConfig {
+            id: id.to_string(),
+            backend: ChardevType::Null,
+        });
+        chardev.realize().unwrap();
+        Arc::new(Mutex::new(chardev))
+    }
+
+    #[test]
+    fn test_chardev_registry() {
+        let mut registry = ChardevRegistry::default();
+        registry
+            .register(null_chardev("chr0"), Some("serial"))
+            .unwrap();
+        registry.register(null_chardev("chr1"), None).unwrap();
+        assert!(registry.register(null_chardev("chr1"), None).is_err());
+
+        let info = registry.query();
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[0].label, "chr0");
+        assert!(info[0].open);
+        assert_eq!(info[1].filename, "null");
+        assert!(!info[1].open);
+
+        // Chardev used by device can't be removed.
+        assert!(registry.unregister("chr0").is_err());
+        assert!(registry.unregister("chr1").is_ok());
+        assert!(registry.unregister("chr1").is_err());
+        assert!(!registry.contains("chr1"));
+    }
+
+    #[test]
+    fn test_chardev_socket_client() {
+        let path = format!("/tmp/test_chardev_{}.sock", std::process::id());
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let mut chardev = Chardev::new(ChardevConfig {
+            id: "chr0".to_string(),
+            backend: ChardevType::Socket {
+                path: path.clone(),
+                server: false,
+                nowait: false,
+            },
+        });
+        chardev.realize().unwrap();
+        assert!(chardev.stream_fd.is_some());
+        assert_eq!(chardev.filename(), format!("unix:{}", path));
+
+        let (mut server, _) = listener.accept().unwrap();
+        chardev
+            .output
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .write_all(b"hello")
+            .unwrap();
+        let mut buf = [0_u8; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -41,12 +41,16 @@ pub enum LegacyError {
     FileSlotsNotAvailable(String),
     #[error("Failed to read DMA request, dma_addr=0x{0:x} size=0x{1:x}")]
     ReadDmaRequest(u64, u64),
+    #[error("DMA range of FwCfg is out of guest memory, gpa=0x{0:x} len=0x{1:x}")]
+    DmaOutOfMemory(u64, u64),
     #[error("Invalid FwCfg entry key {0}")]
     InvalidFwCfgEntry(u16),
     #[error("Flash size is 0x{0:x}, offset 0x{1:x} and size 0x{2:x} in write request overflows")]
     PFlashWriteOverflow(u64, u64, u64),
     #[error("Flash size is 0x{0:x}, offset 0x{1:x} and size 0x{2:x} in read request overflows")]
     PFlashReadOverflow(u64, u64, u64),
+    #[error("Flash image size 0x{0:x} exceeds size 0x{1:x} of flash bank")]
+    PFlashFileSizeErr(u64, u64),
     #[error("Failed to seek to offset 0x{0:x} of PFlash file")]
     PFlashFileSeekErr(u64),
     #[error("Flash CFI table len is 0x{0:x}, request 0x{1:x} overflows")]
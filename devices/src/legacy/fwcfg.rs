@@ -19,7 +19,7 @@ use anyhow::{anyhow, bail, Context, Result};
 use byteorder::LittleEndian;
 use byteorder::{BigEndian, ByteOrder};
 use log::{error, warn};
-use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use sysbus::{AccessResult, IrqMode, SysBus, SysBusDevOps, SysBusDevType, SysBusResult, SysRes};
 use util::byte_code::ByteCode;
 use util::num_ops::extract_u64;
 use util::offset_of;
@@ -240,6 +240,15 @@ struct FwCfgDmaAccess {
 
 impl ByteCode for FwCfgDmaAccess {}
 
+/// Check that DMA range is inside guest RAM, guest can't make FwCfg access MMIO
+/// regions (including its own) or unmapped addresses by DMA.
+fn check_dma_range(addr_space: &Arc<AddressSpace>, addr: GuestAddress, len: u64) -> Result<()> {
+    if !addr_space.address_in_memory(addr, len) {
+        return Err(anyhow!(LegacyError::DmaOutOfMemory(addr.0, len)));
+    }
+    Ok(())
+}
+
 /// write data to DMA memory zone
 fn write_dma_memory(
     addr_space: &Arc<AddressSpace>,
@@ -247,6 +256,7 @@ fn write_dma_memory(
     mut buf: &[u8],
     len: u64,
 ) -> Result<()> {
+    check_dma_range(addr_space, addr, len)?;
     addr_space.write(&mut buf, addr, len).with_context(|| {
         format!(
             "Failed to write dma memory of fwcfg at gpa=0x{:x} len=0x{:x}",
@@ -264,6 +274,7 @@ fn read_dma_memory(
     mut buf: &mut [u8],
     len: u64,
 ) -> Result<()> {
+    check_dma_range(addr_space, addr, len)?;
     addr_space.read(&mut buf, addr, len).with_context(|| {
         format!(
             "Failed to read dma memory of fwcfg at gpa=0x{:x} len=0x{:x}",
@@ -649,7 +660,7 @@ impl FwCfgCommon {
                 offset += len;
             }
             dma.length -= len;
-            dma.address += len as u64
+            dma.address = dma.address.wrapping_add(len as u64);
         }
 
         self.cur_offset = offset;
@@ -819,15 +830,16 @@ fn common_read(
     data: &mut [u8],
     base: GuestAddress,
     offset: u64,
-) -> bool {
+) -> AccessResult {
     let data_len = data.len();
     let io_count = get_io_count(data_len);
     for i in (0..data_len).step_by(io_count) {
-        if !read_bytes(fwcfg_arch, &mut data[i..i + io_count], base, offset) {
-            return false;
+        let ret = read_bytes(fwcfg_arch, &mut data[i..i + io_count], base, offset);
+        if !ret.is_ok() {
+            return ret;
         }
     }
-    true
+    AccessResult::Ok
 }
 
 pub struct FwCfgMem {
@@ -856,7 +868,7 @@ impl FwCfgMem {
 
         let dev = Arc::new(Mutex::new(self));
         sysbus
-            .attach_device(&dev, region_base, region_size)
+            .attach_device(&dev, Some(region_base), region_size)
             .with_context(|| "Failed to attach FwCfg device to system bus.")?;
         Ok(dev)
     }
@@ -866,7 +878,7 @@ fn read_bytes(
     data: &mut [u8],
     _base: GuestAddress,
     offset: u64,
-) -> bool {
+) -> AccessResult {
     let value = match offset {
         0..=7 => match fwcfg_arch.fwcfg.read_data_reg(offset, data.len() as u32) {
             Ok(val) => val,
@@ -875,7 +887,7 @@ fn read_bytes(
                     "{}",
                     format!("Failed to read from FwCfg data register, error is {:?}", e)
                 );
-                return false;
+                return AccessResult::Failed;
             }
         },
         8..=15 => {
@@ -900,14 +912,11 @@ fn read_bytes(
                         "{}",
                         format!("Failed to handle FwCfg DMA-read, error is {:?}", e)
                     );
-                    return false;
+                    return AccessResult::Failed;
                 }
             }
         }
-        _ => {
-            error!("Failed to read FwCfg, offset 0x{:x} is invalid", offset);
-            return false;
-        }
+        _ => return AccessResult::BadOffset,
     };
 
     match data.len() {
@@ -917,7 +926,7 @@ fn read_bytes(
         8 => BigEndian::write_u64(data, value as u64),
         _ => {}
     }
-    true
+    AccessResult::Ok
 }
 
 impl FwCfgOps for FwCfgMem {
@@ -927,11 +936,11 @@ impl FwCfgOps for FwCfgMem {
 }
 
 impl SysBusDevOps for FwCfgMem {
-    fn read(&mut self, data: &mut [u8], base: GuestAddress, offset: u64) -> bool {
+    fn read(&mut self, data: &mut [u8], base: GuestAddress, offset: u64) -> AccessResult {
         common_read(self, data, base, offset)
     }
 
-    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
         let size = data.len() as u32;
         let value = match size {
             1 => data[0] as u64,
@@ -955,15 +964,12 @@ impl SysBusDevOps for FwCfgMem {
                     .is_err()
                 {
                     error!("Failed to write dma at offset=0x{:x}.", offset);
-                    return false;
+                    return AccessResult::Failed;
                 }
             }
-            _ => {
-                error!("Failed to write FwCfg, offset 0x{:x} is invalid", offset);
-                return false;
-            }
+            _ => return AccessResult::BadOffset,
         }
-        true
+        AccessResult::Ok
     }
 
     fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
@@ -975,11 +981,11 @@ impl SysBusDevOps for FwCfgMem {
         _sysbus: &mut SysBus,
         region_base: u64,
         region_size: u64,
-    ) -> sysbus::Result<()> {
+    ) -> SysBusResult<IrqMode> {
         let mut res = self.get_sys_resource().unwrap();
         res.region_base = region_base;
         res.region_size = region_size;
-        Ok(())
+        Ok(IrqMode::None)
     }
 
     /// Get device type.
@@ -1077,13 +1083,14 @@ mod test {
         let sys_io = AddressSpace::new(Region::init_container_region(1 << 16)).unwrap();
         let free_irqs: (i32, i32) = (IRQ_BASE, IRQ_MAX);
         let mmio_region: (u64, u64) = (0x0A00_0000, 0x1000_0000);
-        SysBus::new(
+        SysBus::new_single_window(
             #[cfg(target_arch = "x86_64")]
             &sys_io,
             &sys_mem,
             free_irqs,
             mmio_region,
         )
+        .unwrap()
     }
 
     fn address_space_init() -> Arc<AddressSpace> {
@@ -1313,6 +1320,41 @@ mod test {
         assert_eq!(read_dma_buf, all_zero);
     }
 
+    #[test]
+    fn test_dma_out_of_memory() {
+        let sys_mem = address_space_init();
+        let mut fwcfg_common = FwCfgCommon::new(sys_mem);
+        assert_eq!(fwcfg_common.common_realize().is_ok(), true);
+
+        // Signature is read to the end of guest memory, which overflows.
+        let mut dma_req = FwCfgDmaAccess::default();
+        dma_req.length = *u32::from_bytes(&4_u32.to_be_bytes()).unwrap();
+        dma_req.address = *u64::from_bytes(&0x0fff_fffe_u64.to_be_bytes()).unwrap();
+        dma_req.control = *u32::from_bytes(&FW_CFG_DMA_CTL_READ.to_be_bytes()).unwrap();
+        let dma_request = dma_req.as_mut_bytes();
+        let addr = GuestAddress(0x0000);
+        fwcfg_common
+            .mem_space
+            .write(&mut dma_request.as_ref(), addr, dma_request.len() as u64)
+            .unwrap();
+        fwcfg_common.dma_addr = addr;
+        assert_eq!(fwcfg_common.handle_dma_request().is_ok(), true);
+        assert_eq!(
+            fwcfg_common.mem_space.read_object::<u32>(addr).unwrap(),
+            FW_CFG_DMA_CTL_ERROR.to_be()
+        );
+        let mut read_dma_buf = Vec::new();
+        fwcfg_common
+            .mem_space
+            .read(&mut read_dma_buf, GuestAddress(0x0fff_fffe), 2)
+            .unwrap();
+        assert_eq!(read_dma_buf, vec![0_u8; 2]);
+
+        // DMA request itself is out of guest memory.
+        fwcfg_common.dma_addr = GuestAddress(0x1000_0000);
+        assert!(fwcfg_common.handle_dma_request().is_err());
+    }
+
     #[test]
     #[cfg(target_arch = "aarch64")]
     fn test_read_write_aarch64() {
@@ -1353,6 +1395,37 @@ mod test {
         assert_eq!(f_back, false);
     }
 
+    #[test]
+    #[cfg(target_arch = "riscv64")]
+    fn test_read_write_riscv64() {
+        let mut sys_bus = sysbus_init();
+        let sys_mem = address_space_init();
+        let fwcfg = FwCfgMem::new(sys_mem);
+
+        let fwcfg_dev = FwCfgMem::realize(fwcfg, &mut sys_bus, 0x1010_0000, 0x0000_0018).unwrap();
+        // Read FW_CFG_DMA_SIGNATURE entry.
+        let base = GuestAddress(0x0000);
+        let mut read_data = vec![0xff_u8, 0xff, 0xff, 0xff];
+        let target_data = vec![0x51_u8, 0x45, 0x4d, 0x55];
+        let ret = fwcfg_dev.lock().unwrap().read(&mut read_data, base, 0x10);
+        assert_eq!(ret, AccessResult::Ok);
+        assert_eq!(read_data, target_data);
+
+        // Select entry and read it.
+        let write_data = vec![0x0_u8, 0x0];
+        let ret = fwcfg_dev.lock().unwrap().write(&write_data, base, 0x8);
+        assert_eq!(ret, AccessResult::Ok);
+        let mut read_data = vec![0xff_u8, 0xff, 0xff, 0xff];
+        let sig_entry_data = [b'Q', b'E', b'M', b'U'];
+        let ret = fwcfg_dev.lock().unwrap().read(&mut read_data, base, 0x0);
+        assert_eq!(ret, AccessResult::Ok);
+        assert_eq!(read_data, sig_entry_data);
+
+        // Offset is out of registers.
+        let ret = fwcfg_dev.lock().unwrap().write(&write_data, base, 0x18);
+        assert_eq!(ret, AccessResult::BadOffset);
+    }
+
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_read_write_x86_64() {
@@ -0,0 +1,437 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use address_space::GuestAddress;
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::error;
+use sysbus::{
+    begin_fdt_node, decode_state, encode_state, AccessResult, SysBus, SysBusDevOps, SysBusDevType,
+    SysBusIrqLine, SysRes,
+};
+use util::device_tree::{self, FdtBuilder};
+
+use super::error::LegacyError;
+use crate::timer::DeviceTimer;
+
+/// Registers of SiFive GPIO controller, refer to linux `drivers/gpio/gpio-sifive.c`.
+const GPIO_INPUT_VAL: u64 = 0x00;
+const GPIO_INPUT_EN: u64 = 0x04;
+const GPIO_OUTPUT_EN: u64 = 0x08;
+const GPIO_OUTPUT_VAL: u64 = 0x0c;
+const GPIO_PUE: u64 = 0x10;
+const GPIO_DS: u64 = 0x14;
+const GPIO_RISE_IE: u64 = 0x18;
+const GPIO_RISE_IP: u64 = 0x1c;
+const GPIO_FALL_IE: u64 = 0x20;
+const GPIO_FALL_IP: u64 = 0x24;
+const GPIO_HIGH_IE: u64 = 0x28;
+const GPIO_HIGH_IP: u64 = 0x2c;
+const GPIO_LOW_IE: u64 = 0x30;
+const GPIO_LOW_IP: u64 = 0x34;
+const GPIO_IOF_EN: u64 = 0x38;
+const GPIO_IOF_SEL: u64 = 0x3c;
+const GPIO_OUT_XOR: u64 = 0x40;
+
+/// Number of gpio lines, each of which has its own interrupt. Linux counts the lines by
+/// interrupts, so only the line of power button is described.
+const GPIO_NR_LINES: u32 = 1;
+const GPIO_LINE_MASK: u32 = (1 << GPIO_NR_LINES) - 1;
+/// Line which power button is wired to, active high.
+const POWER_BUTTON_LINE: u32 = 0;
+/// `KEY_POWER` of linux input event codes, which systemd-logind shuts down guest on.
+const KEY_POWER: u32 = 116;
+/// Time power button is held down, which must be longer than debounce of gpio-keys.
+const POWER_BUTTON_HOLD_NS: u64 = 100_000_000;
+
+/// Version of gpio state saved by `state_bytes`.
+const GPIO_STATE_VERSION: u32 = 1;
+/// Size of gpio state: external pin levels and the 17 registers.
+const GPIO_STATE_SIZE: usize = 72;
+
+/// Registers and pins shared between gpio and the timer releasing power button.
+#[derive(Default)]
+struct GpioState {
+    /// Levels driven on the pins from outside, e.g. by power button.
+    pins: u32,
+    input_val: u32,
+    input_en: u32,
+    output_en: u32,
+    output_val: u32,
+    pue: u32,
+    ds: u32,
+    rise_ie: u32,
+    rise_ip: u32,
+    fall_ie: u32,
+    fall_ip: u32,
+    high_ie: u32,
+    high_ip: u32,
+    low_ie: u32,
+    low_ip: u32,
+    iof_en: u32,
+    iof_sel: u32,
+    out_xor: u32,
+    /// Vm is paused, power button is held until it resumes.
+    paused: bool,
+    timer: DeviceTimer,
+    irq_line: Option<SysBusIrqLine>,
+}
+
+impl GpioState {
+    /// Sample the pins, latch their edges and levels into pending bits, and update the
+    /// interrupt. Lines driven as output read back what is driven.
+    fn update(&mut self) {
+        let driven = (self.output_val ^ self.out_xor) & self.output_en;
+        let level = (driven | (self.pins & !self.output_en)) & GPIO_LINE_MASK;
+        let input_val = level & self.input_en;
+        self.rise_ip |= input_val & !self.input_val;
+        self.fall_ip |= !input_val & self.input_val & GPIO_LINE_MASK;
+        self.high_ip |= input_val;
+        self.low_ip |= !input_val & GPIO_LINE_MASK;
+        self.input_val = input_val;
+
+        let pending = (self.rise_ip & self.rise_ie)
+            | (self.fall_ip & self.fall_ie)
+            | (self.high_ip & self.high_ie)
+            | (self.low_ip & self.low_ie);
+        if let Some(line) = &self.irq_line {
+            if let Err(e) = line.set_level(pending & (1 << POWER_BUTTON_LINE) != 0) {
+                error!("Failed to update gpio interrupt: {:?}", e);
+            }
+        }
+    }
+
+    fn set_pin(&mut self, line: u32, level: bool) {
+        if level {
+            self.pins |= 1 << line;
+        } else {
+            self.pins &= !(1 << line);
+        }
+        self.update();
+    }
+
+    fn reg_mut(&mut self, offset: u64) -> Option<&mut u32> {
+        let reg = match offset {
+            GPIO_INPUT_VAL => &mut self.input_val,
+            GPIO_INPUT_EN => &mut self.input_en,
+            GPIO_OUTPUT_EN => &mut self.output_en,
+            GPIO_OUTPUT_VAL => &mut self.output_val,
+            GPIO_PUE => &mut self.pue,
+            GPIO_DS => &mut self.ds,
+            GPIO_RISE_IE => &mut self.rise_ie,
+            GPIO_RISE_IP => &mut self.rise_ip,
+            GPIO_FALL_IE => &mut self.fall_ie,
+            GPIO_FALL_IP => &mut self.fall_ip,
+            GPIO_HIGH_IE => &mut self.high_ie,
+            GPIO_HIGH_IP => &mut self.high_ip,
+            GPIO_LOW_IE => &mut self.low_ie,
+            GPIO_LOW_IP => &mut self.low_ip,
+            GPIO_IOF_EN => &mut self.iof_en,
+            GPIO_IOF_SEL => &mut self.iof_sel,
+            GPIO_OUT_XOR => &mut self.out_xor,
+            _ => return None,
+        };
+        Some(reg)
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) -> AccessResult {
+        let value = value & GPIO_LINE_MASK;
+        match offset {
+            // Input value is read only.
+            GPIO_INPUT_VAL => return AccessResult::Ok,
+            // Pending bits are cleared by writing 1.
+            GPIO_RISE_IP | GPIO_FALL_IP | GPIO_HIGH_IP | GPIO_LOW_IP => {
+                let reg = self.reg_mut(offset).unwrap();
+                *reg &= !value;
+            }
+            _ => match self.reg_mut(offset) {
+                Some(reg) => *reg = value,
+                None => return AccessResult::BadOffset,
+            },
+        }
+        self.update();
+        AccessResult::Ok
+    }
+}
+
+/// Release power button after it's held long enough, timers armed before are dropped.
+fn arm_button_release(state: &Arc<Mutex<GpioState>>) {
+    let mut locked_state = state.lock().unwrap();
+    if locked_state.paused {
+        locked_state.timer.cancel();
+        return;
+    }
+
+    let weak_state: Weak<Mutex<GpioState>> = Arc::downgrade(state);
+    let func = move || {
+        if let Some(state) = weak_state.upgrade() {
+            let mut locked_state = state.lock().unwrap();
+            if locked_state.timer.is_current() {
+                locked_state.set_pin(POWER_BUTTON_LINE, false);
+            }
+        }
+    };
+    locked_state
+        .timer
+        .arm(Duration::from_nanos(POWER_BUTTON_HOLD_NS), func);
+}
+
+/// SiFive GPIO controller with a power button wired to its first line, which linux
+/// binds by `gpio-keys`. Guest without ACPI shuts down in order when it's pressed.
+pub struct SifiveGpio {
+    state: Arc<Mutex<GpioState>>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl Default for SifiveGpio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SifiveGpio {
+    pub fn new() -> Self {
+        SifiveGpio {
+            state: Arc::new(Mutex::new(GpioState::default())),
+            res: SysRes::default(),
+        }
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .with_context(|| anyhow!(LegacyError::SetSysResErr))?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach gpio device")?;
+        Ok(dev)
+    }
+
+    /// Press power button, which is released by itself after a while. Pressing it
+    /// again while it's held down only holds it longer.
+    pub fn press_power_button(&self) {
+        self.state.lock().unwrap().set_pin(POWER_BUTTON_LINE, true);
+        arm_button_release(&self.state);
+    }
+}
+
+impl SysBusDevOps for SifiveGpio {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 4 {
+            return AccessResult::UnsupportedSize;
+        }
+        match self.state.lock().unwrap().reg_mut(offset) {
+            Some(reg) => {
+                LittleEndian::write_u32(data, *reg);
+                AccessResult::Ok
+            }
+            None => AccessResult::BadOffset,
+        }
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 4 {
+            return AccessResult::UnsupportedSize;
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .write_reg(offset, LittleEndian::read_u32(data))
+    }
+
+    fn needs_irq_line(&self) -> bool {
+        true
+    }
+
+    fn set_irq_line(&mut self, line: SysBusIrqLine) {
+        self.state.lock().unwrap().irq_line = Some(line);
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::Gpio
+    }
+
+    /// Gpio controller is also the interrupt controller of its lines, through which
+    /// `gpio-keys` gets the interrupt of power button.
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        if let Some(node_dep) = begin_fdt_node(parent, SysBusDevType::Gpio, &self.res)? {
+            parent.set_property("gpio-controller", &[])?;
+            parent.set_property_u32("#gpio-cells", 2)?;
+            parent.set_property("interrupt-controller", &[])?;
+            parent.set_property_u32("#interrupt-cells", 2)?;
+            parent.set_property_u32("ngpios", GPIO_NR_LINES)?;
+            parent.set_property_u32("phandle", device_tree::GPIO_PHANDLE)?;
+            parent.end_node(node_dep)?;
+        }
+
+        let keys_node_dep = parent.begin_node("gpio-keys")?;
+        parent.set_property_string("compatible", "gpio-keys")?;
+        let key_node_dep = parent.begin_node("key-power")?;
+        parent.set_property_string("label", "Power")?;
+        parent.set_property_u32("linux,code", KEY_POWER)?;
+        parent
+            .set_property_array_u32("gpios", &[device_tree::GPIO_PHANDLE, POWER_BUTTON_LINE, 0])?;
+        parent.end_node(key_node_dep)?;
+        parent.end_node(keys_node_dep)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // Power button is released, the interrupt line is deasserted by sysbus.
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.timer.cancel();
+        let irq_line = locked_state.irq_line.take();
+        let timer = std::mem::take(&mut locked_state.timer);
+        let paused = locked_state.paused;
+        *locked_state = GpioState {
+            paused,
+            timer,
+            irq_line,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        // Power button is held until vm resumes, so that guest doesn't miss it.
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.paused = true;
+        locked_state.timer.cancel();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.paused = false;
+        let held = locked_state.pins & (1 << POWER_BUTTON_LINE) != 0;
+        drop(locked_state);
+        if held {
+            arm_button_release(&self.state);
+        }
+        Ok(())
+    }
+
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        let mut locked_state = self.state.lock().unwrap();
+        let mut state = [0_u8; GPIO_STATE_SIZE];
+        LittleEndian::write_u32(&mut state[0..4], locked_state.pins);
+        for (i, offset) in (GPIO_INPUT_VAL..=GPIO_OUT_XOR).step_by(4).enumerate() {
+            let reg = locked_state.reg_mut(offset).unwrap();
+            LittleEndian::write_u32(&mut state[4 + i * 4..8 + i * 4], *reg);
+        }
+        Ok(encode_state(GPIO_STATE_VERSION, &state))
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let (_, state) = decode_state(data, GPIO_STATE_VERSION)?;
+        if state.len() != GPIO_STATE_SIZE {
+            bail!("Invalid gpio state size {}", state.len());
+        }
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.pins = LittleEndian::read_u32(&state[0..4]);
+        for (i, offset) in (GPIO_INPUT_VAL..=GPIO_OUT_XOR).step_by(4).enumerate() {
+            let reg = locked_state.reg_mut(offset).unwrap();
+            *reg = LittleEndian::read_u32(&state[4 + i * 4..8 + i * 4]);
+        }
+        locked_state.timer.cancel();
+        locked_state.update();
+        let held = locked_state.pins & (1 << POWER_BUTTON_LINE) != 0;
+        drop(locked_state);
+        if held {
+            arm_button_release(&self.state);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_reg(dev: &mut SifiveGpio, offset: u64, value: u32) -> AccessResult {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        dev.write(&data, GuestAddress(0), offset)
+    }
+
+    fn read_reg(dev: &mut SifiveGpio, offset: u64) -> u32 {
+        let mut data = [0_u8; 4];
+        assert!(dev.read(&mut data, GuestAddress(0), offset).is_ok());
+        LittleEndian::read_u32(&data)
+    }
+
+    #[test]
+    fn test_gpio_power_button() {
+        let mut dev = SifiveGpio::new();
+        // Gpio-keys requests the line as input with interrupts of both edges.
+        assert!(write_reg(&mut dev, GPIO_INPUT_EN, 1).is_ok());
+        assert!(write_reg(&mut dev, GPIO_RISE_IE, 1).is_ok());
+        assert!(write_reg(&mut dev, GPIO_FALL_IE, 1).is_ok());
+        // Low level is latched once input is enabled, write 1 to clear it.
+        assert_eq!(read_reg(&mut dev, GPIO_LOW_IP), 1);
+        assert!(write_reg(&mut dev, GPIO_LOW_IP, 1).is_ok());
+        assert_eq!(read_reg(&mut dev, GPIO_LOW_IP), 0);
+
+        dev.state.lock().unwrap().set_pin(POWER_BUTTON_LINE, true);
+        assert_eq!(read_reg(&mut dev, GPIO_INPUT_VAL), 1);
+        assert_eq!(read_reg(&mut dev, GPIO_RISE_IP), 1);
+        assert_eq!(read_reg(&mut dev, GPIO_FALL_IP), 0);
+        // Input value is read only.
+        assert!(write_reg(&mut dev, GPIO_INPUT_VAL, 0).is_ok());
+        assert_eq!(read_reg(&mut dev, GPIO_INPUT_VAL), 1);
+
+        dev.state.lock().unwrap().set_pin(POWER_BUTTON_LINE, false);
+        assert_eq!(read_reg(&mut dev, GPIO_INPUT_VAL), 0);
+        assert_eq!(read_reg(&mut dev, GPIO_FALL_IP), 1);
+
+        // Lines beyond the first one don't exist.
+        assert!(write_reg(&mut dev, GPIO_OUTPUT_EN, 0xffff_ffff).is_ok());
+        assert_eq!(read_reg(&mut dev, GPIO_OUTPUT_EN), 1);
+        // Output line reads back what's driven.
+        assert!(write_reg(&mut dev, GPIO_OUTPUT_VAL, 1).is_ok());
+        assert_eq!(read_reg(&mut dev, GPIO_INPUT_VAL), 1);
+        assert!(write_reg(&mut dev, GPIO_OUT_XOR, 1).is_ok());
+        assert_eq!(read_reg(&mut dev, GPIO_INPUT_VAL), 0);
+
+        let mut data = [0_u8; 4];
+        assert_eq!(
+            dev.read(&mut data, GuestAddress(0), 0x44),
+            AccessResult::BadOffset
+        );
+        assert_eq!(
+            dev.read(&mut data[..2], GuestAddress(0), GPIO_INPUT_VAL),
+            AccessResult::UnsupportedSize
+        );
+
+        let state = dev.state_bytes().unwrap();
+        assert!(dev.reset().is_ok());
+        assert_eq!(read_reg(&mut dev, GPIO_INPUT_EN), 0);
+        assert!(dev.restore_state(&state).is_ok());
+        assert_eq!(read_reg(&mut dev, GPIO_INPUT_EN), 1);
+        assert_eq!(read_reg(&mut dev, GPIO_OUT_XOR), 1);
+    }
+}
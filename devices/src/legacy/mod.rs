@@ -7,6 +7,13 @@
 //! This module offers support for:
 //! 1. Pl031 device, Arm PrimeCell Real Time Clock.
 //! 2. Serial device, Serial UART.
+//! 3. PFlash device, parallel flash of directly mapped memory.
+//! 4. Ramfb device, framebuffer in guest RAM configured through fw_cfg.
+//! 5. DwWdt device, DesignWare APB watchdog timer.
+//! 6. SifiveTest device, test finisher powering off or rebooting the machine.
+//! 7. PvPanic device, through which guest reports its panic.
+//! 8. SbiDebugConsole, backend of SBI debug console extension.
+//! 9. SifiveGpio device, GPIO controller with power button of the machine.
 //!
 //! ## Platform Support
 //!
@@ -17,10 +24,26 @@ mod chardev;
 pub mod error;
 #[allow(dead_code)]
 mod fwcfg;
+mod gpio;
+mod pflash;
+mod pvpanic;
+mod ramfb;
+mod rtc;
+mod sbi_console;
 mod serial;
+mod sifive_test;
+mod watchdog;
 pub use anyhow::Result;
-pub use chardev::{Chardev, InputReceiver};
+pub use chardev::{Chardev, ChardevListener, ChardevRegistry, InputReceiver};
 pub use error::LegacyError;
 pub use fwcfg::FwCfgMem;
 pub use fwcfg::{FwCfgEntryType, FwCfgOps};
+pub use gpio::SifiveGpio;
+pub use pflash::PFlash;
+pub use pvpanic::PvPanic;
+pub use ramfb::{Ramfb, RamfbState, RamfbSurface};
+pub use rtc::GoldfishRtc;
+pub use sbi_console::SbiDebugConsole;
 pub use serial::{Serial, SERIAL_ADDR};
+pub use sifive_test::SifiveTest;
+pub use watchdog::DwWdt;
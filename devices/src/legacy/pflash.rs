@@ -0,0 +1,221 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+use address_space::{GuestAddress, HostMemMapping};
+use anyhow::{anyhow, Context, Result};
+use log::{error, warn};
+use sysbus::{AccessResult, SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use util::device_tree::FdtBuilder;
+
+use super::error::LegacyError;
+
+/// Parallel flash of directly mapped memory, backed by an image file on host,
+/// refer to linux `drivers/mtd/maps/physmap-core.c`. Guest reads the mapped image
+/// without trapping. Writes trap, they are logged and discarded for read-only flash,
+/// and stored into the image file otherwise, e.g. the variable store of firmware.
+pub struct PFlash {
+    /// Host memory mapped from the image file.
+    rom: Arc<HostMemMapping>,
+    /// Whether the flash is read only.
+    read_only: bool,
+    /// System resource.
+    res: SysRes,
+}
+
+impl PFlash {
+    /// Construct a flash from the image file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - Opened image file, whose size is the size of flash.
+    /// * `read_only` - Whether the flash is read only.
+    /// * `region_base` - Guest address the flash is mapped at.
+    /// * `max_size` - Size of the flash bank, which the image can't exceed.
+    pub fn new(file: File, read_only: bool, region_base: u64, max_size: u64) -> Result<Self> {
+        let size = file.metadata()?.len();
+        if size > max_size {
+            return Err(anyhow!(LegacyError::PFlashFileSizeErr(size, max_size)));
+        }
+        let rom = HostMemMapping::new_rom_file(GuestAddress(region_base), file, read_only)
+            .with_context(|| "Failed to map flash image")?;
+        Ok(PFlash {
+            rom: Arc::new(rom),
+            read_only,
+            res: SysRes::default(),
+        })
+    }
+
+    pub fn realize(mut self, sysbus: &mut SysBus) -> Result<Arc<Mutex<Self>>> {
+        let region_base = self.rom.start_address().raw_value();
+        let region_size = self.rom.size();
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .with_context(|| anyhow!(LegacyError::SetSysResErr))?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach flash device")?;
+        Ok(dev)
+    }
+
+    /// Whether the flash is read only.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Host memory of flash content at `offset`, `None` if it overflows the flash.
+    fn content(&self, offset: u64, len: usize) -> Option<*mut u8> {
+        let end = offset.checked_add(len as u64)?;
+        if end > self.rom.size() {
+            return None;
+        }
+        Some((self.rom.host_address() + offset) as *mut u8)
+    }
+}
+
+impl SysBusDevOps for PFlash {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        // Reads are served by the mapping directly, this is only reached by the
+        // accesses which are not handled by hypervisor.
+        match self.content(offset, data.len()) {
+            Some(addr) => {
+                let src = unsafe { std::slice::from_raw_parts(addr as *const u8, data.len()) };
+                data.copy_from_slice(src);
+                AccessResult::Ok
+            }
+            None => {
+                error!(
+                    "{}",
+                    LegacyError::PFlashReadOverflow(self.rom.size(), offset, data.len() as u64)
+                );
+                AccessResult::BadOffset
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        let addr = match self.content(offset, data.len()) {
+            Some(addr) => addr,
+            None => {
+                error!(
+                    "{}",
+                    LegacyError::PFlashWriteOverflow(self.rom.size(), offset, data.len() as u64)
+                );
+                return AccessResult::BadOffset;
+            }
+        };
+        if self.read_only {
+            warn!(
+                "Discard write to read-only flash at offset 0x{:x}, size {}",
+                offset,
+                data.len()
+            );
+            return AccessResult::Ok;
+        }
+        let dst = unsafe { std::slice::from_raw_parts_mut(addr, data.len()) };
+        dst.copy_from_slice(data);
+        AccessResult::Ok
+    }
+
+    fn rom_mapping(&self) -> Option<Arc<HostMemMapping>> {
+        Some(self.rom.clone())
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::Flash
+    }
+
+    /// The node is bound by linux physmap driver.
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        let node_dep = parent.begin_node(&format!("flash@{:x}", self.res.region_base))?;
+        let compatible = if self.read_only { "mtd-rom" } else { "mtd-ram" };
+        parent.set_property_string("compatible", compatible)?;
+        parent.set_property_array_u64("reg", &[self.res.region_base, self.res.region_size])?;
+        parent.set_property_u32("bank-width", 4)?;
+        parent.end_node(node_dep)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use util::unix::host_page_size;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    fn image(content: &[u8]) -> TempFile {
+        let tmp = TempFile::new().unwrap();
+        let mut file = tmp.as_file();
+        file.write_all(content).unwrap();
+        file.set_len(host_page_size()).unwrap();
+        tmp
+    }
+
+    #[test]
+    fn test_pflash_new() {
+        let tmp = image(&[0x5a; 4]);
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(PFlash::new(file, true, 0x1000_0000, host_page_size() - 1).is_err());
+
+        let file = tmp.as_file().try_clone().unwrap();
+        let flash = PFlash::new(file, true, 0x1000_0000, host_page_size()).unwrap();
+        assert!(flash.read_only());
+        assert_eq!(flash.rom.start_address(), GuestAddress(0x1000_0000));
+        assert_eq!(flash.rom.size(), host_page_size());
+    }
+
+    #[test]
+    fn test_pflash_write() {
+        let tmp = image(&[0x5a; 4]);
+        let base = GuestAddress(0x1000_0000);
+
+        // Writes to read-only flash are discarded.
+        let file = tmp.as_file().try_clone().unwrap();
+        let mut rom = PFlash::new(file, true, base.raw_value(), host_page_size()).unwrap();
+        assert!(rom.write(&[0xa5; 2], base, 0).is_ok());
+        let mut data = [0_u8; 4];
+        assert!(rom.read(&mut data, base, 0).is_ok());
+        assert_eq!(data, [0x5a; 4]);
+        assert_eq!(
+            rom.read(&mut data, base, host_page_size() - 2),
+            AccessResult::BadOffset
+        );
+
+        // Writes to writable flash are stored into the image file.
+        let file = tmp.as_file().try_clone().unwrap();
+        let mut flash = PFlash::new(file, false, base.raw_value(), host_page_size()).unwrap();
+        assert!(flash.write(&[0xa5; 2], base, 1).is_ok());
+        assert!(flash.read(&mut data, base, 0).is_ok());
+        assert_eq!(data, [0x5a, 0xa5, 0xa5, 0x5a]);
+        assert_eq!(
+            flash.write(&data, base, host_page_size() - 2),
+            AccessResult::BadOffset
+        );
+
+        let mut file = tmp.as_file();
+        let mut content = [0_u8; 4];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut content).unwrap();
+        assert_eq!(content, data);
+    }
+}
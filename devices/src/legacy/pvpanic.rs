@@ -0,0 +1,143 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::GuestAddress;
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use machine_manager::machine::PanicNotifier;
+use sysbus::{AccessResult, SysBus, SysBusDevOps, SysBusDevType, SysRes};
+
+use super::error::LegacyError;
+
+/// Event register, reading it returns events supported and guest writes the event
+/// happened.
+const PVPANIC_REG: u64 = 0x00;
+/// Guest kernel panicked.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+/// Guest kernel loaded the crash kernel, which is going to take over.
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+const PVPANIC_EVENTS: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+/// Paravirtualized panic device, through which guest reports its panic explicitly.
+/// Linux binds it by `qemu,pvpanic-mmio`.
+pub struct PvPanic {
+    /// Notified when guest panics.
+    panic_notifier: Arc<PanicNotifier>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl PvPanic {
+    pub fn new(panic_notifier: Arc<PanicNotifier>) -> Self {
+        PvPanic {
+            panic_notifier,
+            res: SysRes::default(),
+        }
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .with_context(|| anyhow!(LegacyError::SetSysResErr))?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach pvpanic device")?;
+        Ok(dev)
+    }
+}
+
+impl SysBusDevOps for PvPanic {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 1 {
+            return AccessResult::UnsupportedSize;
+        }
+        if offset != PVPANIC_REG {
+            return AccessResult::BadOffset;
+        }
+        data[0] = PVPANIC_EVENTS;
+        AccessResult::Ok
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 1 {
+            return AccessResult::UnsupportedSize;
+        }
+        if offset != PVPANIC_REG {
+            return AccessResult::BadOffset;
+        }
+
+        // Crash kernel handles the panic, which is reported only if it's not loaded.
+        if data[0] & PVPANIC_CRASH_LOADED != 0 {
+            info!("Guest crash kernel is loaded by pvpanic");
+        } else if data[0] & PVPANIC_PANICKED != 0 {
+            self.panic_notifier.notify("pvpanic");
+        }
+        AccessResult::Ok
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::PvPanic
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::io::AsRawFd;
+
+    use machine_manager::config::PanicAction;
+    use util::loop_context::read_fd;
+
+    use super::*;
+
+    #[test]
+    fn test_pvpanic() {
+        let notifier = Arc::new(PanicNotifier::new(PanicAction::Pause).unwrap());
+        let mut dev = PvPanic::new(notifier.clone());
+
+        let mut data = [0_u8; 1];
+        assert!(dev.read(&mut data, GuestAddress(0), PVPANIC_REG).is_ok());
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+
+        // Panic action is requested only if crash kernel isn't loaded.
+        assert!(dev
+            .write(&[PVPANIC_CRASH_LOADED], GuestAddress(0), PVPANIC_REG)
+            .is_ok());
+        assert_eq!(read_fd(notifier.as_raw_fd()), 0);
+        assert!(dev
+            .write(&[PVPANIC_PANICKED], GuestAddress(0), PVPANIC_REG)
+            .is_ok());
+        assert_eq!(read_fd(notifier.as_raw_fd()), 1);
+
+        assert_eq!(
+            dev.write(&[PVPANIC_PANICKED], GuestAddress(0), 0x1),
+            AccessResult::BadOffset
+        );
+        assert_eq!(
+            dev.write(&[PVPANIC_PANICKED, 0], GuestAddress(0), PVPANIC_REG),
+            AccessResult::UnsupportedSize
+        );
+        assert_eq!(read_fd(notifier.as_raw_fd()), 0);
+    }
+}
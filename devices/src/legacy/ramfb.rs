@@ -0,0 +1,366 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress};
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::error;
+use sysbus::{AccessResult, SysBus, SysBusDevOps, SysBusDevType};
+
+use super::fwcfg::{FwCfgOps, FwCfgWriteCallback};
+
+/// Name of the fw_cfg file which guest writes framebuffer config to.
+const RAMFB_FILE: &str = "etc/ramfb";
+/// Size of the config in `RAMFB_FILE`.
+const RAMFB_CFG_SIZE: usize = 28;
+/// DRM fourcc code of XRGB8888, "XR24".
+const DRM_FORMAT_XRGB8888: u32 = 0x3432_5258;
+const BYTES_PER_PIXEL: u32 = 4;
+const MIN_WIDTH: u32 = 16;
+const MAX_WIDTH: u32 = 16000;
+const MIN_HEIGHT: u32 = 16;
+const MAX_HEIGHT: u32 = 12000;
+
+/// Framebuffer in guest RAM described by guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RamfbSurface {
+    /// Guest physical address of the first pixel.
+    pub addr: u64,
+    /// DRM fourcc code of pixel format.
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes between the starts of two lines.
+    pub stride: u32,
+}
+
+impl RamfbSurface {
+    /// Parse the config written by guest, whose fields are big endian:
+    /// addr(u64), fourcc(u32), flags(u32), width(u32), height(u32), stride(u32).
+    fn from_cfg(data: &[u8]) -> Self {
+        RamfbSurface {
+            addr: BigEndian::read_u64(&data[0..8]),
+            format: BigEndian::read_u32(&data[8..12]),
+            width: BigEndian::read_u32(&data[16..20]),
+            height: BigEndian::read_u32(&data[20..24]),
+            stride: BigEndian::read_u32(&data[24..28]),
+        }
+    }
+
+    fn line_size(&self) -> u64 {
+        (self.width * BYTES_PER_PIXEL) as u64
+    }
+
+    /// Size of guest memory from the first pixel to the last one.
+    fn mem_size(&self) -> u64 {
+        self.stride as u64 * (self.height as u64 - 1) + self.line_size()
+    }
+}
+
+/// Framebuffer state, updated when guest writes `RAMFB_FILE` of fw_cfg.
+pub struct RamfbState {
+    surface: Option<RamfbSurface>,
+    sys_mem: Arc<AddressSpace>,
+}
+
+impl RamfbState {
+    pub fn new(sys_mem: Arc<AddressSpace>) -> Self {
+        RamfbState {
+            surface: None,
+            sys_mem,
+        }
+    }
+
+    /// Add `RAMFB_FILE` to fw_cfg, the writes of which configure the framebuffer.
+    pub fn setup(state: &Arc<Mutex<Self>>, fwcfg: &Arc<Mutex<dyn FwCfgOps>>) -> Result<()> {
+        let write_cb: Arc<Mutex<dyn FwCfgWriteCallback + Send + Sync>> = state.clone();
+        fwcfg
+            .lock()
+            .unwrap()
+            .add_file_callback_entry(
+                RAMFB_FILE,
+                vec![0; RAMFB_CFG_SIZE],
+                None,
+                Some(write_cb),
+                true,
+            )
+            .with_context(|| format!("Failed to add fw_cfg file {}", RAMFB_FILE))
+    }
+
+    /// Current framebuffer, `None` if guest hasn't configured a valid one.
+    pub fn surface(&self) -> Option<RamfbSurface> {
+        self.surface
+    }
+
+    /// Check the framebuffer described by guest, stride 0 means lines are packed.
+    fn check_surface(&self, mut surface: RamfbSurface) -> Result<RamfbSurface> {
+        if surface.format != DRM_FORMAT_XRGB8888 {
+            bail!("Unsupported drm format 0x{:x}", surface.format);
+        }
+        if !(MIN_WIDTH..=MAX_WIDTH).contains(&surface.width)
+            || !(MIN_HEIGHT..=MAX_HEIGHT).contains(&surface.height)
+        {
+            bail!(
+                "The resolution: {}x{} is unsupported",
+                surface.width,
+                surface.height
+            );
+        }
+        if surface.stride == 0 {
+            surface.stride = surface.width * BYTES_PER_PIXEL;
+        }
+        if (surface.stride as u64) < surface.line_size() {
+            bail!(
+                "Invalid stride {} for width {}",
+                surface.stride,
+                surface.width
+            );
+        }
+        if !self
+            .sys_mem
+            .address_in_memory(GuestAddress(surface.addr), surface.mem_size())
+        {
+            bail!(
+                "Failed to get the host address of the framebuffer 0x{:x}, size 0x{:x}",
+                surface.addr,
+                surface.mem_size()
+            );
+        }
+        Ok(surface)
+    }
+
+    /// Write the framebuffer to `path` as binary PPM.
+    pub fn screendump(&self, path: &str) -> Result<()> {
+        let surface = self
+            .surface
+            .ok_or_else(|| anyhow!("Framebuffer of ramfb isn't configured"))?;
+        // Guest memory may be unplugged since the framebuffer is configured.
+        if !self
+            .sys_mem
+            .address_in_memory(GuestAddress(surface.addr), surface.mem_size())
+        {
+            bail!(
+                "Framebuffer 0x{:x} of ramfb is out of guest memory",
+                surface.addr
+            );
+        }
+
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+        let mut writer = BufWriter::new(file);
+        write!(writer, "P6\n{} {}\n255\n", surface.width, surface.height)?;
+        let mut line = vec![0_u8; surface.line_size() as usize];
+        let mut rgb = Vec::with_capacity(surface.width as usize * 3);
+        for y in 0..surface.height as u64 {
+            let addr = GuestAddress(surface.addr + y * surface.stride as u64);
+            self.sys_mem
+                .read(&mut line.as_mut_slice(), addr, surface.line_size())
+                .with_context(|| format!("Failed to read framebuffer line {}", y))?;
+            rgb.clear();
+            // XRGB8888 is little endian, stored as B, G, R, X.
+            for pixel in line.chunks_exact(BYTES_PER_PIXEL as usize) {
+                rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+            writer.write_all(&rgb)?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write {}", path))?;
+        Ok(())
+    }
+}
+
+impl FwCfgWriteCallback for RamfbState {
+    fn write_callback(&mut self, data: Vec<u8>, start: u64, len: usize) {
+        if start != 0 || len != RAMFB_CFG_SIZE || data.len() < RAMFB_CFG_SIZE {
+            error!(
+                "Partial write of ramfb config is unsupported, offset {} size {}",
+                start, len
+            );
+            return;
+        }
+        // Invalid config is dropped, the previous framebuffer is kept.
+        match self.check_surface(RamfbSurface::from_cfg(&data)) {
+            Ok(surface) => self.surface = Some(surface),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+/// Ramfb device, framebuffer in guest RAM configured through fw_cfg. It has no
+/// registers, and is attached to system bus for reset.
+pub struct Ramfb {
+    pub ramfb_state: Arc<Mutex<RamfbState>>,
+}
+
+impl Ramfb {
+    pub fn new(sys_mem: Arc<AddressSpace>) -> Self {
+        Ramfb {
+            ramfb_state: Arc::new(Mutex::new(RamfbState::new(sys_mem))),
+        }
+    }
+
+    pub fn realize(self, sysbus: &mut SysBus) -> Result<Arc<Mutex<Self>>> {
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_dynamic_device(&dev)
+            .with_context(|| "Failed to attach ramfb device to system bus.")?;
+        Ok(dev)
+    }
+}
+
+impl SysBusDevOps for Ramfb {
+    fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+        AccessResult::BadOffset
+    }
+
+    fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+        AccessResult::BadOffset
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::Ramfb
+    }
+
+    fn reset(&mut self) -> sysbus::Result<()> {
+        self.ramfb_state.lock().unwrap().surface = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use address_space::{HostMemMapping, Region};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    const MEM_SIZE: u64 = 0x10_0000;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, MEM_SIZE, None, false, false, false)
+                .unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone()),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    fn ramfb_cfg(addr: u64, format: u32, width: u32, height: u32, stride: u32) -> Vec<u8> {
+        let mut cfg = vec![0_u8; RAMFB_CFG_SIZE];
+        BigEndian::write_u64(&mut cfg[0..8], addr);
+        BigEndian::write_u32(&mut cfg[8..12], format);
+        BigEndian::write_u32(&mut cfg[16..20], width);
+        BigEndian::write_u32(&mut cfg[20..24], height);
+        BigEndian::write_u32(&mut cfg[24..28], stride);
+        cfg
+    }
+
+    #[test]
+    fn test_ramfb_config() {
+        let mut state = RamfbState::new(address_space_init());
+        let fmt = DRM_FORMAT_XRGB8888;
+
+        // Stride 0 means packed lines.
+        state.write_callback(ramfb_cfg(0x1000, fmt, 16, 16, 0), 0, RAMFB_CFG_SIZE);
+        let surface = RamfbSurface {
+            addr: 0x1000,
+            format: fmt,
+            width: 16,
+            height: 16,
+            stride: 64,
+        };
+        assert_eq!(state.surface(), Some(surface));
+
+        // Invalid configs are dropped.
+        let invalid_cfgs = [
+            ramfb_cfg(0x2000, 0, 16, 16, 0),
+            ramfb_cfg(0x2000, fmt, 15, 16, 0),
+            ramfb_cfg(0x2000, fmt, 16, 12001, 0),
+            ramfb_cfg(0x2000, fmt, 16, 16, 63),
+            // The last line is out of RAM.
+            ramfb_cfg(MEM_SIZE - 16 * 64 + 4, fmt, 16, 16, 64),
+            ramfb_cfg(u64::MAX - 4, fmt, 16, 16, 0),
+            ramfb_cfg(0x2000, fmt, 16, 16, u32::MAX),
+        ];
+        for cfg in invalid_cfgs {
+            state.write_callback(cfg, 0, RAMFB_CFG_SIZE);
+            assert_eq!(state.surface(), Some(surface));
+        }
+        state.write_callback(ramfb_cfg(0x2000, fmt, 16, 16, 0), 4, RAMFB_CFG_SIZE - 4);
+        assert_eq!(state.surface(), Some(surface));
+
+        // Framebuffer ends at the end of RAM.
+        state.write_callback(
+            ramfb_cfg(MEM_SIZE - 16 * 64, fmt, 16, 16, 64),
+            0,
+            RAMFB_CFG_SIZE,
+        );
+        assert_eq!(state.surface().unwrap().addr, MEM_SIZE - 16 * 64);
+    }
+
+    #[test]
+    fn test_ramfb_screendump() {
+        let sys_mem = address_space_init();
+        let mut state = RamfbState::new(sys_mem.clone());
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap();
+        assert!(state.screendump(path).is_err());
+
+        // 16x16 framebuffer with padding at the end of lines, pixel (x, y) is
+        // R=x, G=y, B=0x5a.
+        let stride = 16 * 4 + 8;
+        for y in 0..16_u8 {
+            let line: Vec<u8> = (0..16_u8).flat_map(|x| [0x5a, y, x, 0xff]).collect();
+            sys_mem
+                .write(
+                    &mut line.as_slice(),
+                    GuestAddress(0x1000 + y as u64 * stride as u64),
+                    line.len() as u64,
+                )
+                .unwrap();
+        }
+        state.write_callback(
+            ramfb_cfg(0x1000, DRM_FORMAT_XRGB8888, 16, 16, stride),
+            0,
+            RAMFB_CFG_SIZE,
+        );
+        state.screendump(path).unwrap();
+
+        let mut ppm = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut ppm).unwrap();
+        let header = b"P6\n16 16\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        let pixels = &ppm[header.len()..];
+        assert_eq!(pixels.len(), 16 * 16 * 3);
+        for y in 0..16_usize {
+            for x in 0..16_usize {
+                let i = (y * 16 + x) * 3;
+                assert_eq!(pixels[i..i + 3], [x as u8, y as u8, 0x5a]);
+            }
+        }
+    }
+}
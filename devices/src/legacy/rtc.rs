@@ -0,0 +1,623 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use address_space::GuestAddress;
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, error};
+use machine_manager::config::{RtcBase, RtcClock, RtcConfig};
+use sysbus::{
+    decode_state, encode_state, AccessResult, SysBus, SysBusDevOps, SysBusDevType, SysBusIrqLine,
+    SysRes,
+};
+
+use super::error::LegacyError;
+use crate::timer::DeviceTimer;
+
+/// Registers of goldfish rtc, refer to linux `drivers/rtc/rtc-goldfish.c`.
+const RTC_TIME_LOW: u64 = 0x00;
+const RTC_TIME_HIGH: u64 = 0x04;
+const RTC_ALARM_LOW: u64 = 0x08;
+const RTC_ALARM_HIGH: u64 = 0x0c;
+const RTC_IRQ_ENABLED: u64 = 0x10;
+const RTC_CLEAR_ALARM: u64 = 0x14;
+const RTC_ALARM_STATUS: u64 = 0x18;
+const RTC_CLEAR_INTERRUPT: u64 = 0x1c;
+
+/// Version of rtc state saved by `state_bytes`.
+const RTC_STATE_VERSION: u32 = 2;
+/// Size of rtc state of version 1: guest time, latched high 32 bits of time and
+/// paused flag.
+const RTC_STATE_SIZE_V1: usize = 13;
+/// Size of rtc state: version 1 followed by alarm time, irq enabled, alarm running
+/// and irq pending flags.
+const RTC_STATE_SIZE: usize = 24;
+
+/// Get host wall clock time in nanoseconds.
+fn host_time_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Get offset of host local time relative to utc in seconds.
+fn host_utc_offset() -> i64 {
+    // Safe because it only gets the current time.
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    // Safe because this only set the `tm` struct to zero.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // Safe because `tm` is valid and owned by this function.
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        error!("Failed to get host local time, rtc is in utc");
+        return 0;
+    }
+    tm.tm_gmtoff
+}
+
+/// Replace the low (`high` is false) or high 32 bits of `reg` with `val`.
+fn deposit_u32(reg: u64, val: u32, high: bool) -> u64 {
+    if high {
+        reg & 0xffff_ffff | u64::from(val) << 32
+    } else {
+        reg & !0xffff_ffff | u64::from(val)
+    }
+}
+
+/// Alarm state shared between rtc and the timer armed in main loop.
+#[derive(Default)]
+struct RtcAlarm {
+    /// Guest time in nanoseconds when the alarm fires.
+    alarm_next: u64,
+    /// Whether the alarm is set and not fired yet.
+    alarm_running: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    timer: DeviceTimer,
+    irq_line: Option<SysBusIrqLine>,
+}
+
+impl RtcAlarm {
+    /// The interrupt is asserted while it is pending and enabled.
+    fn update_irq(&self) {
+        if let Some(line) = &self.irq_line {
+            if let Err(e) = line.set_level(self.irq_pending && self.irq_enabled) {
+                error!("Failed to update rtc interrupt: {:?}", e);
+            }
+        }
+    }
+
+    fn fire(&mut self) {
+        self.alarm_running = false;
+        self.irq_pending = true;
+        self.update_irq();
+    }
+}
+
+/// Fire the alarm after `delay` nanoseconds, timers armed before are dropped.
+fn arm_alarm(alarm: &Arc<Mutex<RtcAlarm>>, delay: u64) {
+    let weak_alarm: Weak<Mutex<RtcAlarm>> = Arc::downgrade(alarm);
+    let func = move || {
+        if let Some(alarm) = weak_alarm.upgrade() {
+            let mut locked_alarm = alarm.lock().unwrap();
+            if locked_alarm.timer.is_current() {
+                locked_alarm.fire();
+            }
+        }
+    };
+    alarm
+        .lock()
+        .unwrap()
+        .timer
+        .arm(Duration::from_nanos(delay), func);
+}
+
+/// Goldfish real time clock, which provides guest wall clock time in nanoseconds and
+/// an alarm interrupt.
+pub struct GoldfishRtc {
+    /// Offset of guest time relative to host time in nanoseconds. It's kept over vm
+    /// reset, just like the battery backed clock of real hardware.
+    offset: i64,
+    /// Guest time frozen while vm is paused, always none if rtc follows host clock.
+    paused_time: Option<u64>,
+    /// Clock driving rtc.
+    clock: RtcClock,
+    /// High 32 bits of time, latched when low 32 bits are read.
+    time_high: u32,
+    alarm: Arc<Mutex<RtcAlarm>>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl GoldfishRtc {
+    pub fn new() -> Result<Self> {
+        Self::with_config(&RtcConfig::default())
+    }
+
+    /// Create rtc starting at the time given by `base` of `config`.
+    pub fn with_config(config: &RtcConfig) -> Result<Self> {
+        let offset = match config.base {
+            RtcBase::Utc => 0,
+            RtcBase::Localtime => host_utc_offset() * 1_000_000_000,
+            RtcBase::Datetime(secs) => secs
+                .checked_mul(1_000_000_000)
+                .with_context(|| format!("Rtc base {} out of range", secs))?
+                .wrapping_sub(host_time_ns() as i64),
+        };
+        Ok(GoldfishRtc {
+            offset,
+            paused_time: None,
+            clock: config.clock,
+            time_high: 0,
+            alarm: Arc::new(Mutex::new(RtcAlarm::default())),
+            res: SysRes::default(),
+        })
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .with_context(|| anyhow!(LegacyError::SetSysResErr))?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach rtc device")?;
+        Ok(dev)
+    }
+
+    /// Get guest wall clock time in nanoseconds.
+    pub fn guest_time_ns(&self) -> u64 {
+        match self.paused_time {
+            Some(time) => time,
+            None => (host_time_ns() as i64).wrapping_add(self.offset) as u64,
+        }
+    }
+
+    /// Set guest wall clock time to `time` in nanoseconds.
+    fn set_guest_time_ns(&mut self, time: u64) {
+        match self.paused_time.as_mut() {
+            Some(paused_time) => *paused_time = time,
+            None => self.offset = (time as i64).wrapping_sub(host_time_ns() as i64),
+        }
+        // Alarm is due at the new guest time.
+        if self.alarm.lock().unwrap().alarm_running {
+            self.set_alarm();
+        }
+    }
+
+    /// Start the alarm, which fires at once if its time has passed. The timer is armed
+    /// only while vm is running.
+    fn set_alarm(&mut self) {
+        let now = self.guest_time_ns();
+        let mut locked_alarm = self.alarm.lock().unwrap();
+        locked_alarm.alarm_running = true;
+        // Drop the armed timer.
+        locked_alarm.timer.cancel();
+        if locked_alarm.alarm_next <= now {
+            locked_alarm.fire();
+            return;
+        }
+        let delay = locked_alarm.alarm_next - now;
+        drop(locked_alarm);
+        if self.paused_time.is_none() {
+            arm_alarm(&self.alarm, delay);
+        }
+    }
+
+    /// Clock driving rtc.
+    pub fn clock(&self) -> RtcClock {
+        self.clock
+    }
+
+    fn clear_alarm(&mut self) {
+        let mut locked_alarm = self.alarm.lock().unwrap();
+        locked_alarm.alarm_running = false;
+        locked_alarm.timer.cancel();
+    }
+}
+
+impl SysBusDevOps for GoldfishRtc {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 4 {
+            return AccessResult::UnsupportedSize;
+        }
+
+        let value = match offset {
+            RTC_TIME_LOW => {
+                let time = self.guest_time_ns();
+                self.time_high = (time >> 32) as u32;
+                time as u32
+            }
+            RTC_TIME_HIGH => self.time_high,
+            RTC_ALARM_LOW => self.alarm.lock().unwrap().alarm_next as u32,
+            RTC_ALARM_HIGH => (self.alarm.lock().unwrap().alarm_next >> 32) as u32,
+            RTC_IRQ_ENABLED => self.alarm.lock().unwrap().irq_enabled as u32,
+            RTC_ALARM_STATUS => self.alarm.lock().unwrap().alarm_running as u32,
+            _ => return AccessResult::BadOffset,
+        };
+        LittleEndian::write_u32(data, value);
+        AccessResult::Ok
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 4 {
+            return AccessResult::UnsupportedSize;
+        }
+
+        let value = LittleEndian::read_u32(data);
+        match offset {
+            RTC_TIME_LOW | RTC_TIME_HIGH => {
+                let time = deposit_u32(self.guest_time_ns(), value, offset == RTC_TIME_HIGH);
+                debug!("Set rtc time to {} ns", time);
+                self.set_guest_time_ns(time);
+            }
+            // Guest writes high 32 bits first, the alarm starts when low 32 bits are written.
+            RTC_ALARM_LOW => {
+                let mut locked_alarm = self.alarm.lock().unwrap();
+                locked_alarm.alarm_next = deposit_u32(locked_alarm.alarm_next, value, false);
+                drop(locked_alarm);
+                self.set_alarm();
+            }
+            RTC_ALARM_HIGH => {
+                let mut locked_alarm = self.alarm.lock().unwrap();
+                locked_alarm.alarm_next = deposit_u32(locked_alarm.alarm_next, value, true);
+            }
+            RTC_IRQ_ENABLED => {
+                let mut locked_alarm = self.alarm.lock().unwrap();
+                locked_alarm.irq_enabled = value & 1 != 0;
+                locked_alarm.update_irq();
+            }
+            RTC_CLEAR_ALARM => self.clear_alarm(),
+            RTC_CLEAR_INTERRUPT => {
+                let mut locked_alarm = self.alarm.lock().unwrap();
+                locked_alarm.irq_pending = false;
+                locked_alarm.update_irq();
+            }
+            _ => return AccessResult::BadOffset,
+        }
+        AccessResult::Ok
+    }
+
+    fn needs_irq_line(&self) -> bool {
+        true
+    }
+
+    fn set_irq_line(&mut self, line: SysBusIrqLine) {
+        self.alarm.lock().unwrap().irq_line = Some(line);
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::Rtc
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // Guest time set before is kept, the interrupt line is deasserted by sysbus.
+        self.time_high = 0;
+        let mut locked_alarm = self.alarm.lock().unwrap();
+        locked_alarm.alarm_next = 0;
+        locked_alarm.alarm_running = false;
+        locked_alarm.irq_enabled = false;
+        locked_alarm.irq_pending = false;
+        locked_alarm.timer.cancel();
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        if self.clock == RtcClock::Vm && self.paused_time.is_none() {
+            self.paused_time = Some(self.guest_time_ns());
+        }
+        // Drop the armed timer, it's re-armed on resume.
+        self.alarm.lock().unwrap().timer.cancel();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        // Continue from the time when vm is paused, so that guest wall clock
+        // doesn't jump after a long pause.
+        if let Some(time) = self.paused_time.take() {
+            self.offset = (time as i64).wrapping_sub(host_time_ns() as i64);
+        }
+        if self.alarm.lock().unwrap().alarm_running {
+            self.set_alarm();
+        }
+        Ok(())
+    }
+
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        // Guest time instead of offset is saved, as host time differs after restore.
+        let mut state = [0_u8; RTC_STATE_SIZE];
+        LittleEndian::write_u64(&mut state[0..8], self.guest_time_ns());
+        LittleEndian::write_u32(&mut state[8..12], self.time_high);
+        state[12] = self.paused_time.is_some() as u8;
+        let locked_alarm = self.alarm.lock().unwrap();
+        LittleEndian::write_u64(&mut state[13..21], locked_alarm.alarm_next);
+        state[21] = locked_alarm.irq_enabled as u8;
+        state[22] = locked_alarm.alarm_running as u8;
+        state[23] = locked_alarm.irq_pending as u8;
+        Ok(encode_state(RTC_STATE_VERSION, &state))
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let (version, state) = decode_state(data, RTC_STATE_VERSION)?;
+        let expected_size = match version {
+            1 => RTC_STATE_SIZE_V1,
+            _ => RTC_STATE_SIZE,
+        };
+        if state.len() != expected_size {
+            bail!("Invalid rtc state size {}", state.len());
+        }
+        let time = LittleEndian::read_u64(&state[0..8]);
+        self.time_high = LittleEndian::read_u32(&state[8..12]);
+        if state[12] != 0 {
+            self.paused_time = Some(time);
+        } else {
+            self.paused_time = None;
+            self.offset = (time as i64).wrapping_sub(host_time_ns() as i64);
+        }
+
+        // Alarm is off in state of version 1.
+        let mut locked_alarm = self.alarm.lock().unwrap();
+        locked_alarm.timer.cancel();
+        if version == 1 {
+            locked_alarm.alarm_running = false;
+            return Ok(());
+        }
+        locked_alarm.alarm_next = LittleEndian::read_u64(&state[13..21]);
+        locked_alarm.irq_enabled = state[21] != 0;
+        locked_alarm.alarm_running = state[22] != 0;
+        locked_alarm.irq_pending = state[23] != 0;
+        locked_alarm.update_irq();
+        let alarm_running = locked_alarm.alarm_running;
+        drop(locked_alarm);
+        if alarm_running {
+            self.set_alarm();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+
+    use machine_manager::event_loop::EventLoop;
+
+    use super::*;
+
+    fn read_time(rtc: &mut GoldfishRtc) -> u64 {
+        let mut low = [0_u8; 4];
+        let mut high = [0_u8; 4];
+        assert!(rtc.read(&mut low, GuestAddress(0), RTC_TIME_LOW).is_ok());
+        assert!(rtc.read(&mut high, GuestAddress(0), RTC_TIME_HIGH).is_ok());
+        u64::from(LittleEndian::read_u32(&high)) << 32 | u64::from(LittleEndian::read_u32(&low))
+    }
+
+    #[test]
+    fn test_rtc_read_time() {
+        let mut rtc = GoldfishRtc::new().unwrap();
+        let before = host_time_ns();
+        let time = read_time(&mut rtc);
+        assert!(time >= before && time <= host_time_ns());
+    }
+
+    fn write_reg(rtc: &mut GoldfishRtc, offset: u64, val: u32) {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, val);
+        assert!(rtc.write(&data, GuestAddress(0), offset).is_ok());
+    }
+
+    fn read_reg(rtc: &mut GoldfishRtc, offset: u64) -> u32 {
+        let mut data = [0_u8; 4];
+        assert!(rtc.read(&mut data, GuestAddress(0), offset).is_ok());
+        LittleEndian::read_u32(&data)
+    }
+
+    /// Set alarm the same way as guest driver, high 32 bits first.
+    fn set_alarm(rtc: &mut GoldfishRtc, time: u64) {
+        write_reg(rtc, RTC_ALARM_HIGH, (time >> 32) as u32);
+        write_reg(rtc, RTC_ALARM_LOW, time as u32);
+    }
+
+    fn irq_pending(rtc: &GoldfishRtc) -> bool {
+        rtc.alarm.lock().unwrap().irq_pending
+    }
+
+    #[test]
+    fn test_rtc_set_time() {
+        let mut rtc = GoldfishRtc::new().unwrap();
+        let time = host_time_ns() + Duration::from_secs(3600).as_nanos() as u64;
+        write_reg(&mut rtc, RTC_TIME_HIGH, (time >> 32) as u32);
+        write_reg(&mut rtc, RTC_TIME_LOW, time as u32);
+        let now = read_time(&mut rtc);
+        assert!(now >= time && now - time < Duration::from_millis(50).as_nanos() as u64);
+
+        // Guest time survives vm reset.
+        rtc.reset().unwrap();
+        assert!(read_time(&mut rtc) >= time);
+
+        // Set time while paused.
+        rtc.pause().unwrap();
+        let paused = read_time(&mut rtc);
+        write_reg(&mut rtc, RTC_TIME_LOW, 0);
+        assert_eq!(read_time(&mut rtc), paused & !0xffff_ffff);
+    }
+
+    #[test]
+    fn test_rtc_alarm_in_past() {
+        let mut rtc = GoldfishRtc::new().unwrap();
+        write_reg(&mut rtc, RTC_IRQ_ENABLED, 1);
+        assert_eq!(read_reg(&mut rtc, RTC_IRQ_ENABLED), 1);
+
+        // Alarm in the past fires at once.
+        let alarm = read_time(&mut rtc) - Duration::from_secs(1).as_nanos() as u64;
+        set_alarm(&mut rtc, alarm);
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_LOW), alarm as u32);
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_HIGH), (alarm >> 32) as u32);
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_STATUS), 0);
+        assert!(irq_pending(&rtc));
+
+        write_reg(&mut rtc, RTC_CLEAR_INTERRUPT, 1);
+        assert!(!irq_pending(&rtc));
+    }
+
+    #[test]
+    fn test_rtc_alarm() {
+        EventLoop::object_init(&None).unwrap();
+        let mut rtc = GoldfishRtc::new().unwrap();
+        write_reg(&mut rtc, RTC_IRQ_ENABLED, 1);
+
+        set_alarm(&mut rtc, read_time(&mut rtc) + 10_000_000);
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_STATUS), 1);
+        assert!(!irq_pending(&rtc));
+        sleep(Duration::from_millis(20));
+        EventLoop::get_ctx(None).unwrap().run_timers();
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_STATUS), 0);
+        assert!(irq_pending(&rtc));
+        write_reg(&mut rtc, RTC_CLEAR_INTERRUPT, 1);
+
+        // Cancelled alarm never fires.
+        set_alarm(&mut rtc, read_time(&mut rtc) + 10_000_000);
+        write_reg(&mut rtc, RTC_CLEAR_ALARM, 1);
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_STATUS), 0);
+        sleep(Duration::from_millis(20));
+        EventLoop::get_ctx(None).unwrap().run_timers();
+        assert!(!irq_pending(&rtc));
+
+        // Alarm is held while paused.
+        set_alarm(&mut rtc, read_time(&mut rtc) + 10_000_000);
+        rtc.pause().unwrap();
+        sleep(Duration::from_millis(20));
+        EventLoop::get_ctx(None).unwrap().run_timers();
+        assert!(!irq_pending(&rtc));
+        rtc.resume().unwrap();
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_STATUS), 1);
+
+        // Reset cancels the alarm.
+        rtc.reset().unwrap();
+        assert_eq!(read_reg(&mut rtc, RTC_ALARM_STATUS), 0);
+        assert_eq!(read_reg(&mut rtc, RTC_IRQ_ENABLED), 0);
+    }
+
+    #[test]
+    fn test_rtc_pause_resume() {
+        let mut rtc = GoldfishRtc::new().unwrap();
+        rtc.pause().unwrap();
+        let paused = read_time(&mut rtc);
+        sleep(Duration::from_millis(50));
+        // Time is frozen while paused.
+        assert_eq!(read_time(&mut rtc), paused);
+
+        rtc.resume().unwrap();
+        let resumed = read_time(&mut rtc);
+        assert!(resumed >= paused);
+        assert!(resumed - paused < Duration::from_millis(50).as_nanos() as u64);
+        assert!(rtc.offset < 0);
+
+        // Pause and resume again round-trips the offset.
+        let offset = rtc.offset;
+        rtc.pause().unwrap();
+        rtc.resume().unwrap();
+        assert!((rtc.offset - offset).abs() < Duration::from_millis(10).as_nanos() as i64);
+    }
+
+    #[test]
+    fn test_rtc_config() {
+        // Fixed start time.
+        let config = RtcConfig {
+            base: RtcBase::Datetime(1_150_560_081),
+            clock: RtcClock::Vm,
+        };
+        let mut rtc = GoldfishRtc::with_config(&config).unwrap();
+        let start = 1_150_560_081 * 1_000_000_000;
+        let time = read_time(&mut rtc);
+        assert!(time >= start && time - start < Duration::from_millis(50).as_nanos() as u64);
+        assert_eq!(rtc.clock(), RtcClock::Vm);
+
+        // Local time differs from utc by the offset of host timezone.
+        let config = RtcConfig {
+            base: RtcBase::Localtime,
+            clock: RtcClock::Host,
+        };
+        let mut rtc = GoldfishRtc::with_config(&config).unwrap();
+        let expected = host_time_ns() as i64 + host_utc_offset() * 1_000_000_000;
+        let time = read_time(&mut rtc) as i64;
+        assert!((time - expected).abs() < Duration::from_millis(50).as_nanos() as i64);
+
+        // Host clock keeps going while paused.
+        rtc.pause().unwrap();
+        let paused = read_time(&mut rtc);
+        sleep(Duration::from_millis(20));
+        assert!(read_time(&mut rtc) >= paused + Duration::from_millis(20).as_nanos() as u64);
+        rtc.resume().unwrap();
+        assert!(rtc.paused_time.is_none());
+
+        let config = RtcConfig {
+            base: RtcBase::Datetime(i64::MAX),
+            clock: RtcClock::Vm,
+        };
+        assert!(GoldfishRtc::with_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_rtc_state_round_trip() {
+        let mut rtc = GoldfishRtc::new().unwrap();
+        rtc.offset = -(Duration::from_secs(3600).as_nanos() as i64);
+        rtc.pause().unwrap();
+        let time = read_time(&mut rtc);
+        let state = rtc.state_bytes().unwrap();
+
+        let mut restored = GoldfishRtc::new().unwrap();
+        restored.restore_state(&state).unwrap();
+        // High 32 bits latched before saving are kept.
+        let mut high = [0_u8; 4];
+        assert!(restored
+            .read(&mut high, GuestAddress(0), RTC_TIME_HIGH)
+            .is_ok());
+        assert_eq!(LittleEndian::read_u32(&high), (time >> 32) as u32);
+        assert_eq!(read_time(&mut restored), time);
+
+        // Running clock continues from the saved time.
+        rtc.resume().unwrap();
+        let state = rtc.state_bytes().unwrap();
+        let mut restored = GoldfishRtc::new().unwrap();
+        restored.restore_state(&state).unwrap();
+        assert!(restored.paused_time.is_none());
+        let now = read_time(&mut restored);
+        assert!(now >= time && now - time < Duration::from_millis(50).as_nanos() as u64);
+
+        // Fired alarm with interrupt pending.
+        write_reg(&mut rtc, RTC_IRQ_ENABLED, 1);
+        set_alarm(&mut rtc, 1 << 32 | 1);
+        let state = rtc.state_bytes().unwrap();
+        let mut restored = GoldfishRtc::new().unwrap();
+        restored.restore_state(&state).unwrap();
+        assert_eq!(read_reg(&mut restored, RTC_ALARM_HIGH), 1);
+        assert_eq!(read_reg(&mut restored, RTC_IRQ_ENABLED), 1);
+        assert_eq!(read_reg(&mut restored, RTC_ALARM_STATUS), 0);
+        assert!(irq_pending(&restored));
+
+        assert!(restored.restore_state(&state[..state.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress};
+use anyhow::{anyhow, Context, Result};
+use log::error;
+use machine_manager::config::ChardevConfig;
+use machine_manager::event_loop::EventLoop;
+use machine_manager::machine::SbiConsoleError;
+use util::boot_time::{record_boot_milestone, BootMilestone};
+use util::loop_context::EventNotifierHelper;
+
+use super::chardev::{Chardev, InputReceiver};
+use super::error::LegacyError;
+
+/// Bytes transferred by one call at most, guest calls again for the rest.
+const SBI_CONSOLE_XFER_MAX: u64 = 4096;
+/// Size of the buffer holding input which guest has not read yet.
+const SBI_CONSOLE_INPUT_SIZE: usize = 4096;
+
+type ConsoleResult<T> = std::result::Result<T, SbiConsoleError>;
+
+/// Input of SBI debug console, buffered until guest polls it.
+#[derive(Default)]
+struct SbiConsoleInput {
+    buffer: VecDeque<u8>,
+}
+
+impl InputReceiver for SbiConsoleInput {
+    fn input_handle(&mut self, data: &[u8]) {
+        let len = data.len().min(self.get_remain_space_size());
+        self.buffer.extend(&data[..len]);
+    }
+
+    fn get_remain_space_size(&mut self) -> usize {
+        SBI_CONSOLE_INPUT_SIZE - self.buffer.len()
+    }
+}
+
+/// Backend of SBI debug console extension, through which guest prints before any
+/// console driver is probed.
+///
+/// It shares the chardev of serial, whose input belongs to serial so reads are not
+/// supported, or owns a dedicated chardev given by `-sbi-console`.
+pub struct SbiDebugConsole {
+    chardev: Arc<Mutex<Chardev>>,
+    /// Input of the dedicated chardev.
+    input: Option<Arc<Mutex<SbiConsoleInput>>>,
+    sys_mem: Arc<AddressSpace>,
+}
+
+impl SbiDebugConsole {
+    /// Create console sharing `chardev` of serial, which realizes it.
+    pub fn new_shared(chardev: Arc<Mutex<Chardev>>, sys_mem: Arc<AddressSpace>) -> Self {
+        SbiDebugConsole {
+            chardev,
+            input: None,
+            sys_mem,
+        }
+    }
+
+    /// Create console on a dedicated chardev, which is realized here.
+    pub fn new_dedicated(cfg: ChardevConfig, sys_mem: Arc<AddressSpace>) -> Result<Self> {
+        let mut chardev = Chardev::new(cfg);
+        chardev
+            .realize()
+            .with_context(|| "Failed to realize chardev")?;
+        let chardev = Arc::new(Mutex::new(chardev));
+        let input = Arc::new(Mutex::new(SbiConsoleInput::default()));
+        chardev.lock().unwrap().set_input_callback(&input);
+        EventLoop::update_event(
+            EventNotifierHelper::internal_notifiers(chardev.clone()),
+            None,
+        )
+        .with_context(|| anyhow!(LegacyError::RegNotifierErr))?;
+
+        Ok(SbiDebugConsole {
+            chardev,
+            input: Some(input),
+            sys_mem,
+        })
+    }
+
+    /// Character device of console.
+    pub fn chardev(&self) -> Arc<Mutex<Chardev>> {
+        self.chardev.clone()
+    }
+
+    /// Write bytes at `addr` of guest, returns the bytes written.
+    pub fn write(&self, addr: u64, len: u64) -> ConsoleResult<Vec<u8>> {
+        let len = self.guest_buffer(addr, len)?;
+        let mut data = Vec::with_capacity(len as usize);
+        self.sys_mem
+            .read(&mut data, GuestAddress(addr), len)
+            .map_err(|e| {
+                error!("Failed to read sbi console buffer 0x{:x}: {:?}", addr, e);
+                SbiConsoleError::Failed
+            })?;
+        self.output(&data)?;
+        Ok(data)
+    }
+
+    /// Read input to `addr` of guest, returns the number of bytes read.
+    pub fn read(&self, addr: u64, len: u64) -> ConsoleResult<u64> {
+        let input = self.input.as_ref().ok_or(SbiConsoleError::NotSupported)?;
+        let len = self.guest_buffer(addr, len)?;
+        let data: Vec<u8> = {
+            let mut locked_input = input.lock().unwrap();
+            let count = locked_input.buffer.len().min(len as usize);
+            locked_input.buffer.drain(..count).collect()
+        };
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.sys_mem
+            .write(&mut data.as_slice(), GuestAddress(addr), data.len() as u64)
+            .map_err(|e| {
+                error!("Failed to write sbi console buffer 0x{:x}: {:?}", addr, e);
+                SbiConsoleError::Failed
+            })?;
+        Ok(data.len() as u64)
+    }
+
+    pub fn write_byte(&self, byte: u8) -> ConsoleResult<()> {
+        self.output(&[byte])
+    }
+
+    /// Check guest buffer at `addr`, whose length is clamped to what one call transfers.
+    fn guest_buffer(&self, addr: u64, len: u64) -> ConsoleResult<u64> {
+        let len = len.min(SBI_CONSOLE_XFER_MAX);
+        if addr.checked_add(len).is_none()
+            || !self.sys_mem.address_in_memory(GuestAddress(addr), len)
+        {
+            return Err(SbiConsoleError::InvalidParam);
+        }
+        Ok(len)
+    }
+
+    fn output(&self, data: &[u8]) -> ConsoleResult<()> {
+        // Output is dropped while socket backend is not connected.
+        let output = match self.chardev.lock().unwrap().output.clone() {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let mut locked_output = output.lock().unwrap();
+        locked_output
+            .write_all(data)
+            .and_then(|_| locked_output.flush())
+            .map_err(|e| {
+                error!("Failed to write sbi console: {:?}", e);
+                SbiConsoleError::Failed
+            })?;
+        record_boot_milestone(BootMilestone::FirstSerialOutput);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use address_space::{HostMemMapping, Region};
+    use machine_manager::config::ChardevType;
+
+    use super::*;
+
+    const RAM_SIZE: u64 = 0x10_0000;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, RAM_SIZE, None, false, false, false)
+                .unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(Region::init_ram_region(host_mmap), 0)
+            .unwrap();
+        sys_space
+    }
+
+    fn file_chardev(path: &str) -> Arc<Mutex<Chardev>> {
+        let mut chardev = Chardev::new(ChardevConfig {
+            id: "sbi0".to_string(),
+            backend: ChardevType::File(path.to_string()),
+        });
+        chardev.realize().unwrap();
+        Arc::new(Mutex::new(chardev))
+    }
+
+    #[test]
+    fn test_sbi_console_write() {
+        let path = "/tmp/test_sbi_console_write.log";
+        let sys_mem = address_space_init();
+        let console = SbiDebugConsole::new_shared(file_chardev(path), sys_mem.clone());
+
+        sys_mem
+            .write(&mut b"hello".as_ref(), GuestAddress(0x1000), 5)
+            .unwrap();
+        assert_eq!(console.write(0x1000, 5).unwrap(), b"hello");
+        assert!(console.write_byte(b'\n').is_ok());
+        assert_eq!(std::fs::read(path).unwrap(), b"hello\n");
+
+        // Long write is partial.
+        assert_eq!(
+            console.write(0, 2 * SBI_CONSOLE_XFER_MAX).unwrap().len() as u64,
+            SBI_CONSOLE_XFER_MAX
+        );
+        // Buffer out of guest memory.
+        assert_eq!(
+            console.write(RAM_SIZE - 2, 5),
+            Err(SbiConsoleError::InvalidParam)
+        );
+        assert_eq!(
+            console.write(u64::MAX - 1, 5),
+            Err(SbiConsoleError::InvalidParam)
+        );
+        // Input belongs to serial.
+        assert_eq!(console.read(0x1000, 5), Err(SbiConsoleError::NotSupported));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sbi_console_read() {
+        let path = "/tmp/test_sbi_console_read.log";
+        let sys_mem = address_space_init();
+        let input = Arc::new(Mutex::new(SbiConsoleInput::default()));
+        let console = SbiDebugConsole {
+            chardev: file_chardev(path),
+            input: Some(input.clone()),
+            sys_mem: sys_mem.clone(),
+        };
+
+        assert_eq!(console.read(0x1000, 4).unwrap(), 0);
+        input.lock().unwrap().input_handle(b"abcdef");
+        assert_eq!(console.read(0x1000, 4).unwrap(), 4);
+        assert_eq!(console.read(0x2000, 4).unwrap(), 2);
+        let mut data = Vec::new();
+        sys_mem.read(&mut data, GuestAddress(0x1000), 4).unwrap();
+        assert_eq!(data, b"abcd");
+        data.clear();
+        sys_mem.read(&mut data, GuestAddress(0x2000), 2).unwrap();
+        assert_eq!(data, b"ef");
+        assert_eq!(
+            console.read(RAM_SIZE, 1),
+            Err(SbiConsoleError::InvalidParam)
+        );
+
+        // Input beyond the buffer is dropped.
+        let mut locked_input = input.lock().unwrap();
+        locked_input.input_handle(&[0_u8; SBI_CONSOLE_INPUT_SIZE + 1]);
+        assert_eq!(locked_input.buffer.len(), SBI_CONSOLE_INPUT_SIZE);
+        assert_eq!(locked_input.get_remain_space_size(), 0);
+        drop(locked_input);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
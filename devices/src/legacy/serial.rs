@@ -20,7 +20,7 @@ use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
 use address_space::GuestAddress;
-use log::error;
+use log::{error, info};
 use machine_manager::config::{BootSource, Param};
 use machine_manager::{config::SerialConfig, event_loop::EventLoop};
 use migration::{
@@ -28,8 +28,13 @@ use migration::{
     MigrationManager, StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
-use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use sysbus::{
+    begin_fdt_node, decode_state, encode_state, AccessResult, SysBus, SysBusDevOps, SysBusDevType,
+    SysRes,
+};
+use util::boot_time::{record_boot_milestone, BootMilestone};
 use util::byte_code::ByteCode;
+use util::device_tree::FdtBuilder;
 use util::loop_context::EventNotifierHelper;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -61,6 +66,9 @@ const UART_MSR_DCD: u8 = 0x80;
 
 const RECEIVER_BUFF_SIZE: usize = 1024;
 
+/// Version of serial state saved by `state_bytes`.
+const SERIAL_STATE_VERSION: u32 = 1;
+
 /// Contain register status of serial device.
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
@@ -134,6 +142,12 @@ impl Serial {
             irq_chip,
         }
     }
+
+    /// Character device of serial, shared with SBI debug console.
+    pub fn chardev(&self) -> Arc<Mutex<Chardev>> {
+        self.chardev.clone()
+    }
+
     pub fn realize(
         mut self,
         sysbus: &mut SysBus,
@@ -147,11 +161,13 @@ impl Serial {
             .realize()
             .with_context(|| "Failed to realize chardev")?;
         self.interrupt_evt = Some(EventFd::new(libc::EFD_NONBLOCK)?);
-        self.set_sys_resource(sysbus, region_base, region_size)
+        let irq_mode = self
+            .set_sys_resource(sysbus, region_base, region_size)
             .with_context(|| anyhow!(LegacyError::SetSysResErr))?;
+        info!("Serial irq {} is delivered by {:?}", self.res.irq, irq_mode);
 
         let dev = Arc::new(Mutex::new(self));
-        sysbus.attach_device(&dev, region_base, region_size)?;
+        sysbus.attach_device(&dev, Some(region_base), region_size)?;
 
         MigrationManager::register_device_instance(
             SerialState::descriptor(),
@@ -192,7 +208,7 @@ impl Serial {
             if irq_chip
                 .lock()
                 .unwrap()
-                .kvm_irq_line(self.get_sys_resource().unwrap().irq as u8, 1)
+                .set_irq_level(self.res.irq as u8, self.res.region_base, 1)
                 .is_err()
             {
                 error!("serial: failed to update iir.");
@@ -202,7 +218,7 @@ impl Serial {
             if irq_chip
                 .lock()
                 .unwrap()
-                .kvm_irq_line(self.get_sys_resource().unwrap().irq as u8, 0)
+                .set_irq_level(self.res.irq as u8, self.res.region_base, 0)
                 .is_err()
             {
                 error!("serial: failed to update iir.");
@@ -309,18 +325,27 @@ impl Serial {
                         self.rbr.push_back(data);
                         self.state.lsr |= UART_LSR_DR;
                     } else {
-                        let output = self.chardev.lock().unwrap().output.clone();
-                        if output.is_none() {
+                        let (output, listening) = {
+                            let locked_chardev = self.chardev.lock().unwrap();
+                            (
+                                locked_chardev.output.clone(),
+                                locked_chardev.listener.is_some(),
+                            )
+                        };
+                        if let Some(output) = output {
+                            let mut locked_output = output.lock().unwrap();
+                            locked_output
+                                .write_all(&[data])
+                                .with_context(|| "serial: failed to write.")?;
+                            locked_output
+                                .flush()
+                                .with_context(|| "serial: failed to flush.")?;
+                            record_boot_milestone(BootMilestone::FirstSerialOutput);
+                        } else if !listening {
                             self.update_iir();
                             bail!("serial: failed to get output fd.");
                         }
-                        let mut locked_output = output.as_ref().unwrap().lock().unwrap();
-                        locked_output
-                            .write_all(&[data])
-                            .with_context(|| "serial: failed to write.")?;
-                        locked_output
-                            .flush()
-                            .with_context(|| "serial: failed to flush.")?;
+                        // Output is dropped while the server socket has no client.
                     }
 
                     self.update_iir();
@@ -378,13 +403,16 @@ impl InputReceiver for Serial {
 }
 
 impl SysBusDevOps for Serial {
-    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
         data[0] = self.read_internal(offset);
-        true
+        AccessResult::Ok
     }
 
-    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
-        self.write_internal(offset, data[0]).is_ok()
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        match self.write_internal(offset, data[0]) {
+            Ok(()) => AccessResult::Ok,
+            Err(_) => AccessResult::Failed,
+        }
     }
 
     fn interrupt_evt(&self) -> Option<&EventFd> {
@@ -398,6 +426,36 @@ impl SysBusDevOps for Serial {
     fn get_type(&self) -> SysBusDevType {
         SysBusDevType::Serial
     }
+
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        if let Some(node_dep) = begin_fdt_node(parent, SysBusDevType::Serial, &self.res)? {
+            parent.set_property_u32("clock-frequency", 3686400)?;
+            parent.end_node(node_dep)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.rbr.clear();
+        self.state = SerialState::new();
+        Ok(())
+    }
+
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        let state = self.get_state_vec()?;
+        Ok(encode_state(SERIAL_STATE_VERSION, &state))
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let (_, payload) = decode_state(data, SERIAL_STATE_VERSION)?;
+        // Copy the payload to keep the alignment of `SerialState`.
+        let mut state = SerialState::default();
+        if payload.len() != state.as_bytes().len() {
+            bail!("Invalid serial state size {}", payload.len());
+        }
+        state.as_mut_bytes().copy_from_slice(payload);
+        self.set_state_mut(state.as_bytes())
+    }
 }
 
 impl StateTransfer for Serial {
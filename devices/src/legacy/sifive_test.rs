@@ -0,0 +1,222 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::GuestAddress;
+use anyhow::{anyhow, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{error, info, warn};
+use machine_manager::signal_handler::set_vm_exit_code;
+use sysbus::{AccessResult, SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use util::boot_time::{record_boot_milestone, BootMilestone};
+use util::device_tree::{self, FdtBuilder};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::error::LegacyError;
+
+/// Finisher register, the low 16 bits are status and the high 16 bits are exit code.
+const FINISHER_REG: u64 = 0x00;
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+const FINISHER_STATUS_MASK: u32 = 0xffff;
+const FINISHER_CODE_SHIFT: u32 = 16;
+/// Doorbell rung by guest once it has booted, e.g. by an init script, for `-boottime`.
+const BOOT_DONE_REG: u64 = 0x04;
+
+/// SiFive test finisher, through which guest powers off the machine with an exit code
+/// of process or reboots it. Linux binds it by `syscon-poweroff` and `syscon-reboot`.
+/// Any write to the register next to the finisher reports that guest has booted.
+///
+/// Process exits once the main loop is over, so temporary files are cleaned as usual.
+pub struct SifiveTest {
+    /// Notified when guest powers off the machine.
+    poweroff_req: Arc<EventFd>,
+    /// Notified when guest reboots the machine.
+    reboot_req: Arc<EventFd>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl SifiveTest {
+    pub fn new(poweroff_req: Arc<EventFd>, reboot_req: Arc<EventFd>) -> Self {
+        SifiveTest {
+            poweroff_req,
+            reboot_req,
+            res: SysRes::default(),
+        }
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .with_context(|| anyhow!(LegacyError::SetSysResErr))?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach sifive test device")?;
+        Ok(dev)
+    }
+
+    fn notify(evt: &EventFd, name: &str) -> AccessResult {
+        match evt.write(1) {
+            Ok(()) => AccessResult::Ok,
+            Err(e) => {
+                error!("Failed to request {}: {:?}", name, e);
+                AccessResult::Failed
+            }
+        }
+    }
+}
+
+impl SysBusDevOps for SifiveTest {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 2 && data.len() != 4 {
+            return AccessResult::UnsupportedSize;
+        }
+        if offset != FINISHER_REG && offset != BOOT_DONE_REG {
+            return AccessResult::BadOffset;
+        }
+        data.fill(0);
+        AccessResult::Ok
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        let value = match data.len() {
+            2 => u32::from(LittleEndian::read_u16(data)),
+            4 => LittleEndian::read_u32(data),
+            _ => return AccessResult::UnsupportedSize,
+        };
+        if offset == BOOT_DONE_REG {
+            record_boot_milestone(BootMilestone::GuestBootComplete);
+            return AccessResult::Ok;
+        }
+        if offset != FINISHER_REG {
+            return AccessResult::BadOffset;
+        }
+
+        let code = (value >> FINISHER_CODE_SHIFT) as i32;
+        match value & FINISHER_STATUS_MASK {
+            FINISHER_PASS => {
+                info!("Guest powers off by sifive test: pass");
+                set_vm_exit_code(0);
+                SifiveTest::notify(&self.poweroff_req, "poweroff")
+            }
+            FINISHER_FAIL => {
+                info!("Guest powers off by sifive test: fail, code {}", code);
+                set_vm_exit_code(code);
+                SifiveTest::notify(&self.poweroff_req, "poweroff")
+            }
+            FINISHER_RESET => SifiveTest::notify(&self.reboot_req, "reboot"),
+            status => {
+                warn!("Invalid sifive test status 0x{:x}", status);
+                AccessResult::Ok
+            }
+        }
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::SifiveTest
+    }
+
+    /// The finisher is a syscon, whose poweroff and reboot nodes are bound by linux.
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        let node_dep = parent.begin_node(&format!("test@{:x}", self.res.region_base))?;
+        parent.set_property("compatible", b"sifive,test1\0sifive,test0\0syscon\0")?;
+        parent.set_property_array_u64("reg", &[self.res.region_base, self.res.region_size])?;
+        parent.set_property_u32("phandle", device_tree::SIFIVE_TEST_PHANDLE)?;
+        parent.end_node(node_dep)?;
+
+        for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            let node_dep = parent.begin_node(name)?;
+            parent.set_property_string("compatible", &format!("syscon-{}", name))?;
+            parent.set_property_u32("regmap", device_tree::SIFIVE_TEST_PHANDLE)?;
+            parent.set_property_u32("offset", FINISHER_REG as u32)?;
+            parent.set_property_u32("value", value)?;
+            parent.end_node(node_dep)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use machine_manager::signal_handler::vm_exit_code;
+
+    use super::*;
+
+    fn new_test_dev() -> (SifiveTest, Arc<EventFd>, Arc<EventFd>) {
+        let poweroff_req = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let reboot_req = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let dev = SifiveTest::new(poweroff_req.clone(), reboot_req.clone());
+        (dev, poweroff_req, reboot_req)
+    }
+
+    fn write_reg(dev: &mut SifiveTest, value: u32) -> AccessResult {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        dev.write(&data, GuestAddress(0), FINISHER_REG)
+    }
+
+    #[test]
+    fn test_sifive_test_finisher() {
+        let (mut dev, poweroff_req, reboot_req) = new_test_dev();
+
+        assert!(write_reg(&mut dev, 3 << FINISHER_CODE_SHIFT | FINISHER_FAIL).is_ok());
+        assert_eq!(poweroff_req.read().unwrap(), 1);
+        assert_eq!(vm_exit_code(), 3);
+
+        // Exit code given with pass is ignored.
+        assert!(write_reg(&mut dev, 3 << FINISHER_CODE_SHIFT | FINISHER_PASS).is_ok());
+        assert_eq!(poweroff_req.read().unwrap(), 1);
+        assert_eq!(vm_exit_code(), 0);
+
+        assert!(write_reg(&mut dev, FINISHER_RESET).is_ok());
+        assert_eq!(reboot_req.read().unwrap(), 1);
+
+        // Invalid status does nothing.
+        assert!(write_reg(&mut dev, 0x1234).is_ok());
+        assert!(poweroff_req.read().is_err());
+        assert!(reboot_req.read().is_err());
+
+        let mut data = [0xff_u8; 4];
+        assert!(dev.read(&mut data, GuestAddress(0), FINISHER_REG).is_ok());
+        assert_eq!(data, [0_u8; 4]);
+        assert_eq!(
+            dev.write(&data, GuestAddress(0), 0x8),
+            AccessResult::BadOffset
+        );
+        // Boot done doorbell doesn't power off.
+        assert!(dev.write(&data, GuestAddress(0), BOOT_DONE_REG).is_ok());
+        assert!(poweroff_req.read().is_err());
+        assert_eq!(
+            dev.write(&data[..1], GuestAddress(0), FINISHER_REG),
+            AccessResult::UnsupportedSize
+        );
+        // Halfword write carries status only.
+        assert!(dev.write(&data[..2], GuestAddress(0), FINISHER_REG).is_ok());
+        LittleEndian::write_u16(&mut data, FINISHER_PASS as u16);
+        assert!(dev.write(&data[..2], GuestAddress(0), FINISHER_REG).is_ok());
+        assert_eq!(poweroff_req.read().unwrap(), 1);
+    }
+}
@@ -0,0 +1,557 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use address_space::GuestAddress;
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, error};
+use sysbus::{
+    begin_fdt_node, decode_state, encode_state, AccessResult, ConstRegister, SysBus, SysBusDevOps,
+    SysBusDevType, SysRes,
+};
+use util::device_tree::{self, FdtBuilder};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::error::LegacyError;
+use crate::timer::DeviceTimer;
+
+/// Registers of DesignWare APB watchdog timer, refer to linux `drivers/watchdog/dw_wdt.c`.
+const WDT_CR: u64 = 0x00;
+const WDT_TORR: u64 = 0x04;
+const WDT_CCVR: u64 = 0x08;
+const WDT_CRR: u64 = 0x0c;
+const WDT_STAT: u64 = 0x10;
+const WDT_EOI: u64 = 0x14;
+const WDT_COMP_PARAMS_5: u64 = 0xe4;
+const WDT_COMP_PARAMS_4: u64 = 0xe8;
+const WDT_COMP_PARAMS_3: u64 = 0xec;
+const WDT_COMP_PARAMS_2: u64 = 0xf0;
+const WDT_COMP_PARAMS_1: u64 = 0xf4;
+const WDT_COMP_VERSION: u64 = 0xf8;
+const WDT_COMP_TYPE: u64 = 0xfc;
+
+const WDT_CR_EN: u32 = 1 << 0;
+/// Response mode, the first timeout raises interrupt and the second one resets system.
+const WDT_CR_RMOD: u32 = 1 << 1;
+const WDT_TORR_TOP_MASK: u32 = 0xf;
+const WDT_TORR_TOP_INIT_SHIFT: u32 = 4;
+/// Value written to CRR to restart the counter.
+const WDT_CRR_KICK: u32 = 0x76;
+/// Timeout periods are the fixed 2^(16 + TOP) cycles.
+const WDT_PARAMS_1_USE_FIX_TOP: u32 = 1 << 6;
+const WDT_VERSION: u32 = 0x3130_332a;
+const WDT_TYPE: u32 = 0x4457_0120;
+
+/// Frequency of the fixed clock of the counter, timeout ranges from 65ms to 35 minutes.
+const WDT_CLOCK_FREQ: u64 = 1_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Version of watchdog state saved by `state_bytes`.
+const WDT_STATE_VERSION: u32 = 1;
+/// Size of watchdog state: CR, TORR, running and interrupt status flags, and
+/// nanoseconds left of the counter.
+const WDT_STATE_SIZE: usize = 18;
+
+/// Timeout in nanoseconds selected by `top`, which is 2^(16 + top) cycles.
+fn timeout_ns(top: u32) -> u64 {
+    (1_u64 << (16 + (top & WDT_TORR_TOP_MASK))) * (NANOS_PER_SEC / WDT_CLOCK_FREQ)
+}
+
+/// Counter state shared between watchdog and the timer armed in main loop.
+struct WdtCounter {
+    /// Whether the counter is counting down, it stops when the watchdog expires.
+    running: bool,
+    /// Host time when the counter reaches zero, valid while running and vm isn't paused.
+    deadline: Instant,
+    /// Whether vm is paused, the counter is frozen at `paused_left`.
+    paused: bool,
+    /// Nanoseconds left of the counter when vm is paused.
+    paused_left: u64,
+    /// RMOD bit of CR taken when the counter is loaded.
+    resp_mode: bool,
+    /// Interrupt status, set by the first timeout in interrupt mode.
+    irq_pending: bool,
+    /// Timeout in nanoseconds reloaded after the first timeout in interrupt mode.
+    period: u64,
+    timer: DeviceTimer,
+    /// Notified when the watchdog expires, the machine takes the configured action.
+    expired: Arc<EventFd>,
+}
+
+impl WdtCounter {
+    fn new(expired: Arc<EventFd>) -> Self {
+        WdtCounter {
+            running: false,
+            deadline: Instant::now(),
+            paused: false,
+            paused_left: 0,
+            resp_mode: false,
+            irq_pending: false,
+            period: 0,
+            timer: DeviceTimer::default(),
+            expired,
+        }
+    }
+
+    /// Nanoseconds left until the counter reaches zero.
+    fn left_ns(&self) -> u64 {
+        if !self.running {
+            0
+        } else if self.paused {
+            self.paused_left
+        } else {
+            self.deadline
+                .saturating_duration_since(Instant::now())
+                .as_nanos() as u64
+        }
+    }
+
+    fn stop(&mut self) {
+        self.running = false;
+        self.timer.cancel();
+    }
+}
+
+/// Count down `left` nanoseconds from now, timers armed before are dropped. The
+/// counter is frozen instead while vm is paused.
+fn start_countdown(counter: &Arc<Mutex<WdtCounter>>, left: u64) {
+    let mut locked_counter = counter.lock().unwrap();
+    locked_counter.running = true;
+    if locked_counter.paused {
+        locked_counter.timer.cancel();
+        locked_counter.paused_left = left;
+        return;
+    }
+    locked_counter.deadline = Instant::now() + Duration::from_nanos(left);
+
+    let weak_counter: Weak<Mutex<WdtCounter>> = Arc::downgrade(counter);
+    let func = move || {
+        if let Some(counter) = weak_counter.upgrade() {
+            counter_timeout(&counter);
+        }
+    };
+    locked_counter.timer.arm(Duration::from_nanos(left), func);
+}
+
+/// The counter reaches zero. In interrupt mode the first timeout only sets the interrupt
+/// status and reloads the counter, the watchdog expires if the guest doesn't restart it
+/// before the second one.
+fn counter_timeout(counter: &Arc<Mutex<WdtCounter>>) {
+    let mut locked_counter = counter.lock().unwrap();
+    if !locked_counter.timer.is_current() {
+        return;
+    }
+    if locked_counter.resp_mode && !locked_counter.irq_pending {
+        locked_counter.irq_pending = true;
+        let period = locked_counter.period;
+        drop(locked_counter);
+        start_countdown(counter, period);
+        return;
+    }
+
+    locked_counter.stop();
+    debug!("Watchdog expires");
+    if let Err(e) = locked_counter.expired.write(1) {
+        error!("Failed to notify watchdog expiry: {:?}", e);
+    }
+}
+
+/// DesignWare APB watchdog timer. Once enabled, the guest must restart the counter
+/// before it reaches zero, otherwise the watchdog expires and the machine takes the
+/// action given by `-watchdog-action`. Only reset disables it.
+///
+/// There is no interrupt line, so guest driver always runs it in system reset mode.
+pub struct DwWdt {
+    /// Control register, the enable bit is cleared only by reset.
+    cr: u32,
+    /// Timeout range register, TOP_INIT is loaded when enabled and TOP on restart.
+    torr: u32,
+    counter: Arc<Mutex<WdtCounter>>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl DwWdt {
+    /// Create watchdog which notifies `expired` when it expires.
+    pub fn new(expired: Arc<EventFd>) -> Self {
+        DwWdt {
+            cr: 0,
+            torr: 0,
+            counter: Arc::new(Mutex::new(WdtCounter::new(expired))),
+            res: SysRes::default(),
+        }
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .with_context(|| anyhow!(LegacyError::SetSysResErr))?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, Some(region_base), region_size)
+            .with_context(|| "Failed to attach watchdog device")?;
+        Ok(dev)
+    }
+
+    fn enabled(&self) -> bool {
+        self.cr & WDT_CR_EN != 0
+    }
+
+    /// Load the counter with the timeout selected by `top` and clear the interrupt.
+    fn load_counter(&self, top: u32) {
+        let period = timeout_ns(top);
+        let mut locked_counter = self.counter.lock().unwrap();
+        locked_counter.resp_mode = self.cr & WDT_CR_RMOD != 0;
+        locked_counter.irq_pending = false;
+        locked_counter.period = period;
+        drop(locked_counter);
+        start_countdown(&self.counter, period);
+    }
+
+    fn const_values() -> [(u64, u32); 7] {
+        [
+            (WDT_COMP_PARAMS_5, 0),
+            (WDT_COMP_PARAMS_4, 0),
+            (WDT_COMP_PARAMS_3, 0),
+            (WDT_COMP_PARAMS_2, 0),
+            (WDT_COMP_PARAMS_1, WDT_PARAMS_1_USE_FIX_TOP),
+            (WDT_COMP_VERSION, WDT_VERSION),
+            (WDT_COMP_TYPE, WDT_TYPE),
+        ]
+    }
+}
+
+impl SysBusDevOps for DwWdt {
+    fn const_registers(&self) -> Vec<ConstRegister> {
+        DwWdt::const_values()
+            .iter()
+            .map(|(offset, value)| ConstRegister {
+                offset: *offset,
+                size: 4,
+                value: u64::from(*value),
+            })
+            .collect()
+    }
+
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 4 {
+            return AccessResult::UnsupportedSize;
+        }
+
+        let value = match offset {
+            WDT_CR => self.cr,
+            WDT_TORR => self.torr,
+            WDT_CCVR => {
+                let left = self.counter.lock().unwrap().left_ns();
+                (u128::from(left) * u128::from(WDT_CLOCK_FREQ) / u128::from(NANOS_PER_SEC)) as u32
+            }
+            WDT_STAT => self.counter.lock().unwrap().irq_pending as u32,
+            // Reading EOI clears the interrupt.
+            WDT_EOI => {
+                self.counter.lock().unwrap().irq_pending = false;
+                0
+            }
+            _ => match DwWdt::const_values().iter().find(|(reg, _)| *reg == offset) {
+                Some((_, value)) => *value,
+                None => return AccessResult::BadOffset,
+            },
+        };
+        LittleEndian::write_u32(data, value);
+        AccessResult::Ok
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+        if data.len() != 4 {
+            return AccessResult::UnsupportedSize;
+        }
+
+        let value = LittleEndian::read_u32(data);
+        match offset {
+            WDT_CR => {
+                let was_enabled = self.enabled();
+                self.cr = value & (WDT_CR_EN | WDT_CR_RMOD) | self.cr & WDT_CR_EN;
+                if !was_enabled && self.enabled() {
+                    debug!("Watchdog enabled, torr 0x{:x}", self.torr);
+                    self.load_counter(self.torr >> WDT_TORR_TOP_INIT_SHIFT);
+                }
+            }
+            WDT_TORR => self.torr = value & 0xff,
+            WDT_CRR => {
+                if self.enabled() && value == WDT_CRR_KICK {
+                    self.load_counter(self.torr);
+                }
+            }
+            _ => return AccessResult::BadOffset,
+        }
+        AccessResult::Ok
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::Watchdog
+    }
+
+    /// Linux driver needs the clock of counter to compute timeouts.
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        let clk_node_dep = parent.begin_node("wdt-clk")?;
+        parent.set_property_string("compatible", "fixed-clock")?;
+        parent.set_property_u32("#clock-cells", 0)?;
+        parent.set_property_u32("clock-frequency", WDT_CLOCK_FREQ as u32)?;
+        parent.set_property_u32("phandle", device_tree::WDT_CLK_PHANDLE)?;
+        parent.end_node(clk_node_dep)?;
+
+        if let Some(node_dep) = begin_fdt_node(parent, SysBusDevType::Watchdog, &self.res)? {
+            parent.set_property_u32("clocks", device_tree::WDT_CLK_PHANDLE)?;
+            parent.end_node(node_dep)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.cr = 0;
+        self.torr = 0;
+        let mut locked_counter = self.counter.lock().unwrap();
+        locked_counter.stop();
+        locked_counter.resp_mode = false;
+        locked_counter.irq_pending = false;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        let mut locked_counter = self.counter.lock().unwrap();
+        if !locked_counter.paused {
+            locked_counter.paused_left = locked_counter.left_ns();
+            locked_counter.paused = true;
+        }
+        // Drop the armed timer, it's re-armed on resume.
+        locked_counter.timer.cancel();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let mut locked_counter = self.counter.lock().unwrap();
+        if !locked_counter.paused {
+            return Ok(());
+        }
+        locked_counter.paused = false;
+        let (running, left) = (locked_counter.running, locked_counter.paused_left);
+        drop(locked_counter);
+        if running {
+            start_countdown(&self.counter, left);
+        }
+        Ok(())
+    }
+
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        let mut state = [0_u8; WDT_STATE_SIZE];
+        LittleEndian::write_u32(&mut state[0..4], self.cr);
+        LittleEndian::write_u32(&mut state[4..8], self.torr);
+        let locked_counter = self.counter.lock().unwrap();
+        state[8] = locked_counter.running as u8;
+        state[9] = locked_counter.irq_pending as u8;
+        LittleEndian::write_u64(&mut state[10..18], locked_counter.left_ns());
+        Ok(encode_state(WDT_STATE_VERSION, &state))
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let (_, state) = decode_state(data, WDT_STATE_VERSION)?;
+        if state.len() != WDT_STATE_SIZE {
+            bail!("Invalid watchdog state size {}", state.len());
+        }
+        self.cr = LittleEndian::read_u32(&state[0..4]);
+        self.torr = LittleEndian::read_u32(&state[4..8]);
+        let mut locked_counter = self.counter.lock().unwrap();
+        locked_counter.stop();
+        locked_counter.resp_mode = self.cr & WDT_CR_RMOD != 0;
+        locked_counter.irq_pending = state[9] != 0;
+        locked_counter.period = timeout_ns(self.torr);
+        drop(locked_counter);
+        if state[8] != 0 {
+            start_countdown(&self.counter, LittleEndian::read_u64(&state[10..18]));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+
+    use machine_manager::event_loop::EventLoop;
+
+    use super::*;
+
+    fn write_reg(wdt: &mut DwWdt, offset: u64, val: u32) {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, val);
+        assert!(wdt.write(&data, GuestAddress(0), offset).is_ok());
+    }
+
+    fn read_reg(wdt: &mut DwWdt, offset: u64) -> u32 {
+        let mut data = [0_u8; 4];
+        assert!(wdt.read(&mut data, GuestAddress(0), offset).is_ok());
+        LittleEndian::read_u32(&data)
+    }
+
+    fn run_timers_after(ms: u64) {
+        sleep(Duration::from_millis(ms));
+        EventLoop::get_ctx(None).unwrap().run_timers();
+    }
+
+    fn expired(evt: &EventFd) -> bool {
+        evt.read().is_ok()
+    }
+
+    fn new_wdt() -> (DwWdt, Arc<EventFd>) {
+        EventLoop::object_init(&None).unwrap();
+        let evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        (DwWdt::new(evt.clone()), evt)
+    }
+
+    #[test]
+    fn test_wdt_registers() {
+        let (mut wdt, _) = new_wdt();
+        assert_eq!(read_reg(&mut wdt, WDT_COMP_TYPE), WDT_TYPE);
+        assert_eq!(
+            read_reg(&mut wdt, WDT_COMP_PARAMS_1),
+            WDT_PARAMS_1_USE_FIX_TOP
+        );
+        assert_eq!(wdt.const_registers().len(), 7);
+        assert_eq!(read_reg(&mut wdt, WDT_CCVR), 0);
+
+        // Timeout of TOP 15 at 1MHz is 2^31 cycles.
+        write_reg(&mut wdt, WDT_TORR, 0xff);
+        assert_eq!(read_reg(&mut wdt, WDT_TORR), 0xff);
+        write_reg(&mut wdt, WDT_CR, WDT_CR_EN);
+        let count = read_reg(&mut wdt, WDT_CCVR);
+        assert!(count <= 1 << 31 && count > (1 << 31) - 100_000);
+
+        // Enable bit can't be cleared by guest.
+        write_reg(&mut wdt, WDT_CR, 0);
+        assert_eq!(read_reg(&mut wdt, WDT_CR), WDT_CR_EN);
+
+        let mut data = [0_u8; 4];
+        assert_eq!(
+            wdt.write(&data, GuestAddress(0), WDT_CCVR),
+            AccessResult::BadOffset
+        );
+        assert_eq!(
+            wdt.read(&mut data[..2], GuestAddress(0), WDT_CR),
+            AccessResult::UnsupportedSize
+        );
+
+        // Reset disables the watchdog.
+        wdt.reset().unwrap();
+        assert_eq!(read_reg(&mut wdt, WDT_CR), 0);
+        assert_eq!(read_reg(&mut wdt, WDT_CCVR), 0);
+    }
+
+    #[test]
+    fn test_wdt_expire() {
+        let (mut wdt, evt) = new_wdt();
+        // Timeout of TOP 0 is 65.536ms.
+        write_reg(&mut wdt, WDT_CR, WDT_CR_EN);
+        run_timers_after(40);
+        assert!(!expired(&evt));
+
+        // Kicking restarts the counter, other values are ignored.
+        write_reg(&mut wdt, WDT_CRR, 0x12);
+        write_reg(&mut wdt, WDT_CRR, WDT_CRR_KICK);
+        run_timers_after(40);
+        assert!(!expired(&evt));
+        run_timers_after(40);
+        assert!(expired(&evt));
+        assert_eq!(read_reg(&mut wdt, WDT_CCVR), 0);
+
+        // Counter stops after expiry until it's kicked again.
+        run_timers_after(80);
+        assert!(!expired(&evt));
+        write_reg(&mut wdt, WDT_CRR, WDT_CRR_KICK);
+        run_timers_after(80);
+        assert!(expired(&evt));
+
+        // Reset disarms the watchdog.
+        write_reg(&mut wdt, WDT_CRR, WDT_CRR_KICK);
+        wdt.reset().unwrap();
+        run_timers_after(80);
+        assert!(!expired(&evt));
+    }
+
+    #[test]
+    fn test_wdt_interrupt_mode() {
+        let (mut wdt, evt) = new_wdt();
+        write_reg(&mut wdt, WDT_CR, WDT_CR_EN | WDT_CR_RMOD);
+        run_timers_after(80);
+        assert!(!expired(&evt));
+        assert_eq!(read_reg(&mut wdt, WDT_STAT), 1);
+
+        // Kicking clears the interrupt, so the next timeout raises it again.
+        write_reg(&mut wdt, WDT_CRR, WDT_CRR_KICK);
+        assert_eq!(read_reg(&mut wdt, WDT_STAT), 0);
+        run_timers_after(80);
+        assert_eq!(read_reg(&mut wdt, WDT_STAT), 1);
+        run_timers_after(80);
+        assert!(expired(&evt));
+        assert_eq!(read_reg(&mut wdt, WDT_EOI), 0);
+        assert_eq!(read_reg(&mut wdt, WDT_STAT), 0);
+    }
+
+    #[test]
+    fn test_wdt_pause_resume() {
+        let (mut wdt, evt) = new_wdt();
+        write_reg(&mut wdt, WDT_CR, WDT_CR_EN);
+        run_timers_after(30);
+        wdt.pause().unwrap();
+        let count = read_reg(&mut wdt, WDT_CCVR);
+        // Counter is frozen while paused.
+        run_timers_after(80);
+        assert!(!expired(&evt));
+        assert_eq!(read_reg(&mut wdt, WDT_CCVR), count);
+
+        wdt.resume().unwrap();
+        assert!(read_reg(&mut wdt, WDT_CCVR) <= count);
+        run_timers_after(80);
+        assert!(expired(&evt));
+    }
+
+    #[test]
+    fn test_wdt_state_round_trip() {
+        let (mut wdt, evt) = new_wdt();
+        write_reg(&mut wdt, WDT_TORR, 0xf0);
+        write_reg(&mut wdt, WDT_CR, WDT_CR_EN | WDT_CR_RMOD);
+        wdt.pause().unwrap();
+        let state = wdt.state_bytes().unwrap();
+
+        let mut restored = DwWdt::new(evt);
+        restored.pause().unwrap();
+        restored.restore_state(&state).unwrap();
+        assert_eq!(read_reg(&mut restored, WDT_CR), WDT_CR_EN | WDT_CR_RMOD);
+        assert_eq!(read_reg(&mut restored, WDT_TORR), 0xf0);
+        assert_eq!(
+            read_reg(&mut restored, WDT_CCVR),
+            read_reg(&mut wdt, WDT_CCVR)
+        );
+        assert!(restored.restore_state(&state[..state.len() - 1]).is_err());
+    }
+}
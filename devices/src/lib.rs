@@ -20,9 +20,11 @@
 pub mod pcie_mem; 
 mod interrupt_controller;
 pub mod legacy;
+mod timer;
 
 #[cfg(target_arch = "riscv64")]
 pub use interrupt_controller::{
-     InterruptController, InterruptControllerConfig, MAX_DEVICES
+     Clint, ClintIrqLines, InterruptController, InterruptControllerConfig, CLINT_REGION_SIZE,
+     MAX_DEVICES
 };
 pub use legacy::error::LegacyError as LegacyErrs;
\ No newline at end of file
@@ -1,4 +1,4 @@
-use sysbus::{SysBusDevOps, SysRes, SysBusDevType};
+use sysbus::{AccessResult, SysBusDevOps, SysRes, SysBusDevType};
 use address_space::GuestAddress;
 
 pub struct PcieMem {
@@ -16,20 +16,20 @@ impl PcieMem {
 }
 
 impl SysBusDevOps for PcieMem {
-    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
         if offset as usize + data.len() > self.mem.len() {
-            return false;
+            return AccessResult::BadOffset;
         }
         data.copy_from_slice(&self.mem[offset as usize..offset as usize + data.len()]);
-        true
+        AccessResult::Ok
     }
 
-    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
         if offset as usize + data.len() > self.mem.len() {
-            return false;
+            return AccessResult::BadOffset;
         }
         self.mem[offset as usize..offset as usize + data.len()].copy_from_slice(data);
-        true
+        AccessResult::Ok
     }
 
     fn get_type(&self) -> SysBusDevType {
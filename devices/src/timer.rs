@@ -0,0 +1,53 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::time::Duration;
+
+use log::error;
+use machine_manager::event_loop::EventLoop;
+use util::loop_context::TimerHandle;
+
+/// Timer of a device in main loop. It's added to the loop when it's armed the first
+/// time, as the device may be created before the loop.
+#[derive(Default)]
+pub(crate) struct DeviceTimer {
+    handle: Option<TimerHandle>,
+}
+
+impl DeviceTimer {
+    /// Call `func` after `delay` from now, the timer armed before is dropped. `func`
+    /// is only taken when the timer is added, it's called for every arming later.
+    pub(crate) fn arm<F: Fn() + Send + Sync + 'static>(&mut self, delay: Duration, func: F) {
+        if let Some(handle) = &self.handle {
+            handle.rearm(delay);
+            return;
+        }
+        match EventLoop::add_timer(delay, None, Box::new(func)) {
+            Ok(handle) => self.handle = Some(handle),
+            Err(e) => error!("Failed to arm device timer: {:?}", e),
+        }
+    }
+
+    /// Drop the armed timer.
+    pub(crate) fn cancel(&self) {
+        if let Some(handle) = &self.handle {
+            handle.cancel();
+        }
+    }
+
+    /// Whether the expiry handled by the timer function is not dropped yet. The
+    /// function checks it with the device state locked.
+    pub(crate) fn is_current(&self) -> bool {
+        matches!(&self.handle, Some(handle) if handle.is_current())
+    }
+}
@@ -11,16 +11,17 @@
 // See the Mulan PSL v2 for more details.
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use arc_swap::ArcSwap;
 use kvm_bindings::kvm_userspace_memory_region as MemorySlot;
 use kvm_bindings::*;
-use kvm_ioctls::{Kvm, VmFd};
+use kvm_ioctls::{Cap, Kvm, VmFd};
 use log::error;
 use once_cell::sync::Lazy;
 use vmm_sys_util::{
-     ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr,
+     eventfd::EventFd, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr,
 };
 
 use anyhow::{Context, Result};
@@ -46,6 +47,8 @@ pub struct KVMFds {
     pub fd: Option<Kvm>,
     pub vm_fd: Option<VmFd>,
     pub mem_slots: Arc<Mutex<HashMap<u32, MemorySlot>>>,
+    /// Whether dirty page tracking is started, memory slots added later need it too.
+    pub dirty_log: AtomicBool,
 }
 
 impl KVMFds {
@@ -63,6 +66,7 @@ impl KVMFds {
                     fd: Some(fd),
                     vm_fd: Some(vm_fd),
                     mem_slots: Arc::new(Mutex::new(HashMap::new())),
+                    dirty_log: AtomicBool::new(false),
                 }
             }
             Err(e) => {
@@ -72,9 +76,44 @@ impl KVMFds {
         }
     }
 
+    /// Check whether irqfd (`KVM_CAP_IRQFD`) is supported by kvm.
+    pub fn irqfd_supported(&self) -> bool {
+        self.vm_fd
+            .as_ref()
+            .map_or(false, |vm_fd| vm_fd.check_extension(Cap::Irqfd))
+    }
+
+    /// Check whether ioeventfd (`KVM_CAP_IOEVENTFD`) is supported by kvm. Matching the
+    /// written data comes with it, there is no capability of its own.
+    pub fn ioeventfd_supported(&self) -> bool {
+        self.vm_fd
+            .as_ref()
+            .map_or(false, |vm_fd| vm_fd.check_extension(Cap::Ioeventfd))
+    }
+
+    /// Register eventfd as irqfd of `gsi` in kvm.
+    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        self.vm_fd
+            .as_ref()
+            .unwrap()
+            .register_irqfd(fd, gsi)
+            .with_context(|| format!("Failed to register irqfd: gsi {}.", gsi))
+    }
+
+    /// Unregister irqfd of `gsi` from kvm.
+    pub fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        self.vm_fd
+            .as_ref()
+            .unwrap()
+            .unregister_irqfd(fd, gsi)
+            .with_context(|| format!("Failed to unregister irqfd: gsi {}.", gsi))
+    }
+
     /// Start dirty page tracking in kvm.
     pub fn start_dirty_log(&self) -> Result<()> {
-        for (_, region) in self.mem_slots.lock().unwrap().iter_mut() {
+        let mut locked_slots = self.mem_slots.lock().unwrap();
+        self.dirty_log.store(true, Ordering::SeqCst);
+        for (_, region) in locked_slots.iter_mut() {
             region.flags = KVM_MEM_LOG_DIRTY_PAGES;
             // Safe because region from `KVMFds` is reliable.
             unsafe {
@@ -96,7 +135,9 @@ impl KVMFds {
 
     /// Stop dirty page tracking in kvm.
     pub fn stop_dirty_log(&self) -> Result<()> {
-        for (_, region) in self.mem_slots.lock().unwrap().iter_mut() {
+        let mut locked_slots = self.mem_slots.lock().unwrap();
+        self.dirty_log.store(false, Ordering::SeqCst);
+        for (_, region) in locked_slots.iter_mut() {
             region.flags = 0;
             // Safe because region from `KVMFds` is reliable.
             unsafe {
@@ -116,6 +157,11 @@ impl KVMFds {
         Ok(())
     }
 
+    /// Check whether dirty page tracking is started in kvm.
+    pub fn dirty_log_enabled(&self) -> bool {
+        self.dirty_log.load(Ordering::SeqCst)
+    }
+
     /// Get dirty page bitmap in kvm.
     pub fn get_dirty_log(&self, slot: u32, mem_size: u64) -> Result<Vec<u64>> {
         let res = self
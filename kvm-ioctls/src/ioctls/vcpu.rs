@@ -96,6 +96,12 @@ pub enum VcpuExit<'a> {
     IoapicEoi(u8 /* vector */),
     /// Corresponds to KVM_EXIT_HYPERV.
     Hyperv,
+    /// Corresponds to KVM_EXIT_RISCV_SBI.
+    ///
+    /// An SBI call not handled by KVM, `ret` of the given call should be filled in
+    /// before [run()](struct.VcpuFd.html#method.run) is called again.
+    #[cfg(target_arch = "riscv64")]
+    RiscvSbi(&'a mut kvm_run__bindgen_ty_1__bindgen_ty_23),
     /// Corresponds to an exit reason that is unknown from the current version
     /// of the kvm-ioctls crate. Let the consumer decide about what to do with
     /// it.
@@ -1145,7 +1151,8 @@ impl VcpuFd {
         target_arch = "x86_64",
         target_arch = "aarch64",
         target_arch = "s390",
-        target_arch = "ppc"
+        target_arch = "ppc",
+        target_arch = "riscv64"
     ))]
     pub fn set_guest_debug(&self, debug_struct: &kvm_guest_debug) -> Result<()> {
         // SAFETY: Safe because we allocated the structure and we trust the kernel.
@@ -1421,6 +1428,13 @@ impl VcpuFd {
                     Ok(VcpuExit::IoapicEoi(eoi.vector))
                 }
                 KVM_EXIT_HYPERV => Ok(VcpuExit::Hyperv),
+                #[cfg(target_arch = "riscv64")]
+                KVM_EXIT_RISCV_SBI => {
+                    // SAFETY: Safe because the exit_reason (which comes from the kernel) told us
+                    // which union field to use.
+                    let riscv_sbi = unsafe { &mut run.__bindgen_anon_1.riscv_sbi };
+                    Ok(VcpuExit::RiscvSbi(riscv_sbi))
+                }
                 r => Ok(VcpuExit::Unsupported(r)),
             }
         } else {
@@ -30,6 +30,11 @@ pub enum MachineError {
         #[from]
         source: devices::legacy::error::LegacyError,
     },
+    #[error("SysBus")]
+    SysBus {
+        #[from]
+        source: sysbus::error::SysBusError,
+    },
     #[error("MicroVm")]
     MicroVm {
         #[from]
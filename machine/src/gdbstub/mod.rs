@@ -0,0 +1,762 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Gdb stub serving gdb remote serial protocol on a tcp socket, to debug guest
+//! with gdb's `target remote`.
+//!
+//! Once gdb connects, the vm is stopped and guest `ebreak` exits to userspace.
+//! Each vcpu is a thread for gdb, and thread id is vcpu id plus one.
+
+mod packet;
+mod riscv;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+
+use address_space::{AddressSpace, GuestAddress};
+use cpu::{CPUInterface, CPU, GDB_NUM_CORE_REGS};
+use machine_manager::machine::{KvmVmState, MachineLifecycle};
+use util::loop_context::{
+    gen_delete_notifiers, read_fd, EventNotifier, EventNotifierHelper, NotifierCallback,
+    NotifierOperation,
+};
+
+use packet::{decode_hex, encode_hex, encode_packet, parse_hex, GdbEvent, PacketParser};
+
+/// Packet size told to gdb, which bounds the packets gdb sends.
+const PACKET_SIZE: usize = 0x1000;
+/// Max bytes of memory read by a packet, the reply is twice it in hex.
+const MAX_MEM_READ: u64 = 0x7f0;
+/// Register number of pc in gdb's riscv core feature.
+const GDB_REG_PC: usize = 32;
+const GDB_REG_NAMES: [&str; GDB_NUM_CORE_REGS] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6", "pc",
+];
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+/// Error replies, with errno as value.
+const ERR_FAULT: &str = "E0e";
+const ERR_INVAL: &str = "E16";
+
+/// Breakpoint stops raised by vcpu threads, handled by `GdbStub` in main loop.
+pub struct GdbStopNotifier {
+    fd: EventFd,
+    /// The first vcpu stopped since last handled.
+    cpu: Mutex<Option<u8>>,
+}
+
+impl GdbStopNotifier {
+    pub fn new() -> Result<Self> {
+        Ok(GdbStopNotifier {
+            fd: EventFd::new(libc::EFD_NONBLOCK)?,
+            cpu: Mutex::new(None),
+        })
+    }
+
+    /// Report that vcpu `cpu_id` stopped at a breakpoint.
+    pub fn notify(&self, cpu_id: u8) -> bool {
+        self.cpu.lock().unwrap().get_or_insert(cpu_id);
+        self.fd.write(1).is_ok()
+    }
+
+    fn take(&self) -> Option<u8> {
+        self.cpu.lock().unwrap().take()
+    }
+}
+
+/// Gdb stub for one gdb connection at a time.
+pub struct GdbStub {
+    listener: TcpListener,
+    /// Connection of gdb.
+    stream: Option<TcpStream>,
+    parser: PacketParser,
+    /// Whether packets are acknowledged, which is turned off by `QStartNoAckMode`.
+    no_ack: bool,
+    /// Last reply, sent again if gdb asks for retransmission.
+    last_reply: Vec<u8>,
+    /// Whether gdb asked to detach.
+    detaching: bool,
+    cpus: Vec<Arc<CPU>>,
+    sys_mem: Arc<AddressSpace>,
+    vm: Arc<Mutex<dyn MachineLifecycle + Send + Sync>>,
+    vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
+    stop_notifier: Arc<GdbStopNotifier>,
+    /// Index of vcpu reported by last stop.
+    stop_cpu: usize,
+    /// Index of vcpu whose registers are accessed, selected by `Hg`.
+    reg_cpu: usize,
+    /// Index of vcpu stepped by `s`, selected by `Hc`.
+    run_cpu: Option<usize>,
+    /// Whether gdb is waiting for the target to stop.
+    running: bool,
+    /// Index of vcpu being stepped alone, while the vm keeps paused.
+    step_cpu: Option<usize>,
+    /// Breakpoints set by gdb, with the guest instructions they replace.
+    breakpoints: HashMap<u64, Vec<u8>>,
+    /// Temporary breakpoints emulating single-step.
+    step_breakpoints: HashMap<u64, Vec<u8>>,
+}
+
+impl GdbStub {
+    /// Create gdb stub listening on `addr`.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Tcp address to listen on.
+    /// * `cpus` - Vcpus of the vm.
+    /// * `sys_mem` - Guest memory.
+    /// * `vm` - The vm, paused and resumed by gdb.
+    /// * `vm_state` - Running state of the vm.
+    /// * `stop_notifier` - Breakpoint stops reported by vcpus.
+    pub fn new(
+        addr: &str,
+        cpus: Vec<Arc<CPU>>,
+        sys_mem: Arc<AddressSpace>,
+        vm: Arc<Mutex<dyn MachineLifecycle + Send + Sync>>,
+        vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
+        stop_notifier: Arc<GdbStopNotifier>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to bind gdb server to {}", addr))?;
+        info!("Waiting for gdb connection on {}", addr);
+
+        Ok(GdbStub {
+            listener,
+            stream: None,
+            parser: PacketParser::default(),
+            no_ack: false,
+            last_reply: Vec::new(),
+            detaching: false,
+            cpus,
+            sys_mem,
+            vm,
+            vm_state,
+            stop_notifier,
+            stop_cpu: 0,
+            reg_cpu: 0,
+            run_cpu: None,
+            running: false,
+            step_cpu: None,
+            breakpoints: HashMap::new(),
+            step_breakpoints: HashMap::new(),
+        })
+    }
+
+    /// Take over the vm for a new gdb connection, which expects a stopped target.
+    fn attach(&mut self, stream: TcpStream) -> Result<()> {
+        info!("gdb connected from {:?}", stream.peer_addr());
+        self.stream = Some(stream);
+        self.parser = PacketParser::default();
+        self.no_ack = false;
+        self.last_reply.clear();
+        self.detaching = false;
+        self.stop_cpu = 0;
+        self.reg_cpu = 0;
+        self.run_cpu = None;
+        self.running = false;
+
+        self.stop_target()?;
+        for cpu in self.cpus.iter() {
+            cpu.set_guest_debug(true)?;
+        }
+        Ok(())
+    }
+
+    /// Remove all breakpoints and let the vm run freely.
+    fn detach(&mut self) {
+        info!("gdb disconnected");
+        self.stream = None;
+        self.running = false;
+        if let Err(e) = self.stop_target() {
+            error!("Failed to stop vm for gdb detach: {:?}", e);
+        }
+        let breakpoints: Vec<(u64, Vec<u8>)> = self.breakpoints.drain().collect();
+        for (addr, insn) in breakpoints {
+            if let Err(e) = self.write_mem(addr, &insn) {
+                error!("Failed to remove breakpoint at 0x{:x}: {:?}", addr, e);
+            }
+        }
+        if let Err(e) = self.remove_step_breakpoints() {
+            error!("{:?}", e);
+        }
+        for cpu in self.cpus.iter() {
+            if let Err(e) = cpu.set_guest_debug(false) {
+                error!("{:?}", e);
+            }
+        }
+        if !self.vm.lock().unwrap().resume() {
+            error!("Failed to resume vm for gdb detach");
+        }
+    }
+
+    /// Pause the vm, and the vcpu stepped alone.
+    fn stop_target(&mut self) -> Result<()> {
+        let vm_running = *self.vm_state.0.lock().unwrap() == KvmVmState::Running;
+        if vm_running && !self.vm.lock().unwrap().pause() {
+            bail!("Failed to pause vm");
+        }
+        if let Some(cpu) = self.step_cpu.take() {
+            self.cpus[cpu].pause()?;
+        }
+        Ok(())
+    }
+
+    /// Resume the target.
+    ///
+    /// # Arguments
+    ///
+    /// * `step` - Index of vcpu to be single-stepped.
+    /// * `all` - Resume all vcpus, otherwise only the stepped one runs.
+    fn resume_target(&mut self, step: Option<usize>, all: bool) -> Result<()> {
+        if let Some(cpu) = step {
+            self.insert_step_breakpoints(cpu)?;
+        }
+        self.running = true;
+        if all {
+            if !self.vm.lock().unwrap().resume() {
+                bail!("Failed to resume vm");
+            }
+        } else if let Some(cpu) = step {
+            self.step_cpu = Some(cpu);
+            self.cpus[cpu].resume()?;
+        }
+        Ok(())
+    }
+
+    /// Handle breakpoint stops reported by vcpus.
+    fn handle_stop(&mut self) {
+        let cpu = match self.stop_notifier.take() {
+            Some(cpu) => cpu as usize,
+            None => return,
+        };
+        if let Err(e) = self.stop_target() {
+            error!("{:?}", e);
+        }
+        if let Err(e) = self.remove_step_breakpoints() {
+            error!("{:?}", e);
+        }
+
+        if self.stream.is_none() {
+            // Stopped by a breakpoint hit just before gdb detached.
+            if !self.vm.lock().unwrap().resume() {
+                error!("Failed to resume vm");
+            }
+        } else if self.running {
+            self.running = false;
+            self.stop_cpu = cpu;
+            self.reg_cpu = cpu;
+            let reply = self.stop_reply(SIGTRAP);
+            self.send_reply(reply.as_bytes());
+        }
+    }
+
+    /// Handle input from gdb, returns false if the connection should be closed.
+    fn handle_input(&mut self) -> bool {
+        let mut buf = [0_u8; PACKET_SIZE];
+        let len = match self.stream.as_mut().map(|stream| stream.read(&mut buf)) {
+            Some(Ok(len)) if len > 0 => len,
+            _ => return false,
+        };
+
+        for event in self.parser.feed(&buf[..len]) {
+            match event {
+                GdbEvent::Packet(packet) => {
+                    if !self.no_ack {
+                        self.send(b"+");
+                    }
+                    let reply = match self.handle_packet(&packet) {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            warn!(
+                                "Failed to handle gdb packet {}: {:?}",
+                                String::from_utf8_lossy(&packet),
+                                e
+                            );
+                            Some(ERR_INVAL.to_string())
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        self.send_reply(reply.as_bytes());
+                    }
+                }
+                GdbEvent::BadPacket => {
+                    if !self.no_ack {
+                        self.send(b"-");
+                    }
+                }
+                GdbEvent::Nack => {
+                    let reply = self.last_reply.clone();
+                    self.send(&reply);
+                }
+                GdbEvent::Interrupt => {
+                    if self.running {
+                        if let Err(e) = self.stop_target() {
+                            error!("{:?}", e);
+                        }
+                        if let Err(e) = self.remove_step_breakpoints() {
+                            error!("{:?}", e);
+                        }
+                        self.running = false;
+                        let reply = self.stop_reply(SIGINT);
+                        self.send_reply(reply.as_bytes());
+                    }
+                }
+            }
+        }
+        !self.detaching
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        if let Some(stream) = self.stream.as_mut() {
+            if let Err(e) = stream.write_all(data) {
+                error!("Failed to send to gdb: {:?}", e);
+            }
+        }
+    }
+
+    fn send_reply(&mut self, reply: &[u8]) {
+        self.last_reply = encode_packet(reply);
+        let packet = self.last_reply.clone();
+        self.send(&packet);
+    }
+
+    /// Handle a packet, returns the reply, or `None` if the reply is deferred or omitted.
+    fn handle_packet(&mut self, packet: &[u8]) -> Result<Option<String>> {
+        let (cmd, args) = match packet.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Ok(Some(String::new())),
+        };
+        let reply = match cmd {
+            b'?' => self.stop_reply(SIGTRAP),
+            b'g' => {
+                let mut regs = String::new();
+                for reg in 0..GDB_NUM_CORE_REGS {
+                    regs.push_str(&encode_hex(
+                        &self.read_reg(self.reg_cpu, reg)?.to_le_bytes(),
+                    ));
+                }
+                regs
+            }
+            b'G' => {
+                let regs = decode_hex(args)?;
+                if regs.len() != GDB_NUM_CORE_REGS * 8 {
+                    bail!("Invalid length {} of registers", regs.len());
+                }
+                for (reg, value) in regs.chunks(8).enumerate() {
+                    self.write_reg(self.reg_cpu, reg, u64::from_le_bytes(value.try_into()?))?;
+                }
+                "OK".to_string()
+            }
+            b'p' => {
+                let reg = parse_hex(args)? as usize;
+                encode_hex(&self.read_reg(self.reg_cpu, reg)?.to_le_bytes())
+            }
+            b'P' => {
+                let (reg, value) = split_at_byte(args, b'=')?;
+                let value = decode_hex(value)?;
+                let value: [u8; 8] = value.as_slice().try_into()?;
+                self.write_reg(
+                    self.reg_cpu,
+                    parse_hex(reg)? as usize,
+                    u64::from_le_bytes(value),
+                )?;
+                "OK".to_string()
+            }
+            b'm' => {
+                let (addr, len) = split_at_byte(args, b',')?;
+                let len = parse_hex(len)?.min(MAX_MEM_READ);
+                match self.read_mem(parse_hex(addr)?, len) {
+                    Ok(data) => encode_hex(&data),
+                    Err(_) => ERR_FAULT.to_string(),
+                }
+            }
+            b'M' => {
+                let (addr_len, data) = split_at_byte(args, b':')?;
+                let (addr, len) = split_at_byte(addr_len, b',')?;
+                let data = decode_hex(data)?;
+                if data.len() as u64 != parse_hex(len)? {
+                    bail!("Invalid length of memory data");
+                }
+                match self.write_mem(parse_hex(addr)?, &data) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => ERR_FAULT.to_string(),
+                }
+            }
+            b'Z' | b'z' => return self.handle_breakpoint(cmd == b'Z', args),
+            b'c' => {
+                if !args.is_empty() {
+                    let cpu = self.run_cpu.unwrap_or(self.stop_cpu);
+                    self.write_reg(cpu, GDB_REG_PC, parse_hex(args)?)?;
+                }
+                self.resume_target(None, true)?;
+                return Ok(None);
+            }
+            b's' => {
+                let cpu = self.run_cpu.unwrap_or(self.stop_cpu);
+                if !args.is_empty() {
+                    self.write_reg(cpu, GDB_REG_PC, parse_hex(args)?)?;
+                }
+                self.resume_target(Some(cpu), false)?;
+                return Ok(None);
+            }
+            b'H' => {
+                let (op, thread) = args
+                    .split_first()
+                    .ok_or_else(|| anyhow!("Missing thread operation"))?;
+                let cpu = self.parse_thread(thread)?;
+                match op {
+                    b'g' => self.reg_cpu = cpu.unwrap_or(self.stop_cpu),
+                    b'c' => self.run_cpu = cpu,
+                    _ => bail!("Unknown thread operation {}", *op as char),
+                }
+                "OK".to_string()
+            }
+            b'T' => {
+                self.parse_thread(args)?;
+                "OK".to_string()
+            }
+            b'D' => {
+                self.detaching = true;
+                "OK".to_string()
+            }
+            // Kill only detaches gdb, the vm keeps running.
+            b'k' => {
+                self.detaching = true;
+                return Ok(None);
+            }
+            b'q' | b'Q' | b'v' => return self.handle_query(std::str::from_utf8(packet)?),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn handle_query(&mut self, query: &str) -> Result<Option<String>> {
+        let reply = if query.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;vContSupported+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if query == "QStartNoAckMode" {
+            // The ack of this packet has been sent.
+            self.no_ack = true;
+            "OK".to_string()
+        } else if query == "qAttached" {
+            "1".to_string()
+        } else if query == "qC" {
+            format!("QC{:x}", self.stop_cpu + 1)
+        } else if query == "qfThreadInfo" {
+            let threads: Vec<String> = (1..=self.cpus.len())
+                .map(|id| format!("{:x}", id))
+                .collect();
+            format!("m{}", threads.join(","))
+        } else if query == "qsThreadInfo" {
+            "l".to_string()
+        } else if let Some(range) = query.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = split_at_byte(range.as_bytes(), b',')?;
+            xfer_chunk(
+                &target_xml(),
+                parse_hex(offset)? as usize,
+                parse_hex(len)? as usize,
+            )
+        } else if query == "vCont?" {
+            "vCont;c;C;s;S".to_string()
+        } else if let Some(actions) = query.strip_prefix("vCont;") {
+            self.handle_vcont(actions)?;
+            return Ok(None);
+        } else {
+            String::new()
+        };
+        Ok(Some(reply))
+    }
+
+    /// Resume the target as `vCont` actions. Stepping is per vcpu, and continuing
+    /// any vcpu resumes all of them. Signals are ignored as they can't be injected.
+    fn handle_vcont(&mut self, actions: &str) -> Result<()> {
+        let mut step = None;
+        let mut all = false;
+        for action in actions.split(';') {
+            let (action, thread) = match action.split_once(':') {
+                Some((action, thread)) => (action, self.parse_thread(thread.as_bytes())?),
+                None => (action, None),
+            };
+            match action.as_bytes().first() {
+                Some(b'c') | Some(b'C') => all = true,
+                Some(b's') | Some(b'S') => {
+                    step.get_or_insert(thread.unwrap_or(self.stop_cpu));
+                }
+                _ => bail!("Unsupported vCont action {}", action),
+            }
+        }
+        self.resume_target(step, all || step.is_none())
+    }
+
+    fn handle_breakpoint(&mut self, insert: bool, args: &[u8]) -> Result<Option<String>> {
+        let mut fields = args.split(|b| *b == b',');
+        // Only software breakpoint is supported.
+        if fields.next() != Some(&b"0"[..]) {
+            return Ok(Some(String::new()));
+        }
+        let addr = parse_hex(fields.next().unwrap_or_default())?;
+        let kind = parse_hex(fields.next().unwrap_or_default())?;
+
+        let reply = if insert {
+            if self.breakpoints.contains_key(&addr) {
+                "OK".to_string()
+            } else {
+                match self.insert_breakpoint(addr, kind) {
+                    Ok(insn) => {
+                        self.breakpoints.insert(addr, insn);
+                        "OK".to_string()
+                    }
+                    Err(_) => ERR_FAULT.to_string(),
+                }
+            }
+        } else {
+            match self.breakpoints.remove(&addr) {
+                Some(insn) => match self.write_mem(addr, &insn) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => ERR_FAULT.to_string(),
+                },
+                None => "OK".to_string(),
+            }
+        };
+        Ok(Some(reply))
+    }
+
+    /// Put breakpoints on where vcpu `cpu` goes after its current instruction.
+    fn insert_step_breakpoints(&mut self, cpu: usize) -> Result<()> {
+        let pc = self.read_reg(cpu, GDB_REG_PC)?;
+        let insn = self.read_insn(pc)?;
+        let targets = riscv::next_pcs(insn, pc, |reg| self.read_reg(cpu, reg))?;
+        for target in targets {
+            if self.breakpoints.contains_key(&target) || self.step_breakpoints.contains_key(&target)
+            {
+                continue;
+            }
+            let kind = riscv::insn_len(self.read_insn(target)?);
+            let insn = self.insert_breakpoint(target, kind)?;
+            self.step_breakpoints.insert(target, insn);
+        }
+        Ok(())
+    }
+
+    fn remove_step_breakpoints(&mut self) -> Result<()> {
+        let breakpoints: Vec<(u64, Vec<u8>)> = self.step_breakpoints.drain().collect();
+        for (addr, insn) in breakpoints {
+            self.write_mem(addr, &insn)
+                .with_context(|| format!("Failed to remove step breakpoint at 0x{:x}", addr))?;
+        }
+        Ok(())
+    }
+
+    /// Write breakpoint instruction of `kind` at `addr`, returns the replaced instruction.
+    fn insert_breakpoint(&self, addr: u64, kind: u64) -> Result<Vec<u8>> {
+        let bp_insn = riscv::breakpoint_insn(kind)?;
+        let insn = self.read_mem(addr, bp_insn.len() as u64)?;
+        self.write_mem(addr, bp_insn)?;
+        Ok(insn)
+    }
+
+    /// Read the instruction at `addr`, the upper halfword is 0 if it's compressed.
+    fn read_insn(&self, addr: u64) -> Result<u32> {
+        let low = self.read_mem(addr, 2)?;
+        let mut insn = u16::from_le_bytes([low[0], low[1]]) as u32;
+        if riscv::insn_len(insn) == 4 {
+            let high = self.read_mem(addr + 2, 2)?;
+            insn |= (u16::from_le_bytes([high[0], high[1]]) as u32) << 16;
+        }
+        Ok(insn)
+    }
+
+    fn read_reg(&self, cpu: usize, reg: usize) -> Result<u64> {
+        let cpu = &self.cpus[cpu];
+        cpu.arch().lock().unwrap().get_gdb_reg(cpu.fd(), reg)
+    }
+
+    fn write_reg(&self, cpu: usize, reg: usize, value: u64) -> Result<()> {
+        let cpu = &self.cpus[cpu];
+        cpu.arch().lock().unwrap().set_gdb_reg(cpu.fd(), reg, value)
+    }
+
+    fn read_mem(&self, addr: u64, len: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0_u8; len as usize];
+        self.sys_mem
+            .read(&mut data.as_mut_slice(), GuestAddress(addr), len)
+            .with_context(|| format!("Failed to read guest memory 0x{:x}", addr))?;
+        Ok(data)
+    }
+
+    fn write_mem(&self, addr: u64, data: &[u8]) -> Result<()> {
+        self.sys_mem
+            .write(&mut &data[..], GuestAddress(addr), data.len() as u64)
+            .with_context(|| format!("Failed to write guest memory 0x{:x}", addr))?;
+        Ok(())
+    }
+
+    fn stop_reply(&self, signal: u8) -> String {
+        format!("T{:02x}thread:{:x};", signal, self.stop_cpu + 1)
+    }
+
+    /// Parse thread id to vcpu index, `None` for all threads.
+    fn parse_thread(&self, thread: &[u8]) -> Result<Option<usize>> {
+        match thread {
+            b"-1" => Ok(None),
+            b"0" => Ok(Some(self.stop_cpu)),
+            _ => {
+                let id = parse_hex(thread)? as usize;
+                if id == 0 || id > self.cpus.len() {
+                    bail!("Invalid thread id {}", id);
+                }
+                Ok(Some(id - 1))
+            }
+        }
+    }
+
+    fn stream_notifier(&self, gdb: Arc<Mutex<Self>>) -> Option<EventNotifier> {
+        let stream_fd = self.stream.as_ref()?.as_raw_fd();
+        let handler: Rc<NotifierCallback> = Rc::new(move |event, fd| {
+            let mut locked_gdb = gdb.lock().unwrap();
+            if event & EventSet::HANG_UP != EventSet::HANG_UP && locked_gdb.handle_input() {
+                return None;
+            }
+            locked_gdb.detach();
+            Some(gen_delete_notifiers(&[fd]))
+        });
+        // Park the listener, so that only one gdb is served.
+        Some(EventNotifier::new(
+            NotifierOperation::AddShared,
+            stream_fd,
+            Some(self.listener.as_raw_fd()),
+            EventSet::IN | EventSet::HANG_UP,
+            vec![handler],
+        ))
+    }
+}
+
+impl EventNotifierHelper for GdbStub {
+    fn internal_notifiers(gdb: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_gdb = gdb.lock().unwrap();
+
+        let cloned_gdb = gdb.clone();
+        let accept_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            let mut locked_gdb = cloned_gdb.lock().unwrap();
+            let stream = match locked_gdb.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Failed to accept gdb connection: {:?}", e);
+                    return None;
+                }
+            };
+            if let Err(e) = locked_gdb.attach(stream) {
+                error!("Failed to attach gdb: {:?}", e);
+                locked_gdb.detach();
+                return None;
+            }
+            locked_gdb
+                .stream_notifier(cloned_gdb.clone())
+                .map(|notifier| vec![notifier])
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            locked_gdb.listener.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![accept_handler],
+        ));
+
+        let cloned_gdb = gdb.clone();
+        let stop_handler: Rc<NotifierCallback> = Rc::new(move |_, fd| {
+            read_fd(fd);
+            cloned_gdb.lock().unwrap().handle_stop();
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            locked_gdb.stop_notifier.fd.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![stop_handler],
+        ));
+
+        notifiers
+    }
+}
+
+fn split_at_byte(data: &[u8], sep: u8) -> Result<(&[u8], &[u8])> {
+    let pos = data
+        .iter()
+        .position(|b| *b == sep)
+        .ok_or_else(|| anyhow!("Missing '{}' in packet", sep as char))?;
+    Ok((&data[..pos], &data[pos + 1..]))
+}
+
+/// Target description, telling gdb the registers in `g` packet.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target><architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (regnum, name) in GDB_REG_NAMES.iter().enumerate() {
+        let reg_type = match *name {
+            "ra" | "pc" => "code_ptr",
+            "sp" | "gp" | "tp" | "fp" => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>",
+            name, reg_type, regnum
+        ));
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+/// Reply of `qXfer` reading `len` bytes at `offset` of `data`.
+fn xfer_chunk(data: &str, offset: usize, len: usize) -> String {
+    if offset >= data.len() {
+        return "l".to_string();
+    }
+    let end = offset.saturating_add(len).min(data.len());
+    let more = if end < data.len() { 'm' } else { 'l' };
+    format!("{}{}", more, &data[offset..end])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_target_xml() {
+        let xml = target_xml();
+        assert!(xml.contains("<architecture>riscv:rv64</architecture>"));
+        assert!(xml.contains("<reg name=\"zero\" bitsize=\"64\" type=\"int\" regnum=\"0\"/>"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/>"));
+
+        assert_eq!(xfer_chunk("abcdef", 0, 4), "mabcd");
+        assert_eq!(xfer_chunk("abcdef", 4, 4), "lef");
+        assert_eq!(xfer_chunk("abcdef", 6, 4), "l");
+    }
+}
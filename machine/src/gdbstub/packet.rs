@@ -0,0 +1,210 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Framing of gdb remote serial protocol.
+//!
+//! A packet is `$<data>#<checksum>`, where checksum is two hex digits of the
+//! modulo 256 sum of data. `$`, `#`, `}` and `*` in data are escaped by `}`
+//! followed by the byte xor 0x20. Out of packets, `+`/`-` acknowledge the
+//! previous packet and 0x03 interrupts the running target.
+
+use anyhow::{bail, Result};
+
+/// Byte sent by gdb to interrupt the target, aka. Ctrl-C.
+const INTERRUPT: u8 = 0x03;
+const ESCAPE: u8 = b'}';
+
+/// Events decoded from the byte stream of gdb.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GdbEvent {
+    /// A packet with valid checksum, unescaped.
+    Packet(Vec<u8>),
+    /// A packet with bad checksum, which should be answered by `-`.
+    BadPacket,
+    /// gdb asks for retransmission of the last reply.
+    Nack,
+    /// gdb asks to stop the running target.
+    Interrupt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParseState {
+    /// Out of packet.
+    Idle,
+    /// Between `$` and `#`.
+    Data,
+    /// After `#`, the number of checksum digits received.
+    Checksum(usize),
+}
+
+/// Incremental parser of the byte stream received from gdb.
+pub struct PacketParser {
+    state: ParseState,
+    data: Vec<u8>,
+    sum: u8,
+    checksum: [u8; 2],
+}
+
+impl Default for PacketParser {
+    fn default() -> Self {
+        PacketParser {
+            state: ParseState::Idle,
+            data: Vec::new(),
+            sum: 0,
+            checksum: [0; 2],
+        }
+    }
+}
+
+impl PacketParser {
+    /// Feed received bytes, returns events completed by them.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<GdbEvent> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            match self.state {
+                ParseState::Idle => match byte {
+                    b'$' => {
+                        self.data.clear();
+                        self.sum = 0;
+                        self.state = ParseState::Data;
+                    }
+                    b'-' => events.push(GdbEvent::Nack),
+                    INTERRUPT => events.push(GdbEvent::Interrupt),
+                    // Acks and garbage between packets.
+                    _ => {}
+                },
+                ParseState::Data => {
+                    if byte == b'#' {
+                        self.state = ParseState::Checksum(0);
+                    } else {
+                        self.sum = self.sum.wrapping_add(byte);
+                        self.data.push(byte);
+                    }
+                }
+                ParseState::Checksum(n) => {
+                    self.checksum[n] = byte;
+                    if n == 0 {
+                        self.state = ParseState::Checksum(1);
+                        continue;
+                    }
+                    self.state = ParseState::Idle;
+                    let valid =
+                        parse_hex(&self.checksum).map_or(false, |sum| sum == self.sum as u64);
+                    if valid {
+                        events.push(GdbEvent::Packet(unescape(&self.data)));
+                    } else {
+                        events.push(GdbEvent::BadPacket);
+                    }
+                }
+            }
+        }
+        events
+    }
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == ESCAPE {
+            if let Some(&next) = iter.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(byte);
+        }
+    }
+    out
+}
+
+/// Frame `data` as a packet, escaping the reserved bytes.
+pub fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 4);
+    out.push(b'$');
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            out.push(ESCAPE);
+            out.push(byte ^ 0x20);
+        } else {
+            out.push(byte);
+        }
+    }
+    let sum = out[1..].iter().fold(0_u8, |sum, b| sum.wrapping_add(*b));
+    out.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+    out
+}
+
+/// Encode bytes as lowercase hex digits.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode hex digits to bytes.
+pub fn decode_hex(hex: &[u8]) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        bail!("Odd length of hex string {}", String::from_utf8_lossy(hex));
+    }
+    hex.chunks(2)
+        .map(|pair| parse_hex(pair).map(|v| v as u8))
+        .collect()
+}
+
+/// Parse a big-endian hex number, as used for addresses and lengths.
+pub fn parse_hex(hex: &[u8]) -> Result<u64> {
+    let s = std::str::from_utf8(hex)?;
+    if s.is_empty() {
+        bail!("Empty hex number");
+    }
+    Ok(u64::from_str_radix(s, 16)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_parser() {
+        let mut parser = PacketParser::default();
+        // Acks are skipped, split packets are joined.
+        let mut events = parser.feed(b"+$g#6");
+        assert!(events.is_empty());
+        events = parser.feed(b"7+$m80200000,4#");
+        assert_eq!(events, vec![GdbEvent::Packet(b"g".to_vec())]);
+        events = parser.feed(b"57");
+        assert_eq!(events, vec![GdbEvent::Packet(b"m80200000,4".to_vec())]);
+
+        // Bad checksum, retransmission request and interrupt.
+        events = parser.feed(b"$g#00-\x03");
+        assert_eq!(
+            events,
+            vec![GdbEvent::BadPacket, GdbEvent::Nack, GdbEvent::Interrupt]
+        );
+
+        // Escaped bytes are counted in checksum before unescaping.
+        let packet = encode_packet(b"X0,2:}#");
+        assert_eq!(packet, b"$X0,2:}]}\x03#7a".to_vec());
+        events = parser.feed(&packet);
+        assert_eq!(events, vec![GdbEvent::Packet(b"X0,2:}#".to_vec())]);
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode_packet(b"OK"), b"$OK#9a".to_vec());
+        assert_eq!(encode_hex(&[0x00, 0x5a, 0xff]), "005aff");
+        assert_eq!(decode_hex(b"005aFF").unwrap(), vec![0x00, 0x5a, 0xff]);
+        assert!(decode_hex(b"5af").is_err());
+        assert!(decode_hex(b"zz").is_err());
+        assert_eq!(parse_hex(b"80200000").unwrap(), 0x8020_0000);
+        assert!(parse_hex(b"").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! RISC-V KVM has no hardware single-step, so a step is emulated by placing
+//! temporary breakpoints on every instruction that may run next.
+
+use anyhow::{bail, Result};
+
+/// `ebreak`.
+const EBREAK: [u8; 4] = [0x73, 0x00, 0x10, 0x00];
+/// `c.ebreak`, used on compressed instructions so that it doesn't cover the next one.
+const C_EBREAK: [u8; 2] = [0x02, 0x90];
+
+const OPCODE_BRANCH: u32 = 0x63;
+const OPCODE_JALR: u32 = 0x67;
+const OPCODE_JAL: u32 = 0x6f;
+
+/// Length of the instruction whose lowest halfword is `insn`.
+pub fn insn_len(insn: u32) -> u64 {
+    if insn & 0x3 == 0x3 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Breakpoint instruction of gdb breakpoint `kind`, which is the length of
+/// the instruction it replaces.
+pub fn breakpoint_insn(kind: u64) -> Result<&'static [u8]> {
+    match kind {
+        2 => Ok(&C_EBREAK),
+        4 => Ok(&EBREAK),
+        _ => bail!("Unsupported breakpoint kind {}", kind),
+    }
+}
+
+/// Sign-extend the lowest `bits` bits of `value`.
+fn sign_extend(value: u32, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((value as u64) << shift) as i64 >> shift) as u64
+}
+
+fn bits(insn: u32, hi: u32, lo: u32) -> u32 {
+    (insn >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Returns the addresses where the instruction at `pc` may go.
+///
+/// # Arguments
+///
+/// * `insn` - The instruction at `pc`, only the lowest halfword is used if it's compressed.
+/// * `pc` - Address of the instruction.
+/// * `reg` - Reads general register, for the targets of indirect jumps.
+pub fn next_pcs(insn: u32, pc: u64, reg: impl Fn(usize) -> Result<u64>) -> Result<Vec<u64>> {
+    let len = insn_len(insn);
+    let next = pc.wrapping_add(len);
+    if len == 4 {
+        let rs1 = bits(insn, 19, 15) as usize;
+        match insn & 0x7f {
+            OPCODE_JAL => {
+                let imm = bits(insn, 31, 31) << 20
+                    | bits(insn, 19, 12) << 12
+                    | bits(insn, 20, 20) << 11
+                    | bits(insn, 30, 21) << 1;
+                return Ok(vec![pc.wrapping_add(sign_extend(imm, 21))]);
+            }
+            OPCODE_JALR => {
+                let imm = sign_extend(bits(insn, 31, 20), 12);
+                return Ok(vec![reg(rs1)?.wrapping_add(imm) & !1]);
+            }
+            OPCODE_BRANCH => {
+                let imm = bits(insn, 31, 31) << 12
+                    | bits(insn, 7, 7) << 11
+                    | bits(insn, 30, 25) << 5
+                    | bits(insn, 11, 8) << 1;
+                return Ok(vec![pc.wrapping_add(sign_extend(imm, 13)), next]);
+            }
+            _ => return Ok(vec![next]),
+        }
+    }
+
+    let funct3 = bits(insn, 15, 13);
+    match (insn & 0x3, funct3) {
+        // c.j
+        (1, 0b101) => {
+            let imm = bits(insn, 12, 12) << 11
+                | bits(insn, 8, 8) << 10
+                | bits(insn, 10, 9) << 8
+                | bits(insn, 6, 6) << 7
+                | bits(insn, 7, 7) << 6
+                | bits(insn, 2, 2) << 5
+                | bits(insn, 11, 11) << 4
+                | bits(insn, 5, 3) << 1;
+            Ok(vec![pc.wrapping_add(sign_extend(imm, 12))])
+        }
+        // c.beqz and c.bnez
+        (1, 0b110) | (1, 0b111) => {
+            let imm = bits(insn, 12, 12) << 8
+                | bits(insn, 6, 5) << 6
+                | bits(insn, 2, 2) << 5
+                | bits(insn, 11, 10) << 3
+                | bits(insn, 4, 3) << 1;
+            Ok(vec![pc.wrapping_add(sign_extend(imm, 9)), next])
+        }
+        // c.jr and c.jalr, rs2 is zero and rs1 isn't.
+        (2, 0b100) if bits(insn, 6, 2) == 0 && bits(insn, 11, 7) != 0 => {
+            Ok(vec![reg(bits(insn, 11, 7) as usize)? & !1])
+        }
+        _ => Ok(vec![next]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn regs(reg: usize) -> Result<u64> {
+        Ok(0x8020_0000 + reg as u64 * 0x10)
+    }
+
+    #[test]
+    fn test_next_pcs() {
+        let pc = 0x8020_1000;
+        // addi a0, a0, 1
+        assert_eq!(next_pcs(0x0015_0513, pc, regs).unwrap(), vec![pc + 4]);
+        // jal ra, -8
+        assert_eq!(next_pcs(0xff9f_f0ef, pc, regs).unwrap(), vec![pc - 8]);
+        // jalr zero, 5(a1)
+        assert_eq!(next_pcs(0x0055_8067, pc, regs).unwrap(), vec![0x8020_00b4]);
+        // beq a0, a1, 0x800
+        assert_eq!(
+            next_pcs(0x00b5_00e3, pc, regs).unwrap(),
+            vec![pc + 0x800, pc + 4]
+        );
+        // bne a0, zero, -4
+        assert_eq!(
+            next_pcs(0xfe05_1ee3, pc, regs).unwrap(),
+            vec![pc - 4, pc + 4]
+        );
+
+        // c.addi a0, 1
+        assert_eq!(next_pcs(0x0505, pc, regs).unwrap(), vec![pc + 2]);
+        // c.j -2
+        assert_eq!(next_pcs(0xbffd, pc, regs).unwrap(), vec![pc - 2]);
+        // c.j 0x7fe
+        assert_eq!(next_pcs(0xaffd, pc, regs).unwrap(), vec![pc + 0x7fe]);
+        // c.beqz a0, 0x10
+        assert_eq!(next_pcs(0xc901, pc, regs).unwrap(), vec![pc + 0x10, pc + 2]);
+        // c.bnez a5, -2
+        assert_eq!(next_pcs(0xfffd, pc, regs).unwrap(), vec![pc - 2, pc + 2]);
+        // c.jr ra
+        assert_eq!(next_pcs(0x8082, pc, regs).unwrap(), vec![0x8020_0010]);
+        // c.jalr a5
+        assert_eq!(next_pcs(0x9782, pc, regs).unwrap(), vec![0x8020_00f0]);
+        // c.mv a0, a5 has rs2 set.
+        assert_eq!(next_pcs(0x853e, pc, regs).unwrap(), vec![pc + 2]);
+        // c.ebreak
+        assert_eq!(next_pcs(0x9002, pc, regs).unwrap(), vec![pc + 2]);
+    }
+
+    #[test]
+    fn test_breakpoint_insn() {
+        assert_eq!(insn_len(0x0015_0513), 4);
+        assert_eq!(insn_len(0x9002), 2);
+        assert_eq!(breakpoint_insn(2).unwrap(), &[0x02, 0x90]);
+        assert_eq!(breakpoint_insn(4).unwrap(), &[0x73, 0x00, 0x10, 0x00]);
+        assert!(breakpoint_insn(3).is_err());
+    }
+}
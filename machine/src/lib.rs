@@ -17,18 +17,20 @@
 // See the Mulan PSL v2 for more details.
 
 pub mod error;
+mod gdbstub;
 pub mod micro_vm;
 
 pub use crate::error::MachineError;
 use std::collections::{BTreeMap, HashMap};
-use std::fs::{remove_file, File};
+use std::fs::File;
 use std::net::TcpListener;
 use std::ops::Deref;
-use std::os::unix::{io::AsRawFd, net::UnixListener};
+use std::os::unix::io::AsRawFd;
 use std::rc::Rc;
 use std::sync::{Arc, Barrier, Condvar, Mutex, Weak};
 
 use kvm_ioctls::VcpuFd;
+use log::error;
 use util::file::{lock_file, unlock_file};
 use util::loop_context::read_fd;
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
@@ -36,19 +38,20 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 pub use micro_vm::LightMachine;
 
 use address_space::{
-    create_host_mmaps, set_host_memory_policy, AddressSpace, KvmMemoryListener, Region,
+    create_host_mmaps, set_host_memory_policy, AddressSpace, HostMemMapping, KvmMemoryListener,
+    Region,
 };
 pub use anyhow::Result;
 use anyhow::{anyhow, bail, Context};
-use cpu::{ArchCPU, CPUBootConfig, CPUInterface, CPUTopology, CPU};
-use devices::legacy::FwCfgOps;
+use cpu::{ArchCPU, CPUBootConfig, CPUFeatures, CPUInterface, CPUTopology, CPU};
+use devices::legacy::{Chardev, FwCfgOps};
 #[cfg(target_arch = "riscv64")]
 use devices::InterruptController;
 use hypervisor::kvm::KVM_FDS;
 use machine_manager::config::{
     parse_device_id, 
     parse_virtconsole, parse_virtio_serial, Incoming,
-    MachineMemConfig, MigrateMode, SerialConfig, VmConfig, DriveFile
+    MachineMemConfig, MigrateMode, PFlashConfig, SerialConfig, VmConfig, DriveFile
 };
 use machine_manager::{
     event_loop::EventLoop,
@@ -63,6 +66,29 @@ use util::{
 };
 use virtio::{Console, VirtioConsoleState, VirtioDevice, VirtioMmioDevice, VirtioMmioState};
 
+/// Mmap guest RAM of `ram_ranges` and set its NUMA policy, which touches no machine
+/// state, so it can run while devices are being realized.
+///
+/// # Arguments
+///
+/// * `ram_ranges` - Ranges of guest RAM, see `MachineOps::ram_ranges_to_map`.
+/// * `mem_config` - Memory setting.
+/// * `nr_cpus` - The number of vcpus, which bounds threads of prealloc.
+pub fn map_guest_ram(
+    ram_ranges: &[(u64, u64)],
+    mem_config: &MachineMemConfig,
+    nr_cpus: u8,
+) -> Result<Vec<Arc<HostMemMapping>>> {
+    if ram_ranges.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mem_mappings = create_host_mmaps(ram_ranges, mem_config, nr_cpus)
+        .with_context(|| "Failed to mmap guest ram.")?;
+    set_host_memory_policy(&mem_mappings, &mem_config.mem_zones)
+        .with_context(|| "Failed to set host memory NUMA policy.")?;
+    Ok(mem_mappings)
+}
+
 pub trait MachineOps {
     /// Calculate the ranges of memory according to architecture.
     ///
@@ -95,37 +121,64 @@ pub trait MachineOps {
         // call registers some notifier functions in the KVM, which are frequently triggered when
         // doing memory prealloc.To avoid affecting memory prealloc performance, create_host_mmaps
         // needs to be invoked first.
-        let mut mem_mappings = Vec::new();
-        let migrate_info = self.get_migrate_info();
-        if migrate_info.0 != MigrateMode::File {
-            let ram_ranges = self.arch_ram_ranges(mem_config.mem_size);
-            mem_mappings = create_host_mmaps(&ram_ranges, mem_config, nr_cpus)
-                .with_context(|| "Failed to mmap guest ram.")?;
-            set_host_memory_policy(&mem_mappings, &mem_config.mem_zones)
-                .with_context(|| "Failed to set host memory NUMA policy.")?;
+        let ram_ranges = self.ram_ranges_to_map(mem_config.mem_size);
+        let mem_mappings = map_guest_ram(&ram_ranges, mem_config, nr_cpus)?;
+        self.register_memory_listener(sys_mem)?;
+        self.add_guest_ram(sys_mem, &mem_mappings)
+    }
+
+    /// Ranges of guest RAM to be mapped by host, which are empty if RAM is restored
+    /// from a migration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_size` - memory size of VM.
+    fn ram_ranges_to_map(&self, mem_size: u64) -> Vec<(u64, u64)> {
+        if self.get_migrate_info().0 == MigrateMode::File {
+            return Vec::new();
         }
+        self.arch_ram_ranges(mem_size)
+    }
 
+    /// Register KVM listener of memory space, which must be done before any device
+    /// adds its regions or ioeventfds, as only regions are replayed to the listener.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - Memory address space.
+    fn register_memory_listener(&self, sys_mem: &Arc<AddressSpace>) -> Result<()> {
         sys_mem
             .register_listener(Arc::new(Mutex::new(KvmMemoryListener::new(
                 KVM_FDS.load().fd.as_ref().unwrap().get_nr_memslots() as u32,
             ))))
-            .with_context(|| "Failed to register KVM listener for memory space.")?;
-
-        if migrate_info.0 != MigrateMode::File {
-            for mmap in mem_mappings.iter() {
-                let base = mmap.start_address().raw_value();
-                let size = mmap.size();
-                sys_mem
-                    .root()
-                    .add_subregion(Region::init_ram_region(mmap.clone()), base)
-                    .with_context(|| anyhow!(MachineError::RegMemRegionErr(base, size)))?;
-            }
+            .with_context(|| "Failed to register KVM listener for memory space.")
+    }
+
+    /// Add guest RAM mapped by `map_guest_ram` to memory space.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - Memory address space.
+    /// * `mem_mappings` - Host mappings of guest RAM.
+    fn add_guest_ram(
+        &self,
+        sys_mem: &Arc<AddressSpace>,
+        mem_mappings: &[Arc<HostMemMapping>],
+    ) -> Result<()> {
+        for mmap in mem_mappings.iter() {
+            let base = mmap.start_address().raw_value();
+            let size = mmap.size();
+            sys_mem
+                .root()
+                .add_subregion(Region::init_ram_region(mmap.clone()), base)
+                .with_context(|| anyhow!(MachineError::RegMemRegionErr(base, size)))?;
         }
 
         MigrationManager::register_memory_instance(sys_mem.clone());
 
         Ok(())
     }
+
     /// Init vcpu register with boot message.
     ///
     /// # Arguments
@@ -134,12 +187,14 @@ pub trait MachineOps {
     /// * `nr_cpus` - The number of vcpus.
     /// * `fds` - File descriptors obtained by creating new Vcpu in KVM.
     /// * `boot_cfg` - Boot message generated by reading boot source to guest memory.
+    /// * `features` - ISA extensions of vcpus given by `-cpu`.
     fn init_vcpu(
         vm: Arc<Mutex<dyn MachineInterface + Send + Sync>>,
         nr_cpus: u8,
         topology: &CPUTopology,
         fds: &[Arc<VcpuFd>],
         boot_cfg: &Option<CPUBootConfig>,
+        features: &CPUFeatures,
     ) -> Result<Vec<Arc<CPU>>>
     where
         Self: Sized,
@@ -161,10 +216,7 @@ pub trait MachineOps {
         if let Some(boot_config) = boot_cfg {
             for cpu_index in 0..nr_cpus as usize {
                 cpus[cpu_index as usize]
-                    .realize(
-                        boot_config,
-                        topology,
-                    )
+                    .realize(boot_config, topology, features)
                     .with_context(|| {
                         format!(
                             "Failed to realize arch cpu register/features for CPU {}/KVM",
@@ -199,6 +251,83 @@ pub trait MachineOps {
         irq_chip: Arc<Mutex<InterruptController>>,
     ) -> Result<()>;
 
+    /// Add SBI debug console on a dedicated chardev, which shares the serial's by
+    /// default.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Configuration of the dedicated chardev.
+    fn add_sbi_console(&mut self, _config: &SerialConfig) -> Result<()> {
+        bail!("Dedicated SBI debug console is not supported!");
+    }
+
+    /// Register the chardev used by device `frontend`, which is listed by
+    /// `query-chardev` and can't be removed by `chardev-remove`.
+    ///
+    /// # Arguments
+    ///
+    /// * `chardev` - The chardev realized or to be realized by device.
+    /// * `frontend` - Id of the device.
+    fn register_chardev(&mut self, _chardev: Arc<Mutex<Chardev>>, _frontend: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add RTC device.
+    fn add_rtc_device(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add watchdog device, whose expiry is handled by the action of `-watchdog-action`.
+    fn add_watchdog_device(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add sifive test device, through which guest powers off or reboots the machine.
+    fn add_sifive_test_device(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add gpio device with power button pressed by QMP `system_powerdown`.
+    fn add_gpio_device(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add fw_cfg device, which publishes boot parameters to guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `nr_cpus` - Number of vcpus.
+    fn add_fwcfg_device(&mut self, _nr_cpus: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add ramfb device, which needs fw_cfg device.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg_args` - Device configuration args.
+    fn add_ramfb(&mut self, _cfg_args: &str) -> Result<()> {
+        bail!("Ramfb device is not supported!");
+    }
+
+    /// Add pvpanic device, through which guest reports its panic.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg_args` - Device configuration args.
+    fn add_pvpanic(&mut self, _cfg_args: &str) -> Result<()> {
+        bail!("Pvpanic device is not supported!");
+    }
+
+    /// Add pflash device.
+    ///
+    /// # Arguments
+    ///
+    /// * `configs` - Configs of pflash devices.
+    fn add_pflash_device(&mut self, _configs: &[PFlashConfig]) -> Result<()> {
+        bail!("Pflash device is not supported!");
+    }
+
     /// Add block device.
     ///
     /// # Arguments
@@ -226,6 +355,12 @@ pub trait MachineOps {
     /// Tcp, Unix, File and Unknown.
     fn get_migrate_info(&self) -> Incoming;
 
+    /// Listen on unix socket `path` for live migration, which restores and runs the
+    /// vm in background.
+    fn listen_incoming_migration(&self, _path: &str) -> Result<()> {
+        bail!("Live migration is not supported!");
+    }
+
     /// Add net device.
     ///
     /// # Arguments
@@ -241,6 +376,36 @@ pub trait MachineOps {
         bail!("Virtio mmio device Not supported!");
     }
 
+    /// Add virtio balloon device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    fn add_virtio_balloon(
+        &mut self,
+        _vm_config: &mut VmConfig,
+        _cfg_args: &str,
+        _irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<()> {
+        bail!("Virtio balloon device is not supported!");
+    }
+
+    /// Add virtio 9p device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    fn add_virtio_9p(
+        &mut self,
+        _vm_config: &mut VmConfig,
+        _cfg_args: &str,
+        _irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<()> {
+        bail!("Virtio 9p device is not supported!");
+    }
+
     /// Add console device.
     ///
     /// # Arguments
@@ -291,6 +456,8 @@ pub trait MachineOps {
         } else {
             bail!("No virtio-serial-bus specified");
         }
+        let chardev = console.lock().unwrap().chardev();
+        self.register_chardev(chardev, &device_cfg.id)?;
         MigrationManager::register_device_instance(
             VirtioConsoleState::descriptor(),
             console,
@@ -320,11 +487,32 @@ pub trait MachineOps {
         irq_chip: Arc<Mutex<InterruptController>>,
     ) -> Result<()> {
 
+        self.add_rtc_device()
+            .with_context(|| anyhow!(MachineError::AddDevErr("rtc".to_string())))?;
+        self.add_watchdog_device()
+            .with_context(|| anyhow!(MachineError::AddDevErr("watchdog".to_string())))?;
+        self.add_sifive_test_device()
+            .with_context(|| anyhow!(MachineError::AddDevErr("sifive-test".to_string())))?;
+        self.add_gpio_device()
+            .with_context(|| anyhow!(MachineError::AddDevErr("gpio".to_string())))?;
+
         let cloned_vm_config = vm_config.clone();
+        if let Some(pflashs) = cloned_vm_config.pflashs.as_ref() {
+            self.add_pflash_device(pflashs)
+                .with_context(|| anyhow!(MachineError::AddDevErr("pflash".to_string())))?;
+        }
         if let Some(serial) = cloned_vm_config.serial.as_ref() {
             self.add_serial_device(serial, #[cfg(target_arch = "riscv64")] irq_chip.clone())
                 .with_context(|| anyhow!(MachineError::AddDevErr("serial".to_string())))?;
         }
+        if let Some(console) = cloned_vm_config.sbi_console.as_ref() {
+            self.add_sbi_console(console)
+                .with_context(|| anyhow!(MachineError::AddDevErr("sbi-console".to_string())))?;
+        }
+
+        // Serial appends console to kernel cmdline, publish the final cmdline.
+        self.add_fwcfg_device(vm_config.machine_config.nr_cpus)
+            .with_context(|| anyhow!(MachineError::AddDevErr("fwcfg".to_string())))?;
 
         for dev in &cloned_vm_config.devices {
             let cfg_args = dev.1.as_str();
@@ -339,10 +527,32 @@ pub trait MachineOps {
                 "virtio-net-device" => {
                     self.add_virtio_mmio_net(vm_config, cfg_args, #[cfg(target_arch = "riscv64")] irq_chip.clone())?;
                 }
+                "virtio-balloon-device" => {
+                    self.add_virtio_balloon(
+                        vm_config,
+                        cfg_args,
+                        #[cfg(target_arch = "riscv64")]
+                        irq_chip.clone(),
+                    )?;
+                }
+                "virtio-9p-device" => {
+                    self.add_virtio_9p(
+                        vm_config,
+                        cfg_args,
+                        #[cfg(target_arch = "riscv64")]
+                        irq_chip.clone(),
+                    )?;
+                }
                 "virtio-serial-device" | "virtio-serial-pci" => {
                     self.add_virtio_serial(vm_config, cfg_args)?;
                 }
-                "virtconsole" => {
+                "ramfb" => {
+                    self.add_ramfb(cfg_args)?;
+                }
+                "pvpanic" => {
+                    self.add_pvpanic(cfg_args)?;
+                }
+                "virtconsole" | "virtio-console" => {
                     self.add_virtio_console(vm_config, cfg_args, #[cfg(target_arch = "riscv64")] irq_chip.clone())?;
                 }
                 _ => {
@@ -390,15 +600,21 @@ pub trait MachineOps {
     }
 
     /// Register a new drive backend file.
-    fn register_drive_file(&self, path: &str, read_only: bool, direct: bool) -> Result<()> {
+    fn register_drive_file(
+        &self,
+        path: &str,
+        read_only: bool,
+        direct: bool,
+        share_rw: bool,
+    ) -> Result<()> {
         let files = self.get_drive_files();
         let mut drive_files = files.lock().unwrap();
-        VmConfig::add_drive_file(&mut drive_files, path, read_only, direct)?;
+        VmConfig::add_drive_file(&mut drive_files, path, read_only, direct, share_rw)?;
 
         // Lock the added file if VM is running.
         let drive_file = drive_files.get_mut(path).unwrap();
         let vm_state = self.get_vm_state().deref().0.lock().unwrap();
-        if *vm_state == KvmVmState::Running && !drive_file.locked {
+        if *vm_state == KvmVmState::Running && !drive_file.locked && !drive_file.share_rw {
             if let Err(e) = lock_file(&drive_file.file, path, read_only) {
                 VmConfig::remove_drive_file(&mut drive_files, path)?;
                 return Err(e);
@@ -418,7 +634,7 @@ pub trait MachineOps {
     /// Active drive backend files. i.e., Apply lock.
     fn active_drive_files(&self) -> Result<()> {
         for drive_file in self.get_drive_files().lock().unwrap().values_mut() {
-            if drive_file.locked {
+            if drive_file.locked || drive_file.share_rw {
                 continue;
             }
             lock_file(&drive_file.file, &drive_file.path, drive_file.read_only)?;
@@ -453,10 +669,11 @@ pub trait MachineOps {
     ///
     /// # Arguments
     ///
-    /// * `paused` - Flag for `paused` when `LightMachine` starts to run.
+    /// * `paused` - Flag for `paused` when `LightMachine` starts to run, the vm is
+    ///   left in `Prelaunch` state until `cont`.
     fn run(&self, paused: bool) -> Result<()>;
 
-    /// Start machine as `Running` or `Paused` state.
+    /// Start machine as `Running` or `Prelaunch` state.
     ///
     /// # Arguments
     ///
@@ -469,20 +686,29 @@ pub trait MachineOps {
             self.active_drive_files()?;
         }
 
-        let nr_vcpus = cpus.len();
-        let cpus_thread_barrier = Arc::new(Barrier::new((nr_vcpus + 1) as usize));
-        for cpu_index in 0..nr_vcpus {
-            let cpu_thread_barrier = cpus_thread_barrier.clone();
-            let cpu = cpus[cpu_index as usize].clone();
-            CPU::start(cpu, cpu_thread_barrier, paused)
-                .with_context(|| format!("Failed to run vcpu{}", cpu_index))?;
-        }
-
+        self.vm_create_vcpus(cpus, paused)?;
         if paused {
-            *vm_state = KvmVmState::Paused;
+            *vm_state = KvmVmState::Prelaunch;
         } else {
             *vm_state = KvmVmState::Running;
         }
+
+        Ok(())
+    }
+
+    /// Create threads of all vcpus, which are parked until kicked by `vm_resume` if
+    /// `paused`, and wait until they are all ready.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpus` - Cpus vector restore cpu structure.
+    /// * `paused` - Park vcpus or not.
+    fn vm_create_vcpus(&self, cpus: &[Arc<CPU>], paused: bool) -> Result<()> {
+        let cpus_thread_barrier = Arc::new(Barrier::new(cpus.len() + 1));
+        for (cpu_index, cpu) in cpus.iter().enumerate() {
+            CPU::start(cpu.clone(), cpus_thread_barrier.clone(), paused)
+                .with_context(|| format!("Failed to run vcpu{}", cpu_index))?;
+        }
         cpus_thread_barrier.wait();
 
         Ok(())
@@ -589,7 +815,7 @@ pub trait MachineOps {
                 vm_state,
             )
             .with_context(|| "Failed to pause vm.")?,
-            (Paused, Running) => self
+            (Paused, Running) | (Prelaunch, Running) => self
             .vm_resume(cpus, vm_state)
                 .with_context(|| "Failed to resume vm.")?,
             (_, Shutdown) => {
@@ -637,6 +863,40 @@ pub fn vm_run(
 }
 
 
+/// Vm whose sysbus devices are unrealized by panic hook.
+static PANIC_TEARDOWN_VM: Mutex<Option<Weak<Mutex<dyn MachineOps + Send + Sync>>>> =
+    Mutex::new(None);
+
+/// Register the vm to be torn down if the process panics.
+pub fn set_panic_teardown_vm(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) {
+    if let Ok(mut teardown_vm) = PANIC_TEARDOWN_VM.lock() {
+        *teardown_vm = Some(Arc::downgrade(vm));
+    }
+}
+
+/// Unrealize sysbus devices of the vm registered by `set_panic_teardown_vm`. It's called
+/// by panic hook, so the vm is skipped if it's locked, e.g. by the panicking vcpu thread.
+pub fn panic_teardown() {
+    let vm = match PANIC_TEARDOWN_VM.try_lock() {
+        Ok(teardown_vm) => teardown_vm.as_ref().and_then(|vm| vm.upgrade()),
+        Err(_) => None,
+    };
+    let vm = match vm {
+        Some(vm) => vm,
+        None => return,
+    };
+    let result = match vm.try_lock() {
+        Ok(mut locked_vm) => locked_vm.get_sys_bus().unrealize_all(),
+        Err(_) => {
+            error!("Failed to lock vm, skip unrealizing sysbus devices");
+            return;
+        }
+    };
+    if let Err(e) = result {
+        error!("Failed to unrealize sysbus devices: {:?}", e);
+    }
+}
+
 /// Start incoming migration from destination.
 fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Result<()> {
     let (mode, path) = vm.lock().unwrap().get_migrate_info();
@@ -650,18 +910,14 @@ fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Re
                 .with_context(|| "Failed to start VM.")?;
         }
         MigrateMode::Unix => {
-            let listener = UnixListener::bind(&path)?;
-            let (mut sock, _) = listener.accept()?;
-            remove_file(&path)?;
-
-            MigrationManager::recv_migration(&mut sock)
-                .with_context(|| "Failed to receive migration with unix mode")?;
-            vm.lock()
-                .unwrap()
-                .run(false)
-                .with_context(|| "Failed to start VM.")?;
-            MigrationManager::finish_migration(&mut sock)
-                .with_context(|| "Failed to finish migraton.")?;
+            // Vcpus are parked until the migrated vm is restored, which ends the
+            // migration.
+            let locked_vm = vm.lock().unwrap();
+            locked_vm.run(true).with_context(|| "Failed to start VM.")?;
+            locked_vm
+                .listen_incoming_migration(&path)
+                .with_context(|| "Failed to listen for migration with unix mode")?;
+            return Ok(());
         }
         MigrateMode::Tcp => {
             let listener = TcpListener::bind(&path)?;
@@ -676,6 +932,14 @@ fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Re
             MigrationManager::finish_migration(&mut sock)
                 .with_context(|| "Failed to finish migraton.")?;
         }
+        MigrateMode::Defer => {
+            // Vcpus are parked until `snapshot-load`, which ends the migration.
+            vm.lock()
+                .unwrap()
+                .run(true)
+                .with_context(|| "Failed to start VM.")?;
+            return Ok(());
+        }
         MigrateMode::Unknown => {
             bail!("Unknown migration mode");
         }
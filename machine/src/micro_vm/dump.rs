@@ -0,0 +1,428 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{remove_file, rename, File};
+use std::io::{BufWriter, Write};
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use address_space::{AddressRange, AddressSpace, GuestAddress};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info};
+use machine_manager::event;
+use machine_manager::event_loop::EventLoop;
+use machine_manager::machine::{KvmVmState, MachineLifecycle};
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use util::loop_context::{read_fd, EventNotifier, NotifierCallback, NotifierOperation};
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+
+use super::LightMachine;
+
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const ELF_HEADER_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+/// Name of register notes with terminating NUL, padded to 4 bytes.
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+const NOTE_NAME_SIZE: u32 = 5;
+/// Size of `elf_prstatus` of riscv64, whose pid and registers are at the offsets.
+const PRSTATUS_SIZE: usize = 376;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REG_OFFSET: usize = 112;
+/// RAM is written in chunks of this size, between which progress is updated.
+const DUMP_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Guest memory dump, whose detached one runs in a worker thread and ends in main loop.
+pub(super) struct GuestDump {
+    /// A dump is in progress, and vm is kept paused.
+    active: AtomicBool,
+    /// Vm was running before the dump, and is resumed after it.
+    resume: AtomicBool,
+    /// Bytes of RAM to dump, and the ones written.
+    total: AtomicU64,
+    completed: AtomicU64,
+    /// Error of the dump finished.
+    result: Mutex<Option<Result<()>>>,
+    done_evt: EventFd,
+}
+
+impl GuestDump {
+    pub(super) fn new() -> Result<Self> {
+        Ok(GuestDump {
+            active: AtomicBool::new(false),
+            resume: AtomicBool::new(false),
+            total: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            result: Mutex::new(None),
+            done_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+/// RAM to dump, clipped to the window of `begin` and `length` if given.
+fn dump_ranges(ram: &[AddressRange], window: Option<(u64, u64)>) -> Result<Vec<AddressRange>> {
+    let (begin, length) = match window {
+        Some(window) => window,
+        None => return Ok(ram.to_vec()),
+    };
+    if length == 0 || begin.checked_add(length).is_none() {
+        bail!("Invalid dump range 0x{:x}+0x{:x}", begin, length);
+    }
+    let window = AddressRange::new(GuestAddress(begin), length);
+    let ranges: Vec<AddressRange> = ram
+        .iter()
+        .filter_map(|range| range.find_intersection(window))
+        .collect();
+    if ranges.is_empty() {
+        bail!(
+            "Dump range 0x{:x}+0x{:x} is out of guest memory",
+            begin,
+            length
+        );
+    }
+    Ok(ranges)
+}
+
+/// `NT_PRSTATUS` notes of vcpus, whose registers are pc followed by x1-x31.
+fn build_notes(regs: &[[u64; 32]]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (index, regs) in regs.iter().enumerate() {
+        data.extend_from_slice(&NOTE_NAME_SIZE.to_le_bytes());
+        data.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+        data.extend_from_slice(NOTE_NAME);
+
+        let mut prstatus = [0_u8; PRSTATUS_SIZE];
+        // Pid of hart n is n + 1, as thread ids in core file are nonzero.
+        prstatus[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4]
+            .copy_from_slice(&(index as u32 + 1).to_le_bytes());
+        for (n, reg) in regs.iter().enumerate() {
+            let offset = PRSTATUS_REG_OFFSET + n * 8;
+            prstatus[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+        }
+        data.extend_from_slice(&prstatus);
+    }
+    data
+}
+
+fn push_phdr(data: &mut Vec<u8>, p_type: u32, offset: u64, paddr: u64, size: u64) {
+    data.extend_from_slice(&p_type.to_le_bytes());
+    // Flags.
+    data.extend_from_slice(&0_u32.to_le_bytes());
+    // Offset, virtual address which is unknown without paging, physical address,
+    // size in file and in memory, and alignment.
+    for value in [offset, 0, paddr, size, size, 0] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// ELF header and program headers of core file, which are followed by `notes_len`
+/// bytes of notes and data of `ranges` in order.
+fn build_elf_header(ranges: &[AddressRange], notes_len: usize) -> Vec<u8> {
+    let phnum = ranges.len() + 1;
+    let notes_offset = (ELF_HEADER_SIZE + phnum * PHDR_SIZE) as u64;
+    let mut data = Vec::with_capacity(notes_offset as usize);
+    // Magic, 64-bit, little endian, current version and System V ABI.
+    data.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&ET_CORE.to_le_bytes());
+    data.extend_from_slice(&EM_RISCV.to_le_bytes());
+    data.extend_from_slice(&1_u32.to_le_bytes());
+    // Entry, offset of program headers and section headers.
+    for value in [0, ELF_HEADER_SIZE as u64, 0] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(&0_u32.to_le_bytes());
+    // Sizes of headers, number of program headers, no section headers.
+    for value in [ELF_HEADER_SIZE, PHDR_SIZE, phnum, 0, 0, 0] {
+        data.extend_from_slice(&(value as u16).to_le_bytes());
+    }
+
+    push_phdr(&mut data, PT_NOTE, notes_offset, 0, notes_len as u64);
+    let mut offset = notes_offset + notes_len as u64;
+    for range in ranges.iter() {
+        push_phdr(
+            &mut data,
+            PT_LOAD,
+            offset,
+            range.base.raw_value(),
+            range.size,
+        );
+        offset += range.size;
+    }
+    data
+}
+
+/// Core file being written by a dump.
+struct DumpJob {
+    sys_mem: Arc<AddressSpace>,
+    path: String,
+    ranges: Vec<AddressRange>,
+    notes: Vec<u8>,
+}
+
+impl DumpJob {
+    /// Write the core file to a temporary file, which is renamed to the path when
+    /// it's completed and removed otherwise.
+    fn run(&self, completed: &AtomicU64) -> Result<()> {
+        let tmp_path = format!("{}.tmp", self.path);
+        let ret = self.write(&tmp_path, completed).and_then(|_| {
+            rename(&tmp_path, &self.path)
+                .with_context(|| format!("Failed to rename dump file to {}", self.path))
+        });
+        if ret.is_err() {
+            if let Err(e) = remove_file(&tmp_path) {
+                error!("Failed to remove dump file {}: {:?}", tmp_path, e);
+            }
+        }
+        ret
+    }
+
+    fn write(&self, path: &str, completed: &AtomicU64) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create dump file {}", path))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&build_elf_header(&self.ranges, self.notes.len()))?;
+        writer.write_all(&self.notes)?;
+        for range in self.ranges.iter() {
+            let mut offset = 0;
+            while offset < range.size {
+                let size = (range.size - offset).min(DUMP_CHUNK_SIZE);
+                let addr = range.base.unchecked_add(offset);
+                self.sys_mem
+                    .read(&mut writer, addr, size)
+                    .with_context(|| format!("Failed to dump RAM at 0x{:x}", addr.raw_value()))?;
+                offset += size;
+                completed.fetch_add(size, Ordering::SeqCst);
+            }
+        }
+        // Errors of the data buffered, such as no space, are reported by flush and sync.
+        let file = writer
+            .into_inner()
+            .map_err(|e| anyhow!("Failed to write dump file {}: {}", path, e.error()))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to write dump file {}", path))
+    }
+}
+
+impl LightMachine {
+    /// Dump guest RAM and vcpu registers to ELF core file, with vcpus paused.
+    pub(super) fn start_dump(
+        &self,
+        paging: bool,
+        protocol: &str,
+        detach: bool,
+        window: Option<(u64, u64)>,
+        format: Option<&str>,
+    ) -> Result<()> {
+        if paging {
+            bail!("Dump with paging is not supported");
+        }
+        if let Some(format) = format.filter(|format| *format != "elf") {
+            bail!("Unsupported dump format {}", format);
+        }
+        let path = match protocol.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => path,
+            _ => bail!("Unsupported dump protocol {}", protocol),
+        };
+        if self.dump.is_active() {
+            bail!("Dump is in progress");
+        }
+        let vmstate = *self.vm_state.0.lock().unwrap();
+        if vmstate != KvmVmState::Running && vmstate != KvmVmState::Paused {
+            bail!("Vm is not running");
+        }
+        let ranges = dump_ranges(&self.sys_mem.ram_ranges(), window)?;
+
+        let running = vmstate == KvmVmState::Running;
+        if running && !self.pause() {
+            bail!("Failed to pause vm");
+        }
+        let mut regs = Vec::with_capacity(self.cpus.len());
+        for cpu in self.cpus.iter() {
+            match cpu.arch().lock().unwrap().get_user_regs(cpu.fd()) {
+                Ok(cpu_regs) => regs.push(cpu_regs),
+                Err(e) => {
+                    if running && !self.resume() {
+                        error!("Failed to resume vm after dump");
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let job = DumpJob {
+            sys_mem: self.sys_mem.clone(),
+            path: path.to_string(),
+            ranges,
+            notes: build_notes(&regs),
+        };
+        let dump = self.dump.clone();
+        dump.active.store(true, Ordering::SeqCst);
+        dump.resume.store(running, Ordering::SeqCst);
+        dump.total.store(
+            job.ranges.iter().map(|range| range.size).sum(),
+            Ordering::SeqCst,
+        );
+        dump.completed.store(0, Ordering::SeqCst);
+
+        if !detach {
+            let ret = job.run(&dump.completed);
+            *dump.result.lock().unwrap() = Some(ret);
+            return self.finish_dump();
+        }
+        let ret = thread::Builder::new()
+            .name("dump".to_string())
+            .spawn(move || {
+                let ret = job.run(&dump.completed);
+                *dump.result.lock().unwrap() = Some(ret);
+                if let Err(e) = dump.done_evt.write(1) {
+                    error!("Failed to notify end of dump: {:?}", e);
+                }
+            });
+        if let Err(e) = ret {
+            *self.dump.result.lock().unwrap() = Some(Err(anyhow!("{}", e)));
+            return self
+                .finish_dump()
+                .with_context(|| "Failed to create dump thread");
+        }
+        Ok(())
+    }
+
+    /// End the dump finished, resume the vm paused by it and report the result.
+    fn finish_dump(&self) -> Result<()> {
+        let ret = self
+            .dump
+            .result
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| Err(anyhow!("Dump is not finished")));
+        self.dump.active.store(false, Ordering::SeqCst);
+        if self.dump.resume.load(Ordering::SeqCst) && !self.resume() {
+            error!("Failed to resume vm after dump");
+        }
+
+        let (status, error) = match ret.as_ref() {
+            Ok(()) => ("completed", None),
+            Err(e) => ("failed", Some(format!("{:?}", e))),
+        };
+        info!("Guest memory dump {}", status);
+        let completed = qmp_schema::DumpCompleted {
+            result: qmp_schema::DumpQueryResult {
+                status: status.to_string(),
+                completed: self.dump.completed.load(Ordering::SeqCst),
+                total: self.dump.total.load(Ordering::SeqCst),
+            },
+            error,
+        };
+        event!(DumpCompleted; completed);
+        ret
+    }
+
+    pub(super) fn register_dump_event(&self, vm: Arc<Mutex<Self>>) -> Result<()> {
+        let done_fd = self.dump.done_evt.as_raw_fd();
+        let done_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(done_fd);
+            if let Err(e) = vm.lock().unwrap().finish_dump() {
+                error!("Failed to dump guest memory: {:?}", e);
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            done_fd,
+            None,
+            EventSet::IN,
+            vec![done_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ram() -> Vec<AddressRange> {
+        vec![
+            AddressRange::new(GuestAddress(0x8000_0000), 0x1000_0000),
+            AddressRange::new(GuestAddress(0x2_0000_0000), 0x800_0000),
+        ]
+    }
+
+    #[test]
+    fn test_dump_ranges() {
+        assert_eq!(dump_ranges(&ram(), None).unwrap(), ram());
+        assert_eq!(
+            dump_ranges(&ram(), Some((0x8fff_f000, 0x1_7000_2000))).unwrap(),
+            vec![
+                AddressRange::new(GuestAddress(0x8fff_f000), 0x1000),
+                AddressRange::new(GuestAddress(0x2_0000_0000), 0x1000),
+            ]
+        );
+        assert!(dump_ranges(&ram(), Some((0x1000, 0x1000))).is_err());
+        assert!(dump_ranges(&ram(), Some((0x8000_0000, 0))).is_err());
+        assert!(dump_ranges(&ram(), Some((u64::MAX, 2))).is_err());
+    }
+
+    #[test]
+    fn test_dump_elf_header() {
+        let mut regs = [0_u64; 32];
+        regs[0] = 0x8020_0000;
+        regs[2] = 0xffff_ffff_8000_1000;
+        let notes = build_notes(&[[0; 32], regs]);
+        assert_eq!(notes.len(), 2 * (12 + 8 + PRSTATUS_SIZE));
+        let note = &notes[12 + 8 + PRSTATUS_SIZE..];
+        assert_eq!(note[..12], [5, 0, 0, 0, 0x78, 1, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&note[12..16], b"CORE");
+        let prstatus = &note[20..];
+        assert_eq!(prstatus[PRSTATUS_PID_OFFSET], 2);
+        let reg = |n: usize| {
+            let offset = PRSTATUS_REG_OFFSET + n * 8;
+            u64::from_le_bytes(prstatus[offset..offset + 8].try_into().unwrap())
+        };
+        assert_eq!(reg(0), 0x8020_0000);
+        assert_eq!(reg(2), 0xffff_ffff_8000_1000);
+
+        let header = build_elf_header(&ram(), notes.len());
+        assert_eq!(header.len(), ELF_HEADER_SIZE + 3 * PHDR_SIZE);
+        assert_eq!(header[..4], [0x7f, b'E', b'L', b'F']);
+        let half = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let word =
+            |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+        assert_eq!(half(16), ET_CORE);
+        assert_eq!(half(18), EM_RISCV);
+        assert_eq!(half(56), 3);
+        // The note is followed by RAM ranges with their guest physical addresses.
+        let phdr = |n: usize| ELF_HEADER_SIZE + n * PHDR_SIZE;
+        assert_eq!(header[phdr(0)], PT_NOTE as u8);
+        assert_eq!(word(phdr(0) + 8), header.len() as u64);
+        assert_eq!(word(phdr(0) + 32), notes.len() as u64);
+        assert_eq!(header[phdr(1)], PT_LOAD as u8);
+        assert_eq!(word(phdr(1) + 8), (header.len() + notes.len()) as u64);
+        assert_eq!(word(phdr(1) + 24), 0x8000_0000);
+        assert_eq!(word(phdr(2) + 8), word(phdr(1) + 8) + 0x1000_0000);
+        assert_eq!(word(phdr(2) + 24), 0x2_0000_0000);
+        assert_eq!(word(phdr(2) + 40), 0x800_0000);
+    }
+}
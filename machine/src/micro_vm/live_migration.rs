@@ -0,0 +1,1010 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::VecDeque;
+use std::fs::remove_file;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use address_space::{AddressRange, AddressSpace, GuestAddress};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info};
+use machine_manager::config::MigrateMode;
+use machine_manager::event_loop::EventLoop;
+use machine_manager::machine::{KvmVmState, MachineLifecycle};
+use machine_manager::qmp::qmp_schema::{
+    CompressionStats, MigrationInfo, MigrationParameters, MigrationStats, XbzrleCacheStats,
+};
+use migration::MigrationStatus;
+use util::bitmap::Bitmap;
+use util::loop_context::{read_fd, EventNotifier, NotifierCallback, NotifierOperation};
+use util::unix::host_page_size;
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+
+use super::snapshot::{decode_state_section, SnapshotHeader};
+use super::xbzrle::{self, XbzrleCache};
+use super::LightMachine;
+use crate::MachineOps;
+
+/// Messages of migration stream. Each one is laid out as kind (u32), length of
+/// payload (u64) and payload.
+///
+/// Header of machine configuration, which destination answers with ack or error.
+const MSG_HEADER: u32 = 1;
+/// Guest address (u64) followed by data of RAM.
+const MSG_RAM: u32 = 2;
+/// State section of vcpus and devices, answered after the vm is restored.
+const MSG_STATE: u32 = 3;
+const MSG_ACK: u32 = 4;
+/// Error message, as utf-8 string.
+const MSG_ERROR: u32 = 5;
+/// Source gives up the migration.
+const MSG_CANCEL: u32 = 6;
+/// Flags (u32) of compression used by the stream, which destination answers with ack
+/// or error.
+const MSG_SETUP: u32 = 7;
+/// Guest address (u64) followed by RAM compressed by zstd.
+const MSG_RAM_ZSTD: u32 = 8;
+/// Guest address (u64) of a page followed by its xbzrle delta.
+const MSG_RAM_XBZRLE: u32 = 9;
+const SETUP_ZSTD: u32 = 1 << 0;
+const SETUP_XBZRLE: u32 = 1 << 1;
+const MSG_HEAD_SIZE: usize = 12;
+/// Upper limit of payload, larger ones mean a corrupted stream.
+const MAX_MSG_LEN: u64 = 64 << 20;
+/// RAM is sent in chunks of this size, between which bandwidth is throttled and
+/// cancel is checked.
+const RAM_CHUNK_SIZE: u64 = 1 << 20;
+/// Rounds of sending dirty RAM before the vm is stopped whether or not the dirty RAM
+/// converges.
+const MAX_DIRTY_ROUNDS: u32 = 30;
+/// Timeout of writing to and waiting for answer from destination.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_BANDWIDTH: u64 = 128 << 20;
+const DEFAULT_DOWNTIME_LIMIT: u64 = 300;
+/// Levels above it need much more memory to compress and decompress.
+const MAX_COMPRESS_LEVEL: u8 = 19;
+
+/// Requests of migration thread, which touch vcpus and devices and are handled in
+/// main loop.
+enum MigrationRequest {
+    /// Pause the vm and answer its state section, and whether it was running.
+    StopAndCopy(Sender<Result<(Vec<u8>, bool)>>),
+    /// Migration failed after the vm is stopped, resume it if it was running.
+    Recover(bool),
+    /// Restore the vm with state section received, and run it.
+    Restore(Vec<u8>, Sender<Result<()>>),
+}
+
+struct MigrationProgress {
+    status: MigrationStatus,
+    start: Instant,
+    /// Milliseconds from start to completion.
+    total_time: Option<u64>,
+    /// Milliseconds the vm is stopped in the last stage.
+    downtime: Option<u64>,
+    stats: MigrationStats,
+    compression: Option<CompressionStats>,
+    xbzrle_cache: Option<XbzrleCacheStats>,
+    /// Dirty pages found in xbzrle cache.
+    xbzrle_hits: u64,
+}
+
+/// Live migration of the machine, shared by QMP commands, migration thread and
+/// main loop.
+pub(super) struct LiveMigration {
+    params: Mutex<MigrationParameters>,
+    progress: Mutex<MigrationProgress>,
+    requests: Mutex<VecDeque<MigrationRequest>>,
+    req_evt: EventFd,
+}
+
+impl LiveMigration {
+    pub(super) fn new() -> Result<Self> {
+        Ok(LiveMigration {
+            params: Mutex::new(MigrationParameters {
+                max_bandwidth: DEFAULT_MAX_BANDWIDTH,
+                downtime_limit: DEFAULT_DOWNTIME_LIMIT,
+                compress_level: 0,
+                xbzrle_cache_size: 0,
+            }),
+            progress: Mutex::new(MigrationProgress {
+                status: MigrationStatus::None,
+                start: Instant::now(),
+                total_time: None,
+                downtime: None,
+                stats: MigrationStats::default(),
+                compression: None,
+                xbzrle_cache: None,
+                xbzrle_hits: 0,
+            }),
+            requests: Mutex::new(VecDeque::new()),
+            req_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    pub(super) fn parameters(&self) -> MigrationParameters {
+        self.params.lock().unwrap().clone()
+    }
+
+    /// Set the parameters given. Compression is negotiated when migration starts, so
+    /// it can't be changed during migration.
+    pub(super) fn set_parameters(
+        &self,
+        max_bandwidth: Option<u64>,
+        downtime_limit: Option<u64>,
+        compress_level: Option<u8>,
+        xbzrle_cache_size: Option<u64>,
+    ) -> Result<()> {
+        if compress_level.map_or(false, |level| level > MAX_COMPRESS_LEVEL) {
+            bail!(
+                "compress-level must be in range [0, {}]",
+                MAX_COMPRESS_LEVEL
+            );
+        }
+        if xbzrle_cache_size.map_or(false, |size| size != 0 && size < host_page_size()) {
+            bail!(
+                "xbzrle-cache-size must be 0 or at least {}",
+                host_page_size()
+            );
+        }
+        if (compress_level.is_some() || xbzrle_cache_size.is_some())
+            && self.status() == MigrationStatus::Active
+        {
+            bail!("Compression can't be changed during migration");
+        }
+
+        let mut params = self.params.lock().unwrap();
+        if let Some(bandwidth) = max_bandwidth {
+            params.max_bandwidth = bandwidth;
+        }
+        if let Some(limit) = downtime_limit {
+            params.downtime_limit = limit;
+        }
+        if let Some(level) = compress_level {
+            params.compress_level = level;
+        }
+        if let Some(size) = xbzrle_cache_size {
+            params.xbzrle_cache_size = size;
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> MigrationStatus {
+        self.progress.lock().unwrap().status
+    }
+
+    fn set_status(&self, status: MigrationStatus) {
+        let mut progress = self.progress.lock().unwrap();
+        if status == MigrationStatus::Completed {
+            progress.total_time = Some(progress.start.elapsed().as_millis() as u64);
+        }
+        progress.status = status;
+    }
+
+    /// Reset progress for migration of `total` bytes of RAM, with compression of
+    /// `params` reported.
+    fn begin(&self, total: u64, params: &MigrationParameters) {
+        let compression = (params.compress_level != 0).then(CompressionStats::default);
+        let xbzrle_cache = (params.xbzrle_cache_size != 0).then(|| XbzrleCacheStats {
+            cache_size: params.xbzrle_cache_size,
+            ..Default::default()
+        });
+        *self.progress.lock().unwrap() = MigrationProgress {
+            status: MigrationStatus::Active,
+            start: Instant::now(),
+            total_time: None,
+            downtime: None,
+            stats: MigrationStats {
+                total,
+                remaining: total,
+                page_size: host_page_size(),
+                ..Default::default()
+            },
+            compression,
+            xbzrle_cache,
+            xbzrle_hits: 0,
+        };
+    }
+
+    /// Cancel the active migration, which the migration thread notices between chunks.
+    pub(super) fn cancel(&self) -> Result<()> {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.status != MigrationStatus::Active {
+            bail!("No migration in progress");
+        }
+        progress.status = MigrationStatus::Canceled;
+        Ok(())
+    }
+
+    /// Information of the last live migration, none if it never ran.
+    pub(super) fn info(&self) -> Option<MigrationInfo> {
+        let progress = self.progress.lock().unwrap();
+        if progress.status == MigrationStatus::None {
+            return None;
+        }
+        let total_time = progress
+            .total_time
+            .unwrap_or_else(|| progress.start.elapsed().as_millis() as u64);
+        let compression = progress.compression.clone().map(|mut stats| {
+            if stats.compressed_size != 0 {
+                stats.compression_rate = stats.bytes as f64 / stats.compressed_size as f64;
+            }
+            stats
+        });
+        let xbzrle_cache = progress.xbzrle_cache.clone().map(|mut stats| {
+            let lookups = progress.xbzrle_hits + stats.cache_miss;
+            if lookups != 0 {
+                stats.cache_hit_rate = progress.xbzrle_hits as f64 / lookups as f64;
+            }
+            stats
+        });
+        Some(MigrationInfo {
+            status: Some(progress.status.to_string()),
+            total_time: Some(total_time),
+            downtime: progress.downtime,
+            ram: Some(progress.stats.clone()),
+            compression,
+            xbzrle_cache,
+        })
+    }
+
+    fn request(&self, req: MigrationRequest) -> Result<()> {
+        self.requests.lock().unwrap().push_back(req);
+        self.req_evt
+            .write(1)
+            .with_context(|| "Failed to notify migration request")
+    }
+}
+
+fn write_msg_head(dst: &mut dyn Write, kind: u32, len: u64) -> Result<()> {
+    let mut head = [0_u8; MSG_HEAD_SIZE];
+    head[..4].copy_from_slice(&kind.to_le_bytes());
+    head[4..].copy_from_slice(&len.to_le_bytes());
+    dst.write_all(&head)
+        .with_context(|| "Failed to write migration stream")
+}
+
+fn send_msg(dst: &mut dyn Write, kind: u32, payload: &[u8]) -> Result<()> {
+    write_msg_head(dst, kind, payload.len() as u64)?;
+    dst.write_all(payload)
+        .with_context(|| "Failed to write migration stream")?;
+    dst.flush()
+        .with_context(|| "Failed to write migration stream")
+}
+
+fn read_msg_head(src: &mut dyn Read) -> Result<(u32, u64)> {
+    let mut head = [0_u8; MSG_HEAD_SIZE];
+    src.read_exact(&mut head)
+        .with_context(|| "Failed to read migration stream")?;
+    let kind = u32::from_le_bytes(head[..4].try_into().unwrap());
+    let len = u64::from_le_bytes(head[4..].try_into().unwrap());
+    if len > MAX_MSG_LEN {
+        bail!("Invalid length {} of migration message", len);
+    }
+    Ok((kind, len))
+}
+
+fn read_payload(src: &mut dyn Read, len: u64) -> Result<Vec<u8>> {
+    let mut payload = vec![0_u8; len as usize];
+    src.read_exact(&mut payload)
+        .with_context(|| "Failed to read migration stream")?;
+    Ok(payload)
+}
+
+fn recv_msg(src: &mut dyn Read) -> Result<(u32, Vec<u8>)> {
+    let (kind, len) = read_msg_head(src)?;
+    Ok((kind, read_payload(src, len)?))
+}
+
+/// Wait for destination to accept the last message.
+fn expect_ack(src: &mut dyn Read) -> Result<()> {
+    match recv_msg(src)? {
+        (MSG_ACK, _) => Ok(()),
+        (MSG_ERROR, msg) => bail!("Destination refused: {}", String::from_utf8_lossy(&msg)),
+        (kind, _) => bail!("Unexpected migration message {}", kind),
+    }
+}
+
+/// Runs of contiguous dirty pages in `dirty` synced from `range`, as guest address
+/// and length clamped to the range.
+fn dirty_runs(
+    dirty: &Bitmap<u64>,
+    range: &AddressRange,
+    page_size: u64,
+) -> Result<Vec<(u64, u64)>> {
+    let base = range.base.raw_value();
+    let end = base + range.size;
+    let first_page = base / page_size;
+    let mut runs = Vec::new();
+    let mut bit = dirty.find_next_bit(0)?;
+    while bit < dirty.vol() {
+        let next = dirty.find_next_zero(bit)?;
+        let start = ((first_page + bit as u64) * page_size).max(base);
+        let stop = ((first_page + next as u64) * page_size).min(end);
+        if start >= end {
+            break;
+        }
+        runs.push((start, stop - start));
+        bit = dirty.find_next_bit(next)?;
+    }
+    Ok(runs)
+}
+
+/// Time to wait so that `bytes` sent in `elapsed` don't exceed `bandwidth` bytes per
+/// second, 0 means unlimited.
+fn throttle_delay(bytes: u64, bandwidth: u64, elapsed: Duration) -> Duration {
+    if bandwidth == 0 {
+        return Duration::ZERO;
+    }
+    let expected = Duration::from_nanos((bytes as u128 * 1_000_000_000 / bandwidth as u128) as u64);
+    expected.saturating_sub(elapsed)
+}
+
+/// Source side of live migration, run in migration thread.
+struct MigrationSource {
+    sys_mem: Arc<AddressSpace>,
+    migration: Arc<LiveMigration>,
+    header: SnapshotHeader,
+    writer: BufWriter<UnixStream>,
+    reader: UnixStream,
+    /// Dirty log is started.
+    logging: bool,
+    /// The vm is stopped for the last stage, and whether it was running.
+    stopped: Option<bool>,
+    /// Level of zstd compression, 0 means RAM is sent as is.
+    compress_level: u8,
+    /// Cache of dirty pages sent, none if xbzrle is disabled.
+    xbzrle: Option<XbzrleCache>,
+}
+
+impl MigrationSource {
+    fn run(&mut self) -> Result<()> {
+        send_msg(&mut self.writer, MSG_HEADER, &self.header.to_bytes())?;
+        expect_ack(&mut self.reader)?;
+        let mut flags = 0;
+        if self.compress_level != 0 {
+            flags |= SETUP_ZSTD;
+        }
+        if self.xbzrle.is_some() {
+            flags |= SETUP_XBZRLE;
+        }
+        send_msg(&mut self.writer, MSG_SETUP, &flags.to_le_bytes())?;
+        expect_ack(&mut self.reader)?;
+
+        self.sys_mem
+            .start_dirty_log()
+            .with_context(|| "Failed to start dirty log")?;
+        self.logging = true;
+
+        // Send all RAM first, then the pages dirtied meanwhile until the rest can be
+        // sent within downtime limit.
+        let mut pending: Vec<(u64, u64)> = self
+            .header
+            .ram
+            .iter()
+            .map(|range| (range.base.raw_value(), range.size))
+            .collect();
+        for round in 0..MAX_DIRTY_ROUNDS {
+            let start = Instant::now();
+            // Pages dirtied are likely to be dirtied again, only they are cached.
+            let sent = self.send_ram(&pending, true, round != 0)?;
+            let elapsed = start.elapsed();
+            pending = self.sync_dirty(elapsed)?;
+
+            let remaining: u64 = pending.iter().map(|(_, len)| len).sum();
+            let downtime_limit = self.migration.parameters().downtime_limit;
+            let expected_ms = if sent == 0 {
+                0
+            } else {
+                remaining as u128 * elapsed.as_millis() / sent as u128
+            };
+            if expected_ms <= downtime_limit as u128 {
+                break;
+            }
+        }
+
+        // Stop the vm, then send the last dirty pages and its state.
+        let (tx, rx) = channel();
+        self.migration.request(MigrationRequest::StopAndCopy(tx))?;
+        let (state, was_running) = rx
+            .recv()
+            .with_context(|| "Main loop dropped migration request")??;
+        self.stopped = Some(was_running);
+        let stop = Instant::now();
+
+        let pending = self.sync_dirty(Duration::ZERO)?;
+        self.send_ram(&pending, false, true)?;
+        self.sys_mem.stop_dirty_log()?;
+        self.logging = false;
+        send_msg(&mut self.writer, MSG_STATE, &state)?;
+        expect_ack(&mut self.reader)?;
+        self.migration.progress.lock().unwrap().downtime = Some(stop.elapsed().as_millis() as u64);
+        Ok(())
+    }
+
+    /// Send RAM `runs`, throttled to max bandwidth if `throttle`. Pages are sent as
+    /// xbzrle delta if `dirty` and xbzrle is enabled. Returns bytes of RAM sent.
+    fn send_ram(&mut self, runs: &[(u64, u64)], throttle: bool, dirty: bool) -> Result<u64> {
+        let start = Instant::now();
+        let mut sent = 0;
+        for (base, len) in runs.iter() {
+            let mut offset = 0;
+            while offset < *len {
+                if self.migration.status() == MigrationStatus::Canceled {
+                    bail!("Migration canceled");
+                }
+                let size = (len - offset).min(RAM_CHUNK_SIZE);
+                let gpa = base + offset;
+                let wire = if self.compress_level == 0 && self.xbzrle.is_none() {
+                    write_msg_head(&mut self.writer, MSG_RAM, 8 + size)?;
+                    self.writer
+                        .write_all(&gpa.to_le_bytes())
+                        .with_context(|| "Failed to write migration stream")?;
+                    self.sys_mem
+                        .read(&mut self.writer, GuestAddress(gpa), size)
+                        .with_context(|| format!("Failed to send RAM at 0x{:x}", gpa))?;
+                    MSG_HEAD_SIZE as u64 + 8 + size
+                } else {
+                    let mut data = Vec::with_capacity(size as usize);
+                    self.sys_mem
+                        .read(&mut data, GuestAddress(gpa), size)
+                        .with_context(|| format!("Failed to send RAM at 0x{:x}", gpa))?;
+                    self.send_data(gpa, &data, dirty)?
+                };
+                offset += size;
+                sent += size;
+
+                let mut progress = self.migration.progress.lock().unwrap();
+                progress.stats.transferred += wire;
+                progress.stats.remaining = progress.stats.remaining.saturating_sub(size);
+                drop(progress);
+                if throttle {
+                    let bandwidth = self.migration.parameters().max_bandwidth;
+                    thread::sleep(throttle_delay(sent, bandwidth, start.elapsed()));
+                }
+            }
+        }
+        self.writer
+            .flush()
+            .with_context(|| "Failed to write migration stream")?;
+        Ok(sent)
+    }
+
+    /// Send `data` of RAM at `gpa`, the pages found in xbzrle cache as delta if `dirty`.
+    /// Returns bytes written to the stream.
+    fn send_data(&mut self, gpa: u64, data: &[u8], dirty: bool) -> Result<u64> {
+        if !dirty || self.xbzrle.is_none() {
+            return self.send_block(gpa, data);
+        }
+        let cache = self.xbzrle.as_mut().unwrap();
+        let page_size = host_page_size() as usize;
+        let mut deltas = Vec::new();
+        let (mut hits, mut misses) = (0, 0);
+        for (idx, page) in data.chunks(page_size).enumerate() {
+            if page.len() != page_size {
+                break;
+            }
+            let page_gpa = gpa + (idx * page_size) as u64;
+            match cache.update(page_gpa, page) {
+                Some(old) => {
+                    hits += 1;
+                    if let Some(delta) = xbzrle::encode(&old, page) {
+                        deltas.push((idx, delta));
+                    }
+                }
+                None => misses += 1,
+            }
+        }
+
+        // Pages not sent as delta are sent in blocks of contiguous ones.
+        let mut wire = 0;
+        let mut block_start = 0;
+        let mut delta_bytes = 0;
+        for (idx, delta) in deltas.iter() {
+            let offset = idx * page_size;
+            if offset > block_start {
+                wire += self.send_block(gpa + block_start as u64, &data[block_start..offset])?;
+            }
+            write_msg_head(&mut self.writer, MSG_RAM_XBZRLE, 8 + delta.len() as u64)?;
+            self.writer
+                .write_all(&(gpa + offset as u64).to_le_bytes())
+                .and_then(|_| self.writer.write_all(delta))
+                .with_context(|| "Failed to write migration stream")?;
+            wire += MSG_HEAD_SIZE as u64 + 8 + delta.len() as u64;
+            delta_bytes += delta.len() as u64;
+            block_start = offset + page_size;
+        }
+        if block_start < data.len() {
+            wire += self.send_block(gpa + block_start as u64, &data[block_start..])?;
+        }
+
+        let mut progress = self.migration.progress.lock().unwrap();
+        progress.xbzrle_hits += hits;
+        if let Some(stats) = progress.xbzrle_cache.as_mut() {
+            stats.pages += deltas.len() as u64;
+            stats.bytes += delta_bytes;
+            stats.cache_miss += misses;
+        }
+        Ok(wire)
+    }
+
+    /// Send `data` of RAM at `gpa`, compressed if it's enabled and saves bytes.
+    /// Returns bytes written to the stream.
+    fn send_block(&mut self, gpa: u64, data: &[u8]) -> Result<u64> {
+        let mut kind = MSG_RAM;
+        let mut payload = data;
+        let compressed;
+        if self.compress_level != 0 {
+            compressed = zstd::bulk::compress(data, self.compress_level as i32)
+                .with_context(|| format!("Failed to compress RAM at 0x{:x}", gpa))?;
+            let mut progress = self.migration.progress.lock().unwrap();
+            if let Some(stats) = progress.compression.as_mut() {
+                stats.bytes += data.len() as u64;
+                stats.compressed_size += compressed.len().min(data.len()) as u64;
+            }
+            if compressed.len() < data.len() {
+                kind = MSG_RAM_ZSTD;
+                payload = &compressed;
+            }
+        }
+        write_msg_head(&mut self.writer, kind, 8 + payload.len() as u64)?;
+        self.writer
+            .write_all(&gpa.to_le_bytes())
+            .and_then(|_| self.writer.write_all(payload))
+            .with_context(|| "Failed to write migration stream")?;
+        Ok(MSG_HEAD_SIZE as u64 + 8 + payload.len() as u64)
+    }
+
+    /// Collect pages dirtied in `elapsed` since the last sync.
+    fn sync_dirty(&mut self, elapsed: Duration) -> Result<Vec<(u64, u64)>> {
+        let page_size = host_page_size();
+        let mut runs = Vec::new();
+        for range in self.header.ram.iter() {
+            let dirty = self.sys_mem.sync_dirty_bitmap(*range)?;
+            runs.append(&mut dirty_runs(&dirty, range, page_size)?);
+        }
+        let remaining: u64 = runs.iter().map(|(_, len)| len).sum();
+
+        let mut progress = self.migration.progress.lock().unwrap();
+        progress.stats.remaining = remaining;
+        progress.stats.dirty_sync_count += 1;
+        if !elapsed.is_zero() {
+            progress.stats.dirty_pages_rate =
+                (remaining / page_size) * 1000 / (elapsed.as_millis() as u64).max(1);
+        }
+        Ok(runs)
+    }
+}
+
+/// Answer ack to the last message, or error which is returned as well.
+fn answer(dst: &mut dyn Write, checked: Result<()>) -> Result<()> {
+    match checked {
+        Ok(()) => send_msg(dst, MSG_ACK, &[]),
+        Err(e) => {
+            send_msg(dst, MSG_ERROR, e.to_string().as_bytes())?;
+            Err(e)
+        }
+    }
+}
+
+/// Check that `len` bytes of RAM at `gpa` are in guest memory described by `header`.
+fn check_ram(header: &SnapshotHeader, gpa: u64, len: u64) -> Result<()> {
+    let valid = header.ram.iter().any(|range| {
+        gpa >= range.base.raw_value()
+            && gpa
+                .checked_add(len)
+                .map_or(false, |end| end <= range.base.raw_value() + range.size)
+    });
+    if !valid {
+        bail!("RAM 0x{:x}+0x{:x} is out of guest memory", gpa, len);
+    }
+    Ok(())
+}
+
+/// Write RAM message `kind` at `gpa`, whose rest `len` bytes are read from `src`.
+/// Returns bytes of RAM written.
+fn receive_ram(
+    sys_mem: &AddressSpace,
+    migration: &LiveMigration,
+    header: &SnapshotHeader,
+    src: &mut dyn Read,
+    kind: u32,
+    gpa: u64,
+    len: u64,
+) -> Result<u64> {
+    match kind {
+        MSG_RAM => {
+            check_ram(header, gpa, len)?;
+            sys_mem.write(&mut src.take(len), GuestAddress(gpa), len)?;
+            Ok(len)
+        }
+        MSG_RAM_ZSTD => {
+            let compressed = read_payload(src, len)?;
+            let data = zstd::bulk::decompress(&compressed, RAM_CHUNK_SIZE as usize)
+                .with_context(|| "Corrupted zstd data")?;
+            let size = data.len() as u64;
+            check_ram(header, gpa, size)?;
+            sys_mem.write(&mut data.as_slice(), GuestAddress(gpa), size)?;
+            let mut progress = migration.progress.lock().unwrap();
+            if let Some(stats) = progress.compression.as_mut() {
+                stats.bytes += size;
+                stats.compressed_size += len;
+            }
+            Ok(size)
+        }
+        _ => {
+            let delta = read_payload(src, len)?;
+            let page_size = host_page_size();
+            check_ram(header, gpa, page_size)?;
+            let mut page = Vec::with_capacity(page_size as usize);
+            sys_mem.read(&mut page, GuestAddress(gpa), page_size)?;
+            xbzrle::decode(&delta, &mut page)?;
+            sys_mem.write(&mut page.as_slice(), GuestAddress(gpa), page_size)?;
+            let mut progress = migration.progress.lock().unwrap();
+            progress.xbzrle_hits += 1;
+            if let Some(stats) = progress.xbzrle_cache.as_mut() {
+                stats.pages += 1;
+                stats.bytes += len;
+            }
+            Ok(page_size)
+        }
+    }
+}
+
+/// Destination side of live migration, run in migration thread.
+fn receive_migration(
+    sys_mem: &AddressSpace,
+    migration: &LiveMigration,
+    header: &SnapshotHeader,
+    stream: UnixStream,
+) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let (kind, data) = recv_msg(&mut reader)?;
+    if kind != MSG_HEADER {
+        bail!("Unexpected migration message {}", kind);
+    }
+    let checked =
+        SnapshotHeader::read_from(&mut data.as_slice()).and_then(|source| source.check(header));
+    answer(&mut writer, checked)?;
+
+    // Refuse compression destination isn't configured for, whose data can't be
+    // decoded into RAM.
+    let (kind, data) = recv_msg(&mut reader)?;
+    if kind != MSG_SETUP || data.len() != 4 {
+        bail!("Unexpected migration message {}", kind);
+    }
+    let flags = u32::from_le_bytes(data.as_slice().try_into().unwrap());
+    let params = migration.parameters();
+    let checked = if flags & !(SETUP_ZSTD | SETUP_XBZRLE) != 0 {
+        Err(anyhow!("Unknown compression flags 0x{:x}", flags))
+    } else if flags & SETUP_ZSTD != 0 && params.compress_level == 0 {
+        Err(anyhow!(
+            "Stream is compressed by zstd, but compress-level isn't set"
+        ))
+    } else if flags & SETUP_XBZRLE != 0 && params.xbzrle_cache_size == 0 {
+        Err(anyhow!(
+            "Stream is encoded by xbzrle, but xbzrle-cache-size isn't set"
+        ))
+    } else {
+        Ok(())
+    };
+    answer(&mut writer, checked)?;
+    let zstd = flags & SETUP_ZSTD != 0;
+    let xbzrle = flags & SETUP_XBZRLE != 0;
+
+    loop {
+        let (kind, len) = read_msg_head(&mut reader)?;
+        match kind {
+            MSG_RAM | MSG_RAM_ZSTD | MSG_RAM_XBZRLE => {
+                if len < 8 {
+                    bail!("Invalid length {} of RAM message", len);
+                }
+                if (kind == MSG_RAM_ZSTD && !zstd) || (kind == MSG_RAM_XBZRLE && !xbzrle) {
+                    bail!("RAM message {} isn't negotiated", kind);
+                }
+                let gpa = u64::from_le_bytes(read_payload(&mut reader, 8)?.try_into().unwrap());
+                let size = receive_ram(sys_mem, migration, header, &mut reader, kind, gpa, len - 8)
+                    .with_context(|| format!("Failed to receive RAM at 0x{:x}", gpa))?;
+                let mut progress = migration.progress.lock().unwrap();
+                progress.stats.transferred += MSG_HEAD_SIZE as u64 + len;
+                progress.stats.remaining = progress.stats.remaining.saturating_sub(size);
+            }
+            MSG_STATE => {
+                let state = read_payload(&mut reader, len)?;
+                let (tx, rx) = channel();
+                migration.request(MigrationRequest::Restore(state, tx))?;
+                let ret = rx
+                    .recv()
+                    .with_context(|| "Main loop dropped migration request")
+                    .and_then(|ret| ret);
+                match ret {
+                    Ok(()) => return send_msg(&mut writer, MSG_ACK, &[]),
+                    Err(e) => {
+                        send_msg(&mut writer, MSG_ERROR, e.to_string().as_bytes())?;
+                        return Err(e);
+                    }
+                }
+            }
+            MSG_CANCEL => bail!("Migration canceled by source"),
+            _ => bail!("Unexpected migration message {}", kind),
+        }
+    }
+}
+
+impl LightMachine {
+    /// Start live migration to the destination listening on unix socket `path`.
+    pub(super) fn start_migration(&self, path: &str) -> Result<()> {
+        match *self.vm_state.0.lock().unwrap() {
+            KvmVmState::Running | KvmVmState::Paused => {}
+            _ => bail!("Vm is not running"),
+        }
+        if matches!(
+            self.get_migrate_info().0,
+            MigrateMode::Defer | MigrateMode::Unix
+        ) {
+            bail!("Vm is waiting for incoming migration");
+        }
+        if self.migration.status() == MigrationStatus::Active {
+            bail!("Migration is already in progress");
+        }
+
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to migration socket {}", path))?;
+        stream.set_read_timeout(Some(STREAM_TIMEOUT))?;
+        stream.set_write_timeout(Some(STREAM_TIMEOUT))?;
+        let header = self.snapshot_header();
+        let params = self.migration.parameters();
+        self.migration
+            .begin(header.ram.iter().map(|range| range.size).sum(), &params);
+        let mut source = MigrationSource {
+            sys_mem: self.sys_mem.clone(),
+            migration: self.migration.clone(),
+            header,
+            writer: BufWriter::new(stream.try_clone()?),
+            reader: stream,
+            logging: false,
+            stopped: None,
+            compress_level: params.compress_level,
+            xbzrle: (params.xbzrle_cache_size != 0)
+                .then(|| XbzrleCache::new(params.xbzrle_cache_size, host_page_size())),
+        };
+        thread::Builder::new()
+            .name("migration".to_string())
+            .spawn(move || {
+                let ret = source.run();
+                let migration = source.migration.clone();
+                match ret {
+                    Ok(()) => {
+                        info!("Migration completed");
+                        migration.set_status(MigrationStatus::Completed);
+                        return;
+                    }
+                    Err(e) if migration.status() == MigrationStatus::Canceled => {
+                        info!("Migration canceled: {:?}", e);
+                        let _ = send_msg(&mut source.writer, MSG_CANCEL, &[]);
+                    }
+                    Err(e) => {
+                        error!("Migration failed: {:?}", e);
+                        migration.set_status(MigrationStatus::Failed);
+                    }
+                }
+                if source.logging {
+                    if let Err(e) = source.sys_mem.stop_dirty_log() {
+                        error!("{:?}", e);
+                    }
+                }
+                if let Some(was_running) = source.stopped {
+                    if let Err(e) = migration.request(MigrationRequest::Recover(was_running)) {
+                        error!("{:?}", e);
+                    }
+                }
+            })
+            .with_context(|| "Failed to create migration thread")?;
+        Ok(())
+    }
+
+    /// Listen on unix socket `path` for live migration, the vm waits with vcpus parked
+    /// until it's restored.
+    pub(super) fn listen_migration(&self, path: &str) -> Result<()> {
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind migration socket {}", path))?;
+        let sys_mem = self.sys_mem.clone();
+        let migration = self.migration.clone();
+        let header = self.snapshot_header();
+        let path = path.to_string();
+        thread::Builder::new()
+            .name("migration".to_string())
+            .spawn(move || {
+                let ret = listener
+                    .accept()
+                    .with_context(|| "Failed to accept migration")
+                    .and_then(|(stream, _)| {
+                        if let Err(e) = remove_file(&path) {
+                            error!("Failed to remove migration socket {}: {:?}", path, e);
+                        }
+                        let total = header.ram.iter().map(|range| range.size).sum();
+                        migration.begin(total, &migration.parameters());
+                        receive_migration(&sys_mem, &migration, &header, stream)
+                    });
+                match ret {
+                    Ok(()) => {
+                        info!("Incoming migration completed");
+                        migration.set_status(MigrationStatus::Completed);
+                    }
+                    Err(e) => {
+                        error!("Incoming migration failed: {:?}", e);
+                        migration.set_status(MigrationStatus::Failed);
+                    }
+                }
+            })
+            .with_context(|| "Failed to create migration thread")?;
+        Ok(())
+    }
+
+    pub(super) fn register_migration_event(&self, vm: Arc<Mutex<Self>>) -> Result<()> {
+        let req_fd = self.migration.req_evt.as_raw_fd();
+        let migration = self.migration.clone();
+        let req_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(req_fd);
+            let requests: Vec<MigrationRequest> =
+                migration.requests.lock().unwrap().drain(..).collect();
+            for req in requests {
+                vm.lock().unwrap().handle_migration_request(req);
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            req_fd,
+            None,
+            EventSet::IN,
+            vec![req_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)?;
+        Ok(())
+    }
+
+    fn handle_migration_request(&self, req: MigrationRequest) {
+        match req {
+            MigrationRequest::StopAndCopy(reply) => {
+                let vmstate = *self.vm_state.0.lock().unwrap();
+                let ret = match vmstate {
+                    KvmVmState::Running | KvmVmState::Paused => {
+                        let running = vmstate == KvmVmState::Running;
+                        if running && !self.pause() {
+                            Err(anyhow!("Failed to pause vm"))
+                        } else {
+                            self.save_state_section().map(|state| (state, running))
+                        }
+                    }
+                    _ => Err(anyhow!("Vm is not running")),
+                };
+                if let Err(SendError(Ok((_, true)))) = reply.send(ret) {
+                    // Migration thread is gone, don't leave the vm paused by it.
+                    self.resume();
+                }
+            }
+            MigrationRequest::Recover(resume) => {
+                if resume && !self.resume() {
+                    error!("Failed to resume vm after migration failed");
+                }
+            }
+            MigrationRequest::Restore(state, reply) => {
+                let ret = self.restore_migration(&state);
+                let _ = reply.send(ret);
+            }
+        }
+    }
+
+    fn restore_migration(&self, state: &[u8]) -> Result<()> {
+        let (cpus, devices) = decode_state_section(state, self.cpus.len() as u32)?;
+        self.restore_state_section(&cpus, devices)?;
+
+        // End the incoming migration, then run the restored vm.
+        if let Some((mode, _)) = self.get_vm_config().lock().unwrap().incoming.as_mut() {
+            *mode = MigrateMode::Unknown;
+        }
+        if !self.resume() {
+            bail!("Failed to resume vm");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_migration_msg() {
+        let (mut src, mut dst) = UnixStream::pair().unwrap();
+        send_msg(&mut src, MSG_HEADER, &[1, 2, 3]).unwrap();
+        send_msg(&mut src, MSG_ACK, &[]).unwrap();
+        assert_eq!(recv_msg(&mut dst).unwrap(), (MSG_HEADER, vec![1, 2, 3]));
+        assert!(expect_ack(&mut dst).is_ok());
+
+        send_msg(&mut src, MSG_ERROR, b"vcpus").unwrap();
+        let err = expect_ack(&mut dst).unwrap_err();
+        assert!(err.to_string().contains("vcpus"));
+
+        // Oversized message is refused before its payload is read.
+        write_msg_head(&mut src, MSG_RAM, MAX_MSG_LEN + 1).unwrap();
+        assert!(recv_msg(&mut dst).is_err());
+        drop(src);
+        assert!(recv_msg(&mut dst).is_err());
+    }
+
+    #[test]
+    fn test_dirty_runs() {
+        let range = AddressRange::new(GuestAddress(0x8000_0000), 0x8000);
+        let mut dirty = Bitmap::<u64>::new(1);
+        assert!(dirty_runs(&dirty, &range, 0x1000).unwrap().is_empty());
+
+        for page in [0, 1, 4, 7] {
+            dirty.set(page).unwrap();
+        }
+        assert_eq!(
+            dirty_runs(&dirty, &range, 0x1000).unwrap(),
+            vec![
+                (0x8000_0000, 0x2000),
+                (0x8000_4000, 0x1000),
+                (0x8000_7000, 0x1000)
+            ]
+        );
+
+        // Pages out of the range are dropped, partial pages are clamped.
+        dirty.set(8).unwrap();
+        let range = AddressRange::new(GuestAddress(0x8000_0800), 0x7000);
+        assert_eq!(
+            dirty_runs(&dirty, &range, 0x1000).unwrap(),
+            vec![
+                (0x8000_0800, 0x1800),
+                (0x8000_4000, 0x1000),
+                (0x8000_7000, 0x800)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_ram() {
+        let mut header = SnapshotHeader::default();
+        header.ram = vec![AddressRange::new(GuestAddress(0x8000_0000), 0x10_0000)];
+        assert!(check_ram(&header, 0x8000_0000, 0x10_0000).is_ok());
+        assert!(check_ram(&header, 0x8008_0000, 0x8_0000).is_ok());
+        assert!(check_ram(&header, 0x8008_0000, 0x8_1000).is_err());
+        assert!(check_ram(&header, 0x7fff_f000, 0x2000).is_err());
+        assert!(check_ram(&header, u64::MAX, 0x2).is_err());
+    }
+
+    #[test]
+    fn test_throttle_delay() {
+        assert_eq!(throttle_delay(1 << 20, 0, Duration::ZERO), Duration::ZERO);
+        assert_eq!(
+            throttle_delay(1 << 20, 1 << 20, Duration::from_millis(400)),
+            Duration::from_millis(600)
+        );
+        assert_eq!(
+            throttle_delay(1 << 20, 1 << 20, Duration::from_secs(2)),
+            Duration::ZERO
+        );
+    }
+}
@@ -14,20 +14,38 @@
 /// The type of memory layout entry on riscv64
 #[repr(usize)]
 pub enum LayoutEntryType {
+    SifiveTest,
+    Rtc,
+    Watchdog,
+    PvPanic,
+    Gpio,
+    Clint,
+    Flash,
     Plic,
     Uart,
     Mmio,
+    FwCfg,
     PcieEcam,
     PcieMmio,
     Mem,
+    HighMmio,
 }
 /// Layout of riscv64
 pub const MEM_LAYOUT: &[(u64, u64)] = &[
+    (0x0010_0000, 0x0000_1000),    // SifiveTest
+    (0x0010_1000, 0x0000_1000),    // Rtc
+    (0x0010_2000, 0x0000_1000),    // Watchdog
+    (0x0010_3000, 0x0000_1000),    // PvPanic
+    (0x0010_4000, 0x0000_1000),    // Gpio
+    (0x0200_0000, 0x0001_0000),    // Clint
+    (0x0400_0000, 0x0400_0000),    // Flash
     (0x0c00_0000, 0x0400_0000),    // Plic 
     (0x1000_0000, 0x0000_0100),    // Uart
     (0x1000_1000, 0x0000_1000),    // Mmio
+    (0x1010_0000, 0x0000_0018),    // FwCfg
     (0x2000_0000, 0x1000_0000),      // PcieEcam
     (0x3000_0000, 0x1000_0000),      // PcieMmio
     (0x8000_0000, 0x80_0000_0000), // Mem
+    (0x100_0000_0000, 0x100_0000_0000), // HighMmio
 ];
 
@@ -20,57 +20,108 @@ pub mod error;
 pub use error::MicroVmError;
 use util::aio::AioEngine;
 
+mod dump;
+mod live_migration;
 pub mod mem_layout;
+mod snapshot;
+mod user_dtb;
+mod xbzrle;
 
 use super::Result as MachineResult;
-use log::error;
-use std::collections::HashMap;
+use log::{error, warn};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fmt::Debug;
+use std::io::{Seek, SeekFrom};
 use std::ops::Deref;
-use std::os::unix::io::RawFd;
-use std::sync::{Arc, Condvar, Mutex};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 use std::vec::Vec;
-use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
-use address_space::{AddressSpace, GuestAddress, Region};
+use address_space::{AddressSpace, FileBackend, GuestAddress, HostMemMapping, Region};
 use boot_loader::{load_linux, BootLoaderConfig};
-use cpu::{CPUBootConfig, CPUTopology, CpuLifecycleState, CpuTopology, CPU};
-use devices::legacy::{FwCfgOps, Serial};
+use cpu::{
+    ArchCPU, CPUBootConfig, CPUFeatures, CPUInterface, CPUTopology, CpuLifecycleState, CpuTopology,
+    CPU,
+};
+use devices::legacy::{
+    Chardev, ChardevRegistry, DwWdt, FwCfgEntryType, FwCfgMem, FwCfgOps, GoldfishRtc, PFlash,
+    PvPanic, Ramfb, RamfbState, SbiDebugConsole, Serial, SifiveGpio, SifiveTest,
+};
 #[cfg(target_arch = "riscv64")]
-use devices::{InterruptController, InterruptControllerConfig, MAX_DEVICES};
+use devices::{Clint, InterruptController, InterruptControllerConfig};
 use hypervisor::kvm::KVM_FDS;
 use kvm_ioctls::VcpuFd;
 use machine_manager::config::{
-    parse_blk, parse_incoming_uri, parse_net, BlkDevConfig, Incoming, MigrateMode,
+    format_datetime, get_chardev_config, parse_balloon, parse_blk, parse_incoming_uri, parse_net,
+    parse_virtio_9p, BlkDevConfig, CmdParser, DriveConfig, Incoming, MachineType, MigrateMode,
+    PFlashConfig, PanicAction, PowerdownAction, RebootAction, ShutdownAction, WatchdogAction,
+    CPU_MODELS, ISA_EXTENSIONS, MAX_NR_CPUS,
 };
 use machine_manager::event;
+use machine_manager::event_loop::EventLoop;
 use machine_manager::machine::{
-    DeviceInterface, KvmVmState, MachineAddressInterface, MachineExternalInterface,
-    MachineInterface, MachineLifecycle, MachineTestInterface, MigrateInterface,
+    supported_machines, DeviceInterface, KvmVmState, MachineAddressInterface,
+    MachineExternalInterface, MachineInterface, MachineLifecycle, MachineOobInterface,
+    MachineTestInterface, MigrateInterface, PanicNotifier, SbiConsoleError,
+};
+use machine_manager::signal_handler::{
+    set_vm_exit_code, VM_EXIT_GUEST_FAILURE, VM_EXIT_GUEST_REBOOT,
 };
 use machine_manager::{
-    config::{BootSource, ConfigCheck, NetworkInterfaceConfig, SerialConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE, DriveFile},
-    qmp::{qmp_schema, QmpChannel, Response},
+    config::{BootSource, ConfigCheck, DriveFile, NetworkInterfaceConfig, SerialConfig, VmConfig},
+    qmp::{parse_fdset_path, qmp_schema, QmpChannel, Response},
 };
 use mem_layout::{LayoutEntryType, MEM_LAYOUT};
 use migration::{MigrationManager, MigrationStatus};
-use sysbus::{SysBus, SysBusDevType, SysRes, IRQ_BASE, IRQ_MAX};
-use util::device_tree::{self, CompileFDT, FdtBuilder};
-use util::{loop_context::EventLoopManager, set_termi_canon_mode};
+use sysbus::{
+    begin_fdt_node, check_mmio_trace_events, SysBus, SysBusDevOps, SysBusDevType, SysRes,
+    EMPTY_IRQ_RANGE, IRQ_BASE, IRQ_MAX,
+};
+use util::boot_time::{record_boot_milestone, BootMilestone};
+use util::byte_code::ByteCode;
+use util::device_tree::{self, CompileFDT, Fdt, FdtBuilder};
+use util::loop_context::{
+    read_fd, EventLoopManager, EventNotifier, EventNotifierHelper, NotifierCallback,
+    NotifierOperation, TimerHandle,
+};
+use util::parallel::{run_parallel, ParallelTask};
+use util::privilege::chroot_dir;
+use util::set_termi_canon_mode;
+use util::syscall::host_cpu_exists;
+use util::tap::Tap;
+use util::test_helper::is_test_enabled;
+use util::trace::set_trace_event_enabled;
 use virtio::{
-    create_tap, Block, BlockState, Net, VhostKern, VirtioDevice, VirtioMmioDevice,
-    VirtioMmioState, VirtioNetState,
+    Balloon, Block, BlockState, Net, VhostKern, Virtio9p, VirtioBalloonState, VirtioDevice,
+    VirtioMmioDevice, VirtioMmioState, VirtioNetState,
 };
 use devices::pcie_mem::PcieMem;
 
-use super::{error::MachineError, MachineOps};
+use super::gdbstub::{GdbStopNotifier, GdbStub};
+use super::{error::MachineError, map_guest_ram, MachineOps};
 use anyhow::{anyhow, bail, Context, Result};
+use dump::GuestDump;
+use live_migration::LiveMigration;
+use user_dtb::{load_user_dtb, merge_user_dtb};
 
 // The replaceable block device maximum count.
 const MMIO_REPLACEABLE_BLK_NR: usize = 1;
 // The replaceable network device maximum count.
 const MMIO_REPLACEABLE_NET_NR: usize = 1;
+// The count of virtio-mmio slots declared to guest for devices plugged by `device_add`.
+const MMIO_HOTPLUG_SLOT_NR: usize = 4;
+// The alignment of base address and size of hotplugged memory.
+const MEM_HOTPLUG_ALIGN: u64 = 0x800_0000;
+// The flash is divided into banks of the same size, one for each pflash unit.
+const FLASH_BANK_NR: u64 = 2;
+// Driver of vcpus hot-added by `device_add`.
+const CPU_DRIVER: &str = "host-riscv64-cpu";
 
 // The config of replaceable device.
 #[derive(Debug)]
@@ -112,6 +163,9 @@ struct MmioReplaceableInfo {
     block_count: usize,
     // The count of network device which is plugin.
     net_count: usize,
+    // Backends filled while machine is being realized, which are realized together
+    // once all devices are added. None if they are realized when filled.
+    pending_backends: Option<Vec<(usize, Arc<dyn ConfigCheck>)>>,
 }
 
 impl MmioReplaceableInfo {
@@ -121,10 +175,101 @@ impl MmioReplaceableInfo {
             devices: Arc::new(Mutex::new(Vec::new())),
             block_count: 0_usize,
             net_count: 0_usize,
+            pending_backends: None,
+        }
+    }
+}
+
+// The memory backend created by `object-add`.
+#[derive(Debug, Clone)]
+struct MemoryBackend {
+    // Size of the memory in bytes.
+    size: u64,
+    // Whether the memory is mapped shared.
+    share: bool,
+    // Whether the memory is created from memfd, whose size is sealed if `seal` is true.
+    memfd: bool,
+    seal: bool,
+    // Path of the file or the directory backing the memory, None if it's anonymous.
+    mem_path: Option<String>,
+    // Whether the memory is mergeable by KSM.
+    merge: bool,
+    // Whether the memory is included in core dump of the process.
+    dump: bool,
+    // Whether the memory is preallocated when it's plugged.
+    prealloc: bool,
+    // Host numa nodes the memory is bound to, sorted in ascending order.
+    host_nodes: Vec<u32>,
+}
+
+impl MemoryBackend {
+    fn qom_type(&self) -> &'static str {
+        if self.mem_path.is_some() {
+            "memory-backend-file"
+        } else if self.memfd {
+            "memory-backend-memfd"
+        } else {
+            "memory-backend-ram"
         }
     }
 }
 
+// The memory region plugged by `device_add`.
+#[derive(Debug)]
+struct PluggedMemory {
+    // Device id.
+    id: String,
+    // Id of the memory backend.
+    memdev: String,
+    // Guest physical address of the region.
+    base: u64,
+    // Size of the region.
+    size: u64,
+}
+
+// The block backend named by `-drive id` or `blockdev-add node-name`.
+#[derive(Debug)]
+struct BlockBackend {
+    // Configuration of the backend file.
+    drive: DriveConfig,
+    // Id of the device using the backend, None if it's free.
+    user: Option<String>,
+}
+
+// The network backend added by `netdev_add`, whose tap is opened at once.
+struct NetBackend {
+    // Backend type, "tap" or "fd".
+    net_type: String,
+    // Name of the tap device for "tap", or of the fd passed by `getfd` or the
+    // fd set path for "fd".
+    name: String,
+    // The opened tap, the device gets a duplicate of its fd.
+    tap: Tap,
+    // Id of the device using the backend, None if it's free.
+    user: Option<String>,
+}
+
+// The virtio-mmio slot declared to guest at boot, in which `device_add` plugs a device.
+// Guest finds the plugged device by reprobing the node of the slot.
+struct MmioHotplugSlot {
+    // Base address of the MMIO range reserved by the slot.
+    base: u64,
+    // IRQ allocated for the slot, shared by the devices plugged one after another.
+    irq: i32,
+    // The plugged device, None if the slot is empty.
+    device: Option<MmioPluggedDevice>,
+}
+
+// The virtio-mmio device plugged by `device_add`.
+struct MmioPluggedDevice {
+    // Device id.
+    id: String,
+    // The transport attached to system bus.
+    transport: Arc<Mutex<VirtioMmioDevice>>,
+    // The block device, None if it's not virtio-blk.
+    block: Option<Arc<Mutex<Block>>>,
+}
+
 /// A wrapper around creating and using a kvm-based micro VM.
 pub struct LightMachine {
     // `vCPU` topology, support sockets, cores, threads.
@@ -137,16 +282,72 @@ pub struct LightMachine {
     sysbus: SysBus,
     // All replaceable device information.
     replaceable_info: MmioReplaceableInfo,
+    // Slots for virtio-mmio devices plugged by `device_add`.
+    hotplug_slots: Vec<MmioHotplugSlot>,
     // VM running state.
     vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
     // Vm boot_source config.
     boot_source: Arc<Mutex<BootSource>>,
+    // Device tree given by `-dtb`, which is loaded instead of the generated one.
+    user_dtb: Option<Fdt>,
     // VM power button, handle VM `Shutdown` event.
     power_button: Arc<EventFd>,
+    // Reset request from guest, handled in main loop.
+    reset_req: Arc<EventFd>,
+    // Expiry of guest watchdog, handled in main loop.
+    watchdog_expired: Arc<EventFd>,
+    // Power off request from guest devices, handled in main loop.
+    poweroff_req: Arc<EventFd>,
+    // Reboot request from guest devices, which `-no-reboot` turns into shutdown.
+    reboot_req: Arc<EventFd>,
+    // Guest panic reported by vcpus and pvpanic, whose action is taken in main loop.
+    panic_notifier: Arc<PanicNotifier>,
+    // Vm is paused because guest panicked.
+    panicked: Arc<AtomicBool>,
+    // Whether pvpanic device is added.
+    has_pvpanic: bool,
+    // Virtio balloon device, through which `balloon` resizes guest memory.
+    balloon: Option<Arc<Mutex<Balloon>>>,
+    // Virtio block devices of the replaceable slots, the unused ones have no id.
+    replaceable_blocks: Vec<Arc<Mutex<Block>>>,
+    // Gpio device with power button, none for machine without devices.
+    gpio: Option<Arc<Mutex<SifiveGpio>>>,
+    // Guest ignores power button until the timeout of `-action powerdown=force-off`.
+    powerdown_expired: Arc<EventFd>,
+    // Timer of powerdown timeout, added to main loop when it's armed the first time.
+    powerdown_timer: Mutex<Option<TimerHandle>>,
+    // Backend of SBI debug console, none without serial or `-sbi-console`.
+    sbi_console: Option<SbiDebugConsole>,
     // All configuration information of virtual machine.
     vm_config: Arc<Mutex<VmConfig>>,
     // Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    // Block backends keyed by node name.
+    block_backends: Arc<Mutex<BTreeMap<String, BlockBackend>>>,
+    // Network backends added by `netdev_add`, keyed by id.
+    net_backends: Arc<Mutex<BTreeMap<String, NetBackend>>>,
+    // Memory backends created by `object-add`, keyed by id.
+    mem_backends: HashMap<String, MemoryBackend>,
+    // Memory regions hotplugged by `device_add`.
+    plugged_mem: Vec<PluggedMemory>,
+    // Fw_cfg device publishing boot parameters.
+    fwcfg_dev: Option<Arc<Mutex<dyn FwCfgOps>>>,
+    // Ramfb device, whose framebuffer is dumped by `screendump`.
+    ramfb: Option<Arc<Mutex<Ramfb>>>,
+    // Rtc device, whose time is queried by `query-rtc`.
+    rtc: Option<Arc<Mutex<GoldfishRtc>>>,
+    // Breakpoint stops of vcpus, reported to gdb stub.
+    gdb_stop: Option<Arc<GdbStopNotifier>>,
+    // Interrupt controller, whose contexts are connected to hot-added vcpus.
+    irq_chip: Option<Arc<Mutex<InterruptController>>>,
+    // Vcpus hot-added by `device_add`, keyed by device id.
+    plugged_cpus: HashMap<String, u8>,
+    // Live migration from or to this machine.
+    migration: Arc<LiveMigration>,
+    // Guest memory dump by `dump-guest-memory`.
+    dump: Arc<GuestDump>,
+    // Character devices used by devices or added by `chardev-add`.
+    chardevs: ChardevRegistry,
 }
 
 impl LightMachine {
@@ -158,16 +359,25 @@ impl LightMachine {
     pub fn new(vm_config: &VmConfig) -> MachineResult<Self> {
         let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))
             .with_context(|| anyhow!(MachineError::CrtMemSpaceErr))?;
-        let free_irqs: (i32, i32) = (IRQ_BASE, IRQ_MAX);
-        let mmio_region: (u64, u64) = (
-            MEM_LAYOUT[LayoutEntryType::Mmio as usize].0,
-            MEM_LAYOUT[LayoutEntryType::Mmio as usize + 1].0,
-        );
-        let sysbus = SysBus::new(
-            &sys_mem,
-            free_irqs,
-            mmio_region,
-        );
+        // None machine is created without any device.
+        let no_devices = vm_config.machine_config.mach_type == MachineType::None;
+        let (free_irqs, mmio_windows) = if no_devices {
+            (EMPTY_IRQ_RANGE, Vec::new())
+        } else {
+            // The window is shared by virtio-mmio devices and FwCfg, which sits at
+            // a fixed address in it.
+            let mmio_region: (u64, u64) = (
+                MEM_LAYOUT[LayoutEntryType::Mmio as usize].0,
+                MEM_LAYOUT[LayoutEntryType::PcieEcam as usize].0,
+            );
+            let high_mmio = MEM_LAYOUT[LayoutEntryType::HighMmio as usize];
+            let high_mmio_region: (u64, u64) = (high_mmio.0, high_mmio.0 + high_mmio.1);
+            ((IRQ_BASE, IRQ_MAX), vec![mmio_region, high_mmio_region])
+        };
+        let mut sysbus = SysBus::new(&sys_mem, free_irqs, mmio_windows)
+            .with_context(|| "Failed to create system bus")?;
+        // Ioeventfds are emulated by address space in test mode.
+        sysbus.set_ioeventfd_supported(is_test_enabled() || KVM_FDS.load().ioeventfd_supported());
 
         // Machine state init
         let vm_state = Arc::new((Mutex::new(KvmVmState::Created), Condvar::new()));
@@ -175,6 +385,48 @@ impl LightMachine {
             Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
                 anyhow!(MachineError::InitEventFdErr("power_button".to_string()))
             })?);
+        let reset_req = Arc::new(
+            EventFd::new(libc::EFD_NONBLOCK)
+                .with_context(|| anyhow!(MachineError::InitEventFdErr("reset_req".to_string())))?,
+        );
+        let watchdog_expired = Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+            anyhow!(MachineError::InitEventFdErr("watchdog_expired".to_string()))
+        })?);
+        let poweroff_req =
+            Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+                anyhow!(MachineError::InitEventFdErr("poweroff_req".to_string()))
+            })?);
+        let reboot_req =
+            Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+                anyhow!(MachineError::InitEventFdErr("reboot_req".to_string()))
+            })?);
+        let powerdown_expired = Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+            anyhow!(MachineError::InitEventFdErr(
+                "powerdown_expired".to_string()
+            ))
+        })?);
+        let panic_notifier = Arc::new(
+            PanicNotifier::new(vm_config.panic_action)
+                .with_context(|| anyhow!(MachineError::InitEventFdErr("panic".to_string())))?,
+        );
+        let migration = Arc::new(
+            LiveMigration::new()
+                .with_context(|| anyhow!(MachineError::InitEventFdErr("migration".to_string())))?,
+        );
+        let dump = Arc::new(
+            GuestDump::new()
+                .with_context(|| anyhow!(MachineError::InitEventFdErr("dump".to_string())))?,
+        );
+        let user_dtb = match &vm_config.boot_source.dtb {
+            Some(dtb) => Some(load_user_dtb(dtb)?),
+            None => None,
+        };
+        let panicked = Arc::new(AtomicBool::new(false));
+        QmpChannel::set_oob_handler(Arc::new(LightMachineOob {
+            vm_state: vm_state.clone(),
+            panicked: panicked.clone(),
+            migration: migration.clone(),
+        }));
 
         Ok(LightMachine {
             cpu_topo: CpuTopology::new(
@@ -190,11 +442,51 @@ impl LightMachine {
             sys_mem,
             sysbus,
             replaceable_info: MmioReplaceableInfo::new(),
+            hotplug_slots: Vec::new(),
             boot_source: Arc::new(Mutex::new(vm_config.clone().boot_source)),
+            user_dtb,
             vm_state,
             power_button,
+            reset_req,
+            watchdog_expired,
+            poweroff_req,
+            reboot_req,
+            panic_notifier,
+            panicked,
+            has_pvpanic: false,
+            balloon: None,
+            replaceable_blocks: Vec::new(),
+            gpio: None,
+            powerdown_expired,
+            powerdown_timer: Mutex::new(None),
+            sbi_console: None,
             vm_config: Arc::new(Mutex::new(vm_config.clone())),
             drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
+            block_backends: Arc::new(Mutex::new(
+                vm_config
+                    .drives
+                    .iter()
+                    .map(|(id, drive)| {
+                        let backend = BlockBackend {
+                            drive: drive.clone(),
+                            user: None,
+                        };
+                        (id.clone(), backend)
+                    })
+                    .collect(),
+            )),
+            net_backends: Arc::new(Mutex::new(BTreeMap::new())),
+            mem_backends: HashMap::new(),
+            plugged_mem: Vec::new(),
+            fwcfg_dev: None,
+            ramfb: None,
+            rtc: None,
+            gdb_stop: None,
+            irq_chip: None,
+            plugged_cpus: HashMap::new(),
+            migration,
+            dump,
+            chardevs: ChardevRegistry::default(),
         })
     }
 
@@ -210,6 +502,7 @@ impl LightMachine {
             )));
             let virtio_mmio = VirtioMmioDevice::new(&self.sys_mem, block.clone(), #[cfg(target_arch = "riscv64")] irq_chip.clone());
             rpl_devs.push(virtio_mmio);
+            self.replaceable_blocks.push(block.clone());
 
             MigrationManager::register_device_instance(
                 BlockState::descriptor(),
@@ -229,9 +522,9 @@ impl LightMachine {
             );
         }
 
-        let mut region_base = self.sysbus.min_free_base;
         let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
         for (id, dev) in rpl_devs.into_iter().enumerate() {
+            let region_base = self.sysbus.allocate_mmio(region_size, region_size)?;
             self.replaceable_info
                 .devices
                 .lock()
@@ -248,16 +541,14 @@ impl LightMachine {
                     dev,
                     &mut self.sysbus,
                     region_base,
-                    MEM_LAYOUT[LayoutEntryType::Mmio as usize].1,
+                    region_size,
                     #[cfg(target_arch = "x86_64")]
                     &self.boot_source,
                 )
                 .with_context(|| anyhow!(MicroVmError::RlzVirtioMmioErr))?,
                 &id.to_string(),
             );
-            region_base += region_size;
         }
-        self.sysbus.min_free_base = region_base;
         Ok(())
     }
 
@@ -275,12 +566,15 @@ impl LightMachine {
 
             device_info.id = id.to_string();
             device_info.used = true;
-            device_info
-                .device
-                .lock()
-                .unwrap()
-                .update_config(Some(dev_config.clone()))
-                .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?;
+            match self.replaceable_info.pending_backends.as_mut() {
+                Some(pending) => pending.push((index, dev_config.clone())),
+                None => device_info
+                    .device
+                    .lock()
+                    .unwrap()
+                    .update_config(Some(dev_config.clone()))
+                    .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?,
+            }
         }
 
         self.add_replaceable_config(id, dev_config)?;
@@ -310,6 +604,81 @@ impl LightMachine {
         Ok(())
     }
 
+    /// Configuration of block device `id` on backend `node_name`, which must be free.
+    fn block_backend_config(&self, node_name: &str, id: &str) -> Result<BlkDevConfig> {
+        let backends = self.block_backends.lock().unwrap();
+        let backend = backends
+            .get(node_name)
+            .with_context(|| format!("Block backend {} not found", node_name))?;
+        if let Some(user) = &backend.user {
+            bail!("Block backend {} is in use by {}", node_name, user);
+        }
+        Ok(BlkDevConfig {
+            id: id.to_string(),
+            path_on_host: backend.drive.path_on_host.clone(),
+            read_only: backend.drive.read_only,
+            direct: backend.drive.direct,
+            iops: backend.drive.iops,
+            aio: backend.drive.aio,
+            ..Default::default()
+        })
+    }
+
+    /// Mark block backend `node_name` as used by device `id`.
+    fn claim_block_backend(&self, node_name: &str, id: &str) {
+        if let Some(backend) = self.block_backends.lock().unwrap().get_mut(node_name) {
+            backend.user = Some(id.to_string());
+        }
+    }
+
+    /// Free the block backend used by device `id`, so that it can be deleted.
+    fn release_block_backend(&self, id: &str) {
+        for backend in self.block_backends.lock().unwrap().values_mut() {
+            if backend.user.as_deref() == Some(id) {
+                backend.user = None;
+            }
+        }
+    }
+
+    /// Configuration of net device `id` on backend `netdev`, which must be free.
+    fn net_backend_config(&self, netdev: &str, id: &str) -> Result<NetworkInterfaceConfig> {
+        let backends = self.net_backends.lock().unwrap();
+        let backend = backends
+            .get(netdev)
+            .with_context(|| format!("Netdev {} not found", netdev))?;
+        if let Some(user) = &backend.user {
+            bail!("Netdev {} is in use by {}", netdev, user);
+        }
+        // The device owns the duplicated fd, and closes it when it's removed.
+        let tap_fd = backend
+            .tap
+            .file
+            .try_clone()
+            .with_context(|| format!("Failed to duplicate tap fd of {}", netdev))?
+            .into_raw_fd();
+        Ok(NetworkInterfaceConfig {
+            id: id.to_string(),
+            tap_fds: Some(vec![tap_fd]),
+            ..Default::default()
+        })
+    }
+
+    /// Mark network backend `netdev` as used by device `id`.
+    fn claim_net_backend(&self, netdev: &str, id: &str) {
+        if let Some(backend) = self.net_backends.lock().unwrap().get_mut(netdev) {
+            backend.user = Some(id.to_string());
+        }
+    }
+
+    /// Free the network backend used by device `id`, so that it can be deleted.
+    fn release_net_backend(&self, id: &str) {
+        for backend in self.net_backends.lock().unwrap().values_mut() {
+            if backend.user.as_deref() == Some(id) {
+                backend.user = None;
+            }
+        }
+    }
+
     fn add_replaceable_device(&self, id: &str, driver: &str, slot: usize) -> Result<()> {
         // Find the configuration by id.
         let configs_lock = self.replaceable_info.configs.lock().unwrap();
@@ -319,6 +688,17 @@ impl LightMachine {
                 dev_config = Some(config.dev_config.clone());
             }
         }
+        // Device named after a block or network backend is backed by it.
+        let mut backend = None;
+        if dev_config.is_none() && driver.contains("blk") {
+            let config: Arc<dyn ConfigCheck> = Arc::new(self.block_backend_config(id, id)?);
+            dev_config = Some(config);
+            backend = Some(id);
+        } else if dev_config.is_none() && driver.contains("net") {
+            let config: Arc<dyn ConfigCheck> = Arc::new(self.net_backend_config(id, id)?);
+            dev_config = Some(config);
+            backend = Some(id);
+        }
         if dev_config.is_none() {
             bail!("Failed to find device configuration.");
         }
@@ -367,6 +747,11 @@ impl LightMachine {
                 .update_config(dev_config)
                 .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?;
         }
+        match backend {
+            Some(backend) if driver.contains("blk") => self.claim_block_backend(backend, id),
+            Some(backend) => self.claim_net_backend(backend, id),
+            None => (),
+        }
         Ok(())
     }
 
@@ -376,9 +761,6 @@ impl LightMachine {
         let mut configs_lock = self.replaceable_info.configs.lock().unwrap();
         for (index, config) in configs_lock.iter().enumerate() {
             if config.id == id {
-                if let Some(blkconf) = config.dev_config.as_any().downcast_ref::<BlkDevConfig>() {
-                    self.unregister_drive_file(&blkconf.path_on_host)?;
-                }
                 configs_lock.remove(index);
                 is_exist = true;
                 break;
@@ -388,7 +770,8 @@ impl LightMachine {
         // set the status of the device to 'unused'
         let mut replaceable_devices = self.replaceable_info.devices.lock().unwrap();
         for device_info in replaceable_devices.iter_mut() {
-            if device_info.id == id {
+            if device_info.used && device_info.id == id {
+                is_exist = true;
                 device_info.id = "".to_string();
                 device_info.used = false;
                 device_info
@@ -403,8 +786,532 @@ impl LightMachine {
         if !is_exist {
             bail!("Device {} not found", id);
         }
+        self.release_block_backend(id);
+        self.release_net_backend(id);
         Ok(id.to_string())
     }
+
+    /// Plug virtio-mmio device, whose backend is added by `blockdev-add` or `netdev_add`,
+    /// into an empty hotplug slot. The transport is mapped at once, guest binds it by
+    /// reprobing the virtio-mmio node of the slot.
+    fn plug_virtio_mmio(&mut self, args: &qmp_schema::DeviceAddArgument) -> Result<()> {
+        let id_used = self
+            .replaceable_info
+            .configs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c.id == args.id)
+            || self
+                .replaceable_info
+                .devices
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|d| d.used && d.id == args.id)
+            || self.plugged_virtio_mmio(&args.id).is_some();
+        if id_used {
+            bail!("Device {} already exists", args.id);
+        }
+        let (backend, is_blk) = match args.driver.as_str() {
+            "virtio-blk-device" => (args.drive.as_ref(), true),
+            _ => (args.netdev.as_ref(), false),
+        };
+        let backend = backend.with_context(|| format!("Backend of {} is not set", args.id))?;
+        let slot = self
+            .hotplug_slots
+            .iter()
+            .position(|slot| slot.device.is_none())
+            .with_context(|| format!("No empty hotplug slot for {}", args.id))?;
+        let irq_chip = self
+            .irq_chip
+            .clone()
+            .with_context(|| "Interrupt controller is not initialized")?;
+
+        let mut block = None;
+        let device: Arc<Mutex<dyn VirtioDevice>> = if is_blk {
+            let mut config = self.block_backend_config(backend, &args.id)?;
+            if args.serial_num.is_some() {
+                config.serial_num = args.serial_num.clone();
+            }
+            let device = Arc::new(Mutex::new(Block::new(config, self.get_drive_files())));
+            block = Some(device.clone());
+            device
+        } else {
+            let mut config = self.net_backend_config(backend, &args.id)?;
+            if args.mac.is_some() {
+                config.mac = args.mac.clone();
+            }
+            Arc::new(Mutex::new(Net::new(config)))
+        };
+
+        let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
+        let (base, irq) = (self.hotplug_slots[slot].base, self.hotplug_slots[slot].irq);
+        let transport = VirtioMmioDevice::new(&self.sys_mem, device, irq_chip)
+            .realize_in_slot(&mut self.sysbus, base, region_size, irq)
+            .with_context(|| anyhow!(MicroVmError::RlzVirtioMmioErr))?;
+
+        // The backend belongs to the device from now on.
+        if is_blk {
+            self.claim_block_backend(backend, &args.id);
+        } else {
+            self.claim_net_backend(backend, &args.id);
+        }
+        self.hotplug_slots[slot].device = Some(MmioPluggedDevice {
+            id: args.id.clone(),
+            transport,
+            block,
+        });
+        Ok(())
+    }
+
+    /// Virtio block devices in use, filled in the replaceable slots or plugged by
+    /// `device_add`.
+    fn virtio_blocks(&self) -> Vec<Arc<Mutex<Block>>> {
+        let plugged = self
+            .hotplug_slots
+            .iter()
+            .filter_map(|slot| slot.device.as_ref()?.block.clone());
+        self.replaceable_blocks
+            .iter()
+            .cloned()
+            .chain(plugged)
+            .filter(|block| !block.lock().unwrap().config().id.is_empty())
+            .collect()
+    }
+
+    /// Name of the block backend used by device `id`.
+    fn block_backend_of(&self, id: &str) -> Option<String> {
+        self.block_backends
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, backend)| backend.user.as_deref() == Some(id))
+            .map(|(node_name, _)| node_name.clone())
+    }
+
+    fn plugged_virtio_mmio(&self, id: &str) -> Option<usize> {
+        self.hotplug_slots
+            .iter()
+            .position(|slot| slot.device.as_ref().map_or(false, |device| device.id == id))
+    }
+
+    /// Unplug virtio-mmio device plugged by `device_add`. Its queues are stopped before
+    /// the transport is detached, and the slot is left empty for the next device.
+    fn unplug_virtio_mmio(&mut self, slot: usize) -> Result<()> {
+        let (id, transport) = match self.hotplug_slots[slot].device.as_ref() {
+            Some(device) => (device.id.clone(), device.transport.clone()),
+            None => return Ok(()),
+        };
+        transport
+            .lock()
+            .unwrap()
+            .reset()
+            .with_context(|| format!("Failed to stop queues of {}", id))?;
+        self.sysbus
+            .detach_device(&transport)
+            .with_context(|| format!("Failed to detach {}", id))?;
+
+        let device = self.hotplug_slots[slot].device.take().unwrap();
+        let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
+        self.sysbus
+            .reserve_mmio(self.hotplug_slots[slot].base, region_size)?;
+        if let Err(e) = device.transport.lock().unwrap().unrealize() {
+            warn!("Failed to unrealize {}: {:?}", device.id, e);
+        }
+        self.release_block_backend(&device.id);
+        self.release_net_backend(&device.id);
+        Ok(())
+    }
+
+    fn add_block_backend(&self, args: &qmp_schema::BlockDevAddArgument) -> Result<()> {
+        if args.file.driver != "file" {
+            bail!(
+                "Unsupported file driver {} of {}",
+                args.file.driver,
+                args.node_name
+            );
+        }
+        if let Some(format) = args.driver.as_ref().filter(|format| *format != "raw") {
+            bail!("Unsupported format {} of {}", format, args.node_name);
+        }
+        let mut backends = self.block_backends.lock().unwrap();
+        if backends.contains_key(&args.node_name) {
+            bail!("Block backend {} already exists", args.node_name);
+        }
+
+        let direct = args
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.direct)
+            .unwrap_or(true);
+        let drive = DriveConfig {
+            id: args.node_name.clone(),
+            path_on_host: args.file.filename.clone(),
+            read_only: args.read_only.unwrap_or(false),
+            direct,
+            share_rw: false,
+            iops: args.iops,
+            // TODO Add aio option by qmp, now we set it based on "direct".
+            aio: if direct {
+                AioEngine::Native
+            } else {
+                AioEngine::Off
+            },
+        };
+        drive.check()?;
+        if let Err(e) = drive.check_path().and_then(|_| {
+            self.register_drive_file(
+                &drive.path_on_host,
+                drive.read_only,
+                drive.direct,
+                drive.share_rw,
+            )
+        }) {
+            // Paths given after the privilege drop are resolved inside the new root.
+            match chroot_dir() {
+                Some(root) => bail!("{:#}, paths are resolved inside chroot {}", e, root),
+                None => return Err(e),
+            }
+        }
+        let backend = BlockBackend { drive, user: None };
+        backends.insert(args.node_name.clone(), backend);
+        Ok(())
+    }
+
+    fn del_block_backend(&self, node_name: &str) -> Result<()> {
+        let mut backends = self.block_backends.lock().unwrap();
+        let backend = backends
+            .get(node_name)
+            .with_context(|| format!("Block backend {} not found", node_name))?;
+        if let Some(user) = &backend.user {
+            bail!("Block backend {} is in use by {}", node_name, user);
+        }
+        self.unregister_drive_file(&backend.drive.path_on_host)?;
+        backends.remove(node_name);
+        Ok(())
+    }
+
+    /// Resize the image of device `device`, or of the device using backend `node_name`.
+    fn resize_block(
+        &self,
+        device: Option<String>,
+        node_name: Option<String>,
+        size: u64,
+        force: bool,
+    ) -> Result<()> {
+        let device = match (device, node_name) {
+            (Some(device), _) => device,
+            (None, Some(node_name)) => self
+                .block_backends
+                .lock()
+                .unwrap()
+                .get(&node_name)
+                .with_context(|| format!("Block backend {} not found", node_name))?
+                .user
+                .clone()
+                .with_context(|| format!("Block backend {} is not used by a device", node_name))?,
+            (None, None) => bail!("Neither device nor node-name is given"),
+        };
+        let block = self
+            .virtio_blocks()
+            .into_iter()
+            .find(|block| block.lock().unwrap().config().id == device)
+            .with_context(|| format!("Block device {} not found", device))?;
+        let mut locked_block = block.lock().unwrap();
+        locked_block.resize(size, force)
+    }
+
+    fn add_net_backend(&self, args: &qmp_schema::NetDevAddArgument) -> Result<()> {
+        let mut backends = self.net_backends.lock().unwrap();
+        if backends.contains_key(&args.id) {
+            bail!("Netdev {} already exists", args.id);
+        }
+        if let Some(vhost) = args.vhost.as_ref().filter(|vhost| *vhost != "off") {
+            bail!("Unsupported vhost {} of {}", vhost, args.id);
+        }
+        if args.queues.map_or(false, |queues| queues > 1) {
+            bail!("Netdev {} supports only one queue pair", args.id);
+        }
+
+        let net_type = args.net_type.clone().unwrap_or_else(|| "tap".to_string());
+        let (name, tap) = match net_type.as_str() {
+            "tap" => {
+                for script in [&args.script, &args.downscript].into_iter().flatten() {
+                    if script != "no" {
+                        bail!("Unsupported script {} of {}", script, args.id);
+                    }
+                }
+                let ifname = args
+                    .if_name
+                    .clone()
+                    .with_context(|| format!("Netdev {} of type tap requires ifname", args.id))?;
+                let tap = Tap::new(Some(&ifname), None, 1)
+                    .with_context(|| format!("Failed to open tap {}", ifname))?;
+                (ifname, tap)
+            }
+            "fd" => {
+                let fdname = args
+                    .fds
+                    .clone()
+                    .with_context(|| format!("Netdev {} of type fd requires fds", args.id))?;
+                let fd = match parse_fdset_path(&fdname)? {
+                    // The tap owns a duplicated fd, the fd set keeps the original one.
+                    Some(fdset_id) => QmpChannel::dup_fdset_fd(fdset_id, libc::O_RDWR)?,
+                    // The tap owns the fd from now on, it can't be used twice.
+                    None => QmpChannel::take_fd(&fdname)
+                        .with_context(|| format!("Fd {} is not passed by getfd", fdname))?,
+                };
+                let tap = Tap::new(None, Some(fd), 1)
+                    .with_context(|| format!("Fd {} is not a tap", fdname))?;
+                (fdname, tap)
+            }
+            _ => bail!("Unsupported netdev type {}", net_type),
+        };
+        let backend = NetBackend {
+            net_type,
+            name,
+            tap,
+            user: None,
+        };
+        backends.insert(args.id.clone(), backend);
+        Ok(())
+    }
+
+    fn del_net_backend(&self, id: &str) -> Result<()> {
+        let mut backends = self.net_backends.lock().unwrap();
+        let backend = backends
+            .get(id)
+            .with_context(|| format!("Netdev {} not found", id))?;
+        if let Some(user) = &backend.user {
+            bail!("Netdev {} is in use by {}", id, user);
+        }
+        // Tap is closed when the backend is dropped.
+        backends.remove(id);
+        Ok(())
+    }
+
+    fn add_memory_backend(&mut self, args: &qmp_schema::ObjectAddArgument) -> Result<()> {
+        let memfd = match args.qom_type.as_str() {
+            "memory-backend-ram" | "memory-backend-file" => false,
+            "memory-backend-memfd" => true,
+            _ => bail!("Unsupported object type {}", args.qom_type),
+        };
+        let is_file = args.qom_type == "memory-backend-file";
+        if is_file != args.mem_path.is_some() {
+            bail!("Property mem-path is required by and only by memory-backend-file");
+        }
+        let vm_config = self.vm_config.lock().unwrap();
+        let boot_backend = vm_config.machine_config.mem_config.mem_backend.as_ref();
+        if self.mem_backends.contains_key(&args.id)
+            || boot_backend.map_or(false, |b| b.id == args.id)
+        {
+            bail!("Object {} already exists", args.id);
+        }
+        let dump_guest_core = vm_config.machine_config.mem_config.dump_guest_core;
+        drop(vm_config);
+        let size = args
+            .size
+            .with_context(|| format!("Size of memory backend {} is not set", args.id))?;
+        if size == 0 || size % MEM_HOTPLUG_ALIGN != 0 {
+            bail!(
+                "Size 0x{:X} of memory backend {} is not a multiple of 0x{:X}",
+                size,
+                args.id,
+                MEM_HOTPLUG_ALIGN
+            );
+        }
+        let mut host_nodes = args.host_nodes.clone().unwrap_or_default();
+        host_nodes.sort_unstable();
+        host_nodes.dedup();
+        let backend = MemoryBackend {
+            size,
+            share: args.share.unwrap_or(memfd),
+            memfd,
+            seal: args.seal.unwrap_or(true),
+            mem_path: args.mem_path.clone(),
+            merge: args.merge.unwrap_or(false),
+            dump: args.dump.unwrap_or(dump_guest_core),
+            prealloc: args.prealloc.unwrap_or(false),
+            host_nodes,
+        };
+        self.mem_backends.insert(args.id.clone(), backend);
+        Ok(())
+    }
+
+    /// Map the memory backend `memdev` into guest at the next free address above
+    /// the existing RAM. The region is described by the memory node of device tree,
+    /// which is generated at boot, so the running guest has to online it by itself.
+    fn plug_memory(&mut self, id: &str, memdev: &str) -> Result<()> {
+        if self.plugged_mem.iter().any(|mem| mem.id == id) {
+            bail!("Device {} already exists", id);
+        }
+        if self.plugged_mem.iter().any(|mem| mem.memdev == memdev) {
+            bail!("Memory backend {} is in use", memdev);
+        }
+        let backend = self
+            .mem_backends
+            .get(memdev)
+            .with_context(|| format!("Memory backend {} not found", memdev))?
+            .clone();
+
+        let mem_layout = MEM_LAYOUT[LayoutEntryType::Mem as usize];
+        let mut hotplug_end = mem_layout.0 + mem_layout.1;
+        let mem_config = self.vm_config.lock().unwrap().machine_config.mem_config.clone();
+        if let Some(hotplug) = &mem_config.hotplug {
+            if self.plugged_mem.len() as u64 >= hotplug.slots {
+                bail!(
+                    "No free slot to plug memory {}, all {} slots are used",
+                    id,
+                    hotplug.slots
+                );
+            }
+            // Hotplugged memory is put in the space left for maxmem above the boot
+            // memory.
+            let hotplug_base = GuestAddress(mem_layout.0 + mem_config.mem_size)
+                .align_up(MEM_HOTPLUG_ALIGN)
+                .map_or(hotplug_end, |addr| addr.raw_value());
+            let hotplug_size = hotplug.max_size - mem_config.mem_size;
+            hotplug_end = hotplug_end.min(hotplug_base + hotplug_size);
+        }
+        let ram_end = self.sys_mem.memory_end_address().raw_value();
+        let base = GuestAddress(ram_end.max(mem_layout.0))
+            .align_up(MEM_HOTPLUG_ALIGN)
+            .map(|addr| addr.raw_value())
+            .filter(|base| {
+                base.checked_add(backend.size)
+                    .map_or(false, |end| end <= hotplug_end)
+            })
+            .with_context(|| format!("No space to plug memory {} of 0x{:X}", id, backend.size))?;
+        if self.sysbus.mmio_overlaps(base, backend.size) {
+            bail!("Memory {} at 0x{:X} overlaps with MMIO", id, base);
+        }
+
+        let file_back = if let Some(path) = &backend.mem_path {
+            Some(FileBackend::new_mem(path, backend.size)?)
+        } else if backend.memfd {
+            Some(FileBackend::new_memfd(memdev, backend.size, backend.seal)?)
+        } else {
+            None
+        };
+        let mmap = HostMemMapping::new(
+            GuestAddress(base),
+            None,
+            backend.size,
+            file_back,
+            backend.dump,
+            backend.share,
+            false,
+        )?;
+        if backend.merge {
+            mmap.set_mergeable()?;
+        }
+        mmap.bind_host_nodes(&backend.host_nodes)?;
+        if backend.prealloc {
+            let threads = self
+                .vm_config
+                .lock()
+                .unwrap()
+                .machine_config
+                .mem_config
+                .prealloc_threads
+                .unwrap_or(self.cpu_topo.nrcpus);
+            mmap.prealloc(threads)
+                .with_context(|| format!("Failed to prealloc memory {}", id))?;
+        }
+        self.sys_mem
+            .root()
+            .add_subregion(Region::init_ram_region(Arc::new(mmap)), base)
+            .with_context(|| anyhow!(MachineError::RegMemRegionErr(base, backend.size)))?;
+        self.plugged_mem.push(PluggedMemory {
+            id: id.to_string(),
+            memdev: memdev.to_string(),
+            base,
+            size: backend.size,
+        });
+        Ok(())
+    }
+
+    /// Pin the thread of `cpu` to the host cpu given by `-cpu-affinity`, if any.
+    fn set_vcpu_affinity(&self, cpu: &CPU) -> Result<()> {
+        let locked_config = self.vm_config.lock().unwrap();
+        if let Some(host_cpu) = locked_config.machine_config.cpu_affinity.vcpu(cpu.id()) {
+            if !host_cpu_exists(host_cpu) {
+                bail!("Host cpu {} of vcpu{} doesn't exist", host_cpu, cpu.id());
+            }
+            cpu.set_affinity(host_cpu);
+        }
+        Ok(())
+    }
+
+    /// Hot-add the vcpu at `socket-id`, `core-id` and `thread-id` given by `args`.
+    /// Its hart is described in device tree since boot, and stays stopped until
+    /// guest starts it through SBI HSM, which is handled by kvm.
+    fn plug_cpu(&mut self, args: &qmp_schema::DeviceAddArgument) -> Result<()> {
+        if self.plugged_cpus.contains_key(&args.id) {
+            bail!("Device {} already exists", args.id);
+        }
+        let socket_id = args.socket_id.unwrap_or(0);
+        let core_id = args.core_id.unwrap_or(0);
+        let thread_id = args.thread_id.unwrap_or(0);
+        let vcpu_id = self
+            .cpu_topo
+            .find_offline_vcpu(socket_id, core_id, thread_id)
+            .with_context(|| {
+                format!(
+                    "No hotpluggable cpu at socket-id {} core-id {} thread-id {} within maxcpus {}",
+                    socket_id, core_id, thread_id, self.cpu_topo.max_cpus
+                )
+            })?;
+
+        let vcpu_fd = Arc::new(
+            KVM_FDS
+                .load()
+                .vm_fd
+                .as_ref()
+                .unwrap()
+                .create_vcpu(vcpu_id as u64)?,
+        );
+        let boot_cpu = self.cpus[0].clone();
+        let vm = boot_cpu
+            .vm()
+            .upgrade()
+            .with_context(|| "Vm of vcpu0 has been destroyed")?;
+        let cpu = Arc::new(CPU::new(
+            vcpu_fd.clone(),
+            vcpu_id,
+            Arc::new(Mutex::new(ArchCPU::new(u32::from(vcpu_id)))),
+            vm,
+        ));
+        // Secondary harts take boot arguments from SBI HSM, only hart id is set.
+        let features = CPUFeatures::from(&self.vm_config.lock().unwrap().machine_config.cpu_config);
+        cpu.realize(&CPUBootConfig::default(), &CPUTopology::new(), &features)
+            .with_context(|| format!("Failed to realize vcpu{}", vcpu_id))?;
+        self.set_vcpu_affinity(&cpu)?;
+        if let Some(irq_chip) = &self.irq_chip {
+            irq_chip
+                .lock()
+                .unwrap()
+                .set_hart_vcpu(u32::from(vcpu_id), vcpu_fd)?;
+        }
+
+        let paused = *self.vm_state.0.lock().unwrap() != KvmVmState::Running;
+        if paused {
+            // Guest time is restored from the saved one on resume.
+            let timer_regs = boot_cpu.arch().lock().unwrap().timer_regs();
+            cpu.arch().lock().unwrap().set_saved_timer(timer_regs);
+        }
+        let thread_barrier = Arc::new(Barrier::new(2));
+        CPU::start(cpu.clone(), thread_barrier.clone(), paused)
+            .with_context(|| format!("Failed to run vcpu{}", vcpu_id))?;
+        thread_barrier.wait();
+
+        self.cpu_topo.set_mask(vcpu_id as usize, 1);
+        let pos = self.cpus.partition_point(|cpu| cpu.id() < vcpu_id);
+        self.cpus.insert(pos, cpu);
+        self.plugged_cpus.insert(args.id.clone(), vcpu_id);
+        Ok(())
+    }
 }
 
 impl MachineOps for LightMachine {
@@ -459,6 +1366,7 @@ impl MachineOps for LightMachine {
             kernel: boot_source.kernel_file.clone(),
             initrd,
             mem_start: MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
+            bios: boot_source.bios.clone(),
         };
         let layout = load_linux(&bootloader_config, &self.sys_mem, fwcfg)
             .with_context(|| anyhow!(MachineError::LoadKernErr))?;
@@ -470,6 +1378,7 @@ impl MachineOps for LightMachine {
         Ok(CPUBootConfig {
             fdt_addr: layout.dtb_start,
             boot_pc: layout.boot_pc,
+            fw_dynamic_addr: layout.fw_dynamic_start,
         })
     }
 
@@ -477,16 +1386,15 @@ impl MachineOps for LightMachine {
         &mut self,
         dev: VirtioMmioDevice,
     ) -> MachineResult<Arc<Mutex<VirtioMmioDevice>>> {
-        let region_base = self.sysbus.min_free_base;
         let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
-        let realized_virtio_mmio_device = VirtioMmioDevice::realize(
-            dev,
-            &mut self.sysbus,
-            region_base,
-            region_size,
-        )
-        .with_context(|| anyhow!(MicroVmError::RlzVirtioMmioErr))?;
-        self.sysbus.min_free_base += region_size;
+        let region_base = self.sysbus.allocate_mmio(region_size, region_size)?;
+        let realized = VirtioMmioDevice::realize(dev, &mut self.sysbus, region_base, region_size);
+        if realized.is_err() {
+            // Free the range allocated for the device, so that it can be used later.
+            self.sysbus.release_mmio(region_base);
+        }
+        let realized_virtio_mmio_device =
+            realized.with_context(|| anyhow!(MicroVmError::RlzVirtioMmioErr))?;
         Ok(realized_virtio_mmio_device)
     }
 
@@ -510,64 +1418,298 @@ impl MachineOps for LightMachine {
         (MigrateMode::Unknown, String::new())
     }
 
+    fn listen_incoming_migration(&self, path: &str) -> Result<()> {
+        self.listen_migration(path)
+    }
+
     fn get_sys_bus(&mut self) -> &SysBus {
         &self.sysbus
     }
 
-    fn add_serial_device(
-        &mut self,
-        config: &SerialConfig,
-        #[cfg(target_arch = "riscv64")]
-        irq_chip: Arc<Mutex<InterruptController>>,
-    ) -> MachineResult<()> {
-        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
-        let region_base: u64 = MEM_LAYOUT[LayoutEntryType::Uart as usize].0;
-        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
-        let region_size: u64 = MEM_LAYOUT[LayoutEntryType::Uart as usize].1;
+    fn add_rtc_device(&mut self) -> MachineResult<()> {
+        let rtc_config = self.vm_config.lock().unwrap().rtc;
+        let rtc = GoldfishRtc::with_config(&rtc_config)
+            .with_context(|| "Failed to create rtc device.")?;
+        let rtc = rtc
+            .realize(
+                &mut self.sysbus,
+                MEM_LAYOUT[LayoutEntryType::Rtc as usize].0,
+                MEM_LAYOUT[LayoutEntryType::Rtc as usize].1,
+            )
+            .with_context(|| "Failed to realize rtc device.")?;
+        self.rtc = Some(rtc);
+        Ok(())
+    }
 
-        let serial = Serial::new(config.clone(), #[cfg(target_arch = "riscv64")] irq_chip.clone());
-        serial
+    fn add_watchdog_device(&mut self) -> MachineResult<()> {
+        let watchdog = DwWdt::new(self.watchdog_expired.clone());
+        watchdog
             .realize(
                 &mut self.sysbus,
-                region_base,
-                region_size,
-                &self.boot_source,
+                MEM_LAYOUT[LayoutEntryType::Watchdog as usize].0,
+                MEM_LAYOUT[LayoutEntryType::Watchdog as usize].1,
             )
-            .with_context(|| "Failed to realize serial device.")?;
+            .with_context(|| "Failed to realize watchdog device.")?;
         Ok(())
     }
 
-    fn add_virtio_mmio_net(
-        &mut self,
-        vm_config: &mut VmConfig,
-        cfg_args: &str,
-        #[cfg(target_arch = "riscv64")]
-        irq_chip: Arc<Mutex<InterruptController>>,
-    ) -> MachineResult<()> {
-        let device_cfg = parse_net(vm_config, cfg_args)?;
-        if device_cfg.vhost_type.is_some() {
-            let net = Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &self.sys_mem)));
-            let device = VirtioMmioDevice::new(&self.sys_mem, net, #[cfg(target_arch = "riscv64")] irq_chip.clone());
-            self.realize_virtio_mmio_device(device)?;
-        } else {
-            let index = MMIO_REPLACEABLE_BLK_NR + self.replaceable_info.net_count;
-            if index >= MMIO_REPLACEABLE_BLK_NR + MMIO_REPLACEABLE_NET_NR {
-                bail!(
-                    "A maximum of {} net replaceable devices are supported.",
-                    MMIO_REPLACEABLE_NET_NR
-                );
-            }
+    fn add_sifive_test_device(&mut self) -> MachineResult<()> {
+        let sifive_test = SifiveTest::new(self.poweroff_req.clone(), self.reboot_req.clone());
+        sifive_test
+            .realize(
+                &mut self.sysbus,
+                MEM_LAYOUT[LayoutEntryType::SifiveTest as usize].0,
+                MEM_LAYOUT[LayoutEntryType::SifiveTest as usize].1,
+            )
+            .with_context(|| "Failed to realize sifive test device.")?;
+        Ok(())
+    }
+
+    fn add_gpio_device(&mut self) -> MachineResult<()> {
+        let gpio = SifiveGpio::new()
+            .realize(
+                &mut self.sysbus,
+                MEM_LAYOUT[LayoutEntryType::Gpio as usize].0,
+                MEM_LAYOUT[LayoutEntryType::Gpio as usize].1,
+            )
+            .with_context(|| "Failed to realize gpio device.")?;
+        self.gpio = Some(gpio);
+        Ok(())
+    }
+
+    fn add_fwcfg_device(&mut self, nr_cpus: u8) -> MachineResult<()> {
+        let mut fwcfg = FwCfgMem::new(self.sys_mem.clone());
+        fwcfg.add_data_entry(FwCfgEntryType::NbCpus, (nr_cpus as u16).as_bytes().to_vec())?;
+
+        let boot_source = self.boot_source.lock().unwrap();
+        let cmdline = boot_source.kernel_cmdline.to_string();
+        fwcfg.add_data_entry(
+            FwCfgEntryType::CmdlineSize,
+            ((cmdline.len() + 1) as u32).as_bytes().to_vec(),
+        )?;
+        fwcfg.add_string_entry(FwCfgEntryType::CmdlineData, cmdline.as_str())?;
+        if let Some(kernel) = &boot_source.kernel_file {
+            let kernel_data = std::fs::read(kernel)
+                .with_context(|| format!("Failed to read kernel {:?}", kernel))?;
+            fwcfg.add_data_entry(
+                FwCfgEntryType::KernelSize,
+                (kernel_data.len() as u32).as_bytes().to_vec(),
+            )?;
+            fwcfg.add_data_entry(FwCfgEntryType::KernelData, kernel_data)?;
+        }
+        if let Some(initrd) = &boot_source.initrd {
+            let initrd_data = std::fs::read(&initrd.initrd_file)
+                .with_context(|| format!("Failed to read initrd {:?}", initrd.initrd_file))?;
+            fwcfg.add_data_entry(
+                FwCfgEntryType::InitrdSize,
+                (initrd_data.len() as u32).as_bytes().to_vec(),
+            )?;
+            fwcfg.add_data_entry(FwCfgEntryType::InitrdData, initrd_data)?;
+        }
+        drop(boot_source);
+
+        let fwcfg_dev = fwcfg
+            .realize(
+                &mut self.sysbus,
+                MEM_LAYOUT[LayoutEntryType::FwCfg as usize].0,
+                MEM_LAYOUT[LayoutEntryType::FwCfg as usize].1,
+            )
+            .with_context(|| "Failed to realize fwcfg device.")?;
+        self.fwcfg_dev = Some(fwcfg_dev);
+        Ok(())
+    }
+
+    fn add_ramfb(&mut self, cfg_args: &str) -> MachineResult<()> {
+        let mut cmd_parser = CmdParser::new("ramfb");
+        cmd_parser.push("").push("id");
+        cmd_parser.parse(cfg_args)?;
+        if self.ramfb.is_some() {
+            bail!("Only one ramfb device is supported");
+        }
+        let fwcfg_dev = self
+            .fwcfg_dev
+            .as_ref()
+            .with_context(|| "Ramfb device needs fwcfg device")?;
+
+        let ramfb = Ramfb::new(self.sys_mem.clone());
+        RamfbState::setup(&ramfb.ramfb_state, fwcfg_dev)?;
+        let ramfb = ramfb
+            .realize(&mut self.sysbus)
+            .with_context(|| "Failed to realize ramfb device.")?;
+        self.ramfb = Some(ramfb);
+        Ok(())
+    }
+
+    fn add_sbi_console(&mut self, config: &SerialConfig) -> MachineResult<()> {
+        let console = SbiDebugConsole::new_dedicated(config.chardev.clone(), self.sys_mem.clone())
+            .with_context(|| "Failed to realize sbi console.")?;
+        self.register_chardev(console.chardev(), "sbi-console")?;
+        self.sbi_console = Some(console);
+        Ok(())
+    }
+
+    fn register_chardev(
+        &mut self,
+        chardev: Arc<Mutex<Chardev>>,
+        frontend: &str,
+    ) -> MachineResult<()> {
+        self.chardevs.register(chardev, Some(frontend))?;
+        Ok(())
+    }
+
+    fn add_pvpanic(&mut self, cfg_args: &str) -> MachineResult<()> {
+        let mut cmd_parser = CmdParser::new("pvpanic");
+        cmd_parser.push("").push("id");
+        cmd_parser.parse(cfg_args)?;
+        if self.has_pvpanic {
+            bail!("Only one pvpanic device is supported");
+        }
+
+        let pvpanic = PvPanic::new(self.panic_notifier.clone());
+        pvpanic
+            .realize(
+                &mut self.sysbus,
+                MEM_LAYOUT[LayoutEntryType::PvPanic as usize].0,
+                MEM_LAYOUT[LayoutEntryType::PvPanic as usize].1,
+            )
+            .with_context(|| "Failed to realize pvpanic device.")?;
+        self.has_pvpanic = true;
+        Ok(())
+    }
+
+    fn add_pflash_device(&mut self, configs: &[PFlashConfig]) -> MachineResult<()> {
+        let (flash_base, flash_size) = MEM_LAYOUT[LayoutEntryType::Flash as usize];
+        let bank_size = flash_size / FLASH_BANK_NR;
+        for config in configs {
+            let file = self.fetch_drive_file(&config.path_on_host)?;
+            let region_base = flash_base + config.unit as u64 * bank_size;
+            let pflash = PFlash::new(file, config.read_only, region_base, bank_size)
+                .with_context(|| format!("Failed to create pflash unit {}", config.unit))?;
+            pflash
+                .realize(&mut self.sysbus)
+                .with_context(|| format!("Failed to realize pflash unit {}", config.unit))?;
+        }
+        Ok(())
+    }
+
+    fn add_serial_device(
+        &mut self,
+        config: &SerialConfig,
+        #[cfg(target_arch = "riscv64")]
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        let region_base: u64 = MEM_LAYOUT[LayoutEntryType::Uart as usize].0;
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        let region_size: u64 = MEM_LAYOUT[LayoutEntryType::Uart as usize].1;
+
+        let serial = Serial::new(config.clone(), #[cfg(target_arch = "riscv64")] irq_chip.clone());
+        self.register_chardev(serial.chardev(), "serial")?;
+        self.sbi_console = Some(SbiDebugConsole::new_shared(
+            serial.chardev(),
+            self.sys_mem.clone(),
+        ));
+        serial
+            .realize(
+                &mut self.sysbus,
+                region_base,
+                region_size,
+                &self.boot_source,
+            )
+            .with_context(|| "Failed to realize serial device.")?;
+        Ok(())
+    }
+
+    fn add_virtio_mmio_net(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        #[cfg(target_arch = "riscv64")]
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        let device_cfg = parse_net(vm_config, cfg_args)?;
+        if device_cfg.vhost_type.is_some() {
+            let net = Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &self.sys_mem)));
+            let device = VirtioMmioDevice::new(&self.sys_mem, net, #[cfg(target_arch = "riscv64")] irq_chip.clone());
+            self.realize_virtio_mmio_device(device)?;
+        } else {
+            let index = MMIO_REPLACEABLE_BLK_NR + self.replaceable_info.net_count;
+            if index >= MMIO_REPLACEABLE_BLK_NR + MMIO_REPLACEABLE_NET_NR {
+                bail!(
+                    "A maximum of {} net replaceable devices are supported.",
+                    MMIO_REPLACEABLE_NET_NR
+                );
+            }
             self.fill_replaceable_device(&device_cfg.id, Arc::new(device_cfg.clone()), index)?;
             self.replaceable_info.net_count += 1;
         }
         Ok(())
     }
 
+    fn add_virtio_balloon(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        #[cfg(target_arch = "riscv64")] irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        let device_cfg = parse_balloon(cfg_args)?;
+        if self.balloon.is_some() {
+            bail!("Only one virtio balloon device is supported");
+        }
+
+        let ram_size = vm_config.machine_config.mem_config.mem_size;
+        let balloon = Arc::new(Mutex::new(Balloon::new(device_cfg.clone(), ram_size)));
+        let device = VirtioMmioDevice::new(
+            &self.sys_mem,
+            balloon.clone(),
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        );
+        MigrationManager::register_device_instance(
+            VirtioMmioState::descriptor(),
+            self.realize_virtio_mmio_device(device)?,
+            &device_cfg.id,
+        );
+        MigrationManager::register_device_instance(
+            VirtioBalloonState::descriptor(),
+            balloon.clone(),
+            &device_cfg.id,
+        );
+        self.balloon = Some(balloon);
+        Ok(())
+    }
+
+    fn add_virtio_9p(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        #[cfg(target_arch = "riscv64")] irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        let device_cfg = parse_virtio_9p(vm_config, cfg_args)?;
+        let p9 = Arc::new(Mutex::new(Virtio9p::new(device_cfg.clone())));
+        let device = VirtioMmioDevice::new(
+            &self.sys_mem,
+            p9,
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        );
+        MigrationManager::register_device_instance(
+            VirtioMmioState::descriptor(),
+            self.realize_virtio_mmio_device(device)?,
+            &device_cfg.id,
+        );
+        Ok(())
+    }
+
     fn add_virtio_mmio_block(
         &mut self,
         vm_config: &mut VmConfig,
         cfg_args: &str,
     ) -> MachineResult<()> {
+        let mut cmd_parser = CmdParser::new("virtio-blk");
+        cmd_parser.push("drive");
+        cmd_parser.get_parameters(cfg_args)?;
+        let drive = cmd_parser.get_value::<String>("drive")?;
         let device_cfg = parse_blk(vm_config, cfg_args, None)?;
         if self.replaceable_info.block_count >= MMIO_REPLACEABLE_BLK_NR {
             bail!(
@@ -578,6 +1720,9 @@ impl MachineOps for LightMachine {
         let index = self.replaceable_info.block_count;
         self.fill_replaceable_device(&device_cfg.id, Arc::new(device_cfg.clone()), index)?;
         self.replaceable_info.block_count += 1;
+        if let Some(drive) = drive {
+            self.claim_block_backend(&drive, &device_cfg.id);
+        }
         Ok(())
     }
 
@@ -590,6 +1735,8 @@ impl MachineOps for LightMachine {
 
 
     fn realize(vm: &Arc<Mutex<Self>>, vm_config: &mut VmConfig) -> MachineResult<()> {
+        // Device types traced by `-trace sysbus:<type>` are only known by sysbus.
+        check_mmio_trace_events()?;
         let mut locked_vm = vm.lock().unwrap();
 
         // 创建并注册PcieMem设备
@@ -598,7 +1745,7 @@ impl MachineOps for LightMachine {
         )));
         locked_vm.sysbus.attach_device(
             &pcie_ecam,
-            MEM_LAYOUT[LayoutEntryType::PcieEcam as usize].0,
+            Some(MEM_LAYOUT[LayoutEntryType::PcieEcam as usize].0),
             MEM_LAYOUT[LayoutEntryType::PcieEcam as usize].1,
         )?;
 
@@ -607,7 +1754,7 @@ impl MachineOps for LightMachine {
         )));
         locked_vm.sysbus.attach_device(
             &pcie_mmio,
-            MEM_LAYOUT[LayoutEntryType::PcieMmio as usize].0,
+            Some(MEM_LAYOUT[LayoutEntryType::PcieMmio as usize].0),
             MEM_LAYOUT[LayoutEntryType::PcieMmio as usize].1,
         )?;
 
@@ -615,39 +1762,36 @@ impl MachineOps for LightMachine {
         trace_sysbus(&locked_vm.sysbus);
         trace_vm_state(&locked_vm.vm_state);
 
-        locked_vm.init_memory(
-            &vm_config.machine_config.mem_config,
-            &locked_vm.sys_mem,
-            vm_config.machine_config.nr_cpus,
-        )?;
+        let realize_threads = vm_config.machine_config.realize_threads as usize;
+        let mem_config = vm_config.machine_config.mem_config.clone();
+        let nr_cpus = vm_config.machine_config.nr_cpus;
+        let vcpu_fds = if realize_threads > 1 {
+            // Guest RAM is mapped, and maybe preallocated, while vcpus and devices are
+            // created. SysBus devices are still attached on this thread one by one, so
+            // their addresses are the same as serial realize.
+            let ram_ranges = locked_vm.ram_ranges_to_map(mem_config.mem_size);
+            locked_vm.register_memory_listener(&locked_vm.sys_mem)?;
+            locked_vm.replaceable_info.pending_backends = Some(Vec::new());
+            let (mem_mappings, vcpu_fds) = thread::scope(|scope| {
+                let mapping = scope.spawn(|| map_guest_ram(&ram_ranges, &mem_config, nr_cpus));
+                let vcpu_fds = locked_vm.create_vcpus_and_devices(vm_config, realize_threads - 1);
+                (mapping.join().unwrap(), vcpu_fds)
+            });
+            let vcpu_fds = vcpu_fds?;
+            record_boot_milestone(BootMilestone::DevicesRealized);
+            locked_vm.add_guest_ram(&locked_vm.sys_mem, &mem_mappings?)?;
+            record_boot_milestone(BootMilestone::MemoryMapped);
+            vcpu_fds
+        } else {
+            locked_vm.init_memory(&mem_config, &locked_vm.sys_mem, nr_cpus)?;
+            record_boot_milestone(BootMilestone::MemoryMapped);
+            let vcpu_fds = locked_vm.create_vcpus_and_devices(vm_config, 1)?;
+            record_boot_milestone(BootMilestone::DevicesRealized);
+            vcpu_fds
+        };
 
         let migrate_info = locked_vm.get_migrate_info();
 
-        let mut vcpu_fds = vec![];
-        for vcpu_id in 0..vm_config.machine_config.nr_cpus {
-            vcpu_fds.push(Arc::new(
-                KVM_FDS
-                    .load()
-                    .vm_fd
-                    .as_ref()
-                    .unwrap()
-                    .create_vcpu(vcpu_id as u64)?,
-            ));
-        }
-
-
-        #[cfg(target_arch = "riscv64")]
-        let irq_chip = locked_vm.init_interrupt_controller(
-            vcpu_fds.clone(),
-            u32::from(vm_config.machine_config.nr_cpus),
-        )?;
-
-        locked_vm
-            .create_replaceable_devices(#[cfg(target_arch = "riscv64")] irq_chip.clone())
-            .with_context(|| "Failed to create replaceable devices.")?;
-        locked_vm.add_devices(vm_config, #[cfg(target_arch = "riscv64")] irq_chip.clone())?;
-        trace_replaceable_info(&locked_vm.replaceable_info);
-
         let boot_config = Some(locked_vm.load_boot_source(None)?);
         // if migrate_info.0 == MigrateMode::Unknown {
         //     Some(locked_vm.load_boot_source(None)?)
@@ -676,34 +1820,565 @@ impl MachineOps for LightMachine {
             &topology,
             &vcpu_fds,
             &boot_config,
+            &CPUFeatures::from(&vm_config.machine_config.cpu_config),
         )?);
+        for cpu in locked_vm.cpus.iter() {
+            locked_vm.set_vcpu_affinity(cpu)?;
+        }
+        record_boot_milestone(BootMilestone::VcpusCreated);
+        #[cfg(target_arch = "riscv64")]
+        locked_vm
+            .add_clint_device()
+            .with_context(|| "Failed to add clint device.")?;
 
         if let Some(boot_cfg) = boot_config {
-            let mut fdt_helper = FdtBuilder::new();
-            locked_vm
-                .generate_fdt_node(&mut fdt_helper)
-                .with_context(|| anyhow!(MachineError::GenFdtErr))?;
-            let fdt_vec = fdt_helper.finish()?;
-            locked_vm
-                .sys_mem
-                .write(
-                    &mut fdt_vec.as_slice(),
-                    GuestAddress(boot_cfg.fdt_addr as u64),
-                    fdt_vec.len() as u64,
-                )
-                .with_context(|| {
-                    anyhow!(MachineError::WrtFdtErr(boot_cfg.fdt_addr, fdt_vec.len()))
-                })?;
+            locked_vm.load_fdt(boot_cfg.fdt_addr)?;
         }
         locked_vm
             .register_power_event(locked_vm.power_button.clone())
             .with_context(|| anyhow!(MachineError::InitEventFdErr("power_button".to_string())))?;
+        locked_vm
+            .register_reset_event(vm.clone())
+            .with_context(|| anyhow!(MachineError::InitEventFdErr("reset_req".to_string())))?;
+        locked_vm
+            .register_watchdog_event(vm.clone())
+            .with_context(|| {
+                anyhow!(MachineError::InitEventFdErr("watchdog_expired".to_string()))
+            })?;
+        locked_vm
+            .register_guest_power_events(vm.clone())
+            .with_context(|| {
+                anyhow!(MachineError::InitEventFdErr(
+                    "poweroff_req/reboot_req".to_string()
+                ))
+            })?;
+        locked_vm
+            .register_panic_event(vm.clone())
+            .with_context(|| anyhow!(MachineError::InitEventFdErr("panic".to_string())))?;
+        locked_vm
+            .register_powerdown_event(vm.clone())
+            .with_context(|| {
+                anyhow!(MachineError::InitEventFdErr(
+                    "powerdown_expired".to_string()
+                ))
+            })?;
+        locked_vm
+            .register_migration_event(vm.clone())
+            .with_context(|| anyhow!(MachineError::InitEventFdErr("migration".to_string())))?;
+        locked_vm
+            .register_dump_event(vm.clone())
+            .with_context(|| anyhow!(MachineError::InitEventFdErr("dump".to_string())))?;
+        if let Some(gdb_addr) = vm_config.gdb.as_ref() {
+            locked_vm
+                .add_gdbstub(vm.clone(), gdb_addr)
+                .with_context(|| "Failed to add gdb stub.")?;
+        }
 
         Ok(())
     }
 
     fn run(&self, paused: bool) -> MachineResult<()> {
-        self.vm_start(paused, &self.cpus, &mut self.vm_state.0.lock().unwrap())
+        self.vm_start(paused, &self.cpus, &mut self.vm_state.0.lock().unwrap())?;
+        // Device clocks don't tick before the first `cont`, as guest time doesn't.
+        if paused {
+            self.sysbus
+                .pause_all()
+                .with_context(|| "Failed to pause sysbus devices")?;
+        }
+        Ok(())
+    }
+}
+
+impl LightMachine {
+    /// Create vcpu fds, the interrupt controller and devices given by command line.
+    /// Backends deferred by `pending_backends` are realized on `threads` threads.
+    fn create_vcpus_and_devices(
+        &mut self,
+        vm_config: &mut VmConfig,
+        threads: usize,
+    ) -> Result<Vec<Arc<VcpuFd>>> {
+        let mut vcpu_fds = vec![];
+        for vcpu_id in 0..vm_config.machine_config.nr_cpus {
+            vcpu_fds.push(Arc::new(
+                KVM_FDS
+                    .load()
+                    .vm_fd
+                    .as_ref()
+                    .unwrap()
+                    .create_vcpu(vcpu_id as u64)?,
+            ));
+        }
+
+        // Contexts of harts up to maxcpus are created, hot-added vcpus are
+        // connected later.
+        #[cfg(target_arch = "riscv64")]
+        let irq_chip = self.init_interrupt_controller(
+            vcpu_fds.clone(),
+            u32::from(vm_config.machine_config.max_cpus),
+        )?;
+        #[cfg(target_arch = "riscv64")]
+        {
+            self.irq_chip = Some(irq_chip.clone());
+        }
+
+        self.create_replaceable_devices(
+            #[cfg(target_arch = "riscv64")]
+            irq_chip.clone(),
+        )
+        .with_context(|| "Failed to create replaceable devices.")?;
+        self.add_devices(
+            vm_config,
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        )?;
+        self.realize_pending_backends(threads)?;
+        trace_replaceable_info(&self.replaceable_info);
+        self.reserve_hotplug_slots()
+            .with_context(|| "Failed to reserve hotplug slots.")?;
+        Ok(vcpu_fds)
+    }
+
+    /// Reserve MMIO ranges and IRQs of the slots for virtio-mmio devices plugged by
+    /// `device_add`. They are reserved after all of the boot devices, which keep their
+    /// addresses whether the slots exist or not.
+    fn reserve_hotplug_slots(&mut self) -> Result<()> {
+        let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
+        for _ in 0..MMIO_HOTPLUG_SLOT_NR {
+            let base = self.sysbus.allocate_mmio(region_size, region_size)?;
+            let irq = self.sysbus.alloc_irq()?;
+            self.hotplug_slots.push(MmioHotplugSlot {
+                base,
+                irq,
+                device: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Realize backends of replaceable devices deferred by `pending_backends`, e.g.
+    /// seeking disk images and creating taps, on `threads` threads. Their transports
+    /// are attached to sysbus already, so the order doesn't matter.
+    fn realize_pending_backends(&mut self, threads: usize) -> Result<()> {
+        let pending = match self.replaceable_info.pending_backends.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let devices = self.replaceable_info.devices.lock().unwrap();
+        let tasks: Vec<ParallelTask<'_, Result<()>>> = pending
+            .into_iter()
+            .map(|(index, dev_config)| {
+                let device = devices[index].device.clone();
+                let id = devices[index].id.clone();
+                Box::new(move || {
+                    device
+                        .lock()
+                        .unwrap()
+                        .update_config(Some(dev_config))
+                        .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id)))
+                }) as ParallelTask<'_, Result<()>>
+            })
+            .collect();
+        run_parallel(threads, tasks).into_iter().collect()
+    }
+
+    /// Generate `/cpus/cpu-map` node describing the topology of the first
+    /// `nr_vcpus` vcpus. Device tree has no die level, so dies of a socket are
+    /// flattened into its clusters.
+    fn generate_cpu_map_node(&self, fdt: &mut FdtBuilder, nr_vcpus: usize) -> util::Result<()> {
+        let topo = &self.cpu_topo;
+        let clusters = topo.dies as usize * topo.clusters as usize;
+        let cores = topo.cores as usize;
+        let threads = topo.threads as usize;
+
+        let cpu_map_node_dep = fdt.begin_node("cpu-map")?;
+        for socket in 0..topo.sockets as usize {
+            let socket_base = socket * clusters * cores * threads;
+            if socket_base >= nr_vcpus {
+                break;
+            }
+            let socket_node_dep = fdt.begin_node(&format!("socket{}", socket))?;
+            for cluster in 0..clusters {
+                let cluster_base = socket_base + cluster * cores * threads;
+                if cluster_base >= nr_vcpus {
+                    break;
+                }
+                let cluster_node_dep = fdt.begin_node(&format!("cluster{}", cluster))?;
+                for core in 0..cores {
+                    let core_base = cluster_base + core * threads;
+                    if core_base >= nr_vcpus {
+                        break;
+                    }
+                    let core_node_dep = fdt.begin_node(&format!("core{}", core))?;
+                    for thread in 0..threads.min(nr_vcpus - core_base) {
+                        let thread_node_dep = fdt.begin_node(&format!("thread{}", thread))?;
+                        fdt.set_property_u32(
+                            "cpu",
+                            (core_base + thread) as u32 + device_tree::CPU_PHANDLE_START,
+                        )?;
+                        fdt.end_node(thread_node_dep)?;
+                    }
+                    fdt.end_node(core_node_dep)?;
+                }
+                fdt.end_node(cluster_node_dep)?;
+            }
+            fdt.end_node(socket_node_dep)?;
+        }
+        fdt.end_node(cpu_map_node_dep)?;
+
+        Ok(())
+    }
+
+    /// Generate device tree, which is merged into the one given by `-dtb` if any, and
+    /// write it to guest memory at `fdt_addr`.
+    fn load_fdt(&self, fdt_addr: u64) -> Result<()> {
+        let mut fdt_helper = FdtBuilder::new();
+        self.generate_fdt_node(&mut fdt_helper)
+            .with_context(|| anyhow!(MachineError::GenFdtErr))?;
+        let mut fdt_vec = fdt_helper.finish()?;
+        if let Some(user_dtb) = &self.user_dtb {
+            let no_fixup = self.boot_source.lock().unwrap().dtb_no_fixup;
+            fdt_vec = merge_user_dtb(user_dtb, &Fdt::from_blob(&fdt_vec)?, no_fixup)?;
+        }
+        self.sys_mem
+            .write(
+                &mut fdt_vec.as_slice(),
+                GuestAddress(fdt_addr),
+                fdt_vec.len() as u64,
+            )
+            .with_context(|| anyhow!(MachineError::WrtFdtErr(fdt_addr, fdt_vec.len())))?;
+        Ok(())
+    }
+
+    fn register_reset_event(&self, vm: Arc<Mutex<Self>>) -> Result<()> {
+        let reset_req_fd = self.reset_req.as_raw_fd();
+        let reset_req_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(reset_req_fd);
+            if let Err(e) = LightMachine::handle_reset_request(&vm) {
+                error!("Fail to reboot micro VM, {:?}", e);
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            reset_req_fd,
+            None,
+            EventSet::IN,
+            vec![reset_req_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| anyhow!(MachineError::RegNotifierErr))?;
+        Ok(())
+    }
+
+    /// Reboot the machine requested by guest.
+    fn handle_reset_request(vm: &Arc<Mutex<Self>>) -> Result<()> {
+        vm.lock().unwrap().reset_to_boot(false)
+    }
+
+    fn register_watchdog_event(&self, vm: Arc<Mutex<Self>>) -> Result<()> {
+        let expired_fd = self.watchdog_expired.as_raw_fd();
+        let expired_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(expired_fd);
+            LightMachine::handle_watchdog_expiry(&vm);
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            expired_fd,
+            None,
+            EventSet::IN,
+            vec![expired_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| anyhow!(MachineError::RegNotifierErr))?;
+        Ok(())
+    }
+
+    /// Report the expiry of guest watchdog, then take the action given by
+    /// `-watchdog-action` the same way as guest reboot, QMP `quit` and `stop`.
+    fn handle_watchdog_expiry(vm: &Arc<Mutex<Self>>) {
+        let mut locked_vm = vm.lock().unwrap();
+        let action = locked_vm.vm_config.lock().unwrap().watchdog_action;
+        warn!("Guest watchdog expires, action: {}", action.name());
+        if QmpChannel::is_connected() {
+            let watchdog_msg = qmp_schema::Watchdog {
+                action: action.name().to_string(),
+            };
+            event!(Watchdog; watchdog_msg);
+        }
+
+        match action {
+            WatchdogAction::Reset => locked_vm.guest_reboot(),
+            WatchdogAction::Poweroff => locked_vm.guest_poweroff(),
+            WatchdogAction::Pause => {
+                locked_vm.pause();
+            }
+            WatchdogAction::None => {}
+        }
+    }
+
+    /// Flush the writable drive files to disk.
+    fn flush_drive_files(&self) {
+        for (path, drive_file) in self.drive_files.lock().unwrap().iter() {
+            if drive_file.read_only {
+                continue;
+            }
+            if let Err(e) = drive_file.file.sync_all() {
+                error!("Failed to flush drive file {}: {:?}", path, e);
+            }
+        }
+    }
+
+    /// Register power off and reboot requests from guest devices, e.g. sifive test.
+    fn register_guest_power_events(&self, vm: Arc<Mutex<Self>>) -> Result<()> {
+        let poweroff_fd = self.poweroff_req.as_raw_fd();
+        let poweroff_vm = vm.clone();
+        let poweroff_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(poweroff_fd);
+            poweroff_vm.lock().unwrap().guest_poweroff();
+            None
+        });
+        let reboot_fd = self.reboot_req.as_raw_fd();
+        let reboot_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(reboot_fd);
+            vm.lock().unwrap().guest_reboot();
+            None
+        });
+        let notifiers = vec![
+            EventNotifier::new(
+                NotifierOperation::AddShared,
+                poweroff_fd,
+                None,
+                EventSet::IN,
+                vec![poweroff_handler],
+            ),
+            EventNotifier::new(
+                NotifierOperation::AddShared,
+                reboot_fd,
+                None,
+                EventSet::IN,
+                vec![reboot_handler],
+            ),
+        ];
+        EventLoop::update_event(notifiers, None)
+            .with_context(|| anyhow!(MachineError::RegNotifierErr))?;
+        Ok(())
+    }
+
+    fn register_panic_event(&self, vm: Arc<Mutex<Self>>) -> Result<()> {
+        let panic_fd = self.panic_notifier.as_raw_fd();
+        let panic_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(panic_fd);
+            LightMachine::handle_guest_panic(&vm);
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            panic_fd,
+            None,
+            EventSet::IN,
+            vec![panic_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| anyhow!(MachineError::RegNotifierErr))?;
+        Ok(())
+    }
+
+    /// Take the action given by `-action panic` after guest panic is reported.
+    fn handle_guest_panic(vm: &Arc<Mutex<Self>>) {
+        let locked_vm = vm.lock().unwrap();
+        match locked_vm.panic_notifier.action() {
+            PanicAction::Pause => {
+                if locked_vm.pause() {
+                    locked_vm.panicked.store(true, Ordering::SeqCst);
+                }
+            }
+            PanicAction::Shutdown => {
+                set_vm_exit_code(VM_EXIT_GUEST_FAILURE);
+                locked_vm.guest_poweroff();
+            }
+            // Exit even with `-action shutdown=pause`, so that harness sees the failure.
+            PanicAction::ExitFailure => {
+                set_vm_exit_code(VM_EXIT_GUEST_FAILURE);
+                if locked_vm.destroy() && QmpChannel::is_connected() {
+                    let shutdown_msg = qmp_schema::Shutdown {
+                        guest: true,
+                        reason: "guest-panic".to_string(),
+                        resumable: None,
+                    };
+                    event!(Shutdown; shutdown_msg);
+                }
+            }
+            PanicAction::None => {}
+        }
+    }
+
+    /// Power off the machine requested by guest, the same way as guest shutdown by SBI.
+    /// Process exits with the exit code set by guest once the main loop is over, or
+    /// the machine is paused for inspection with `-action shutdown=pause`.
+    fn guest_poweroff(&self) {
+        if self.vm_config.lock().unwrap().shutdown_action == ShutdownAction::Pause {
+            let shutdown_msg = qmp_schema::Shutdown {
+                guest: true,
+                reason: "guest-shutdown".to_string(),
+                resumable: Some(true),
+            };
+            event!(Shutdown; shutdown_msg);
+            self.pause();
+            return;
+        }
+        if self.destroy() && QmpChannel::is_connected() {
+            let shutdown_msg = qmp_schema::Shutdown {
+                guest: true,
+                reason: "guest-shutdown".to_string(),
+                resumable: None,
+            };
+            event!(Shutdown; shutdown_msg);
+        }
+    }
+
+    /// Force off the machine if guest is still running after the timeout of
+    /// `-action powerdown=force-off`, which drops the timer armed before.
+    fn arm_powerdown_timer(&self, timeout: u64) {
+        let delay = Duration::from_secs(timeout);
+        let mut powerdown_timer = self.powerdown_timer.lock().unwrap();
+        if let Some(timer) = powerdown_timer.as_ref() {
+            timer.rearm(delay);
+            return;
+        }
+        let expired = self.powerdown_expired.clone();
+        let func = Box::new(move || {
+            if let Err(e) = expired.write(1) {
+                error!("Failed to notify powerdown timeout: {:?}", e);
+            }
+        });
+        match EventLoop::add_timer(delay, None, func) {
+            Ok(timer) => *powerdown_timer = Some(timer),
+            Err(e) => error!("Failed to arm powerdown timer: {:?}", e),
+        }
+    }
+
+    /// Register the timeout of powerdown, which is handled as QMP `quit`.
+    fn register_powerdown_event(&self, vm: Arc<Mutex<Self>>) -> Result<()> {
+        let expired_fd = self.powerdown_expired.as_raw_fd();
+        let expired_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(expired_fd);
+            let locked_vm = vm.lock().unwrap();
+            warn!("Guest ignores power button, force off");
+            if locked_vm.destroy() && QmpChannel::is_connected() {
+                let shutdown_msg = qmp_schema::Shutdown {
+                    guest: false,
+                    reason: "host-qmp-system-powerdown".to_string(),
+                    resumable: None,
+                };
+                event!(Shutdown; shutdown_msg);
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            expired_fd,
+            None,
+            EventSet::IN,
+            vec![expired_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| anyhow!(MachineError::RegNotifierErr))?;
+        Ok(())
+    }
+
+    /// Reboot the machine requested by guest, the same way as guest reboot by SBI.
+    /// Machine may turn it into shutdown by `-no-reboot`, which reports its own event.
+    fn guest_reboot(&mut self) {
+        if self.reset() && QmpChannel::is_connected() {
+            let reset_msg = qmp_schema::Reset { guest: true };
+            event!(Reset; reset_msg);
+        }
+    }
+
+    /// Reset the machine to its boot state: pause vcpus, reset devices, reload kernel
+    /// and dtb, then resume vcpus from their boot state unless vm is paused.
+    ///
+    /// # Arguments
+    ///
+    /// * `clear_memory` - Zero guest RAM before reloading kernel and dtb.
+    fn reset_to_boot(&self, clear_memory: bool) -> Result<()> {
+        self.panicked.store(false, Ordering::SeqCst);
+        // Guest reboots instead of powering down, drop the powerdown timer.
+        if let Some(timer) = self.powerdown_timer.lock().unwrap().as_ref() {
+            timer.cancel();
+        }
+        for (cpu_index, cpu) in self.cpus.iter().enumerate() {
+            cpu.pause()
+                .with_context(|| format!("Failed to pause vcpu{}", cpu_index))?;
+            cpu.stats().reset(cpu.tid());
+        }
+
+        self.sysbus
+            .reset_all()
+            .with_context(|| "Failed to reset sysbus devices")?;
+        if clear_memory {
+            let mem_start = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+            let mem_size = self
+                .vm_config
+                .lock()
+                .unwrap()
+                .machine_config
+                .mem_config
+                .mem_size;
+            self.sys_mem
+                .discard_range(GuestAddress(mem_start), mem_size)
+                .with_context(|| "Failed to clear guest memory")?;
+        }
+        let boot_config = self.load_boot_source(None)?;
+        self.load_fdt(boot_config.fdt_addr)?;
+
+        let running = *self.vm_state.0.lock().unwrap() == KvmVmState::Running;
+        for (cpu_index, cpu) in self.cpus.iter().enumerate() {
+            cpu.set_to_boot_state();
+            cpu.reset()
+                .with_context(|| format!("Failed to reset vcpu{}", cpu_index))?;
+            if running {
+                cpu.resume()
+                    .with_context(|| format!("Failed to resume vcpu{}", cpu_index))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start gdb server on `addr`, vcpus stop at guest breakpoints once gdb connects.
+    fn add_gdbstub(&mut self, vm: Arc<Mutex<Self>>, addr: &str) -> Result<()> {
+        let stop_notifier = Arc::new(GdbStopNotifier::new()?);
+        let gdb = GdbStub::new(
+            addr,
+            self.cpus.clone(),
+            self.sys_mem.clone(),
+            vm,
+            self.vm_state.clone(),
+            stop_notifier.clone(),
+        )?;
+        EventLoop::update_event(
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(gdb))),
+            None,
+        )
+        .with_context(|| anyhow!(MachineError::RegNotifierErr))?;
+        self.gdb_stop = Some(stop_notifier);
+        Ok(())
+    }
+
+    // The CLINT needs the timebase frequency reported by KVM, so it is created
+    // once the vCPUs exist. Timer and software interrupts target M-mode, which
+    // KVM can't inject, so the pending bits are only tracked in the device.
+    #[cfg(target_arch = "riscv64")]
+    fn add_clint_device(&mut self) -> Result<()> {
+        let frequency = self.cpus[0].arch().lock().unwrap().timer_regs().frequency;
+        let clint = Clint::new(self.cpu_topo.max_cpus as usize, frequency, None)?;
+        clint.realize(
+            &mut self.sysbus,
+            MEM_LAYOUT[LayoutEntryType::Clint as usize].0,
+            MEM_LAYOUT[LayoutEntryType::Clint as usize].1,
+        )?;
+        Ok(())
     }
 }
 
@@ -732,7 +2407,16 @@ impl MachineOps for LightMachine {
 
 impl MachineLifecycle for LightMachine {
     fn pause(&self) -> bool {
+        // Stopping a paused vm again is not an error, neither is stopping a prelaunch
+        // vm whose vcpus are parked.
+        let vmstate = *self.vm_state.0.lock().unwrap();
+        if vmstate == KvmVmState::Paused || vmstate == KvmVmState::Prelaunch {
+            return true;
+        }
         if self.notify_lifecycle(KvmVmState::Running, KvmVmState::Paused) {
+            if let Err(e) = self.sysbus.pause_all() {
+                error!("Failed to pause sysbus devices: {:?}", e);
+            }
             event!(Stop);
             true
         } else {
@@ -741,9 +2425,29 @@ impl MachineLifecycle for LightMachine {
     }
 
     fn resume(&self) -> bool {
-        if !self.notify_lifecycle(KvmVmState::Paused, KvmVmState::Running) {
+        if matches!(
+            self.get_migrate_info().0,
+            MigrateMode::Defer | MigrateMode::Unix
+        ) {
+            error!("Vm is waiting for incoming migration, can't be resumed");
+            return false;
+        }
+        if self.dump.is_active() {
+            error!("Guest memory is being dumped, vm can't be resumed");
+            return false;
+        }
+        let old_state = match *self.vm_state.0.lock().unwrap() {
+            KvmVmState::Running => return true,
+            KvmVmState::Prelaunch => KvmVmState::Prelaunch,
+            _ => KvmVmState::Paused,
+        };
+        if !self.notify_lifecycle(old_state, KvmVmState::Running) {
             return false;
         }
+        self.panicked.store(false, Ordering::SeqCst);
+        if let Err(e) = self.sysbus.resume_all() {
+            error!("Failed to resume sysbus devices: {:?}", e);
+        }
 
         event!(Resume);
         true
@@ -759,6 +2463,12 @@ impl MachineLifecycle for LightMachine {
             return false;
         }
 
+        // Vcpus are destroyed, flush what guest has written before exit.
+        self.flush_drive_files();
+        if let Err(e) = self.sysbus.unrealize_all() {
+            error!("Failed to unrealize sysbus devices: {:?}", e);
+        }
+
         if self.power_button.write(1).is_err() {
             error!("Micro vm write power button failed");
             return false;
@@ -768,13 +2478,32 @@ impl MachineLifecycle for LightMachine {
 
 
     fn reset(&mut self) -> bool {
-        // For micro vm, the reboot command is equivalent to the shutdown command.
+        if self.vm_config.lock().unwrap().reboot_action == RebootAction::Reset {
+            // The caller may be a vcpu thread, which has to be paused for reboot.
+            return self.reset_req.write(1).is_ok();
+        }
+
+        // With `-action reboot=shutdown`, the reboot command is equivalent to the shutdown command,
+        // except that process exits with its own code.
         for cpu in self.cpus.iter() {
             let (cpu_state, _) = cpu.state();
             *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
         }
 
-        self.destroy()
+        if let Err(e) = self.sysbus.reset_all() {
+            error!("Failed to reset sysbus devices: {:?}", e);
+        }
+
+        set_vm_exit_code(VM_EXIT_GUEST_REBOOT);
+        if self.destroy() {
+            let shutdown_msg = qmp_schema::Shutdown {
+                guest: true,
+                reason: "guest-reset".to_string(),
+                resumable: None,
+            };
+            event!(Shutdown; shutdown_msg);
+        }
+        false
     }
 
     fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
@@ -806,74 +2535,200 @@ impl MachineAddressInterface for LightMachine {
 
 impl DeviceInterface for LightMachine {
     fn query_status(&self) -> Response {
-        //todo
-        let vmstate = self.vm_state.deref().0.lock().unwrap();
-        let qmp_state = match *vmstate {
-            KvmVmState::Running => qmp_schema::StatusInfo {
-                singlestep: false,
-                running: true,
-                status: qmp_schema::RunState::running,
-            },
-            KvmVmState::Paused => qmp_schema::StatusInfo {
-                singlestep: false,
-                running: false,
-                status: qmp_schema::RunState::paused,
-            },
-            _ => Default::default(),
+        status_response(&self.vm_state, &self.panicked)
+    }
+
+    fn system_reset(&self, clear_memory: bool) -> Response {
+        let vmstate = *self.vm_state.deref().0.lock().unwrap();
+        if vmstate != KvmVmState::Running && vmstate != KvmVmState::Paused {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Failed to reset vm in {:?} state",
+                    vmstate
+                )),
+                None,
+            );
+        }
+        if let Err(e) = self.reset_to_boot(clear_memory) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
+        }
+
+        let reset_msg = qmp_schema::Reset { guest: false };
+        event!(Reset; reset_msg);
+        Response::create_empty_response()
+    }
+
+    fn system_powerdown(&self) -> Response {
+        let vmstate = *self.vm_state.deref().0.lock().unwrap();
+        if vmstate != KvmVmState::Running && vmstate != KvmVmState::Paused {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Failed to power down vm in {:?} state",
+                    vmstate
+                )),
+                None,
+            );
+        }
+        let gpio = match self.gpio.as_ref() {
+            Some(gpio) => gpio,
+            None => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(
+                        "No power button on the machine".to_string(),
+                    ),
+                    None,
+                );
+            }
         };
-     
-        Response::create_response(serde_json::to_value(&qmp_state).unwrap(), None)
+
+        // Paused guest sees the button once vm resumes.
+        gpio.lock().unwrap().press_power_button();
+        event!(Powerdown);
+        let powerdown = self.vm_config.lock().unwrap().powerdown;
+        if powerdown.action == PowerdownAction::ForceOff {
+            self.arm_powerdown_timer(powerdown.timeout);
+        }
+        Response::create_empty_response()
     }
 
     fn query_cpus(&self) -> Response {
         let mut cpu_vec: Vec<serde_json::Value> = Vec::new();
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            if self.cpu_topo.get_mask(cpu_index as usize) == 1 {
-                let thread_id = self.cpus[cpu_index as usize].tid();
-                let cpu_instance = self.cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
-                let cpu_common = qmp_schema::CpuInfoCommon {
-                    current: true,
-                    qom_path: String::from("/machine/unattached/device[")
-                        + &cpu_index.to_string()
-                        + "]",
-                    halted: false,
-                    props: Some(cpu_instance),
-                    CPU: cpu_index as isize,
-                    thread_id: thread_id as isize,
+        for cpu in self.cpus.iter() {
+            let cpu_index = cpu.id();
+            let thread_id = cpu.tid();
+            let (cpu_state, _) = cpu.state();
+            let halted = *cpu_state.lock().unwrap() != CpuLifecycleState::Running;
+            let cpu_instance = self.cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
+            let cpu_common = qmp_schema::CpuInfoCommon {
+                current: cpu_index == 0,
+                qom_path: String::from("/machine/unattached/device[")
+                    + &cpu_index.to_string()
+                    + "]",
+                halted,
+                props: Some(cpu_instance),
+                CPU: cpu_index as isize,
+                thread_id: thread_id as isize,
+            };
+            #[cfg(target_arch = "x86_64")]
+            {
+                let cpu_info = qmp_schema::CpuInfo::x86 {
+                    common: cpu_common,
+                    x86: qmp_schema::CpuInfoX86 {},
                 };
-                #[cfg(target_arch = "x86_64")]
-                {
-                    let cpu_info = qmp_schema::CpuInfo::x86 {
-                        common: cpu_common,
-                        x86: qmp_schema::CpuInfoX86 {},
-                    };
-                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
-                }
-                #[cfg(target_arch = "aarch64")]
-                {
-                    let cpu_info = qmp_schema::CpuInfo::Arm {
-                        common: cpu_common,
-                        arm: qmp_schema::CpuInfoArm {},
-                    };
-                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
-                }
-                #[cfg(target_arch = "riscv64")]
-                {
-                    let cpu_info = qmp_schema::CpuInfo::RISCV {
-                        common: cpu_common,
-                        arm: qmp_schema::CpuInfoRISCV {},
-                    };
-                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
-                }
+                cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                let cpu_info = qmp_schema::CpuInfo::Arm {
+                    common: cpu_common,
+                    arm: qmp_schema::CpuInfoArm {},
+                };
+                cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
             }
+            #[cfg(target_arch = "riscv64")]
+            {
+                let cpu_info = qmp_schema::CpuInfo::RISCV {
+                    common: cpu_common,
+                    arm: qmp_schema::CpuInfoRISCV {},
+                };
+                cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
+            }
+        }
+        Response::create_response(cpu_vec.into(), None)
+    }
+
+    fn query_cpus_fast(&self) -> Response {
+        let mut cpu_vec: Vec<serde_json::Value> = Vec::new();
+        for cpu in self.cpus.iter() {
+            let cpu_index = cpu.id() as usize;
+            let cpu_info = qmp_schema::CpuInfoFast {
+                cpu_index: cpu_index as isize,
+                qom_path: format!("/machine/unattached/device[{}]", cpu_index),
+                thread_id: cpu.tid() as isize,
+                props: Some(self.cpu_topo.get_topo_instance_for_qmp(cpu_index)),
+                target: std::env::consts::ARCH.to_string(),
+                host_cpus: cpu.host_cpus(),
+            };
+            cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
         }
         Response::create_response(cpu_vec.into(), None)
     }
 
+    fn query_vcpu_stats(&self) -> Response {
+        let stats_vec: Vec<serde_json::Value> = self
+            .cpus
+            .iter()
+            .map(|cpu| serde_json::to_value(cpu.stats().snapshot(cpu.id(), cpu.tid())).unwrap())
+            .collect();
+        Response::create_response(stats_vec.into(), None)
+    }
+
+    fn query_machines(&self) -> Response {
+        // Vcpus are limited by both KVM and the `-smp` range of TeleVM.
+        let kvm_max_vcpus = KVM_FDS.load().fd.as_ref().unwrap().get_max_vcpus();
+        let cpu_max = kvm_max_vcpus.min(MAX_NR_CPUS as usize) as u8;
+        let machines = supported_machines(cpu_max);
+        Response::create_response(serde_json::to_value(&machines).unwrap(), None)
+    }
+
+    fn query_cpu_model_expansion(
+        &self,
+        type_: String,
+        model: qmp_schema::CpuModelInfo,
+    ) -> Response {
+        if type_ != "static" && type_ != "full" {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Invalid expansion type {}, must be \"static\" or \"full\"",
+                    type_
+                )),
+                None,
+            );
+        }
+        // All models are the host cpu, each of them is expanded to the model of vcpus.
+        if !CPU_MODELS.contains(&model.name.as_str()) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Invalid cpu model {}, must be one of {:?}",
+                    model.name, CPU_MODELS
+                )),
+                None,
+            );
+        }
+
+        let name = self
+            .vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .cpu_config
+            .model
+            .clone();
+
+        let isa = self.cpus[0].arch().lock().unwrap().config_regs().isa;
+        let props = ISA_EXTENSIONS
+            .iter()
+            .map(|ext| {
+                let enabled = isa & CPUFeatures::isa_bit(*ext) != 0;
+                (ext.to_string(), enabled)
+            })
+            .collect();
+        let expansion = qmp_schema::CpuModelExpansionInfo {
+            model: qmp_schema::CpuModelInfo {
+                name,
+                props: Some(props),
+            },
+        };
+        Response::create_response(serde_json::to_value(&expansion).unwrap(), None)
+    }
+
     fn query_hotpluggable_cpus(&self) -> Response {
         let mut hotplug_vec: Vec<serde_json::Value> = Vec::new();
         #[cfg(target_arch = "riscv64")]
-        let cpu_type = String::from("host-riscv64-cpu");
+        let cpu_type = String::from(CPU_DRIVER);
 
         for cpu_index in 0..self.cpu_topo.max_cpus {
             if self.cpu_topo.get_mask(cpu_index as usize) == 0 {
@@ -902,9 +2757,38 @@ impl DeviceInterface for LightMachine {
     }
 
     fn balloon(&self, value: u64) -> Response {
-        // if qmp_balloon(value) {
-        //     return Response::create_empty_response();
-        // }
+        let balloon = match &self.balloon {
+            Some(balloon) => balloon,
+            None => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::DeviceNotActive(
+                        "No balloon device has been activated".to_string(),
+                    ),
+                    None,
+                )
+            }
+        };
+        match balloon.lock().unwrap().set_target_size(value) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("Failed to set balloon target: {:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn query_balloon(&self) -> Response {
+        if let Some(balloon) = &self.balloon {
+            let locked_balloon = balloon.lock().unwrap();
+            let ret = qmp_schema::BalloonInfo {
+                actual: locked_balloon.actual_size(),
+                target: Some(locked_balloon.target_size()),
+            };
+            return Response::create_response(serde_json::to_value(&ret).unwrap(), None);
+        }
         Response::create_error_response(
             qmp_schema::QmpErrorClass::DeviceNotActive(
                 "No balloon device has been activated".to_string(),
@@ -913,20 +2797,173 @@ impl DeviceInterface for LightMachine {
         )
     }
 
-    fn query_balloon(&self) -> Response {
-        // if let Some(actual) = qmp_query_balloon() {
-        //     let ret = qmp_schema::BalloonInfo { actual };
-        //     return Response::create_response(serde_json::to_value(&ret).unwrap(), None);
-        // }
+    fn query_rtc(&self) -> Response {
+        if let Some(rtc) = &self.rtc {
+            let locked_rtc = rtc.lock().unwrap();
+            let time = locked_rtc.guest_time_ns();
+            let ret = qmp_schema::RtcInfo {
+                time,
+                date_time: format_datetime((time / 1_000_000_000) as i64),
+                clock: locked_rtc.clock().name().to_string(),
+            };
+            return Response::create_response(serde_json::to_value(&ret).unwrap(), None);
+        }
         Response::create_error_response(
-            qmp_schema::QmpErrorClass::DeviceNotActive(
-                "No balloon device has been activated".to_string(),
-            ),
+            qmp_schema::QmpErrorClass::DeviceNotActive("No rtc device has been added".to_string()),
             None,
         )
     }
 
+    fn query_sysbus(&self) -> Response {
+        let devices: Vec<qmp_schema::SysBusDeviceInfo> = self
+            .sysbus
+            .iter_resources()
+            .map(|(dev_type, res)| qmp_schema::SysBusDeviceInfo {
+                dev_type: format!("{:?}", dev_type),
+                region_base: res.map(|r| r.region_base),
+                region_size: res.map(|r| r.region_size),
+                irq: res.map(|r| r.irq).filter(|irq| *irq >= 0),
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(&devices).unwrap(), None)
+    }
+
+    fn trace_mmio(&self, device: String, enable: bool) -> Response {
+        match SysBusDevType::from_name(&device) {
+            Some(dev_type) => {
+                set_trace_event_enabled(&dev_type.mmio_trace_event(), enable);
+                Response::create_empty_response()
+            }
+            None => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Invalid sysbus device type {}",
+                    device
+                )),
+                None,
+            ),
+        }
+    }
+
+    fn screendump(&self, filename: String, format: Option<String>) -> Response {
+        let ret = match (&self.ramfb, format) {
+            (_, Some(format)) if format != "ppm" => {
+                Err(anyhow!("Unsupported image format {}", format))
+            }
+            (Some(ramfb), _) => {
+                let state = ramfb.lock().unwrap().ramfb_state.clone();
+                let ret = state.lock().unwrap().screendump(&filename);
+                ret
+            }
+            (None, _) => Err(anyhow!("No display device")),
+        };
+        if let Err(e) = ret {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
+        }
+        Response::create_empty_response()
+    }
+
+    fn dump_guest_memory(
+        &self,
+        paging: bool,
+        protocol: String,
+        detach: Option<bool>,
+        begin: Option<u64>,
+        length: Option<u64>,
+        format: Option<String>,
+    ) -> Response {
+        let ret = match (begin, length) {
+            (Some(begin), Some(length)) => Ok(Some((begin, length))),
+            (None, None) => Ok(None),
+            _ => Err(anyhow!("Begin and length must be given together")),
+        }
+        .and_then(|window| {
+            self.start_dump(
+                paging,
+                &protocol,
+                detach.unwrap_or(false),
+                window,
+                format.as_deref(),
+            )
+        });
+        if let Err(e) = ret {
+            error!("Failed to dump guest memory: {:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
+        }
+        Response::create_empty_response()
+    }
+
+    fn query_mmio_trace(&self) -> Response {
+        let trace = self.sysbus.mmio_trace().lock().unwrap();
+        let info = qmp_schema::MmioTraceInfo {
+            entries: trace
+                .entries()
+                .map(|entry| qmp_schema::MmioTraceEntryInfo {
+                    timestamp_ns: entry.timestamp_ns,
+                    dev_type: entry.dev_type.name().to_string(),
+                    region_base: entry.region_base,
+                    offset: entry.offset,
+                    size: entry.size,
+                    value: entry.value,
+                    write: entry.is_write,
+                })
+                .collect(),
+            overflow: trace.overflow(),
+        };
+        Response::create_response(serde_json::to_value(&info).unwrap(), None)
+    }
+
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
+        if args.driver == CPU_DRIVER {
+            return match self.plug_cpu(&args) {
+                Ok(()) => Response::create_empty_response(),
+                Err(ref e) => {
+                    error!("Failed to plug cpu: {:?}", e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    )
+                }
+            };
+        }
+        if args.driver == "pc-dimm" {
+            let ret = match &args.memdev {
+                Some(memdev) => self.plug_memory(&args.id, memdev),
+                None => Err(anyhow!("Memory backend of {} is not set", args.id)),
+            };
+            return match ret {
+                Ok(()) => Response::create_empty_response(),
+                Err(ref e) => {
+                    error!("Failed to plug memory: {:?}", e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    )
+                }
+            };
+        }
+
+        // Devices given their backend by `drive` or `netdev` are plugged into hotplug
+        // slots, the others take the replaceable device named by their id.
+        let virtio_mmio = args.driver == "virtio-blk-device" || args.driver == "virtio-net-device";
+        if virtio_mmio && (args.drive.is_some() || args.netdev.is_some()) {
+            return match self.plug_virtio_mmio(&args) {
+                Ok(()) => Response::create_empty_response(),
+                Err(ref e) => {
+                    error!("Failed to plug {}: {:?}", args.id, e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    )
+                }
+            };
+        }
+
         // get slot of bus by addr or lun
         let mut slot = 0;
         if let Some(addr) = args.addr {
@@ -952,7 +2989,99 @@ impl DeviceInterface for LightMachine {
         }
     }
 
+    fn object_add(&mut self, args: qmp_schema::ObjectAddArgument) -> Response {
+        match self.add_memory_backend(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("Failed to add object: {:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn query_memory_size_summary(&self) -> Response {
+        let vm_config = self.vm_config.lock().unwrap();
+        let summary = qmp_schema::MemorySizeSummary {
+            base_memory: vm_config.machine_config.mem_config.mem_size,
+            plugged_memory: self.plugged_mem.iter().map(|mem| mem.size).sum(),
+        };
+        Response::create_response(serde_json::to_value(&summary).unwrap(), None)
+    }
+
+    fn query_memdev(&self) -> Response {
+        let mut memdevs = Vec::new();
+        let vm_config = self.vm_config.lock().unwrap();
+        let mem_config = &vm_config.machine_config.mem_config;
+        if let Some(backend) = &mem_config.mem_backend {
+            memdevs.push(qmp_schema::MemdevInfo {
+                id: backend.id.clone(),
+                qom_type: "memory-backend-memfd".to_string(),
+                size: backend.size,
+                merge: false,
+                dump: mem_config.dump_guest_core,
+                prealloc: mem_config.mem_prealloc,
+                host_nodes: Vec::new(),
+                share: backend.share,
+                plugged: true,
+            });
+        }
+        let mut ids: Vec<&String> = self.mem_backends.keys().collect();
+        ids.sort();
+        for id in ids {
+            let backend = &self.mem_backends[id];
+            memdevs.push(qmp_schema::MemdevInfo {
+                id: id.clone(),
+                qom_type: backend.qom_type().to_string(),
+                size: backend.size,
+                merge: backend.merge,
+                dump: backend.dump,
+                prealloc: backend.prealloc,
+                host_nodes: backend.host_nodes.clone(),
+                share: backend.share,
+                plugged: self.plugged_mem.iter().any(|mem| &mem.memdev == id),
+            });
+        }
+        Response::create_response(serde_json::to_value(&memdevs).unwrap(), None)
+    }
+
+    fn query_kernel(&self) -> Response {
+        let boot_source = self.boot_source.lock().unwrap();
+        let path_str = |path: &std::path::PathBuf| path.to_string_lossy().to_string();
+        let info = qmp_schema::KernelInfo {
+            kernel: boot_source.kernel_file.as_ref().map(path_str),
+            initrd: boot_source
+                .initrd
+                .as_ref()
+                .map(|initrd| path_str(&initrd.initrd_file)),
+            cmdline: boot_source.kernel_cmdline.to_string(),
+        };
+        Response::create_response(serde_json::to_value(&info).unwrap(), None)
+    }
+
     fn device_del(&mut self, device_id: String) -> Response {
+        if let Some(slot) = self.plugged_virtio_mmio(&device_id) {
+            return match self.unplug_virtio_mmio(slot) {
+                Ok(()) => {
+                    let device_del_event = qmp_schema::DeviceDeleted {
+                        device: Some(device_id.clone()),
+                        path: device_id,
+                    };
+                    event!(DeviceDeleted; device_del_event);
+                    Response::create_empty_response()
+                }
+                Err(ref e) => {
+                    error!("Failed to unplug {}: {:?}", device_id, e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    )
+                }
+            };
+        }
+
         match self.del_replaceable_device(&device_id) {
             Ok(path) => {
                 let block_del_event = qmp_schema::DeviceDeleted {
@@ -974,47 +3103,42 @@ impl DeviceInterface for LightMachine {
     }
 
     fn blockdev_add(&self, args: Box<qmp_schema::BlockDevAddArgument>) -> Response {
-        let read_only = args.read_only.unwrap_or(false);
-        let direct = if let Some(cache) = args.cache {
-            match cache.direct {
-                Some(direct) => direct,
-                _ => true,
+        match self.add_block_backend(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("Failed to add block backend: {:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
             }
-        } else {
-            true
-        };
+        }
+    }
 
-        let config = BlkDevConfig {
-            id: args.node_name.clone(),
-            path_on_host: args.file.filename,
-            read_only,
-            direct,
-            serial_num: None,
-            iothread: None,
-            iops: None,
-            queues: 1,
-            boot_index: None,
-            chardev: None,
-            socket_path: None,
-            // TODO Add aio option by qmp, now we set it based on "direct".
-            aio: if direct {
-                AioEngine::Native
-            } else {
-                AioEngine::Off
-            },
-            queue_size: DEFAULT_VIRTQUEUE_SIZE,
-        };
-        if let Err(e) = config.check() {
-            error!("{:?}", e);
-            return Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-                None,
-            );
+    fn blockdev_del(&self, node_name: String) -> Response {
+        match self.del_block_backend(&node_name) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("Failed to delete block backend: {:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
         }
-        match self.add_replaceable_config(&args.node_name, Arc::new(config)) {
+    }
+
+    fn block_resize(
+        &self,
+        device: Option<String>,
+        node_name: Option<String>,
+        size: u64,
+        force: bool,
+    ) -> Response {
+        match self.resize_block(device, node_name, size, force) {
             Ok(()) => Response::create_empty_response(),
             Err(ref e) => {
-                error!("{:?}", e);
+                error!("Failed to resize block device: {:?}", e);
                 Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
@@ -1023,73 +3147,103 @@ impl DeviceInterface for LightMachine {
         }
     }
 
-    fn blockdev_del(&self, _node_name: String) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("blockdev_del not support yet".to_string()),
-            None,
-        )
+    fn query_block(&self) -> Response {
+        let blocks: Vec<qmp_schema::BlockInfo> = self
+            .virtio_blocks()
+            .iter()
+            .map(|block| {
+                let locked_block = block.lock().unwrap();
+                let config = locked_block.config();
+                // The device without backend file has nothing inserted.
+                let inserted = if config.path_on_host.is_empty() {
+                    None
+                } else {
+                    Some(qmp_schema::BlockDeviceInfo {
+                        node_name: self.block_backend_of(&config.id).unwrap_or_default(),
+                        file: config.path_on_host.clone(),
+                        drv: "raw".to_string(),
+                        ro: config.read_only,
+                        image: qmp_schema::ImageInfo {
+                            filename: config.path_on_host.clone(),
+                            format: "raw".to_string(),
+                            virtual_size: locked_block.disk_size(),
+                        },
+                    })
+                };
+                qmp_schema::BlockInfo {
+                    device: config.id.clone(),
+                    locked: false,
+                    removable: false,
+                    inserted,
+                }
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(&blocks).unwrap(), None)
     }
 
-    fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
-        let mut config = NetworkInterfaceConfig {
-            id: args.id.clone(),
-            host_dev_name: "".to_string(),
-            mac: None,
-            tap_fds: None,
-            vhost_type: None,
-            vhost_fds: None,
-            iothread: None,
-            queues: 2,
-            mq: false,
-            socket_path: None,
-            queue_size: DEFAULT_VIRTQUEUE_SIZE,
-        };
+    fn query_blockstats(&self) -> Response {
+        let stats: Vec<qmp_schema::BlockStats> = self
+            .virtio_blocks()
+            .iter()
+            .map(|block| {
+                let locked_block = block.lock().unwrap();
+                let id = &locked_block.config().id;
+                qmp_schema::BlockStats {
+                    device: id.clone(),
+                    node_name: self.block_backend_of(id),
+                    stats: locked_block.stats().snapshot(),
+                }
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(&stats).unwrap(), None)
+    }
 
-        if let Some(fds) = args.fds {
-            let netdev_fd = if fds.contains(':') {
-                let col: Vec<_> = fds.split(':').collect();
-                String::from(col[col.len() - 1])
-            } else {
-                String::from(&fds)
-            };
+    fn query_named_block_nodes(&self) -> Response {
+        let backends = self.block_backends.lock().unwrap();
+        let drive_files = self.drive_files.lock().unwrap();
+        let nodes: Vec<qmp_schema::BlockNodeInfo> = backends
+            .iter()
+            .map(|(node_name, backend)| {
+                let path = &backend.drive.path_on_host;
+                let virtual_size = VmConfig::fetch_drive_file(&drive_files, path)
+                    .and_then(|mut file| Ok(file.seek(SeekFrom::End(0))?))
+                    .unwrap_or(0);
+                qmp_schema::BlockNodeInfo {
+                    node_name: node_name.clone(),
+                    file: path.clone(),
+                    drv: "raw".to_string(),
+                    ro: backend.drive.read_only,
+                    direct: backend.drive.direct,
+                    image: qmp_schema::ImageInfo {
+                        filename: path.clone(),
+                        format: "raw".to_string(),
+                        virtual_size,
+                    },
+                }
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(&nodes).unwrap(), None)
+    }
 
-            if let Some(fd_num) = QmpChannel::get_fd(&netdev_fd) {
-                config.tap_fds = Some(vec![fd_num]);
-            } else {
-                // try to convert string to RawFd
-                let fd_num = match netdev_fd.parse::<i32>() {
-                    Ok(fd) => fd,
-                    _ => {
-                        error!(
-                            "Add netdev error: failed to convert {} to RawFd.",
-                            netdev_fd
-                        );
-                        return Response::create_error_response(
-                            qmp_schema::QmpErrorClass::GenericError(
-                                "Add netdev error: failed to convert {} to RawFd.".to_string(),
-                            ),
-                            None,
-                        );
-                    }
-                };
-                config.tap_fds = Some(vec![fd_num]);
-            }
-        } else if let Some(if_name) = args.if_name {
-            config.host_dev_name = if_name.clone();
-            if create_tap(None, Some(&if_name), 1).is_err() {
-                return Response::create_error_response(
-                    qmp_schema::QmpErrorClass::GenericError(
-                        "Tap device already in use".to_string(),
-                    ),
+    fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
+        match self.add_net_backend(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("Failed to add netdev: {:?}", e);
+                // Keep the cause, such as EPERM of opening tap, in the reply.
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!("{:#}", e)),
                     None,
-                );
+                )
             }
         }
+    }
 
-        match self.add_replaceable_config(&args.id, Arc::new(config)) {
+    fn netdev_del(&mut self, id: String) -> Response {
+        match self.del_net_backend(&id) {
             Ok(()) => Response::create_empty_response(),
             Err(ref e) => {
-                error!("{:?}", e);
+                error!("Failed to delete netdev: {:?}", e);
                 Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
@@ -1098,40 +3252,85 @@ impl DeviceInterface for LightMachine {
         }
     }
 
-    fn netdev_del(&mut self, _node_name: String) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("netdev_del not support yet".to_string()),
-            None,
-        )
+    fn query_netdev(&self) -> Response {
+        let netdevs: Vec<qmp_schema::NetdevInfo> = self
+            .net_backends
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, backend)| {
+                let is_tap = backend.net_type == "tap";
+                qmp_schema::NetdevInfo {
+                    id: id.clone(),
+                    net_type: backend.net_type.clone(),
+                    ifname: is_tap.then(|| backend.name.clone()),
+                    fdname: (!is_tap).then(|| backend.name.clone()),
+                    device: backend.user.clone(),
+                }
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(&netdevs).unwrap(), None)
     }
 
-    fn chardev_add(&mut self, _args: qmp_schema::CharDevAddArgument) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError(
-                "chardev_add not supported yet for microVM".to_string(),
-            ),
-            None,
-        )
-    }
+    fn chardev_add(&mut self, args: qmp_schema::CharDevAddArgument) -> Response {
+        let config = match get_chardev_config(args) {
+            Ok(config) => config,
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                    None,
+                );
+            }
+        };
+        // Chardevs of command line which are not used by device are also taken.
+        let in_cmdline = self
+            .vm_config
+            .lock()
+            .unwrap()
+            .chardev
+            .contains_key(&config.id);
+        if in_cmdline || self.chardevs.contains(&config.id) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Chardev {} already exists",
+                    config.id
+                )),
+                None,
+            );
+        }
 
-    fn chardev_remove(&mut self, _id: String) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError(
-                "chardev_remove not supported yet for microVM".to_string(),
-            ),
-            None,
-        )
+        let mut chardev = Chardev::new(config);
+        if let Err(e) = chardev.realize() {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
+        }
+        let ret = qmp_schema::ChardevReturn {
+            pty: chardev.pty_path(),
+        };
+        if let Err(e) = self.chardevs.register(Arc::new(Mutex::new(chardev)), None) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
+        }
+        Response::create_response(serde_json::to_value(&ret).unwrap(), None)
     }
 
-    fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
-        if let Some(fd) = if_fd {
-            QmpChannel::set_fd(fd_name, fd);
-            Response::create_empty_response()
-        } else {
-            let err_resp =
-                qmp_schema::QmpErrorClass::GenericError("Invalid SCM message".to_string());
-            Response::create_error_response(err_resp, None)
+    fn chardev_remove(&mut self, id: String) -> Response {
+        if let Err(e) = self.chardevs.unregister(&id) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
         }
+        Response::create_empty_response()
+    }
+
+    fn query_chardev(&self) -> Response {
+        let chardevs = self.chardevs.query();
+        Response::create_response(serde_json::to_value(&chardevs).unwrap(), None)
     }
 }
 
@@ -1139,14 +3338,22 @@ impl MigrateInterface for LightMachine {
     fn migrate(&self, uri: String) -> Response {
         match parse_incoming_uri(&uri) {
             Ok((MigrateMode::File, path)) => migration::snapshot(path),
-            Ok((MigrateMode::Unix, _)) | Ok((MigrateMode::Tcp, _)) => {
-                Response::create_error_response(
-                    qmp_schema::QmpErrorClass::GenericError(
-                        "MicroVM does not support migration".to_string(),
-                    ),
-                    None,
-                )
-            }
+            Ok((MigrateMode::Unix, path)) => match self.start_migration(&path) {
+                Ok(()) => Response::create_empty_response(),
+                Err(e) => {
+                    error!("Failed to start migration: {:?}", e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                        None,
+                    )
+                }
+            },
+            Ok((MigrateMode::Tcp, _)) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(
+                    "MicroVM does not support migration over tcp".to_string(),
+                ),
+                None,
+            ),
             _ => Response::create_error_response(
                 qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
                 None,
@@ -1155,11 +3362,168 @@ impl MigrateInterface for LightMachine {
     }
 
     fn query_migrate(&self) -> Response {
-        migration::query_migrate()
+        match self.migration.info() {
+            Some(info) => Response::create_response(serde_json::to_value(info).unwrap(), None),
+            None => migration::query_migrate(),
+        }
+    }
+
+    fn cancel_migrate(&self) -> Response {
+        cancel_migrate_response(&self.migration)
+    }
+
+    fn migrate_set_parameters(
+        &self,
+        max_bandwidth: Option<u64>,
+        downtime_limit: Option<u64>,
+        compress_level: Option<u8>,
+        xbzrle_cache_size: Option<u64>,
+    ) -> Response {
+        if let Err(e) = self.migration.set_parameters(
+            max_bandwidth,
+            downtime_limit,
+            compress_level,
+            xbzrle_cache_size,
+        ) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        Response::create_empty_response()
+    }
+
+    fn query_migrate_parameters(&self) -> Response {
+        Response::create_response(
+            serde_json::to_value(self.migration.parameters()).unwrap(),
+            None,
+        )
+    }
+
+    fn snapshot_save(&self, filename: String) -> Response {
+        let vmstate = *self.vm_state.0.lock().unwrap();
+        let ret = match vmstate {
+            _ if matches!(
+                self.get_migrate_info().0,
+                MigrateMode::Defer | MigrateMode::Unix
+            ) =>
+            {
+                Err(anyhow!("Vm is waiting for incoming migration"))
+            }
+            KvmVmState::Running | KvmVmState::Paused => {
+                // Vcpus must stay paused while their registers and RAM are saved.
+                let running = vmstate == KvmVmState::Running;
+                if running && !self.pause() {
+                    Err(anyhow!("Failed to pause vm"))
+                } else {
+                    let ret = self.save_snapshot(&filename);
+                    if running && !self.resume() {
+                        error!("Failed to resume vm after snapshot-save");
+                    }
+                    ret
+                }
+            }
+            _ => Err(anyhow!("Vm is not running")),
+        };
+        if let Err(e) = ret {
+            error!("Failed to save snapshot: {:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
+        }
+        Response::create_empty_response()
+    }
+
+    fn snapshot_load(&self, filename: String) -> Response {
+        let ret = if self.get_migrate_info().0 != MigrateMode::Defer {
+            Err(anyhow!("Vm is not started with -incoming defer"))
+        } else {
+            self.load_snapshot(&filename)
+        };
+        if let Err(e) = ret {
+            error!("Failed to load snapshot: {:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            );
+        }
+
+        // End the incoming migration, then run the restored vm.
+        if let Some((mode, _)) = self.get_vm_config().lock().unwrap().incoming.as_mut() {
+            *mode = MigrateMode::Unknown;
+        }
+        if !self.resume() {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError("Failed to resume vm".to_string()),
+                None,
+            );
+        }
+        Response::create_empty_response()
     }
 }
 
-impl MachineInterface for LightMachine {}
+impl MachineInterface for LightMachine {
+    fn debug_exit(&self, cpu_id: u8) -> bool {
+        self.gdb_stop
+            .as_ref()
+            .map_or(false, |stop| stop.notify(cpu_id))
+    }
+
+    fn guest_panicked(&self, reason: &str) -> bool {
+        self.panic_notifier.notify(reason)
+    }
+
+    fn pause_on_shutdown(&self) -> bool {
+        if self.vm_config.lock().unwrap().shutdown_action != ShutdownAction::Pause {
+            return false;
+        }
+        // The caller is a vcpu thread, which has to be paused in main loop.
+        self.poweroff_req.write(1).is_ok()
+    }
+
+    fn sbi_console_write(
+        &self,
+        addr: u64,
+        len: u64,
+    ) -> std::result::Result<Vec<u8>, SbiConsoleError> {
+        self.sbi_console
+            .as_ref()
+            .ok_or(SbiConsoleError::NotSupported)?
+            .write(addr, len)
+    }
+
+    fn sbi_console_read(&self, addr: u64, len: u64) -> std::result::Result<u64, SbiConsoleError> {
+        self.sbi_console
+            .as_ref()
+            .ok_or(SbiConsoleError::NotSupported)?
+            .read(addr, len)
+    }
+
+    fn sbi_console_write_byte(&self, byte: u8) -> std::result::Result<(), SbiConsoleError> {
+        self.sbi_console
+            .as_ref()
+            .ok_or(SbiConsoleError::NotSupported)?
+            .write_byte(byte)
+    }
+
+    /// Only boot RAM is shared, memory plugged later may be unmapped by unplug.
+    fn guest_ram_host_address(&self, addr: u64, len: u64) -> Option<u64> {
+        let mem_start = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+        let mem_size = self
+            .vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .mem_config
+            .mem_size;
+        let end = addr.checked_add(len)?;
+        if addr < mem_start || end > mem_start + mem_size {
+            return None;
+        }
+        self.sys_mem.get_host_address(GuestAddress(addr))
+    }
+}
 impl MachineExternalInterface for LightMachine {}
 
 impl EventLoopManager for LightMachine {
@@ -1174,80 +3538,6 @@ impl EventLoopManager for LightMachine {
     }
 }
 
-#[cfg(target_arch = "riscv64")]
-fn generate_plic_device_node(
-    fdt: &mut FdtBuilder,
-    res: &SysRes,
-    nr_vcpu: usize,
-) -> util::Result<()> {
-    let region_base = res.region_base;
-    let region_size = res.region_size;
-    let node = format!("interrupt-controller@{:x}", region_base);
-    let intc_node_dep = fdt.begin_node(&node)?;
-    fdt.set_property_string("compatible", "riscv,plic0")?;
-    fdt.set_property("interrupt-controller", &Vec::new())?;
-    fdt.set_property_u32("#interrupt-cells", 0x1)?;
-    fdt.set_property_u32("phandle", device_tree::PLIC_PHANDLE)?;
-    fdt.set_property_u32("riscv,ndev", 10)?;
-    // fdt.set_property_u32("riscv,ndev", MAX_DEVICES - 1)?;
-    fdt.set_property_array_u64("reg", &[region_base, region_size])?;
-
-    let num_context = nr_vcpu * 2;
-    let mut irq_cells = Vec::new();
-    let mut i: u32 = 0;
-    while i < (num_context / 2) as u32 {
-        irq_cells.push(device_tree::INCT_PHANDLE_START + i);
-        irq_cells.push(0xffff_ffff);
-        irq_cells.push(device_tree::INCT_PHANDLE_START + i);
-        irq_cells.push(9);
-        i += 1;
-    }
-
-    let irq_cells = irq_cells.as_slice();
-    fdt.set_property_array_u32("interrupts-extended", irq_cells)?;
-
-    fdt.end_node(intc_node_dep)?;
-
-    Ok(())
-}
-
-// Function that helps to generate serial node in device-tree.
-//
-// # Arguments
-//
-// * `dev_info` - Device resource info of serial device.
-// * `fdt` - Flatted device-tree blob where serial node will be filled into.
-#[cfg(target_arch = "riscv64")]
-fn generate_serial_device_node(fdt: &mut FdtBuilder, res: &SysRes) -> util::Result<()> {
-    let node = format!("uart@{:x}", res.region_base);
-    let serial_node_dep = fdt.begin_node(&node)?;
-    fdt.set_property_string("compatible", "ns16550a")?;
-    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
-    fdt.set_property_u32("clock-frequency", 3686400)?;
-    fdt.set_property_u32("interrupt-parent", device_tree::PLIC_PHANDLE)?;
-    fdt.set_property_u32("interrupts", res.irq as u32)?;
-    fdt.end_node(serial_node_dep)?;
-    Ok(())
-}
-
-// Function that helps to generate Virtio-Mmio device's node in device-tree.
-//
-// # Arguments
-//
-// * `dev_info` - Device resource info of Virtio-Mmio device.
-// * `fdt` - Flatted device-tree blob where node will be filled into.
-#[cfg(target_arch = "riscv64")]
-fn generate_virtio_devices_node(fdt: &mut FdtBuilder, res: &SysRes) -> util::Result<()> {
-    let node = format!("virtio_mmio@{:x}", res.region_base);
-    let virtio_node_dep = fdt.begin_node(&node)?;
-    fdt.set_property_string("compatible", "virtio,mmio")?;
-    fdt.set_property_u32("interrupt-parent", device_tree::PLIC_PHANDLE)?;
-    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
-    fdt.set_property_u32("interrupts", res.irq as u32)?;
-    fdt.end_node(virtio_node_dep)?;
-    Ok(())
-}
-
 /// Trait that helps to generate all nodes in device-tree.
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 trait CompileFDTHelper {
@@ -1273,8 +3563,14 @@ impl CompileFDTHelper for LightMachine {
         let frequency = cpus[0].arch().lock().unwrap().timer_regs().frequency;
         fdt.set_property_u32("timebase-frequency", frequency as u32)?;
 
-        let nr_vcpus = cpus.len();
+        // Harts which may be hot-added are described since boot, guest fails to
+        // start them until their vcpus are created.
+        let nr_vcpus = self.cpu_topo.max_cpus as usize;
         for cpu_index in 0..nr_vcpus {
+            let cpu = cpus
+                .iter()
+                .find(|cpu| cpu.id() as usize == cpu_index)
+                .unwrap_or(&cpus[0]);
             let node = format!("cpu@{:x}", cpu_index);
             let cpu_node_dep = fdt.begin_node(&node)?;
             fdt.set_property_u32(
@@ -1284,22 +3580,12 @@ impl CompileFDTHelper for LightMachine {
             fdt.set_property_string("device_type", "cpu")?;
             fdt.set_property_string("compatible", "riscv")?;
 
-            let xlen = self.cpus[cpu_index]
-                .arch()
-                .lock()
-                .unwrap()
-                .get_xlen()
-                .to_string();
+            let xlen = cpu.arch().lock().unwrap().get_xlen().to_string();
             let mut isa = format!("rv{}", xlen);
             let valid_isa_order = String::from("IEMAFDQCLBJTPVNSUHKORWXYZG");
             for char in valid_isa_order.chars() {
                 let index = char as u32 - 'A' as u32;
-                let cpu_isa = self.cpus[cpu_index]
-                    .arch()
-                    .lock()
-                    .unwrap()
-                    .config_regs()
-                    .isa;
+                let cpu_isa = cpu.arch().lock().unwrap().config_regs().isa;
                 if (cpu_isa & (1 << index) as u64) > 0 {
                     let tmp = char::from('a' as u8 + index as u8);
                     isa = format!("{}{}", isa, tmp);
@@ -1324,6 +3610,8 @@ impl CompileFDTHelper for LightMachine {
             fdt.end_node(cpu_node_dep)?;
         }
 
+        self.generate_cpu_map_node(fdt, nr_vcpus)?;
+
         fdt.end_node(cpus_node_dep)?;
 
         Ok(())
@@ -1331,12 +3619,17 @@ impl CompileFDTHelper for LightMachine {
 
     fn generate_memory_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
         let mem_base = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
-        let mem_size = self.sys_mem.memory_end_address().raw_value()
-            - MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+        let vm_config = self.vm_config.lock().unwrap();
+        let mem_size = vm_config.machine_config.mem_config.mem_size;
+        // Hotplugged memory isn't contiguous with the boot memory.
+        let mut reg = vec![mem_base, mem_size];
+        for mem in self.plugged_mem.iter() {
+            reg.extend_from_slice(&[mem.base, mem.size]);
+        }
         let node = "memory";
         let memory_node_dep = fdt.begin_node(node)?;
         fdt.set_property_string("device_type", "memory")?;
-        fdt.set_property_array_u64("reg", &[mem_base, mem_size as u64])?;
+        fdt.set_property_array_u64("reg", &reg)?;
         fdt.end_node(memory_node_dep)?;
 
         Ok(())
@@ -1348,17 +3641,34 @@ impl CompileFDTHelper for LightMachine {
         fdt.set_property_string("compatible", "simple-bus")?;
         fdt.set_property_u32("#address-cells", 0x02)?;
         fdt.set_property_u32("#size-cells", 0x2)?;
-        fdt.set_property("ranges", &Vec::new())?;
-
-        for dev in self.sysbus.devices.iter() {
-            let mut locked_dev = dev.lock().unwrap();
-            let dev_type = locked_dev.get_type();
-            let sys_res = locked_dev.get_sys_resource().unwrap();
-            match dev_type {
-                SysBusDevType::Plic => generate_plic_device_node(fdt, sys_res, self.cpus.len())?,
-                SysBusDevType::Serial => generate_serial_device_node(fdt, sys_res)?,
-                SysBusDevType::VirtioMmio => generate_virtio_devices_node(fdt, sys_res)?,
-                _ => (),
+        // Identity mapping of every MMIO window, and of platform devices outside them.
+        let mut ranges = Vec::new();
+        for (start, end) in self.sysbus.mmio_windows.iter() {
+            ranges.extend_from_slice(&[*start, *start, end - start]);
+        }
+        for (start, range) in self.sysbus.mmio_ranges.iter() {
+            if !self.sysbus.in_mmio_window(*start, range.size) {
+                ranges.extend_from_slice(&[*start, *start, range.size]);
+            }
+        }
+        fdt.set_property_array_u64("ranges", &ranges)?;
+
+        self.sysbus.generate_fdt_nodes(fdt)?;
+        // Empty hotplug slots are declared as well, plugged ones are attached to sysbus.
+        let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
+        for slot in self
+            .hotplug_slots
+            .iter()
+            .filter(|slot| slot.device.is_none())
+        {
+            let res = SysRes {
+                region_base: slot.base,
+                region_size,
+                irq: slot.irq,
+                ..Default::default()
+            };
+            if let Some(node_dep) = begin_fdt_node(fdt, SysBusDevType::VirtioMmio, &res)? {
+                fdt.end_node(node_dep)?;
             }
         }
         fdt.end_node(smb_node_dep)?;
@@ -1406,7 +3716,17 @@ impl device_tree::CompileFDT for LightMachine {
     }
 }
 
-impl MachineTestInterface for LightMachine {}
+impl MachineTestInterface for LightMachine {
+    #[cfg(target_arch = "riscv64")]
+    fn boot_regs(&self, cpu_index: usize) -> Option<[u64; 4]> {
+        let cpu = self
+            .cpus
+            .iter()
+            .find(|cpu| cpu.id() as usize == cpu_index)?;
+        let regs = cpu.boot_state().lock().unwrap().core_regs().regs;
+        Some([regs.pc, regs.a0, regs.a1, regs.a2])
+    }
+}
 
 /// Trace descriptions for some devices at stratovirt startup.
 fn trace_cpu_topo(cpu_topo: &CPUTopology) {
@@ -1421,6 +3741,71 @@ fn trace_replaceable_info(replaceable_info: &MmioReplaceableInfo) {
     util::ftrace!(trace_replaceable_info, "{:?}", replaceable_info);
 }
 
+/// The out-of-band commands of `LightMachine`, which are executed on the qmp
+/// monitor thread without taking the lock of vm.
+struct LightMachineOob {
+    vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
+    panicked: Arc<AtomicBool>,
+    migration: Arc<LiveMigration>,
+}
+
+impl MachineOobInterface for LightMachineOob {
+    fn query_status(&self) -> Response {
+        status_response(&self.vm_state, &self.panicked)
+    }
+
+    fn cancel_migrate(&self) -> Response {
+        cancel_migrate_response(&self.migration)
+    }
+}
+
+fn status_response(
+    vm_state: &Arc<(Mutex<KvmVmState>, Condvar)>,
+    panicked: &AtomicBool,
+) -> Response {
+    let vmstate = vm_state.deref().0.lock().unwrap();
+    let qmp_state = match *vmstate {
+        KvmVmState::Running => qmp_schema::StatusInfo {
+            singlestep: false,
+            running: true,
+            status: qmp_schema::RunState::running,
+        },
+        KvmVmState::Paused if panicked.load(Ordering::SeqCst) => qmp_schema::StatusInfo {
+            singlestep: false,
+            running: false,
+            status: qmp_schema::RunState::guest_panicked,
+        },
+        KvmVmState::Paused => qmp_schema::StatusInfo {
+            singlestep: false,
+            running: false,
+            status: qmp_schema::RunState::paused,
+        },
+        KvmVmState::Created | KvmVmState::Prelaunch => qmp_schema::StatusInfo {
+            singlestep: false,
+            running: false,
+            status: qmp_schema::RunState::prelaunch,
+        },
+        KvmVmState::Shutdown => qmp_schema::StatusInfo {
+            singlestep: false,
+            running: false,
+            status: qmp_schema::RunState::shutdown,
+        },
+        _ => Default::default(),
+    };
+
+    Response::create_response(serde_json::to_value(&qmp_state).unwrap(), None)
+}
+
+fn cancel_migrate_response(migration: &LiveMigration) -> Response {
+    if let Err(e) = migration.cancel() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        );
+    }
+    Response::create_empty_response()
+}
+
 fn trace_vm_state(vm_state: &Arc<(Mutex<KvmVmState>, Condvar)>) {
     util::ftrace!(trace_vm_state, "{:#?}", vm_state);
 }
@@ -0,0 +1,362 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use address_space::{AddressRange, GuestAddress};
+use anyhow::{bail, Context, Result};
+use cpu::CPUSnapshot;
+use sysbus::StateReader;
+
+use super::LightMachine;
+
+/// Magic number of snapshot file, "TVSS" in little endian.
+const SNAPSHOT_MAGIC: u32 = 0x5353_5654;
+/// Version of snapshot file layout.
+const SNAPSHOT_VERSION: u32 = 1;
+/// Size of the fixed part of header: magic, version, number of vcpus and RAM ranges,
+/// ISA and length of state section.
+const HEADER_FIXED_SIZE: usize = 32;
+/// Upper limit of RAM ranges, more ones mean a corrupted header.
+const MAX_RAM_RANGES: usize = 1024;
+
+/// Header of snapshot file. The file is laid out as header, state section of vcpus
+/// and devices, and data of RAM ranges in header order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct SnapshotHeader {
+    /// Number of vcpus.
+    nr_cpus: u32,
+    /// ISA extensions of vcpu0.
+    isa: u64,
+    /// Guest RAM ranges.
+    pub(super) ram: Vec<AddressRange>,
+    /// Length of state section.
+    state_len: u64,
+}
+
+impl SnapshotHeader {
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_FIXED_SIZE + self.ram.len() * 16);
+        for value in [
+            SNAPSHOT_MAGIC,
+            SNAPSHOT_VERSION,
+            self.nr_cpus,
+            self.ram.len() as u32,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&self.isa.to_le_bytes());
+        data.extend_from_slice(&self.state_len.to_le_bytes());
+        for range in self.ram.iter() {
+            data.extend_from_slice(&range.base.raw_value().to_le_bytes());
+            data.extend_from_slice(&range.size.to_le_bytes());
+        }
+        data
+    }
+
+    pub(super) fn read_from(src: &mut dyn Read) -> Result<Self> {
+        let mut fixed = [0_u8; HEADER_FIXED_SIZE];
+        src.read_exact(&mut fixed)
+            .with_context(|| "Failed to read snapshot header")?;
+        let mut reader = StateReader::new(&fixed);
+        if reader.read_u32()? != SNAPSHOT_MAGIC {
+            bail!("Not a snapshot file");
+        }
+        let version = reader.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            bail!("Unsupported snapshot version {}", version);
+        }
+        let nr_cpus = reader.read_u32()?;
+        let nr_ram = reader.read_u32()? as usize;
+        if nr_ram > MAX_RAM_RANGES {
+            bail!("Invalid number of RAM ranges {}", nr_ram);
+        }
+        let isa = reader.read_u64()?;
+        let state_len = reader.read_u64()?;
+
+        let mut ranges = vec![0_u8; nr_ram * 16];
+        src.read_exact(&mut ranges)
+            .with_context(|| "Failed to read RAM ranges of snapshot")?;
+        let mut reader = StateReader::new(&ranges);
+        let mut ram = Vec::with_capacity(nr_ram);
+        for _ in 0..nr_ram {
+            let base = reader.read_u64()?;
+            ram.push(AddressRange::new(GuestAddress(base), reader.read_u64()?));
+        }
+        Ok(SnapshotHeader {
+            nr_cpus,
+            isa,
+            ram,
+            state_len,
+        })
+    }
+
+    /// Total length of snapshot file described by this header.
+    fn file_len(&self) -> u64 {
+        let ram_len: u64 = self.ram.iter().map(|range| range.size).sum();
+        (HEADER_FIXED_SIZE + self.ram.len() * 16) as u64 + self.state_len + ram_len
+    }
+
+    /// Check that the snapshot is taken on a machine configured as `machine`.
+    pub(super) fn check(&self, machine: &SnapshotHeader) -> Result<()> {
+        if self.nr_cpus != machine.nr_cpus {
+            bail!(
+                "Snapshot has {} vcpus, but machine has {}",
+                self.nr_cpus,
+                machine.nr_cpus
+            );
+        }
+        if self.isa != machine.isa {
+            bail!(
+                "Snapshot ISA 0x{:x} doesn't match machine ISA 0x{:x}",
+                self.isa,
+                machine.isa
+            );
+        }
+        if self.ram != machine.ram {
+            bail!(
+                "Snapshot memory [{}] doesn't match machine memory [{}]",
+                ram_desc(&self.ram),
+                ram_desc(&machine.ram)
+            );
+        }
+        Ok(())
+    }
+}
+
+fn ram_desc(ram: &[AddressRange]) -> String {
+    ram.iter()
+        .map(|range| format!("0x{:x}+0x{:x}", range.base.raw_value(), range.size))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Build state section from registers of each vcpu and state of sysbus devices.
+fn encode_state_section(cpus: &[CPUSnapshot], devices: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for cpu in cpus.iter() {
+        data.extend_from_slice(&cpu.mp_state.to_le_bytes());
+        data.extend_from_slice(&(cpu.regs.len() as u32).to_le_bytes());
+        for (id, value) in cpu.regs.iter() {
+            data.extend_from_slice(&id.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    data.extend_from_slice(&(devices.len() as u64).to_le_bytes());
+    data.extend_from_slice(devices);
+    data
+}
+
+/// Parse state section built by `encode_state_section` with `nr_cpus` vcpus.
+pub(super) fn decode_state_section(data: &[u8], nr_cpus: u32) -> Result<(Vec<CPUSnapshot>, &[u8])> {
+    let mut reader = StateReader::new(data);
+    let mut cpus = Vec::new();
+    for _ in 0..nr_cpus {
+        let mp_state = reader.read_u32()?;
+        let nr_regs = reader.read_u32()?;
+        let mut regs = Vec::new();
+        for _ in 0..nr_regs {
+            let id = reader.read_u64()?;
+            regs.push((id, reader.read_u64()?));
+        }
+        cpus.push(CPUSnapshot { mp_state, regs });
+    }
+    let devices_len = reader.read_u64()? as usize;
+    let devices = reader.read_bytes(devices_len)?;
+    if !reader.is_empty() {
+        bail!("Trailing bytes in snapshot state");
+    }
+    Ok((cpus, devices))
+}
+
+impl LightMachine {
+    /// Configuration of this machine kept in snapshot header.
+    pub(super) fn snapshot_header(&self) -> SnapshotHeader {
+        SnapshotHeader {
+            nr_cpus: self.cpus.len() as u32,
+            isa: self
+                .cpus
+                .first()
+                .map_or(0, |cpu| cpu.arch().lock().unwrap().get_isa()),
+            ram: self.sys_mem.ram_ranges(),
+            state_len: 0,
+        }
+    }
+
+    /// Build state section from vcpus and devices of the paused vm.
+    pub(super) fn save_state_section(&self) -> Result<Vec<u8>> {
+        let mut cpus = Vec::with_capacity(self.cpus.len());
+        for cpu in self.cpus.iter() {
+            cpus.push(cpu.arch().lock().unwrap().save_regs(cpu.fd())?);
+        }
+        let devices = self
+            .sysbus
+            .save_all()
+            .with_context(|| "Failed to save device state")?;
+        Ok(encode_state_section(&cpus, &devices))
+    }
+
+    /// Restore devices and vcpus parsed from state section, after RAM is restored.
+    pub(super) fn restore_state_section(&self, cpus: &[CPUSnapshot], devices: &[u8]) -> Result<()> {
+        // Virtio queues are resumed on the restored rings.
+        self.sysbus
+            .restore_all(devices)
+            .with_context(|| "Failed to restore device state")?;
+        for (cpu, snapshot) in self.cpus.iter().zip(cpus.iter()) {
+            cpu.arch()
+                .lock()
+                .unwrap()
+                .restore_regs(cpu.fd(), snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Write vcpus, devices and RAM of the paused vm to snapshot file `path`.
+    pub(crate) fn save_snapshot(&self, path: &str) -> Result<()> {
+        let state = self.save_state_section()?;
+        let mut header = self.snapshot_header();
+        header.state_len = state.len() as u64;
+
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create snapshot file {}", path))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&header.to_bytes())?;
+        writer.write_all(&state)?;
+        for range in header.ram.iter() {
+            self.sys_mem
+                .read(&mut writer, range.base, range.size)
+                .with_context(|| format!("Failed to save RAM at 0x{:x}", range.base.raw_value()))?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write snapshot file {}", path))
+    }
+
+    /// Restore RAM, devices and vcpus of the vm waiting with parked vcpus from snapshot
+    /// file `path`. The snapshot is checked against machine configuration and parsed
+    /// before anything is restored.
+    pub(crate) fn load_snapshot(&self, path: &str) -> Result<()> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open snapshot file {}", path))?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let header = SnapshotHeader::read_from(&mut reader)?;
+        header.check(&self.snapshot_header())?;
+        if file_len != header.file_len() {
+            bail!(
+                "Snapshot file has {} bytes, expected {}",
+                file_len,
+                header.file_len()
+            );
+        }
+        let mut state = Vec::new();
+        (&mut reader)
+            .take(header.state_len)
+            .read_to_end(&mut state)
+            .with_context(|| "Failed to read snapshot state")?;
+        let (cpus, devices) = decode_state_section(&state, header.nr_cpus)?;
+
+        for range in header.ram.iter() {
+            self.sys_mem
+                .write(&mut reader, range.base, range.size)
+                .with_context(|| format!("Failed to load RAM at 0x{:x}", range.base.raw_value()))?;
+        }
+        self.restore_state_section(&cpus, devices)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn machine_header() -> SnapshotHeader {
+        SnapshotHeader {
+            nr_cpus: 2,
+            isa: 0x112d,
+            ram: vec![
+                AddressRange::new(GuestAddress(0x8000_0000), 0x1000_0000),
+                AddressRange::new(GuestAddress(0x2_0000_0000), 0x800_0000),
+            ],
+            state_len: 0,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_header() {
+        let mut header = machine_header();
+        header.state_len = 0x100;
+        let data = header.to_bytes();
+        assert_eq!(data.len(), HEADER_FIXED_SIZE + 32);
+        let read = SnapshotHeader::read_from(&mut data.as_slice()).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(read.file_len(), data.len() as u64 + 0x100 + 0x1800_0000);
+        assert!(read.check(&machine_header()).is_ok());
+
+        // Bad magic, newer version and truncated RAM ranges.
+        let mut bad = data.clone();
+        bad[0] ^= 0xff;
+        assert!(SnapshotHeader::read_from(&mut bad.as_slice()).is_err());
+        let mut bad = data.clone();
+        bad[4] = 2;
+        assert!(SnapshotHeader::read_from(&mut bad.as_slice()).is_err());
+        assert!(SnapshotHeader::read_from(&mut &data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_header_mismatch() {
+        let header = machine_header();
+
+        let mut machine = machine_header();
+        machine.nr_cpus = 1;
+        assert!(header.check(&machine).is_err());
+
+        let mut machine = machine_header();
+        machine.isa &= !0x8;
+        assert!(header.check(&machine).is_err());
+
+        // Memory differs in size, or in plugged ranges.
+        let mut machine = machine_header();
+        machine.ram[0].size = 0x2000_0000;
+        assert!(header.check(&machine).is_err());
+        let mut machine = machine_header();
+        machine.ram.pop();
+        let err = header.check(&machine).unwrap_err();
+        assert!(err.to_string().contains("0x200000000+0x8000000"));
+    }
+
+    #[test]
+    fn test_snapshot_state_section() {
+        let cpus = vec![
+            CPUSnapshot {
+                mp_state: 0,
+                regs: vec![(1, 2), (3, 4)],
+            },
+            CPUSnapshot {
+                mp_state: 1,
+                regs: Vec::new(),
+            },
+        ];
+        let data = encode_state_section(&cpus, &[5, 6, 7]);
+        let (read_cpus, devices) = decode_state_section(&data, 2).unwrap();
+        assert_eq!(read_cpus, cpus);
+        assert_eq!(devices, &[5, 6, 7]);
+
+        // Number of vcpus must match, truncated or trailing bytes are refused.
+        assert!(decode_state_section(&data, 3).is_err());
+        assert!(decode_state_section(&data[..data.len() - 1], 2).is_err());
+        let mut long = data;
+        long.push(0);
+        assert!(decode_state_section(&long, 2).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Device tree blob given by `-dtb`, which replaces the generated one.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use util::device_tree::{Fdt, FdtNode, FDT_MAX_SIZE};
+
+/// Properties of /chosen which are owned by the boot source config.
+const CHOSEN_PROPERTIES: [&str; 3] = ["bootargs", "linux,initrd-start", "linux,initrd-end"];
+
+/// Read and parse the device tree blob. It is done once when the machine is
+/// created, since the file may be out of chroot entered later.
+pub(crate) fn load_user_dtb(path: &Path) -> Result<Fdt> {
+    let blob =
+        std::fs::read(path).with_context(|| format!("Failed to read dtb {}", path.display()))?;
+    Fdt::from_blob(&blob).with_context(|| format!("Invalid dtb {}", path.display()))
+}
+
+/// Build the blob loaded into guest from the one given by user and the one
+/// generated by the machine. Unless `no_fixup`, /chosen and memory nodes of the
+/// user blob are patched with the generated ones. Devices placed differently by
+/// the two are warned about.
+pub(crate) fn merge_user_dtb(user: &Fdt, generated: &Fdt, no_fixup: bool) -> Result<Vec<u8>> {
+    let mismatches = device_mismatches(&user.root, &generated.root);
+    if !mismatches.is_empty() {
+        warn!(
+            "Devices in dtb are placed differently from the machine:\n  {}",
+            mismatches.join("\n  ")
+        );
+    }
+
+    let mut fdt = user.clone();
+    if !no_fixup {
+        fixup_chosen(&mut fdt.root, &generated.root);
+        fixup_memory(&mut fdt.root, &generated.root)?;
+    }
+    let blob = fdt.finish()?;
+    if blob.len() > FDT_MAX_SIZE as usize {
+        bail!(
+            "Dtb of {} bytes is larger than {} bytes reserved for it",
+            blob.len(),
+            FDT_MAX_SIZE
+        );
+    }
+    Ok(blob)
+}
+
+fn fixup_chosen(root: &mut FdtNode, generated: &FdtNode) {
+    let generated = generated.children.iter().find(|node| node.name == "chosen");
+    let chosen = root.child_or_insert("chosen");
+    for name in CHOSEN_PROPERTIES {
+        match generated.and_then(|node| node.property(name)) {
+            // Keep bootargs of dtb if no kernel cmdline is given.
+            Some([0]) if name == "bootargs" => {}
+            Some(value) => chosen.set_property(name, value),
+            None => chosen.remove_property(name),
+        }
+    }
+}
+
+fn is_memory_node(node: &FdtNode) -> bool {
+    node.property_str("device_type") == Some("memory")
+}
+
+/// Replace memory nodes with the generated one, whose reg is encoded by cells of
+/// the user blob.
+fn fixup_memory(root: &mut FdtNode, generated: &FdtNode) -> Result<()> {
+    let mut memory = match generated.children.iter().find(|node| is_memory_node(node)) {
+        Some(memory) => memory.clone(),
+        None => return Ok(()),
+    };
+    // Cell sizes of the root node default to 2 and 1 by spec.
+    let address_cells = root.property_u32("#address-cells").unwrap_or(2);
+    let size_cells = root.property_u32("#size-cells").unwrap_or(1);
+    let mut reg = Vec::new();
+    for (i, value) in memory
+        .property("reg")
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .enumerate()
+    {
+        let value = u64::from_be_bytes(value.try_into().unwrap());
+        let cells = if i % 2 == 0 {
+            address_cells
+        } else {
+            size_cells
+        };
+        match cells {
+            1 if value <= u64::from(u32::MAX) => {
+                reg.extend_from_slice(&(value as u32).to_be_bytes())
+            }
+            2 => reg.extend_from_slice(&value.to_be_bytes()),
+            _ => bail!(
+                "Memory reg 0x{:x} can't be encoded by {} cells of dtb root node",
+                value,
+                cells
+            ),
+        }
+    }
+    memory.set_property("reg", &reg);
+
+    let position = root.children.iter().position(is_memory_node);
+    root.children.retain(|node| !is_memory_node(node));
+    root.children
+        .insert(position.unwrap_or(root.children.len()), memory);
+    Ok(())
+}
+
+/// Compatible and unit address of the node, e.g. ("ns16550a", 0x10000000) for
+/// "uart@10000000".
+fn placement(node: &FdtNode) -> Option<(&str, u64)> {
+    let (_, address) = node.name.split_once('@')?;
+    let address = u64::from_str_radix(address, 16).ok()?;
+    Some((node.property_str("compatible")?, address))
+}
+
+/// Devices under /soc of the generated tree, which the user tree describes with
+/// the same compatible at other addresses only.
+fn device_mismatches(user: &FdtNode, generated: &FdtNode) -> Vec<String> {
+    let mut placed = Vec::new();
+    user.walk("/", &mut |path, node| {
+        if let Some((compatible, address)) = placement(node) {
+            placed.push((compatible, address, path.to_string()));
+        }
+    });
+
+    let mut mismatches = Vec::new();
+    let soc = match generated.children.iter().find(|node| node.name == "soc") {
+        Some(soc) => soc,
+        None => return mismatches,
+    };
+    soc.walk("/soc", &mut |path, node| {
+        if let Some((compatible, address)) = placement(node) {
+            let same: Vec<_> = placed.iter().filter(|(c, _, _)| *c == compatible).collect();
+            if same.is_empty() || same.iter().any(|(_, a, _)| *a == address) {
+                return;
+            }
+            let paths: Vec<_> = same.iter().map(|(_, _, p)| p.as_str()).collect();
+            mismatches.push(format!(
+                "{} ({}) is at 0x{:x}, but dtb has {}",
+                path,
+                compatible,
+                address,
+                paths.join(", ")
+            ));
+        }
+    });
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::device_tree::FdtBuilder;
+
+    fn generated_fdt(bootargs: &str) -> Fdt {
+        let mut fdt = FdtBuilder::new();
+        let root_node = fdt.begin_node("").unwrap();
+        fdt.set_property_u32("#address-cells", 2).unwrap();
+        fdt.set_property_u32("#size-cells", 2).unwrap();
+        let memory_node = fdt.begin_node("memory").unwrap();
+        fdt.set_property_string("device_type", "memory").unwrap();
+        fdt.set_property_array_u64("reg", &[0x8000_0000, 0x4000_0000])
+            .unwrap();
+        fdt.end_node(memory_node).unwrap();
+        let soc_node = fdt.begin_node("soc").unwrap();
+        for address in [0x1000_0000, 0x1000_1000] {
+            let uart_node = fdt.begin_node(&format!("uart@{:x}", address)).unwrap();
+            fdt.set_property_string("compatible", "ns16550a").unwrap();
+            fdt.end_node(uart_node).unwrap();
+        }
+        let rtc_node = fdt.begin_node("rtc@101000").unwrap();
+        fdt.set_property_string("compatible", "google,goldfish-rtc")
+            .unwrap();
+        fdt.end_node(rtc_node).unwrap();
+        fdt.end_node(soc_node).unwrap();
+        let chosen_node = fdt.begin_node("chosen").unwrap();
+        fdt.set_property_string("bootargs", bootargs).unwrap();
+        fdt.set_property_u64("linux,initrd-start", 0x8800_0000)
+            .unwrap();
+        fdt.end_node(chosen_node).unwrap();
+        fdt.end_node(root_node).unwrap();
+        Fdt::from_blob(&fdt.finish().unwrap()).unwrap()
+    }
+
+    fn user_fdt() -> Fdt {
+        let mut fdt = FdtBuilder::new();
+        let root_node = fdt.begin_node("").unwrap();
+        fdt.set_property_u32("#address-cells", 1).unwrap();
+        fdt.set_property_u32("#size-cells", 1).unwrap();
+        for address in [0x8000_0000_u32, 0x9000_0000] {
+            let memory_node = fdt.begin_node(&format!("memory@{:x}", address)).unwrap();
+            fdt.set_property_string("device_type", "memory").unwrap();
+            fdt.set_property_array_u32("reg", &[address, 0x1000_0000])
+                .unwrap();
+            fdt.end_node(memory_node).unwrap();
+        }
+        let uart_node = fdt.begin_node("serial@10000000").unwrap();
+        fdt.set_property_string("compatible", "ns16550a").unwrap();
+        fdt.end_node(uart_node).unwrap();
+        let chosen_node = fdt.begin_node("chosen").unwrap();
+        fdt.set_property_string("bootargs", "console=hvc0").unwrap();
+        fdt.set_property_u32("linux,initrd-end", 0x8900_0000)
+            .unwrap();
+        fdt.set_property_string("stdout-path", "/serial@10000000")
+            .unwrap();
+        fdt.end_node(chosen_node).unwrap();
+        fdt.end_node(root_node).unwrap();
+        Fdt::from_blob(&fdt.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_merge_user_dtb() {
+        let generated = generated_fdt("console=ttyS0");
+        let fdt = Fdt::from_blob(&merge_user_dtb(&user_fdt(), &generated, false).unwrap()).unwrap();
+        let names: Vec<_> = fdt.root.children.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, vec!["memory", "serial@10000000", "chosen"]);
+        assert_eq!(
+            fdt.root.children[0].property("reg"),
+            Some([0x80, 0, 0, 0, 0x40, 0, 0, 0].as_slice())
+        );
+        let chosen = &fdt.root.children[2];
+        assert_eq!(chosen.property_str("bootargs"), Some("console=ttyS0"));
+        assert_eq!(
+            chosen.property("linux,initrd-start"),
+            Some(0x8800_0000_u64.to_be_bytes().as_slice())
+        );
+        assert!(chosen.property("linux,initrd-end").is_none());
+        assert_eq!(chosen.property_str("stdout-path"), Some("/serial@10000000"));
+
+        // Bootargs of dtb is kept without kernel cmdline.
+        let fdt = Fdt::from_blob(&merge_user_dtb(&user_fdt(), &generated_fdt(""), false).unwrap())
+            .unwrap();
+        assert_eq!(
+            fdt.root.children[2].property_str("bootargs"),
+            Some("console=hvc0")
+        );
+
+        // Dtb is loaded as it is without fixup.
+        let user = user_fdt();
+        let fdt = Fdt::from_blob(&merge_user_dtb(&user, &generated, true).unwrap()).unwrap();
+        assert_eq!(fdt.root, user.root);
+    }
+
+    #[test]
+    fn test_memory_out_of_cells() {
+        let mut user = user_fdt();
+        user.root
+            .set_property("#address-cells", &1_u32.to_be_bytes());
+        let mut generated = generated_fdt("");
+        generated.root.children[0].set_property(
+            "reg",
+            &[0x1_0000_0000_u64, 0x1000].map(u64::to_be_bytes).concat(),
+        );
+        assert!(merge_user_dtb(&user, &generated, false).is_err());
+        assert!(merge_user_dtb(&user, &generated, true).is_ok());
+    }
+
+    #[test]
+    fn test_device_mismatches() {
+        let generated = generated_fdt("");
+        let mismatches = device_mismatches(&user_fdt().root, &generated.root);
+        // The other uart is placed differently, rtc is not described by dtb.
+        assert_eq!(
+            mismatches,
+            vec!["/soc/uart@10001000 (ns16550a) is at 0x10001000, but dtb has /serial@10000000"]
+        );
+        assert!(device_mismatches(&generated.root, &generated.root).is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Xbzrle delta of guest pages, sent instead of pages dirtied again whose last copy
+//! is cached. Delta is laid out as pairs of runs, the first one is the length of
+//! unchanged bytes and the second one is the length of changed bytes followed by
+//! them, both lengths in ULEB128. Unchanged bytes at the end of page are omitted.
+
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{bail, Result};
+
+/// Delta larger than this part of page is not worth sending.
+const MAX_DELTA_RATIO: usize = 2;
+
+/// Cache of the pages sent last, keyed by guest address. The oldest page is evicted
+/// when the cache is full.
+pub(super) struct XbzrleCache {
+    capacity: usize,
+    pages: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+}
+
+impl XbzrleCache {
+    pub(super) fn new(size: u64, page_size: u64) -> Self {
+        XbzrleCache {
+            capacity: (size / page_size) as usize,
+            pages: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Cache `page` sent at `gpa`, and return the copy sent before if it's cached.
+    pub(super) fn update(&mut self, gpa: u64, page: &[u8]) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        if let Some(cached) = self.pages.get_mut(&gpa) {
+            return Some(std::mem::replace(cached, page.to_vec()));
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.pages.remove(&old);
+            }
+        }
+        self.pages.insert(gpa, page.to_vec());
+        self.order.push_back(gpa);
+        None
+    }
+}
+
+fn put_uleb128(dst: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            dst.push(byte);
+            return;
+        }
+        dst.push(byte | 0x80);
+    }
+}
+
+fn get_uleb128(src: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut value = 0_usize;
+    let mut shift = 0;
+    loop {
+        let byte = match src.get(*pos) {
+            Some(byte) => *byte,
+            None => bail!("Truncated xbzrle delta"),
+        };
+        *pos += 1;
+        if shift >= usize::BITS {
+            bail!("Invalid length in xbzrle delta");
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Delta turning `old` into `new`, none if it's too large to be worth sending.
+pub(super) fn encode(old: &[u8], new: &[u8]) -> Option<Vec<u8>> {
+    let limit = new.len() / MAX_DELTA_RATIO;
+    let mut delta = Vec::new();
+    let mut pos = 0;
+    while pos < new.len() {
+        let start = pos;
+        while pos < new.len() && old[pos] == new[pos] {
+            pos += 1;
+        }
+        if pos == new.len() {
+            break;
+        }
+        let changed = pos;
+        while pos < new.len() && old[pos] != new[pos] {
+            pos += 1;
+        }
+        put_uleb128(&mut delta, changed - start);
+        put_uleb128(&mut delta, pos - changed);
+        delta.extend_from_slice(&new[changed..pos]);
+        if delta.len() > limit {
+            return None;
+        }
+    }
+    Some(delta)
+}
+
+/// Apply `delta` to `page`, which holds the copy the delta is built against.
+pub(super) fn decode(delta: &[u8], page: &mut [u8]) -> Result<()> {
+    let mut src = 0;
+    let mut dst = 0_usize;
+    while src < delta.len() {
+        let unchanged = get_uleb128(delta, &mut src)?;
+        let changed = get_uleb128(delta, &mut src)?;
+        let start = dst.saturating_add(unchanged);
+        let end = start.saturating_add(changed);
+        if end > page.len() || src + changed > delta.len() {
+            bail!("Xbzrle delta overflows page");
+        }
+        page[start..end].copy_from_slice(&delta[src..src + changed]);
+        src += changed;
+        dst = end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_xbzrle_delta() {
+        let old = vec![0_u8; 4096];
+        let mut new = old.clone();
+        assert_eq!(encode(&old, &new), Some(Vec::new()));
+
+        new[0] = 1;
+        new[200..203].copy_from_slice(&[2, 3, 4]);
+        new[4095] = 5;
+        let delta = encode(&old, &new).unwrap();
+        assert_eq!(delta[..3], [0, 1, 1]);
+        let mut page = old.clone();
+        decode(&delta, &mut page).unwrap();
+        assert_eq!(page, new);
+
+        // Mostly changed page is sent as is.
+        let new = vec![0xff_u8; 4096];
+        assert!(encode(&old, &new).is_none());
+
+        // Delta out of page or truncated is refused.
+        let mut page = old.clone();
+        assert!(decode(&[0x80, 0x20, 1, 1], &mut page).is_err());
+        assert!(decode(&[0, 3, 1], &mut page).is_err());
+        assert!(decode(&[0x80], &mut page).is_err());
+    }
+
+    #[test]
+    fn test_xbzrle_cache() {
+        let mut cache = XbzrleCache::new(0x2000, 0x1000);
+        assert!(cache.update(0x1000, &[1]).is_none());
+        assert!(cache.update(0x2000, &[2]).is_none());
+        assert_eq!(cache.update(0x1000, &[3]), Some(vec![1]));
+
+        // The oldest page is evicted.
+        assert!(cache.update(0x3000, &[4]).is_none());
+        assert!(cache.update(0x1000, &[5]).is_none());
+        assert_eq!(cache.update(0x3000, &[6]), Some(vec![4]));
+
+        let mut cache = XbzrleCache::new(0, 0x1000);
+        assert!(cache.update(0x1000, &[1]).is_none());
+        assert!(cache.update(0x1000, &[1]).is_none());
+    }
+}
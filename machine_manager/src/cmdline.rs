@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
 use std::os::unix::net::UnixListener;
 
 use anyhow::{bail, Context, Result};
@@ -17,7 +18,12 @@ use util::arg_parser::{Arg, ArgMatches, ArgParser};
 use util::unix::{limit_permission, parse_unix_uri};
 
 use crate::{
-    config::{add_trace_events, ChardevType, CmdParser, MachineType, VmConfig},
+    config::{
+        add_trace_events, log_file_in_dir, parse_config_file, ChardevType, CmdParser, MachineType,
+        Setting, SettingSource, VmConfig, BIOS_PATH_ENV, LOG_DIR_ENV, LOG_FILTER_ENV,
+        QMP_DEFAULT_ENV,
+    },
+    socket::{MonitorMode, SocketLimits, SocketListener},
     temp_cleaner::TempCleaner,
 };
 
@@ -30,14 +36,14 @@ const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 /// # Examples
 ///
 /// ```text
-/// add_args_to_config!(name, vm_cfg, update_name);
+/// add_args_to_config!((args.value_of("name")), vm_cfg, update_name);
 /// add_args_to_config!(name, vm_cfg, update_name, vec);
 /// add_args_to_config!(name, vm_cfg, update_name, bool);
 /// ```
 macro_rules! add_args_to_config {
-    ( $x:tt, $z:expr, $s:tt ) => {
-        if let Some(temp) = &$x {
-            $z.$s(temp)?;
+    ( ($a:ident . $f:ident ($n:expr)), $z:expr, $s:tt ) => {
+        if let Some(temp) = &$a.$f($n) {
+            with_origin(&$a, $n, temp, $z.$s(temp))?;
         }
     };
     ( $x:tt, $z:expr, $s:tt, vec ) => {
@@ -58,13 +64,16 @@ macro_rules! add_args_to_config {
 /// # Examples
 ///
 /// ```text
-/// add_args_to_config_multi!(drive, vm_cfg, update_drive);
+/// add_args_to_config_multi!((args.values_of("drive")), vm_cfg, update_drive);
 /// ```
 macro_rules! add_args_to_config_multi {
-    ( $x:tt, $z:expr, $s:tt ) => {
-        if let Some(temps) = &$x {
+    ( ($a:ident . $f:ident ($n:expr)), $z:expr, $s:tt ) => {
+        if let Some(temps) = &$a.$f($n) {
             for temp in temps {
-                $z.$s(temp)?;
+                let result = $z
+                    .$s(temp)
+                    .with_context(|| format!("Invalid -{} {}", $n, temp));
+                with_origin(&$a, $n, temp, result)?;
             }
         }
     };
@@ -83,13 +92,28 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .help("set the name of the guest.")
             .takes_value(true),
         )
+        .arg(
+            Arg::with_name("config")
+            .long("config")
+            .value_name("<file>")
+            .help("read the VM configuration from a JSON file, whose keys stand for the arguments: name, machine, memory, cpus, kernel, initrd, append, drives, netdevs, chardevs, devices, objects, serial, qmp, etc. \
+                   An argument given on the command line overrides the key, except that drives, netdevs, chardevs, devices, objects and append are added after those of the file")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("print-config")
+            .long("print-config")
+            .help("print the effective VM configuration merged from the config file and the command line, then exit")
+            .takes_value(false),
+        )
         .arg(
             Arg::with_name("machine")
             .long("machine")
-            .value_name("[type=]<name>[,dump_guest_core=on|off][,mem-share=on|off]")
+            .value_name("[type=]<name>[,dump_guest_core=on|off][,mem-share=on|off][,mem-reserve=on|off]")
             .help("'type' selects emulated machine type and set properties. \
                    'dump_guest_core' includes guest memory in a core dump. \
-                   'mem-share' sets guest memory is shareable.")
+                   'mem-share' sets guest memory is shareable. \
+                   'mem-reserve=off' maps guest memory without reserving swap space to allow overcommit.")
             .takes_value(true),
         )
         .arg(
@@ -107,7 +131,7 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("cpu")
             .long("cpu")
-            .value_name("host[,pmu=on|off]")
+            .value_name("host|rv64[,pmu=on|off][,<ext>=on|off][,steal-time=on|off]")
             .help("set CPU model and features.")
             .can_no_value(false)
             .takes_value(true)
@@ -116,22 +140,22 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             Arg::with_name("freeze_cpu")
             .short("S")
             .long("freeze")
-            .help("freeze CPU at startup")
+            .help("freeze CPU at startup, the vm is in prelaunch until QMP cont")
             .takes_value(false)
             .required(false),
         )
         .arg(
             Arg::with_name("memory")
             .long("m")
-            .value_name("[size=]<megs>[m|M|g|G]")
-            .help("configure guest RAM(default unit: MiB).")
+            .value_name("[size=]<megs>[k|K|m|M|g|G|t|T][,maxmem=<size>,slots=<n>]")
+            .help("configure guest RAM(default unit: MiB). 'maxmem' and 'slots' leave guest address space above RAM for memory hotplug, up to 'slots' devices of 'maxmem' in total with RAM.")
             .takes_value(true),
         )
         .arg(
             Arg::with_name("mem-path")
             .long("mem-path")
             .value_name("<filebackend file path>")
-            .help("configure file path that backs guest memory.")
+            .help("configure file path that backs guest memory, or a directory such as hugetlbfs mount point.")
             .takes_value(true),
         )
         .arg(
@@ -141,6 +165,20 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .takes_value(false)
             .required(false),
         )
+        .arg(
+            Arg::with_name("mem-prealloc-threads")
+            .long("mem-prealloc-threads")
+            .value_name("<n>")
+            .help("number of threads preallocating memory, default is the smaller one of vcpus and host cpus")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("realize-threads")
+            .long("realize-threads")
+            .value_name("<n>")
+            .help("number of threads realizing machine, which maps memory and realizes device backends concurrently, 1 for serial realize, default is 4")
+            .takes_value(true),
+        )
         .arg(
             Arg::with_name("numa")
             .multiple(true)
@@ -172,11 +210,32 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .help("use 'initrd-file' as initial ram disk")
             .takes_value(true),
         )
+        .arg(
+            Arg::with_name("bios")
+            .long("bios")
+            .value_name("<firmware_path>")
+            .help("boot OpenSBI fw_dynamic firmware loaded at the start of RAM before kernel, TELEVM_BIOS_PATH is used if not given. 'none' boots kernel directly")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dtb")
+            .long("dtb")
+            .value_name("<dtb_path>")
+            .help("use the device tree blob instead of generating one; /chosen (bootargs, initrd) and memory nodes are still patched, and devices it places differently from StratoVirt are warned about")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dtb-no-fixup")
+            .long("dtb-no-fixup")
+            .help("load the device tree blob given by -dtb as it is, without patching /chosen and memory nodes")
+            .takes_value(false)
+            .required(false),
+        )
         .arg(
             Arg::with_name("qmp")
             .long("qmp")
-            .value_name("unix:<socket_path>")
-            .help("set QMP's unix socket path")
+            .value_name("unix:<socket_path>|tcp:<host>:<port>[,allow-remote][,max-cmd-size=<bytes>]")
+            .help("set QMP's unix socket path or tcp address, tcp only listens on loopback address unless 'allow-remote' is given. A client sending a command larger than 'max-cmd-size' (1MiB by default) is disconnected. TELEVM_QMP_DEFAULT is used if neither -qmp nor -mon is given")
             .takes_value(true)
         )
         .arg(
@@ -191,7 +250,8 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .multiple(true)
             .long("drive")
             .value_name("<parameters>")
-            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>]; \
+            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,if=none][,format=raw][,readonly=on|off][,direct=on|off][,share-rw=on|off][,throttling.iops-total=<200>]; \
+                   \n\t\treference it by id: -device virtio-blk-device,id=<blk_id>,drive=<drive_id>; \
                    \n\t\tset pflash drive image: -drive file=<pflash_path>,if=pflash,unit=0|1[,readonly=true|false]; \
                    \n\t\tset scsi drive image: -drive id=<drive-scsi0-0-0-0>,file=<path_on_host>[,readonly=true|false]")
             .takes_values(true),
@@ -206,12 +266,20 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .help("configure a host TAP network with ID 'str'")
             .takes_values(true),
         )
+        .arg(
+            Arg::with_name("fsdev")
+            .multiple(true)
+            .long("fsdev")
+            .value_name("local,id=<str>,path=<dir>,security_model=none[,readonly=on|off]")
+            .help("share host directory 'dir' with guest through a virtio-9p device")
+            .takes_values(true),
+        )
         .arg(
             Arg::with_name("chardev")
             .multiple(true)
             .long("chardev")
-            .value_name("socket,id=<str>,path=<socket_path>")
-            .help("set char device virtio console for vm")
+            .value_name("socket,id=<str>,path=<socket_path>|host=<str>,port=<port>[,server][,nowait] or pty|file|null,id=<str>[,path=<file_path>]")
+            .help("set char device for serial, virtio console and other devices of vm")
             .takes_values(true),
         )
         .arg(
@@ -226,12 +294,13 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                    \n\t\tadd virtio pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
                    \n\t\tadd vhost mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
                    \n\t\tadd vhost pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
-                   \n\t\tadd virtio mmio console: -device virtio-serial-device[,id=<virtio-serial0>] -device virtconsole,id=console_id,chardev=<virtioconsole1>; \
+                   \n\t\tadd virtio mmio console: -device virtio-serial-device[,id=<virtio-serial0>] -device virtconsole|virtio-console,id=console_id,chardev=<virtioconsole1>; \
                    \n\t\tadd virtio pci console: -device virtio-serial-pci,id=<virtio-serial0>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off] -device virtconsole,id=<console_id>,chardev=<virtioconsole1>; \
                    \n\t\tadd vhost mmio vsock: -device vhost-vsock-device,id=<vsock_id>,guest-cid=<N>; \
                    \n\t\tadd vhost pci vsock: -device vhost-vsock-pci,id=<vsock_id>,guest-cid=<N>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off]; \
                    \n\t\tadd virtio mmio balloon: -device virtio-balloon-device[,deflate-on-oom=true|false][,free-page-reporting=true|false]; \
                    \n\t\tadd virtio pci balloon: -device virtio-balloon-pci,id=<balloon_id>,bus=<pcie.0>,addr=<0x4>[,deflate-on-oom=true|false][,free-page-reporting=true|false][,multifunction=on|off]; \
+                   \n\t\tadd virtio mmio 9p: -device virtio-9p-device,id=<9p_id>,fsdev=<fsdev_id>,mount_tag=<tag>; \
                    \n\t\tadd virtio mmio rng: -device virtio-rng-device,rng=<objrng0>,max-bytes=<1234>,period=<1000>; \
                    \n\t\tadd virtio pci rng: -device virtio-rng-pci,id=<rng_id>,rng=<objrng0>,max-bytes=<1234>,period=<1000>,bus=<pcie.0>,addr=<0x1>[,multifunction=on|off]; \
                    \n\t\tadd pcie root port: -device pcie-root-port,id=<pcie.1>,port=<0x1>,bus=<pcie.0>,addr=<0x1>[,multifunction=on|off]; \
@@ -247,18 +316,39 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("serial")
             .long("serial")
+            .value_name("stdio|pty|null|file:<path>|unix:<path>[,server][,nowait]|tcp:[<host>]:<port>[,server][,nowait] or chardev:<char_id>")
+            .help("add serial and set chardev for it, the long form backend[,path=<str>,server,nowait] is also accepted")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cpu-affinity")
+            .long("cpu-affinity")
+            .value_name("<host cpu list> or vcpu<n>=<host cpu>[;<iothread id>=<host cpu>]")
+            .help("pin vcpus, and optionally iothreads, to host cpus")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sbi-console")
+            .long("sbi-console")
             .value_name("backend[,path=<str>,server,nowait] or chardev:<char_id>")
-            .help("add serial and set chardev for it")
+            .help("set dedicated chardev for SBI debug console, which shares the serial's by default")
             .takes_value(true),
         )
         .arg(
             Arg::with_name("display log")
             .long("D")
-            .value_name("[log path]")
-            .help("output log to logfile (default stderr)")
+            .value_name("[log path][,rotate-size=<size>][,rotate-keep=<num>] | syslog[:ident=<ident>]")
+            .help("output log to logfile, or to stdout without path. If not given, log goes to televm-<pid>.log in TELEVM_LOG_DIR, or stderr without it. The logfile is rotated to <log path>.1..<num> (5 by default) before it exceeds rotate-size, and reopened on SIGHUP. 'syslog' sends log to /dev/log tagged by ident ('televm' by default), or to stderr if it's unavailable")
             .takes_value(true)
             .can_no_value(true),
         )
+        .arg(
+            Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("<[module=]level,...>")
+            .help("set log level globally or per module, such as 'virtio=debug,sysbus=trace,info'. TELEVM_LOG is used if not given")
+            .takes_value(true),
+        )
         .arg(
             Arg::with_name("pidfile")
             .long("pidfile")
@@ -288,7 +378,29 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .value_name("<parameters>")
             .help("\n\t\tdo the migration using tcp socket: -incoming tcp:<ip>:<port>; \
                    \n\t\tdo the migration using unix socket: -incoming unix:<socket path>; \
-                   \n\t\tdo the virtual machine snapshot: -incoming file:<file path>")
+                   \n\t\tdo the virtual machine snapshot: -incoming file:<file path>; \
+                   \n\t\twait for snapshot-load with vcpus paused: -incoming defer")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gdb")
+            .long("gdb")
+            .value_name("tcp:[<ip>]:<port>")
+            .help("wait for gdb connection on the tcp address, e.g. -gdb tcp::1234")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watchdog-action")
+            .long("watchdog-action")
+            .value_name("reset|poweroff|pause|none")
+            .help("action taken when the watchdog expires, reset by default")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("action")
+            .long("action")
+            .value_name("[reboot=<reset|shutdown>][,shutdown=<poweroff|pause>][,panic=<pause|shutdown|exit-failure|none>][,watchdog=<action>][,powerdown=<force-off|none>][,powerdown-timeout=<secs>]")
+            .help("actions taken on guest events, guest panic is only reported by default. '-no-reboot' is the same as 'reboot=shutdown', and '-no-shutdown' is the same as 'shutdown=pause'")
             .takes_value(true),
         )
         .arg(
@@ -297,16 +409,24 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .long("object")
             .value_name("<parameters>")
             .help("\n\t\tadd memory backend ram object: -object memory-backend-ram,id=<memid>,size=<2G>,host-nodes=<0-1>,policy=<bind>; \
+                   \n\t\tadd memfd memory backend backing guest RAM: -object memory-backend-memfd,id=<memid>,size=<2G>[,share=<on|off>][,seal=<on|off>]; \
                    \n\t\tadd iothread object: -object iothread,id=<iothread_id>; \
                    \n\t\tadd rng object: -object rng-random,id=<rng_id>,filename=<file_path>; \
                    \n\t\tadd vnc tls object: -object tls-creds-x509,id=<vnc_id>,dir=</etc/pki/vnc>; \
                    \n\t\tadd authz object: -object authz-simple,id=<authz_id>,identity=<username>")
             .takes_values(true),
         )
+        .arg(
+            Arg::with_name("monitor")
+            .long("monitor")
+            .value_name("unix:<socket_path>|tcp:<host>:<port>,server,nowait[,allow-remote][,max-cmd-size=<bytes>]")
+            .help("set human monitor's socket, it accepts line-oriented commands, type 'help' for the list")
+            .takes_value(true),
+        )
         .arg(
             Arg::with_name("mon")
             .long("mon")
-            .value_name("chardev=<chardev_id>,id=<mon_id>[,mode=control]")
+            .value_name("chardev=<chardev_id>,id=<mon_id>[,mode=control|readline]")
             .help("-mon is another way to create qmp channel. To use it, the chardev should be specified")
             .takes_value(true),
         )
@@ -340,14 +460,6 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .can_no_value(true)
             .takes_value(true),
         )
-        .arg(
-            Arg::with_name("sandbox")
-            .long("sandbox")
-            .value_name("[on,obsolete=deny]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
         .arg(
             Arg::with_name("msg")
             .long("msg")
@@ -356,20 +468,54 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .can_no_value(true)
             .takes_value(true),
         )
+        .arg(
+            Arg::with_name("sandbox")
+            .long("sandbox")
+            .value_name("on|off[,obsolete=allow|deny][,debug=on|off]")
+            .help("confine threads to the syscalls they need by seccomp once devices are realized, others kill the vm, or are only logged to audit log with debug=on")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("runas")
+            .long("runas")
+            .value_name("user[:group]")
+            .help("switch to user and group once devices are realized, before vcpus start; the log file and the directories of sockets must be writable by them")
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("chroot")
+            .long("chroot")
+            .value_name("dir")
+            .help("change root directory to dir once devices are realized, paths given later by qmp such as images of blockdev-add are resolved inside it")
+            .takes_value(true),
+        )
         .arg(
             Arg::with_name("rtc")
             .long("rtc")
-            .value_name("[base=utc]")
-            .hidden(true)
-            .can_no_value(true)
+            .value_name("[base=utc|localtime|<datetime>][,clock=host|vm]")
+            .help("set the initial time of rtc, datetime is in format 2006-06-17T16:01:21 of utc; clock vm (default) stops rtc while vm is paused, host keeps it following host time")
             .takes_value(true),
         )
+        .arg(
+            Arg::with_name("boottime")
+            .long("boottime")
+            .help("record boot milestones, printed at exit and queried by QMP query-boot-time")
+            .takes_value(false)
+            .required(false),
+        )
+        .arg(
+            Arg::with_name("no-reboot")
+            .long("no-reboot")
+            .help("exit with code 3 instead of rebooting when guest requests reboot")
+            .takes_value(false)
+            .required(false),
+        )
         .arg(
             Arg::with_name("no-shutdown")
             .long("no-shutdown")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
+            .help("pause instead of exiting when guest shuts down, so the state can be inspected; SHUTDOWN event is marked resumable, and the machine can be resumed by cont or reset by system_reset")
+            .takes_value(false)
+            .required(false),
         )
         .arg(
             Arg::with_name("boot")
@@ -411,10 +557,10 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         )
         .arg(
             Arg::with_name("trace")
-            .multiple(false)
+            .multiple(true)
             .long("trace")
-            .value_name("events=<file>")
-            .help("specify the file lists trace events to enable")
+            .value_name("events=<file>|sysbus:<type>")
+            .help("specify the file lists trace events to enable, or the type of sysbus device whose MMIO accesses are traced")
             .takes_value(true),
         )
         .arg(
@@ -450,9 +596,6 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
 /// Input arguments is illegal for `VmConfig` or `VmConfig`'s health check
 /// failed -- with this unhealthy `VmConfig`, VM will not boot successfully.
 pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
-    // Parse config-file json.
-    // VmConfig can be transformed by json file which described VmConfig
-    // directly.
     let mut vm_cfg = VmConfig::default();
 
     // Parse cmdline args which need to set in VmConfig
@@ -460,12 +603,41 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
     add_args_to_config!((args.value_of("machine")), vm_cfg, add_machine);
     add_args_to_config!((args.value_of("memory")), vm_cfg, add_memory);
     add_args_to_config!((args.value_of("mem-path")), vm_cfg, add_mem_path);
+    add_args_to_config!(
+        (args.value_of("mem-prealloc-threads")),
+        vm_cfg,
+        add_mem_prealloc_threads
+    );
+    add_args_to_config!(
+        (args.value_of("realize-threads")),
+        vm_cfg,
+        add_realize_threads
+    );
     add_args_to_config!((args.value_of("smp")), vm_cfg, add_cpu);
     add_args_to_config!((args.value_of("cpu")), vm_cfg, add_cpu_feature);
     add_args_to_config!((args.value_of("kernel")), vm_cfg, add_kernel);
+    let bios = bios_setting(args);
+    if let Some(path) = bios.value.as_deref().filter(|path| *path != "none") {
+        if bios.source == SettingSource::Env && !std::path::Path::new(path).is_file() {
+            bail!("{} {} is not a regular file", BIOS_PATH_ENV, path);
+        }
+        vm_cfg.add_bios(path)?;
+    }
+    add_args_to_config!((args.value_of("dtb")), vm_cfg, add_dtb);
     add_args_to_config!((args.value_of("initrd-file")), vm_cfg, add_initrd);
     //add_args_to_config!((args.value_of("serial")), vm_cfg, add_serial);
     add_args_to_config!((args.value_of("incoming")), vm_cfg, add_incoming);
+    add_args_to_config!((args.value_of("gdb")), vm_cfg, add_gdb);
+    add_args_to_config!(
+        (args.value_of("watchdog-action")),
+        vm_cfg,
+        add_watchdog_action
+    );
+    add_args_to_config!((args.value_of("action")), vm_cfg, add_action);
+    add_args_to_config!((args.value_of("rtc")), vm_cfg, add_rtc);
+    add_args_to_config!((args.value_of("sandbox")), vm_cfg, add_sandbox);
+    add_args_to_config!((args.value_of("runas")), vm_cfg, add_runas);
+    add_args_to_config!((args.value_of("chroot")), vm_cfg, add_chroot);
    // add_args_to_config!((args.value_of("vnc")), vm_cfg, add_vnc);
     add_args_to_config!(
         (args.is_present("mem-prealloc")),
@@ -473,6 +645,24 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
         enable_mem_prealloc,
         bool
     );
+    add_args_to_config!(
+        (args.is_present("dtb-no-fixup")),
+        vm_cfg,
+        enable_dtb_no_fixup,
+        bool
+    );
+    add_args_to_config!(
+        (args.is_present("no-reboot")),
+        vm_cfg,
+        enable_no_reboot,
+        bool
+    );
+    add_args_to_config!(
+        (args.is_present("no-shutdown")),
+        vm_cfg,
+        enable_no_shutdown,
+        bool
+    );
     add_args_to_config!(
         (args.values_of("kernel-cmdline")),
         vm_cfg,
@@ -481,26 +671,85 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
     );
     add_args_to_config_multi!((args.values_of("drive")), vm_cfg, add_drive);
     add_args_to_config_multi!((args.values_of("object")), vm_cfg, add_object);
+    add_args_to_config!((args.value_of("cpu-affinity")), vm_cfg, add_cpu_affinity);
     add_args_to_config_multi!((args.values_of("netdev")), vm_cfg, add_netdev);
     add_args_to_config_multi!((args.values_of("chardev")), vm_cfg, add_chardev);
+    add_args_to_config_multi!((args.values_of("fsdev")), vm_cfg, add_fsdev);
     add_args_to_config!((args.value_of("serial")), vm_cfg, add_serial);
+    add_args_to_config!((args.value_of("sbi-console")), vm_cfg, add_sbi_console);
     add_args_to_config_multi!((args.values_of("device")), vm_cfg, add_device);
     add_args_to_config_multi!((args.values_of("global")), vm_cfg, add_global_config);
 
-    if let Some(s) = args.value_of("trace") {
-        add_trace_events(&s)?;
+    if let Some(traces) = args.values_of("trace") {
+        for s in traces {
+            add_trace_events(&s)?;
+        }
     }
 
     // Check the mini-set for Vm to start is ok
     if vm_cfg.machine_config.mach_type != MachineType::None {
         vm_cfg
-            .check_vmconfig(args.is_present("daemonize"))
+            .validate()
+            .and_then(|_| vm_cfg.check_vmconfig(args.is_present("daemonize")))
             .with_context(|| "Precheck failed, VmConfig is unhealthy, stop running")?;
     }
     Ok(vm_cfg)
 }
 
-/// This function is to parse qmp socket path and type.
+/// Firmware given by `-bios` or `TELEVM_BIOS_PATH`.
+pub fn bios_setting(args: &ArgMatches) -> Setting {
+    Setting::resolve(args.value_of("bios"), BIOS_PATH_ENV)
+}
+
+/// Log file given by `-D`, or placed in the directory given by `TELEVM_LOG_DIR`.
+pub fn log_setting(args: &ArgMatches) -> Setting {
+    Setting::resolve(args.value_of("display log"), LOG_DIR_ENV)
+        .map_env(|dir| log_file_in_dir(&dir, std::process::id()))
+}
+
+/// Log filter given by `-log-level` or `TELEVM_LOG`.
+pub fn log_filter_setting(args: &ArgMatches) -> Setting {
+    Setting::resolve(args.value_of("log-level"), LOG_FILTER_ENV)
+}
+
+/// QMP socket given by `-qmp` or `TELEVM_QMP_DEFAULT`. The default isn't used if
+/// a monitor is given by `-mon`.
+pub fn qmp_setting(args: &ArgMatches) -> Setting {
+    if args.value_of("qmp").is_none() && args.value_of("mon").is_some() {
+        return Setting {
+            value: None,
+            source: SettingSource::Cli,
+        };
+    }
+    Setting::resolve(args.value_of("qmp"), QMP_DEFAULT_ENV)
+}
+
+/// Merge the config file given by `-config` into `args`, the arguments given on
+/// the command line take precedence over the file.
+///
+/// # Arguments
+///
+/// * `args` - The structure accepted input cmdline arguments.
+pub fn merge_config_file(args: &mut ArgMatches) -> Result<()> {
+    if let Some(path) = args.value_of("config") {
+        for file_arg in parse_config_file(&path)? {
+            args.supply_value(file_arg.arg, file_arg.value, &file_arg.key)
+                .with_context(|| format!("Invalid config file key '{}'", file_arg.key))?;
+        }
+    }
+    Ok(())
+}
+
+/// Point out the config file key of `value` in the error of using it, if the
+/// value comes from the config file.
+fn with_origin<T>(args: &ArgMatches, arg_name: &str, value: &str, result: Result<T>) -> Result<T> {
+    match args.origin_of(arg_name, value) {
+        Some(key) => result.with_context(|| format!("Invalid config file key '{}'", key)),
+        None => result,
+    }
+}
+
+/// This function is to parse qmp socket path or tcp address and type.
 ///
 /// # Arguments
 ///
@@ -509,26 +758,39 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
 /// # Errors
 ///
 /// The value of `qmp` is illegel.
-pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<Vec<UnixListener>> {
+pub fn check_api_channel(
+    args: &ArgMatches,
+    vm_config: &mut VmConfig,
+) -> Result<Vec<(SocketListener, MonitorMode, SocketLimits)>> {
     let mut sock_paths = Vec::new();
-    if let Some(qmp_config) = args.value_of("qmp") {
-        let mut cmd_parser = CmdParser::new("qmp");
-        cmd_parser.push("").push("server").push("nowait");
-
-        cmd_parser.parse(&qmp_config)?;
-        if let Some(uri) = cmd_parser.get_value::<String>("")? {
-            let api_path =
-                parse_unix_uri(&uri).with_context(|| "Failed to parse qmp socket path")?;
-            sock_paths.push(api_path);
-        } else {
-            bail!("No uri found for qmp");
-        }
-        if cmd_parser.get_value::<String>("server")?.is_none() {
-            bail!("Argument \'server\' is needed for qmp");
-        }
-        if cmd_parser.get_value::<String>("nowait")?.is_none() {
-            bail!("Argument \'nowait\' is needed for qmp");
-        }
+    let mut tcp_addrs = Vec::new();
+    let qmp = qmp_setting(args);
+    if let Some(qmp_config) = qmp.value {
+        let result = parse_api_config("qmp", &qmp_config);
+        let (addr, limits) = match qmp.source {
+            SettingSource::Env => result.with_context(|| format!("Invalid {}", QMP_DEFAULT_ENV)),
+            _ => with_origin(args, "qmp", &qmp_config, result),
+        }?;
+        addr.push_to(
+            MonitorMode::Control,
+            limits,
+            &mut sock_paths,
+            &mut tcp_addrs,
+        );
+    }
+    if let Some(monitor_config) = args.value_of("monitor") {
+        let (addr, limits) = with_origin(
+            args,
+            "monitor",
+            &monitor_config,
+            parse_api_config("monitor", &monitor_config),
+        )?;
+        addr.push_to(
+            MonitorMode::Readline,
+            limits,
+            &mut sock_paths,
+            &mut tcp_addrs,
+        );
     }
     if let Some(mon_config) = args.value_of("mon") {
         let mut cmd_parser = CmdParser::new("monitor");
@@ -542,13 +804,14 @@ pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<
             bail!("Argument \'chardev\'  is missing for \'mon\'");
         };
 
-        if let Some(mode) = cmd_parser.get_value::<String>("mode")? {
-            if mode != *"control" {
-                bail!("Invalid \'mode\' parameter: {:?} for monitor", &mode);
+        let mode = match cmd_parser.get_value::<String>("mode")?.as_deref() {
+            Some("control") => MonitorMode::Control,
+            Some("readline") => MonitorMode::Readline,
+            Some(mode) => bail!("Invalid \'mode\' parameter: {:?} for monitor", mode),
+            None => {
+                bail!("Argument \'mode\' of \'mon\' should be set to \'control\' or \'readline\'.")
             }
-        } else {
-            bail!("Argument \'mode\' of \'mon\' should be set to \'control\'.");
-        }
+        };
 
         if let Some(cfg) = vm_config.chardev.remove(&chardev) {
             if let ChardevType::Socket {
@@ -563,7 +826,7 @@ pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<
                         path
                     );
                 }
-                sock_paths.push(path);
+                sock_paths.push((path, mode, SocketLimits::default()));
             } else {
                 bail!("Only socket-type of chardev can be used for monitor");
             }
@@ -572,20 +835,125 @@ pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<
         }
     }
 
-    if sock_paths.is_empty() {
-        bail!("Please use \'-qmp\' or \'-mon\' to give a qmp path for Unix socket");
+    if sock_paths.is_empty() && tcp_addrs.is_empty() {
+        bail!(
+            "Please use \'-qmp\', \'-mon\' or \'-monitor\' to give a path for Unix or Tcp socket"
+        );
     }
     let mut listeners = Vec::new();
-    for path in sock_paths {
-        listeners.push(
-            bind_socket(path.clone())
-                .with_context(|| format!("Failed to bind socket for path: {:?}", &path))?,
-        )
+    for (path, mode, limits) in sock_paths {
+        listeners.push((
+            SocketListener::Unix(
+                bind_socket(path.clone())
+                    .with_context(|| format!("Failed to bind socket for path: {:?}", &path))?,
+            ),
+            mode,
+            limits,
+        ))
+    }
+    for (addr, mode, limits) in tcp_addrs {
+        // Std sets SO_REUSEADDR on the listener, so restarting on the same
+        // port does not fail with sockets left in TIME_WAIT.
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to bind tcp socket for address: {}", addr))?;
+        listeners.push((SocketListener::Tcp(listener), mode, limits));
     }
 
     Ok(listeners)
 }
 
+/// Address of an api socket given by `-qmp` or `-monitor`.
+enum ApiAddr {
+    Unix(String),
+    Tcp(SocketAddr),
+}
+
+impl ApiAddr {
+    fn push_to(
+        self,
+        mode: MonitorMode,
+        limits: SocketLimits,
+        sock_paths: &mut Vec<(String, MonitorMode, SocketLimits)>,
+        tcp_addrs: &mut Vec<(SocketAddr, MonitorMode, SocketLimits)>,
+    ) {
+        match self {
+            ApiAddr::Unix(path) => sock_paths.push((path, mode, limits)),
+            ApiAddr::Tcp(addr) => tcp_addrs.push((addr, mode, limits)),
+        }
+    }
+}
+
+/// Parse `unix:<socket_path>|tcp:<host>:<port>,server,nowait[,allow-remote]`
+/// `[,max-cmd-size=<bytes>]` given by option `name`, return the address and
+/// the limits on the input of its clients.
+fn parse_api_config(name: &str, config: &str) -> Result<(ApiAddr, SocketLimits)> {
+    let mut cmd_parser = CmdParser::new(name);
+    cmd_parser
+        .push("")
+        .push("server")
+        .push("nowait")
+        .push("allow-remote")
+        .push("max-cmd-size");
+
+    cmd_parser.parse(config)?;
+    let allow_remote = cmd_parser.get_value::<String>("allow-remote")?.is_some();
+    let addr = if let Some(uri) = cmd_parser.get_value::<String>("")? {
+        if uri.starts_with("tcp:") {
+            let addr = parse_tcp_uri(&uri, allow_remote)
+                .with_context(|| format!("Failed to parse {} tcp address", name))?;
+            ApiAddr::Tcp(addr)
+        } else if allow_remote {
+            bail!("Argument \'allow-remote\' is only for tcp {}", name);
+        } else {
+            let api_path = parse_unix_uri(&uri)
+                .with_context(|| format!("Failed to parse {} socket path", name))?;
+            ApiAddr::Unix(api_path)
+        }
+    } else {
+        bail!("No uri found for {}", name);
+    };
+    if cmd_parser.get_value::<String>("server")?.is_none() {
+        bail!("Argument \'server\' is needed for {}", name);
+    }
+    if cmd_parser.get_value::<String>("nowait")?.is_none() {
+        bail!("Argument \'nowait\' is needed for {}", name);
+    }
+    let mut limits = SocketLimits::default();
+    if let Some(size) = cmd_parser.get_value::<usize>("max-cmd-size")? {
+        if size == 0 {
+            bail!("Argument \'max-cmd-size\' of {} should be positive", name);
+        }
+        limits.max_command_size = size;
+    }
+    Ok((addr, limits))
+}
+
+/// Parse `tcp:<host>:<port>` to the address to listen on. An empty host
+/// means all interfaces, only loopback address is allowed without `allow_remote`.
+fn parse_tcp_uri(uri: &str, allow_remote: bool) -> Result<SocketAddr> {
+    let (host, port) = uri
+        .strip_prefix("tcp:")
+        .and_then(|addr| addr.rsplit_once(':'))
+        .with_context(|| format!("Invalid tcp uri: {}", uri))?;
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("Invalid port in tcp uri: {}", uri))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host = if host.is_empty() { "0.0.0.0" } else { host };
+    let addr = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve host: {}", host))?
+        .next()
+        .with_context(|| format!("No address found for host: {}", host))?;
+    if !allow_remote && !addr.ip().is_loopback() {
+        bail!(
+            "Tcp qmp address {} is not loopback, \'allow-remote\' is needed",
+            addr
+        );
+    }
+    Ok(addr)
+}
+
 fn bind_socket(path: String) -> Result<UnixListener> {
     let listener = UnixListener::bind(&path)
         .with_context(|| format!("Failed to bind socket file {}", &path))?;
@@ -595,3 +963,138 @@ fn bind_socket(path: String) -> Result<UnixListener> {
         .with_context(|| format!("Failed to limit permission for socket file {}", &path))?;
     Ok(listener)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tcp_uri() {
+        let addr = parse_tcp_uri("tcp:127.0.0.1:4444", false).unwrap();
+        assert_eq!(addr, "127.0.0.1:4444".parse().unwrap());
+        let addr = parse_tcp_uri("tcp:[::1]:4444", false).unwrap();
+        assert_eq!(addr, "[::1]:4444".parse().unwrap());
+
+        // Remote address needs allow-remote.
+        assert!(parse_tcp_uri("tcp::4444", false).is_err());
+        assert!(parse_tcp_uri("tcp:10.0.0.1:4444", false).is_err());
+        let addr = parse_tcp_uri("tcp::4444", true).unwrap();
+        assert_eq!(addr, "0.0.0.0:4444".parse().unwrap());
+
+        assert!(parse_tcp_uri("tcp:127.0.0.1", false).is_err());
+        assert!(parse_tcp_uri("tcp:127.0.0.1:port", false).is_err());
+        assert!(parse_tcp_uri("unix:/tmp/qmp.sock", false).is_err());
+    }
+
+    #[test]
+    fn test_merge_config_file() {
+        let kernel = std::env::temp_dir().join("test_merge_config_file.kernel");
+        std::fs::File::create(&kernel).unwrap();
+        let config = std::env::temp_dir().join("test_merge_config_file.json");
+        let cmd_args = |extra: &[&str]| {
+            let mut cmd_args = vec!["televm", "-config", config.to_str().unwrap()];
+            cmd_args.extend_from_slice(extra);
+            let cmd_args: Vec<String> = cmd_args.iter().map(|arg| arg.to_string()).collect();
+            let mut args = create_args_parser().get_matches_from(&cmd_args).unwrap();
+            merge_config_file(&mut args).map(|_| args)
+        };
+
+        std::fs::write(
+            &config,
+            serde_json::json!({
+                "name": "vm-file",
+                "memory": "1G",
+                "cpus": 2,
+                "kernel": kernel,
+                "drives": [{"id": "rootfs", "file": "/path/to/rootfs"}],
+                "devices": [{"driver": "virtio-blk-device", "id": "blk0", "drive": "rootfs"}],
+                "qmp": "unix:/tmp/file.sock,server,nowait"
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let args = cmd_args(&[
+            "-m",
+            "2G",
+            "-drive",
+            "id=data,file=/path/to/data",
+            "-qmp",
+            "unix:/tmp/cli.sock,server,nowait",
+        ])
+        .unwrap();
+        // Single value arguments are overridden, multiple ones are added.
+        assert_eq!(args.value_of("name").unwrap(), "vm-file");
+        assert_eq!(args.value_of("memory").unwrap(), "2G");
+        assert_eq!(
+            args.value_of("qmp").unwrap(),
+            "unix:/tmp/cli.sock,server,nowait"
+        );
+        assert_eq!(
+            args.values_of("drive").unwrap(),
+            vec![
+                "file=/path/to/rootfs,id=rootfs",
+                "id=data,file=/path/to/data"
+            ]
+        );
+        assert_eq!(
+            args.origin_of("drive", "file=/path/to/rootfs,id=rootfs"),
+            Some("drives[0]")
+        );
+        assert_eq!(args.origin_of("drive", "id=data,file=/path/to/data"), None);
+        assert_eq!(args.origin_of("memory", "1G"), None);
+
+        let vm_cfg = create_vmconfig(&args).unwrap();
+        assert_eq!(vm_cfg.guest_name, "vm-file");
+        assert_eq!(vm_cfg.machine_config.mem_config.mem_size, 2 << 30);
+        assert_eq!(vm_cfg.machine_config.nr_cpus, 2);
+        assert!(vm_cfg.drives.contains_key("rootfs"));
+        assert!(vm_cfg.drives.contains_key("data"));
+        assert!(vm_cfg
+            .to_json()
+            .unwrap()
+            .contains("\"guest_name\": \"vm-file\""));
+
+        // Errors point out the key of the config file.
+        std::fs::write(
+            &config,
+            serde_json::json!({
+                "kernel": kernel,
+                "drives": [
+                    {"id": "rootfs", "file": "/path/to/rootfs"},
+                    {"id": "rootfs", "file": "/path/to/data"}
+                ]
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let err = create_vmconfig(&cmd_args(&[]).unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid config file key 'drives[1]'");
+        std::fs::write(&config, r#"{"memory": "1G", "smp": 2}"#).unwrap();
+        let err = cmd_args(&[]).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "Invalid config file key 'smp': unknown key"
+        );
+        std::fs::write(&config, r#"{"daemonize": true, "pidfile": 1"#).unwrap();
+        assert!(cmd_args(&[]).is_err());
+
+        std::fs::remove_file(kernel).unwrap();
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn test_parse_api_config_limits() {
+        let (_, limits) = parse_api_config("qmp", "unix:/tmp/qmp.sock,server,nowait").unwrap();
+        assert_eq!(limits, SocketLimits::default());
+        let (_, limits) =
+            parse_api_config("qmp", "unix:/tmp/qmp.sock,server,nowait,max-cmd-size=4096").unwrap();
+        assert_eq!(limits.max_command_size, 4096);
+
+        assert!(
+            parse_api_config("qmp", "unix:/tmp/qmp.sock,server,nowait,max-cmd-size=0").is_err()
+        );
+        assert!(
+            parse_api_config("qmp", "unix:/tmp/qmp.sock,server,nowait,max-cmd-size=1M").is_err()
+        );
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{CmdParser, VmConfig};
+
+/// Action taken when guest panics, given by `-action panic=<action>`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PanicAction {
+    /// Pause the machine, so that guest can be inspected.
+    Pause,
+    /// Power off the machine as `-action shutdown` says, process exits with guest
+    /// failure.
+    Shutdown,
+    /// Power off the machine and exit with guest failure, even with
+    /// `-action shutdown=pause`.
+    ExitFailure,
+    /// Only report the panic, guest handles it itself.
+    None,
+}
+
+impl Default for PanicAction {
+    fn default() -> Self {
+        PanicAction::None
+    }
+}
+
+impl PanicAction {
+    /// Name of the action used by cmdline and the GUEST_PANICKED QMP event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PanicAction::Pause => "pause",
+            PanicAction::Shutdown => "shutdown",
+            PanicAction::ExitFailure => "exit-failure",
+            PanicAction::None => "none",
+        }
+    }
+}
+
+impl FromStr for PanicAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pause" => Ok(PanicAction::Pause),
+            "shutdown" => Ok(PanicAction::Shutdown),
+            "exit-failure" => Ok(PanicAction::ExitFailure),
+            "none" => Ok(PanicAction::None),
+            _ => Err(anyhow!(
+                "Invalid panic action {}, must be one of pause, shutdown, exit-failure or none",
+                s
+            )),
+        }
+    }
+}
+
+/// Action taken when guest reboots, given by `-action reboot=<action>`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RebootAction {
+    /// Reset the machine.
+    Reset,
+    /// Shut down the machine instead, the same as `-no-reboot`. Process exits
+    /// with `VM_EXIT_GUEST_REBOOT`, which tells it from guest shutdown.
+    Shutdown,
+}
+
+impl Default for RebootAction {
+    fn default() -> Self {
+        RebootAction::Reset
+    }
+}
+
+impl RebootAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RebootAction::Reset => "reset",
+            RebootAction::Shutdown => "shutdown",
+        }
+    }
+}
+
+impl FromStr for RebootAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reset" => Ok(RebootAction::Reset),
+            "shutdown" => Ok(RebootAction::Shutdown),
+            _ => Err(anyhow!(
+                "Invalid reboot action {}, must be one of reset or shutdown",
+                s
+            )),
+        }
+    }
+}
+
+/// Action taken when guest shuts down, given by `-action shutdown=<action>`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ShutdownAction {
+    /// Power off the machine, process exits with the code set by guest.
+    Poweroff,
+    /// Pause the machine so the state can be inspected, the same as `-no-shutdown`.
+    Pause,
+}
+
+impl Default for ShutdownAction {
+    fn default() -> Self {
+        ShutdownAction::Poweroff
+    }
+}
+
+impl ShutdownAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShutdownAction::Poweroff => "poweroff",
+            ShutdownAction::Pause => "pause",
+        }
+    }
+}
+
+impl FromStr for ShutdownAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "poweroff" => Ok(ShutdownAction::Poweroff),
+            "pause" => Ok(ShutdownAction::Pause),
+            _ => Err(anyhow!(
+                "Invalid shutdown action {}, must be one of poweroff or pause",
+                s
+            )),
+        }
+    }
+}
+
+/// Seconds guest is given to power down by itself by default.
+pub const DEFAULT_POWERDOWN_TIMEOUT: u64 = 30;
+
+/// Action taken when guest ignores `system_powerdown`, given by `-action powerdown=<action>`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PowerdownAction {
+    /// Power off the machine as `quit` does.
+    ForceOff,
+    /// Leave it to guest.
+    None,
+}
+
+impl PowerdownAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerdownAction::ForceOff => "force-off",
+            PowerdownAction::None => "none",
+        }
+    }
+}
+
+impl FromStr for PowerdownAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "force-off" => Ok(PowerdownAction::ForceOff),
+            "none" => Ok(PowerdownAction::None),
+            _ => Err(anyhow!(
+                "Invalid powerdown action {}, must be one of force-off or none",
+                s
+            )),
+        }
+    }
+}
+
+/// Fallback of `system_powerdown`, taken if vm is still running after the timeout.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PowerdownConfig {
+    pub action: PowerdownAction,
+    /// Seconds from pressing power button, given by `-action powerdown-timeout=<secs>`.
+    pub timeout: u64,
+}
+
+impl Default for PowerdownConfig {
+    fn default() -> Self {
+        PowerdownConfig {
+            action: PowerdownAction::None,
+            timeout: DEFAULT_POWERDOWN_TIMEOUT,
+        }
+    }
+}
+
+impl VmConfig {
+    /// Set actions taken on guest events, e.g. `-action panic=pause,watchdog=none`.
+    pub fn add_action(&mut self, action_config: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::new("action");
+        cmd_parser
+            .push("reboot")
+            .push("shutdown")
+            .push("panic")
+            .push("watchdog")
+            .push("powerdown")
+            .push("powerdown-timeout");
+        cmd_parser.parse(action_config)?;
+
+        if let Some(reboot) = cmd_parser.get_value::<String>("reboot")? {
+            self.reboot_action = reboot.parse()?;
+        }
+        if let Some(shutdown) = cmd_parser.get_value::<String>("shutdown")? {
+            self.shutdown_action = shutdown.parse()?;
+        }
+        if let Some(panic) = cmd_parser.get_value::<String>("panic")? {
+            self.panic_action = panic.parse()?;
+        }
+        if let Some(watchdog) = cmd_parser.get_value::<String>("watchdog")? {
+            self.watchdog_action = watchdog.parse()?;
+        }
+        if let Some(powerdown) = cmd_parser.get_value::<String>("powerdown")? {
+            self.powerdown.action = powerdown.parse()?;
+        }
+        if let Some(timeout) = cmd_parser.get_value::<u64>("powerdown-timeout")? {
+            if timeout == 0 {
+                bail!("powerdown-timeout must be greater than 0");
+            }
+            self.powerdown.timeout = timeout;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WatchdogAction;
+
+    #[test]
+    fn test_add_action() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.panic_action, PanicAction::None);
+
+        for action in ["pause", "shutdown", "exit-failure", "none"] {
+            assert!(vm_config.add_action(&format!("panic={}", action)).is_ok());
+            assert_eq!(vm_config.panic_action.name(), action);
+        }
+        assert!(vm_config
+            .add_action("panic=pause,watchdog=poweroff")
+            .is_ok());
+        assert_eq!(vm_config.panic_action, PanicAction::Pause);
+        assert_eq!(vm_config.watchdog_action, WatchdogAction::Poweroff);
+
+        assert!(vm_config.add_action("panic=reset").is_err());
+        assert!(vm_config.add_action("crash=shutdown").is_err());
+        assert!(vm_config.add_action("pause").is_err());
+        assert_eq!(vm_config.panic_action, PanicAction::Pause);
+    }
+
+    #[test]
+    fn test_reboot_shutdown_action() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.reboot_action, RebootAction::Reset);
+        assert_eq!(vm_config.shutdown_action, ShutdownAction::Poweroff);
+
+        assert!(vm_config
+            .add_action("reboot=shutdown,shutdown=pause,panic=exit-failure")
+            .is_ok());
+        assert_eq!(vm_config.reboot_action, RebootAction::Shutdown);
+        assert_eq!(vm_config.shutdown_action, ShutdownAction::Pause);
+        assert_eq!(vm_config.panic_action, PanicAction::ExitFailure);
+        assert!(vm_config
+            .add_action("reboot=reset,shutdown=poweroff")
+            .is_ok());
+        assert_eq!(vm_config.reboot_action.name(), "reset");
+        assert_eq!(vm_config.shutdown_action.name(), "poweroff");
+
+        assert!(vm_config.add_action("reboot=pause").is_err());
+        assert!(vm_config.add_action("shutdown=reset").is_err());
+        assert!(vm_config
+            .add_action("shutdown=poweroff,reboot=none")
+            .is_err());
+
+        // `-no-reboot` and `-no-shutdown` are shorthands of the actions.
+        vm_config.enable_no_reboot();
+        vm_config.enable_no_shutdown();
+        assert_eq!(vm_config.reboot_action, RebootAction::Shutdown);
+        assert_eq!(vm_config.shutdown_action, ShutdownAction::Pause);
+    }
+
+    #[test]
+    fn test_powerdown_action() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.powerdown.action, PowerdownAction::None);
+        assert_eq!(vm_config.powerdown.timeout, DEFAULT_POWERDOWN_TIMEOUT);
+
+        assert!(vm_config.add_action("powerdown=force-off").is_ok());
+        assert_eq!(vm_config.powerdown.action, PowerdownAction::ForceOff);
+        assert_eq!(vm_config.powerdown.timeout, DEFAULT_POWERDOWN_TIMEOUT);
+        assert!(vm_config
+            .add_action("powerdown=none,powerdown-timeout=5")
+            .is_ok());
+        assert_eq!(vm_config.powerdown.action.name(), "none");
+        assert_eq!(vm_config.powerdown.timeout, 5);
+
+        assert!(vm_config.add_action("powerdown=shutdown").is_err());
+        assert!(vm_config.add_action("powerdown-timeout=0").is_err());
+        assert!(vm_config.add_action("powerdown-timeout=-1").is_err());
+        assert_eq!(vm_config.powerdown.timeout, 5);
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::VmConfig;
+
+/// Host CPUs which vcpus are pinned to, given by `-cpu-affinity`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CpuAffinity {
+    /// Host cpu of each pinned vcpu, keyed by vcpu index.
+    pub vcpus: BTreeMap<u8, usize>,
+    /// Given as a list of host cpus, which must cover all vcpus of `-smp`.
+    pub is_list: bool,
+}
+
+impl CpuAffinity {
+    /// Host cpu which vcpu `vcpu_id` is pinned to.
+    pub fn vcpu(&self, vcpu_id: u8) -> Option<usize> {
+        self.vcpus.get(&vcpu_id).copied()
+    }
+
+    /// Check the affinity against `-smp`.
+    pub fn check(&self, nr_cpus: u8, max_cpus: u8) -> Result<()> {
+        if self.is_list && self.vcpus.len() != nr_cpus as usize {
+            bail!(
+                "cpu-affinity gives {} host cpus, which mismatches {} vcpus of -smp",
+                self.vcpus.len(),
+                nr_cpus
+            );
+        }
+        if let Some(vcpu_id) = self.vcpus.keys().find(|id| **id >= max_cpus) {
+            bail!(
+                "cpu-affinity pins vcpu{}, which is beyond maxcpus {}",
+                vcpu_id,
+                max_cpus
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_host_cpu(value: &str) -> Result<usize> {
+    value
+        .trim()
+        .parse::<usize>()
+        .with_context(|| format!("Invalid host cpu {:?} of cpu-affinity", value))
+}
+
+impl VmConfig {
+    /// Pin vcpus and iothreads to host cpus, either by a list `2,3,4,5` for all vcpus,
+    /// or by `vcpu0=2;vcpu1=3;iothread0=6` for the named ones. Iothreads must be added
+    /// before.
+    pub fn add_cpu_affinity(&mut self, affinity: &str) -> Result<()> {
+        let mut cpu_affinity = CpuAffinity::default();
+        if !affinity.contains('=') {
+            for (vcpu_id, host_cpu) in affinity.split(',').enumerate() {
+                let vcpu_id = u8::try_from(vcpu_id)
+                    .map_err(|_| anyhow!("Too many host cpus for cpu-affinity"))?;
+                cpu_affinity
+                    .vcpus
+                    .insert(vcpu_id, parse_host_cpu(host_cpu)?);
+            }
+            cpu_affinity.is_list = true;
+            self.machine_config.cpu_affinity = cpu_affinity;
+            return Ok(());
+        }
+
+        let mut iothreads = BTreeMap::new();
+        for item in affinity.split(';') {
+            let (name, host_cpu) = item
+                .split_once('=')
+                .with_context(|| format!("Invalid item {:?} of cpu-affinity", item))?;
+            let host_cpu = parse_host_cpu(host_cpu)?;
+            let duplicated = match name
+                .strip_prefix("vcpu")
+                .and_then(|id| id.parse::<u8>().ok())
+            {
+                Some(vcpu_id) => cpu_affinity.vcpus.insert(vcpu_id, host_cpu).is_some(),
+                None => iothreads.insert(name, host_cpu).is_some(),
+            };
+            if duplicated {
+                bail!("{} is pinned more than once by cpu-affinity", name);
+            }
+        }
+
+        for (id, host_cpu) in iothreads {
+            let iothread = self
+                .iothreads
+                .iter_mut()
+                .flatten()
+                .find(|iothread| iothread.id == id)
+                .with_context(|| format!("Iothread {} of cpu-affinity is not found", id))?;
+            iothread.cpu_affinity = Some(host_cpu);
+        }
+        self.machine_config.cpu_affinity = cpu_affinity;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_affinity_list() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_cpu_affinity("2,3,4,5").is_ok());
+        let affinity = &vm_config.machine_config.cpu_affinity;
+        assert_eq!(affinity.vcpu(0), Some(2));
+        assert_eq!(affinity.vcpu(3), Some(5));
+        assert_eq!(affinity.vcpu(4), None);
+        assert!(affinity.check(4, 8).is_ok());
+        assert!(affinity.check(2, 8).is_err());
+        assert!(affinity.check(8, 8).is_err());
+
+        assert!(vm_config.add_cpu_affinity("2,a").is_err());
+        assert!(vm_config.add_cpu_affinity("2,,3").is_err());
+    }
+
+    #[test]
+    fn test_cpu_affinity_named() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_object("iothread,id=iothread0").is_ok());
+        assert!(vm_config
+            .add_cpu_affinity("vcpu0=2;vcpu3=5;iothread0=6")
+            .is_ok());
+        let affinity = &vm_config.machine_config.cpu_affinity;
+        assert_eq!(affinity.vcpu(0), Some(2));
+        assert_eq!(affinity.vcpu(1), None);
+        assert_eq!(affinity.vcpu(3), Some(5));
+        // Vcpus not named are not pinned.
+        assert!(affinity.check(1, 4).is_ok());
+        assert!(affinity.check(1, 2).is_err());
+        assert_eq!(
+            vm_config.iothreads.as_ref().unwrap()[0].cpu_affinity,
+            Some(6)
+        );
+
+        assert!(vm_config.add_cpu_affinity("vcpu0=2;vcpu0=3").is_err());
+        assert!(vm_config.add_cpu_affinity("iothread1=2").is_err());
+        assert!(vm_config.add_cpu_affinity("vcpu0=2;vcpu1").is_err());
+        assert!(vm_config.add_cpu_affinity("vcpu0=-1").is_err());
+    }
+}
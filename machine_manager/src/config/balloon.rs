@@ -0,0 +1,72 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, Result};
+
+use super::error::ConfigError;
+use crate::config::{CmdParser, ConfigCheck, ExBool, MAX_STRING_LENGTH};
+
+/// Config structure for virtio-balloon.
+#[derive(Debug, Clone, Default)]
+pub struct BalloonConfig {
+    pub id: String,
+    /// Guest may deflate the balloon when it runs out of memory.
+    pub deflate_on_oom: bool,
+}
+
+impl ConfigCheck for BalloonConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "balloon id".to_string(),
+                MAX_STRING_LENGTH,
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_balloon(balloon_config: &str) -> Result<BalloonConfig> {
+    let mut cmd_parser = CmdParser::new("virtio-balloon-device");
+    cmd_parser.push("").push("id").push("deflate-on-oom");
+    cmd_parser.parse(balloon_config)?;
+
+    let mut balloon_cfg = BalloonConfig::default();
+    if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        balloon_cfg.id = id;
+    }
+    if let Some(deflate_on_oom) = cmd_parser.get_value::<ExBool>("deflate-on-oom")? {
+        balloon_cfg.deflate_on_oom = deflate_on_oom.into();
+    }
+    balloon_cfg.check()?;
+    Ok(balloon_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_balloon() {
+        let balloon_cfg = parse_balloon("virtio-balloon-device,id=balloon0").unwrap();
+        assert_eq!(balloon_cfg.id, "balloon0");
+        assert!(!balloon_cfg.deflate_on_oom);
+
+        let balloon_cfg =
+            parse_balloon("virtio-balloon-device,id=balloon0,deflate-on-oom=on").unwrap();
+        assert!(balloon_cfg.deflate_on_oom);
+
+        assert!(parse_balloon("virtio-balloon-device,deflate-on-oom=maybe").is_err());
+        assert!(parse_balloon("virtio-balloon-device,free-page-reporting=on").is_err());
+    }
+}
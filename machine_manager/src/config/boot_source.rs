@@ -15,7 +15,7 @@ use std::path::PathBuf;
 
 use super::error::ConfigError;
 use crate::config::{ConfigCheck, VmConfig, MAX_PATH_LENGTH, MAX_STRING_LENGTH};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
 
 /// Config struct for boot-source.
@@ -28,6 +28,12 @@ pub struct BootSource {
     pub kernel_cmdline: KernelParams,
     /// Config of initrd.
     pub initrd: Option<InitrdConfig>,
+    /// Path of OpenSBI fw_dynamic firmware booted before kernel, given by `-bios`.
+    pub bios: Option<PathBuf>,
+    /// Path of device tree blob used instead of the generated one, given by `-dtb`.
+    pub dtb: Option<PathBuf>,
+    /// Load `dtb` as it is, without patching /chosen and memory nodes.
+    pub dtb_no_fixup: bool,
 }
 
 impl BootSource {
@@ -58,6 +64,34 @@ impl ConfigCheck for BootSource {
             self.initrd.as_ref().unwrap().check()?;
         }
 
+        if let Some(bios) = &self.bios {
+            if bios.to_str().unwrap().len() > MAX_PATH_LENGTH {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "bios path".to_string(),
+                    MAX_PATH_LENGTH,
+                )));
+            }
+            if !bios.is_file() {
+                return Err(anyhow!(ConfigError::UnRegularFile(
+                    "Input bios".to_string()
+                )));
+            }
+        }
+
+        if let Some(dtb) = &self.dtb {
+            if dtb.to_str().unwrap().len() > MAX_PATH_LENGTH {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "dtb path".to_string(),
+                    MAX_PATH_LENGTH,
+                )));
+            }
+            if !dtb.is_file() {
+                return Err(anyhow!(ConfigError::UnRegularFile("Input dtb".to_string())));
+            }
+        } else if self.dtb_no_fixup {
+            bail!("-dtb-no-fixup is given without -dtb");
+        }
+
         Ok(())
     }
 }
@@ -116,6 +150,14 @@ impl ConfigCheck for KernelParams {
                     MAX_STRING_LENGTH,
                 )));
             }
+            // Kernel has no escape for quote, an unpaired one swallows the rest of cmdline.
+            let param = param.to_string();
+            if param.matches('"').count() % 2 != 0 || param.contains('\0') {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    param,
+                    "kernel params".to_string()
+                )));
+            }
         }
 
         Ok(())
@@ -124,15 +166,30 @@ impl ConfigCheck for KernelParams {
 
 impl KernelParams {
     /// Created `Kernel` from `String`.
+    ///
+    /// Params are separated by whitespaces out of double quotes, the quotes are kept
+    /// as they are parsed by kernel, e.g. `init="/bin/sh -c reboot"`.
     fn from_str(kernel_cmdline: String) -> Self {
-        let split = kernel_cmdline.split(' ');
-        let vec = split.collect::<Vec<&str>>();
         let mut params: Vec<Param> = Vec::new();
-        let mut length: usize = 0;
-        for item in vec {
-            params.push(Param::from_str(item));
-            length += 1;
+        let mut item = String::new();
+        let mut in_quotes = false;
+        for c in kernel_cmdline.chars() {
+            if c.is_whitespace() && !in_quotes {
+                if !item.is_empty() {
+                    params.push(Param::from_str(&item));
+                    item.clear();
+                }
+                continue;
+            }
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            item.push(c);
         }
+        if !item.is_empty() {
+            params.push(Param::from_str(&item));
+        }
+        let length = params.len();
         KernelParams { params, length }
     }
 
@@ -185,7 +242,7 @@ impl fmt::Display for KernelParams {
 pub struct Param {
     /// The item on the left of `=`, if no `=`, param_type is ""
     pub param_type: String,
-    /// The item on the right of `=`, if no `=`, the whole is value
+    /// The item on the right of the first `=`, if no `=`, the whole is value
     pub value: String,
 }
 
@@ -196,30 +253,33 @@ impl Param {
     ///
     /// * `item` - The `str` transformed to `Param`.
     fn from_str(item: &str) -> Self {
-        let split = item.split('=');
-        let vec = split.collect::<Vec<&str>>();
-        if vec.len() == 1 {
-            Param {
+        match item.split_once('=') {
+            Some((param_type, value)) => Param {
+                param_type: String::from(param_type),
+                value: String::from(value),
+            },
+            None => Param {
                 param_type: String::new(),
-                value: String::from(vec[0]),
-            }
-        } else {
-            Param {
-                param_type: String::from(vec[0]),
-                value: String::from(vec[1]),
-            }
+                value: String::from(item),
+            },
         }
     }
 }
 
 impl fmt::Display for Param {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Value with whitespaces is quoted, unless it's quoted by user already.
+        let value = if self.value.contains(char::is_whitespace) && !self.value.contains('"') {
+            format!("\"{}\"", self.value)
+        } else {
+            self.value.clone()
+        };
         let mut str1 = String::from(&self.param_type);
         let param_str = if str1.is_empty() {
-            String::from(&self.value)
+            value
         } else {
             str1 += "=";
-            str1 + &self.value
+            str1 + &value
         };
         write!(f, "{}", param_str)
     }
@@ -243,6 +303,22 @@ impl VmConfig {
         self.boot_source.initrd = Some(InitrdConfig::new(initrd));
         Ok(())
     }
+
+    /// Add `-bios firmware_path` config to `VmConfig`
+    pub fn add_bios(&mut self, bios: &str) -> Result<()> {
+        self.boot_source.bios = Some(PathBuf::from(bios));
+        Ok(())
+    }
+
+    /// Add `-dtb dtb_path` config to `VmConfig`
+    pub fn add_dtb(&mut self, dtb: &str) -> Result<()> {
+        self.boot_source.dtb = Some(PathBuf::from(dtb));
+        Ok(())
+    }
+
+    pub fn enable_dtb_no_fixup(&mut self) {
+        self.boot_source.dtb_no_fixup = true;
+    }
 }
 
 #[cfg(test)]
@@ -267,6 +343,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_kernel_params_quotes() {
+        let cmdline = r#"console=ttyS0  root=/dev/vda init="/bin/sh -c 'mount -a'" dyndbg=file=x"#;
+        let mut params = KernelParams::from_str(cmdline.to_string());
+        assert_eq!(params.length, 4);
+        assert_eq!(params.params[2].param_type, "init");
+        assert_eq!(params.params[2].value, r#""/bin/sh -c 'mount -a'""#);
+        assert_eq!(params.params[3].value, "file=x");
+        assert!(params.check().is_ok());
+
+        // Generated params follow the ones given by user.
+        params.push(Param {
+            param_type: "earlycon".to_string(),
+            value: "uart,mmio,0x10000000".to_string(),
+        });
+        params.push(Param {
+            param_type: "label".to_string(),
+            value: "two words".to_string(),
+        });
+        assert_eq!(
+            params.to_string(),
+            r#"console=ttyS0 root=/dev/vda init="/bin/sh -c 'mount -a'" dyndbg=file=x earlycon=uart,mmio,0x10000000 label="two words""#
+        );
+
+        let params = KernelParams::from_str(r#"init="/bin/sh"#.to_string());
+        assert!(params.check().is_err());
+    }
+
     #[test]
     fn test_bootsource_cmdline_parser() {
         let kernel_path = String::from("vmlinux.bin");
@@ -304,4 +408,38 @@ mod tests {
         std::fs::remove_file(&kernel_path).unwrap();
         std::fs::remove_file(&initrd_path).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_add_bios() {
+        let bios_path = String::from("fw_dynamic.bin");
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.boot_source.bios.is_none());
+        vm_config.add_bios(&bios_path).unwrap();
+        assert_eq!(vm_config.boot_source.bios, Some(PathBuf::from(&bios_path)));
+        // Firmware doesn't exist.
+        assert!(vm_config.boot_source.check().is_err());
+
+        File::create(&bios_path).unwrap().set_len(100_u64).unwrap();
+        assert!(vm_config.boot_source.check().is_ok());
+        std::fs::remove_file(&bios_path).unwrap();
+    }
+
+    #[test]
+    fn test_add_dtb() {
+        let dtb_path = String::from("test_add_dtb.dtb");
+        let mut vm_config = VmConfig::default();
+        vm_config.enable_dtb_no_fixup();
+        // No-fixup without dtb.
+        assert!(vm_config.boot_source.check().is_err());
+
+        vm_config.add_dtb(&dtb_path).unwrap();
+        assert_eq!(vm_config.boot_source.dtb, Some(PathBuf::from(&dtb_path)));
+        assert!(vm_config.boot_source.dtb_no_fixup);
+        // Dtb doesn't exist.
+        assert!(vm_config.boot_source.check().is_err());
+
+        File::create(&dtb_path).unwrap().set_len(100_u64).unwrap();
+        assert!(vm_config.boot_source.check().is_ok());
+        std::fs::remove_file(&dtb_path).unwrap();
+    }
+}
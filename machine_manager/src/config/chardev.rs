@@ -11,7 +11,6 @@
 // See the Mulan PSL v2 for more details.
 
 use anyhow::{anyhow, bail, Context, Result};
-use log::error;
 use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, get_pci_bdf, pci_args_check, PciBdf};
@@ -21,17 +20,31 @@ use crate::qmp::qmp_schema;
 const MAX_GUEST_CID: u64 = 4_294_967_295;
 const MIN_GUEST_CID: u64 = 3;
 
+/// Default host of tcp-type chardev.
+const DEFAULT_TCP_HOST: &str = "127.0.0.1";
+
 /// Charecter device options.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChardevType {
     Stdio,
     Pty,
+    /// Unix socket, which listens on `path` if `server`, otherwise connects to it.
+    /// A server without `nowait` waits for the first client when realized.
     Socket {
         path: String,
         server: bool,
         nowait: bool,
     },
+    /// Tcp socket, whose `server` and `nowait` are the same as unix socket's.
+    TcpSocket {
+        host: String,
+        port: u16,
+        server: bool,
+        nowait: bool,
+    },
     File(String),
+    /// Output is discarded and there is no input.
+    Null,
 }
 
 /// Config structure for virtio-console.
@@ -59,6 +72,7 @@ impl ConfigCheck for ChardevConfig {
 
         let len = match &self.backend {
             ChardevType::Socket { path, .. } => path.len(),
+            ChardevType::TcpSocket { host, .. } => host.len(),
             ChardevType::File(path) => path.len(),
             _ => 0,
         };
@@ -79,7 +93,7 @@ fn check_chardev_args(cmd_parser: CmdParser) -> Result<()> {
         let server = cmd_parser.get_value::<String>("server")?;
         let nowait = cmd_parser.get_value::<String>("nowait")?;
         match chardev_str {
-            "stdio" | "pty" | "file" => {
+            "stdio" | "pty" | "file" | "null" => {
                 if server.is_some() {
                     bail!(
                         "Chardev of {}-type does not support \'server\' argument",
@@ -119,6 +133,8 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
     };
     let backend = cmd_parser.get_value::<String>("")?;
     let path = cmd_parser.get_value::<String>("path")?;
+    let host = cmd_parser.get_value::<String>("host")?;
+    let port = cmd_parser.get_value::<u16>("port")?;
     let server = if let Some(server) = cmd_parser.get_value::<String>("server")? {
         if server.ne("") {
             bail!("No parameter needed for server");
@@ -140,20 +156,33 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
         match backend.as_str() {
             "stdio" => ChardevType::Stdio,
             "pty" => ChardevType::Pty,
-            "socket" => {
-                if let Some(path) = path {
+            "socket" => match (path, port) {
+                (Some(_), Some(_)) => {
+                    bail!("Argument \'path\' and \'port\' of socket-type chardev are exclusive")
+                }
+                (Some(path), None) => {
+                    if host.is_some() {
+                        bail!("Argument \'host\' is only for tcp socket-type chardev");
+                    }
                     ChardevType::Socket {
                         path,
                         server,
                         nowait,
                     }
-                } else {
+                }
+                (None, Some(port)) => ChardevType::TcpSocket {
+                    host: host.unwrap_or_else(|| DEFAULT_TCP_HOST.to_string()),
+                    port,
+                    server,
+                    nowait,
+                },
+                (None, None) => {
                     return Err(anyhow!(ConfigError::FieldIsMissing(
                         "path",
                         "socket-type chardev"
                     )));
                 }
-            }
+            },
             "file" => {
                 if let Some(path) = path {
                     ChardevType::File(path)
@@ -164,6 +193,7 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
                     )));
                 }
             }
+            "null" => ChardevType::Null,
             _ => {
                 return Err(anyhow!(ConfigError::InvalidParam(
                     backend,
@@ -188,39 +218,69 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
 /// * `args` - The qmp arguments.
 pub fn get_chardev_config(args: qmp_schema::CharDevAddArgument) -> Result<ChardevConfig> {
     let backend = args.backend;
-    if backend.backend_type.as_str() != "socket" {
-        return Err(anyhow!(ConfigError::InvalidParam(
-            "backend".to_string(),
-            backend.backend_type
-        )));
-    }
-
     let data = backend.backend_data;
-    if data.server {
-        error!("Not support chardev socket as server now.");
-        return Err(anyhow!(ConfigError::InvalidParam(
-            "backend".to_string(),
-            "server".to_string()
-        )));
-    }
-
-    let addr = data.addr;
-    if addr.addr_type.as_str() != "unix" {
-        error!("Just support \"unix\" addr type option now.");
-        return Err(anyhow!(ConfigError::InvalidParam(
-            "backend".to_string(),
-            "addr".to_string()
-        )));
-    }
+    let chardev_type = match backend.backend_type.as_str() {
+        "socket" => {
+            let server = data.server.unwrap_or(false);
+            // A hot-added server must not block the monitor waiting for client.
+            if server && data.wait.unwrap_or(false) {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "wait".to_string(),
+                    "chardev-add".to_string()
+                )));
+            }
+            let addr = data
+                .addr
+                .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("addr", "socket backend")))?;
+            let addr_data = addr.addr_data;
+            match addr.addr_type.as_str() {
+                "unix" => ChardevType::Socket {
+                    path: addr_data
+                        .path
+                        .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("path", "unix addr")))?,
+                    server,
+                    nowait: true,
+                },
+                "inet" => ChardevType::TcpSocket {
+                    host: addr_data
+                        .host
+                        .unwrap_or_else(|| DEFAULT_TCP_HOST.to_string()),
+                    port: addr_data
+                        .port
+                        .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("port", "inet addr")))?
+                        .parse::<u16>()
+                        .with_context(|| "Invalid port of inet addr")?,
+                    server,
+                    nowait: true,
+                },
+                addr_type => {
+                    return Err(anyhow!(ConfigError::InvalidParam(
+                        "addr".to_string(),
+                        addr_type.to_string()
+                    )))
+                }
+            }
+        }
+        "pty" => ChardevType::Pty,
+        "file" => ChardevType::File(
+            data.out
+                .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("out", "file backend")))?,
+        ),
+        "null" => ChardevType::Null,
+        backend_type => {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                "backend".to_string(),
+                backend_type.to_string()
+            )))
+        }
+    };
 
-    Ok(ChardevConfig {
+    let config = ChardevConfig {
         id: args.id,
-        backend: ChardevType::Socket {
-            path: addr.addr_data.path,
-            server: data.server,
-            nowait: false,
-        },
-    })
+        backend: chardev_type,
+    };
+    config.check()?;
+    Ok(config)
 }
 
 /// Get chardev socket path from ChardevConfig struct.
@@ -291,6 +351,8 @@ impl VmConfig {
             .push("")
             .push("id")
             .push("path")
+            .push("host")
+            .push("port")
             .push("server")
             .push("nowait");
 
@@ -349,33 +411,91 @@ pub struct SerialConfig {
 
 impl VmConfig {
     pub fn add_serial(&mut self, serial_config: &str) -> Result<()> {
-        let parse_vec: Vec<&str> = serial_config.split(':').collect();
+        let char_dev = self.take_chardev(serial_config, "serial", "serial_chardev")?;
+        self.serial = Some(SerialConfig { chardev: char_dev });
+        Ok(())
+    }
+
+    /// Set the dedicated backend of SBI debug console, which shares the one of serial
+    /// if not given.
+    pub fn add_sbi_console(&mut self, console_config: &str) -> Result<()> {
+        let char_dev = self.take_chardev(console_config, "sbi-console", "sbi_console_chardev")?;
+        self.sbi_console = Some(SerialConfig { chardev: char_dev });
+        Ok(())
+    }
+
+    /// Take the chardev used by `device`, which is either `chardev:<id>` of an existing
+    /// chardev or a backend created with `default_id`.
+    fn take_chardev(
+        &mut self,
+        config: &str,
+        device: &str,
+        default_id: &str,
+    ) -> Result<ChardevConfig> {
+        let parse_vec: Vec<&str> = config.split(':').collect();
         let chardev_id = match parse_vec[0] {
             "chardev" => {
                 if parse_vec.len() == 2 {
                     parse_vec[1]
                 } else {
                     return Err(anyhow!(ConfigError::InvalidParam(
-                        serial_config.to_string(),
-                        "serial".to_string(),
+                        config.to_string(),
+                        device.to_string(),
                     )));
                 }
             }
             _ => {
-                let chardev_config = serial_config.to_string() + ",id=serial_chardev";
+                let backend = expand_backend_uri(config, device)?;
+                let chardev_config = format!("{},id={}", backend, default_id);
                 self.add_chardev(&chardev_config)
                     .with_context(|| "Failed to add chardev")?;
-                "serial_chardev"
+                default_id
             }
         };
         if let Some(char_dev) = self.chardev.remove(chardev_id) {
-            self.serial = Some(SerialConfig { chardev: char_dev });
-            return Ok(());
+            return Ok(char_dev);
         }
         bail!("Chardev {:?} not found or is in use", chardev_id);
     }
 }
 
+/// Expand the uri form of a backend, `file:<path>`, `unix:<path>[,server][,nowait]` and
+/// `tcp:[<host>]:<port>[,server][,nowait]`, to the arguments of `-chardev`. Other forms
+/// such as `stdio`, `pty` and `null` are kept as they are.
+fn expand_backend_uri(config: &str, device: &str) -> Result<String> {
+    let invalid = || {
+        anyhow!(ConfigError::InvalidParam(
+            config.to_string(),
+            device.to_string()
+        ))
+    };
+    if let Some(path) = config.strip_prefix("file:") {
+        if path.is_empty() {
+            return Err(invalid());
+        }
+        return Ok(format!("file,path={}", path));
+    }
+    if let Some(uri) = config.strip_prefix("unix:") {
+        if uri.is_empty() || uri.starts_with(',') {
+            return Err(invalid());
+        }
+        return Ok(format!("socket,path={}", uri));
+    }
+    if let Some(uri) = config.strip_prefix("tcp:") {
+        let (addr, options) = uri.split_once(',').unwrap_or((uri, ""));
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+        let mut backend = format!("socket,port={}", port);
+        if !host.is_empty() {
+            backend = format!("{},host={}", backend, host);
+        }
+        if !options.is_empty() {
+            backend = format!("{},{}", backend, options);
+        }
+        return Ok(backend);
+    }
+    Ok(config.to_string())
+}
+
 /// Config structure for virtio-vsock.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct VsockConfig {
@@ -553,6 +673,15 @@ mod tests {
         );
         // test_console1 does not exist.
         assert!(virt_console.is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_chardev("pty,id=test_console").is_ok());
+        let virt_console = parse_virtconsole(
+            &mut vm_config,
+            "virtio-console,chardev=test_console,id=console1",
+        )
+        .unwrap();
+        assert_eq!(virt_console.chardev.backend, ChardevType::Pty);
     }
 
     #[test]
@@ -637,5 +766,166 @@ mod tests {
         } else {
             assert!(false);
         }
+
+        assert!(vm_config
+            .add_chardev("socket,id=tcp0,port=4444,server,nowait")
+            .is_ok());
+        assert_eq!(
+            vm_config.chardev.remove("tcp0").unwrap().backend,
+            ChardevType::TcpSocket {
+                host: "127.0.0.1".to_string(),
+                port: 4444,
+                server: true,
+                nowait: true,
+            }
+        );
+        assert!(vm_config
+            .add_chardev("socket,id=tcp1,host=0.0.0.0,port=4444")
+            .is_ok());
+        assert!(vm_config
+            .add_chardev("socket,id=tcp2,path=/path/to/socket,port=4444")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=tcp3,host=0.0.0.0,path=/path/to/socket")
+            .is_err());
+        assert!(vm_config.add_chardev("null,id=null0").is_ok());
+        assert_eq!(
+            vm_config.chardev.remove("null0").unwrap().backend,
+            ChardevType::Null
+        );
+        assert!(vm_config.add_chardev("null,id=null1,server").is_err());
+    }
+
+    #[test]
+    fn test_chardev_config_qmp_parser() {
+        let args = |backend: serde_json::Value| -> qmp_schema::CharDevAddArgument {
+            serde_json::from_value(serde_json::json!({"id": "chr0", "backend": backend})).unwrap()
+        };
+
+        let config = get_chardev_config(args(serde_json::json!({
+            "type": "socket",
+            "data": {"addr": {"type": "inet", "data": {"host": "0.0.0.0", "port": "4444"}},
+                     "server": true, "wait": false}
+        })))
+        .unwrap();
+        assert_eq!(config.id, "chr0");
+        assert_eq!(
+            config.backend,
+            ChardevType::TcpSocket {
+                host: "0.0.0.0".to_string(),
+                port: 4444,
+                server: true,
+                nowait: true,
+            }
+        );
+        let config = get_chardev_config(args(serde_json::json!({
+            "type": "socket",
+            "data": {"addr": {"type": "unix", "data": {"path": "/path/to/socket"}}}
+        })))
+        .unwrap();
+        assert_eq!(
+            config.backend,
+            ChardevType::Socket {
+                path: "/path/to/socket".to_string(),
+                server: false,
+                nowait: true,
+            }
+        );
+        let config =
+            get_chardev_config(args(serde_json::json!({"type": "pty", "data": {}}))).unwrap();
+        assert_eq!(config.backend, ChardevType::Pty);
+        let config = get_chardev_config(args(serde_json::json!({"type": "null"}))).unwrap();
+        assert_eq!(config.backend, ChardevType::Null);
+        let config = get_chardev_config(args(serde_json::json!({
+            "type": "file", "data": {"out": "/path/to/file"}
+        })))
+        .unwrap();
+        assert_eq!(
+            config.backend,
+            ChardevType::File("/path/to/file".to_string())
+        );
+
+        // A server can't wait for client, and the port must be a number.
+        assert!(get_chardev_config(args(serde_json::json!({
+            "type": "socket",
+            "data": {"addr": {"type": "unix", "data": {"path": "/path/to/socket"}},
+                     "server": true, "wait": true}
+        })))
+        .is_err());
+        assert!(get_chardev_config(args(serde_json::json!({
+            "type": "socket",
+            "data": {"addr": {"type": "inet", "data": {"port": "http"}}}
+        })))
+        .is_err());
+        assert!(get_chardev_config(args(serde_json::json!({"type": "stdio"}))).is_err());
+    }
+
+    #[test]
+    fn test_sbi_console_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_sbi_console("pty").is_ok());
+        let console = vm_config.sbi_console.take().unwrap();
+        assert_eq!(console.chardev.id, "sbi_console_chardev");
+        assert_eq!(console.chardev.backend, ChardevType::Pty);
+
+        assert!(vm_config
+            .add_chardev("socket,id=sbi0,path=/path/to/socket,server,nowait")
+            .is_ok());
+        assert!(vm_config.add_sbi_console("chardev:sbi0").is_ok());
+        assert_eq!(vm_config.sbi_console.as_ref().unwrap().chardev.id, "sbi0");
+        // Chardev is used by sbi console already.
+        assert!(vm_config.add_serial("chardev:sbi0").is_err());
+        assert!(vm_config.add_sbi_console("chardev:sbi0:1").is_err());
+    }
+
+    #[test]
+    fn test_serial_backend_uri() {
+        let serial_backend = |config: &str| -> Result<ChardevType> {
+            let mut vm_config = VmConfig::default();
+            vm_config.add_serial(config)?;
+            Ok(vm_config.serial.unwrap().chardev.backend)
+        };
+        assert_eq!(
+            serial_backend("file:/var/log/vm-console.log").unwrap(),
+            ChardevType::File("/var/log/vm-console.log".to_string())
+        );
+        assert_eq!(
+            serial_backend("unix:/run/vm.console,server,nowait").unwrap(),
+            ChardevType::Socket {
+                path: "/run/vm.console".to_string(),
+                server: true,
+                nowait: true,
+            }
+        );
+        assert_eq!(
+            serial_backend("tcp::5555,server").unwrap(),
+            ChardevType::TcpSocket {
+                host: DEFAULT_TCP_HOST.to_string(),
+                port: 5555,
+                server: true,
+                nowait: false,
+            }
+        );
+        assert_eq!(
+            serial_backend("tcp:0.0.0.0:5555").unwrap(),
+            ChardevType::TcpSocket {
+                host: "0.0.0.0".to_string(),
+                port: 5555,
+                server: false,
+                nowait: false,
+            }
+        );
+        assert_eq!(serial_backend("null").unwrap(), ChardevType::Null);
+        assert_eq!(serial_backend("stdio").unwrap(), ChardevType::Stdio);
+        // The long form is still accepted.
+        assert_eq!(
+            serial_backend("file,path=/tmp/serial.log").unwrap(),
+            ChardevType::File("/tmp/serial.log".to_string())
+        );
+
+        assert!(serial_backend("tcp:5555").is_err());
+        assert!(serial_backend("tcp::port").is_err());
+        assert!(serial_backend("unix:/run/vm.console,wait").is_err());
+        assert!(serial_backend("file:").is_err());
     }
 }
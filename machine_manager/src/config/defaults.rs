@@ -0,0 +1,159 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fmt;
+
+/// Log filter used when `-log-level` is not given.
+pub use util::logger::LOG_FILTER_ENV;
+
+/// Default firmware path used when `-bios` is not given.
+pub const BIOS_PATH_ENV: &str = "TELEVM_BIOS_PATH";
+/// Default directory of the log file used when `-D` is not given.
+pub const LOG_DIR_ENV: &str = "TELEVM_LOG_DIR";
+/// Default QMP socket used when neither `-qmp` nor `-mon` is given.
+pub const QMP_DEFAULT_ENV: &str = "TELEVM_QMP_DEFAULT";
+
+/// Where the effective value of a setting comes from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SettingSource {
+    /// Command line argument, or the config file given by `-config`.
+    Cli,
+    /// Environment variable.
+    Env,
+    /// Built-in default.
+    Builtin,
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = match self {
+            SettingSource::Cli => "cli",
+            SettingSource::Env => "env",
+            SettingSource::Builtin => "builtin",
+        };
+        write!(f, "{}", source)
+    }
+}
+
+/// Setting which may be given by command line argument or environment variable.
+/// Command line argument always wins, and an empty environment variable is
+/// taken as unset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting {
+    /// Effective value, `None` for the built-in default.
+    pub value: Option<String>,
+    pub source: SettingSource,
+}
+
+impl Setting {
+    /// Resolve the setting from command line argument `cli` and environment variable
+    /// `env_name`.
+    pub fn resolve(cli: Option<String>, env_name: &str) -> Self {
+        Self::resolve_with(cli, std::env::var(env_name).ok())
+    }
+
+    fn resolve_with(cli: Option<String>, env: Option<String>) -> Self {
+        match (cli, env) {
+            (Some(value), _) => Setting {
+                value: Some(value),
+                source: SettingSource::Cli,
+            },
+            (None, Some(value)) if !value.trim().is_empty() => Setting {
+                value: Some(value),
+                source: SettingSource::Env,
+            },
+            _ => Setting {
+                value: None,
+                source: SettingSource::Builtin,
+            },
+        }
+    }
+
+    /// Convert the value which comes from environment variable by `f`.
+    pub fn map_env<F: FnOnce(String) -> String>(mut self, f: F) -> Self {
+        if self.source == SettingSource::Env {
+            self.value = self.value.map(f);
+        }
+        self
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{:?} ({})", value, self.source),
+            None => write!(f, "none ({})", self.source),
+        }
+    }
+}
+
+/// Path of the log file placed in the directory given by `TELEVM_LOG_DIR`, which
+/// is named after the process to not be shared by vms.
+pub fn log_file_in_dir(dir: &str, pid: u32) -> String {
+    format!("{}/televm-{}.log", dir.trim_end_matches('/'), pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setting_precedence() {
+        let cli = Some("/cli/fw.bin".to_string());
+        let env = Some("/env/fw.bin".to_string());
+
+        let setting = Setting::resolve_with(cli.clone(), env.clone());
+        assert_eq!(setting.value, cli);
+        assert_eq!(setting.source, SettingSource::Cli);
+        // Cli wins even if it is empty, e.g. `-D` without path.
+        let setting = Setting::resolve_with(Some(String::new()), env.clone());
+        assert_eq!(setting.value, Some(String::new()));
+        assert_eq!(setting.source, SettingSource::Cli);
+
+        let setting = Setting::resolve_with(None, env.clone());
+        assert_eq!(setting.value, env);
+        assert_eq!(setting.source, SettingSource::Env);
+
+        // Empty environment variable is unset.
+        for env in [None, Some(String::new()), Some(" ".to_string())] {
+            let setting = Setting::resolve_with(None, env);
+            assert_eq!(setting.value, None);
+            assert_eq!(setting.source, SettingSource::Builtin);
+        }
+    }
+
+    #[test]
+    fn test_setting_map_env() {
+        let map = |dir: String| log_file_in_dir(&dir, 42);
+        let setting = Setting::resolve_with(None, Some("/var/log/televm/".to_string()));
+        assert_eq!(
+            setting.map_env(map).value.as_deref(),
+            Some("/var/log/televm/televm-42.log")
+        );
+        // The value given by cli is a file already.
+        let setting = Setting::resolve_with(Some("/tmp/vm.log".to_string()), None);
+        assert_eq!(setting.map_env(map).value.as_deref(), Some("/tmp/vm.log"));
+        let setting = Setting::resolve_with(None, None);
+        assert_eq!(setting.map_env(map).value, None);
+    }
+
+    #[test]
+    fn test_setting_display() {
+        let setting = Setting::resolve_with(None, Some("unix:/run/qmp.sock".to_string()));
+        assert_eq!(setting.to_string(), "\"unix:/run/qmp.sock\" (env)");
+        assert_eq!(
+            Setting::resolve_with(None, None).to_string(),
+            "none (builtin)"
+        );
+    }
+}
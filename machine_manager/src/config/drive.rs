@@ -10,12 +10,13 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::HashMap;
 use std::fs::{metadata, File};
 use std::os::linux::fs::MetadataExt;
 use std::path::Path;
 
 use anyhow::{anyhow, bail, Result};
-use log::error;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check};
@@ -23,11 +24,20 @@ use crate::config::{
     get_chardev_socket_path, CmdParser, ConfigCheck, ExBool, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
     MAX_PATH_LENGTH, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
 };
-use crate::qmp::qmp_schema;
+use crate::qmp::{parse_fdset_path, qmp_schema};
 use util::aio::{aio_probe, AioEngine};
 const MAX_SERIAL_NUM: usize = 20;
 const MAX_IOPS: u64 = 1_000_000;
 const MAX_UNIT_ID: usize = 2;
+/// Throttling keys of `-drive` which are accepted for compatibility, but not
+/// implemented yet.
+const UNSUPPORTED_THROTTLING: [&str; 5] = [
+    "throttling.iops-read",
+    "throttling.iops-write",
+    "throttling.bps-total",
+    "throttling.bps-read",
+    "throttling.bps-write",
+];
 
 // Seg_max = queue_size - 2. So, size of each virtqueue for virtio-blk should be larger than 2.
 const MIN_QUEUE_SIZE_BLK: u16 = 2;
@@ -46,6 +56,8 @@ pub struct DriveFile {
     pub read_only: bool,
     /// File lock status.
     pub locked: bool,
+    /// File is shared with other processes for writing, so it's never locked.
+    pub share_rw: bool,
     /// The align requirement of request(offset/len).
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
@@ -106,6 +118,7 @@ pub struct DriveConfig {
     pub path_on_host: String,
     pub read_only: bool,
     pub direct: bool,
+    pub share_rw: bool,
     pub iops: Option<u64>,
     pub aio: AioEngine,
 }
@@ -117,6 +130,7 @@ impl Default for DriveConfig {
             path_on_host: "".to_string(),
             read_only: false,
             direct: true,
+            share_rw: false,
             iops: None,
             aio: AioEngine::Native,
         }
@@ -126,6 +140,10 @@ impl Default for DriveConfig {
 impl DriveConfig {
     /// Check whether the drive file path on the host is valid.
     pub fn check_path(&self) -> Result<()> {
+        // The fd set is checked when it's opened.
+        if parse_fdset_path(&self.path_on_host)?.is_some() {
+            return Ok(());
+        }
         let blk = Path::new(&self.path_on_host);
         match metadata(blk) {
             Ok(meta) => {
@@ -295,7 +313,15 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     if let Some(direct) = cmd_parser.get_value::<ExBool>("direct")? {
         drive.direct = direct.into();
     }
+    if let Some(share_rw) = cmd_parser.get_value::<ExBool>("share-rw")? {
+        drive.share_rw = share_rw.into();
+    }
     drive.iops = cmd_parser.get_value::<u64>("throttling.iops-total")?;
+    for key in UNSUPPORTED_THROTTLING {
+        if cmd_parser.get_value::<u64>(key)?.is_some() {
+            warn!("Drive {}: {} is not supported, ignored", drive.id, key);
+        }
+    }
     drive.aio = cmd_parser.get_value::<AioEngine>("aio")?.unwrap_or({
         if drive.direct {
             AioEngine::Native
@@ -367,6 +393,7 @@ pub fn parse_blk(
         blkdevcfg.queue_size = queue_size;
     }
 
+    // The drive is taken by the device, so it can't be claimed twice.
     if let Some(drive_arg) = &vm_config.drives.remove(&blkdrive) {
         blkdevcfg.path_on_host = drive_arg.path_on_host.clone();
         blkdevcfg.read_only = drive_arg.read_only;
@@ -374,7 +401,11 @@ pub fn parse_blk(
         blkdevcfg.iops = drive_arg.iops;
         blkdevcfg.aio = drive_arg.aio;
     } else {
-        bail!("No drive configured matched for blk device");
+        bail!(
+            "Drive {} of blk device {} is not added or used by another device",
+            blkdrive,
+            blkdevcfg.id
+        );
     }
     blkdevcfg.check()?;
     Ok(blkdevcfg)
@@ -502,10 +533,14 @@ impl VmConfig {
             .push("id")
             .push("readonly")
             .push("direct")
+            .push("share-rw")
             .push("format")
             .push("if")
             .push("throttling.iops-total")
             .push("aio");
+        for key in UNSUPPORTED_THROTTLING {
+            cmd_parser.push(key);
+        }
 
         cmd_parser.parse(block_config)?;
         let drive_cfg = parse_drive(cmd_parser)?;
@@ -522,7 +557,34 @@ impl VmConfig {
         if self.drives.get(&drive_id).is_none() {
             self.drives.insert(drive_id, drive_conf);
         } else {
-            bail!("Drive {} has been added", drive_id);
+            return Err(anyhow!(ConfigError::IdRepeat(
+                drive_id,
+                "drive".to_string()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check the drives referenced by `-device ...,drive=<id>`, each of them must be
+    /// added by `-drive` and used by one device only. The drives referenced by no
+    /// device are left for `device_add`.
+    pub fn check_drive_references(&self) -> Result<()> {
+        let mut users: HashMap<String, String> = HashMap::new();
+        for (_, device_config) in &self.devices {
+            let mut cmd_parser = CmdParser::new("device");
+            cmd_parser.push("id").push("drive");
+            cmd_parser.get_parameters(device_config)?;
+            let drive = match cmd_parser.get_value::<String>("drive")? {
+                Some(drive) => drive,
+                None => continue,
+            };
+            let id = cmd_parser.get_value::<String>("id")?.unwrap_or_default();
+            if !self.drives.contains_key(&drive) {
+                bail!("Drive {} of device {} is not added by -drive", drive, id);
+            }
+            if let Some(user) = users.insert(drive.clone(), id.clone()) {
+                bail!("Drive {} is used by both device {} and {}", drive, user, id);
+            }
         }
         Ok(())
     }
@@ -683,6 +745,134 @@ mod tests {
             None,
         );
         assert!(blk_cfg_res.is_err()); // Can not find drive named "rootfs1".
+
+        // The image is locked unless it's shared for writing.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        assert!(!vm_config.drives["rootfs"].share_rw);
+        assert!(vm_config
+            .add_drive("id=data,file=/path/to/data,share-rw=on")
+            .is_ok());
+        assert!(vm_config.drives["data"].share_rw);
+        assert!(vm_config
+            .add_drive("id=data1,file=/path/to/data,share-rw=maybe")
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_device_reference() {
+        // Legacy style, the drive is neither typed nor formatted.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        assert!(vm_config
+            .add_device("virtio-blk-device,drive=rootfs,id=blk0")
+            .is_ok());
+        assert!(vm_config.check_drive_references().is_ok());
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=blk0",
+            None,
+        );
+        assert_eq!(blk_cfg.unwrap().path_on_host, "/path/to/rootfs");
+
+        // QEMU style, with the accepted but unimplemented throttling keys.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive(
+                "if=none,id=drive0,file=/path/to/disk,format=raw,readonly=on,direct=off,\
+                 throttling.iops-total=100,throttling.bps-total=1048576,throttling.iops-read=50"
+            )
+            .is_ok());
+        assert!(vm_config
+            .add_device("virtio-blk-device,id=blk0,drive=drive0")
+            .is_ok());
+        assert!(vm_config.check_drive_references().is_ok());
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,id=blk0,drive=drive0",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.path_on_host, "/path/to/disk");
+        assert_eq!(blk_cfg.read_only, true);
+        assert_eq!(blk_cfg.direct, false);
+        assert_eq!(blk_cfg.iops, Some(100));
+
+        // Invalid format and throttling value.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("if=none,id=drive0,file=/path/to/disk,format=qcow2")
+            .is_err());
+        assert!(vm_config
+            .add_drive("if=none,id=drive0,file=/path/to/disk,throttling.bps-total=fast")
+            .is_err());
+
+        // Duplicate drive id.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("if=none,id=drive0,file=/path/to/disk0")
+            .is_ok());
+        let err = vm_config
+            .add_drive("if=none,id=drive0,file=/path/to/disk1")
+            .unwrap_err();
+        assert!(err.to_string().contains("drive0"));
+
+        // Dangling drive reference.
+        assert!(vm_config
+            .add_device("virtio-blk-device,id=blk0,drive=drive1")
+            .is_ok());
+        let err = vm_config.check_drive_references().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Drive drive1 of device blk0 is not added by -drive"
+        );
+        let err = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,id=blk0,drive=drive1",
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("drive1"));
+
+        // Drive claimed by two devices.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("if=none,id=drive0,file=/path/to/disk0")
+            .is_ok());
+        assert!(vm_config
+            .add_device("virtio-blk-device,id=blk0,drive=drive0")
+            .is_ok());
+        assert!(vm_config
+            .add_device("virtio-blk-device,id=blk1,drive=drive0")
+            .is_ok());
+        let err = vm_config.check_drive_references().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Drive drive0 is used by both device blk0 and blk1"
+        );
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,id=blk0,drive=drive0",
+            None
+        )
+        .is_ok());
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,id=blk1,drive=drive0",
+            None
+        )
+        .is_err());
+
+        // Drives referenced by no device are kept for hotplug.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("if=none,id=drive0,file=/path/to/disk0")
+            .is_ok());
+        assert!(vm_config.check_drive_references().is_ok());
     }
 
     #[test]
@@ -0,0 +1,348 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Config file given by `-config`, an alternative to the command line.
+//!
+//! The file is a JSON object whose keys stand for command line arguments, each
+//! of them is translated into the value the argument would have on the command
+//! line:
+//!
+//! ```text
+//! {
+//!     "machine": {"type": "microvm", "dump-guest-core": false},
+//!     "memory": "1G",
+//!     "cpus": 2,
+//!     "kernel": "/path/to/vmlinux.bin",
+//!     "append": "console=ttyS0 root=/dev/vda",
+//!     "drives": [{"id": "rootfs", "file": "/path/to/rootfs", "readonly": false}],
+//!     "devices": [{"driver": "virtio-blk-device", "id": "blk0", "drive": "rootfs"}],
+//!     "qmp": "unix:/path/to/qmp.sock,server,nowait"
+//! }
+//! ```
+//!
+//! Booleans become `on` or `off`, and `null` leaves the key without value, such
+//! as `"server": null` for `server`.
+
+use std::fs::read_to_string;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+use super::error::ConfigError;
+
+/// How the value of a config file key is written.
+#[derive(Clone, Copy)]
+enum FileKeyKind {
+    /// A string, number or boolean given as is.
+    Scalar,
+    /// A scalar, or an object of parameters. The parameter named by the
+    /// optional `&str` is put first without its key.
+    Params(Option<&'static str>),
+    /// An array, each member is `Params` and given once.
+    List(Option<&'static str>),
+    /// A string or an array of strings, joined with spaces.
+    Words,
+    /// A boolean, `true` gives the flag.
+    Flag,
+}
+
+/// Config file keys, with the command line argument they stand for.
+const FILE_KEYS: [(&str, &str, FileKeyKind); 32] = [
+    ("name", "name", FileKeyKind::Scalar),
+    ("machine", "machine", FileKeyKind::Params(None)),
+    ("memory", "memory", FileKeyKind::Params(None)),
+    ("mem-path", "mem-path", FileKeyKind::Scalar),
+    ("mem-prealloc", "mem-prealloc", FileKeyKind::Flag),
+    ("cpus", "smp", FileKeyKind::Params(None)),
+    ("cpu", "cpu", FileKeyKind::Params(None)),
+    ("kernel", "kernel", FileKeyKind::Scalar),
+    ("initrd", "initrd-file", FileKeyKind::Scalar),
+    ("bios", "bios", FileKeyKind::Scalar),
+    ("dtb", "dtb", FileKeyKind::Scalar),
+    ("dtb-no-fixup", "dtb-no-fixup", FileKeyKind::Flag),
+    ("append", "kernel-cmdline", FileKeyKind::Words),
+    ("drives", "drive", FileKeyKind::List(None)),
+    ("netdevs", "netdev", FileKeyKind::List(Some("type"))),
+    ("chardevs", "chardev", FileKeyKind::List(Some("backend"))),
+    ("fsdevs", "fsdev", FileKeyKind::List(Some("fsdriver"))),
+    ("devices", "device", FileKeyKind::List(Some("driver"))),
+    ("objects", "object", FileKeyKind::List(Some("qom-type"))),
+    ("serial", "serial", FileKeyKind::Params(None)),
+    ("log-level", "log-level", FileKeyKind::Scalar),
+    ("qmp", "qmp", FileKeyKind::Scalar),
+    ("monitor", "monitor", FileKeyKind::Scalar),
+    ("action", "action", FileKeyKind::Params(None)),
+    ("rtc", "rtc", FileKeyKind::Params(None)),
+    ("sandbox", "sandbox", FileKeyKind::Params(None)),
+    ("runas", "runas", FileKeyKind::Scalar),
+    ("chroot", "chroot", FileKeyKind::Scalar),
+    ("no-reboot", "no-reboot", FileKeyKind::Flag),
+    ("no-shutdown", "no-shutdown", FileKeyKind::Flag),
+    ("daemonize", "daemonize", FileKeyKind::Flag),
+    ("pidfile", "pidfile", FileKeyKind::Scalar),
+];
+
+/// A command line argument given by the config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigFileArg {
+    /// Name of the command line argument.
+    pub arg: &'static str,
+    /// Value of the argument, `None` for a flag.
+    pub value: Option<String>,
+    /// Key path of the value in the config file, such as `drives[1]`.
+    pub key: String,
+}
+
+/// Read the config file at `path` into command line arguments.
+pub fn parse_config_file(path: &str) -> Result<Vec<ConfigFileArg>> {
+    let content =
+        read_to_string(path).with_context(|| format!("Failed to read config file {}", path))?;
+    let config: Value = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse config file {}", path))?;
+    parse_config_value(&config).with_context(|| format!("Invalid config file {}", path))
+}
+
+/// Translate the content of a config file into command line arguments.
+pub fn parse_config_value(config: &Value) -> Result<Vec<ConfigFileArg>> {
+    let config = config
+        .as_object()
+        .ok_or_else(|| invalid_key("", "expected an object"))?;
+    let mut args = Vec::new();
+    for (key, value) in config {
+        let (arg, kind) = FILE_KEYS
+            .iter()
+            .find(|(name, _, _)| name == key)
+            .map(|(_, arg, kind)| (*arg, *kind))
+            .ok_or_else(|| invalid_key(key, "unknown key"))?;
+        let mut push = |value: Option<String>, key: String| {
+            args.push(ConfigFileArg { arg, value, key });
+        };
+        match kind {
+            FileKeyKind::Scalar => push(Some(scalar(value, key)?), key.clone()),
+            FileKeyKind::Params(first) => push(Some(params(value, key, first)?), key.clone()),
+            FileKeyKind::List(first) => {
+                let members = value
+                    .as_array()
+                    .ok_or_else(|| invalid_key(key, "expected an array"))?;
+                for (index, member) in members.iter().enumerate() {
+                    let key = format!("{}[{}]", key, index);
+                    push(Some(params(member, &key, first)?), key);
+                }
+            }
+            FileKeyKind::Words => {
+                let words = match value {
+                    Value::String(words) => vec![words.as_str()],
+                    Value::Array(members) => {
+                        let mut words = Vec::new();
+                        for (index, member) in members.iter().enumerate() {
+                            words.push(member.as_str().ok_or_else(|| {
+                                invalid_key(&format!("{}[{}]", key, index), "expected a string")
+                            })?);
+                        }
+                        words
+                    }
+                    _ => return Err(invalid_key(key, "expected a string or an array")),
+                };
+                for word in words {
+                    push(Some(word.to_string()), key.clone());
+                }
+            }
+            FileKeyKind::Flag => match value {
+                Value::Bool(true) => push(None, key.clone()),
+                Value::Bool(false) => {}
+                _ => return Err(invalid_key(key, "expected a boolean")),
+            },
+        }
+    }
+    Ok(args)
+}
+
+fn invalid_key(key: &str, reason: &str) -> anyhow::Error {
+    anyhow!(ConfigError::InvalidFileKey(
+        key.to_string(),
+        reason.to_string()
+    ))
+}
+
+/// A string, number or boolean in command line form.
+fn scalar(value: &Value, key: &str) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Bool(true) => Ok("on".to_string()),
+        Value::Bool(false) => Ok("off".to_string()),
+        _ => Err(invalid_key(key, "expected a string, number or boolean")),
+    }
+}
+
+/// A scalar, or an object of parameters joined as `key=value,...`.
+fn params(value: &Value, key: &str, first: Option<&str>) -> Result<String> {
+    let map = match value {
+        Value::Object(map) => map,
+        Value::Array(_) | Value::Null => {
+            return Err(invalid_key(key, "expected a string, number or object"))
+        }
+        _ => return scalar(value, key),
+    };
+    if map.is_empty() {
+        return Err(invalid_key(key, "expected a non-empty object"));
+    }
+
+    let mut items = Vec::new();
+    if let Some(first) = first {
+        let value = map
+            .get(first)
+            .ok_or_else(|| invalid_key(key, &format!("missing '{}'", first)))?;
+        items.push(param_value(value, &format!("{}.{}", key, first))?);
+    }
+    // Keys without value come last, a leading one would be taken as the
+    // value of the first parameter.
+    let mut bare = Vec::new();
+    for (name, value) in map.iter().filter(|(name, _)| Some(name.as_str()) != first) {
+        let key = format!("{}.{}", key, name);
+        if name.is_empty() || name.contains([',', '=']) {
+            return Err(invalid_key(&key, "invalid parameter name"));
+        }
+        match value {
+            Value::Null => bare.push(name.clone()),
+            _ => items.push(format!("{}={}", name, param_value(value, &key)?)),
+        }
+    }
+    items.append(&mut bare);
+    Ok(items.join(","))
+}
+
+/// A scalar which fits in a `key=value,...` list.
+fn param_value(value: &Value, key: &str) -> Result<String> {
+    let value = scalar(value, key)?;
+    if value.is_empty() || value.contains(',') {
+        return Err(invalid_key(key, "expected a non-empty value without ','"));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn arg(arg: &'static str, value: Option<&str>, key: &str) -> ConfigFileArg {
+        ConfigFileArg {
+            arg,
+            value: value.map(|value| value.to_string()),
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_config_value() {
+        let args = parse_config_value(&json!({
+            "machine": {"type": "microvm", "dump-guest-core": false},
+            "memory": "1G",
+            "cpus": 2,
+            "append": "console=ttyS0 init=\"/bin/sh -c 'mount -a'\"",
+            "drives": [
+                {"id": "rootfs", "file": "/path/to/rootfs", "readonly": true},
+                "id=data,file=/path/to/data"
+            ],
+            "chardevs": [{"backend": "socket", "id": "chr0", "path": "/tmp/chr0", "server": null}],
+            "devices": [{"driver": "virtio-blk-device", "id": "blk0", "drive": "rootfs"}],
+            "qmp": "unix:/tmp/qmp.sock,server,nowait",
+            "daemonize": true,
+            "no-reboot": false
+        }))
+        .unwrap();
+        assert_eq!(
+            args,
+            vec![
+                arg(
+                    "kernel-cmdline",
+                    Some("console=ttyS0 init=\"/bin/sh -c 'mount -a'\""),
+                    "append"
+                ),
+                arg(
+                    "chardev",
+                    Some("socket,id=chr0,path=/tmp/chr0,server"),
+                    "chardevs[0]"
+                ),
+                arg("smp", Some("2"), "cpus"),
+                arg("daemonize", None, "daemonize"),
+                arg(
+                    "device",
+                    Some("virtio-blk-device,drive=rootfs,id=blk0"),
+                    "devices[0]"
+                ),
+                arg(
+                    "drive",
+                    Some("file=/path/to/rootfs,id=rootfs,readonly=on"),
+                    "drives[0]"
+                ),
+                arg("drive", Some("id=data,file=/path/to/data"), "drives[1]"),
+                arg(
+                    "machine",
+                    Some("dump-guest-core=off,type=microvm"),
+                    "machine"
+                ),
+                arg("memory", Some("1G"), "memory"),
+                arg("qmp", Some("unix:/tmp/qmp.sock,server,nowait"), "qmp"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_config_value_error() {
+        let error = |config: Value| parse_config_value(&config).unwrap_err().to_string();
+
+        assert_eq!(
+            error(json!(["memory"])),
+            "Invalid config file key '': expected an object"
+        );
+        assert_eq!(
+            error(json!({"mem": "1G"})),
+            "Invalid config file key 'mem': unknown key"
+        );
+        assert_eq!(
+            error(json!({"drives": {"id": "rootfs"}})),
+            "Invalid config file key 'drives': expected an array"
+        );
+        assert_eq!(
+            error(json!({"drives": [{"id": "rootfs"}, {"id": "data", "file": ["/a"]}]})),
+            "Invalid config file key 'drives[1].file': expected a string, number or boolean"
+        );
+        assert_eq!(
+            error(json!({"drives": [{"id": "a,b"}]})),
+            "Invalid config file key 'drives[0].id': expected a non-empty value without ','"
+        );
+        assert_eq!(
+            error(json!({"devices": [{"id": "blk0"}]})),
+            "Invalid config file key 'devices[0]': missing 'driver'"
+        );
+        assert_eq!(
+            error(json!({"machine": {}})),
+            "Invalid config file key 'machine': expected a non-empty object"
+        );
+        assert_eq!(
+            error(json!({"append": ["console=ttyS0", 1]})),
+            "Invalid config file key 'append[1]': expected a string"
+        );
+        assert_eq!(
+            error(json!({"daemonize": "on"})),
+            "Invalid config file key 'daemonize': expected a boolean"
+        );
+        assert_eq!(
+            error(json!({"qmp": null})),
+            "Invalid config file key 'qmp': expected a string, number or boolean"
+        );
+    }
+}
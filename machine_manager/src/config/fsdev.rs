@@ -0,0 +1,234 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::error::ConfigError;
+use crate::config::{
+    CmdParser, ConfigCheck, ExBool, VmConfig, MAX_PATH_LENGTH, MAX_STRING_LENGTH, MAX_TAG_LENGTH,
+};
+
+/// Config structure for `-fsdev`, a host directory shared with guest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FsDevConfig {
+    pub id: String,
+    /// Canonical path of the shared directory, guest can't get out of it.
+    pub path: String,
+    /// Files are created with the credentials of TeleVM, only `none` is supported.
+    pub security_model: String,
+    pub readonly: bool,
+}
+
+impl ConfigCheck for FsDevConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "fsdev id".to_string(),
+                MAX_STRING_LENGTH,
+            )));
+        }
+        if self.path.len() > MAX_PATH_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "fsdev path".to_string(),
+                MAX_PATH_LENGTH,
+            )));
+        }
+        if self.security_model != "none" {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                self.security_model.clone(),
+                "security_model".to_string(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Config structure for virtio-9p.
+#[derive(Debug, Clone, Default)]
+pub struct Virtio9pConfig {
+    pub id: String,
+    /// Tag guest mounts the shared directory by.
+    pub mount_tag: String,
+    pub fsdev: FsDevConfig,
+}
+
+impl ConfigCheck for Virtio9pConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "virtio-9p id".to_string(),
+                MAX_STRING_LENGTH,
+            )));
+        }
+        if self.mount_tag.is_empty() || self.mount_tag.len() >= MAX_TAG_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "virtio-9p mount_tag".to_string(),
+                MAX_TAG_LENGTH - 1,
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    /// Add `-fsdev local,id=<id>,path=<dir>,security_model=none[,readonly=on]` to vm config.
+    pub fn add_fsdev(&mut self, fsdev_config: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::new("fsdev");
+        cmd_parser
+            .push("")
+            .push("id")
+            .push("path")
+            .push("security_model")
+            .push("readonly");
+        cmd_parser.parse(fsdev_config)?;
+
+        match cmd_parser.get_value::<String>("")? {
+            Some(driver) if driver == "local" => {}
+            Some(driver) => bail!(
+                "Unsupported fsdev driver {}, only local is supported",
+                driver
+            ),
+            None => bail!("Fsdev driver is missing, only local is supported"),
+        }
+        let id = cmd_parser
+            .get_value::<String>("id")?
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("id", "fsdev")))?;
+        let path = cmd_parser
+            .get_value::<String>("path")?
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("path", "fsdev")))?;
+        if !Path::new(&path).is_dir() {
+            return Err(anyhow!(ConfigError::DirNotExist(path)));
+        }
+        let path = std::fs::canonicalize(&path)
+            .with_context(|| format!("Failed to resolve fsdev path {}", path))?;
+        let fsdev = FsDevConfig {
+            id,
+            path: path.to_string_lossy().to_string(),
+            security_model: cmd_parser
+                .get_value::<String>("security_model")?
+                .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("security_model", "fsdev")))?,
+            readonly: cmd_parser
+                .get_value::<ExBool>("readonly")?
+                .map_or(false, |readonly| readonly.into()),
+        };
+        fsdev.check()?;
+
+        if self.fsdevs.contains_key(&fsdev.id) {
+            return Err(anyhow!(ConfigError::IdRepeat(
+                fsdev.id,
+                "fsdev".to_string()
+            )));
+        }
+        self.fsdevs.insert(fsdev.id.clone(), fsdev);
+        Ok(())
+    }
+}
+
+/// Parse `-device virtio-9p-device,fsdev=<id>,mount_tag=<tag>`, the fsdev is
+/// taken from vm config, so it can't be shared by two devices.
+pub fn parse_virtio_9p(vm_config: &mut VmConfig, p9_config: &str) -> Result<Virtio9pConfig> {
+    let mut cmd_parser = CmdParser::new("virtio-9p-device");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("fsdev")
+        .push("mount_tag");
+    cmd_parser.parse(p9_config)?;
+
+    let fsdev_id = cmd_parser
+        .get_value::<String>("fsdev")?
+        .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("fsdev", "virtio-9p")))?;
+    let fsdev = vm_config
+        .fsdevs
+        .remove(&fsdev_id)
+        .ok_or_else(|| anyhow!("Fsdev {:?} not found or is in use", fsdev_id))?;
+    let p9_cfg = Virtio9pConfig {
+        id: cmd_parser.get_value::<String>("id")?.unwrap_or_default(),
+        mount_tag: cmd_parser
+            .get_value::<String>("mount_tag")?
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("mount_tag", "virtio-9p")))?,
+        fsdev,
+    };
+    p9_cfg.check()?;
+    Ok(p9_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_fsdev() {
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        let mut vm_config = VmConfig::default();
+        let fsdev = format!("local,id=fs0,path={},security_model=none", dir);
+        assert!(vm_config.add_fsdev(&fsdev).is_ok());
+        let fsdev_cfg = vm_config.fsdevs.get("fs0").unwrap();
+        assert!(!fsdev_cfg.readonly);
+        assert!(Path::new(&fsdev_cfg.path).is_absolute());
+        assert!(vm_config.add_fsdev(&fsdev).is_err());
+
+        let mut vm_config = VmConfig::default();
+        for fsdev in [
+            format!("proxy,id=fs0,path={},security_model=none", dir),
+            format!("local,path={},security_model=none", dir),
+            "local,id=fs0,path=/no/such/dir,security_model=none".to_string(),
+            format!("local,id=fs0,path={},security_model=mapped-xattr", dir),
+            format!("local,id=fs0,path={}", dir),
+            format!(
+                "local,id=fs0,path={},security_model=none,readonly=maybe",
+                dir
+            ),
+        ] {
+            assert!(vm_config.add_fsdev(&fsdev).is_err());
+        }
+        let fsdev = format!("local,id=fs1,path={},security_model=none,readonly=on", dir);
+        assert!(vm_config.add_fsdev(&fsdev).is_ok());
+        assert!(vm_config.fsdevs.get("fs1").unwrap().readonly);
+    }
+
+    #[test]
+    fn test_parse_virtio_9p() {
+        let dir = std::env::temp_dir();
+        let mut vm_config = VmConfig::default();
+        let fsdev = format!(
+            "local,id=fs0,path={},security_model=none",
+            dir.to_str().unwrap()
+        );
+        vm_config.add_fsdev(&fsdev).unwrap();
+
+        assert!(parse_virtio_9p(&mut vm_config, "virtio-9p-device,fsdev=fs0").is_err());
+        assert!(parse_virtio_9p(&mut vm_config, "virtio-9p-device,mount_tag=share").is_err());
+        let long_tag = format!("virtio-9p-device,fsdev=fs0,mount_tag={}", "t".repeat(36));
+        assert!(parse_virtio_9p(&mut vm_config, &long_tag).is_err());
+
+        let mut vm_config = VmConfig::default();
+        vm_config.add_fsdev(&fsdev).unwrap();
+        let p9_cfg = parse_virtio_9p(
+            &mut vm_config,
+            "virtio-9p-device,id=p9,fsdev=fs0,mount_tag=share",
+        )
+        .unwrap();
+        assert_eq!(p9_cfg.id, "p9");
+        assert_eq!(p9_cfg.mount_tag, "share");
+        assert_eq!(p9_cfg.fsdev.id, "fs0");
+        // The fsdev is used by the first device.
+        assert!(
+            parse_virtio_9p(&mut vm_config, "virtio-9p-device,fsdev=fs0,mount_tag=other").is_err()
+        );
+    }
+}
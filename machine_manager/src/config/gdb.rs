@@ -0,0 +1,74 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Result};
+
+use super::VmConfig;
+
+/// Parse `-gdb tcp:[<ip>]:<port>` to the address gdb server listens on.
+/// An empty ip means listening on all interfaces.
+pub fn parse_gdb_uri(uri: &str) -> Result<String> {
+    let parse_vec: Vec<&str> = uri.split(':').collect();
+    if parse_vec.len() != 3 || parse_vec[0] != "tcp" {
+        bail!(
+            "Invalid gdb uri {}, only tcp:[<ip>]:<port> is supported",
+            uri
+        );
+    }
+
+    let ip = if parse_vec[1].is_empty() {
+        "0.0.0.0"
+    } else {
+        parse_vec[1]
+    };
+    if ip.parse::<Ipv4Addr>().is_err() {
+        bail!("Invalid ip address {}", parse_vec[1]);
+    }
+    if parse_vec[2].parse::<u16>().is_err() {
+        bail!("Invalid ip port {}", parse_vec[2]);
+    }
+
+    Ok(format!("{}:{}", ip, parse_vec[2]))
+}
+
+impl VmConfig {
+    /// Add address of gdb server.
+    pub fn add_gdb(&mut self, config: &str) -> Result<()> {
+        self.gdb = Some(parse_gdb_uri(config)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gdb_uri() {
+        assert_eq!(parse_gdb_uri("tcp::1234").unwrap(), "0.0.0.0:1234");
+        assert_eq!(
+            parse_gdb_uri("tcp:127.0.0.1:1234").unwrap(),
+            "127.0.0.1:1234"
+        );
+        assert!(parse_gdb_uri("unix:/tmp/gdb.sock").is_err());
+        assert!(parse_gdb_uri("tcp:1234").is_err());
+        assert!(parse_gdb_uri("tcp:300.0.0.1:1234").is_err());
+        assert!(parse_gdb_uri("tcp::65568").is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_gdb("tcp::1234").is_ok());
+        assert_eq!(vm_config.gdb, Some("0.0.0.0:1234".to_string()));
+    }
+}
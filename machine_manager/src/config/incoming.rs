@@ -22,6 +22,8 @@ pub enum MigrateMode {
     File,
     Unix,
     Tcp,
+    /// Wait for `snapshot-load` with vcpus paused.
+    Defer,
     Unknown,
 }
 
@@ -31,6 +33,7 @@ impl From<&str> for MigrateMode {
             "file" | "File" | "FILE" => MigrateMode::File,
             "unix" | "Unix" | "UNIX" => MigrateMode::Unix,
             "tcp" | "Tcp" | "TCP" => MigrateMode::Tcp,
+            "defer" => MigrateMode::Defer,
             _ => MigrateMode::Unknown,
         }
     }
@@ -39,7 +42,9 @@ impl From<&str> for MigrateMode {
 /// Parse `-incoming` cmdline to migrate mode and path.
 pub fn parse_incoming_uri(uri: &str) -> Result<(MigrateMode, String)> {
     let parse_vec: Vec<&str> = uri.split(':').collect();
-    if parse_vec.len() == 2 {
+    if parse_vec.len() == 1 && MigrateMode::from(uri) == MigrateMode::Defer {
+        Ok((MigrateMode::Defer, String::new()))
+    } else if parse_vec.len() == 2 {
         match MigrateMode::from(parse_vec[0]) {
             MigrateMode::File => Ok((MigrateMode::File, String::from(parse_vec[1]))),
             MigrateMode::Unix => Ok((MigrateMode::Unix, String::from(parse_vec[1]))),
@@ -78,6 +83,7 @@ impl VmConfig {
             MigrateMode::File => (MigrateMode::File, uri),
             MigrateMode::Unix => (MigrateMode::Unix, uri),
             MigrateMode::Tcp => (MigrateMode::Tcp, uri),
+            MigrateMode::Defer => (MigrateMode::Defer, uri),
             MigrateMode::Unknown => {
                 bail!("Unsupported incoming unix path type")
             }
@@ -127,6 +133,10 @@ mod tests {
         let incoming_case5 = "tcp:192.168.1.2:65568";
         let result_5 = parse_incoming_uri(incoming_case5);
         assert!(result_5.is_err());
+
+        let result_6 = parse_incoming_uri("defer");
+        assert_eq!(result_6.unwrap(), (MigrateMode::Defer, String::new()));
+        assert!(parse_incoming_uri("defer:/tmp/snap").is_err());
     }
 
     #[test]
@@ -22,6 +22,8 @@ const MAX_IOTHREAD_NUM: usize = 8;
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct IothreadConfig {
     pub id: String,
+    /// Host cpu which the iothread is pinned to, given by `-cpu-affinity`.
+    pub cpu_affinity: Option<usize>,
 }
 
 impl ConfigCheck for IothreadConfig {
@@ -0,0 +1,185 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, bail, Result};
+
+use super::machine_config::memory_unit_conversion;
+use super::{CmdParser, ConfigError};
+
+/// Rotated log files kept when `rotate-keep` is not given.
+const DEFAULT_ROTATE_KEEP: usize = 5;
+const MAX_ROTATE_KEEP: u64 = 100;
+/// `-D` value selecting syslog, optionally followed by ":ident=<ident>".
+const SYSLOG_BACKEND: &str = "syslog";
+const DEFAULT_SYSLOG_IDENT: &str = "televm";
+const MAX_SYSLOG_IDENT_LEN: usize = 64;
+
+/// Where log goes, given by `-D`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogConfig {
+    Stdout,
+    File(LogFileConfig),
+    /// Local syslog daemon, with the ident which records are tagged by.
+    Syslog(String),
+}
+
+/// Log file given by `-D`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFileConfig {
+    pub path: String,
+    /// Size in bytes beyond which the log file is rotated.
+    pub rotate_size: Option<u64>,
+    /// Number of rotated files kept.
+    pub rotate_keep: usize,
+}
+
+/// Parse `-D` value, which is empty for stdout, "syslog[:ident=<ident>]" for syslog,
+/// or a log file like "televm.log,rotate-size=50M,rotate-keep=5".
+pub fn parse_log_config(log_config: &str) -> Result<LogConfig> {
+    if log_config.is_empty() {
+        return Ok(LogConfig::Stdout);
+    }
+    if log_config == SYSLOG_BACKEND {
+        return Ok(LogConfig::Syslog(DEFAULT_SYSLOG_IDENT.to_string()));
+    }
+    match log_config
+        .strip_prefix(SYSLOG_BACKEND)
+        .and_then(|s| s.strip_prefix(':'))
+    {
+        Some(syslog_config) => parse_syslog(syslog_config).map(LogConfig::Syslog),
+        None => parse_log_file(log_config).map(LogConfig::File),
+    }
+}
+
+fn parse_syslog(syslog_config: &str) -> Result<String> {
+    let mut cmd_parser = CmdParser::new("syslog");
+    cmd_parser.push("ident");
+    cmd_parser.parse(syslog_config)?;
+
+    let ident = cmd_parser
+        .get_value::<String>("ident")?
+        .unwrap_or_else(|| DEFAULT_SYSLOG_IDENT.to_string());
+    if ident.is_empty()
+        || ident
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "[]:".contains(c))
+    {
+        bail!("Invalid syslog ident \"{}\"", ident);
+    }
+    if ident.len() > MAX_SYSLOG_IDENT_LEN {
+        return Err(anyhow!(ConfigError::StringLengthTooLong(
+            "syslog ident".to_string(),
+            MAX_SYSLOG_IDENT_LEN
+        )));
+    }
+    Ok(ident)
+}
+
+fn parse_log_file(log_config: &str) -> Result<LogFileConfig> {
+    let mut cmd_parser = CmdParser::new("D");
+    cmd_parser.push("").push("rotate-size").push("rotate-keep");
+    cmd_parser.parse(log_config)?;
+
+    let path = match cmd_parser.get_value::<String>("")? {
+        Some(path) if !path.is_empty() => path,
+        _ => bail!("Log file path is missing for -D"),
+    };
+    let rotate_size = match cmd_parser.get_value::<String>("rotate-size")? {
+        Some(size) => {
+            let size = memory_unit_conversion(&size)?;
+            if size == 0 {
+                return Err(anyhow!(ConfigError::IllegalValueUnilateral(
+                    "rotate-size".to_string(),
+                    true,
+                    false,
+                    0
+                )));
+            }
+            Some(size)
+        }
+        None => None,
+    };
+    let rotate_keep = match cmd_parser.get_value::<u64>("rotate-keep")? {
+        Some(keep) => {
+            if rotate_size.is_none() {
+                bail!("rotate-keep of -D requires rotate-size");
+            }
+            if keep == 0 || keep > MAX_ROTATE_KEEP {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    "rotate-keep".to_string(),
+                    1,
+                    true,
+                    MAX_ROTATE_KEEP,
+                    true
+                )));
+            }
+            keep as usize
+        }
+        None => DEFAULT_ROTATE_KEEP,
+    };
+
+    Ok(LogFileConfig {
+        path,
+        rotate_size,
+        rotate_keep,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_config() {
+        assert_eq!(parse_log_config("").unwrap(), LogConfig::Stdout);
+        assert_eq!(
+            parse_log_config("syslog").unwrap(),
+            LogConfig::Syslog("televm".to_string())
+        );
+        assert_eq!(
+            parse_log_config("syslog:ident=televm-vm1").unwrap(),
+            LogConfig::Syslog("televm-vm1".to_string())
+        );
+        assert!(parse_log_config("syslog:ident=").is_err());
+        assert!(parse_log_config("syslog:ident=televm vm1").is_err());
+        assert!(parse_log_config("syslog:ident=televm[1]").is_err());
+        assert!(parse_log_config(&format!("syslog:ident={}", "a".repeat(65))).is_err());
+        assert!(parse_log_config("syslog:facility=daemon").is_err());
+        match parse_log_config("syslog.log,rotate-size=1M").unwrap() {
+            LogConfig::File(config) => assert_eq!(config.path, "syslog.log"),
+            _ => panic!("syslog.log is a log file"),
+        }
+    }
+
+    #[test]
+    fn test_parse_log_file() {
+        let config = parse_log_file("/var/log/televm.log").unwrap();
+        assert_eq!(config.path, "/var/log/televm.log");
+        assert!(config.rotate_size.is_none());
+
+        let config = parse_log_file("/var/log/televm.log,rotate-size=50M,rotate-keep=3").unwrap();
+        assert_eq!(config.rotate_size, Some(50 * 1024 * 1024));
+        assert_eq!(config.rotate_keep, 3);
+        let config = parse_log_file("televm.log,rotate-size=4K").unwrap();
+        assert_eq!(config.rotate_size, Some(4096));
+        assert_eq!(config.rotate_keep, DEFAULT_ROTATE_KEEP);
+
+        assert!(parse_log_file("televm.log,rotate-keep=3").is_err());
+        assert!(parse_log_file("televm.log,rotate-size=0").is_err());
+        assert!(parse_log_file("televm.log,rotate-size=1M,rotate-keep=0").is_err());
+        assert!(parse_log_file("televm.log,rotate-size=1M,rotate-keep=101").is_err());
+        assert!(parse_log_file("televm.log,rotate-size=huge").is_err());
+        assert!(parse_log_file("televm.log,rotate=1M").is_err());
+        assert!(parse_log_file("rotate-size=1M").is_err());
+    }
+}
@@ -10,14 +10,18 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
 use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use util::unix::HUGETLBFS_MAGIC;
 
 use super::error::ConfigError;
 use crate::config::{
-    CmdParser, ConfigCheck, ExBool, IntegerList, VmConfig, MAX_NODES, MAX_STRING_LENGTH,
+    CmdParser, ConfigCheck, CpuAffinity, ExBool, IntegerList, RebootAction, ShutdownAction,
+    VmConfig, MAX_NODES, MAX_STRING_LENGTH,
 };
 
 const DEFAULT_CPUS: u8 = 1;
@@ -28,12 +32,21 @@ const DEFAULT_CLUSTERS: u8 = 1;
 const DEFAULT_SOCKETS: u8 = 1;
 const DEFAULT_MAX_CPUS: u8 = 1;
 const DEFAULT_MEMSIZE: u64 = 256;
-const MAX_NR_CPUS: u64 = 254;
+const DEFAULT_REALIZE_THREADS: u8 = 4;
+pub const MAX_NR_CPUS: u64 = 254;
 const MIN_NR_CPUS: u64 = 1;
 const MAX_MEMSIZE: u64 = 549_755_813_888;
 const MIN_MEMSIZE: u64 = 134_217_728;
+/// Maximum number of memory slots of `-m slots`.
+const MAX_MEM_SLOTS: u64 = 256;
+const K: u64 = 1024;
 pub const M: u64 = 1024 * 1024;
 pub const G: u64 = 1024 * 1024 * 1024;
+const T: u64 = 1024 * G;
+/// Cpu models of `-cpu`, which are both the host cpu.
+pub const CPU_MODELS: [&str; 2] = ["host", "rv64"];
+/// Single-letter ISA extensions which can be turned on or off by `-cpu`.
+pub const ISA_EXTENSIONS: [char; 8] = ['a', 'c', 'd', 'f', 'h', 'i', 'm', 'v'];
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MachineType {
@@ -94,6 +107,17 @@ impl Default for MemZoneConfig {
     }
 }
 
+/// Config of `memory-backend-memfd` object, which backs guest RAM with memfd.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemFdBackendConfig {
+    pub id: String,
+    pub size: u64,
+    /// Whether RAM is mapped shared, so that it can be used by vhost-user backend.
+    pub share: bool,
+    /// Whether size of the memfd is sealed.
+    pub seal: bool,
+}
+
 /// Config that contains machine's memory information config.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MachineMemConfig {
@@ -103,6 +127,25 @@ pub struct MachineMemConfig {
     pub mem_share: bool,
     pub mem_prealloc: bool,
     pub mem_zones: Option<Vec<MemZoneConfig>>,
+    pub mem_backend: Option<MemFdBackendConfig>,
+    /// Number of threads touching pages for `mem_prealloc`, default is the
+    /// smaller one of vcpus and host cpus.
+    pub prealloc_threads: Option<u8>,
+    /// Whether swap space (or hugepages for hugetlbfs) is reserved for RAM,
+    /// RAM is mapped with `MAP_NORESERVE` to allow overcommit if not.
+    pub mem_reserve: bool,
+    /// Room for memory hotplug given by `-m maxmem=<size>,slots=<n>`.
+    pub hotplug: Option<MemHotplugConfig>,
+}
+
+/// Config of memory hotplug, the guest physical address space is left above the
+/// boot memory for `max_size` of memory in total.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MemHotplugConfig {
+    /// Maximum size of boot and hotplugged memory.
+    pub max_size: u64,
+    /// Maximum count of hotplugged memory devices.
+    pub slots: u64,
 }
 
 impl Default for MachineMemConfig {
@@ -114,13 +157,34 @@ impl Default for MachineMemConfig {
             mem_share: false,
             mem_prealloc: false,
             mem_zones: None,
+            mem_backend: None,
+            prealloc_threads: None,
+            mem_reserve: true,
+            hotplug: None,
         }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CpuConfig {
     pub pmu: PmuConfig,
+    /// Cpu model, one of `CPU_MODELS`.
+    pub model: String,
+    /// ISA extensions turned on or off, others are left as KVM provides.
+    pub isa_ext: BTreeMap<char, bool>,
+    /// Report steal time to guest by SBI STA extension.
+    pub steal_time: bool,
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        CpuConfig {
+            pmu: PmuConfig::default(),
+            model: CPU_MODELS[0].to_string(),
+            isa_ext: BTreeMap::new(),
+            steal_time: false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -149,6 +213,10 @@ pub struct MachineConfig {
     pub max_cpus: u8,
     pub mem_config: MachineMemConfig,
     pub cpu_config: CpuConfig,
+    pub cpu_affinity: CpuAffinity,
+    /// Number of threads realizing independent parts of machine, e.g. mapping guest
+    /// RAM and realizing device backends, 1 realizes them one by one for debugging.
+    pub realize_threads: u8,
 }
 
 impl Default for MachineConfig {
@@ -165,16 +233,89 @@ impl Default for MachineConfig {
             max_cpus: DEFAULT_MAX_CPUS,
             mem_config: MachineMemConfig::default(),
             cpu_config: CpuConfig::default(),
+            cpu_affinity: CpuAffinity::default(),
+            realize_threads: DEFAULT_REALIZE_THREADS,
         }
     }
 }
 
+/// Whether `path`, or its parent directory if it doesn't exist yet, is on hugetlbfs.
+fn is_hugetlbfs(path: &str) -> bool {
+    let mut path = std::path::Path::new(path);
+    if !path.exists() {
+        path = match path.parent() {
+            Some(parent) => parent,
+            None => return false,
+        };
+    }
+    let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    // Safe because struct `statfs` only contains plain-data-type field,
+    // and set to all-zero will not cause any undefined behavior.
+    let mut fstat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut fstat) } != 0 {
+        return false;
+    }
+    fstat.f_type as i64 == HUGETLBFS_MAGIC
+}
+
 impl ConfigCheck for MachineConfig {
     fn check(&self) -> Result<()> {
         if self.mem_config.mem_size < MIN_MEMSIZE || self.mem_config.mem_size > MAX_MEMSIZE {
             bail!("Memory size must >= 128MiB and <= 512GiB, default unit: MiB, current memory size: {:?} bytes",
             &self.mem_config.mem_size);
         }
+        if let Some(hotplug) = &self.mem_config.hotplug {
+            if hotplug.max_size < self.mem_config.mem_size {
+                bail!(
+                    "maxmem {} bytes must be no less than memory size {} bytes",
+                    hotplug.max_size,
+                    self.mem_config.mem_size
+                );
+            }
+            if hotplug.max_size > MAX_MEMSIZE {
+                bail!(
+                    "maxmem must be <= 512GiB, the address space of guest RAM, current maxmem: {} bytes",
+                    hotplug.max_size
+                );
+            }
+            if hotplug.slots == 0 || hotplug.slots > MAX_MEM_SLOTS {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    "slots".to_string(),
+                    1,
+                    true,
+                    MAX_MEM_SLOTS,
+                    true
+                )));
+            }
+        }
+        if let Some(backend) = &self.mem_config.mem_backend {
+            if backend.size != self.mem_config.mem_size {
+                bail!(
+                    "Size {} of memory backend {} doesn't match memory size {}",
+                    backend.size,
+                    backend.id,
+                    self.mem_config.mem_size
+                );
+            }
+            if self.mem_config.mem_path.is_some() {
+                bail!("Memory backend {} conflicts with -mem-path", backend.id);
+            }
+        }
+        if !self.mem_config.mem_reserve && !self.mem_config.mem_prealloc {
+            if let Some(path) = &self.mem_config.mem_path {
+                if is_hugetlbfs(path) {
+                    bail!(
+                        "Hugepages of {} are not reserved with mem-reserve=off, \
+                        guest may get SIGBUS when the pool runs out, use -mem-prealloc",
+                        path
+                    );
+                }
+            }
+        }
+        self.cpu_affinity.check(self.nr_cpus, self.max_cpus)?;
 
         Ok(())
     }
@@ -194,7 +335,8 @@ impl VmConfig {
             .push("accel")
             .push("usb")
             .push("dump-guest-core")
-            .push("mem-share");
+            .push("mem-share")
+            .push("mem-reserve");
         cmd_parser.parse(mach_config)?;
 
 
@@ -226,6 +368,9 @@ impl VmConfig {
         if let Some(mem_share) = cmd_parser.get_value::<ExBool>("mem-share")? {
             self.machine_config.mem_config.mem_share = mem_share.into();
         }
+        if let Some(mem_reserve) = cmd_parser.get_value::<ExBool>("mem-reserve")? {
+            self.machine_config.mem_config.mem_reserve = mem_reserve.into();
+        }
 
         Ok(())
     }
@@ -233,7 +378,11 @@ impl VmConfig {
     /// Add '-m' memory config to `VmConfig`.
     pub fn add_memory(&mut self, mem_config: &str) -> Result<()> {
         let mut cmd_parser = CmdParser::new("m");
-        cmd_parser.push("").push("size");
+        cmd_parser
+            .push("")
+            .push("size")
+            .push("maxmem")
+            .push("slots");
 
         cmd_parser.parse(mem_config)?;
 
@@ -247,6 +396,17 @@ impl VmConfig {
 
         self.machine_config.mem_config.mem_size = mem;
 
+        let max_size = cmd_parser.get_value::<String>("maxmem")?;
+        let slots = cmd_parser.get_value::<u64>("slots")?;
+        self.machine_config.mem_config.hotplug = match (max_size, slots) {
+            (Some(max_size), Some(slots)) => Some(MemHotplugConfig {
+                max_size: memory_unit_conversion(&max_size)?,
+                slots,
+            }),
+            (None, None) => None,
+            _ => bail!("maxmem and slots of -m must be given together"),
+        };
+
         Ok(())
     }
 
@@ -319,17 +479,19 @@ impl VmConfig {
         }
 
         if max_cpus < cpu {
-            return Err(anyhow!(ConfigError::IllegalValue(
-                "maxcpus".to_string(),
-                cpu as u64,
-                true,
-                MAX_NR_CPUS,
-                true,
-            )));
+            bail!("maxcpus({}) must not be less than cpus({})", max_cpus, cpu);
         }
 
         if sockets * dies * clusters * cores * threads != max_cpus {
-            bail!("sockets * dies * clusters * cores * threads must be equal to max_cpus");
+            bail!(
+                "sockets({}) * dies({}) * clusters({}) * cores({}) * threads({}) must be equal to maxcpus({})",
+                sockets,
+                dies,
+                clusters,
+                cores,
+                threads,
+                max_cpus
+            );
         }
 
         self.machine_config.nr_cpus = cpu as u8;
@@ -347,7 +509,37 @@ impl VmConfig {
         let mut cmd_parser = CmdParser::new("cpu");
         cmd_parser.push("");
         cmd_parser.push("pmu");
+        cmd_parser.push("steal-time");
+        for ext in ISA_EXTENSIONS {
+            cmd_parser.push(&ext.to_string());
+        }
         cmd_parser.parse(features)?;
+        if let Some(model) = cmd_parser.get_value::<String>("")? {
+            if !CPU_MODELS.contains(&model.as_str()) {
+                bail!(
+                    "Invalid cpu model {}, must be one of {:?}",
+                    model,
+                    CPU_MODELS
+                );
+            }
+            self.machine_config.cpu_config.model = model;
+        }
+        for ext in ISA_EXTENSIONS {
+            if let Some(enabled) = cmd_parser.get_value::<String>(&ext.to_string())? {
+                let enabled = match enabled.as_ref() {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!(
+                        "Invalid option of ISA extension {}, must be \"on\" or \"off\".",
+                        ext
+                    ),
+                };
+                self.machine_config.cpu_config.isa_ext.insert(ext, enabled);
+            }
+        }
+        if let Some(steal_time) = cmd_parser.get_value::<ExBool>("steal-time")? {
+            self.machine_config.cpu_config.steal_time = steal_time.into();
+        }
         //Check PMU when actually enabling PMU.
         if let Some(k) = cmd_parser.get_value::<String>("pmu")? {
             self.machine_config.cpu_config.pmu = match k.as_ref() {
@@ -367,6 +559,96 @@ impl VmConfig {
     pub fn enable_mem_prealloc(&mut self) {
         self.machine_config.mem_config.mem_prealloc = true;
     }
+
+    /// `-no-reboot` is the same as `-action reboot=shutdown`.
+    pub fn enable_no_reboot(&mut self) {
+        self.reboot_action = RebootAction::Shutdown;
+    }
+
+    /// `-no-shutdown` is the same as `-action shutdown=pause`.
+    pub fn enable_no_shutdown(&mut self) {
+        self.shutdown_action = ShutdownAction::Pause;
+    }
+
+    pub fn add_mem_prealloc_threads(&mut self, threads: &str) -> Result<()> {
+        let threads = threads.parse::<u8>().map_err(|_| {
+            anyhow!(ConfigError::ConvertValueFailed(
+                String::from("u8"),
+                "mem-prealloc-threads".to_string()
+            ))
+        })?;
+        if threads == 0 {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "mem-prealloc-threads".to_string(),
+                1,
+                true,
+                u8::MAX as u64,
+                true
+            )));
+        }
+        self.machine_config.mem_config.prealloc_threads = Some(threads);
+        Ok(())
+    }
+
+    pub fn add_realize_threads(&mut self, threads: &str) -> Result<()> {
+        let threads = threads.parse::<u8>().map_err(|_| {
+            anyhow!(ConfigError::ConvertValueFailed(
+                String::from("u8"),
+                "realize-threads".to_string()
+            ))
+        })?;
+        if threads == 0 {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "realize-threads".to_string(),
+                1,
+                true,
+                u8::MAX as u64,
+                true
+            )));
+        }
+        self.machine_config.realize_threads = threads;
+        Ok(())
+    }
+
+    /// Add `memory-backend-memfd` object which backs guest RAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_backend` - The memory backend cmdline string.
+    pub fn add_memfd_backend(&mut self, mem_backend: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::new("memory-backend-memfd");
+        cmd_parser
+            .push("")
+            .push("id")
+            .push("size")
+            .push("share")
+            .push("seal");
+        cmd_parser.parse(mem_backend)?;
+
+        if self.machine_config.mem_config.mem_backend.is_some() {
+            bail!("Only one memory-backend-memfd object is supported");
+        }
+        let id = cmd_parser
+            .get_value::<String>("id")?
+            .with_context(|| ConfigError::FieldIsMissing("id", "memory-backend-memfd"))?;
+        let size = cmd_parser
+            .get_value::<String>("size")?
+            .with_context(|| ConfigError::FieldIsMissing("size", "memory-backend-memfd"))?;
+        // Memfd is created to be shared with other process, share and seal it by default.
+        let share = cmd_parser
+            .get_value::<ExBool>("share")?
+            .map_or(true, |share| share.into());
+        let seal = cmd_parser
+            .get_value::<ExBool>("seal")?
+            .map_or(true, |seal| seal.into());
+        self.machine_config.mem_config.mem_backend = Some(MemFdBackendConfig {
+            id,
+            size: memory_unit_conversion(&size)?,
+            share,
+            seal,
+        });
+        Ok(())
+    }
 }
 
 impl VmConfig {
@@ -539,56 +821,28 @@ fn adjust_topology(
     (max_cpus, sockets, cores, threads)
 }
 
-/// Convert memory units from GiB, Mib to Byte.
+/// Convert memory units from KiB, MiB, GiB or TiB to Byte.
 ///
 /// # Arguments
 ///
 /// * `origin_value` - The origin memory value from user.
-fn memory_unit_conversion(origin_value: &str) -> Result<u64> {
-    if (origin_value.ends_with('M') | origin_value.ends_with('m'))
-        && (origin_value.contains('M') ^ origin_value.contains('m'))
-    {
-        let value = origin_value.replacen('M', "", 1);
-        let value = value.replacen('m', "", 1);
-        get_inner(
-            value
-                .parse::<u64>()
-                .map_err(|_| {
-                    anyhow!(ConfigError::ConvertValueFailed(
-                        origin_value.to_string(),
-                        String::from("u64")
-                    ))
-                })?
-                .checked_mul(M),
-        )
-    } else if (origin_value.ends_with('G') | origin_value.ends_with('g'))
-        && (origin_value.contains('G') ^ origin_value.contains('g'))
-    {
-        let value = origin_value.replacen('G', "", 1);
-        let value = value.replacen('g', "", 1);
-        get_inner(
-            value
-                .parse::<u64>()
-                .map_err(|_| {
-                    anyhow!(ConfigError::ConvertValueFailed(
-                        origin_value.to_string(),
-                        String::from("u64")
-                    ))
-                })?
-                .checked_mul(G),
-        )
-    } else {
-        let size = origin_value.parse::<u64>().map_err(|_| {
-            anyhow!(ConfigError::ConvertValueFailed(
-                origin_value.to_string(),
-                String::from("u64")
-            ))
-        })?;
-
-        let memory_size = size.checked_mul(M);
-
-        get_inner(memory_size)
-    }
+pub(crate) fn memory_unit_conversion(origin_value: &str) -> Result<u64> {
+    let (value, unit) = match origin_value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&origin_value[..origin_value.len() - 1], K),
+        Some('M') => (&origin_value[..origin_value.len() - 1], M),
+        Some('G') => (&origin_value[..origin_value.len() - 1], G),
+        Some('T') => (&origin_value[..origin_value.len() - 1], T),
+        // Default unit is MiB.
+        _ => (origin_value, M),
+    };
+    let size = value.parse::<u64>().map_err(|_| {
+        anyhow!(ConfigError::ConvertValueFailed(
+            origin_value.to_string(),
+            String::from("u64")
+        ))
+    })?;
+
+    get_inner(size.checked_mul(unit))
 }
 
 fn get_inner<T>(outer: Option<T>) -> Result<T> {
@@ -612,6 +866,10 @@ mod tests {
             dump_guest_core: false,
             mem_prealloc: false,
             mem_zones: None,
+            mem_backend: None,
+            prealloc_threads: None,
+            mem_reserve: true,
+            hotplug: None,
         };
         let mut machine_config = MachineConfig {
             mach_type: MachineType::MicroVm,
@@ -624,6 +882,8 @@ mod tests {
             max_cpus: MIN_NR_CPUS as u8,
             mem_config: memory_config,
             cpu_config: CpuConfig::default(),
+            cpu_affinity: CpuAffinity::default(),
+            realize_threads: DEFAULT_REALIZE_THREADS,
         };
         assert!(machine_config.check().is_ok());
 
@@ -721,6 +981,16 @@ mod tests {
         let test_string = "M6m";
         let ret = memory_unit_conversion(test_string);
         assert!(ret.is_err());
+
+        assert_eq!(memory_unit_conversion("512k").unwrap(), 512 * 1024);
+        assert_eq!(memory_unit_conversion("512K").unwrap(), 512 * 1024);
+        assert_eq!(memory_unit_conversion("1t").unwrap(), 1024 * G);
+        assert_eq!(memory_unit_conversion("1T").unwrap(), 1024 * G);
+        assert!(memory_unit_conversion("").is_err());
+        assert!(memory_unit_conversion("G").is_err());
+        assert!(memory_unit_conversion("-1G").is_err());
+        // Overflow.
+        assert!(memory_unit_conversion("16777216T").is_err());
     }
 
     #[test]
@@ -824,6 +1094,65 @@ mod tests {
         assert_eq!(mem_size, 8 * 1024 * 1024 * 1024);
     }
 
+    #[test]
+    fn test_add_memory_hotplug() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_memory("2G").is_ok());
+        assert_eq!(vm_config.machine_config.mem_config.mem_size, 2 * G);
+        assert_eq!(vm_config.machine_config.mem_config.hotplug, None);
+        assert!(vm_config.machine_config.check().is_ok());
+
+        assert!(vm_config.add_memory("size=2G,maxmem=8G,slots=4").is_ok());
+        assert_eq!(vm_config.machine_config.mem_config.mem_size, 2 * G);
+        assert_eq!(
+            vm_config.machine_config.mem_config.hotplug,
+            Some(MemHotplugConfig {
+                max_size: 8 * G,
+                slots: 4
+            })
+        );
+        assert!(vm_config.machine_config.check().is_ok());
+        // maxmem can be the same as size.
+        assert!(vm_config.add_memory("2G,maxmem=2G,slots=1").is_ok());
+        assert!(vm_config.machine_config.check().is_ok());
+
+        // maxmem and slots go together.
+        assert!(vm_config.add_memory("size=2G,maxmem=8G").is_err());
+        assert!(vm_config.add_memory("size=2G,slots=4").is_err());
+        assert!(vm_config.add_memory("size=2G,maxmem=8X,slots=4").is_err());
+        assert!(vm_config.add_memory("size=2G,maxmem=8G,slots=-1").is_err());
+
+        // Values out of range are rejected by check.
+        for mem in [
+            "0",
+            "size=0,maxmem=8G,slots=4",
+            "size=2G,maxmem=1G,slots=4",
+            "size=2G,maxmem=0,slots=4",
+            "size=2G,maxmem=1T,slots=4",
+            "size=2G,maxmem=8G,slots=0",
+            "size=2G,maxmem=8G,slots=257",
+        ] {
+            assert!(vm_config.add_memory(mem).is_ok());
+            assert!(vm_config.machine_config.check().is_err(), "{}", mem);
+        }
+        let err = vm_config.machine_config.check().unwrap_err();
+        assert_eq!(err.to_string(), "slots must >= 1 and <= 256.");
+    }
+
+    #[test]
+    fn test_mem_reserve() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.machine_config.mem_config.mem_reserve);
+        vm_config.add_machine("type=none,mem-reserve=off").unwrap();
+        assert!(!vm_config.machine_config.mem_config.mem_reserve);
+        assert!(vm_config.machine_config.check().is_ok());
+        vm_config.add_mem_path("/tmp").unwrap();
+        assert!(vm_config.machine_config.check().is_ok());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_machine("type=none,mem-reserve=none").is_err());
+    }
+
     #[test]
     fn test_add_machine() {
         let mut vm_config = VmConfig::default();
@@ -898,6 +1227,34 @@ mod tests {
         assert_eq!(mem_path, memory_path_str);
     }
 
+    #[test]
+    fn test_add_memfd_backend() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_object("memory-backend-memfd,id=mem0,share=on")
+            .is_err());
+        vm_config
+            .add_object("memory-backend-memfd,id=mem0,size=1G,seal=off")
+            .unwrap();
+        let backend = vm_config.machine_config.mem_config.mem_backend.clone();
+        let backend = backend.unwrap();
+        assert_eq!(backend.id, "mem0");
+        assert_eq!(backend.size, 1 << 30);
+        assert!(backend.share);
+        assert!(!backend.seal);
+        assert!(vm_config
+            .add_object("memory-backend-memfd,id=mem1,size=1G")
+            .is_err());
+
+        // Size of backend must match memory size.
+        vm_config.add_memory("512M").unwrap();
+        assert!(vm_config.machine_config.check().is_err());
+        vm_config.add_memory("1G").unwrap();
+        assert!(vm_config.machine_config.check().is_ok());
+        vm_config.add_mem_path("/path/to/memory-backend").unwrap();
+        assert!(vm_config.machine_config.check().is_err());
+    }
+
     #[test]
     fn test_enable_memory_prealloc() {
         let mut vm_config = VmConfig::default();
@@ -907,6 +1264,27 @@ mod tests {
         vm_config.enable_mem_prealloc();
         let mem_prealloc = vm_config.machine_config.mem_config.mem_prealloc;
         assert_eq!(mem_prealloc, true);
+
+        assert!(vm_config.add_mem_prealloc_threads("0").is_err());
+        assert!(vm_config.add_mem_prealloc_threads("256").is_err());
+        vm_config.add_mem_prealloc_threads("4").unwrap();
+        assert_eq!(
+            vm_config.machine_config.mem_config.prealloc_threads,
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn test_add_realize_threads() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(
+            vm_config.machine_config.realize_threads,
+            DEFAULT_REALIZE_THREADS
+        );
+        assert!(vm_config.add_realize_threads("0").is_err());
+        assert!(vm_config.add_realize_threads("a").is_err());
+        vm_config.add_realize_threads("1").unwrap();
+        assert_eq!(vm_config.machine_config.realize_threads, 1);
     }
 
     #[test]
@@ -939,6 +1317,36 @@ mod tests {
         let cpu_cfg_str = "cpus=255,sockets=255,cores=1,threads=1";
         let cpu_cfg_ret = vm_config.add_cpu(cpu_cfg_str);
         assert!(cpu_cfg_ret.is_err());
+
+        let mut vm_config = VmConfig::default();
+        vm_config
+            .add_cpu("cpus=8,sockets=2,clusters=2,cores=1,threads=2")
+            .unwrap();
+        let machine_config = &vm_config.machine_config;
+        assert_eq!(machine_config.nr_sockets, 2);
+        assert_eq!(machine_config.nr_clusters, 2);
+        assert_eq!(machine_config.nr_cores, 1);
+        assert_eq!(machine_config.nr_threads, 2);
+        assert_eq!(machine_config.max_cpus, 8);
+
+        // Missing level is derived from the others.
+        let mut vm_config = VmConfig::default();
+        vm_config.add_cpu("cpus=8,sockets=2,threads=2").unwrap();
+        assert_eq!(vm_config.machine_config.nr_cores, 2);
+
+        // Inconsistent topology names the offending values.
+        let mut vm_config = VmConfig::default();
+        let err = vm_config
+            .add_cpu("cpus=8,maxcpus=8,sockets=2,cores=3,threads=2")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "sockets(2) * dies(1) * clusters(1) * cores(3) * threads(2) must be equal to maxcpus(8)"
+        );
+        let err = vm_config
+            .add_cpu("cpus=8,maxcpus=4,sockets=2,cores=2,threads=1")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "maxcpus(4) must not be less than cpus(8)");
     }
 
     #[test]
@@ -983,6 +1391,29 @@ mod tests {
         assert!(policy == HostMemPolicy::NotSupported);
     }
 
+    #[test]
+    fn test_cpu_isa_features() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.cpu_config.model, "host");
+        vm_config.add_cpu_feature("rv64,v=on,h=off").unwrap();
+        let cpu_config = &vm_config.machine_config.cpu_config;
+        assert_eq!(cpu_config.model, "rv64");
+        assert_eq!(cpu_config.isa_ext.get(&'v'), Some(&true));
+        assert_eq!(cpu_config.isa_ext.get(&'h'), Some(&false));
+        assert_eq!(cpu_config.isa_ext.get(&'a'), None);
+
+        assert!(vm_config.add_cpu_feature("rv32").is_err());
+        assert!(vm_config.add_cpu_feature("host,v=yes").is_err());
+        assert!(vm_config.add_cpu_feature("host,zba=on").is_err());
+
+        assert!(!vm_config.machine_config.cpu_config.steal_time);
+        vm_config.add_cpu_feature("host,steal-time=on").unwrap();
+        assert!(vm_config.machine_config.cpu_config.steal_time);
+        vm_config.add_cpu_feature("host,steal-time=off").unwrap();
+        assert!(!vm_config.machine_config.cpu_config.steal_time);
+        assert!(vm_config.add_cpu_feature("host,steal-time=1").is_err());
+    }
+
     #[cfg(target_arch = "aarch64")]
     #[test]
     fn test_cpu_features() {
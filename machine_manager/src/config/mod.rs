@@ -11,41 +11,67 @@
 // See the Mulan PSL v2 for more details.
 
 
+pub use action::*;
+pub use affinity::*;
+pub use balloon::*;
 pub use boot_source::*;
 pub use chardev::*;
+pub use defaults::*;
 pub use devices::*;
 pub use drive::*;
 pub use error::ConfigError;
+pub use file::*;
 pub use fs::*;
+pub use fsdev::*;
+pub use gdb::*;
 pub use incoming::*;
 pub use iothread::*;
+pub use logfile::*;
 pub use machine_config::*;
 pub use network::*;
 pub use pci::*;
+pub use privilege::*;
 pub use rng::*;
+pub use rtc::*;
+pub use sandbox::*;
 pub use sasl_auth::*;
 pub use tls_creds::*;
 pub use vnc::*;
+pub use watchdog::*;
 
+mod action;
+mod affinity;
+mod balloon;
 mod boot_source;
 mod chardev;
+mod defaults;
 mod devices;
 mod drive;
 pub mod error;
+mod file;
 mod fs;
+mod fsdev;
+mod gdb;
 mod incoming;
 mod iothread;
+mod logfile;
 mod machine_config;
 mod network;
 mod pci;
+mod privilege;
 mod rng;
+mod rtc;
+mod sandbox;
 mod sasl_auth;
 mod tls_creds;
+mod validate;
 pub mod vnc;
+mod watchdog;
 
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
+use std::os::unix::io::FromRawFd;
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
@@ -56,10 +82,12 @@ use util::device_tree::{self, FdtBuilder};
 use util::{
     file::{get_file_alignment, open_file},
     test_helper::is_test_enabled,
-    trace::enable_trace_events,
+    trace::{enable_trace_events, set_trace_event_enabled},
     AsAny,
 };
 
+use crate::qmp::{parse_fdset_path, QmpChannel};
+
 pub const MAX_STRING_LENGTH: usize = 255;
 pub const MAX_PATH_LENGTH: usize = 4096;
 // Maximum length of the socket path is restricted by linux.
@@ -90,9 +118,12 @@ pub struct VmConfig {
     pub drives: HashMap<String, DriveConfig>,
     pub netdevs: HashMap<String, NetDevcfg>,
     pub chardev: HashMap<String, ChardevConfig>,
+    pub fsdevs: HashMap<String, FsDevConfig>,
     pub virtio_serial: Option<VirtioSerialInfo>,
     pub devices: Vec<(String, String)>,
     pub serial: Option<SerialConfig>,
+    /// Dedicated backend of SBI debug console.
+    pub sbi_console: Option<SerialConfig>,
     pub iothreads: Option<Vec<IothreadConfig>>,
     pub object: ObjectConfig,
     pub pflashs: Option<Vec<PFlashConfig>>,
@@ -101,6 +132,16 @@ pub struct VmConfig {
   //  pub numa_nodes: Vec<(String, String)>,
     pub incoming: Option<Incoming>,
     pub vnc: Option<VncConfig>,
+    pub gdb: Option<String>,
+    pub reboot_action: RebootAction,
+    pub shutdown_action: ShutdownAction,
+    pub watchdog_action: WatchdogAction,
+    pub panic_action: PanicAction,
+    pub powerdown: PowerdownConfig,
+    pub rtc: RtcConfig,
+    pub sandbox: Option<SandboxConfig>,
+    pub runas: Option<RunAs>,
+    pub chroot: Option<String>,
 }
 
 impl VmConfig {
@@ -130,7 +171,10 @@ impl VmConfig {
         }
 
         let mut stdio_count = 0;
-        if let Some(serial) = self.serial.as_ref() {
+        for serial in [self.serial.as_ref(), self.sbi_console.as_ref()]
+            .into_iter()
+            .flatten()
+        {
             if serial.chardev.backend == ChardevType::Stdio {
                 stdio_count += 1;
             }
@@ -141,15 +185,24 @@ impl VmConfig {
             }
         }
         if stdio_count > 0 && is_daemonize {
-            bail!("Device redirected to stdio and daemonize can't be set together");
+            bail!(
+                "Device redirected to stdio and daemonize can't be set together, stdio is \
+                 closed once daemonized, use a file, unix, tcp or null backend instead"
+            );
         }
         if stdio_count > 1 {
             bail!("Can't set multiple devices redirected to stdio");
         }
+        self.check_drive_references()?;
 
         Ok(())
     }
 
+    /// Serialize the effective configuration for `-print-config`.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).with_context(|| "Failed to serialize VmConfig")
+    }
+
     /// Add argument `name` to `VmConfig`.
     ///
     /// # Arguments
@@ -198,6 +251,9 @@ impl VmConfig {
                     bail!("Object: {} has been added", id);
                 }
             }
+            "memory-backend-memfd" => {
+                self.add_memfd_backend(object_args)?;
+            }
             "tls-creds-x509" => {
                 self.add_tlscred(object_args)?;
             }
@@ -239,27 +295,45 @@ impl VmConfig {
         Ok(())
     }
 
-    /// Add a file to drive file store.
+    /// Add a file to drive file store. A file with `share_rw` is never locked, so
+    /// other processes can write it.
     pub fn add_drive_file(
         drive_files: &mut HashMap<String, DriveFile>,
         path: &str,
         read_only: bool,
         direct: bool,
+        share_rw: bool,
     ) -> Result<()> {
         if let Some(drive_file) = drive_files.get_mut(path) {
-            if drive_file.read_only && read_only {
-                // File can be shared with read_only.
+            if (drive_file.read_only && read_only) || (drive_file.share_rw && share_rw) {
+                // File can be shared with read_only or share-rw.
                 drive_file.count += 1;
                 return Ok(());
             } else {
                 return Err(anyhow!(
-                    "Failed to add drive {}, file can only be shared with read_only. \
+                    "Failed to add drive {}, file can only be shared with read_only or share-rw. \
                     Is it used more than once or another process using the same file?",
                     path
                 ));
             }
         }
-        let mut file = open_file(path, read_only, direct)?;
+        let mut file = match parse_fdset_path(path)? {
+            Some(fdset_id) => {
+                let mut flags = if read_only {
+                    libc::O_RDONLY
+                } else {
+                    libc::O_RDWR
+                };
+                if direct {
+                    flags |= libc::O_DIRECT;
+                }
+                let fd = QmpChannel::dup_fdset_fd(fdset_id, flags)
+                    .with_context(|| format!("Failed to open drive file {}", path))?;
+                // SAFETY: the fd is duplicated for the drive and owned by nobody else.
+                unsafe { File::from_raw_fd(fd) }
+            }
+            None => open_file(path, read_only, direct)?,
+        };
         let (req_align, buf_align) = get_file_alignment(&file, direct);
         if req_align == 0 || buf_align == 0 {
             bail!(
@@ -277,6 +351,7 @@ impl VmConfig {
             read_only,
             path: path.to_string(),
             locked: false,
+            share_rw,
             req_align,
             buf_align,
         };
@@ -334,6 +409,7 @@ impl VmConfig {
                 &drive.path_on_host,
                 drive.read_only,
                 drive.direct,
+                drive.share_rw,
             )?;
         }
         if let Some(pflashs) = self.pflashs.as_ref() {
@@ -343,6 +419,7 @@ impl VmConfig {
                     &pflash.path_on_host,
                     pflash.read_only,
                     false,
+                    false,
                 )?;
             }
         }
@@ -461,7 +538,7 @@ impl CmdParser {
     /// # Arguments
     ///
     /// * `cmd_param`: The whole cmdline parameter string.
-    fn get_parameters(&mut self, cmd_param: &str) -> Result<()> {
+    pub fn get_parameters(&mut self, cmd_param: &str) -> Result<()> {
         if cmd_param.starts_with(',') || cmd_param.ends_with(',') {
             return Err(anyhow!(ConfigError::InvalidParam(
                 cmd_param.to_string(),
@@ -553,6 +630,16 @@ impl From<ExBool> for bool {
 }
 
 pub fn add_trace_events(config: &str) -> Result<()> {
+    // MMIO tracing of sysbus devices, e.g. `sysbus:virtio-mmio`. The device type
+    // is checked by sysbus when machine is realized.
+    if let Some(dev_type) = config.strip_prefix("sysbus:") {
+        if dev_type.is_empty() {
+            bail!("trace: sysbus device type must be set.");
+        }
+        set_trace_event_enabled(config, true);
+        return Ok(());
+    }
+
     let mut cmd_parser = CmdParser::new("trace");
     cmd_parser.push("events");
     cmd_parser.get_parameters(config)?;
@@ -719,6 +806,15 @@ mod tests {
         std::fs::remove_file(file).unwrap();
     }
 
+    #[test]
+    fn test_add_trace_events_sysbus() {
+        use util::trace::is_trace_event_enabled;
+
+        assert!(add_trace_events("sysbus:").is_err());
+        add_trace_events("sysbus:virtio-mmio").unwrap();
+        assert!(is_trace_event_enabled("sysbus:virtio-mmio"));
+    }
+
     #[test]
     fn test_add_global_config() {
         let mut vm_config = VmConfig::default();
@@ -0,0 +1,76 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+pub use util::privilege::RunAs;
+
+use super::VmConfig;
+
+impl VmConfig {
+    /// Add the user given by `-runas user[:group]`, which vm switches to before
+    /// vcpus start.
+    pub fn add_runas(&mut self, runas: &str) -> Result<()> {
+        self.runas = Some(RunAs::parse(runas)?);
+        Ok(())
+    }
+
+    /// Add the root directory given by `-chroot`, which vm changes to before vcpus
+    /// start.
+    pub fn add_chroot(&mut self, dir: &str) -> Result<()> {
+        let path = Path::new(dir)
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve chroot {}", dir))?;
+        if !path.is_dir() {
+            bail!("Chroot {} is not a directory", dir);
+        }
+        if path == Path::new("/") {
+            bail!("Chroot {} is the root directory already", dir);
+        }
+        self.chroot = Some(path.to_string_lossy().into_owned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_runas() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_runas("4242:4343").is_ok());
+        let runas = vm_config.runas.as_ref().unwrap();
+        assert_eq!((runas.uid, runas.gid), (4242, 4343));
+
+        assert!(vm_config.add_runas("root").is_err());
+        assert!(vm_config.add_runas("4242:").is_err());
+        assert!(vm_config.add_runas("televm-no-such-user").is_err());
+    }
+
+    #[test]
+    fn test_add_chroot() {
+        let mut vm_config = VmConfig::default();
+        let dir = std::env::temp_dir();
+        assert!(vm_config.add_chroot(dir.to_str().unwrap()).is_ok());
+        assert_eq!(
+            vm_config.chroot,
+            Some(dir.canonicalize().unwrap().to_string_lossy().into_owned())
+        );
+
+        assert!(vm_config.add_chroot("/").is_err());
+        assert!(vm_config.add_chroot("/televm-no-such-dir").is_err());
+        assert!(vm_config.add_chroot("/proc/self/status").is_err());
+    }
+}
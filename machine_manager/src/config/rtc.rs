@@ -0,0 +1,257 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{CmdParser, VmConfig};
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// Initial time of rtc, given by `base` of `-rtc`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RtcBase {
+    Utc,
+    /// Host local time, for guests expecting the rtc in local time.
+    Localtime,
+    /// Fixed start time in seconds since unix epoch.
+    Datetime(i64),
+}
+
+impl Default for RtcBase {
+    fn default() -> Self {
+        RtcBase::Utc
+    }
+}
+
+impl FromStr for RtcBase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utc" => Ok(RtcBase::Utc),
+            "localtime" => Ok(RtcBase::Localtime),
+            _ => Ok(RtcBase::Datetime(parse_datetime(s).with_context(|| {
+                format!(
+                    "Invalid rtc base {}, must be utc, localtime or a datetime",
+                    s
+                )
+            })?)),
+        }
+    }
+}
+
+/// Clock driving rtc, given by `clock` of `-rtc`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RtcClock {
+    /// Follow host time, also while vm is paused.
+    Host,
+    /// Stop while vm is paused, so that guest doesn't observe time jumps.
+    Vm,
+}
+
+impl Default for RtcClock {
+    fn default() -> Self {
+        RtcClock::Vm
+    }
+}
+
+impl RtcClock {
+    /// Name of the clock used by cmdline and `query-rtc`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RtcClock::Host => "host",
+            RtcClock::Vm => "vm",
+        }
+    }
+}
+
+impl FromStr for RtcClock {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "host" => Ok(RtcClock::Host),
+            "vm" => Ok(RtcClock::Vm),
+            _ => Err(anyhow!("Invalid rtc clock {}, must be host or vm", s)),
+        }
+    }
+}
+
+/// Config of rtc, given by `-rtc base=<base>,clock=<clock>`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RtcConfig {
+    pub base: RtcBase,
+    pub clock: RtcClock,
+}
+
+/// Days since unix epoch of the date in proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of the days since unix epoch, inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Parse ISO8601 datetime `YYYY-MM-DD[THH:MM:SS][Z]` in UTC to seconds since unix epoch.
+pub fn parse_datetime(datetime: &str) -> Result<i64> {
+    let datetime = datetime.strip_suffix('Z').unwrap_or(datetime);
+    let (date, time) = datetime.split_once('T').unwrap_or((datetime, "00:00:00"));
+    let fields = |s: &str, len: usize| -> Result<Vec<i64>> {
+        let fields = s
+            .split(['-', ':'])
+            .map(|field| {
+                if field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()) {
+                    bail!("Invalid field {:?}", field);
+                }
+                Ok(field.parse::<i64>()?)
+            })
+            .collect::<Result<Vec<i64>>>()?;
+        if fields.len() != len {
+            bail!("Expected {} fields in {}", len, s);
+        }
+        Ok(fields)
+    };
+    let (date, time) = (fields(date, 3)?, fields(time, 3)?);
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) {
+        bail!("Date {} out of range", datetime);
+    }
+    // Day is checked by converting the date back.
+    if day < 1 || civil_from_days(days_from_civil(year, month, day)) != (year, month, day) {
+        bail!("Date {} out of range", datetime);
+    }
+    if time[0] > 23 || time[1] > 59 || time[2] > 59 {
+        bail!("Time {} out of range", datetime);
+    }
+    Ok(days_from_civil(year, month, day) * SECS_PER_DAY + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+/// Format seconds since unix epoch to ISO8601 datetime `YYYY-MM-DDTHH:MM:SSZ` in UTC.
+pub fn format_datetime(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+    let secs_of_day = secs.rem_euclid(SECS_PER_DAY);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+impl VmConfig {
+    /// Add the config of rtc given by `-rtc`.
+    pub fn add_rtc(&mut self, rtc_config: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::new("rtc");
+        cmd_parser.push("base").push("clock").push("driftfix");
+        cmd_parser.parse(rtc_config)?;
+
+        if let Some(base) = cmd_parser.get_value::<String>("base")? {
+            self.rtc.base = base.parse()?;
+        }
+        if let Some(clock) = cmd_parser.get_value::<String>("clock")? {
+            self.rtc.clock = clock.parse()?;
+        }
+        // Drift fix is for x86 guests only, it's accepted for compatibility.
+        if let Some(driftfix) = cmd_parser.get_value::<String>("driftfix")? {
+            if driftfix != "none" && driftfix != "slew" {
+                bail!("Invalid rtc driftfix {}, must be none or slew", driftfix);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datetime() {
+        assert_eq!(parse_datetime("1970-01-01").unwrap(), 0);
+        assert_eq!(
+            parse_datetime("2006-06-17T16:01:21").unwrap(),
+            1_150_560_081
+        );
+        assert_eq!(
+            parse_datetime("2006-06-17T16:01:21Z").unwrap(),
+            1_150_560_081
+        );
+        assert_eq!(parse_datetime("2000-02-29T23:59:59").unwrap(), 951_868_799);
+        assert_eq!(format_datetime(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_datetime(1_150_560_081), "2006-06-17T16:01:21Z");
+        assert_eq!(format_datetime(951_868_799), "2000-02-29T23:59:59Z");
+        for secs in [1, 86_399, 86_400, 4_102_444_800, 253_402_300_799] {
+            assert_eq!(parse_datetime(&format_datetime(secs)).unwrap(), secs);
+        }
+
+        assert!(parse_datetime("").is_err());
+        assert!(parse_datetime("2006-06").is_err());
+        assert!(parse_datetime("2006-06-17T16:01").is_err());
+        assert!(parse_datetime("2006-13-17").is_err());
+        assert!(parse_datetime("2006-02-29").is_err());
+        assert!(parse_datetime("2006-06-00").is_err());
+        assert!(parse_datetime("2006-06-17T24:00:00").is_err());
+        assert!(parse_datetime("1969-12-31T23:59:59").is_err());
+        assert!(parse_datetime("2006-06-+7").is_err());
+        assert!(parse_datetime("2006-06-17 16:01:21").is_err());
+    }
+
+    #[test]
+    fn test_add_rtc() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.rtc, RtcConfig::default());
+        assert_eq!(vm_config.rtc.base, RtcBase::Utc);
+        assert_eq!(vm_config.rtc.clock, RtcClock::Vm);
+
+        assert!(vm_config.add_rtc("base=localtime,clock=host").is_ok());
+        assert_eq!(vm_config.rtc.base, RtcBase::Localtime);
+        assert_eq!(vm_config.rtc.clock, RtcClock::Host);
+        assert!(vm_config.add_rtc("base=2006-06-17T16:01:21").is_ok());
+        assert_eq!(vm_config.rtc.base, RtcBase::Datetime(1_150_560_081));
+        assert!(vm_config.add_rtc("base=utc,clock=vm,driftfix=slew").is_ok());
+        assert_eq!(vm_config.rtc, RtcConfig::default());
+
+        assert!(vm_config.add_rtc("base=local").is_err());
+        assert!(vm_config.add_rtc("clock=rt").is_err());
+        assert!(vm_config.add_rtc("driftfix=fast").is_err());
+        assert!(vm_config.add_rtc("base=utc,speed=1").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{CmdParser, ExBool, VmConfig};
+
+/// Seccomp sandbox given by `-sandbox on[,obsolete=allow|deny][,debug=on|off]`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SandboxConfig {
+    /// Obsolete syscalls are out of the allowlists too.
+    pub deny_obsolete: bool,
+    /// Log the syscalls out of the allowlists instead of killing the process.
+    pub debug: bool,
+}
+
+impl VmConfig {
+    /// Add the seccomp sandbox given by `-sandbox`, which is disabled by `off`.
+    pub fn add_sandbox(&mut self, sandbox_config: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::new("sandbox");
+        cmd_parser.push("").push("obsolete").push("debug");
+        cmd_parser.parse(sandbox_config)?;
+
+        let enable = match cmd_parser.get_value::<ExBool>("")? {
+            Some(enable) => enable.into(),
+            None => bail!("Sandbox must be set to on or off"),
+        };
+        let deny_obsolete = match cmd_parser.get_value::<String>("obsolete")?.as_deref() {
+            None | Some("allow") => false,
+            Some("deny") => true,
+            Some(obsolete) => bail!(
+                "Invalid sandbox obsolete {}, must be allow or deny",
+                obsolete
+            ),
+        };
+        let debug = cmd_parser
+            .get_value::<ExBool>("debug")?
+            .map(bool::from)
+            .unwrap_or_default();
+        self.sandbox = if enable {
+            Some(SandboxConfig {
+                deny_obsolete,
+                debug,
+            })
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_sandbox() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.sandbox.is_none());
+        assert!(vm_config.add_sandbox("on").is_ok());
+        assert_eq!(vm_config.sandbox, Some(SandboxConfig::default()));
+        assert!(vm_config.add_sandbox("on,obsolete=deny,debug=on").is_ok());
+        assert_eq!(
+            vm_config.sandbox,
+            Some(SandboxConfig {
+                deny_obsolete: true,
+                debug: true,
+            })
+        );
+        assert!(vm_config.add_sandbox("off").is_ok());
+        assert!(vm_config.sandbox.is_none());
+
+        assert!(vm_config.add_sandbox("obsolete=deny").is_err());
+        assert!(vm_config.add_sandbox("enable").is_err());
+        assert!(vm_config.add_sandbox("on,obsolete=kill").is_err());
+        assert!(vm_config.add_sandbox("on,debug=log").is_err());
+        assert!(vm_config.add_sandbox("on,spawn=deny").is_err());
+    }
+}
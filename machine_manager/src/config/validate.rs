@@ -0,0 +1,236 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use super::{
+    parse_balloon, parse_blk, parse_device_id, parse_net, parse_virtconsole, parse_virtio_9p,
+    parse_virtio_serial, CmdParser, VmConfig,
+};
+
+/// Devices of which one instance is supported at most.
+const SINGLE_DEVICES: [&str; 2] = ["ramfb", "pvpanic"];
+
+impl VmConfig {
+    /// Check the ids and cross references of devices and objects before realize,
+    /// so a broken config doesn't fail realize halfway with tap interfaces and
+    /// temporary files left behind. All problems are reported at once, each one
+    /// with the option it comes from.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = self.validate_devices();
+        problems.extend(self.validate_objects());
+        if problems.is_empty() {
+            return Ok(());
+        }
+        bail!(
+            "{} problem(s) found in config:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        );
+    }
+
+    fn validate_devices(&self) -> Vec<String> {
+        // Devices take the backends they use from the config as realize does, so
+        // a backend claimed by two devices is caught too.
+        let mut config = self.clone();
+        let mut ids: HashMap<String, &str> = HashMap::new();
+        let mut singles: HashMap<&str, &str> = HashMap::new();
+        let mut problems = Vec::new();
+        for (driver, args) in &self.devices {
+            if let Ok(id) = parse_device_id(args) {
+                if let Some(user) = ids.get(&id) {
+                    problems.push(format!(
+                        "-device {}: id {} is used by -device {} already",
+                        args, id, user
+                    ));
+                } else if !id.is_empty() {
+                    ids.insert(id, args);
+                }
+            }
+            if SINGLE_DEVICES.contains(&driver.as_str()) {
+                if let Some(first) = singles.insert(driver, args) {
+                    problems.push(format!(
+                        "-device {}: only one {} is supported, -device {} is added already",
+                        args, driver, first
+                    ));
+                }
+            }
+            if let Err(e) = config.validate_device(driver, args) {
+                problems.push(format!("-device {}: {:#}", args, e));
+            }
+        }
+        problems
+    }
+
+    /// Parse the device as realize does, and resolve its references.
+    fn validate_device(&mut self, driver: &str, args: &str) -> Result<()> {
+        let iothread = match driver {
+            "virtio-blk-device" => parse_blk(self, args, None)?.iothread,
+            "virtio-net-device" => parse_net(self, args)?.iothread,
+            "virtio-balloon-device" => {
+                parse_balloon(args)?;
+                None
+            }
+            "virtio-9p-device" => {
+                parse_virtio_9p(self, args)?;
+                None
+            }
+            "virtio-serial-device" | "virtio-serial-pci" => {
+                parse_virtio_serial(self, args)?;
+                None
+            }
+            "virtconsole" | "virtio-console" => {
+                if self.virtio_serial.is_none() {
+                    bail!("No virtio-serial device is added before it");
+                }
+                parse_virtconsole(self, args)?;
+                None
+            }
+            "ramfb" | "pvpanic" => {
+                let mut cmd_parser = CmdParser::new(driver);
+                cmd_parser.push("").push("id");
+                cmd_parser.parse(args)?;
+                None
+            }
+            _ => bail!("Unsupported device {}", driver),
+        };
+        if let Some(iothread) = iothread {
+            if !self.iothreads.iter().flatten().any(|t| t.id == iothread) {
+                bail!("Iothread {} is not added by -object iothread", iothread);
+            }
+        }
+        Ok(())
+    }
+
+    /// Objects of all kinds share one id space.
+    fn validate_objects(&self) -> Vec<String> {
+        let iothreads = self.iothreads.iter().flatten().map(|t| &t.id);
+        let mut objects: Vec<(&String, &str)> = iothreads
+            .map(|id| (id, "iothread"))
+            .chain(self.object.rng_object.keys().map(|id| (id, "rng-random")))
+            .chain(
+                self.object
+                    .mem_object
+                    .keys()
+                    .map(|id| (id, "memory-backend")),
+            )
+            .chain(
+                self.object
+                    .tls_object
+                    .keys()
+                    .map(|id| (id, "tls-creds-x509")),
+            )
+            .chain(
+                self.object
+                    .sasl_object
+                    .keys()
+                    .map(|id| (id, "authz-simple")),
+            )
+            .collect();
+        objects.sort();
+
+        let mut kinds: HashMap<&String, &str> = HashMap::new();
+        let mut problems = Vec::new();
+        for (id, kind) in objects {
+            if let Some(other) = kinds.insert(id, kind) {
+                problems.push(format!(
+                    "-object {},id={}: id {} is used by -object {} as well",
+                    kind, id, id, other
+                ));
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_devices() {
+        let mut vm_config = VmConfig::default();
+        vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .unwrap();
+        vm_config.add_netdev("tap,id=net0,ifname=tap0").unwrap();
+        vm_config
+            .add_chardev("socket,id=con0,path=/tmp/con0.sock,server,nowait")
+            .unwrap();
+        vm_config.add_object("iothread,id=io0").unwrap();
+        for device in [
+            "virtio-blk-device,drive=rootfs,id=blk0,iothread=io0",
+            "virtio-net-device,netdev=net0,id=net0",
+            "virtio-serial-device,id=serial0",
+            "virtconsole,chardev=con0,id=console0",
+            "pvpanic,id=pvpanic0",
+        ] {
+            vm_config.add_device(device).unwrap();
+        }
+        assert!(vm_config.validate().is_ok());
+
+        let bad_devices = [
+            "virtio-blk-device,drive=rootfs,id=blk1",
+            "virtio-blk-device,drive=missing,id=blk2",
+            "virtio-net-device,netdev=net1,id=net0",
+            "virtio-blk-device,drive=data,id=blk3,iothread=io1",
+            "virtconsole,chardev=con0,id=console1",
+            "virtio-balloon-device,deflate-on-oom=maybe",
+            "pvpanic,id=pvpanic1",
+            "virtio-gpu-device,id=gpu0",
+        ];
+        vm_config.add_drive("id=data,file=/path/to/data").unwrap();
+        for device in bad_devices {
+            vm_config.add_device(device).unwrap();
+        }
+        let err = vm_config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("9 problem(s) found in config:"));
+        for device in bad_devices {
+            assert!(err.contains(&format!("-device {}:", device)));
+        }
+        assert!(err.contains("id net0 is used by -device virtio-net-device,netdev=net0,id=net0"));
+        assert!(err.contains("Iothread io1 is not added"));
+        assert!(err.contains("only one pvpanic is supported"));
+        assert!(err.contains("Unsupported device virtio-gpu-device"));
+    }
+
+    #[test]
+    fn test_validate_virtconsole_without_serial() {
+        let mut vm_config = VmConfig::default();
+        vm_config
+            .add_chardev("socket,id=con0,path=/tmp/con0.sock,server,nowait")
+            .unwrap();
+        vm_config
+            .add_device("virtconsole,chardev=con0,id=console0")
+            .unwrap();
+        let err = vm_config.validate().unwrap_err().to_string();
+        assert!(err.contains("No virtio-serial device is added before it"));
+    }
+
+    #[test]
+    fn test_validate_objects() {
+        let mut vm_config = VmConfig::default();
+        vm_config.add_object("iothread,id=obj0").unwrap();
+        vm_config.add_object("iothread,id=obj1").unwrap();
+        assert!(vm_config.validate().is_ok());
+
+        vm_config
+            .add_object("rng-random,id=obj0,filename=/dev/urandom")
+            .unwrap();
+        let err = vm_config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("1 problem(s) found in config:"));
+        assert!(err.contains("id obj0 is used by -object"));
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::VmConfig;
+
+/// Action taken when the watchdog of guest expires, given by `-watchdog-action`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum WatchdogAction {
+    Reset,
+    Poweroff,
+    Pause,
+    None,
+}
+
+impl Default for WatchdogAction {
+    fn default() -> Self {
+        WatchdogAction::Reset
+    }
+}
+
+impl WatchdogAction {
+    /// Name of the action used by cmdline and the WATCHDOG QMP event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WatchdogAction::Reset => "reset",
+            WatchdogAction::Poweroff => "poweroff",
+            WatchdogAction::Pause => "pause",
+            WatchdogAction::None => "none",
+        }
+    }
+}
+
+impl FromStr for WatchdogAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reset" => Ok(WatchdogAction::Reset),
+            "poweroff" => Ok(WatchdogAction::Poweroff),
+            "pause" => Ok(WatchdogAction::Pause),
+            "none" => Ok(WatchdogAction::None),
+            _ => Err(anyhow!(
+                "Invalid watchdog action {}, must be one of reset, poweroff, pause or none",
+                s
+            )),
+        }
+    }
+}
+
+impl VmConfig {
+    /// Set the action taken when the watchdog expires.
+    pub fn add_watchdog_action(&mut self, action: &str) -> Result<()> {
+        self.watchdog_action = action.parse()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_action() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.watchdog_action, WatchdogAction::Reset);
+
+        for action in ["reset", "poweroff", "pause", "none"] {
+            assert!(vm_config.add_watchdog_action(action).is_ok());
+            assert_eq!(vm_config.watchdog_action.name(), action);
+        }
+        assert!(vm_config.add_watchdog_action("shutdown").is_err());
+        assert!(vm_config.add_watchdog_action("").is_err());
+        assert_eq!(vm_config.watchdog_action, WatchdogAction::None);
+    }
+}
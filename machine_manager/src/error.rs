@@ -29,4 +29,6 @@ pub enum MachineManagerError {
         #[from]
         source: serde_json::Error,
     },
+    #[error("The message exceeds {0} bytes")]
+    MessageTooLong(usize),
 }
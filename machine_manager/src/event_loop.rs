@@ -12,18 +12,23 @@
 
 use std::collections::HashMap;
 use std::os::unix::prelude::RawFd;
+use std::os::unix::thread::JoinHandleExt;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{process, thread};
 
 use super::config::IothreadConfig;
 use crate::machine::IOTHREADS;
 use crate::qmp::qmp_schema::IothreadInfo;
 
-use anyhow::bail;
-use log::info;
+use anyhow::{bail, Context};
+use log::{error, info};
 use util::loop_context::{
     gen_delete_notifiers, get_notifiers_fds, EventLoopContext, EventLoopManager, EventNotifier,
+    TimerHandle,
 };
+use util::seccomp::{install_thread_filter, SeccompThread};
+use util::syscall::set_thread_affinity;
 
 /// This struct used to manage all events occur during VM lifetime.
 /// # Notes
@@ -31,13 +36,21 @@ use util::loop_context::{
 /// When vm started with `-iothread` params,
 /// a certain number of io-threads used to handle events from device will be spawned.
 /// Otherwise, all the events will be handled by `main_loop`
+///
+/// Qmp clients are served by the dedicated `monitor_loop`, so that out-of-band
+/// commands are executed even if `main_loop` is busy.
 pub struct EventLoop {
     /// Used to handle all events which are not monitored by io-threads
     main_loop: EventLoopContext,
+    /// Used to read and parse qmp commands from clients.
+    monitor_loop: EventLoopContext,
     /// Used to monitor events of specified device.
     io_threads: HashMap<String, EventLoopContext>,
 }
 
+/// Name of the thread running `monitor_loop`.
+const MONITOR_THREAD_NAME: &str = "qmp-monitor";
+
 static mut GLOBAL_EVENT_LOOP: Option<EventLoop> = None;
 
 impl EventLoop {
@@ -48,9 +61,13 @@ impl EventLoop {
     /// * `iothreads` - refer to `-iothread` params
     pub fn object_init(iothreads: &Option<Vec<IothreadConfig>>) -> util::Result<()> {
         let mut io_threads = HashMap::new();
+        let mut affinities = HashMap::new();
         if let Some(thrs) = iothreads {
             for thr in thrs {
                 io_threads.insert(thr.id.clone(), EventLoopContext::new());
+                if let Some(host_cpu) = thr.cpu_affinity {
+                    affinities.insert(thr.id.clone(), host_cpu);
+                }
             }
         }
 
@@ -60,12 +77,18 @@ impl EventLoop {
             if GLOBAL_EVENT_LOOP.is_none() {
                 GLOBAL_EVENT_LOOP = Some(EventLoop {
                     main_loop: EventLoopContext::new(),
+                    monitor_loop: EventLoopContext::new(),
                     io_threads,
                 });
 
                 if let Some(event_loop) = GLOBAL_EVENT_LOOP.as_mut() {
+                    let ctx = &mut event_loop.monitor_loop;
+                    thread::Builder::new()
+                        .name(MONITOR_THREAD_NAME.to_string())
+                        .spawn(move || while let Ok(true) = ctx.iothread_run() {})?;
                     for (id, ctx) in &mut event_loop.io_threads {
-                        thread::Builder::new().name(id.to_string()).spawn(move || {
+                        let builder = thread::Builder::new().name(id.to_string());
+                        let handle = builder.spawn(move || {
                             let iothread_info = IothreadInfo {
                                 shrink: 0,
                                 pid: process::id(),
@@ -80,6 +103,10 @@ impl EventLoop {
                                 }
                             }
                         })?;
+                        if let Some(host_cpu) = affinities.get(id) {
+                            set_thread_affinity(handle.as_pthread_t(), *host_cpu)
+                                .with_context(|| format!("Failed to pin iothread {}", id))?;
+                        }
                     }
                 } else {
                     bail!("Global Event Loop have not been initialized.")
@@ -110,6 +137,51 @@ impl EventLoop {
         panic!("Global Event Loop have not been initialized.");
     }
 
+    /// Return the loop serving qmp clients.
+    pub fn get_monitor_ctx() -> Option<&'static mut EventLoopContext> {
+        // SAFETY: All concurrently accessed data of EventLoopContext is protected.
+        unsafe {
+            if let Some(event_loop) = GLOBAL_EVENT_LOOP.as_mut() {
+                return Some(&mut event_loop.monitor_loop);
+            }
+        }
+
+        panic!("Global Event Loop have not been initialized.");
+    }
+
+    /// Update event notifiers to the loop serving qmp clients.
+    ///
+    /// # Arguments
+    ///
+    /// * `notifiers` - The wrapper of events will be handled in the monitor loop.
+    pub fn update_monitor_event(notifiers: Vec<EventNotifier>) -> util::Result<()> {
+        if let Some(ctx) = Self::get_monitor_ctx() {
+            ctx.update_events(notifiers)
+        } else {
+            bail!("Monitor Loop Context not found in EventLoop.")
+        }
+    }
+
+    /// Install seccomp filters of the monitor thread and iothreads, each one is
+    /// installed in its own thread as soon as the loop is woken up.
+    pub fn install_seccomp_filters() {
+        // SAFETY: Timers of EventLoopContext are protected.
+        unsafe {
+            if let Some(event_loop) = GLOBAL_EVENT_LOOP.as_mut() {
+                let ctxs = std::iter::once(&mut event_loop.monitor_loop)
+                    .chain(event_loop.io_threads.values_mut());
+                for ctx in ctxs {
+                    let func = Box::new(|| {
+                        if let Err(e) = install_thread_filter(SeccompThread::IoThread) {
+                            error!("Failed to install seccomp filter of iothread: {:?}", e);
+                        }
+                    });
+                    ctx.delay_call(func, 0);
+                }
+            }
+        }
+    }
+
     /// Set a `manager` to event loop
     ///
     /// # Arguments
@@ -136,6 +208,26 @@ impl EventLoop {
         }
     }
 
+    /// Add a timer calling `func` after `delay` to event loop, and return the
+    /// handle to cancel or re-arm it.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - delay time from now.
+    /// * `name` - specify which event loop to run the timer, main loop if None.
+    /// * `func` - the function will be called in the event loop.
+    pub fn add_timer(
+        delay: Duration,
+        name: Option<&String>,
+        func: Box<dyn Fn() + Send + Sync>,
+    ) -> util::Result<TimerHandle> {
+        if let Some(ctx) = Self::get_ctx(name) {
+            Ok(ctx.add_timer(delay, func))
+        } else {
+            bail!("Loop Context not found in EventLoop.")
+        }
+    }
+
     /// Start to run main loop
     ///
     /// # Notes
@@ -0,0 +1,380 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! This module implements a human monitor (HMP-lite) on the api socket.
+//!
+//! It accepts line-oriented commands such as `info status` or `balloon 512`,
+//! translates them to qmp commands executed by the same handlers as qmp, and
+//! prints human-readable text. Input comes from operators, so any line must
+//! end with a message rather than a panic.
+
+use std::io::Write;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use serde_json::{json, Value};
+use util::leak_bucket::LeakBucket;
+
+use crate::event_loop::EventLoop;
+use crate::machine::MachineExternalInterface;
+use crate::qmp::qmp_schema::{self as schema, QmpCommand};
+use crate::qmp::{handle_quit, qmp_command_exec};
+use crate::socket::{SocketHandler, SocketRWHandler};
+
+const HMP_PROMPT: &str = "(televm) ";
+
+/// Commands and their help messages.
+const HMP_COMMANDS: &[(&str, &str)] = &[
+    ("help", "list the commands"),
+    ("info status", "show the VM status"),
+    ("info block", "show the block backends"),
+    ("info network", "show the network backends"),
+    ("stop", "stop the VM"),
+    ("cont", "resume the VM"),
+    ("system_reset", "reset the VM"),
+    (
+        "balloon <MiB>",
+        "request the guest to change its memory to <MiB>",
+    ),
+    ("quit", "quit the VM"),
+];
+
+/// Send the banner and the first prompt to a new client.
+///
+/// # Arguments
+///
+/// * `stream_fd` - The stream file description of client.
+pub fn send_banner(stream_fd: RawFd) -> std::io::Result<()> {
+    let banner = format!(
+        "TeleVM monitor - type 'help' for more information\r\n{}",
+        HMP_PROMPT
+    );
+    SocketRWHandler::new(stream_fd).write_all(banner.as_bytes())
+}
+
+/// Accept human monitor commands, exec them and print the result.
+///
+/// # Arguments
+///
+/// * `stream_fd` - The input stream file description.
+/// * `controller` - The controller which execute actual command.
+/// * `leak_bucket` - The LeakBucket flow controller for command.
+///
+/// # Errors
+///
+/// This function will fail when socket file description broke.
+pub fn handle_hmp(
+    stream_fd: RawFd,
+    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+    leak_bucket: &mut LeakBucket,
+) -> Result<()> {
+    let mut hmp_service = SocketHandler::new(stream_fd);
+    let mut writer = SocketRWHandler::new(stream_fd);
+
+    if leak_bucket.throttled(EventLoop::get_ctx(None).unwrap(), 1_u64) {
+        hmp_service.discard()?;
+        let msg = format!("Too many commands, try again later\r\n{}", HMP_PROMPT);
+        writer.write_all(msg.as_bytes())?;
+        return Ok(());
+    }
+
+    let input = match hmp_service.get_line() {
+        Ok(Some(input)) => input,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            let msg = format!("Error: {}\r\n{}", e, HMP_PROMPT);
+            writer.write_all(msg.as_bytes())?;
+            return Ok(());
+        }
+    };
+    for line in input.lines() {
+        info!("HMP: <-- {:?}", line);
+        let output = hmp_command_exec(line.trim(), stream_fd, controller);
+        let mut msg = output.replace('\n', "\r\n");
+        if !msg.is_empty() {
+            msg.push_str("\r\n");
+        }
+        writer.write_all(msg.as_bytes())?;
+    }
+    writer.write_all(HMP_PROMPT.as_bytes())?;
+
+    Ok(())
+}
+
+/// Exec a human monitor command line of client `stream_fd` and return the
+/// text to print.
+fn hmp_command_exec(
+    line: &str,
+    stream_fd: RawFd,
+    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let exec = |cmd| qmp_exec(controller, stream_fd, cmd);
+    let result = match words.as_slice() {
+        [] => Ok(String::new()),
+        ["help"] | ["?"] => Ok(help()),
+        ["info"] => Ok(help_info()),
+        ["info", "status"] => exec(json!({"execute": "query-status"})).and_then(format_status),
+        ["info", "block"] => {
+            exec(json!({"execute": "query-named-block-nodes"})).and_then(format_block)
+        }
+        ["info", "network"] => exec(json!({"execute": "query-netdev"})).and_then(format_network),
+        ["info", name] => Err(anyhow!("{}", unknown_command(&format!("info {}", name)))),
+        ["stop"] => exec(json!({"execute": "stop"})).map(|_| String::new()),
+        ["cont"] => exec(json!({"execute": "cont"})).map(|_| String::new()),
+        ["system_reset"] => exec(json!({"execute": "system_reset"})).map(|_| String::new()),
+        ["balloon", size] => parse_mib(size).and_then(|value| {
+            exec(json!({"execute": "balloon", "arguments": {"value": value}}))
+                .map(|_| String::new())
+        }),
+        ["quit"] => quit(controller, stream_fd),
+        [name, ..] if command_names().any(|cmd| cmd.split(' ').next() == Some(*name)) => {
+            Err(anyhow!("Invalid arguments, usage: {}", usage(name)))
+        }
+        _ => Err(anyhow!("{}", unknown_command(line))),
+    };
+
+    result.unwrap_or_else(|e| format!("Error: {}", e))
+}
+
+/// Exec `cmd` by the qmp handlers, return the `return` field of response.
+fn qmp_exec(
+    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+    stream_fd: RawFd,
+    cmd: Value,
+) -> Result<Value> {
+    let qmp_command: QmpCommand = serde_json::from_value(cmd)?;
+    let (resp, _) = qmp_command_exec(qmp_command, controller, stream_fd, &mut None);
+    let mut resp = serde_json::to_value(resp)?;
+    if let Some(ret) = resp.get_mut("return") {
+        return Ok(ret.take());
+    }
+    match resp["error"]["desc"].as_str() {
+        Some(desc) if !desc.is_empty() => bail!("{}", desc),
+        _ => bail!("{}", resp["error"]["class"]),
+    }
+}
+
+fn quit(controller: &Arc<Mutex<dyn MachineExternalInterface>>, stream_fd: RawFd) -> Result<String> {
+    let qmp_command: QmpCommand = serde_json::from_value(json!({"execute": "quit"}))?;
+    let (resp, shutdown_flag) = qmp_command_exec(qmp_command, controller, stream_fd, &mut None);
+    if !shutdown_flag {
+        bail!("Failed to quit: {}", serde_json::to_string(&resp)?);
+    }
+    handle_quit();
+    Ok(String::new())
+}
+
+/// Parse the balloon target in MiB to bytes.
+fn parse_mib(size: &str) -> Result<u64> {
+    size.parse::<u64>()
+        .ok()
+        .and_then(|mib| mib.checked_mul(1 << 20))
+        .ok_or_else(|| anyhow!("Invalid size: {}, usage: {}", size, usage("balloon")))
+}
+
+fn command_names() -> impl Iterator<Item = &'static str> {
+    HMP_COMMANDS
+        .iter()
+        .map(|&(cmd, _)| cmd.split(" <").next().unwrap_or(cmd))
+}
+
+fn usage(name: &str) -> String {
+    HMP_COMMANDS
+        .iter()
+        .find(|(cmd, _)| cmd.split(' ').next() == Some(name))
+        .map_or_else(|| name.to_string(), |(cmd, _)| cmd.to_string())
+}
+
+fn help() -> String {
+    let mut lines = Vec::new();
+    for (cmd, msg) in HMP_COMMANDS {
+        lines.push(format!("{:<16} -- {}", cmd, msg));
+    }
+    lines.join("\n")
+}
+
+fn help_info() -> String {
+    let mut lines = Vec::new();
+    for (cmd, msg) in HMP_COMMANDS
+        .iter()
+        .filter(|(cmd, _)| cmd.starts_with("info "))
+    {
+        lines.push(format!("{:<16} -- {}", cmd, msg));
+    }
+    lines.join("\n")
+}
+
+/// Build the message of an unknown command, with the similar commands.
+fn unknown_command(line: &str) -> String {
+    let similar: Vec<&str> = command_names()
+        .filter(|cmd| cmd.starts_with(line) || edit_distance(cmd, line) <= 2)
+        .collect();
+    let mut name: String = line.chars().take(32).collect();
+    if name.len() < line.len() {
+        name.push_str("...");
+    }
+    if similar.is_empty() {
+        format!("Unknown command: '{}', try 'help'", name)
+    } else {
+        format!(
+            "Unknown command: '{}', did you mean: {}",
+            name,
+            similar.join(", ")
+        )
+    }
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                prev.min(cur).min(row[j]) + 1
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Format rows as a table, each column is padded to its widest cell.
+fn format_table(header: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let header = header.iter().map(|h| h.to_string()).collect();
+    std::iter::once(header)
+        .chain(rows)
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn format_status(ret: Value) -> Result<String> {
+    let status = ret["status"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Invalid status: {}", ret))?;
+    Ok(format!("VM status: {}", status))
+}
+
+fn format_block(ret: Value) -> Result<String> {
+    let nodes: Vec<schema::BlockNodeInfo> = serde_json::from_value(ret)?;
+    if nodes.is_empty() {
+        return Ok("No block backend".to_string());
+    }
+    let rows = nodes
+        .into_iter()
+        .map(|node| {
+            vec![
+                node.node_name,
+                node.image.format,
+                format!("{}", node.image.virtual_size),
+                if node.ro { "ro" } else { "rw" }.to_string(),
+                node.file,
+            ]
+        })
+        .collect();
+    Ok(format_table(
+        &["NAME", "FORMAT", "SIZE", "MODE", "FILE"],
+        rows,
+    ))
+}
+
+fn format_network(ret: Value) -> Result<String> {
+    let netdevs: Vec<schema::NetdevInfo> = serde_json::from_value(ret)?;
+    if netdevs.is_empty() {
+        return Ok("No network backend".to_string());
+    }
+    let rows = netdevs
+        .into_iter()
+        .map(|netdev| {
+            vec![
+                netdev.id,
+                netdev.net_type,
+                netdev
+                    .ifname
+                    .or(netdev.fdname)
+                    .unwrap_or_else(|| "-".to_string()),
+                netdev.device.unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+    Ok(format_table(&["ID", "TYPE", "BACKEND", "DEVICE"], rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmp_unknown_command() {
+        assert_eq!(
+            unknown_command("stpo"),
+            "Unknown command: 'stpo', did you mean: stop"
+        );
+        assert_eq!(
+            unknown_command("info"),
+            "Unknown command: 'info', did you mean: info status, info block, info network"
+        );
+        assert_eq!(
+            unknown_command("xyzzy"),
+            "Unknown command: 'xyzzy', try 'help'"
+        );
+        let long = "x".repeat(100);
+        assert!(unknown_command(&long).contains("..."));
+        assert!(unknown_command("\u{fffd}\u{0}").contains("try 'help'"));
+    }
+
+    #[test]
+    fn test_hmp_parse_mib() {
+        assert_eq!(parse_mib("512").unwrap(), 512 << 20);
+        assert!(parse_mib("-1").is_err());
+        assert!(parse_mib("1G").is_err());
+        assert!(parse_mib(&u64::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_hmp_format_table() {
+        let ret = json!([{"id": "net0", "type": "tap", "ifname": "tap0", "device": "net-dev0"},
+            {"id": "netfd", "type": "fd", "fdname": "fd0"}]);
+        assert_eq!(
+            format_network(ret).unwrap(),
+            "ID     TYPE  BACKEND  DEVICE\n\
+             net0   tap   tap0     net-dev0\n\
+             netfd  fd    fd0      -"
+        );
+        assert_eq!(format_network(json!([])).unwrap(), "No network backend");
+        assert!(format_network(json!({})).is_err());
+    }
+}
@@ -25,6 +25,7 @@ pub mod cmdline;
 pub mod config;
 pub mod error;
 pub mod event_loop;
+pub mod hmp;
 pub mod machine;
 pub mod qmp;
 pub mod signal_handler;
@@ -10,18 +10,25 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Mutex;
 
+use anyhow::Result;
+use log::error;
 use once_cell::sync::Lazy;
 use strum::VariantNames;
+use util::boot_time::boot_milestones;
+use vmm_sys_util::eventfd::EventFd;
 
+use crate::config::{PanicAction, MAX_NR_CPUS};
+use crate::event;
 use crate::qmp::qmp_schema::{
-    BlockDevAddArgument, CharDevAddArgument, ChardevInfo, Cmd, CmdLine, DeviceAddArgument,
-    DeviceProps, Events, GicCap, IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities,
-    NetDevAddArgument, PropList, QmpCommand, QmpEvent, Target, TypeLists,
+    BlockDevAddArgument, BootMilestoneInfo, CharDevAddArgument, ChardevInfo, Cmd, CmdLine,
+    CpuModelInfo, DeviceAddArgument, DeviceProps, Events, GicCap, IothreadInfo, KvmInfo,
+    MachineInfo, MigrateCapabilities, NetDevAddArgument, ObjectAddArgument, PropList, QmpCommand,
+    QmpEvent, Target, TypeLists,
 };
-use crate::qmp::{Response, Version};
+use crate::qmp::{qmp_schema, QmpChannel, Response, Version};
 
 #[derive(Clone)]
 pub struct PathInfo {
@@ -38,6 +45,8 @@ pub enum KvmVmState {
     Migrated = 4,
     Paused = 5,
     Shutdown = 6,
+    /// Vcpus are created but parked until `cont`, started by `-S`.
+    Prelaunch = 7,
 }
 
 /// Event over StratoVirt lifetime.
@@ -59,6 +68,8 @@ unsafe impl Send for VmEvent {}
 ///
 /// `None` --`(new)`--> `Created`
 /// `Created` --`(start)`--> `Running`
+/// `Created` --`(start with -S)`--> `Prelaunch`
+/// `Prelaunch` --`(resume)`--> `Running`
 /// `Running` --`(pause)`--> `Paused`
 /// `Paused` --`(resume)`--> `Running`
 /// `KVM_VMSTATE_*` --`(destroy)`--> `None`
@@ -143,9 +154,24 @@ pub trait DeviceInterface {
     /// Query vm running state.
     fn query_status(&self) -> Response;
 
+    /// Reset vm to its boot state, guest RAM is zeroed first if `clear_memory` is set.
+    fn system_reset(&self, clear_memory: bool) -> Response;
+
+    /// Press power button, so that guest shuts down in order.
+    fn system_powerdown(&self) -> Response;
+
     /// Query each cpu's the topology info.
     fn query_cpus(&self) -> Response;
 
+    /// Query each cpu's topology info without interrupting vcpus.
+    fn query_cpus_fast(&self) -> Response;
+
+    /// Query each cpu's runtime statistics.
+    fn query_vcpu_stats(&self) -> Response;
+
+    /// Expand cpu `model` into ISA extensions in effect.
+    fn query_cpu_model_expansion(&self, type_: String, model: CpuModelInfo) -> Response;
+
     /// Query each `hotpluggable_cpus`'s topology info and hotplug message.
     fn query_hotpluggable_cpus(&self) -> Response;
 
@@ -161,6 +187,15 @@ pub trait DeviceInterface {
     /// Delete a block device.
     fn blockdev_del(&self, node_name: String) -> Response;
 
+    /// Resize the image of the block device `device`, or of the block backend `node_name`.
+    fn block_resize(
+        &self,
+        device: Option<String>,
+        node_name: Option<String>,
+        size: u64,
+        force: bool,
+    ) -> Response;
+
     /// Create a new network device.
     fn netdev_add(&mut self, args: Box<NetDevAddArgument>) -> Response;
 
@@ -172,18 +207,56 @@ pub trait DeviceInterface {
     /// Remove a chardev device.
     fn chardev_remove(&mut self, _id: String) -> Response;
 
-    /// Receive a file descriptor via SCM rights and assign it a name.
-    fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response;
-
     /// Query balloon's size.
     fn query_balloon(&self) -> Response;
 
     /// Set balloon's size.
     fn balloon(&self, size: u64) -> Response;
+
+    /// Query devices attached to system bus.
+    fn query_sysbus(&self) -> Response;
+
+    /// Enable or disable tracing MMIO accesses of sysbus devices with type `device`.
+    fn trace_mmio(&self, device: String, enable: bool) -> Response;
+
+    /// Query MMIO accesses recorded for traced sysbus devices.
+    fn query_mmio_trace(&self) -> Response;
+
+    /// Query the current time of guest rtc.
+    fn query_rtc(&self) -> Response;
+
+    /// Write the framebuffer of display device to `filename` in `format`.
+    fn screendump(&self, filename: String, format: Option<String>) -> Response;
+
+    /// Write guest RAM and vcpu registers to ELF core file given by `protocol`.
+    fn dump_guest_memory(
+        &self,
+        paging: bool,
+        protocol: String,
+        detach: Option<bool>,
+        begin: Option<u64>,
+        length: Option<u64>,
+        format: Option<String>,
+    ) -> Response;
+
+    /// Create a backend object, such as memory backend plugged by `device_add`.
+    fn object_add(&mut self, args: ObjectAddArgument) -> Response;
+
+    /// Query the size of boot memory and hotplugged memory.
+    fn query_memory_size_summary(&self) -> Response;
+
+    /// Query memory backends.
+    fn query_memdev(&self) -> Response;
+
+    /// Query network backends.
+    fn query_netdev(&self) -> Response;
+
+    /// Query kernel, initrd and the final kernel command line.
+    fn query_kernel(&self) -> Response;
    
     /// Query the version of StratoVirt.
     fn query_version(&self) -> Response {
-        let version = Version::new(1, 0, 5);
+        let version = Version::current();
         Response::create_response(serde_json::to_value(&version).unwrap(), None)
     }
 
@@ -219,6 +292,29 @@ pub trait DeviceInterface {
         Response::create_response(serde_json::to_value(&vec_events).unwrap(), None)
     }
 
+    /// Query boot milestones recorded by `-boottime`.
+    fn query_boot_time(&self) -> Response {
+        let milestones = match boot_milestones() {
+            Some(milestones) => milestones,
+            None => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(
+                        "Boot time isn't recorded without -boottime".to_string(),
+                    ),
+                    None,
+                );
+            }
+        };
+        let infos: Vec<BootMilestoneInfo> = milestones
+            .into_iter()
+            .map(|(milestone, time)| BootMilestoneInfo {
+                milestone: milestone.name().to_string(),
+                time_ns: time.as_nanos() as u64,
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(&infos).unwrap(), None)
+    }
+
     /// Query if kvm is used.
     fn query_kvm(&self) -> Response {
         let kvm = KvmInfo {
@@ -228,46 +324,10 @@ pub trait DeviceInterface {
         Response::create_response(serde_json::to_value(&kvm).unwrap(), None)
     }
 
-    /// Query machine types supported by StratoVirt.
+    /// Query machine types supported by TeleVM.
     fn query_machines(&self) -> Response {
-        let mut vec_machine = Vec::new();
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "none".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        vec_machine.push(machine_info);
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "microvm".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        vec_machine.push(machine_info);
-        #[cfg(target_arch = "x86_64")]
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "q35".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        #[cfg(target_arch = "x86_64")]
-        vec_machine.push(machine_info);
-        #[cfg(target_arch = "aarch64")]
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "virt".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        #[cfg(target_arch = "aarch64")]
-        vec_machine.push(machine_info);
-        Response::create_response(serde_json::to_value(&vec_machine).unwrap(), None)
+        let machines = supported_machines(MAX_NR_CPUS as u8);
+        Response::create_response(serde_json::to_value(&machines).unwrap(), None)
     }
 
     /// Get the list type
@@ -433,16 +493,185 @@ pub trait MigrateInterface {
     fn cancel_migrate(&self) -> Response {
         Response::create_empty_response()
     }
+
+    /// Set parameters of live migration, the ones not given are kept.
+    fn migrate_set_parameters(
+        &self,
+        _max_bandwidth: Option<u64>,
+        _downtime_limit: Option<u64>,
+        _compress_level: Option<u8>,
+        _xbzrle_cache_size: Option<u64>,
+    ) -> Response {
+        Response::create_empty_response()
+    }
+
+    /// Returns parameters of live migration.
+    fn query_migrate_parameters(&self) -> Response {
+        Response::create_empty_response()
+    }
+
+    /// Save the vm to snapshot file `filename`.
+    fn snapshot_save(&self, _filename: String) -> Response {
+        Response::create_empty_response()
+    }
+
+    /// Restore the vm waiting with `-incoming defer` from snapshot file `filename`.
+    fn snapshot_load(&self, _filename: String) -> Response {
+        Response::create_empty_response()
+    }
+}
+
+/// Out-of-band external api
+///
+/// # Notes
+///
+/// Commands run by qmp `exec-oob` on the monitor thread, without waiting for
+/// the main loop, which may be busy with the machine locked. So they must
+/// neither take the lock of the machine nor wait for the main loop.
+pub trait MachineOobInterface: Send + Sync {
+    /// Query vm running state.
+    fn query_status(&self) -> Response;
+
+    /// Cancel the active migration.
+    fn cancel_migrate(&self) -> Response;
 }
 
 /// Machine interface which is exposed to inner hypervisor.
-pub trait MachineInterface: MachineLifecycle + MachineAddressInterface {}
+pub trait MachineInterface: MachineLifecycle + MachineAddressInterface {
+    /// Report that vcpu `cpu_id` stopped at a guest breakpoint, returns `false`
+    /// if no debugger is waiting for it.
+    fn debug_exit(&self, _cpu_id: u8) -> bool {
+        false
+    }
+
+    /// Report that guest panicked with `reason`, returns `true` if machine takes
+    /// over the guest, whose own request, e.g. shutdown, should be dropped.
+    fn guest_panicked(&self, _reason: &str) -> bool {
+        false
+    }
+
+    /// Report that guest requests to shut down, returns `true` if machine pauses
+    /// the guest instead as `-action shutdown=pause` says.
+    fn pause_on_shutdown(&self) -> bool {
+        false
+    }
+
+    /// Write `len` bytes at guest physical address `addr` to SBI debug console,
+    /// returns the bytes written, which may be fewer than `len`.
+    fn sbi_console_write(
+        &self,
+        _addr: u64,
+        _len: u64,
+    ) -> std::result::Result<Vec<u8>, SbiConsoleError> {
+        Err(SbiConsoleError::NotSupported)
+    }
+
+    /// Read at most `len` bytes from SBI debug console to guest physical address
+    /// `addr`, returns the number of bytes read.
+    fn sbi_console_read(&self, _addr: u64, _len: u64) -> std::result::Result<u64, SbiConsoleError> {
+        Err(SbiConsoleError::NotSupported)
+    }
+
+    /// Write one byte to SBI debug console.
+    fn sbi_console_write_byte(&self, _byte: u8) -> std::result::Result<(), SbiConsoleError> {
+        Err(SbiConsoleError::NotSupported)
+    }
+
+    /// Host address of `len` bytes of guest RAM at guest physical address `addr`,
+    /// which vcpus share with guest, e.g. for steal time. None if they are not in RAM.
+    fn guest_ram_host_address(&self, _addr: u64, _len: u64) -> Option<u64> {
+        None
+    }
+}
+
+/// Failure of SBI debug console call, which is returned to guest as SBI error.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SbiConsoleError {
+    /// No console backend, or the backend does not support the call.
+    NotSupported,
+    /// Guest buffer is out of guest memory.
+    InvalidParam,
+    /// Backend fails to do I/O.
+    Failed,
+}
+
+/// Notifier of guest panic detected by vcpus or devices. Panic is reported at once,
+/// then machine takes the action of `-action panic` in main loop.
+pub struct PanicNotifier {
+    action: PanicAction,
+    /// Notified when machine should take `action`.
+    action_req: EventFd,
+}
+
+impl PanicNotifier {
+    pub fn new(action: PanicAction) -> Result<Self> {
+        Ok(PanicNotifier {
+            action,
+            action_req: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    pub fn action(&self) -> PanicAction {
+        self.action
+    }
+
+    /// Report guest panic with `reason`, returns `true` if machine is going to take
+    /// an action other than `none`.
+    pub fn notify(&self, reason: &str) -> bool {
+        error!("Guest panicked: {}, action: {}", reason, self.action.name());
+        if QmpChannel::is_connected() {
+            let panic_msg = qmp_schema::GuestPanicked {
+                action: self.action.name().to_string(),
+                reason: reason.to_string(),
+            };
+            event!(GuestPanicked; panic_msg);
+        }
+
+        if self.action == PanicAction::None {
+            return false;
+        }
+        if let Err(e) = self.action_req.write(1) {
+            error!("Failed to request panic action: {:?}", e);
+            return false;
+        }
+        true
+    }
+}
+
+impl AsRawFd for PanicNotifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.action_req.as_raw_fd()
+    }
+}
 
 /// Machine interface which is exposed to outer hypervisor.
 pub trait MachineExternalInterface: MachineLifecycle + DeviceInterface + MigrateInterface {}
 
 /// Machine interface which is exposed to test server.
-pub trait MachineTestInterface: MachineAddressInterface {}
+pub trait MachineTestInterface: MachineAddressInterface {
+    /// Get `pc`, `a0`, `a1` and `a2` of vcpu `cpu_index` set for booting.
+    #[cfg(target_arch = "riscv64")]
+    fn boot_regs(&self, cpu_index: usize) -> Option<[u64; 4]>;
+}
 
 pub static PTY_PATH: Lazy<Mutex<Vec<PathInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
 pub static IOTHREADS: Lazy<Mutex<Vec<IothreadInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Machine types TeleVM can run, `microvm` is the default one.
+///
+/// # Arguments
+///
+/// * `cpu_max` - Maximum number of vcpus a machine can have.
+pub fn supported_machines(cpu_max: u8) -> Vec<MachineInfo> {
+    ["microvm", "none"]
+        .iter()
+        .map(|name| MachineInfo {
+            hotplug: false,
+            name: name.to_string(),
+            numa_mem_support: false,
+            cpu_max,
+            deprecated: false,
+            is_default: *name == "microvm",
+        })
+        .collect()
+}
@@ -19,10 +19,15 @@
 //! It has three feature:
 //! 1. Qmp server is no-async service as well as Qemu's.
 //! Command + events can replace asynchronous command.
-//! 2. Qmp server can only be connected a client at one time.
-//! It's no situation where be communicated with many clients.
-//! When it must use, can use other communication way not QMP.
-//! 3. Qmp's message structure base is transformed by scripts from Qemu's
+//! 2. Each qmp socket can be connected by several clients at the same time,
+//! and `-qmp` and `-mon` can give several sockets. Every client negotiates
+//! by `qmp_capabilities` itself, and events are broadcast to every
+//! negotiated client. Commands of all clients are executed one by one in
+//! the main loop, while clients are served by the monitor thread.
+//! 3. A client enabling "oob" by `qmp_capabilities` can execute "quit",
+//! "query-status" and "migrate_cancel" by "exec-oob" on the monitor thread,
+//! even when the main loop is busy with a long in-band command.
+//! 4. Qmp's message structure base is transformed by scripts from Qemu's
 //! `qmp-schema.json`. It's can be compatible by Qemu's zoology. Those
 //! transformed structures can be found in `machine_manager/src/qmp/qmp_schema.rs`
 
@@ -31,29 +36,38 @@
 #[allow(non_snake_case)]
 pub mod qmp_schema;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::Write;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use log::{info, warn};
+use log::{error, info, warn};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use util::leak_bucket::LeakBucket;
-use util::set_termi_canon_mode;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
 use util::time::NANOSECONDS_PER_SECOND;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 use self::qmp_schema::{self as schema, QmpCommand};
+use crate::error::MachineManagerError;
 use crate::event_loop::EventLoop;
-use crate::machine::MachineExternalInterface;
+use crate::machine::{MachineExternalInterface, MachineOobInterface};
 use crate::socket::SocketRWHandler;
-use crate::temp_cleaner::TempCleaner;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 static mut QMP_CHANNEL: Option<Arc<QmpChannel>> = None;
 
+/// The path prefix to reference a fd set added by `add-fd`.
+pub const FDSET_PATH_PREFIX: &str = "/dev/fdset/";
+
 /// Macro `event!`: send event to qmp-client.
 ///
 /// # Arguments
@@ -176,9 +190,22 @@ impl Version {
         };
         Version {
             application: version_number,
-            package: "StratoVirt-".to_string() + env!("CARGO_PKG_VERSION"),
+            package: format!(
+                "TeleVM-{} ({})",
+                env!("CARGO_PKG_VERSION"),
+                std::env::consts::ARCH
+            ),
         }
     }
+
+    /// Version of the running TeleVM, taken from the crate version.
+    pub fn current() -> Self {
+        Version::new(
+            env!("CARGO_PKG_VERSION_PATCH").parse().unwrap(),
+            env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
+            env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
+        )
+    }
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, PartialEq)]
@@ -202,7 +229,7 @@ impl QmpGreeting {
             minor,
             major,
         };
-        let cap: Vec<String> = Default::default();
+        let cap = vec!["oob".to_string()];
         let version = Version {
             application: version_number,
             package: "".to_string(),
@@ -342,79 +369,282 @@ pub fn create_timestamp() -> TimeStamp {
     }
 }
 
-/// Accept qmp command, analyze and exec it.
+/// Accept qmp commands of a client on the monitor thread. Out-of-band commands
+/// are executed at once, the others are queued for the main loop to execute them
+/// one after another.
 ///
 /// # Arguments
 ///
-/// * `stream_fd` - The input stream file description.
-/// * `controller` - The controller which execute actual qmp command.
+/// * `qmp_service` - The handler of the client's input stream, which keeps the
+///   incomplete message received.
 /// * `leak_bucket` - The LeakBucket flow controller for qmp command.
 ///
 /// # Errors
 ///
-/// This function will fail when json parser failed or socket file description broke.
+/// This function will fail when socket file description broke.
 pub fn handle_qmp(
-    stream_fd: RawFd,
-    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+    qmp_service: &mut crate::socket::SocketHandler,
     leak_bucket: &mut LeakBucket,
 ) -> Result<()> {
-    let mut qmp_service = crate::socket::SocketHandler::new(stream_fd);
-
-    // If flow over `LEAK_BUCKET_LIMIT` per seconds, discard the request and return
-    // a `OperationThrottled` error.
-    if leak_bucket.throttled(EventLoop::get_ctx(None).unwrap(), 1_u64) {
-        qmp_service.discard()?;
-        let err_resp = schema::QmpErrorClass::OperationThrottled(crate::socket::LEAK_BUCKET_LIMIT);
-        qmp_service
-            .send_str(&serde_json::to_string(&Response::create_error_response(
-                err_resp, None,
-            ))?)
-            .with_context(|| "Failed to send message to qmp client.")?;
-        return Ok(());
-    }
-
-    match qmp_service.decode_line() {
-        (Ok(None), _) => Ok(()),
-        (Ok(buffer), if_fd) => {
-            info!("QMP: <-- {:?}", buffer);
-            let qmp_command: schema::QmpCommand = buffer.unwrap();
-            let (return_msg, shutdown_flag) = qmp_command_exec(qmp_command, controller, if_fd);
-            info!("QMP: --> {:?}", return_msg);
-            qmp_service.send_str(&return_msg)?;
-
-            // handle shutdown command
-            if shutdown_flag {
-                let shutdown_msg = schema::Shutdown {
-                    guest: false,
-                    reason: "host-qmp-quit".to_string(),
-                };
-                event!(Shutdown; shutdown_msg);
-                TempCleaner::clean();
-                set_termi_canon_mode().expect("Failed to set terminal to canonical mode.");
+    let stream_fd = qmp_service.get_socket_fd();
+    let client_id = QmpChannel::client_id(stream_fd)
+        .with_context(|| format!("Qmp client {} is not bound", stream_fd))?;
+    let limits = qmp_service.limits();
+
+    let (messages, mut if_fd) = match qmp_service.decode_messages::<Value>() {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            // The client is reset after a too long command, tell it why first.
+            if let Some(MachineManagerError::MessageTooLong(_)) = e.downcast_ref() {
+                let err_resp =
+                    schema::QmpErrorClass::GenericError(format!("JSON parse error, {}", e));
+                let resp = Response::create_error_response(err_resp, None);
+                QmpChannel::send_response(stream_fd, client_id, &resp)?;
+            }
+            return Err(e);
+        }
+    };
+    let mut queued = false;
+    for message in messages {
+        info!("QMP: <-- {:?}", message);
+        let id = message
+            .as_ref()
+            .ok()
+            .and_then(|value| value.get("id"))
+            .and_then(|id| id.as_str())
+            .map(String::from);
+        // If flow over `command_rate` per seconds, drop the command and return
+        // a `OperationThrottled` error.
+        if leak_bucket.throttled(EventLoop::get_monitor_ctx().unwrap(), 1_u64) {
+            let err_resp = schema::QmpErrorClass::OperationThrottled(limits.command_rate);
+            let resp = Response::create_error_response(err_resp, id);
+            QmpChannel::send_response(stream_fd, client_id, &resp)?;
+            continue;
+        }
+        let command = match message.and_then(parse_request) {
+            Ok((qmp_command, true)) => {
+                let (resp, shutdown_flag) = oob_command_exec(stream_fd, qmp_command, id);
+                QmpChannel::send_response(stream_fd, client_id, &resp)?;
+                if shutdown_flag {
+                    handle_oob_quit();
+                }
+                continue;
+            }
+            Ok((qmp_command, false)) => Ok(qmp_command),
+            Err(e) => Err(e),
+        };
+        let request = QmpRequest {
+            stream_fd,
+            client_id,
+            command,
+            fd: if_fd.take(),
+        };
+        if let Err(request) = QmpChannel::queue_request(request, limits.max_in_flight) {
+            if let Some(fd) = request.fd {
+                close_fd(fd);
+            }
+            let err_resp = schema::QmpErrorClass::GenericError(format!(
+                "Too many commands, at most {} commands of a client are in flight",
+                limits.max_in_flight
+            ));
+            let resp = Response::create_error_response(err_resp, id);
+            QmpChannel::send_response(stream_fd, client_id, &resp)?;
+            continue;
+        }
+        queued = true;
+    }
+    // The fd is closed if no command takes it.
+    if let Some(fd) = if_fd {
+        close_fd(fd);
+    }
+    if queued {
+        QmpChannel::inner()
+            .request_evt
+            .write(1)
+            .with_context(|| "Failed to notify main loop of qmp commands")?;
+    }
+    Ok(())
+}
 
-                std::process::exit(0);
+/// Parse a qmp request, which asks for executing a command either in-band by
+/// `execute`, or out-of-band by `exec-oob`. Return the command and whether it's
+/// out-of-band.
+fn parse_request(mut request: Value) -> Result<(QmpCommand, bool)> {
+    let mut oob = false;
+    if let Some(members) = request.as_object_mut() {
+        if let Some(name) = members.remove("exec-oob") {
+            if members.contains_key("execute") {
+                bail!("QMP input members 'execute' and 'exec-oob' are exclusive");
             }
+            members.insert("execute".to_string(), name);
+            oob = true;
+        }
+    }
+    Ok((serde_json::from_value(request)?, oob))
+}
 
-            Ok(())
+/// Execute out-of-band command of client `stream_fd` on the monitor thread,
+/// return the response and whether the command is `quit`.
+fn oob_command_exec(
+    stream_fd: RawFd,
+    qmp_command: QmpCommand,
+    id: Option<String>,
+) -> (Response, bool) {
+    let mut shutdown_flag = false;
+    let handler = QmpChannel::inner().oob_handler.read().unwrap().clone();
+    let mut resp = if !QmpChannel::is_oob_enabled(stream_fd) {
+        let err = "Out-of-band execution is not enabled by qmp_capabilities".to_string();
+        Response::create_error_response(schema::QmpErrorClass::GenericError(err), None)
+    } else {
+        match (qmp_command, handler) {
+            (QmpCommand::quit { .. }, _) => {
+                shutdown_flag = true;
+                Response::create_empty_response()
+            }
+            (QmpCommand::query_status { .. }, Some(handler)) => handler.query_status(),
+            (QmpCommand::cancel_migrate { .. }, Some(handler)) => handler.cancel_migrate(),
+            (qmp_command, _) => {
+                let command = serde_json::to_value(&qmp_command).unwrap();
+                let err = format!(
+                    "The command {} does not support out-of-band execution",
+                    command["execute"].as_str().unwrap_or_default()
+                );
+                Response::create_error_response(schema::QmpErrorClass::GenericError(err), None)
+            }
         }
-        (Err(e), _) => {
-            let err_resp = schema::QmpErrorClass::GenericError(format!("{}", &e));
+    };
+    resp.change_id(id);
+    (resp, shutdown_flag)
+}
+
+/// Exit at once after `quit` is executed out-of-band, without waiting for the
+/// main loop to destroy the VM.
+fn handle_oob_quit() {
+    handle_quit();
+    crate::signal_handler::clean_and_exit(crate::signal_handler::vm_exit_code());
+}
+
+/// Execute an in-band command queued by `handle_qmp` in the main loop, return
+/// whether the command is `quit`.
+fn dispatch_request(
+    request: QmpRequest,
+    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+) -> bool {
+    let QmpRequest {
+        stream_fd,
+        client_id,
+        command,
+        mut fd,
+    } = request;
+    let mut oob = None;
+    let (resp, shutdown_flag) = match command {
+        Err(e) => {
             warn!("Qmp json parser made an error:{}", e);
-            qmp_service.send_str(&serde_json::to_string(&Response::create_error_response(
-                err_resp, None,
-            ))?)?;
-            Ok(())
+            let err_resp = schema::QmpErrorClass::GenericError(format!("{}", &e));
+            (Response::create_error_response(err_resp, None), false)
         }
+        // Each client negotiates once, events are sent to it since then.
+        Ok(QmpCommand::qmp_capabilities { arguments, id }) => {
+            let enable = arguments.enable.unwrap_or_default();
+            let resp = if QmpChannel::is_negotiated(stream_fd) {
+                let err_resp = schema::QmpErrorClass::CommandNotFound(
+                    "Capabilities negotiation is already complete, command ignored".to_string(),
+                );
+                Response::create_error_response(err_resp, id)
+            } else if let Some(cap) = enable.iter().find(|cap| *cap != "oob") {
+                let err_resp = schema::QmpErrorClass::GenericError(format!(
+                    "Capability '{}' is not available",
+                    cap
+                ));
+                Response::create_error_response(err_resp, id)
+            } else {
+                oob = Some(!enable.is_empty());
+                let mut resp = Response::create_empty_response();
+                resp.change_id(id);
+                resp
+            };
+            (resp, false)
+        }
+        Ok(qmp_command) => qmp_command_exec(qmp_command, controller, stream_fd, &mut fd),
+    };
+    // The fd is closed if the command doesn't take it.
+    if let Some(fd) = fd {
+        close_fd(fd);
+    }
+    if let Err(e) = QmpChannel::send_response(stream_fd, client_id, &resp) {
+        error!("{:?}", e);
     }
+    QmpChannel::complete_request(stream_fd, client_id);
+    if let Some(oob) = oob {
+        QmpChannel::negotiate(stream_fd, oob);
+    }
+    if shutdown_flag {
+        handle_quit();
+    }
+    shutdown_flag
+}
+
+/// The dispatcher executing in-band qmp commands of all clients in the main loop,
+/// one after another in the order they are received.
+pub struct QmpDispatcher {
+    /// The controller which execute actual qmp command.
+    controller: Arc<Mutex<dyn MachineExternalInterface>>,
+}
+
+impl QmpDispatcher {
+    pub fn new(controller: Arc<Mutex<dyn MachineExternalInterface>>) -> Self {
+        QmpDispatcher { controller }
+    }
+
+    fn dispatch(&self) {
+        while let Some(request) = QmpChannel::pop_request() {
+            // The main loop exits after `quit`, the commands left are dropped.
+            if dispatch_request(request, &self.controller) {
+                break;
+            }
+        }
+    }
+}
+
+impl EventNotifierHelper for QmpDispatcher {
+    fn internal_notifiers(dispatcher: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd| {
+            read_fd(fd);
+            dispatcher.lock().unwrap().dispatch();
+            None
+        });
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            QmpChannel::inner().request_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        )]
+    }
+}
+
+/// Send `SHUTDOWN` event after the VM is destroyed by `quit` command. The main
+/// loop is over then, and the process cleans up and exits with 0 in `main`.
+pub(crate) fn handle_quit() {
+    let shutdown_msg = schema::Shutdown {
+        guest: false,
+        reason: "host-qmp-quit".to_string(),
+        resumable: None,
+    };
+    event!(Shutdown; shutdown_msg);
 }
 
 /// Create a match , where `qmp_command` and its arguments matching by handle
 /// function, and exec this qmp command.
-fn qmp_command_exec(
+///
+/// `if_fd` is the fd passed by client `stream_fd` along with the command, it's
+/// taken if the command consumes it.
+pub(crate) fn qmp_command_exec(
     qmp_command: QmpCommand,
     controller: &Arc<Mutex<dyn MachineExternalInterface>>,
-    if_fd: Option<RawFd>,
-) -> (String, bool) {
+    stream_fd: RawFd,
+    if_fd: &mut Option<RawFd>,
+) -> (Response, bool) {
     let mut qmp_response = Response::create_empty_response();
     let mut shutdown_flag = false;
 
@@ -423,6 +653,7 @@ fn qmp_command_exec(
         qmp_command.clone(); controller.lock().unwrap(); qmp_response;
         (stop, pause),
         (cont, resume),
+        (system_powerdown, system_powerdown),
         (query_status, query_status),
         (query_version, query_version),
         (query_commands, query_commands),
@@ -445,20 +676,57 @@ fn qmp_command_exec(
         (query_block_jobs, query_block_jobs),
         (query_gic_capabilities, query_gic_capabilities),
         (query_iothreads, query_iothreads),
+        (query_sysbus, query_sysbus),
+        (query_mmio_trace, query_mmio_trace),
+        (query_rtc, query_rtc),
+        (query_memory_size_summary, query_memory_size_summary),
+        (query_memdev, query_memdev),
+        (query_netdev, query_netdev),
+        (query_kernel, query_kernel),
         (query_migrate, query_migrate),
+        (query_migrate_parameters, query_migrate_parameters),
         (cancel_migrate, cancel_migrate),
         (query_cpus, query_cpus),
+        (query_cpus_fast, query_cpus_fast),
+        (query_vcpu_stats, query_vcpu_stats),
+        (query_boot_time, query_boot_time),
         (query_balloon, query_balloon),
         (list_type, list_type),
         (query_hotpluggable_cpus, query_hotpluggable_cpus);
         (device_list_properties, device_list_properties, typename),
         (device_del, device_del, id),
         (blockdev_del, blockdev_del, node_name),
+        (block_resize, block_resize, device, node_name, size, force),
         (netdev_del, netdev_del, id),
         (chardev_remove, chardev_remove, id),
         (balloon, balloon, value),
-        (migrate, migrate, uri);
+        (trace_mmio, trace_mmio, device, enable),
+        (system_reset, system_reset, clear_memory),
+        (screendump, screendump, filename, format),
+        (
+            dump_guest_memory,
+            dump_guest_memory,
+            paging,
+            protocol,
+            detach,
+            begin,
+            length,
+            format
+        ),
+        (query_cpu_model_expansion, query_cpu_model_expansion, type_, model),
+        (migrate, migrate, uri),
+        (
+            migrate_set_parameters,
+            migrate_set_parameters,
+            max_bandwidth,
+            downtime_limit,
+            compress_level,
+            xbzrle_cache_size
+        ),
+        (snapshot_save, snapshot_save, filename),
+        (snapshot_load, snapshot_load, filename);
         (device_add, device_add),
+        (object_add, object_add),
         (blockdev_add, blockdev_add),
         (netdev_add, netdev_add),
         (chardev_add, chardev_add)
@@ -468,12 +736,68 @@ fn qmp_command_exec(
     if id.is_none() {
         id = match qmp_command {
             QmpCommand::quit { id, .. } => {
-                controller.lock().unwrap().destroy();
-                shutdown_flag = true;
+                // Vcpus are stopped, devices are unrealized and drives are flushed.
+                if controller.lock().unwrap().destroy() {
+                    shutdown_flag = true;
+                } else {
+                    qmp_response = Response::create_error_response(
+                        schema::QmpErrorClass::GenericError("Failed to destroy VM".to_string()),
+                        None,
+                    );
+                }
                 id
             }
             QmpCommand::getfd { arguments, id } => {
-                qmp_response = controller.lock().unwrap().getfd(arguments.fd_name, if_fd);
+                qmp_response = match if_fd.take() {
+                    Some(fd) => {
+                        QmpChannel::set_fd(stream_fd, arguments.fd_name, fd);
+                        Response::create_empty_response()
+                    }
+                    None => invalid_scm_response(),
+                };
+                id
+            }
+            QmpCommand::closefd { arguments, id } => {
+                qmp_response = QmpChannel::close_fd(&arguments.fd_name)
+                    .map(|_| Response::create_empty_response())
+                    .unwrap_or_else(generic_error_response);
+                id
+            }
+            QmpCommand::add_fd { arguments, id } => {
+                qmp_response = match if_fd.take() {
+                    Some(fd) => QmpChannel::add_fdset_fd(
+                        stream_fd,
+                        arguments.fdset_id,
+                        fd,
+                        arguments.opaque,
+                    )
+                    .map(|info| {
+                        Response::create_response(serde_json::to_value(info).unwrap(), None)
+                    })
+                    .unwrap_or_else(|e| {
+                        close_fd(fd);
+                        generic_error_response(e)
+                    }),
+                    None => invalid_scm_response(),
+                };
+                id
+            }
+            QmpCommand::remove_fd { arguments, id } => {
+                qmp_response = QmpChannel::remove_fdset_fd(arguments.fdset_id, arguments.fd)
+                    .map(|_| Response::create_empty_response())
+                    .unwrap_or_else(generic_error_response);
+                id
+            }
+            QmpCommand::query_fdsets { id, .. } => {
+                let fdsets = QmpChannel::query_fdsets();
+                qmp_response =
+                    Response::create_response(serde_json::to_value(fdsets).unwrap(), None);
+                id
+            }
+            QmpCommand::set_log_level { arguments, id } => {
+                qmp_response = util::logger::set_log_filter(&arguments.filter)
+                    .map(|_| Response::create_empty_response())
+                    .unwrap_or_else(generic_error_response);
                 id
             }
             _ => None,
@@ -482,10 +806,37 @@ fn qmp_command_exec(
 
     // Change response id with input qmp message
     qmp_response.change_id(id);
-    (
-        serde_json::to_string(&qmp_response).unwrap() + "\r",
-        shutdown_flag,
-    )
+    (qmp_response, shutdown_flag)
+}
+
+fn invalid_scm_response() -> Response {
+    let err_resp = schema::QmpErrorClass::GenericError("Invalid SCM message".to_string());
+    Response::create_error_response(err_resp, None)
+}
+
+fn generic_error_response(e: anyhow::Error) -> Response {
+    let err_resp = schema::QmpErrorClass::GenericError(format!("{:?}", e));
+    Response::create_error_response(err_resp, None)
+}
+
+fn close_fd(fd: RawFd) {
+    // SAFETY: the fd is received from client and owned by `QMP_CHANNEL`.
+    unsafe { libc::close(fd) };
+}
+
+/// Get the fd set id of `path` if it's in form of `/dev/fdset/<id>`.
+///
+/// # Errors
+///
+/// The id of fd set is not a number.
+pub fn parse_fdset_path(path: &str) -> Result<Option<u64>> {
+    match path.strip_prefix(FDSET_PATH_PREFIX) {
+        Some(id) => id
+            .parse::<u64>()
+            .map(Some)
+            .with_context(|| format!("Invalid fd set path {}", path)),
+        None => Ok(None),
+    }
 }
 
 /// The struct `QmpChannel` is the only struct can handle Global variable
@@ -493,10 +844,55 @@ fn qmp_command_exec(
 /// It is used to send event to qmp client and restore some file descriptor
 /// which was sended by client.
 pub struct QmpChannel {
-    /// The `writer` to send `QmpEvent`.
-    event_writer: RwLock<Option<SocketRWHandler>>,
-    /// Restore file descriptor received from client.
-    fds: Arc<RwLock<BTreeMap<String, RawFd>>>,
+    /// The clients to send `QmpEvent`, keyed by their stream fd.
+    event_clients: RwLock<BTreeMap<RawFd, EventClient>>,
+    /// Restore file descriptor received from client by `getfd`.
+    fds: Arc<RwLock<BTreeMap<String, ClientFd>>>,
+    /// Fd sets of file descriptors received from client by `add-fd`.
+    fdsets: RwLock<BTreeMap<u64, Vec<ClientFd>>>,
+    /// In-band commands waiting to be executed by the main loop.
+    requests: Mutex<VecDeque<QmpRequest>>,
+    /// Notify the main loop of the queued commands.
+    request_evt: EventFd,
+    /// The handler executing out-of-band commands on the monitor thread.
+    oob_handler: RwLock<Option<Arc<dyn MachineOobInterface>>>,
+    /// The id of next client, a response is dropped if its client is gone even
+    /// though the stream fd is reused.
+    next_client_id: AtomicU64,
+}
+
+/// An in-band command received on the monitor thread.
+struct QmpRequest {
+    /// The stream fd of the client.
+    stream_fd: RawFd,
+    /// The id of the client.
+    client_id: u64,
+    /// The parsed command, or the error to respond.
+    command: Result<QmpCommand>,
+    /// The file descriptor sent with the command.
+    fd: Option<RawFd>,
+}
+
+/// A file descriptor received from a qmp client.
+struct ClientFd {
+    fd: RawFd,
+    /// The stream fd of the client, the fd is closed when the client is gone.
+    owner: RawFd,
+    opaque: Option<String>,
+}
+
+/// A qmp client which can receive `QmpEvent`.
+struct EventClient {
+    /// The id of client.
+    id: u64,
+    /// The `writer` to send `QmpEvent` and responses.
+    writer: SocketRWHandler,
+    /// Whether the client has negotiated by `qmp_capabilities`.
+    negotiated: bool,
+    /// Whether the client has enabled out-of-band execution.
+    oob: bool,
+    /// The number of in-band commands of the client waiting or executing.
+    in_flight: usize,
 }
 
 impl QmpChannel {
@@ -505,40 +901,240 @@ impl QmpChannel {
         unsafe {
             if QMP_CHANNEL.is_none() {
                 QMP_CHANNEL = Some(Arc::new(QmpChannel {
-                    event_writer: RwLock::new(None),
+                    event_clients: RwLock::new(BTreeMap::new()),
                     fds: Arc::new(RwLock::new(BTreeMap::new())),
+                    fdsets: RwLock::new(BTreeMap::new()),
+                    requests: Mutex::new(VecDeque::new()),
+                    request_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                    oob_handler: RwLock::new(None),
+                    next_client_id: AtomicU64::new(0),
                 }));
             }
         }
     }
 
-    /// Bind a `SocketRWHandler` to `QMP_CHANNEL`.
+    /// Bind a `SocketRWHandler` to `QMP_CHANNEL`. The client receives no
+    /// event until it negotiates by `qmp_capabilities`.
     ///
     /// # Arguments
     ///
     /// * `writer` - The `SocketRWHandler` used to communicate with client.
     pub fn bind_writer(writer: SocketRWHandler) {
-        *Self::inner().event_writer.write().unwrap() = Some(writer);
+        let client = EventClient {
+            id: Self::inner().next_client_id.fetch_add(1, Ordering::SeqCst),
+            writer,
+            negotiated: false,
+            oob: false,
+            in_flight: 0,
+        };
+        Self::inner()
+            .event_clients
+            .write()
+            .unwrap()
+            .insert(client.writer.get_socket_fd(), client);
+    }
+
+    /// Set the handler executing out-of-band commands.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The handler to execute out-of-band commands.
+    pub fn set_oob_handler(handler: Arc<dyn MachineOobInterface>) {
+        *Self::inner().oob_handler.write().unwrap() = Some(handler);
+    }
+
+    /// Mark the client bound with `stream_fd` as negotiated, events will
+    /// be sent to it since then.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The stream fd of client.
+    /// * `oob` - Whether the client enables out-of-band execution.
+    pub fn negotiate(stream_fd: RawFd, oob: bool) {
+        if let Some(client) = Self::inner()
+            .event_clients
+            .write()
+            .unwrap()
+            .get_mut(&stream_fd)
+        {
+            client.negotiated = true;
+            client.oob = oob;
+        }
+    }
+
+    /// Check whether the client bound with `stream_fd` has enabled out-of-band
+    /// execution or not.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The stream fd of client.
+    pub fn is_oob_enabled(stream_fd: RawFd) -> bool {
+        Self::inner()
+            .event_clients
+            .read()
+            .unwrap()
+            .get(&stream_fd)
+            .map_or(false, |client| client.oob)
+    }
+
+    fn client_id(stream_fd: RawFd) -> Option<u64> {
+        Self::inner()
+            .event_clients
+            .read()
+            .unwrap()
+            .get(&stream_fd)
+            .map(|client| client.id)
+    }
+
+    /// Send a response to client `stream_fd`, the response is dropped if the
+    /// client is gone. Responses and events are written under the lock of
+    /// clients, so they don't interleave though sent from different threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The stream fd of client.
+    /// * `client_id` - The id of client when it sent the command.
+    /// * `resp` - The response to send.
+    fn send_response(stream_fd: RawFd, client_id: u64, resp: &Response) -> Result<()> {
+        let resp_str = serde_json::to_string(resp).unwrap() + "\r\n";
+        let mut clients = Self::inner().event_clients.write().unwrap();
+        let client = match clients.get_mut(&stream_fd) {
+            Some(client) if client.id == client_id => client,
+            _ => return Ok(()),
+        };
+        client.writer.flush().unwrap();
+        client
+            .writer
+            .write_all(resp_str.as_bytes())
+            .with_context(|| format!("Failed to send response to qmp client {}", stream_fd))?;
+        info!("QMP: --> {:?}", resp);
+        Ok(())
+    }
+
+    /// Queue an in-band command, it's given back if its client is gone or has
+    /// `max_in_flight` commands waiting or executing.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The in-band command.
+    /// * `max_in_flight` - The max number of commands of the client in flight.
+    fn queue_request(
+        request: QmpRequest,
+        max_in_flight: usize,
+    ) -> std::result::Result<(), QmpRequest> {
+        let mut clients = Self::inner().event_clients.write().unwrap();
+        match clients.get_mut(&request.stream_fd) {
+            Some(client) if client.id == request.client_id => {
+                if client.in_flight >= max_in_flight {
+                    return Err(request);
+                }
+                client.in_flight += 1;
+            }
+            _ => return Err(request),
+        }
+        Self::inner().requests.lock().unwrap().push_back(request);
+        Ok(())
+    }
+
+    /// Mark an in-band command of client `stream_fd` as completed.
+    fn complete_request(stream_fd: RawFd, client_id: u64) {
+        if let Some(client) = Self::inner()
+            .event_clients
+            .write()
+            .unwrap()
+            .get_mut(&stream_fd)
+        {
+            if client.id == client_id {
+                client.in_flight = client.in_flight.saturating_sub(1);
+            }
+        }
+    }
+
+    fn pop_request() -> Option<QmpRequest> {
+        Self::inner().requests.lock().unwrap().pop_front()
+    }
+
+    /// Check whether the client bound with `stream_fd` has negotiated or not.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The stream fd of client.
+    pub fn is_negotiated(stream_fd: RawFd) -> bool {
+        Self::inner()
+            .event_clients
+            .read()
+            .unwrap()
+            .get(&stream_fd)
+            .map_or(false, |client| client.negotiated)
     }
 
-    /// Unbind `SocketRWHandler` from `QMP_CHANNEL`.
-    pub fn unbind() {
-        *Self::inner().event_writer.write().unwrap() = None;
+    /// Unbind the `SocketRWHandler` of client `stream_fd` from `QMP_CHANNEL`,
+    /// and close the file descriptors and commands it left behind.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The stream fd of client.
+    pub fn unbind(stream_fd: RawFd) {
+        Self::inner()
+            .event_clients
+            .write()
+            .unwrap()
+            .remove(&stream_fd);
+
+        Self::inner().requests.lock().unwrap().retain(|request| {
+            if request.stream_fd == stream_fd {
+                if let Some(fd) = request.fd {
+                    close_fd(fd);
+                }
+            }
+            request.stream_fd != stream_fd
+        });
+
+        Self::inner().fds.write().unwrap().retain(|_, named| {
+            if named.owner == stream_fd {
+                close_fd(named.fd);
+            }
+            named.owner != stream_fd
+        });
+        let mut fdsets = Self::inner().fdsets.write().unwrap();
+        for fds in fdsets.values_mut() {
+            fds.retain(|client_fd| {
+                if client_fd.owner == stream_fd {
+                    close_fd(client_fd.fd);
+                }
+                client_fd.owner != stream_fd
+            });
+        }
+        fdsets.retain(|_, fds| !fds.is_empty());
     }
 
-    /// Check whether a `SocketRWHandler` bind with `QMP_CHANNEL` or not.
+    /// Check whether any negotiated client binds with `QMP_CHANNEL` or not.
     pub fn is_connected() -> bool {
-        Self::inner().event_writer.read().unwrap().is_some()
+        Self::inner()
+            .event_clients
+            .read()
+            .unwrap()
+            .values()
+            .any(|client| client.negotiated)
     }
 
-    /// Restore extern file descriptor in `QMP_CHANNEL`.
+    /// Restore extern file descriptor in `QMP_CHANNEL`, the fd restored with
+    /// the same name before is closed.
     ///
     /// # Arguments
     ///
+    /// * `owner` - The stream fd of client.
     /// * `name` - Name of file descriptor.
     /// * `fd` - File descriptor sent by client.
-    pub fn set_fd(name: String, fd: RawFd) {
-        Self::inner().fds.write().unwrap().insert(name, fd);
+    pub fn set_fd(owner: RawFd, name: String, fd: RawFd) {
+        let named = ClientFd {
+            fd,
+            owner,
+            opaque: None,
+        };
+        if let Some(old) = Self::inner().fds.write().unwrap().insert(name, named) {
+            close_fd(old.fd);
+        }
     }
 
     /// Get extern file descriptor restored in `QMP_CHANNEL`.
@@ -547,26 +1143,186 @@ impl QmpChannel {
     ///
     /// * `name` - Name of file descriptor.
     pub fn get_fd(name: &str) -> Option<RawFd> {
-        Self::inner().fds.read().unwrap().get(name).copied()
+        Self::inner()
+            .fds
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|named| named.fd)
     }
 
-    /// Send a `QmpEvent` to client.
+    /// Take extern file descriptor out of `QMP_CHANNEL`, the caller owns it then.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor.
+    pub fn take_fd(name: &str) -> Option<RawFd> {
+        Self::inner()
+            .fds
+            .write()
+            .unwrap()
+            .remove(name)
+            .map(|named| named.fd)
+    }
+
+    /// Close extern file descriptor restored in `QMP_CHANNEL`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor.
+    pub fn close_fd(name: &str) -> Result<()> {
+        let fd = Self::take_fd(name)
+            .with_context(|| format!("File descriptor named '{}' not found", name))?;
+        close_fd(fd);
+        Ok(())
+    }
+
+    /// Add extern file descriptor to a fd set in `QMP_CHANNEL`.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The stream fd of client.
+    /// * `fdset_id` - The id of fd set, the lowest unused one if it's `None`.
+    /// * `fd` - File descriptor sent by client.
+    /// * `opaque` - Free-form string given by client.
+    pub fn add_fdset_fd(
+        owner: RawFd,
+        fdset_id: Option<u64>,
+        fd: RawFd,
+        opaque: Option<String>,
+    ) -> Result<schema::AddfdInfo> {
+        let mut fdsets = Self::inner().fdsets.write().unwrap();
+        let fdset_id = match fdset_id {
+            Some(id) => id,
+            None => (0..)
+                .find(|id| !fdsets.contains_key(id))
+                .with_context(|| "No fd set id is available")?,
+        };
+        fdsets
+            .entry(fdset_id)
+            .or_default()
+            .push(ClientFd { fd, owner, opaque });
+        Ok(schema::AddfdInfo {
+            fdset_id,
+            fd: i64::from(fd),
+        })
+    }
+
+    /// Remove file descriptor `fd` from fd set `fdset_id` and close it. All
+    /// file descriptors in the fd set are removed if `fd` is `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - The id of fd set.
+    /// * `fd` - File descriptor returned by `add-fd`.
+    pub fn remove_fdset_fd(fdset_id: u64, fd: Option<i64>) -> Result<()> {
+        let mut fdsets = Self::inner().fdsets.write().unwrap();
+        let fds = fdsets
+            .get_mut(&fdset_id)
+            .with_context(|| format!("Fd set {} not found", fdset_id))?;
+        match fd {
+            Some(fd) => {
+                let index = fds
+                    .iter()
+                    .position(|client_fd| i64::from(client_fd.fd) == fd)
+                    .with_context(|| format!("Fd {} not found in fd set {}", fd, fdset_id))?;
+                close_fd(fds.remove(index).fd);
+            }
+            None => fds.drain(..).for_each(|client_fd| close_fd(client_fd.fd)),
+        }
+        if fds.is_empty() {
+            fdsets.remove(&fdset_id);
+        }
+        Ok(())
+    }
+
+    /// Query all fd sets in `QMP_CHANNEL`.
+    pub fn query_fdsets() -> Vec<schema::FdsetInfo> {
+        Self::inner()
+            .fdsets
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, fds)| schema::FdsetInfo {
+                fdset_id: *id,
+                fds: fds
+                    .iter()
+                    .map(|client_fd| schema::FdsetFdInfo {
+                        fd: i64::from(client_fd.fd),
+                        opaque: client_fd.opaque.clone(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Duplicate a file descriptor in fd set `fdset_id` whose access mode
+    /// matches `flags`, the caller owns the duplicated one. `O_DIRECT` in
+    /// `flags` is set to the file descriptor.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - The id of fd set.
+    /// * `flags` - The flags to open the file with.
+    pub fn dup_fdset_fd(fdset_id: u64, flags: i32) -> Result<RawFd> {
+        let fdsets = Self::inner().fdsets.read().unwrap();
+        let fds = fdsets
+            .get(&fdset_id)
+            .with_context(|| format!("Fd set {} not found", fdset_id))?;
+        for client_fd in fds {
+            // SAFETY: the fd is owned by `QMP_CHANNEL` and valid.
+            let fd_flags = unsafe { libc::fcntl(client_fd.fd, libc::F_GETFL) };
+            if fd_flags < 0 || fd_flags & libc::O_ACCMODE != flags & libc::O_ACCMODE {
+                continue;
+            }
+            // SAFETY: the fd is owned by `QMP_CHANNEL` and valid.
+            if flags & libc::O_DIRECT != 0
+                && unsafe { libc::fcntl(client_fd.fd, libc::F_SETFL, fd_flags | libc::O_DIRECT) }
+                    < 0
+            {
+                bail!(
+                    "Failed to set O_DIRECT to fd {}: {:?}",
+                    client_fd.fd,
+                    std::io::Error::last_os_error()
+                );
+            }
+            // SAFETY: the fd is owned by `QMP_CHANNEL` and valid.
+            let fd = unsafe { libc::fcntl(client_fd.fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if fd < 0 {
+                bail!(
+                    "Failed to dup fd {}: {:?}",
+                    client_fd.fd,
+                    std::io::Error::last_os_error()
+                );
+            }
+            return Ok(fd);
+        }
+        bail!("No fd in fd set {} matches the access mode", fdset_id)
+    }
+
+    /// Send a `QmpEvent` to all negotiated clients, the event is dropped if
+    /// there is none. An event raised by a command is sent before the
+    /// command's return.
     ///
     /// # Arguments
     ///
     /// * `event` - The `QmpEvent` sent to client.
-    #[allow(clippy::unused_io_amount)]
     pub fn send_event(event: &schema::QmpEvent) {
-        if Self::is_connected() {
-            let event_str = serde_json::to_string(&event).unwrap();
-            let mut writer_unlocked = Self::inner().event_writer.write().unwrap();
-            let writer = writer_unlocked.as_mut().unwrap();
-            writer.flush().unwrap();
-            writer.write(event_str.as_bytes()).unwrap();
-            writer.write(&[b'\r']).unwrap();
-            writer.write(&[b'\n']).unwrap();
+        let event_str = serde_json::to_string(&event).unwrap() + "\r\n";
+        let mut clients = Self::inner().event_clients.write().unwrap();
+        let mut broken = Vec::new();
+        for (fd, client) in clients.iter_mut().filter(|(_, c)| c.negotiated) {
+            client.writer.flush().unwrap();
+            if let Err(e) = client.writer.write_all(event_str.as_bytes()) {
+                warn!("Failed to send event to qmp client {}: {:?}", fd, e);
+                broken.push(*fd);
+                continue;
+            }
             info!("EVENT: --> {:?}", event);
         }
+        for fd in broken {
+            clients.remove(&fd);
+        }
     }
 
     fn inner() -> &'static std::sync::Arc<QmpChannel> {
@@ -602,7 +1358,7 @@ mod tests {
                         },
                         "package": ""
                     },
-                    "capabilities": []
+                    "capabilities": ["oob"]
                 }
             }
         "#;
@@ -611,6 +1367,26 @@ mod tests {
         assert_eq!(greeting_from_json, greeting_msg);
     }
 
+    #[test]
+    fn test_qmp_version() {
+        let version = serde_json::to_value(Version::current()).unwrap();
+        let triplet: Vec<u64> = env!("CARGO_PKG_VERSION")
+            .split('.')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        assert_eq!(version["qemu"]["major"], triplet[0]);
+        assert_eq!(version["qemu"]["minor"], triplet[1]);
+        assert_eq!(version["qemu"]["micro"], triplet[2]);
+        assert_eq!(
+            version["package"],
+            format!(
+                "TeleVM-{} ({})",
+                env!("CARGO_PKG_VERSION"),
+                std::env::consts::ARCH
+            )
+        );
+    }
+
     #[test]
     fn test_qmp_resp() {
         // 1.Empty response and ID change;
@@ -690,8 +1466,15 @@ mod tests {
 
         // Use event! macro to send event msg to client
         let socket = Socket::from_unix_listener(listener, None);
-        socket.bind_unix_stream(server);
-        QmpChannel::bind_writer(SocketRWHandler::new(socket.get_stream_fd()));
+        let stream_fd = socket.bind_unix_stream(server);
+        QmpChannel::bind_writer(SocketRWHandler::new(stream_fd));
+
+        // 0.no event is sent before negotiation
+        event!(Stop);
+        assert!(!QmpChannel::is_negotiated(stream_fd));
+        QmpChannel::negotiate(stream_fd, false);
+        assert!(QmpChannel::is_negotiated(stream_fd));
+        assert!(!QmpChannel::is_oob_enabled(stream_fd));
 
         // 1.send no-content event
         event!(Stop);
@@ -712,6 +1495,7 @@ mod tests {
         let shutdown_event = schema::Shutdown {
             guest: true,
             reason: "guest-shutdown".to_string(),
+            resumable: None,
         };
         event!(Shutdown; shutdown_event);
         let length = client.read(&mut buffer).unwrap();
@@ -729,6 +1513,63 @@ mod tests {
         recover_unix_socket_environment("06");
     }
 
+    #[test]
+    fn test_qmp_parse_request() {
+        let request = serde_json::json!({"execute": "query-status", "id": "1"});
+        let (qmp_command, oob) = parse_request(request).unwrap();
+        assert!(matches!(qmp_command, QmpCommand::query_status { .. }));
+        assert!(!oob);
+
+        let request = serde_json::json!({"exec-oob": "migrate_cancel"});
+        let (qmp_command, oob) = parse_request(request).unwrap();
+        assert!(matches!(qmp_command, QmpCommand::cancel_migrate { .. }));
+        assert!(oob);
+
+        let request = serde_json::json!({"execute": "quit", "exec-oob": "quit"});
+        assert!(parse_request(request).is_err());
+        let request = serde_json::json!({"exec-oob": "no-such-command"});
+        assert!(parse_request(request).is_err());
+    }
+
+    #[test]
+    fn test_qmp_request_queue() {
+        use crate::socket::{Socket, SocketRWHandler, DEFAULT_MAX_IN_FLIGHT};
+
+        QmpChannel::object_init();
+        let (listener, _client, server) = prepare_unix_socket_environment("10");
+        let socket = Socket::from_unix_listener(listener, None);
+        let stream_fd = socket.bind_unix_stream(server);
+        QmpChannel::bind_writer(SocketRWHandler::new(stream_fd));
+        let client_id = QmpChannel::client_id(stream_fd).unwrap();
+
+        let request = || QmpRequest {
+            stream_fd,
+            client_id,
+            command: parse_request(serde_json::json!({"execute": "stop"})).map(|(c, _)| c),
+            fd: None,
+        };
+        for _ in 0..DEFAULT_MAX_IN_FLIGHT {
+            assert!(QmpChannel::queue_request(request(), DEFAULT_MAX_IN_FLIGHT).is_ok());
+        }
+        assert!(QmpChannel::queue_request(request(), DEFAULT_MAX_IN_FLIGHT).is_err());
+        // The command executing is still in flight.
+        assert!(QmpChannel::pop_request().is_some());
+        assert!(QmpChannel::queue_request(request(), DEFAULT_MAX_IN_FLIGHT).is_err());
+        QmpChannel::complete_request(stream_fd, client_id);
+        assert!(QmpChannel::queue_request(request(), DEFAULT_MAX_IN_FLIGHT).is_ok());
+
+        // Commands left are dropped with the client.
+        QmpChannel::unbind(stream_fd);
+        assert!(QmpChannel::inner()
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|r| r.client_id != client_id));
+
+        recover_unix_socket_environment("10");
+    }
+
     #[test]
     fn test_qmp_send_response() {
         use crate::socket::Socket;
@@ -740,10 +1581,10 @@ mod tests {
 
         // Use event! macro to send event msg to client
         let socket = Socket::from_unix_listener(listener, None);
-        socket.bind_unix_stream(server);
+        let stream_fd = socket.bind_unix_stream(server);
 
         // 1.send greeting response
-        socket.send_response(true);
+        socket.send_response(stream_fd, true);
         let length = client.read(&mut buffer).unwrap();
         let qmp_response: QmpGreeting =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
@@ -751,7 +1592,7 @@ mod tests {
         assert_eq!(qmp_greeting, qmp_response);
 
         // 2.send empty response
-        socket.send_response(false);
+        socket.send_response(stream_fd, false);
         let length = client.read(&mut buffer).unwrap();
         let qmp_response: Response =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
@@ -763,6 +1604,60 @@ mod tests {
         drop(socket);
     }
 
+    #[test]
+    fn test_qmp_fdsets() {
+        use std::fs::{File, OpenOptions};
+        use std::os::unix::io::IntoRawFd;
+
+        QmpChannel::object_init();
+        let owner = -2;
+        let ro_fd = File::open("/dev/null").unwrap().into_raw_fd();
+        let rw_fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .unwrap()
+            .into_raw_fd();
+
+        // 1.add fds to fd set and query them
+        let info = QmpChannel::add_fdset_fd(owner, Some(1000), ro_fd, Some("ro".to_string()));
+        assert_eq!(info.unwrap().fdset_id, 1000);
+        QmpChannel::add_fdset_fd(owner, Some(1000), rw_fd, None).unwrap();
+        let fdset = QmpChannel::query_fdsets()
+            .into_iter()
+            .find(|fdset| fdset.fdset_id == 1000)
+            .unwrap();
+        assert_eq!(fdset.fds.len(), 2);
+        assert_eq!(fdset.fds[0].opaque, Some("ro".to_string()));
+
+        // 2.dup the fd matches the access mode
+        let fd = QmpChannel::dup_fdset_fd(1000, libc::O_RDWR).unwrap();
+        assert_ne!(fd, rw_fd);
+        unsafe { libc::close(fd) };
+        assert!(QmpChannel::dup_fdset_fd(1000, libc::O_WRONLY).is_err());
+        assert!(QmpChannel::dup_fdset_fd(1001, libc::O_RDWR).is_err());
+
+        // 3.remove fd from fd set
+        QmpChannel::remove_fdset_fd(1000, Some(i64::from(ro_fd))).unwrap();
+        assert!(QmpChannel::remove_fdset_fd(1000, Some(i64::from(ro_fd))).is_err());
+        assert!(QmpChannel::dup_fdset_fd(1000, libc::O_RDONLY).is_err());
+
+        // 4.fds left behind are closed when client is gone
+        let named_fd = File::open("/dev/null").unwrap().into_raw_fd();
+        QmpChannel::set_fd(owner, "test_fd".to_string(), named_fd);
+        assert_eq!(QmpChannel::get_fd("test_fd"), Some(named_fd));
+        QmpChannel::unbind(owner);
+        assert_eq!(QmpChannel::get_fd("test_fd"), None);
+        assert!(QmpChannel::query_fdsets()
+            .iter()
+            .all(|fdset| fdset.fdset_id != 1000));
+        assert!(QmpChannel::close_fd("test_fd").is_err());
+
+        assert_eq!(parse_fdset_path("/dev/fdset/3").unwrap(), Some(3));
+        assert_eq!(parse_fdset_path("/path/to/block").unwrap(), None);
+        assert!(parse_fdset_path("/dev/fdset/abc").is_err());
+    }
+
     #[test]
     fn test_create_error_response() {
         let strange_msg = "!?/.,、。’】=  -~1！@#￥%……&*（）——+".to_string();
@@ -10,6 +10,8 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 pub use serde_json::Value as Any;
 use strum_macros::{EnumIter, EnumString, EnumVariantNames};
@@ -80,6 +82,18 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    system_reset {
+        #[serde(default)]
+        arguments: system_reset,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    system_powerdown {
+        #[serde(default)]
+        arguments: system_powerdown,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     device_add {
         arguments: Box<device_add>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -128,6 +142,37 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-cpus-fast")]
+    #[strum(serialize = "query-cpus-fast")]
+    query_cpus_fast {
+        #[serde(default)]
+        arguments: query_cpus_fast,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-vcpu-stats")]
+    #[strum(serialize = "query-vcpu-stats")]
+    query_vcpu_stats {
+        #[serde(default)]
+        arguments: query_vcpu_stats,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-boot-time")]
+    #[strum(serialize = "query-boot-time")]
+    query_boot_time {
+        #[serde(default)]
+        arguments: query_boot_time,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-cpu-model-expansion")]
+    #[strum(serialize = "query-cpu-model-expansion")]
+    query_cpu_model_expansion {
+        arguments: query_cpu_model_expansion,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-status")]
     query_status {
         #[serde(default)]
@@ -140,6 +185,34 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    closefd {
+        arguments: closefd,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "add-fd")]
+    #[strum(serialize = "add-fd")]
+    add_fd {
+        #[serde(default)]
+        arguments: add_fd,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "remove-fd")]
+    #[strum(serialize = "remove-fd")]
+    remove_fd {
+        arguments: remove_fd,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-fdsets")]
+    #[strum(serialize = "query-fdsets")]
+    query_fdsets {
+        #[serde(default)]
+        arguments: query_fdsets,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "blockdev-add")]
     blockdev_add {
         arguments: Box<blockdev_add>,
@@ -152,6 +225,11 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    block_resize {
+        arguments: block_resize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "balloon")]
     balloon {
         #[serde(default)]
@@ -186,6 +264,35 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "migrate-set-parameters")]
+    #[strum(serialize = "migrate-set-parameters")]
+    migrate_set_parameters {
+        arguments: migrate_set_parameters,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-migrate-parameters")]
+    #[strum(serialize = "query-migrate-parameters")]
+    query_migrate_parameters {
+        #[serde(default)]
+        arguments: query_migrate_parameters,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "snapshot-save")]
+    #[strum(serialize = "snapshot-save")]
+    snapshot_save {
+        arguments: snapshot_save,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "snapshot-load")]
+    #[strum(serialize = "snapshot-load")]
+    snapshot_load {
+        arguments: snapshot_load,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-version")]
     query_version {
         #[serde(default)]
@@ -365,21 +472,122 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-sysbus")]
+    #[strum(serialize = "query-sysbus")]
+    query_sysbus {
+        #[serde(default)]
+        arguments: query_sysbus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "trace-mmio")]
+    #[strum(serialize = "trace-mmio")]
+    trace_mmio {
+        arguments: trace_mmio,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-mmio-trace")]
+    #[strum(serialize = "query-mmio-trace")]
+    query_mmio_trace {
+        #[serde(default)]
+        arguments: query_mmio_trace,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "set-log-level")]
+    #[strum(serialize = "set-log-level")]
+    set_log_level {
+        arguments: set_log_level,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-rtc")]
+    #[strum(serialize = "query-rtc")]
+    query_rtc {
+        #[serde(default)]
+        arguments: query_rtc,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    screendump {
+        arguments: screendump,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "dump-guest-memory")]
+    #[strum(serialize = "dump-guest-memory")]
+    dump_guest_memory {
+        arguments: dump_guest_memory,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "object-add")]
+    #[strum(serialize = "object-add")]
+    object_add {
+        arguments: object_add,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-memory-size-summary")]
+    #[strum(serialize = "query-memory-size-summary")]
+    query_memory_size_summary {
+        #[serde(default)]
+        arguments: query_memory_size_summary,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-memdev")]
+    #[strum(serialize = "query-memdev")]
+    query_memdev {
+        #[serde(default)]
+        arguments: query_memdev,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-netdev")]
+    #[strum(serialize = "query-netdev")]
+    query_netdev {
+        #[serde(default)]
+        arguments: query_netdev,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-kernel")]
+    #[strum(serialize = "query-kernel")]
+    query_kernel {
+        #[serde(default)]
+        arguments: query_kernel,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
 }
 
 /// qmp_capabilities
 ///
 /// Enable QMP capabilities.
 ///
+/// # Arguments
+///
+/// * `enable` - The capabilities to enable, only "oob" is supported. Once "oob"
+///   is enabled, "quit", "query-status" and "migrate_cancel" can be executed
+///   out-of-band by "exec-oob" instead of "execute", without waiting for the
+///   commands issued before.
+///
 /// # Examples
 ///
 /// ```text
-/// -> { "execute": "qmp_capabilities" }
+/// -> { "execute": "qmp_capabilities", "arguments": { "enable": [ "oob" ] } }
 /// <- { "return": {} }
+/// -> { "exec-oob": "query-status", "id": "oob-1" }
+/// <- { "return": { "status": "running", "singlestep": false, "running": true },
+///      "id": "oob-1" }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct qmp_capabilities {}
+pub struct qmp_capabilities {
+    pub enable: Option<Vec<String>>,
+}
 
 impl Command for qmp_capabilities {
     type Res = Empty;
@@ -458,6 +666,60 @@ impl Command for cont {
     }
 }
 
+/// system_reset
+///
+/// Reset the guest as if it is powered on again: devices are reset, kernel, initrd
+/// and device tree are reloaded, and vcpus restart from their boot state.
+///
+/// # Arguments
+///
+/// * `clear-memory` - Whether guest RAM is zeroed before the images are reloaded,
+///   it is preserved by default.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "system_reset" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct system_reset {
+    #[serde(rename = "clear-memory", default)]
+    pub clear_memory: bool,
+}
+
+impl Command for system_reset {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// system_powerdown
+///
+/// Press the power button of the machine, guest is expected to shut down in order.
+/// It's forced off after the timeout given by `-action powerdown=force-off`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "system_powerdown" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct system_powerdown {}
+
+impl Command for system_powerdown {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// device_add
 ///
 /// # Arguments
@@ -465,6 +727,7 @@ impl Command for cont {
 /// * `id` - the device's ID, must be unique.
 /// * `driver` - the name of the new device's driver.
 /// * `addr` - the address device insert into.
+/// * `memdev` - the memory backend plugged by "pc-dimm" driver.
 ///
 /// Additional arguments depend on the type.
 ///
@@ -474,6 +737,12 @@ impl Command for cont {
 /// -> { "execute": "device_add",
 ///      "arguments": { "id": "net-0", "driver": "virtio-net-mmio", "addr": "0x0"}}
 /// <- { "return": {} }
+/// -> { "execute": "device_add",
+///      "arguments": { "id": "dimm-0", "driver": "pc-dimm", "memdev": "mem-0"}}
+/// <- { "return": {} }
+/// -> { "execute": "device_add",
+///      "arguments": { "id": "cpu2", "driver": "host-riscv64-cpu", "core-id": 2}}
+/// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -513,6 +782,13 @@ pub struct device_add {
     pub queues: Option<u16>,
     pub boot_index: Option<u8>,
     pub sysfsdev: Option<String>,
+    pub memdev: Option<String>,
+    #[serde(rename = "socket-id")]
+    pub socket_id: Option<u8>,
+    #[serde(rename = "core-id")]
+    pub core_id: Option<u8>,
+    #[serde(rename = "thread-id")]
+    pub thread_id: Option<u8>,
 }
 
 pub type DeviceAddArgument = device_add;
@@ -544,17 +820,19 @@ pub struct CacheOptions {
 ///
 /// # Arguments
 ///
-/// * `node_name` - the device's ID, must be unique.
-/// * `file` - the backend file information.
+/// * `node_name` - the backend's name, must be unique.
+/// * `file` - the backend file information, only "file" driver is supported.
+///   The filename can be `/dev/fdset/<id>` to use a fd added by `add-fd`.
 /// * `cache` - if use direct io.
 /// * `read_only` - if readonly.
+/// * `driver` - the image format, only "raw" is supported.
 ///
 /// Additional arguments depend on the type.
 ///
 /// # Examples
 ///
 /// ```text
-/// -> { "execute": "blockdev_add",
+/// -> { "execute": "blockdev-add",
 ///      "arguments":  {"node-name": "drive-0",
 ///                     "file": {"driver": "file", "filename": "/path/to/block"},
 ///                     "cache": {"direct": true}, "read-only": false }}
@@ -595,8 +873,10 @@ impl Command for blockdev_add {
 /// # Arguments
 ///
 /// * `id` - the device's ID, must be unique.
-/// * `ifname` - the backend tap dev name.
-/// * `fds` - the file fd opened by upper level.
+/// * `type` - the backend type, "tap" by default or "fd".
+/// * `ifname` - the backend tap dev name, required by "tap".
+/// * `fds` - the name of tap fd passed by `getfd`, or `/dev/fdset/<id>` of a
+///   fd set added by `add-fd`, required by "fd".
 ///
 /// Additional arguments depend on the type.
 ///
@@ -604,7 +884,10 @@ impl Command for blockdev_add {
 ///
 /// ```text
 /// -> { "execute": "netdev_add",
-///      "arguments":  {"id": "net-0", "ifname": "tap0", "fds": 123 }}
+///      "arguments":  {"id": "net-0", "type": "tap", "ifname": "tap0", "script": "no" }}
+/// <- { "return": {} }
+/// -> { "execute": "netdev_add",
+///      "arguments":  {"id": "net-1", "type": "fd", "fds": "fd1" }}
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -639,7 +922,12 @@ impl Command for netdev_add {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct AddrDataOptions {
-    pub path: String,
+    /// Path of "unix" address.
+    pub path: Option<String>,
+    /// Host of "inet" address.
+    pub host: Option<String>,
+    /// Port of "inet" address.
+    pub port: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -654,8 +942,14 @@ pub struct AddrOptions {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct BackendDataOptions {
-    pub addr: AddrOptions,
-    pub server: bool,
+    /// Address of "socket" backend.
+    pub addr: Option<AddrOptions>,
+    /// Whether "socket" backend listens on the address.
+    pub server: Option<bool>,
+    /// Whether "socket" server waits for client, it's not supported.
+    pub wait: Option<bool>,
+    /// Output file of "file" backend.
+    pub out: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -663,7 +957,7 @@ pub struct BackendDataOptions {
 pub struct BackendOptions {
     #[serde(rename = "type")]
     pub backend_type: String,
-    #[serde(rename = "data")]
+    #[serde(rename = "data", default)]
     pub backend_data: BackendDataOptions,
 }
 
@@ -672,7 +966,8 @@ pub struct BackendOptions {
 /// # Arguments
 ///
 /// * `id` - the character device's ID, must be unique.
-/// * `backend` - the chardev backend info.
+/// * `backend` - the chardev backend info, whose type is "socket", "pty", "file"
+///   or "null".
 ///
 /// Additional arguments depend on the type.
 ///
@@ -684,6 +979,9 @@ pub struct BackendOptions {
 ///            "addr": { "type": "unix", "data": { "path": "/path/to/socket" } },
 ///            "server": false }}}}
 /// <- { "return": {} }
+/// -> { "execute": "chardev-add",
+///      "arguments": { "id": "chardev_pty", "backend": { "type": "pty", "data": {} } } }
+/// <- { "return": { "pty": "/dev/pts/3" } }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -695,13 +993,21 @@ pub struct chardev_add {
 pub type CharDevAddArgument = chardev_add;
 
 impl Command for chardev_add {
-    type Res = Empty;
+    type Res = ChardevReturn;
 
-    fn back(self) -> Empty {
+    fn back(self) -> ChardevReturn {
         Default::default()
     }
 }
 
+/// Return of `chardev-add`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ChardevReturn {
+    /// Path of the pty allocated for "pty" backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pty: Option<String>,
+}
+
 /// chardev-remove
 ///
 /// Remove a chardev backend.
@@ -712,7 +1018,7 @@ impl Command for chardev_add {
 ///
 /// # Errors
 ///
-/// If `id` is not a valid chardev backend, DeviceNotFound
+/// If `id` is not a valid chardev backend, or it's used by a device.
 ///
 /// # Examples
 ///
@@ -776,6 +1082,21 @@ impl Command for device_del {
     }
 }
 
+/// blockdev-del
+///
+/// Remove a block backend, which is refused while a device is using it.
+///
+/// # Arguments
+///
+/// * `node_name` - The name of the block backend.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-del",
+///      "arguments": { "node-name": "drive-0" } }
+/// <- { "return": {} }
+/// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct blockdev_del {
@@ -791,6 +1112,50 @@ impl Command for blockdev_del {
     }
 }
 
+/// block_resize
+///
+/// Resize the image of a raw block backend, the guest is notified of the new
+/// capacity by a config change interrupt. Shrinking is refused unless `force` is set.
+///
+/// # Arguments
+///
+/// * `device` - The id of the virtio-blk device.
+/// * `node_name` - The name of the block backend, used if `device` is not given.
+/// * `size` - The new size in bytes, a multiple of 512.
+/// * `force` - Allow shrinking the image.
+///
+/// # Errors
+///
+/// If the backend is read-only, the size is not aligned or smaller than the current
+/// one without `force`, GenericError
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_resize",
+///      "arguments": { "device": "blk0", "size": 1073741824 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_resize {
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(rename = "node-name", default)]
+    pub node_name: Option<String>,
+    pub size: u64,
+    #[serde(default)]
+    pub force: bool,
+}
+
+impl Command for block_resize {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// netdev_del
 ///
 /// Remove a network backend.
@@ -801,7 +1166,7 @@ impl Command for blockdev_del {
 ///
 /// # Errors
 ///
-/// If `id` is not a valid network backend, DeviceNotFound
+/// If `id` is not a valid network backend, or it is used by a device, GenericError
 ///
 /// # Examples
 ///
@@ -995,80 +1360,297 @@ pub struct CpuInfoArm {}
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct CpuInfoRISCV {}
 
-/// query-status
+/// query-cpus-fast:
 ///
-/// Query the run status of all VCPUs.
+/// Returns information about all virtual CPUs without interrupting them.
 ///
 /// # Returns
 ///
-/// `StatusInfo` reflecting all VCPUs.
+/// A list of information about each virtual CPU, including its topology and the
+/// host cpus its thread is allowed to run on.
 ///
 /// # Examples
 ///
 /// ```text
-/// -> { "execute": "query-status" }
-/// <- { "return": { "running": true,
-///                  "singlestep": false,
-///                  "status": "running" } }
+/// -> { "execute": "query-cpus-fast" }
+/// <- { "return": [
+///          {
+///             "cpu-index":0,
+///             "qom-path":"/machine/unattached/device[0]",
+///             "thread-id":3134,
+///             "props":{"socket-id":0,"core-id":0,"thread-id":0},
+///             "target":"riscv64",
+///             "host-cpus":[2]
+///          },
+///          {
+///             "cpu-index":1,
+///             "qom-path":"/machine/unattached/device[1]",
+///             "thread-id":3135,
+///             "props":{"socket-id":0,"core-id":1,"thread-id":0},
+///             "target":"riscv64",
+///             "host-cpus":[3]
+///          }
+///       ]
+///    }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct query_status {}
+pub struct query_cpus_fast {}
 
-impl Command for query_status {
-    type Res = StatusInfo;
+impl Command for query_cpus_fast {
+    type Res = Vec<CpuInfoFast>;
 
-    fn back(self) -> StatusInfo {
+    fn back(self) -> Vec<CpuInfoFast> {
         Default::default()
     }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct StatusInfo {
-    #[serde(rename = "singlestep")]
-    pub singlestep: bool,
-    #[serde(rename = "running")]
-    pub running: bool,
-    #[serde(rename = "status")]
-    pub status: RunState,
+pub struct CpuInfoFast {
+    #[serde(rename = "cpu-index")]
+    pub cpu_index: isize,
+    #[serde(rename = "qom-path")]
+    pub qom_path: String,
+    #[serde(rename = "thread-id")]
+    pub thread_id: isize,
+    #[serde(rename = "props", default, skip_serializing_if = "Option::is_none")]
+    pub props: Option<CpuInstanceProperties>,
+    #[serde(rename = "target")]
+    pub target: String,
+    /// Host cpus which the vcpu thread is allowed to run on.
+    #[serde(rename = "host-cpus", default, skip_serializing_if = "Option::is_none")]
+    pub host_cpus: Option<Vec<usize>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum RunState {
-    #[serde(rename = "debug")]
-    debug,
-    #[serde(rename = "inmigrate")]
-    inmigrate,
-    #[serde(rename = "internal-error")]
-    internal_error,
-    #[serde(rename = "io-error")]
-    io_error,
-    #[serde(rename = "paused")]
-    paused,
-    #[serde(rename = "postmigrate")]
-    postmigrate,
-    #[serde(rename = "prelaunch")]
-    prelaunch,
-    #[serde(rename = "finish-migrate")]
-    finish_migrate,
-    #[serde(rename = "restore-vm")]
-    restore_vm,
-    #[serde(rename = "running")]
-    running,
-    #[serde(rename = "save-vm")]
-    save_vm,
-    #[serde(rename = "shutdown")]
-    shutdown,
-    #[serde(rename = "suspended")]
-    suspended,
-    #[serde(rename = "watchdog")]
-    watchdog,
-    #[serde(rename = "guest-panicked")]
-    guest_panicked,
-    #[serde(rename = "colo")]
-    colo,
-    #[serde(rename = "preconfig")]
-    preconfig,
+/// query-vcpu-stats:
+///
+/// Returns runtime statistics of all virtual CPUs, which are kept across stop and cont
+/// and zeroed when the machine is reset.
+///
+/// # Returns
+///
+/// A list of statistics of each virtual CPU, time is in nanoseconds.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-vcpu-stats" }
+/// <- { "return": [
+///          {
+///             "cpu-index":0,
+///             "thread-id":3134,
+///             "guest-time-ns":2034567891,
+///             "exit-time-ns":12345678,
+///             "mmio-exits":2048,
+///             "sbi-exits":512,
+///             "interrupt-exits":4,
+///             "other-exits":0,
+///             "steal-time-ns":1048576
+///          }
+///       ]
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_vcpu_stats {}
+
+impl Command for query_vcpu_stats {
+    type Res = Vec<VcpuStats>;
+
+    fn back(self) -> Vec<VcpuStats> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct VcpuStats {
+    #[serde(rename = "cpu-index")]
+    pub cpu_index: isize,
+    #[serde(rename = "thread-id")]
+    pub thread_id: isize,
+    #[serde(rename = "guest-time-ns")]
+    pub guest_time_ns: u64,
+    #[serde(rename = "exit-time-ns")]
+    pub exit_time_ns: u64,
+    #[serde(rename = "mmio-exits")]
+    pub mmio_exits: u64,
+    #[serde(rename = "sbi-exits")]
+    pub sbi_exits: u64,
+    #[serde(rename = "interrupt-exits")]
+    pub interrupt_exits: u64,
+    #[serde(rename = "other-exits")]
+    pub other_exits: u64,
+    /// Time the vcpu thread has waited on host run queues, which guest sees as
+    /// steal time with `-cpu steal-time=on`.
+    #[serde(rename = "steal-time-ns")]
+    pub steal_time_ns: u64,
+}
+
+/// query-boot-time:
+///
+/// Returns boot milestones reached so far, which are recorded with `-boottime`.
+///
+/// # Returns
+///
+/// A list of milestones in the order they are reached, time is in nanoseconds since
+/// process start.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-boot-time" }
+/// <- { "return": [
+///          { "milestone": "config-parsed", "time-ns": 1523478 },
+///          { "milestone": "memory-mapped", "time-ns": 3208771 }
+///       ]
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_boot_time {}
+
+impl Command for query_boot_time {
+    type Res = Vec<BootMilestoneInfo>;
+
+    fn back(self) -> Vec<BootMilestoneInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BootMilestoneInfo {
+    #[serde(rename = "milestone")]
+    pub milestone: String,
+    #[serde(rename = "time-ns")]
+    pub time_ns: u64,
+}
+
+/// query-cpu-model-expansion:
+///
+/// Expand the cpu model of vcpus into ISA extensions in effect, which are given by
+/// `-cpu` and accepted by host KVM.
+///
+/// # Arguments
+///
+/// * `type_` - Expansion type, "static" or "full", which are expanded the same.
+/// * `model` - Cpu model to be expanded, only the model of vcpus is supported.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-cpu-model-expansion",
+///      "arguments": { "type": "full", "model": { "name": "rv64" } } }
+/// <- { "return": {
+///          "model": {
+///             "name": "rv64",
+///             "props": { "a": true, "c": true, "d": true, "f": true,
+///                        "h": false, "i": true, "m": true, "v": false }
+///          }
+///       }
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_cpu_model_expansion {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub model: CpuModelInfo,
+}
+
+impl Command for query_cpu_model_expansion {
+    type Res = CpuModelExpansionInfo;
+
+    fn back(self) -> CpuModelExpansionInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct CpuModelInfo {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub props: Option<BTreeMap<String, bool>>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct CpuModelExpansionInfo {
+    pub model: CpuModelInfo,
+}
+
+/// query-status
+///
+/// Query the run status of all VCPUs.
+///
+/// # Returns
+///
+/// `StatusInfo` reflecting all VCPUs.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-status" }
+/// <- { "return": { "running": true,
+///                  "singlestep": false,
+///                  "status": "running" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_status {}
+
+impl Command for query_status {
+    type Res = StatusInfo;
+
+    fn back(self) -> StatusInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct StatusInfo {
+    #[serde(rename = "singlestep")]
+    pub singlestep: bool,
+    #[serde(rename = "running")]
+    pub running: bool,
+    #[serde(rename = "status")]
+    pub status: RunState,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RunState {
+    #[serde(rename = "debug")]
+    debug,
+    #[serde(rename = "inmigrate")]
+    inmigrate,
+    #[serde(rename = "internal-error")]
+    internal_error,
+    #[serde(rename = "io-error")]
+    io_error,
+    #[serde(rename = "paused")]
+    paused,
+    #[serde(rename = "postmigrate")]
+    postmigrate,
+    #[serde(rename = "prelaunch")]
+    prelaunch,
+    #[serde(rename = "finish-migrate")]
+    finish_migrate,
+    #[serde(rename = "restore-vm")]
+    restore_vm,
+    #[serde(rename = "running")]
+    running,
+    #[serde(rename = "save-vm")]
+    save_vm,
+    #[serde(rename = "shutdown")]
+    shutdown,
+    #[serde(rename = "suspended")]
+    suspended,
+    #[serde(rename = "watchdog")]
+    watchdog,
+    #[serde(rename = "guest-panicked")]
+    guest_panicked,
+    #[serde(rename = "colo")]
+    colo,
+    #[serde(rename = "preconfig")]
+    preconfig,
 }
 
 impl Default for RunState {
@@ -1098,6 +1680,69 @@ impl Command for migrate {
     }
 }
 
+/// snapshot-save
+///
+/// Save vcpu registers, RAM and device state of the vm to a file. A running vm is
+/// paused while saving and resumed afterwards.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the snapshot file on host.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "snapshot-save", "arguments": { "filename": "/tmp/snap" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct snapshot_save {
+    pub filename: String,
+}
+
+impl Command for snapshot_save {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// snapshot-load
+///
+/// Restore the vm started with `-incoming defer` from a file written by
+/// `snapshot-save`, and run it.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the snapshot file on host.
+///
+/// # Errors
+///
+/// If the vm is not waiting for incoming snapshot, or the snapshot is taken on a
+/// machine with other vcpus or memory, GenericError
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "snapshot-load", "arguments": { "filename": "/tmp/snap" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct snapshot_load {
+    pub filename: String,
+}
+
+impl Command for snapshot_load {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// query-migrate:
 ///
 /// Returns information about current migration.
@@ -1112,6 +1757,82 @@ impl Command for query_migrate {
     }
 }
 
+/// migrate-set-parameters
+///
+/// Set parameters of live migration, which take effect on the migration in progress.
+///
+/// # Arguments
+///
+/// * `max_bandwidth` - Maximum rate of sending RAM in bytes per second, 0 means
+///   unlimited.
+/// * `downtime_limit` - Maximum time in milliseconds the vm is paused for the last stage.
+/// * `compress_level` - Level of zstd compressing RAM, 0 means no compression. Both
+///   source and destination must set it before migration.
+/// * `xbzrle_cache_size` - Size in bytes of cache of pages sent, whose changes are
+///   sent as xbzrle delta, 0 means disabled. Destination only needs it to be nonzero.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "migrate-set-parameters",
+///      "arguments": { "max-bandwidth": 33554432, "compress-level": 3 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct migrate_set_parameters {
+    #[serde(rename = "max-bandwidth", default)]
+    pub max_bandwidth: Option<u64>,
+    #[serde(rename = "downtime-limit", default)]
+    pub downtime_limit: Option<u64>,
+    #[serde(rename = "compress-level", default)]
+    pub compress_level: Option<u8>,
+    #[serde(rename = "xbzrle-cache-size", default)]
+    pub xbzrle_cache_size: Option<u64>,
+}
+
+impl Command for migrate_set_parameters {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-migrate-parameters
+///
+/// Returns parameters of live migration.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-migrate-parameters" }
+/// <- { "return": { "max-bandwidth": 134217728, "downtime-limit": 300,
+///                  "compress-level": 0, "xbzrle-cache-size": 0 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_migrate_parameters {}
+
+impl Command for query_migrate_parameters {
+    type Res = MigrationParameters;
+
+    fn back(self) -> MigrationParameters {
+        Default::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrationParameters {
+    #[serde(rename = "max-bandwidth")]
+    pub max_bandwidth: u64,
+    #[serde(rename = "downtime-limit")]
+    pub downtime_limit: u64,
+    #[serde(rename = "compress-level")]
+    pub compress_level: u8,
+    #[serde(rename = "xbzrle-cache-size")]
+    pub xbzrle_cache_size: u64,
+}
+
 /// cancel-migrate:
 ///
 /// Cancel migrate the current VM.
@@ -1130,6 +1851,75 @@ impl Command for cancel_migrate {
 pub struct MigrationInfo {
     #[serde(rename = "status", default, skip_serializing_if = "Option::is_none")]
     pub status: Option<String>,
+    /// Milliseconds since migration started, until it completed.
+    #[serde(
+        rename = "total-time",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub total_time: Option<u64>,
+    /// Milliseconds the vm was paused for the last stage of migration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downtime: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ram: Option<MigrationStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionStats>,
+    #[serde(
+        rename = "xbzrle-cache",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub xbzrle_cache: Option<XbzrleCacheStats>,
+}
+
+/// Statistics of RAM transferred by live migration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrationStats {
+    /// Bytes sent to destination.
+    pub transferred: u64,
+    /// Bytes of dirty RAM waiting to be sent.
+    pub remaining: u64,
+    /// Bytes of guest RAM.
+    pub total: u64,
+    /// Pages dirtied per second in the last round.
+    #[serde(rename = "dirty-pages-rate")]
+    pub dirty_pages_rate: u64,
+    /// Number of dirty page syncs.
+    #[serde(rename = "dirty-sync-count")]
+    pub dirty_sync_count: u64,
+    #[serde(rename = "page-size")]
+    pub page_size: u64,
+}
+
+/// Statistics of RAM compressed by zstd in live migration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompressionStats {
+    /// Bytes of RAM sent compressed.
+    pub bytes: u64,
+    /// Bytes of compressed data sent.
+    #[serde(rename = "compressed-size")]
+    pub compressed_size: u64,
+    /// Ratio of RAM bytes to compressed bytes.
+    #[serde(rename = "compression-rate")]
+    pub compression_rate: f64,
+}
+
+/// Statistics of pages sent as xbzrle delta in live migration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct XbzrleCacheStats {
+    #[serde(rename = "cache-size")]
+    pub cache_size: u64,
+    /// Bytes of delta sent.
+    pub bytes: u64,
+    /// Pages sent as delta.
+    pub pages: u64,
+    /// Dirty pages not found in cache.
+    #[serde(rename = "cache-miss")]
+    pub cache_miss: u64,
+    /// Ratio of dirty pages found in cache.
+    #[serde(rename = "cache-hit-rate")]
+    pub cache_hit_rate: f64,
 }
 
 /// getfd
@@ -1161,6 +1951,144 @@ impl Command for getfd {
     }
 }
 
+/// closefd
+///
+/// Close a file descriptor previously passed via `getfd`.
+///
+/// # Arguments
+///
+/// * `fdname` - File descriptor name.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "closefd", "arguments": { "fdname": "fd1" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct closefd {
+    #[serde(rename = "fdname")]
+    pub fd_name: String,
+}
+
+impl Command for closefd {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// add-fd
+///
+/// Receive a file descriptor via SCM rights and add it to a fd set. The fd
+/// set can be referenced by `/dev/fdset/<fdset-id>` as the filename of
+/// `blockdev-add` or the `fds` of `netdev_add`.
+///
+/// # Arguments
+///
+/// * `fdset-id` - The ID of the fd set to add the fd to, a new fd set is
+///   allocated if it's omitted.
+/// * `opaque` - A free-form string to help the client remember the fd.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "add-fd", "arguments": { "fdset-id": 1 } }
+/// <- { "return": { "fdset-id": 1, "fd": 3 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct add_fd {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: Option<u64>,
+    pub opaque: Option<String>,
+}
+
+impl Command for add_fd {
+    type Res = AddfdInfo;
+
+    fn back(self) -> AddfdInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddfdInfo {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: u64,
+    pub fd: i64,
+}
+
+/// remove-fd
+///
+/// Remove a file descriptor from a fd set, or the whole fd set if `fd` is
+/// omitted.
+///
+/// # Arguments
+///
+/// * `fdset-id` - The ID of the fd set.
+/// * `fd` - The file descriptor returned by `add-fd`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "remove-fd", "arguments": { "fdset-id": 1, "fd": 3 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct remove_fd {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: u64,
+    pub fd: Option<i64>,
+}
+
+impl Command for remove_fd {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-fdsets
+///
+/// Query the fd sets added by `add-fd`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-fdsets" }
+/// <- { "return": [ { "fdset-id": 1, "fds": [ { "fd": 3, "opaque": "rdwr:/tmp/disk" } ] } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_fdsets {}
+
+impl Command for query_fdsets {
+    type Res = Vec<FdsetInfo>;
+
+    fn back(self) -> Vec<FdsetInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FdsetInfo {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: u64,
+    pub fds: Vec<FdsetFdInfo>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FdsetFdInfo {
+    pub fd: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opaque: Option<String>,
+}
+
 /// Shutdown
 ///
 /// Emitted when the virtual machine has shut down, indicating that StratoVirt is
@@ -1179,6 +2107,10 @@ pub struct Shutdown {
     #[serde(rename = "guest")]
     pub guest: bool,
     pub reason: String,
+    /// Set if the machine is paused instead of exiting, by `-no-shutdown`, so
+    /// it can be resumed by `cont` or reset by `system_reset`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumable: Option<bool>,
 }
 
 /// Reset
@@ -1224,13 +2156,100 @@ pub struct Resume {}
 /// ```
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
-pub struct DeviceDeleted {
-    /// Device name.
-    #[serde(rename = "device", default, skip_serializing_if = "Option::is_none")]
-    pub device: Option<String>,
-    /// Device path.
-    #[serde(rename = "path")]
-    pub path: String,
+pub struct DeviceDeleted {
+    /// Device name.
+    #[serde(rename = "device", default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Device path.
+    #[serde(rename = "path")]
+    pub path: String,
+}
+
+/// Watchdog
+///
+/// Emitted when the watchdog device of guest expires, before the action is taken.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "WATCHDOG",
+///      "data": { "action": "reset" },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Watchdog {
+    /// Action given by `-watchdog-action`, one of reset, poweroff, pause and none.
+    #[serde(rename = "action")]
+    pub action: String,
+}
+
+/// Powerdown
+///
+/// Emitted when the power button is pressed by `system_powerdown`.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "POWERDOWN", "data": {},
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Powerdown {}
+
+/// GuestPanicked
+///
+/// Emitted when guest panics, before the action is taken.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "GUEST_PANICKED",
+///      "data": { "action": "pause", "reason": "sbi-system-failure" },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GuestPanicked {
+    /// Action given by `-action panic`, one of pause, shutdown, exit-failure and none.
+    #[serde(rename = "action")]
+    pub action: String,
+    /// How guest reports the panic, or the panic message printed by guest kernel.
+    #[serde(rename = "reason")]
+    pub reason: String,
+}
+
+/// DumpCompleted
+///
+/// Emitted when `dump-guest-memory` ends.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "DUMP_COMPLETED",
+///      "data": { "result": { "total": 1073741824, "status": "completed",
+///                            "completed": 1073741824 } },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DumpCompleted {
+    pub result: DumpQueryResult,
+    /// Why the dump failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DumpQueryResult {
+    /// "completed" or "failed".
+    pub status: String,
+    /// Bytes of guest RAM written.
+    pub completed: u64,
+    /// Bytes of guest RAM to dump.
+    pub total: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, EnumIter, EnumVariantNames, EnumString)]
@@ -1260,11 +2279,32 @@ pub enum QmpEvent {
         data: DeviceDeleted,
         timestamp: TimeStamp,
     },
-    #[serde(rename = "BALLOON_CHANGED")]
+    #[serde(rename = "BALLOON_CHANGE")]
     BalloonChanged {
         data: BalloonInfo,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "WATCHDOG")]
+    Watchdog {
+        data: Watchdog,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "GUEST_PANICKED")]
+    GuestPanicked {
+        data: GuestPanicked,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "POWERDOWN")]
+    Powerdown {
+        #[serde(default)]
+        data: Powerdown,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "DUMP_COMPLETED")]
+    DumpCompleted {
+        data: DumpCompleted,
+        timestamp: TimeStamp,
+    },
 }
 
 /// query-balloon:
@@ -1273,13 +2313,13 @@ pub enum QmpEvent {
 ///
 /// # Returns
 ///
-/// `BalloonInfo` includs the actual size of memory
+/// `BalloonInfo` includs the actual size of memory, and the target size set by `balloon`.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-balloon" }
-/// <- {"return":{"actual":8589934592}}
+/// <- {"return":{"actual":8589934592,"target":4294967296}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_balloon {}
@@ -1293,6 +2333,9 @@ impl Command for query_balloon {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct BalloonInfo {
     pub actual: u64,
+    /// Only reported by `query-balloon`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<u64>,
 }
 
 /// query-vnc:
@@ -1363,6 +2406,7 @@ pub struct VncClientInfo {
 ///
 /// This is only an advice instead of command to VM,
 /// therefore, the VM changes its memory according to `value` and its condation.
+/// `value` below 64MiB or above the configured memory is rejected.
 ///
 /// # Example
 ///
@@ -1391,7 +2435,7 @@ impl Command for balloon {
 ///
 /// ```text
 /// -> { "execute": "query-version" }
-/// <- {"return":{"package":"StratoVirt-0.3.0","qemu":{"major":4,"micro":0,"minor":1}}}
+/// <- {"return":{"package":"TeleVM-2.2.0 (riscv64)","qemu":{"major":2,"micro":0,"minor":2}}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_version {}
@@ -1475,9 +2519,8 @@ impl Command for query_target {
 ///
 /// ```text
 /// -> { "execute": "query-machines" }
-/// <- {"return":[{"cpu-max":255,"deprecated":false,"hotpluggable-cpus":true,"name":"none","numa-mem-supported":false},
-/// {"cpu-max":255,"deprecated":false,"hotpluggable-cpus":true,"name":"microvm","numa-mem-supported":false},
-/// {"cpu-max":255,"deprecated":false,"hotpluggable-cpus":true,"name":"standardvm","numa-mem-supported":false}]}
+/// <- {"return":[{"cpu-max":254,"deprecated":false,"hotpluggable-cpus":false,"is-default":true,"name":"microvm","numa-mem-supported":false},
+/// {"cpu-max":254,"deprecated":false,"hotpluggable-cpus":false,"is-default":false,"name":"none","numa-mem-supported":false}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_machines {}
@@ -1492,6 +2535,8 @@ pub struct MachineInfo {
     #[serde(rename = "cpu-max")]
     pub cpu_max: u8,
     pub deprecated: bool,
+    #[serde(rename = "is-default", default)]
+    pub is_default: bool,
 }
 
 impl Command for query_machines {
@@ -1693,51 +2738,416 @@ pub struct CmdLine {
     pub option: String,
 }
 
-impl Command for query_command_line_options {
-    type Res = Vec<CmdLine>;
+impl Command for query_command_line_options {
+    type Res = Vec<CmdLine>;
+
+    fn back(self) -> Vec<CmdLine> {
+        Default::default()
+    }
+}
+
+/// Query capabilities of migration.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-migrate-capabilities" }
+/// <- {"return":[]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_migrate_capabilities {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MigrateCapabilities {
+    pub state: bool,
+    pub capability: String,
+}
+
+impl Command for query_migrate_capabilities {
+    type Res = Vec<MigrateCapabilities>;
+
+    fn back(self) -> Vec<MigrateCapabilities> {
+        Default::default()
+    }
+}
+
+/// Query target of StratoVirt.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-qmp-schema" }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_qmp_schema {}
+
+impl Command for query_qmp_schema {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// Query capabilities of sev.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-sev-capabilities" }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_sev_capabilities {}
+
+impl Command for query_sev_capabilities {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// List all Qom.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "qom-list" }
+/// <- {"return":[]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct qom_list {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct PropList {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub prop_type: String,
+}
+
+impl Command for qom_list {
+    type Res = Vec<PropList>;
+
+    fn back(self) -> Vec<PropList> {
+        Default::default()
+    }
+}
+
+/// Query char devices, `frontend-open` tells whether a device uses the chardev.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-chardev" }
+/// <- {"return":[{"frontend-open":true,"filename":"stdio","label":"serial_chardev"},
+///     {"frontend-open":false,"filename":"pty:/dev/pts/3","label":"chr0"}]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_chardev {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ChardevInfo {
+    #[serde(rename = "frontend-open")]
+    pub open: bool,
+    pub filename: String,
+    pub label: String,
+}
+
+impl Command for query_chardev {
+    type Res = Vec<ChardevInfo>;
+
+    fn back(self) -> Vec<ChardevInfo> {
+        Default::default()
+    }
+}
+
+/// Query devices attached to system bus, with their MMIO regions and IRQs.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-sysbus" }
+/// <- {"return":[{"type":"Plic","region_base":201326592,"region_size":67108864,"irq":null},
+///     {"type":"Serial","region_base":268435456,"region_size":256,"irq":1},
+///     {"type":"Others","region_base":null,"region_size":null,"irq":null}]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_sysbus {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct SysBusDeviceInfo {
+    #[serde(rename = "type")]
+    pub dev_type: String,
+    pub region_base: Option<u64>,
+    pub region_size: Option<u64>,
+    pub irq: Option<i32>,
+}
+
+impl Command for query_sysbus {
+    type Res = Vec<SysBusDeviceInfo>;
+
+    fn back(self) -> Vec<SysBusDeviceInfo> {
+        Default::default()
+    }
+}
+
+/// trace-mmio:
+///
+/// Enable or disable tracing MMIO accesses of sysbus devices with type `device`.
+///
+/// # Arguments
+///
+/// * `device` - Type of sysbus device, such as "virtio-mmio" or "serial".
+/// * `enable` - Whether the accesses are recorded.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "trace-mmio", "arguments": { "device": "virtio-mmio", "enable": true } }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct trace_mmio {
+    pub device: String,
+    pub enable: bool,
+}
+
+impl Command for trace_mmio {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// Query MMIO accesses recorded for traced sysbus devices, from the oldest to the
+/// newest. `overflow` counts the accesses dropped because the trace buffer is full.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-mmio-trace" }
+/// <- {"return":{"entries":[{"timestamp_ns":1024,"type":"virtio-mmio",
+///     "region_base":268439552,"offset":80,"size":4,"value":0,"write":true}],"overflow":0}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_mmio_trace {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MmioTraceEntryInfo {
+    pub timestamp_ns: u64,
+    #[serde(rename = "type")]
+    pub dev_type: String,
+    pub region_base: u64,
+    pub offset: u64,
+    pub size: usize,
+    pub value: u64,
+    pub write: bool,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MmioTraceInfo {
+    pub entries: Vec<MmioTraceEntryInfo>,
+    pub overflow: u64,
+}
+
+impl Command for query_mmio_trace {
+    type Res = MmioTraceInfo;
+
+    fn back(self) -> MmioTraceInfo {
+        Default::default()
+    }
+}
+
+/// set-log-level:
+///
+/// Replace the log filter of the running VM, in the same format as `-log-level`.
+///
+/// # Arguments
+///
+/// * `filter` - Log level of modules, and a bare level for the others.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "set-log-level", "arguments": { "filter": "virtio=debug,sysbus=trace,info" } }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct set_log_level {
+    pub filter: String,
+}
+
+impl Command for set_log_level {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-rtc:
+///
+/// Query the current time of guest rtc.
+///
+/// # Returns
+///
+/// `RtcInfo` includes the time in nanoseconds since unix epoch, the same time in
+/// ISO8601 and the clock driving rtc.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-rtc" }
+/// <- {"return":{"time":1150560081000000000,"date-time":"2006-06-17T16:01:21Z","clock":"vm"}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_rtc {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct RtcInfo {
+    pub time: u64,
+    #[serde(rename = "date-time")]
+    pub date_time: String,
+    pub clock: String,
+}
+
+impl Command for query_rtc {
+    type Res = RtcInfo;
+
+    fn back(self) -> RtcInfo {
+        Default::default()
+    }
+}
+
+/// screendump
+///
+/// Write the framebuffer of display device to a host file.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the image file on host.
+/// * `format` - Image format, only "ppm" is supported, which is the default.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "screendump", "arguments": { "filename": "/tmp/image" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct screendump {
+    pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+impl Command for screendump {
+    type Res = Empty;
 
-    fn back(self) -> Vec<CmdLine> {
+    fn back(self) -> Empty {
         Default::default()
     }
 }
 
-/// Query capabilities of migration.
+/// dump-guest-memory
 ///
-/// # Example
+/// Write guest RAM to an ELF core file, with registers of each vcpu in notes. The vm
+/// is paused during the dump, and `DUMP_COMPLETED` is emitted at the end.
+///
+/// # Arguments
+///
+/// * `paging` - Dump virtual memory mapped by guest page tables, which is not supported.
+/// * `protocol` - Destination file as "file:<path>".
+/// * `detach` - Dump in background, whose end is reported by `DUMP_COMPLETED` only.
+/// * `begin` - Start of guest physical range to dump, given with `length`.
+/// * `length` - Length of guest physical range to dump.
+/// * `format` - Only "elf" is supported, which is the default.
+///
+/// # Examples
 ///
 /// ```text
-/// -> { "execute": "query-migrate-capabilities" }
-/// <- {"return":[]}
+/// -> { "execute": "dump-guest-memory",
+///      "arguments": { "paging": false, "protocol": "file:/tmp/vmcore" } }
+/// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_migrate_capabilities {}
-
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct MigrateCapabilities {
-    pub state: bool,
-    pub capability: String,
+#[serde(deny_unknown_fields)]
+pub struct dump_guest_memory {
+    pub paging: bool,
+    pub protocol: String,
+    #[serde(default)]
+    pub detach: Option<bool>,
+    #[serde(default)]
+    pub begin: Option<u64>,
+    #[serde(default)]
+    pub length: Option<u64>,
+    #[serde(default)]
+    pub format: Option<String>,
 }
 
-impl Command for query_migrate_capabilities {
-    type Res = Vec<MigrateCapabilities>;
+impl Command for dump_guest_memory {
+    type Res = Empty;
 
-    fn back(self) -> Vec<MigrateCapabilities> {
+    fn back(self) -> Empty {
         Default::default()
     }
 }
 
-/// Query target of StratoVirt.
+/// object-add:
+///
+/// Create a backend object, "memory-backend-ram", "memory-backend-memfd" and
+/// "memory-backend-file" are supported, which are plugged into guest by `device_add`
+/// with "pc-dimm" driver.
+///
+/// # Arguments
+///
+/// * `qom_type` - Type of the object.
+/// * `id` - The object's ID, must be unique.
+/// * `size` - Size of the memory in bytes.
+/// * `share` - Whether the memory is mapped shared, default is false for
+///   "memory-backend-ram" and true for "memory-backend-memfd".
+/// * `seal` - Whether size of memfd is sealed, default is true.
+/// * `mem_path` - Path of the file or the directory of "memory-backend-file".
+/// * `merge` - Whether the memory is mergeable by KSM, default is false.
+/// * `dump` - Whether the memory is included in core dump of the process, default
+///   is the `dump-guest-core` of machine.
+/// * `prealloc` - Whether the memory is preallocated when it's plugged, default is false.
+/// * `host_nodes` - Host numa nodes the memory is bound to.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-qmp-schema" }
+/// -> { "execute": "object-add",
+///      "arguments": { "qom-type": "memory-backend-ram", "id": "mem-0", "size": 1073741824 } }
 /// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_qmp_schema {}
+#[serde(deny_unknown_fields)]
+pub struct object_add {
+    #[serde(rename = "qom-type")]
+    pub qom_type: String,
+    pub id: String,
+    pub size: Option<u64>,
+    pub share: Option<bool>,
+    pub seal: Option<bool>,
+    #[serde(rename = "mem-path")]
+    pub mem_path: Option<String>,
+    pub merge: Option<bool>,
+    pub dump: Option<bool>,
+    pub prealloc: Option<bool>,
+    #[serde(rename = "host-nodes")]
+    pub host_nodes: Option<Vec<u32>>,
+}
 
-impl Command for query_qmp_schema {
+pub type ObjectAddArgument = object_add;
+
+impl Command for object_add {
     type Res = Empty;
 
     fn back(self) -> Empty {
@@ -1745,74 +3155,137 @@ impl Command for query_qmp_schema {
     }
 }
 
-/// Query capabilities of sev.
+/// query-memory-size-summary:
+///
+/// Query the size of boot memory and memory plugged by `device_add`.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-sev-capabilities" }
-/// <- {"return":{}}
+/// -> { "execute": "query-memory-size-summary" }
+/// <- {"return":{"base-memory":1073741824,"plugged-memory":134217728}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_sev_capabilities {}
+pub struct query_memory_size_summary {}
 
-impl Command for query_sev_capabilities {
-    type Res = Empty;
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MemorySizeSummary {
+    #[serde(rename = "base-memory")]
+    pub base_memory: u64,
+    #[serde(rename = "plugged-memory")]
+    pub plugged_memory: u64,
+}
 
-    fn back(self) -> Empty {
+impl Command for query_memory_size_summary {
+    type Res = MemorySizeSummary;
+
+    fn back(self) -> MemorySizeSummary {
         Default::default()
     }
 }
 
-/// List all Qom.
+/// query-memdev:
+///
+/// Query memory backends created by `-object` and `object-add`.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "qom-list" }
-/// <- {"return":[]}
+/// -> { "execute": "query-memdev" }
+/// <- {"return":[{"id":"mem0","type":"memory-backend-memfd","size":1073741824,
+///     "merge":false,"dump":true,"prealloc":false,"host-nodes":[],"share":true,
+///     "plugged":true}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct qom_list {}
+pub struct query_memdev {}
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct PropList {
-    pub name: String,
+pub struct MemdevInfo {
+    pub id: String,
     #[serde(rename = "type")]
-    pub prop_type: String,
+    pub qom_type: String,
+    pub size: u64,
+    pub merge: bool,
+    pub dump: bool,
+    pub prealloc: bool,
+    #[serde(rename = "host-nodes")]
+    pub host_nodes: Vec<u32>,
+    pub share: bool,
+    pub plugged: bool,
 }
 
-impl Command for qom_list {
-    type Res = Vec<PropList>;
+impl Command for query_memdev {
+    type Res = Vec<MemdevInfo>;
 
-    fn back(self) -> Vec<PropList> {
+    fn back(self) -> Vec<MemdevInfo> {
         Default::default()
     }
 }
 
-/// Query char devices.
+/// query-netdev:
+///
+/// Query network backends created by `netdev_add`.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-chardev" }
-/// <- {"return":[]}
+/// -> { "execute": "query-netdev" }
+/// <- {"return":[{"id":"net-0","type":"tap","ifname":"tap0","device":"net0"},
+///     {"id":"net-1","type":"fd","fdname":"fd1"}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_chardev {}
+pub struct query_netdev {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NetdevInfo {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub net_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ifname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fdname: Option<String>,
+    /// Id of the device using the backend.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+impl Command for query_netdev {
+    type Res = Vec<NetdevInfo>;
+
+    fn back(self) -> Vec<NetdevInfo> {
+        Default::default()
+    }
+}
+
+/// query-kernel:
+///
+/// Query the boot source, with the final kernel command line including the
+/// params generated by devices.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-kernel" }
+/// <- {"return":{"kernel":"/path/to/Image","initrd":"/path/to/initrd",
+///     "cmdline":"console=ttyS0 root=/dev/vda earlycon=uart,mmio,0x10000000"}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_kernel {}
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct ChardevInfo {
-    #[serde(rename = "frontend-open")]
-    pub open: bool,
-    pub filename: String,
-    pub label: String,
+pub struct KernelInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initrd: Option<String>,
+    pub cmdline: String,
 }
 
-impl Command for query_chardev {
-    type Res = Vec<ChardevInfo>;
+impl Command for query_kernel {
+    type Res = KernelInfo;
 
-    fn back(self) -> Vec<ChardevInfo> {
+    fn back(self) -> KernelInfo {
         Default::default()
     }
 }
@@ -1838,61 +3311,137 @@ impl Command for qom_get {
 
 /// Query blocks of StratoVirt.
 ///
+/// Virtio block devices and the block backends inserted in them are listed.
+///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-block" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"blk0","locked":false,"removable":false,
+///      "inserted":{"node-name":"drive-0","file":"/path/to/block","drv":"raw",
+///      "ro":false,"image":{"filename":"/path/to/block","format":"raw",
+///      "virtual-size":67108864}}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block {}
 
 impl Command for query_block {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockInfo {
+    pub device: String,
+    pub locked: bool,
+    pub removable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inserted: Option<BlockDeviceInfo>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockDeviceInfo {
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub file: String,
+    pub drv: String,
+    pub ro: bool,
+    pub image: ImageInfo,
+}
+
 /// Query named block node.
 ///
+/// Block backends named by `-drive id` or `blockdev-add node-name` are listed.
+///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-named-block-nodes" }
-/// <- {"return":[]}
+/// <- {"return":[{"node-name":"drive-0","file":"/path/to/block","drv":"raw","ro":false,
+///      "direct":true,"image":{"filename":"/path/to/block","format":"raw",
+///      "virtual-size":67108864}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_named_block_nodes {}
 
 impl Command for query_named_block_nodes {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockNodeInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockNodeInfo> {
         Default::default()
     }
 }
 
-/// Query status of blocks.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockNodeInfo {
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub file: String,
+    pub drv: String,
+    pub ro: bool,
+    pub direct: bool,
+    pub image: ImageInfo,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImageInfo {
+    pub filename: String,
+    pub format: String,
+    #[serde(rename = "virtual-size")]
+    pub virtual_size: u64,
+}
+
+/// Query statistics of virtio block devices.
+///
+/// The counters accumulate since the device is plugged, and are cleared by
+/// `system_reset`. Latency is in nanoseconds.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-blockstats" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"blk0","node-name":"drive-0","stats":{"rd_bytes":4096,
+///      "wr_bytes":512,"rd_operations":2,"wr_operations":1,"flush_operations":1,
+///      "rd_total_time_ns":83000,"wr_total_time_ns":41000,"flush_total_time_ns":9000,
+///      "failed_rd_operations":0,"failed_wr_operations":0,"failed_flush_operations":0}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_blockstats {}
 
 impl Command for query_blockstats {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockStats>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockStats> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockStats {
+    pub device: String,
+    #[serde(rename = "node-name", default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    pub stats: BlockDeviceStats,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub failed_rd_operations: u64,
+    pub failed_wr_operations: u64,
+    pub failed_flush_operations: u64,
+}
+
 /// Query jobs of blocks.
 ///
 /// # Example
@@ -2057,6 +3606,33 @@ mod tests {
         let ret_msg = r#"invalid type: string "isdf", expected struct cont"#;
         assert!(err_msg == ret_msg);
 
+        // qmp: system_powerdown.
+        let json_msg = r#"
+        {
+            "execute": "system_powerdown"
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let ret_msg = r#"ok"#;
+        assert!(err_msg == ret_msg);
+
+        // unexpected arguments for system_powerdown.
+        let json_msg = r#"
+        {
+            "execute": "system_powerdown" ,
+            "arguments": "isdf"
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let ret_msg = r#"invalid type: string "isdf", expected struct system_powerdown"#;
+        assert!(err_msg == ret_msg);
+
         // qmp: query-hotpluggable-cpus.
         let json_msg = r#"
         {
@@ -2111,6 +3687,63 @@ mod tests {
         let ret_msg = r#"invalid type: string "isdf", expected struct query_cpus"#;
         assert!(err_msg == ret_msg);
 
+        // qmp: query-cpus-fast.
+        let json_msg = r#"
+        {
+            "execute": "query-cpus-fast"
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let ret_msg = r#"ok"#;
+        assert!(err_msg == ret_msg);
+
+        // qmp: query-vcpu-stats.
+        let json_msg = r#"
+        {
+            "execute": "query-vcpu-stats"
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let ret_msg = r#"ok"#;
+        assert!(err_msg == ret_msg);
+
+        // qmp: query-boot-time.
+        let json_msg = r#"
+        {
+            "execute": "query-boot-time"
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let ret_msg = r#"ok"#;
+        assert!(err_msg == ret_msg);
+
+        // qmp: query-cpu-model-expansion.
+        let json_msg = r#"
+        {
+            "execute": "query-cpu-model-expansion",
+            "arguments": { "type": "full", "model": { "name": "host" } }
+        }
+        "#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::query_cpu_model_expansion { arguments, .. }) => {
+                assert_eq!(arguments.type_, "full");
+                assert_eq!(arguments.model.name, "host");
+                assert!(arguments.model.props.is_none());
+            }
+            _ => panic!("Failed to parse query-cpu-model-expansion"),
+        }
+        let json_msg = r#"{ "execute": "query-cpu-model-expansion" }"#;
+        assert!(serde_json::from_str::<QmpCommand>(json_msg).is_err());
+
         // qmp: query-ststus.
         let json_msg = r#"
         {
@@ -2273,6 +3906,26 @@ mod tests {
         let ret_msg = r#"ok"#;
         assert!(err_msg == ret_msg);
 
+        // right arguments for add-fd, remove-fd and query-fdsets.
+        for json_msg in [
+            r#"{ "execute": "add-fd" }"#,
+            r#"{ "execute": "add-fd", "arguments": { "fdset-id": 1, "opaque": "disk" } }"#,
+            r#"{ "execute": "remove-fd", "arguments": { "fdset-id": 1, "fd": 3 } }"#,
+            r#"{ "execute": "remove-fd", "arguments": { "fdset-id": 1 } }"#,
+            r#"{ "execute": "query-fdsets" }"#,
+            r#"{ "execute": "closefd", "arguments": { "fdname": "fd1" } }"#,
+        ] {
+            assert!(serde_json::from_str::<QmpCommand>(json_msg).is_ok());
+        }
+
+        // missing fdset-id for remove-fd.
+        let json_msg = r#"{ "execute": "remove-fd", "arguments": { "fd": 3 } }"#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        assert!(err_msg.contains("missing field `fdset-id`"));
+
         // right arguments for blockdev-add.
         let json_msg = r#"
         {
@@ -2406,4 +4059,305 @@ mod tests {
         let part_msg = r#"ok"#;
         assert!(err_msg.contains(part_msg));
     }
+
+    #[test]
+    fn test_qmp_blockdev() {
+        let json_msg = r#"{ "execute": "blockdev-del", "arguments": { "node-name": "drive-0" } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::blockdev_del { arguments, .. }) => {
+                assert_eq!(arguments.node_name, "drive-0");
+            }
+            _ => panic!("Failed to parse blockdev-del"),
+        }
+
+        let json_msg = r#"{ "execute": "block_resize", "arguments": { "node-name": "drive-0", "size": 1024 } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::block_resize { arguments, .. }) => {
+                assert!(arguments.device.is_none());
+                assert_eq!(arguments.node_name.as_deref(), Some("drive-0"));
+                assert_eq!(arguments.size, 1024);
+                assert!(!arguments.force);
+            }
+            _ => panic!("Failed to parse block_resize"),
+        }
+
+        let json_msg =
+            r#"{ "execute": "migrate-set-parameters", "arguments": { "max-bandwidth": 1048576 } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::migrate_set_parameters { arguments, .. }) => {
+                assert_eq!(arguments.max_bandwidth, Some(1048576));
+                assert!(arguments.downtime_limit.is_none());
+                assert!(arguments.compress_level.is_none());
+            }
+            _ => panic!("Failed to parse migrate-set-parameters"),
+        }
+
+        let json_msg =
+            r#"{ "execute": "snapshot-load", "arguments": { "filename": "/tmp/snap" } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::snapshot_load { arguments, .. }) => {
+                assert_eq!(arguments.filename, "/tmp/snap");
+            }
+            _ => panic!("Failed to parse snapshot-load"),
+        }
+
+        let json_msg = r#"{ "execute": "query-named-block-nodes" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_named_block_nodes { .. })
+        ));
+
+        let node = BlockNodeInfo {
+            node_name: "drive-0".to_string(),
+            file: "/path/to/block".to_string(),
+            drv: "raw".to_string(),
+            ro: true,
+            direct: false,
+            image: ImageInfo {
+                filename: "/path/to/block".to_string(),
+                format: "raw".to_string(),
+                virtual_size: 0x1000,
+            },
+        };
+        let ret_msg = r#"{"node-name":"drive-0","file":"/path/to/block","drv":"raw","ro":true,"direct":false,"image":{"filename":"/path/to/block","format":"raw","virtual-size":4096}}"#;
+        assert_eq!(serde_json::to_string(&node).unwrap(), ret_msg);
+    }
+
+    #[test]
+    fn test_qmp_query_sysbus() {
+        let json_msg = r#"{ "execute": "query-sysbus" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_sysbus { .. })
+        ));
+
+        let devices = vec![
+            SysBusDeviceInfo {
+                dev_type: "Serial".to_string(),
+                region_base: Some(0x1000_0000),
+                region_size: Some(0x100),
+                irq: Some(1),
+            },
+            SysBusDeviceInfo {
+                dev_type: "Others".to_string(),
+                ..Default::default()
+            },
+        ];
+        let ret_msg = r#"[{"type":"Serial","region_base":268435456,"region_size":256,"irq":1},{"type":"Others","region_base":null,"region_size":null,"irq":null}]"#;
+        assert_eq!(serde_json::to_string(&devices).unwrap(), ret_msg);
+    }
+
+    #[test]
+    fn test_qmp_set_log_level() {
+        let json_msg =
+            r#"{ "execute": "set-log-level", "arguments": { "filter": "virtio=debug,info" } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::set_log_level { arguments, .. }) => {
+                assert_eq!(arguments.filter, "virtio=debug,info");
+            }
+            _ => panic!("Failed to parse set-log-level"),
+        }
+        let json_msg = r#"{ "execute": "set-log-level" }"#;
+        assert!(serde_json::from_str::<QmpCommand>(json_msg).is_err());
+    }
+
+    #[test]
+    fn test_qmp_mmio_trace() {
+        let json_msg = r#"{ "execute": "trace-mmio", "arguments": { "device": "virtio-mmio", "enable": true } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::trace_mmio { arguments, .. }) => {
+                assert_eq!(arguments.device, "virtio-mmio");
+                assert!(arguments.enable);
+            }
+            _ => panic!("Failed to parse trace-mmio"),
+        }
+        let json_msg = r#"{ "execute": "trace-mmio", "arguments": { "device": "virtio-mmio" } }"#;
+        assert!(serde_json::from_str::<QmpCommand>(json_msg).is_err());
+
+        let json_msg = r#"{ "execute": "query-mmio-trace" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_mmio_trace { .. })
+        ));
+
+        let json_msg = r#"{ "execute": "query-rtc" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_rtc { .. })
+        ));
+
+        let json_msg = r#"{ "execute": "screendump", "arguments": { "filename": "/tmp/image" } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::screendump { arguments, .. }) => {
+                assert_eq!(arguments.filename, "/tmp/image");
+                assert!(arguments.format.is_none());
+            }
+            _ => panic!("Failed to parse screendump"),
+        }
+        let json_msg = r#"{ "execute": "dump-guest-memory", "arguments": { "paging": false, "protocol": "file:/tmp/vmcore", "begin": 2147483648, "length": 4096 } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::dump_guest_memory { arguments, .. }) => {
+                assert!(!arguments.paging);
+                assert_eq!(arguments.protocol, "file:/tmp/vmcore");
+                assert_eq!(arguments.begin, Some(0x8000_0000));
+                assert!(arguments.detach.is_none());
+            }
+            _ => panic!("Failed to parse dump-guest-memory"),
+        }
+        let json_msg = r#"{ "execute": "screendump" }"#;
+        assert!(serde_json::from_str::<QmpCommand>(json_msg).is_err());
+        let info = MmioTraceInfo {
+            entries: vec![MmioTraceEntryInfo {
+                timestamp_ns: 1024,
+                dev_type: "virtio-mmio".to_string(),
+                region_base: 0x1000_1000,
+                offset: 0x50,
+                size: 4,
+                value: 0,
+                write: true,
+            }],
+            overflow: 0,
+        };
+        let ret_msg = r#"{"entries":[{"timestamp_ns":1024,"type":"virtio-mmio","region_base":268439552,"offset":80,"size":4,"value":0,"write":true}],"overflow":0}"#;
+        assert_eq!(serde_json::to_string(&info).unwrap(), ret_msg);
+    }
+
+    #[test]
+    fn test_qmp_memory_hotplug() {
+        let json_msg = r#"{ "execute": "object-add", "arguments": { "qom-type": "memory-backend-ram", "id": "mem-0", "size": 134217728 } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::object_add { arguments, .. }) => {
+                assert_eq!(arguments.qom_type, "memory-backend-ram");
+                assert_eq!(arguments.id, "mem-0");
+                assert_eq!(arguments.size, Some(0x800_0000));
+                assert_eq!(arguments.share, None);
+            }
+            _ => panic!("Failed to parse object-add"),
+        }
+        let json_msg = r#"{ "execute": "object-add", "arguments": { "qom-type": "memory-backend-ram", "id": "mem-0", "policy": "bind" } }"#;
+        assert!(serde_json::from_str::<QmpCommand>(json_msg).is_err());
+
+        let json_msg = r#"{ "execute": "device_add", "arguments": { "id": "dimm-0", "driver": "pc-dimm", "memdev": "mem-0" } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::device_add { arguments, .. }) => {
+                assert_eq!(arguments.memdev, Some("mem-0".to_string()));
+            }
+            _ => panic!("Failed to parse device_add"),
+        }
+
+        let json_msg = r#"{ "execute": "device_add", "arguments": { "id": "cpu2", "driver": "host-riscv64-cpu", "core-id": 2 } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::device_add { arguments, .. }) => {
+                assert_eq!(arguments.core_id, Some(2));
+                assert_eq!(arguments.socket_id, None);
+            }
+            _ => panic!("Failed to parse device_add"),
+        }
+
+        let json_msg = r#"{ "execute": "query-memory-size-summary" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_memory_size_summary { .. })
+        ));
+        let summary = MemorySizeSummary {
+            base_memory: 0x4000_0000,
+            plugged_memory: 0x800_0000,
+        };
+        let ret_msg = r#"{"base-memory":1073741824,"plugged-memory":134217728}"#;
+        assert_eq!(serde_json::to_string(&summary).unwrap(), ret_msg);
+    }
+
+    #[test]
+    fn test_qmp_memdev() {
+        let json_msg = r#"{ "execute": "object-add", "arguments": { "qom-type": "memory-backend-memfd", "id": "mem-0", "size": 134217728, "seal": false } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::object_add { arguments, .. }) => {
+                assert_eq!(arguments.qom_type, "memory-backend-memfd");
+                assert_eq!(arguments.seal, Some(false));
+            }
+            _ => panic!("Failed to parse object-add"),
+        }
+        let json_msg = r#"{ "execute": "object-add", "arguments": { "qom-type": "memory-backend-file", "id": "mem-1", "size": 134217728, "mem-path": "/dev/hugepages", "merge": true, "prealloc": true, "host-nodes": [0, 1] } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::object_add { arguments, .. }) => {
+                assert_eq!(arguments.mem_path, Some("/dev/hugepages".to_string()));
+                assert_eq!(arguments.merge, Some(true));
+                assert_eq!(arguments.dump, None);
+                assert_eq!(arguments.prealloc, Some(true));
+                assert_eq!(arguments.host_nodes, Some(vec![0, 1]));
+            }
+            _ => panic!("Failed to parse object-add"),
+        }
+
+        let json_msg = r#"{ "execute": "query-memdev" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_memdev { .. })
+        ));
+        let memdevs = vec![MemdevInfo {
+            id: "mem0".to_string(),
+            qom_type: "memory-backend-memfd".to_string(),
+            size: 0x4000_0000,
+            merge: false,
+            dump: true,
+            prealloc: false,
+            host_nodes: vec![1],
+            share: true,
+            plugged: true,
+        }];
+        let ret_msg = r#"[{"id":"mem0","type":"memory-backend-memfd","size":1073741824,"merge":false,"dump":true,"prealloc":false,"host-nodes":[1],"share":true,"plugged":true}]"#;
+        assert_eq!(serde_json::to_string(&memdevs).unwrap(), ret_msg);
+    }
+
+    #[test]
+    fn test_qmp_netdev() {
+        let json_msg = r#"{ "execute": "netdev_add", "arguments": { "id": "net-1", "type": "fd", "fds": "fd1" } }"#;
+        match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(QmpCommand::netdev_add { arguments, .. }) => {
+                assert_eq!(arguments.net_type, Some("fd".to_string()));
+                assert_eq!(arguments.fds, Some("fd1".to_string()));
+            }
+            _ => panic!("Failed to parse netdev_add"),
+        }
+
+        let json_msg = r#"{ "execute": "query-netdev" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_netdev { .. })
+        ));
+        let netdevs = vec![
+            NetdevInfo {
+                id: "net-0".to_string(),
+                net_type: "tap".to_string(),
+                ifname: Some("tap0".to_string()),
+                fdname: None,
+                device: Some("net0".to_string()),
+            },
+            NetdevInfo {
+                id: "net-1".to_string(),
+                net_type: "fd".to_string(),
+                ifname: None,
+                fdname: Some("fd1".to_string()),
+                device: None,
+            },
+        ];
+        let ret_msg = r#"[{"id":"net-0","type":"tap","ifname":"tap0","device":"net0"},{"id":"net-1","type":"fd","fdname":"fd1"}]"#;
+        assert_eq!(serde_json::to_string(&netdevs).unwrap(), ret_msg);
+    }
+
+    #[test]
+    fn test_qmp_query_kernel() {
+        let json_msg = r#"{ "execute": "query-kernel" }"#;
+        assert!(matches!(
+            serde_json::from_str::<QmpCommand>(json_msg),
+            Ok(QmpCommand::query_kernel { .. })
+        ));
+        let info = KernelInfo {
+            kernel: Some("/path/to/Image".to_string()),
+            initrd: None,
+            cmdline: "console=ttyS0 root=/dev/vda".to_string(),
+        };
+        let ret_msg = r#"{"kernel":"/path/to/Image","cmdline":"console=ttyS0 root=/dev/vda"}"#;
+        assert_eq!(serde_json::to_string(&info).unwrap(), ret_msg);
+    }
 }
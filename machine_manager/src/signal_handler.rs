@@ -12,14 +12,22 @@
 
 use crate::temp_cleaner::TempCleaner;
 use std::io::Write;
+use std::sync::atomic::{AtomicI32, Ordering};
 
 use libc::{c_int, c_void, siginfo_t};
-use util::set_termi_canon_mode;
+use util::{logger, set_termi_canon_mode};
 use vmm_sys_util::signal::register_signal_handler;
 
 pub const VM_EXIT_GENE_ERR: i32 = 1;
+/// Guest shut down reporting a system failure.
+pub const VM_EXIT_GUEST_FAILURE: i32 = 2;
+/// Guest requested reboot, which `-no-reboot` turns into exit.
+pub const VM_EXIT_GUEST_REBOOT: i32 = 3;
 const SYSTEMCALL_OFFSET: isize = 6;
 
+/// Exit code once the main loop is over, which guest shutdown may change.
+static VM_EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+
 fn basic_clean() {
     // clean temporary file
     TempCleaner::clean();
@@ -27,6 +35,14 @@ fn basic_clean() {
     set_termi_canon_mode().expect("Failed to set terminal to canon mode.");
 }
 
+pub fn set_vm_exit_code(code: i32) {
+    VM_EXIT_CODE.store(code, Ordering::SeqCst);
+}
+
+pub fn vm_exit_code() -> i32 {
+    VM_EXIT_CODE.load(Ordering::SeqCst)
+}
+
 pub fn exit_with_code(code: i32) {
     // Safe, because the basic_clean function has been executed before exit.
     unsafe {
@@ -34,6 +50,12 @@ pub fn exit_with_code(code: i32) {
     }
 }
 
+/// Clean up and exit at once, without waiting for the main loop.
+pub fn clean_and_exit(code: i32) {
+    basic_clean();
+    exit_with_code(code);
+}
+
 extern "C" fn handle_signal_kill(num: c_int, _: *mut siginfo_t, _: *mut c_void) {
     basic_clean();
     write!(
@@ -57,6 +79,10 @@ extern "C" fn handle_signal_sys(_: c_int, info: *mut siginfo_t, _: *mut c_void)
     exit_with_code(VM_EXIT_GENE_ERR);
 }
 
+extern "C" fn handle_signal_hup(_: c_int, _: *mut siginfo_t, _: *mut c_void) {
+    logger::request_log_reopen();
+}
+
 /// Register kill signal handler. Signals supported now are SIGTERM and SIGSYS.
 pub fn register_kill_signal() {
     register_signal_handler(libc::SIGTERM, handle_signal_kill)
@@ -66,3 +92,9 @@ pub fn register_kill_signal() {
     register_signal_handler(libc::SIGINT, handle_signal_kill)
         .expect("Register signal handler for SIGINT failed!");
 }
+
+/// Register `SIGHUP` handler which reopens the log file, so that it works with logrotate(8).
+pub fn register_log_reopen_signal() {
+    register_signal_handler(libc::SIGHUP, handle_signal_hup)
+        .expect("Register signal handler for SIGHUP failed!");
+}
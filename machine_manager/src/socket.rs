@@ -10,8 +10,11 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::rc::Rc;
@@ -26,13 +29,44 @@ use util::loop_context::{
 };
 use vmm_sys_util::epoll::EventSet;
 
+use crate::error::MachineManagerError;
 use crate::machine::MachineExternalInterface;
 use crate::qmp::{QmpChannel, QmpGreeting, Response};
 
 const MAX_SOCKET_MSG_LENGTH: usize = 8192;
-pub(crate) const LEAK_BUCKET_LIMIT: u64 = 100;
+/// The default max size in bytes of a command received from a client.
+pub const DEFAULT_MAX_COMMAND_SIZE: usize = 1 << 20;
+/// The default max number of commands of a client waiting or executing.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+/// The default max number of commands a client issues per second.
+pub const DEFAULT_COMMAND_RATE: u64 = 100;
+
+/// Limits on the input of each client connected to a `Socket`, so a broken or
+/// malicious client can't exhaust the memory or cpu of the VMM.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SocketLimits {
+    /// Max size in bytes of a command, the client is reset when exceeding it.
+    pub max_command_size: usize,
+    /// Max number of commands of a client waiting or executing, the commands
+    /// beyond it are refused.
+    pub max_in_flight: usize,
+    /// Max number of commands a client issues per second, the commands beyond
+    /// it are throttled. 0 means unlimited.
+    pub command_rate: u64,
+}
+
+impl Default for SocketLimits {
+    fn default() -> Self {
+        SocketLimits {
+            max_command_size: DEFAULT_MAX_COMMAND_SIZE,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            command_rate: DEFAULT_COMMAND_RATE,
+        }
+    }
+}
 
-/// The wrapper over Unix socket and socket handler.
+/// The wrapper over Unix or Tcp socket and socket handler. Several clients can
+/// connect to the socket at the same time, each one is served by its own stream.
 ///
 /// # Example
 ///
@@ -50,18 +84,22 @@ pub(crate) const LEAK_BUCKET_LIMIT: u64 = 100;
 ///
 ///     let client_stream = UnixStream::connect("/path/to/my/socket")?;
 ///     let server_stream = socket.accept_unix_stream();
-///     socket.bind_unix_stream(server_stream);
+///     let stream_fd = socket.bind_unix_stream(server_stream);
 ///     assert!(socket.is_connected());
+///     socket.drop_stream(stream_fd);
+///     assert!(!socket.is_connected());
 ///     Ok(())
 /// }
 /// ```
 pub struct Socket {
-    /// Type for Socket
-    sock_type: SocketType,
     /// Socket listener tuple
-    listener: UnixListener,
-    /// Socket stream with RwLock
-    stream: RwLock<Option<SocketStream>>,
+    listener: SocketListener,
+    /// Protocol served on the socket
+    mode: MonitorMode,
+    /// Accepted socket streams keyed by their fd, one for each client
+    streams: RwLock<BTreeMap<RawFd, SocketStream>>,
+    /// Limits on the input of each client
+    limits: SocketLimits,
     /// Perform socket command
     performer: Option<Arc<Mutex<dyn MachineExternalInterface>>>,
 }
@@ -76,99 +114,157 @@ impl Socket {
     pub fn from_unix_listener(
         listener: UnixListener,
         performer: Option<Arc<Mutex<dyn MachineExternalInterface>>>,
+    ) -> Self {
+        Self::from_listener(SocketListener::Unix(listener), performer)
+    }
+
+    /// Allocates a new `Socket` with `SocketListener`.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - The `SocketListener` bind to `Socket`.
+    /// * `performer` - The `VM` to perform socket command.
+    pub fn from_listener(
+        listener: SocketListener,
+        performer: Option<Arc<Mutex<dyn MachineExternalInterface>>>,
     ) -> Self {
         Socket {
-            sock_type: SocketType::Unix,
             listener,
-            stream: RwLock::new(None),
+            mode: MonitorMode::Control,
+            streams: RwLock::new(BTreeMap::new()),
+            limits: SocketLimits::default(),
             performer,
         }
     }
 
+    /// Set the protocol served on `Socket`, it's qmp by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - The `MonitorMode` of `Socket`.
+    pub fn with_mode(mut self, mode: MonitorMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Get the protocol served on `Socket`.
+    pub fn get_mode(&self) -> MonitorMode {
+        self.mode
+    }
+
+    /// Set the limits on the input of each client, `SocketLimits::default()`
+    /// is used if not set.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The `SocketLimits` of `Socket`.
+    pub fn with_limits(mut self, limits: SocketLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Get listener's fd from `Socket`.
     pub fn get_listener_fd(&self) -> RawFd {
         self.listener.as_raw_fd()
     }
 
-    /// Accept stream and bind to Socket.
-    pub fn accept(&self) {
-        match self.sock_type {
-            SocketType::Unix => {
+    /// Accept stream and bind to Socket, return the fd of the stream.
+    pub fn accept(&self) -> RawFd {
+        match &self.listener {
+            SocketListener::Unix(_) => {
                 let stream = self.accept_unix_stream();
-                self.bind_unix_stream(stream);
+                self.bind_unix_stream(stream)
+            }
+            SocketListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().unwrap();
+                info!("QMP: accept tcp client {}", addr);
+                self.bind_stream(SocketStream::Tcp(stream))
             }
         }
     }
 
     /// Accept a new incoming connection unix stream from unix listener.
     pub fn accept_unix_stream(&self) -> UnixStream {
-        let (stream, _) = self.listener.accept().unwrap();
-        stream
+        match &self.listener {
+            SocketListener::Unix(listener) => {
+                let (stream, _) = listener.accept().unwrap();
+                stream
+            }
+            SocketListener::Tcp(_) => panic!("Not a unix socket listener!"),
+        }
     }
 
     /// Get socket type from `Socket`.
     pub fn get_socket_type(&self) -> SocketType {
-        self.sock_type
+        match self.listener {
+            SocketListener::Unix(_) => SocketType::Unix,
+            SocketListener::Tcp(_) => SocketType::Tcp,
+        }
     }
 
-    /// Bind `Socket` with a `UnixStream`.
+    /// Bind `Socket` with a `UnixStream`, return the fd of the stream.
     ///
     /// # Arguments
     ///
     /// * `unix_stream` - The `UnixStream` bind to `Socket`.
-    pub fn bind_unix_stream(&self, unix_stream: UnixStream) {
-        let stream = SocketStream::from_unix_stream(unix_stream);
-        *self.stream.write().unwrap() = Some(stream);
-    }
-
-    /// Unbind stream from `Socket`, reset the state.
-    pub fn drop_stream(&self) {
-        *self.stream.write().unwrap() = None;
+    pub fn bind_unix_stream(&self, unix_stream: UnixStream) -> RawFd {
+        self.bind_stream(SocketStream::from_unix_stream(unix_stream))
     }
 
-    /// Confirm whether socket stream bind to `Socket` or not.
-    pub fn is_connected(&self) -> bool {
-        self.stream.read().unwrap().is_some()
+    fn bind_stream(&self, stream: SocketStream) -> RawFd {
+        let stream_fd = stream.as_raw_fd();
+        self.streams.write().unwrap().insert(stream_fd, stream);
+        stream_fd
     }
 
-    /// Get socket fd from `Socket`, it a private function.
-    pub fn get_stream_fd(&self) -> RawFd {
-        if self.is_connected() {
-            self.stream.read().unwrap().as_ref().unwrap().as_raw_fd()
-        } else {
-            panic!("Failed to get socket fd!");
-        }
+    /// Unbind stream `stream_fd` from `Socket` and close it.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The fd of the stream.
+    pub fn drop_stream(&self, stream_fd: RawFd) {
+        self.streams.write().unwrap().remove(&stream_fd);
     }
 
-    /// Get a `SocketHandler` from `Socket`.
-    pub fn get_socket_handler(&self) -> SocketHandler {
-        SocketHandler::new(self.get_stream_fd())
+    /// Confirm whether any socket stream binds to `Socket` or not.
+    pub fn is_connected(&self) -> bool {
+        !self.streams.read().unwrap().is_empty()
     }
 
     /// In qmp feature, send empty or greeting response to client.
     ///
     /// # Arguments
     ///
+    /// * `stream_fd` - The fd of the stream to send response to.
     /// * `is_greeting` - Whether sending greeting response or not.
-    pub fn send_response(&self, is_greeting: bool) -> std::io::Result<()> {
-        if self.is_connected() {
-            let mut handler = self.get_socket_handler();
-            let resp = if is_greeting {
-                serde_json::to_string(&QmpGreeting::create_greeting(1, 0, 5)).unwrap() + "\r"
-            } else {
-                serde_json::to_string(&Response::create_empty_response()).unwrap() + "\r"
-            };
-            handler.send_str(&resp)?;
-            info!("QMP: --> {:?}", resp);
+    pub fn send_response(&self, stream_fd: RawFd, is_greeting: bool) -> std::io::Result<()> {
+        if !self.streams.read().unwrap().contains_key(&stream_fd) {
+            return Ok(());
+        }
+        if self.mode == MonitorMode::Readline {
+            if is_greeting {
+                crate::hmp::send_banner(stream_fd)?;
+            }
+            return Ok(());
         }
+        let mut handler = SocketHandler::new(stream_fd);
+        let resp = if is_greeting {
+            serde_json::to_string(&QmpGreeting::create_greeting(1, 0, 5)).unwrap() + "\r"
+        } else {
+            serde_json::to_string(&Response::create_empty_response()).unwrap() + "\r"
+        };
+        handler.send_str(&resp)?;
+        info!("QMP: --> {:?}", resp);
         Ok(())
     }
 
-    /// Create socket's accepted stream to `event_notifier`.
+    /// Create socket's accepted stream to `event_notifier`. Each stream has its own
+    /// input buffer and flow controller, while in-band commands of all streams are
+    /// executed one after another in the main loop.
     fn create_event_notifier(&mut self, shared_socket: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut notifiers = Vec::new();
 
-        let leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT);
+        let leak_bucket = LeakBucket::new(self.limits.command_rate);
         if let Err(e) = leak_bucket {
             error!("Failed to create leak bucket, {:?}", e);
             return notifiers;
@@ -177,42 +273,61 @@ impl Socket {
         let shared_leak_bucket = leak_bucket.clone();
         let leak_bucket_fd = leak_bucket.lock().unwrap().as_raw_fd();
 
-        self.accept();
-        QmpChannel::bind_writer(SocketRWHandler::new(self.get_stream_fd()));
-        if let Err(e) = self.send_response(true) {
+        let stream_fd = self.accept();
+        // Events are json, they are only sent to qmp clients.
+        if self.mode == MonitorMode::Control {
+            QmpChannel::bind_writer(SocketRWHandler::new(stream_fd));
+        }
+        if let Err(e) = self.send_response(stream_fd, true) {
             error!("{:?}", e);
-            QmpChannel::unbind();
+            QmpChannel::unbind(stream_fd);
+            self.drop_stream(stream_fd);
             return notifiers;
         }
+        // A message may arrive in pieces, the received part is kept by the stream's handler.
+        let stream_handler = Mutex::new(SocketHandler::new(stream_fd).with_limits(self.limits));
         let handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
+            let mut reset = false;
             if event == EventSet::IN {
                 let socket_mutexed = shared_socket.lock().unwrap();
-                let stream_fd = socket_mutexed.get_stream_fd();
-
                 let performer = &socket_mutexed.performer.as_ref().unwrap();
-                if let Err(e) = crate::qmp::handle_qmp(
-                    stream_fd,
-                    performer,
-                    &mut shared_leak_bucket.lock().unwrap(),
-                ) {
+                let leak_bucket = &mut shared_leak_bucket.lock().unwrap();
+                let result = match socket_mutexed.mode {
+                    MonitorMode::Control => {
+                        crate::qmp::handle_qmp(&mut stream_handler.lock().unwrap(), leak_bucket)
+                    }
+                    MonitorMode::Readline => {
+                        crate::hmp::handle_hmp(stream_fd, performer, leak_bucket)
+                    }
+                };
+                if let Err(e) = result {
                     error!("{:?}", e);
+                    // The client sending a too long command is reset, since the
+                    // rest of the command can't be told from the next one.
+                    if let Some(MachineManagerError::MessageTooLong(_)) = e.downcast_ref() {
+                        reset = true;
+                    }
                 }
             }
-            if event & EventSet::HANG_UP == EventSet::HANG_UP {
-                let socket_mutexed = shared_socket.lock().unwrap();
-                let stream_fd = socket_mutexed.get_stream_fd();
-
-                QmpChannel::unbind();
+            // A half-closed tcp client only raises READ_HANG_UP, the stream stays
+            // readable with EOF, so it must be dropped to avoid spinning.
+            if reset
+                || event & EventSet::HANG_UP == EventSet::HANG_UP
+                || event & EventSet::READ_HANG_UP == EventSet::READ_HANG_UP
+            {
+                QmpChannel::unbind(stream_fd);
+                shared_socket.lock().unwrap().drop_stream(stream_fd);
                 Some(gen_delete_notifiers(&[stream_fd, leak_bucket_fd]))
             } else {
                 None
             }
         });
+        // The listener stays monitored to accept other clients.
         let qmp_notifier = EventNotifier::new(
             NotifierOperation::AddShared,
-            self.get_stream_fd(),
-            Some(self.get_listener_fd()),
-            EventSet::IN | EventSet::HANG_UP,
+            stream_fd,
+            None,
+            EventSet::IN | EventSet::HANG_UP | EventSet::READ_HANG_UP,
             vec![handler],
         );
         notifiers.push(qmp_notifier);
@@ -258,21 +373,53 @@ impl EventNotifierHelper for Socket {
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SocketType {
     Unix = 1,
+    Tcp = 2,
 }
 
-/// Wrapper over UnixSteam.
+/// Protocol served on api socket.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MonitorMode {
+    /// Json-based qmp.
+    Control,
+    /// Line-oriented human monitor, see `crate::hmp`.
+    Readline,
+}
+
+/// Listener for api socket.
 #[derive(Debug)]
-struct SocketStream(UnixStream);
+pub enum SocketListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl AsRawFd for SocketListener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            SocketListener::Unix(listener) => listener.as_raw_fd(),
+            SocketListener::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Wrapper over UnixSteam or TcpStream.
+#[derive(Debug)]
+enum SocketStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
 
 impl SocketStream {
     fn from_unix_stream(stream: UnixStream) -> Self {
-        SocketStream(stream)
+        SocketStream::Unix(stream)
     }
 }
 
 impl AsRawFd for SocketStream {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        match self {
+            SocketStream::Unix(stream) => stream.as_raw_fd(),
+            SocketStream::Tcp(stream) => stream.as_raw_fd(),
+        }
     }
 }
 
@@ -324,6 +471,11 @@ impl SocketRWHandler {
         }
     }
 
+    /// Get the socket file descriptor.
+    pub fn get_socket_fd(&self) -> RawFd {
+        self.socket_fd
+    }
+
     /// Get inner buf as a `String`.
     pub fn get_buf_string(&mut self) -> Result<String> {
         if self.buf.len() > MAX_SOCKET_MSG_LENGTH {
@@ -333,12 +485,19 @@ impl SocketRWHandler {
         Ok(String::from_utf8_lossy(&self.buf).trim().to_string())
     }
 
-    /// Get the last file descriptor read from `scm_fd`.
+    /// Take the last file descriptor read from `scm_fd`, the others are
+    /// closed since only one fd can be used by a command.
     pub fn getfd(&mut self) -> Option<RawFd> {
-        if self.scm_fd.is_empty() {
-            None
-        } else {
-            Some(self.scm_fd[self.scm_fd.len() - 1])
+        let fd = self.scm_fd.pop();
+        self.close_scm_fds();
+        fd
+    }
+
+    /// Close the file descriptors received but not taken by `getfd`.
+    fn close_scm_fds(&mut self) {
+        for fd in self.scm_fd.drain(..) {
+            // SAFETY: the fd is received from client and owned by nobody else.
+            unsafe { libc::close(fd) };
         }
     }
 
@@ -354,12 +513,25 @@ impl SocketRWHandler {
     /// # Errors
     /// The socket file descriptor is broken.
     fn read_fd(&mut self) -> std::io::Result<()> {
+        self.read_fd_limited(usize::MAX)
+    }
+
+    /// Receive bytes and scm_fd from socket file descriptor until `limit` bytes
+    /// are kept in the buffer, the bytes left are received next time.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The max number of bytes kept in the buffer.
+    ///
+    /// # Errors
+    /// The socket file descriptor is broken.
+    fn read_fd_limited(&mut self, limit: usize) -> std::io::Result<()> {
         use libc::{
-            c_uint, c_void, cmsghdr, iovec, msghdr, recvmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_SPACE,
-            MSG_DONTWAIT, SCM_RIGHTS, SOL_SOCKET,
+            c_uint, c_void, cmsghdr, iovec, msghdr, recvmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN,
+            CMSG_SPACE, MSG_CMSG_CLOEXEC, MSG_DONTWAIT, SCM_RIGHTS, SOL_SOCKET,
         };
 
-        loop {
+        while self.buf.len() < limit {
             let tmp_buf = [0_u8; 1];
             let mut iov = iovec {
                 iov_base: tmp_buf.as_ptr() as *mut c_void,
@@ -391,7 +563,8 @@ impl SocketRWHandler {
 
             // MSG_DONTWAIT: Enables nonblocking operation, if the operation would block the call
             // fails with the error EAGAIN or EWOULDBLOCK. When this error occurs, break loop
-            let ret = unsafe { recvmsg(self.socket_fd, &mut mhdr, MSG_DONTWAIT) };
+            let ret =
+                unsafe { recvmsg(self.socket_fd, &mut mhdr, MSG_DONTWAIT | MSG_CMSG_CLOEXEC) };
 
             if ret == -1 {
                 let sock_err = Error::last_os_error();
@@ -419,16 +592,14 @@ impl SocketRWHandler {
 
             if let Some(scm) = cmsg_hdr {
                 if scm.cmsg_level == SOL_SOCKET && scm.cmsg_type == SCM_RIGHTS {
-                    let scm_cmsg_header = unsafe {
-                        std::slice::from_raw_parts(
-                            CMSG_DATA(scm),
-                            std::mem::size_of::<[RawFd; 2]>(),
-                        )
-                    };
-                    for fd in scm_cmsg_header.iter() {
-                        if *fd != 0 {
-                            self.scm_fd.push(i32::from(*fd));
-                        }
+                    let data_len = scm.cmsg_len as usize - unsafe { CMSG_LEN(0) } as usize;
+                    let fd_num = data_len / std::mem::size_of::<RawFd>();
+                    // SAFETY: the kernel fills `fd_num` fds in the data of cmsg,
+                    // which may be unaligned.
+                    let fds = unsafe { CMSG_DATA(scm) } as *const RawFd;
+                    for i in 0..fd_num {
+                        self.scm_fd
+                            .push(unsafe { std::ptr::read_unaligned(fds.add(i)) });
                     }
                 }
             };
@@ -489,11 +660,17 @@ impl SocketRWHandler {
     /// Reset `SocketRWHandler` buffer and pos.
     pub fn clear(&mut self) {
         self.buf.clear();
-        self.scm_fd.clear();
+        self.close_scm_fds();
         self.pos = 0;
     }
 }
 
+impl Drop for SocketRWHandler {
+    fn drop(&mut self) {
+        self.close_scm_fds();
+    }
+}
+
 impl Read for SocketRWHandler {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let start = self.pos;
@@ -550,6 +727,10 @@ pub struct SocketHandler {
     stream: SocketRWHandler,
     /// Buffer to leave with read result
     buffer: String,
+    /// Bytes of the incomplete message received by `decode_messages`
+    pending: Vec<u8>,
+    /// Limits on the input of the client
+    limits: SocketLimits,
 }
 
 impl SocketHandler {
@@ -562,13 +743,35 @@ impl SocketHandler {
         SocketHandler {
             stream: SocketRWHandler::new(r),
             buffer: String::new(),
+            pending: Vec::new(),
+            limits: SocketLimits::default(),
         }
     }
 
+    /// Set the limits on the input received by `SocketHandler`.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The `SocketLimits` of the client.
+    pub fn with_limits(mut self, limits: SocketLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Get the limits on the input received by `SocketHandler`.
+    pub fn limits(&self) -> SocketLimits {
+        self.limits
+    }
+
+    /// Get the socket file descriptor.
+    pub fn get_socket_fd(&self) -> RawFd {
+        self.stream.get_socket_fd()
+    }
+
     pub fn get_line(&mut self) -> Result<Option<String>> {
         self.buffer.clear();
         self.stream.clear();
-        self.stream.read_fd().unwrap();
+        self.stream.read_fd()?;
         self.stream.get_buf_string().map(|buffer| {
             self.buffer = buffer;
             if self.stream.pos == 0 {
@@ -608,11 +811,84 @@ impl SocketHandler {
         }
     }
 
+    /// Parse the complete json messages received by `SocketHandler`, regardless
+    /// of what separates them.
+    ///
+    /// # Notes
+    /// The bytes of an incomplete message are kept until the rest of it is
+    /// received, so a client must be served by the same `SocketHandler`. Bytes
+    /// which are not valid json are reported as one error and skipped up to the
+    /// next `{`, so a later message is still decoded. No more than
+    /// `max_command_size` bytes are kept, and json nested too deeply is invalid,
+    /// so the memory taken by a client is bounded.
+    ///
+    /// # Errors
+    /// The socket file descriptor is broken, or an incomplete message reaches
+    /// `max_command_size` bytes. The client can't be served any longer in the
+    /// latter case since the rest of the message is unknown.
+    pub fn decode_messages<D: DeserializeOwned>(
+        &mut self,
+    ) -> Result<(Vec<Result<D>>, Option<RawFd>)> {
+        let max_size = self.limits.max_command_size;
+        self.stream.clear();
+        self.stream
+            .read_fd_limited(max_size.saturating_sub(self.pending.len()))?;
+        self.pending.extend_from_slice(&self.stream.buf);
+
+        let mut messages = Vec::new();
+        let mut start = 0;
+        loop {
+            let data = &self.pending[start..];
+            let mut values =
+                serde_json::Deserializer::from_slice(data).into_iter::<serde_json::Value>();
+            let mut failed_at = None;
+            for value in values.by_ref() {
+                match value {
+                    Ok(value) => messages.push(serde_json::from_value(value).map_err(From::from)),
+                    Err(e) if e.is_eof() => break,
+                    Err(e) => {
+                        failed_at = Some(start + json_error_offset(data, &e));
+                        messages.push(Err(e.into()));
+                        break;
+                    }
+                }
+            }
+            let consumed = start + values.byte_offset();
+            let failed_at = match failed_at {
+                Some(offset) => offset,
+                None => {
+                    start = consumed;
+                    break;
+                }
+            };
+            // Resync on the next object which may begin a valid message.
+            let from = std::cmp::max(failed_at, consumed + 1).min(self.pending.len());
+            match self.pending[from..].iter().position(|b| *b == b'{') {
+                Some(pos) => start = from + pos,
+                None => {
+                    start = self.pending.len();
+                    break;
+                }
+            }
+        }
+        self.pending.drain(..start);
+        if self.pending.iter().all(u8::is_ascii_whitespace) {
+            self.pending.clear();
+        }
+        if self.pending.len() >= max_size {
+            self.pending.clear();
+            self.stream.clear();
+            return Err(MachineManagerError::MessageTooLong(max_size).into());
+        }
+        Ok((messages, self.stream.getfd()))
+    }
+
     /// Discard message from `socket_fd`.
     pub fn discard(&mut self) -> Result<()> {
         self.stream.read_fd()?;
         self.stream.clear();
         self.buffer.clear();
+        self.pending.clear();
         Ok(())
     }
 
@@ -639,16 +915,31 @@ impl SocketHandler {
     }
 }
 
+/// Get the offset in `data` of the byte where parsing json failed.
+fn json_error_offset(data: &[u8], e: &serde_json::Error) -> usize {
+    let line_start = match e.line() {
+        0 | 1 => 0,
+        line => data
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .nth(line - 2)
+            .map_or(0, |(i, _)| i + 1),
+    };
+    line_start + e.column().saturating_sub(1)
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
     use std::os::unix::io::{AsRawFd, RawFd};
     use std::os::unix::net::{UnixListener, UnixStream};
     use std::time::Duration;
 
     use serde::{Deserialize, Serialize};
 
-    use super::{Socket, SocketHandler, SocketRWHandler, SocketType};
+    use super::{Socket, SocketHandler, SocketListener, SocketRWHandler, SocketType};
 
     // Environment Preparation for UnixSocket
     fn prepare_unix_socket_environment(socket_id: &str) -> (UnixListener, UnixStream, UnixStream) {
@@ -821,6 +1112,227 @@ mod tests {
         recover_unix_socket_environment("03");
     }
 
+    #[test]
+    fn test_socket_handler_decode_messages() {
+        // Pre test. Environment Preparation
+        let (_, mut client, server) = prepare_unix_socket_environment("09");
+        let mut handler = SocketHandler::new(server.as_raw_fd());
+
+        // 1.Two messages in one read, without separator
+        let data = r#"{"name": "a", "age": 1, "phones": []}{"name": "b", "age": 2, "phones": []}"#;
+        client.write_all(data.as_bytes()).unwrap();
+        let (messages, fd) = handler.decode_messages::<JsonTestStruct>().unwrap();
+        assert!(fd.is_none());
+        let names: Vec<String> = messages.into_iter().map(|m| m.unwrap().name).collect();
+        assert_eq!(names, vec!["a".to_string(), "b".to_string()]);
+
+        // 2.A message split in two reads is kept until it's complete
+        client.write_all(br#"{"name": "c", "#).unwrap();
+        let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+        assert!(messages.is_empty());
+        client.write_all(b"\"age\": 3, \"phones\": []}\n").unwrap();
+        let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages.into_iter().next().unwrap().unwrap().age, 3);
+
+        // 3.A valid json which is not the type fails alone
+        client
+            .write_all(br#"{"age": 4} {"name": "d", "age": 5, "phones": []}"#)
+            .unwrap();
+        let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].is_err());
+        assert!(messages[1].is_ok());
+
+        // 4.Malformed json is dropped
+        client.write_all(b"{]").unwrap();
+        let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].is_err());
+        assert!(handler.pending.is_empty());
+
+        // After test. Environment Recover
+        recover_unix_socket_environment("09");
+    }
+
+    #[test]
+    fn test_socket_handler_limits() {
+        use super::{SocketLimits, DEFAULT_MAX_COMMAND_SIZE};
+        use crate::error::MachineManagerError;
+
+        // Pre test. Environment Preparation
+        let (_, mut client, server) = prepare_unix_socket_environment("11");
+        let limits = SocketLimits {
+            max_command_size: 64,
+            ..Default::default()
+        };
+        let mut handler = SocketHandler::new(server.as_raw_fd()).with_limits(limits);
+        assert_eq!(
+            SocketHandler::new(server.as_raw_fd())
+                .limits()
+                .max_command_size,
+            DEFAULT_MAX_COMMAND_SIZE
+        );
+
+        // 1.Messages exceeding the limit together are received in several reads
+        let data = r#"{"name": "a", "age": 1, "phones": []}"#.repeat(3);
+        client.write_all(data.as_bytes()).unwrap();
+        let mut count = 0;
+        while count < 3 {
+            let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+            assert!(!messages.is_empty());
+            count += messages.into_iter().filter(|m| m.is_ok()).count();
+        }
+        assert!(handler.pending.is_empty());
+
+        // 2.An incomplete message reaching the limit fails, without reading the rest
+        let data = format!(
+            r#"{{"name": "{}", "age": 1, "phones": []}}"#,
+            "b".repeat(100)
+        );
+        client.write_all(data.as_bytes()).unwrap();
+        let err = handler.decode_messages::<JsonTestStruct>().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MachineManagerError>(),
+            Some(MachineManagerError::MessageTooLong(64))
+        ));
+        assert!(handler.pending.is_empty());
+
+        // After test. Environment Recover
+        recover_unix_socket_environment("11");
+    }
+
+    #[test]
+    fn test_socket_handler_fuzz() {
+        use super::SocketLimits;
+        use crate::error::MachineManagerError;
+
+        // Pre test. Environment Preparation
+        const MAX_SIZE: usize = 1024;
+        let (_, mut client, server) = prepare_unix_socket_environment("12");
+        let limits = SocketLimits {
+            max_command_size: MAX_SIZE,
+            ..Default::default()
+        };
+        let mut handler = SocketHandler::new(server.as_raw_fd()).with_limits(limits);
+        let message = r#"{"name": "a", "age": 1, "phones": ["1", "2"]}"#;
+
+        // 1.Truncated json: a message received byte by byte is decoded once complete
+        for (i, byte) in message.bytes().enumerate() {
+            client.write_all(&[byte]).unwrap();
+            let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+            if i + 1 < message.len() {
+                assert!(messages.is_empty());
+            } else {
+                assert_eq!(messages.len(), 1);
+                assert!(messages[0].is_ok());
+            }
+        }
+
+        // 2.Garbage bytes between messages are reported once and skipped, and
+        // garbage left at the end doesn't swallow the next message
+        for garbage in [&b"\x00\xff"[..], b"}}]]", b"nul", b"\"\n\"", b"{:}"] {
+            let mut data = message.as_bytes().to_vec();
+            data.extend_from_slice(garbage);
+            data.extend_from_slice(message.as_bytes());
+            client.write_all(&data).unwrap();
+            let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+            assert_eq!(messages.len(), 3);
+            assert!(messages[0].is_ok());
+            assert!(messages[1].is_err());
+            assert!(messages[2].is_ok());
+            assert!(handler.pending.is_empty());
+
+            let mut data = message.as_bytes().to_vec();
+            data.extend_from_slice(garbage);
+            client.write_all(&data).unwrap();
+            let (mut messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+            client.write_all(message.as_bytes()).unwrap();
+            messages.extend(handler.decode_messages::<JsonTestStruct>().unwrap().0);
+            assert_eq!(messages.len(), 3);
+            assert!(messages[0].is_ok());
+            assert!(messages[1].is_err());
+            assert!(messages[2].is_ok());
+            assert!(handler.pending.is_empty());
+        }
+
+        // 3.Deep nesting fails with the recursion limit, whether complete or not
+        let nested = format!("{}{}", "[".repeat(200), "]".repeat(200));
+        client.write_all(nested.as_bytes()).unwrap();
+        let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].is_err());
+        assert!(handler.pending.is_empty());
+        client.write_all("{\"a\":".repeat(200).as_bytes()).unwrap();
+        let (messages, _) = handler.decode_messages::<JsonTestStruct>().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].is_err());
+
+        // 4.Random pieces of json never make the bytes kept exceed the limit
+        let tokens: [&[u8]; 12] = [
+            b"{",
+            b"}",
+            b"[",
+            b"]",
+            b"\"",
+            b":",
+            b",",
+            b"1",
+            b"name",
+            b" ",
+            b"\xfe",
+            message.as_bytes(),
+        ];
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            client.write_all(tokens[(seed % 12) as usize]).unwrap();
+            match handler.decode_messages::<JsonTestStruct>() {
+                Ok(_) => assert!(handler.pending.len() < MAX_SIZE),
+                Err(e) => {
+                    assert!(matches!(
+                        e.downcast_ref::<MachineManagerError>(),
+                        Some(MachineManagerError::MessageTooLong(MAX_SIZE))
+                    ));
+                    // The client is reset by the socket.
+                    handler = SocketHandler::new(server.as_raw_fd()).with_limits(limits);
+                }
+            }
+        }
+
+        // After test. Environment Recover
+        recover_unix_socket_environment("12");
+    }
+
+    #[test]
+    fn test_socket_handler_scm_fds() {
+        use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+        // Pre test. Environment Preparation
+        let (_, client, server) = prepare_unix_socket_environment("08");
+        let mut handler = SocketHandler::new(server.as_raw_fd());
+        let null = std::fs::File::open("/dev/null").unwrap();
+        let zero = std::fs::File::open("/dev/zero").unwrap();
+
+        // Only the last fd passed along with msg is taken, the others are closed.
+        let data = r#"{"name": "fd", "age": 2, "phones": []}"#;
+        client
+            .send_with_fds(&[data.as_bytes()], &[null.as_raw_fd(), zero.as_raw_fd()])
+            .unwrap();
+        let fd = match handler.decode_line::<JsonTestStruct>() {
+            (Ok(Some(_)), Some(fd)) => fd,
+            _ => panic!("Failed to receive fd!"),
+        };
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", fd)).unwrap();
+        assert_eq!(path, std::path::PathBuf::from("/dev/zero"));
+        unsafe { libc::close(fd) };
+
+        // After test. Environment Recover
+        recover_unix_socket_environment("08");
+    }
+
     #[test]
     fn test_socket_lifecycle() {
         // Pre test. Environment Preparation
@@ -832,21 +1344,44 @@ mod tests {
         assert_eq!(socket.is_connected(), false);
 
         // 2.Connected
-        socket.bind_unix_stream(server);
+        let stream_fd = socket.bind_unix_stream(server);
         assert_eq!(socket.is_connected(), true);
         assert_eq!(socket.get_socket_type(), SocketType::Unix);
 
         // 3.Unbind SocketStream, reset state
-        socket.drop_stream();
+        socket.drop_stream(stream_fd);
         assert_eq!(socket.is_connected(), false);
 
-        // 4.Accept and reconnect a new UnixStream
+        // 4.Accept and reconnect new UnixStreams, which are bound at the same time
         let _new_client = UnixStream::connect("test_04.sock");
-        let new_server = socket.accept_unix_stream();
-        socket.bind_unix_stream(new_server);
+        let _another_client = UnixStream::connect("test_04.sock");
+        let first_fd = socket.accept();
+        let second_fd = socket.accept();
+        assert_ne!(first_fd, second_fd);
+        socket.drop_stream(first_fd);
         assert_eq!(socket.is_connected(), true);
+        socket.drop_stream(second_fd);
+        assert_eq!(socket.is_connected(), false);
 
         // After test. Environment Recover
         recover_unix_socket_environment("04");
     }
+    #[test]
+    fn test_tcp_socket_lifecycle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = Socket::from_listener(SocketListener::Tcp(listener), None);
+        assert_eq!(socket.get_socket_type(), SocketType::Tcp);
+        assert_eq!(socket.is_connected(), false);
+
+        // Clients are accepted one after another.
+        for _ in 0..2 {
+            let client = TcpStream::connect(addr).unwrap();
+            let stream_fd = socket.accept();
+            assert_eq!(socket.is_connected(), true);
+            assert!(socket_basic_rw(client.as_raw_fd(), stream_fd));
+            socket.drop_stream(stream_fd);
+            assert_eq!(socket.is_connected(), false);
+        }
+    }
 }
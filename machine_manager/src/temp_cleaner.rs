@@ -10,59 +10,267 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::fs;
+use std::cell::Cell;
+use std::fs::{self, File};
 use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
 
 static mut GLOBAL_TEMP_CLEANER: Option<TempCleaner> = None;
 
+thread_local! {
+    /// Whether `TempCleaner::clean` is running in this thread.
+    static CLEANING: Cell<bool> = Cell::new(false);
+}
+
+/// Action registered by `TempCleaner::add_cleanup`.
+pub type CleanupFn = Box<dyn FnOnce() + Send>;
+
 /// This structure used to keep temporary file which was created by program, and would be deleted
-/// when Vm exit.
+/// when Vm exit. Cleanup actions, e.g. deleting tap interfaces, are kept along with them.
 pub struct TempCleaner {
-    /// Path of files that should be removed after exiting the vm.
-    paths: Vec<String>,
+    /// Files to be removed and actions to be taken after exiting the vm, in
+    /// registration order.
+    items: Vec<TempItem>,
+}
+
+enum TempItem {
+    Path(TempPath),
+    Cleanup(String, CleanupFn),
+}
+
+impl TempItem {
+    fn run(self) {
+        match self {
+            TempItem::Path(path) => path.remove(),
+            TempItem::Cleanup(id, cleanup) => {
+                // The panic is reported by panic hook, the rest are cleaned anyway.
+                if catch_unwind(AssertUnwindSafe(cleanup)).is_err() {
+                    write!(
+                        &mut std::io::stderr(),
+                        "Cleanup {} panicked, continue with the others.\r\n",
+                        id
+                    )
+                    .expect("Failed to write to stderr");
+                }
+            }
+        }
+    }
+}
+
+/// A file to be removed, with its opened file if it's locked by the process.
+struct TempPath {
+    path: String,
+    lock: Option<File>,
+}
+
+impl TempPath {
+    /// Whether the file can be removed. A locked file is removed only if it's still
+    /// the one locked by the process, not a file recreated at the same path by
+    /// another instance.
+    fn owned(&self) -> bool {
+        let lock = match self.lock.as_ref() {
+            Some(lock) => lock,
+            None => return true,
+        };
+        match (lock.metadata(), fs::metadata(&self.path)) {
+            (Ok(locked), Ok(current)) => {
+                locked.dev() == current.dev() && locked.ino() == current.ino()
+            }
+            _ => false,
+        }
+    }
+
+    fn remove(self) {
+        let path = &self.path;
+        if !self.owned() {
+            write!(
+                &mut std::io::stderr(),
+                "Skip deleting file: {}, it's not locked by this process.\r\n",
+                path
+            )
+            .expect("Failed to write to stderr");
+            return;
+        }
+        if let Err(ref e) = fs::remove_file(path) {
+            write!(
+                &mut std::io::stderr(),
+                "Failed to delete console / socket file:{} :{} \r\n",
+                path,
+                e
+            )
+            .expect("Failed to write to stderr");
+        } else {
+            write!(
+                &mut std::io::stdout(),
+                "Delete file: {} successfully.\r\n",
+                path
+            )
+            .expect("Failed to write to stdout");
+        }
+    }
+}
+
+/// Resolve a relative path against the current directory, as it may be changed
+/// later, e.g. by daemonize.
+fn absolute_path(path: String) -> String {
+    match std::env::current_dir() {
+        Ok(dir) if Path::new(&path).is_relative() => dir.join(&path).to_string_lossy().into_owned(),
+        _ => path,
+    }
+}
+
+/// Run the items taken by `next` one by one, until it returns `None`.
+fn run_items<F: FnMut() -> Option<TempItem>>(mut next: F) {
+    while let Some(item) = next() {
+        item.run();
+    }
 }
 
 impl TempCleaner {
     pub fn object_init() {
         unsafe {
             if GLOBAL_TEMP_CLEANER.is_none() {
-                GLOBAL_TEMP_CLEANER = Some(TempCleaner { paths: Vec::new() });
+                GLOBAL_TEMP_CLEANER = Some(TempCleaner { items: Vec::new() });
             }
         }
     }
 
-    /// Add to be removed file path
+    /// Add to be removed file path, a relative path is resolved against the current
+    /// directory as it may be changed later, e.g. by daemonize.
     pub fn add_path(path: String) {
+        Self::push(TempItem::Path(TempPath {
+            path: absolute_path(path),
+            lock: None,
+        }));
+    }
+
+    /// Add to be removed file path which is locked by `lock`, e.g. pidfile. The
+    /// lock is held until the file is removed, and it's not removed unless the
+    /// file at `path` is still the locked one.
+    pub fn add_locked_path(path: String, lock: File) {
+        Self::push(TempItem::Path(TempPath {
+            path: absolute_path(path),
+            lock: Some(lock),
+        }));
+    }
+
+    /// Add cleanup action `cleanup` named `id`, which is taken when vm exits.
+    /// Files and actions are cleaned in reverse registration order, so a resource
+    /// is torn down before those it depends on.
+    pub fn add_cleanup(id: &str, cleanup: CleanupFn) {
+        Self::push(TempItem::Cleanup(id.to_string(), cleanup));
+    }
+
+    /// Remove the cleanup actions named `id` without taking them, as the resource
+    /// is already torn down. Returns whether any is removed.
+    pub fn remove(id: &str) -> bool {
+        unsafe {
+            match GLOBAL_TEMP_CLEANER.as_mut() {
+                Some(tmp) => tmp.remove_cleanup(id),
+                None => false,
+            }
+        }
+    }
+
+    fn push(item: TempItem) {
         unsafe {
             if let Some(tmp) = GLOBAL_TEMP_CLEANER.as_mut() {
-                tmp.paths.push(path);
+                tmp.items.push(item);
             }
         }
     }
 
-    /// Clean the temporary files
-    pub fn clean() {
+    fn remove_cleanup(&mut self, id: &str) -> bool {
+        let len = self.items.len();
+        self.items
+            .retain(|item| !matches!(item, TempItem::Cleanup(item_id, _) if item_id == id));
+        self.items.len() != len
+    }
+
+    /// Paths of the files to be removed.
+    pub fn paths() -> Vec<String> {
+        unsafe {
+            GLOBAL_TEMP_CLEANER
+                .as_ref()
+                .map(|tmp| {
+                    tmp.items
+                        .iter()
+                        .filter_map(|item| match item {
+                            TempItem::Path(tmp_path) => Some(tmp_path.path.clone()),
+                            TempItem::Cleanup(..) => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+    }
+
+    /// Resolve the paths inside `root` once the process changed its root
+    /// directory to it, paths out of it are kept as they are.
+    pub fn change_root(root: &str) {
         unsafe {
             if let Some(tmp) = GLOBAL_TEMP_CLEANER.as_mut() {
-                while let Some(path) = tmp.paths.pop() {
-                    if let Err(ref e) = fs::remove_file(&path) {
-                        write!(
-                            &mut std::io::stderr(),
-                            "Failed to delete console / socket file:{} :{} \r\n",
-                            &path,
-                            e
-                        )
-                        .expect("Failed to write to stderr");
-                    } else {
-                        write!(
-                            &mut std::io::stdout(),
-                            "Delete file: {} successfully.\r\n",
-                            &path
-                        )
-                        .expect("Failed to write to stdout");
+                for item in tmp.items.iter_mut() {
+                    if let TempItem::Path(tmp_path) = item {
+                        if let Ok(inner) = Path::new(tmp_path.path.as_str()).strip_prefix(root) {
+                            tmp_path.path =
+                                Path::new("/").join(inner).to_string_lossy().into_owned();
+                        }
                     }
                 }
             }
         }
     }
+
+    /// Whether `clean` is running in the current thread, i.e. a panic comes from
+    /// a cleanup action, which `clean` recovers from.
+    pub fn is_cleaning() -> bool {
+        CLEANING.with(|cleaning| cleaning.get())
+    }
+
+    /// Clean the temporary files and take the cleanup actions, in reverse
+    /// registration order. A panicking action doesn't stop the others, and the
+    /// call made while cleaning, e.g. by panic hook, returns at once.
+    pub fn clean() {
+        if CLEANING.with(|cleaning| cleaning.replace(true)) {
+            return;
+        }
+        // Each item is taken out before it runs, so an action can register or
+        // remove others.
+        run_items(|| unsafe { GLOBAL_TEMP_CLEANER.as_mut().and_then(|tmp| tmp.items.pop()) });
+        CLEANING.with(|cleaning| cleaning.set(false));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn test_cleanup_order() {
+        let mut cleaner = TempCleaner { items: Vec::new() };
+        let taken = Arc::new(Mutex::new(Vec::new()));
+        for id in ["a", "b", "c", "d"] {
+            let taken = taken.clone();
+            let cleanup: CleanupFn = Box::new(move || {
+                if id == "c" {
+                    panic!("cleanup c fails");
+                }
+                taken.lock().unwrap().push(id);
+            });
+            cleaner
+                .items
+                .push(TempItem::Cleanup(id.to_string(), cleanup));
+        }
+
+        assert!(cleaner.remove_cleanup("b"));
+        assert!(!cleaner.remove_cleanup("b"));
+        run_items(|| cleaner.items.pop());
+        // Reverse order, "c" panics and "b" is already removed.
+        assert_eq!(*taken.lock().unwrap(), vec!["d", "a"]);
+    }
 }
@@ -230,6 +230,20 @@ fn handle_test_cmd(stream_fd: RawFd, controller: &Arc<Mutex<dyn MachineTestInter
                 false => handler.send_str("OK FALSE".to_string().as_str()).unwrap(),
             }
         }
+        #[cfg(target_arch = "riscv64")]
+        "boot_regs" => {
+            assert!(cmd.len() == 2);
+            let cpu_index = cmd[1].parse::<usize>().unwrap();
+            match controller.lock().unwrap().boot_regs(cpu_index) {
+                Some(regs) => {
+                    let regs: Vec<String> = regs.iter().map(|reg| format!("0x{:x}", reg)).collect();
+                    handler
+                        .send_str(format!("OK {}", regs.join(" ")).as_str())
+                        .unwrap();
+                }
+                None => handler.send_str("FAIL").unwrap(),
+            }
+        }
         _ => {
             handler
                 .send_str(format!("Unsupported command: {}", cmd[0]).as_str())
@@ -147,6 +147,7 @@ pub fn query_migrate() -> Response {
     let status_str = MigrationManager::status().to_string();
     let migration_info = qmp_schema::MigrationInfo {
         status: Some(status_str),
+        ..Default::default()
     };
 
     Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
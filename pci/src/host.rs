@@ -24,7 +24,7 @@ use acpi::{AmlIoDecode, AmlIoResource};
 use acpi::{AmlOne, AmlQWordDesc};
 use address_space::{AddressSpace, GuestAddress, RegionOps};
 use anyhow::Context;
-use sysbus::SysBusDevOps;
+use sysbus::{AccessResult, SysBusDevOps};
 
 use crate::{bus::PciBus, PciDevOps};
 #[cfg(target_arch = "x86_64")]
@@ -120,10 +120,14 @@ impl PciHost {
     pub fn build_mmconfig_ops(host_bridge: Arc<Mutex<Self>>) -> RegionOps {
         let cloned_hb = host_bridge.clone();
         let read = move |data: &mut [u8], addr: GuestAddress, offset: u64| -> bool {
-            cloned_hb.lock().unwrap().read(data, addr, offset)
+            cloned_hb.lock().unwrap().read(data, addr, offset).is_ok()
         };
         let write = move |data: &[u8], addr: GuestAddress, offset: u64| {
-            host_bridge.lock().unwrap().write(data, addr, offset)
+            host_bridge
+                .lock()
+                .unwrap()
+                .write(data, addr, offset)
+                .is_ok()
         };
         RegionOps {
             read: Arc::new(read),
@@ -220,7 +224,7 @@ impl PciHost {
 }
 
 impl SysBusDevOps for PciHost {
-    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
         let bus_num = ((offset as u32 >> ECAM_BUS_SHIFT) & CONFIG_BUS_MASK) as u8;
         let devfn = ((offset as u32 >> ECAM_DEVFN_SHIFT) & CONFIG_DEVFN_MASK) as u8;
         match self.find_device(bus_num, devfn) {
@@ -234,19 +238,19 @@ impl SysBusDevOps for PciHost {
                 }
             }
         }
-        true
+        AccessResult::Ok
     }
 
-    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
         let bus_num = ((offset as u32 >> ECAM_BUS_SHIFT) & CONFIG_BUS_MASK) as u8;
         let devfn = ((offset as u32 >> ECAM_DEVFN_SHIFT) & CONFIG_DEVFN_MASK) as u8;
         match self.find_device(bus_num, devfn) {
             Some(dev) => {
                 let addr: usize = (offset & ECAM_OFFSET_MASK) as usize;
                 dev.lock().unwrap().write_config(addr, data);
-                true
+                AccessResult::Ok
             }
-            None => true,
+            None => AccessResult::Ok,
         }
     }
 
@@ -17,28 +17,42 @@
 // See the Mulan PSL v2 for more details.
 
 use std::io::Write;
-use std::os::unix::fs::OpenOptionsExt;
 use std::sync::{Arc, Mutex};
 use std::path::Path;
 use std::fs::File;
+use std::time::Instant;
 
 use anyhow::{bail, Context, Result};
 use log::{error, info};
 use machine::{LightMachine, MachineOps};
 use machine_manager::{
-    cmdline::{check_api_channel, create_args_parser, create_vmconfig},
+    cmdline::{
+        bios_setting, check_api_channel, create_args_parser, create_vmconfig, log_filter_setting,
+        log_setting, merge_config_file, qmp_setting,
+    },
     config::MachineType,
-    config::VmConfig,
+    config::{parse_log_config, LogConfig, SandboxConfig, VmConfig},
     event_loop::EventLoop,
-    qmp::QmpChannel,
-    signal_handler::{exit_with_code, register_kill_signal, VM_EXIT_GENE_ERR},
-    socket::Socket,
+    qmp::{QmpChannel, QmpDispatcher},
+    signal_handler::{
+        exit_with_code, register_kill_signal, register_log_reopen_signal, vm_exit_code,
+        VM_EXIT_GENE_ERR,
+    },
+    socket::{MonitorMode, Socket},
     temp_cleaner::TempCleaner,
     test_server::TestSock,
 };
+use util::boot_time::{boot_time_summary, enable_boot_time, record_boot_milestone, BootMilestone};
 use util::loop_context::EventNotifierHelper;
+use util::privilege;
+use util::seccomp::{enable_sandbox, install_thread_filter, Sandbox, SeccompAction, SeccompThread};
 use util::test_helper::{is_test_enabled, set_test_enabled};
-use util::{arg_parser, daemonize::daemonize, logger, set_termi_canon_mode};
+use util::{
+    arg_parser,
+    daemonize::{daemon_failed, daemon_ready, daemonize},
+    logger::{self, LogBackend},
+    set_termi_canon_mode,
+};
 
 use thiserror::Error;
 
@@ -90,33 +104,43 @@ fn main() {
             write!(&mut ::std::io::stderr(), "{}", format!("{:?}\r\n", e))
                 .expect("Error writing to stderr");
 
-            1
+            VM_EXIT_GENE_ERR
         }
     });
 }
 
-fn run() -> Result<()> {
-    let cmd_args = create_args_parser().get_matches()?;
+fn run() -> Result<i32> {
+    let start = Instant::now();
+    let mut cmd_args = create_args_parser().get_matches()?;
+    merge_config_file(&mut cmd_args)?;
+    if cmd_args.is_present("boottime") {
+        enable_boot_time(start);
+    }
 
     if cmd_args.is_present("mod-test") {
         set_test_enabled();
     }
 
-    if let Some(logfile_path) = cmd_args.value_of("display log") {
-        if logfile_path.is_empty() {
-            logger::init_logger_with_env(Some(Box::new(std::io::stdout())))
-                .with_context(|| "Failed to init logger.")?;
-        } else {
-            let logfile = std::fs::OpenOptions::new()
-                .read(false)
-                .write(true)
-                .append(true)
-                .create(true)
-                .mode(0o640)
-                .open(logfile_path)
-                .with_context(|| "Failed to open log file")?;
-            logger::init_logger_with_env(Some(Box::new(logfile)))
-                .with_context(|| "Failed to init logger.")?;
+    let log = log_setting(&cmd_args);
+    let log_config = log.value.as_deref().map(parse_log_config).transpose()?;
+    let log_filter = log_filter_setting(&cmd_args);
+    if let Some(log_config) = &log_config {
+        let backend = match log_config {
+            LogConfig::Stdout => LogBackend::Stdout,
+            LogConfig::File(file) => LogBackend::File(logger::LogFile::open(
+                &file.path,
+                file.rotate_size,
+                file.rotate_keep,
+            )?),
+            LogConfig::Syslog(ident) => LogBackend::Syslog {
+                ident: ident.clone(),
+                vm_id: cmd_args.value_of("name").unwrap_or_default(),
+            },
+        };
+        logger::init_logger_with_backend(log_filter.value.as_deref(), backend)
+            .with_context(|| "Failed to init logger.")?;
+        if let LogConfig::File(_) = log_config {
+            register_log_reopen_signal();
         }
     }
 
@@ -130,25 +154,49 @@ fn run() -> Result<()> {
         } else {
             error!("Panic at [{}: {}].", panic_file, panic_line);
         }
+        // A panicking cleanup action is recovered by the ongoing clean, which
+        // goes on with the others and exits by itself.
+        if TempCleaner::is_cleaning() {
+            return;
+        }
 
+        machine::panic_teardown();
         // clean temporary file
         TempCleaner::clean();
         exit_with_code(VM_EXIT_GENE_ERR);
     }));
 
     let mut vm_config: VmConfig = create_vmconfig(&cmd_args)?;
+    if cmd_args.is_present("print-config") {
+        println!("{}", vm_config.to_json()?);
+        return Ok(0);
+    }
+    info!(
+        "Startup settings: bios {}, log {}, qmp {}",
+        bios_setting(&cmd_args),
+        log,
+        qmp_setting(&cmd_args)
+    );
     info!("VmConfig is {:?}", vm_config);
+    record_boot_milestone(BootMilestone::ConfigParsed);
 
-    match real_main(&cmd_args, &mut vm_config) {
+    let log_path = match &log_config {
+        Some(LogConfig::File(file)) => Some(file.path.as_str()),
+        _ => None,
+    };
+    match real_main(&cmd_args, &mut vm_config, log_path) {
         Ok(()) => {
             info!("MainLoop over, Vm exit");
+            if let Some(summary) = boot_time_summary() {
+                write!(&mut std::io::stderr(), "{}", summary).expect("Failed to write to stderr");
+            }
             // clean temporary file
             TempCleaner::clean();
         }
         Err(ref e) => {
             println!("exit at real_main err");
             set_termi_canon_mode().expect("Failed to set terminal to canonical mode.");
-            if cmd_args.is_present("display log") {
+            if log_config.is_some() {
                 error!("{}", format!("{:?}", e));
             } else {
                 write!(&mut std::io::stderr(), "{}", format!("{:?}", e))
@@ -156,21 +204,94 @@ fn run() -> Result<()> {
             }
             // clean temporary file
             TempCleaner::clean();
+            // The launching process exits once it gets the error, after the cleanup.
+            daemon_failed(&format!("{:?}", e));
             exit_with_code(VM_EXIT_GENE_ERR);
         }
     }
 
+    // Main loop is over by host `quit` or guest shutdown, which exit with 0
+    // unless guest reports a failure.
+    Ok(vm_exit_code())
+}
+
+/// Confine threads once devices are realized. Vcpus created afterwards install
+/// their own filters when they start.
+fn install_sandbox(config: &SandboxConfig, log_file: bool) -> Result<()> {
+    let action = if config.debug {
+        SeccompAction::Log
+    } else {
+        SeccompAction::Kill
+    };
+    enable_sandbox(Sandbox {
+        action,
+        deny_obsolete: config.deny_obsolete,
+        log_file,
+    })?;
+    EventLoop::install_seccomp_filters();
+    install_thread_filter(SeccompThread::Main)
+}
+
+/// Change root directory and switch to the user given by `-chroot` and `-runas`
+/// once devices are realized. Files kept in use afterwards are checked first,
+/// as they can't be fixed up after the drop.
+fn drop_privileges(log_file: Option<&str>, vm_config: &VmConfig) -> Result<()> {
+    let chroot = vm_config.chroot.as_deref();
+    let runas = vm_config.runas.as_ref();
+    if chroot.is_none() && runas.is_none() {
+        return Ok(());
+    }
+
+    if let (Some(runas), Some(logfile_path)) = (runas, log_file) {
+        if !logfile_path.is_empty() && !runas.can_write(Path::new(logfile_path))? {
+            bail!(
+                "Log file {} won't be writable by {} after dropping privileges",
+                logfile_path,
+                runas
+            );
+        }
+    }
+    // Sockets and pidfile are removed when vm exits.
+    for path in TempCleaner::paths() {
+        let path = Path::new(&path);
+        let abs_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if let Some(root) = chroot.filter(|root| !abs_path.starts_with(root)) {
+            bail!(
+                "{} is out of chroot {}, it couldn't be removed after changing root",
+                path.display(),
+                root
+            );
+        }
+        let dir = abs_path.parent().unwrap_or_else(|| Path::new("/"));
+        if let Some(runas) = runas.filter(|runas| !runas.can_write(dir).unwrap_or(false)) {
+            bail!(
+                "Directory of {} won't be writable by {} after dropping privileges, it couldn't be removed",
+                path.display(),
+                runas
+            );
+        }
+    }
+
+    privilege::drop_privileges(chroot, runas)?;
+    if let Some(root) = chroot {
+        TempCleaner::change_root(root);
+    }
     Ok(())
 }
 
-fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Result<()> {
+fn real_main(
+    cmd_args: &arg_parser::ArgMatches,
+    vm_config: &mut VmConfig,
+    log_file: Option<&str>,
+) -> Result<()> {
     TempCleaner::object_init();
 
     if cmd_args.is_present("daemonize") {
-        match daemonize(cmd_args.value_of("pidfile")) {
-            Ok(()) => {
-                if let Some(pidfile) = cmd_args.value_of("pidfile") {
-                    TempCleaner::add_path(pidfile);
+        match daemonize(cmd_args.value_of("pidfile"), log_file) {
+            Ok(pid_file) => {
+                // The pidfile is kept locked until it's removed when vm exits.
+                if let (Some(path), Some(lock)) = (cmd_args.value_of("pidfile"), pid_file) {
+                    TempCleaner::add_locked_path(path, lock);
                 }
                 info!("Daemonize mode start!");
             }
@@ -186,6 +307,7 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
 
     let listeners = check_api_channel(cmd_args, vm_config)?;
     let mut sockets = Vec::new();
+    let mut dispatcher = None;
     let vm: Arc<Mutex<dyn MachineOps + Send + Sync>> = match vm_config.machine_config.mach_type {
         MachineType::MicroVm => {
             let vm = Arc::new(Mutex::new(
@@ -222,9 +344,14 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
                 .with_context(|| "Failed to add test socket to MainLoop")?;
             }
 
-            for listener in listeners {
-                sockets.push(Socket::from_unix_listener(listener, Some(vm.clone())));
+            for (listener, mode, limits) in listeners {
+                sockets.push(
+                    Socket::from_listener(listener, Some(vm.clone()))
+                        .with_mode(mode)
+                        .with_limits(limits),
+                );
             }
+            dispatcher = Some(QmpDispatcher::new(vm.clone()));
             vm
         }
         MachineType::None => {
@@ -232,22 +359,48 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
                 LightMachine::new(vm_config).with_context(|| "Failed to init NoneVM")?,
             ));
             EventLoop::set_manager(vm.clone(), None);
-            for listener in listeners {
-                sockets.push(Socket::from_unix_listener(listener, Some(vm.clone())));
+            for (listener, mode, limits) in listeners {
+                sockets.push(
+                    Socket::from_listener(listener, Some(vm.clone()))
+                        .with_mode(mode)
+                        .with_limits(limits),
+                );
             }
+            dispatcher = Some(QmpDispatcher::new(vm.clone()));
             vm
         }
     };
 
-    for socket in sockets {
+    // Privileged syscalls are out of the sandbox, drop privileges before it.
+    drop_privileges(log_file, vm_config).with_context(|| "Failed to drop privileges")?;
+    if let Some(sandbox) = vm_config.sandbox.as_ref() {
+        install_sandbox(sandbox, log_file.is_some())
+            .with_context(|| "Failed to install sandbox")?;
+    }
+
+    // Qmp clients are served by the monitor thread, while their in-band commands
+    // are executed by the dispatcher in the main loop.
+    if let Some(dispatcher) = dispatcher {
         EventLoop::update_event(
-            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(socket))),
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(dispatcher))),
             None,
         )
-        .with_context(|| "Failed to add api event to MainLoop")?;
+        .with_context(|| "Failed to add qmp dispatcher to MainLoop")?;
+    }
+    for socket in sockets {
+        let mode = socket.get_mode();
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(socket)));
+        match mode {
+            MonitorMode::Control => EventLoop::update_monitor_event(notifiers)
+                .with_context(|| "Failed to add api event to monitor loop")?,
+            MonitorMode::Readline => EventLoop::update_event(notifiers, None)
+                .with_context(|| "Failed to add api event to MainLoop")?,
+        }
     }
 
+    machine::set_panic_teardown_vm(&vm);
     machine::vm_run(&vm, cmd_args).with_context(|| "Failed to start VM.")?;
+    daemon_ready()?;
 
     EventLoop::loop_run().with_context(|| "MainLoop exits unexpectedly: error occurs")?;
     Ok(())
@@ -24,9 +24,133 @@ pub enum SysBusError {
         #[from]
         source: hypervisor::error::HypervisorError,
     },
+    #[error(
+        "Region 0x{base:X} (size 0x{size:X}) of {dev_type} overlaps with {other_type} at 0x{other_base:X}"
+    )]
+    AddressOverlap {
+        base: u64,
+        size: u64,
+        dev_type: String,
+        other_base: u64,
+        other_type: String,
+    },
+    #[error("Region 0x{base:X} (size 0x{size:X}) of {dev_type} overlaps with guest RAM")]
+    RamOverlap {
+        base: u64,
+        size: u64,
+        dev_type: String,
+    },
+    #[error(
+        "Region 0x{base:X} (size 0x{size:X}) is out of MMIO window [0x{window_base:X}, 0x{window_end:X})"
+    )]
+    AddressOutOfWindow {
+        base: u64,
+        size: u64,
+        window_base: u64,
+        window_end: u64,
+    },
     #[error("KvmIoctl")]
     KvmIoctl {
         #[from]
         source: kvm_ioctls::Error,
     },
+    #[error("Invalid IRQ range [{start}, {end}], use an explicitly empty range for no IRQ")]
+    InvalidIrqRange { start: i32, end: i32 },
+    #[error("IRQ range [{start}, {end}] is out of [{min}, {max}]")]
+    IrqRangeOutOfBounds {
+        start: i32,
+        end: i32,
+        min: i32,
+        max: i32,
+    },
+    #[error("Invalid MMIO window [0x{base:X}, 0x{end:X})")]
+    InvalidMmioWindow { base: u64, end: u64 },
+    #[error("MMIO window [0x{base:X}, 0x{end:X}) overlaps with {other}")]
+    MmioWindowOverlap { base: u64, end: u64, other: String },
+    #[error("IRQ number exhausted, max IRQ is {max}")]
+    IrqExhausted { max: i32 },
+    #[error("IRQ {0} is not allocated")]
+    IrqNotAllocated(i32),
+    #[error("Failed to route interrupt eventfd of IRQ {irq}")]
+    RouteIrq {
+        irq: i32,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to unroute interrupt eventfd of IRQ {irq}")]
+    UnrouteIrq {
+        irq: i32,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to inject IRQ {irq}")]
+    InjectIrq {
+        irq: i32,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Invalid region: base 0x{base:X}, size 0x{size:X}")]
+    InvalidRegion { base: u64, size: u64 },
+    #[error("Invalid MMIO allocation: size 0x{size:X}, align 0x{align:X}")]
+    InvalidAllocation { size: u64, align: u64 },
+    #[error("MMIO region exhausted: size 0x{size:X}, align 0x{align:X}")]
+    MmioExhausted { size: u64, align: u64 },
+    #[error("Failed to register region in memory space: base 0x{base:X}, size 0x{size:X}")]
+    RegisterRegion {
+        base: u64,
+        size: u64,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to unregister region in memory space: base 0x{base:X}, size 0x{size:X}")]
+    UnregisterRegion {
+        base: u64,
+        size: u64,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to get sys resource of device")]
+    MissingSysResource,
+    #[error("Device is not attached to system bus")]
+    DeviceNotAttached,
+    #[error("Failed to reset sysbus devices: [{0}]")]
+    ResetFailed(String),
+    #[error("Failed to pause {dev_type} device")]
+    PauseFailed {
+        dev_type: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to resume {dev_type} device")]
+    ResumeFailed {
+        dev_type: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to unrealize sysbus devices: [{0}]")]
+    UnrealizeFailed(String),
+    #[error("Failed to save state of {dev_type} device")]
+    SaveStateFailed {
+        dev_type: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to restore state of {dev_type} device")]
+    RestoreStateFailed {
+        dev_type: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to generate device tree node of {dev_type} device")]
+    Fdt {
+        dev_type: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Invalid device state: {0}")]
+    InvalidState(String),
+    #[error("Unknown sysbus device type {0} to trace")]
+    UnknownTraceDevType(String),
 }
+
+pub type SysBusResult<T> = std::result::Result<T, SysBusError>;
@@ -0,0 +1,120 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::{IrqRouter, SysBusError, SysBusResult};
+
+enum IrqLineTarget {
+    /// Interrupt eventfd of device registered as irqfd, which only delivers edges.
+    Irqfd(EventFd),
+    /// Interrupt controller behind the router, which keeps the pending bit of the line.
+    Router(Arc<dyn IrqRouter>),
+}
+
+/// Handle to inject IRQ of sysbus device synchronously, e.g. from inside `write()`.
+/// It's created by system bus when device is attached, clones share the same level.
+#[derive(Clone)]
+pub struct SysBusIrqLine {
+    irq: i32,
+    /// Identifies the device among the sources of a shared line.
+    source: u64,
+    target: Arc<IrqLineTarget>,
+    level: Arc<AtomicBool>,
+}
+
+impl SysBusIrqLine {
+    pub(crate) fn new_irqfd(irq: i32, source: u64, evt: EventFd) -> Self {
+        Self::new(irq, source, IrqLineTarget::Irqfd(evt))
+    }
+
+    pub(crate) fn new_routed(irq: i32, source: u64, router: Arc<dyn IrqRouter>) -> Self {
+        Self::new(irq, source, IrqLineTarget::Router(router))
+    }
+
+    fn new(irq: i32, source: u64, target: IrqLineTarget) -> Self {
+        SysBusIrqLine {
+            irq,
+            source,
+            target: Arc::new(target),
+            level: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn irq(&self) -> i32 {
+        self.irq
+    }
+
+    /// Level currently asserted by `set_level`.
+    pub fn level(&self) -> bool {
+        self.level.load(Ordering::SeqCst)
+    }
+
+    /// Inject an edge-triggered interrupt.
+    pub fn pulse(&self) -> SysBusResult<()> {
+        let ret = match &*self.target {
+            IrqLineTarget::Irqfd(evt) => evt.write(1).map_err(anyhow::Error::from),
+            IrqLineTarget::Router(router) => router.trigger_irq(self.irq),
+        };
+        ret.map_err(|source| SysBusError::InjectIrq {
+            irq: self.irq,
+            source,
+        })
+    }
+
+    /// Assert or deassert a level-triggered interrupt. Irqfd can't deassert the line,
+    /// so only the rising edge is delivered through it.
+    pub fn set_level(&self, level: bool) -> SysBusResult<()> {
+        let old = self.level.swap(level, Ordering::SeqCst);
+        let ret = match &*self.target {
+            IrqLineTarget::Irqfd(evt) if level && !old => evt.write(1).map_err(anyhow::Error::from),
+            IrqLineTarget::Irqfd(_) => Ok(()),
+            IrqLineTarget::Router(router) => router.set_irq_level(self.irq, self.source, level),
+        };
+        ret.map_err(|source| {
+            self.level.store(old, Ordering::SeqCst);
+            SysBusError::InjectIrq {
+                irq: self.irq,
+                source,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_irq_line_irqfd() {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let line = SysBusIrqLine::new_irqfd(5, 0x1000, evt.try_clone().unwrap());
+        assert_eq!(line.irq(), 5);
+
+        line.pulse().unwrap();
+        assert_eq!(evt.read().unwrap(), 1);
+
+        // Only the rising edge is delivered.
+        let clone = line.clone();
+        line.set_level(true).unwrap();
+        clone.set_level(true).unwrap();
+        assert!(clone.level());
+        assert_eq!(evt.read().unwrap(), 1);
+        line.set_level(false).unwrap();
+        assert!(!clone.level());
+        assert!(evt.read().is_err());
+    }
+}
@@ -17,11 +17,29 @@
 // See the Mulan PSL v2 for more details.
 
 pub mod error;
-pub use error::SysBusError;
+pub mod irq_line;
+pub mod mmio_trace;
+pub mod state;
+pub use error::{SysBusError, SysBusResult};
+pub use irq_line::SysBusIrqLine;
+pub use state::{decode_state, encode_state, StateReader};
+pub use mmio_trace::{
+    check_mmio_trace_events, MmioTrace, MmioTraceEntry, MMIO_TRACE_CAPACITY,
+    MMIO_TRACE_EVENT_PREFIX,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
-use std::sync::{Arc, Mutex};
-use address_space::{AddressSpace, GuestAddress, Region, RegionIoEventFd, RegionOps};
-pub use anyhow::{bail, Context, Result};
+use std::sync::{Arc, Mutex, TryLockError};
+use address_space::{
+    AddressSpace, GuestAddress, HostMemMapping, Region, RegionIoEventFd, RegionOps,
+};
+pub use anyhow::{anyhow, bail, Context, Result};
+use arc_swap::ArcSwap;
+use log::{debug, warn};
+#[cfg(target_arch = "riscv64")]
+use util::device_tree;
+use util::device_tree::FdtBuilder;
+use util::trace::is_trace_event_enabled;
 use vmm_sys_util::eventfd::EventFd;
 
 // According to the PLIC document, IRQ number 0 is not used
@@ -30,13 +48,87 @@ pub const IRQ_BASE: i32 = 1;
 #[cfg(target_arch = "riscv64")]
 pub const IRQ_MAX: i32 = 1024;
 
+/// Version of the state saved by `SysBus::save_all`.
+const SYSBUS_STATE_VERSION: u32 = 1;
+
+/// IRQ range of system bus which has no IRQ to allocate.
+pub const EMPTY_IRQ_RANGE: (i32, i32) = (0, -1);
+
+/// Check the inclusive IRQ range is explicitly empty or a valid range of IRQ numbers.
+fn check_irq_range(free_irqs: (i32, i32)) -> SysBusResult<()> {
+    let (start, end) = free_irqs;
+    if free_irqs == EMPTY_IRQ_RANGE {
+        return Ok(());
+    }
+    if start > end {
+        return Err(SysBusError::InvalidIrqRange { start, end });
+    }
+    #[cfg(target_arch = "riscv64")]
+    if start < IRQ_BASE || end > IRQ_MAX {
+        return Err(SysBusError::IrqRangeOutOfBounds {
+            start,
+            end,
+            min: IRQ_BASE,
+            max: IRQ_MAX,
+        });
+    }
+    Ok(())
+}
+
+/// Check the sorted MMIO windows are non-empty, and overlap with neither each other nor
+/// RAM in `sys_mem`.
+fn check_mmio_windows(sys_mem: &AddressSpace, windows: &[(u64, u64)]) -> SysBusResult<()> {
+    for (i, (base, end)) in windows.iter().copied().enumerate() {
+        if base >= end {
+            return Err(SysBusError::InvalidMmioWindow { base, end });
+        }
+        if let Some((prev_base, prev_end)) = i.checked_sub(1).map(|prev| windows[prev]) {
+            if prev_end > base {
+                return Err(SysBusError::MmioWindowOverlap {
+                    base,
+                    end,
+                    other: format!("MMIO window [0x{:X}, 0x{:X})", prev_base, prev_end),
+                });
+            }
+        }
+        if sys_mem.memory_overlaps(GuestAddress(base), end - base) {
+            return Err(SysBusError::MmioWindowOverlap {
+                base,
+                end,
+                other: "guest RAM".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
 pub struct SysBus {
     pub sys_mem: Arc<AddressSpace>,
     pub devices: Vec<Arc<Mutex<dyn SysBusDevOps>>>,
     pub free_irqs: (i32, i32),
     pub min_free_irq: i32,
-    pub mmio_region: (u64, u64),
-    pub min_free_base: u64,
+    /// MMIO windows `[start, end)` sorted by start address, in which non-platform
+    /// devices are located.
+    pub mmio_windows: Vec<(u64, u64)>,
+    /// Occupied MMIO ranges sorted by base address.
+    pub mmio_ranges: BTreeMap<u64, MmioRange>,
+    /// IRQ numbers released by detached devices, reused before `min_free_irq`.
+    pub released_irqs: BTreeSet<i32>,
+    /// Regions registered for each device, index-aligned with `devices`.
+    regions: Vec<Vec<Region>>,
+    /// Router delivering interrupt eventfd of devices to guest.
+    irq_router: Option<Arc<dyn IrqRouter>>,
+    /// IRQ line handed to each device, index-aligned with `devices`.
+    irq_lines: Vec<Option<SysBusIrqLine>>,
+    /// Constant registers of the primary region of each device, index-aligned with
+    /// `devices`.
+    const_regs: Vec<Arc<ArcSwap<ConstRegisterTable>>>,
+    /// Number of extra devices bound to an allocated IRQ line by `request_shared_irq`.
+    shared_irqs: BTreeMap<i32, u32>,
+    /// Whether ioeventfds can be registered to hypervisor.
+    ioeventfd_supported: bool,
+    /// MMIO accesses of devices whose trace event is enabled.
+    mmio_trace: Arc<Mutex<MmioTrace>>,
 }
 
 impl fmt::Debug for SysBus {
@@ -47,38 +139,441 @@ impl fmt::Debug for SysBus {
             .field("sys_mem", &self.sys_mem)
             .field("free_irqs", &self.free_irqs)
             .field("min_free_irq", &self.min_free_irq)
-            .field("mmio_region", &self.mmio_region)
-            .field("min_free_base", &self.min_free_base)
+            .field("mmio_windows", &self.mmio_windows)
+            .field("mmio_ranges", &self.mmio_ranges)
+            .field("released_irqs", &self.released_irqs)
+            .field("shared_irqs", &self.shared_irqs)
             .finish();
         debug
     }
 }
 
 impl SysBus {
+    /// Create system bus with MMIO windows, e.g. a small window below 4G for legacy
+    /// devices and a large one above 4G for devices with big regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - System memory, in which MMIO windows mustn't overlap with RAM.
+    /// * `free_irqs` - Inclusive range of IRQ numbers for devices, `EMPTY_IRQ_RANGE` for
+    ///   vm without devices.
+    /// * `mmio_windows` - MMIO windows `[start, end)`, may be empty for vm without devices.
     pub fn new(
         sys_mem: &Arc<AddressSpace>,
         free_irqs: (i32, i32),
-        mmio_region: (u64, u64),
-    ) -> Self {
-        Self {
+        mut mmio_windows: Vec<(u64, u64)>,
+    ) -> SysBusResult<Self> {
+        check_irq_range(free_irqs)?;
+        mmio_windows.sort_unstable();
+        check_mmio_windows(sys_mem, &mmio_windows)?;
+        Ok(Self {
             sys_mem: sys_mem.clone(),
             devices: Vec::new(),
             free_irqs,
             min_free_irq: free_irqs.0,
-            mmio_region,
-            min_free_base: mmio_region.0,
+            mmio_windows,
+            mmio_ranges: BTreeMap::new(),
+            released_irqs: BTreeSet::new(),
+            regions: Vec::new(),
+            irq_router: None,
+            irq_lines: Vec::new(),
+            const_regs: Vec::new(),
+            shared_irqs: BTreeMap::new(),
+            ioeventfd_supported: true,
+            mmio_trace: Arc::new(Mutex::new(MmioTrace::new(MMIO_TRACE_CAPACITY))),
+        })
+    }
+
+    /// Create system bus with a single MMIO window.
+    pub fn new_single_window(
+        sys_mem: &Arc<AddressSpace>,
+        free_irqs: (i32, i32),
+        mmio_region: (u64, u64),
+    ) -> SysBusResult<Self> {
+        Self::new(sys_mem, free_irqs, vec![mmio_region])
+    }
+
+    /// Set whether ioeventfds can be registered to hypervisor.
+    pub fn set_ioeventfd_supported(&mut self, supported: bool) {
+        self.ioeventfd_supported = supported;
+    }
+
+    /// Ioeventfds to register for device. Without ioeventfd support none is registered,
+    /// writes to their addresses reach the device, which dispatches them by the value
+    /// written.
+    fn resolve_ioeventfds(&self, evtfds: Vec<RegionIoEventFd>) -> Vec<RegionIoEventFd> {
+        if self.ioeventfd_supported {
+            evtfds
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// MMIO trace buffer shared by all the attached devices.
+    pub fn mmio_trace(&self) -> &Arc<Mutex<MmioTrace>> {
+        &self.mmio_trace
+    }
+
+    /// Set the router used to deliver interrupt eventfd of devices.
+    pub fn set_irq_router(&mut self, router: Arc<dyn IrqRouter>) {
+        self.irq_router = Some(router);
+    }
+
+    /// Route interrupt eventfd of `irq` to guest. Irqfd is preferred if it's supported by
+    /// hypervisor, otherwise the eventfd is polled in userspace.
+    ///
+    /// # Arguments
+    ///
+    /// * `evt` - Interrupt eventfd of device.
+    /// * `irq` - IRQ number allocated for device.
+    pub fn route_irq(&self, evt: &EventFd, irq: i32) -> SysBusResult<IrqMode> {
+        let router = match &self.irq_router {
+            Some(router) => router,
+            None => return Ok(IrqMode::None),
+        };
+
+        if router.irqfd_supported() {
+            match router.register_irqfd(evt, irq) {
+                Ok(()) => return Ok(IrqMode::Irqfd),
+                Err(e) => warn!(
+                    "Failed to register irqfd for irq {}, fall back to polling: {:?}",
+                    irq, e
+                ),
+            }
+        }
+        router
+            .register_polling(evt, irq)
+            .map_err(|source| SysBusError::RouteIrq { irq, source })?;
+        Ok(IrqMode::Polling)
+    }
+
+    /// Allocate an IRQ number, preferring the ones released by detached devices.
+    pub fn alloc_irq(&mut self) -> SysBusResult<i32> {
+        if let Some(irq) = self.released_irqs.pop_first() {
+            return Ok(irq);
+        }
+
+        let irq = self.min_free_irq;
+        if irq > self.free_irqs.1 {
+            return Err(SysBusError::IrqExhausted {
+                max: self.free_irqs.1,
+            });
+        }
+        self.min_free_irq = irq + 1;
+        Ok(irq)
+    }
+
+    /// Give an IRQ number back to the free pool.
+    pub fn release_irq(&mut self, irq: i32) {
+        if let Some(sharers) = self.shared_irqs.get_mut(&irq) {
+            *sharers -= 1;
+            if *sharers == 0 {
+                self.shared_irqs.remove(&irq);
+            }
+            return;
+        }
+        if irq >= self.free_irqs.0 && irq < self.min_free_irq {
+            self.released_irqs.insert(irq);
+        }
+    }
+
+    /// Check whether `irq` has been allocated to some device.
+    fn irq_allocated(&self, irq: i32) -> bool {
+        irq >= self.free_irqs.0 && irq < self.min_free_irq && !self.released_irqs.contains(&irq)
+    }
+
+    /// Bind another device to an already allocated IRQ line, the line is only
+    /// released after all of the devices bound to it are detached.
+    ///
+    /// Shared lines are level-triggered: the interrupt controller asserts the
+    /// line while any of the sharers asserts it, so it stays asserted until
+    /// every sharer deasserts.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - The allocated IRQ number to share.
+    /// * `evt` - Interrupt eventfd of the device.
+    pub fn request_shared_irq(&mut self, irq: i32, evt: &EventFd) -> SysBusResult<IrqMode> {
+        if !self.irq_allocated(irq) {
+            return Err(SysBusError::IrqNotAllocated(irq));
+        }
+        let mode = self.route_irq(evt, irq)?;
+        *self.shared_irqs.entry(irq).or_insert(0) += 1;
+        Ok(mode)
+    }
+
+    /// Allocate a range of guest physical address inside the first MMIO window
+    /// which has room for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Size of the range.
+    /// * `align` - Alignment of the base address, must be power of 2.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if none of the MMIO windows has free range to hold it.
+    pub fn allocate_mmio(&mut self, size: u64, align: u64) -> SysBusResult<u64> {
+        self.allocate_mmio_hinted(size, align, false)
+    }
+
+    /// Allocate a range like `allocate_mmio`, but the windows are tried from the highest
+    /// one if `prefer_high` is set.
+    pub fn allocate_mmio_hinted(
+        &mut self,
+        size: u64,
+        align: u64,
+        prefer_high: bool,
+    ) -> SysBusResult<u64> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(SysBusError::InvalidAllocation { size, align });
+        }
+
+        let mut windows = self.mmio_windows.clone();
+        if prefer_high {
+            windows.reverse();
+        }
+        let base = windows
+            .into_iter()
+            .find_map(|window| self.find_free_mmio(window, size, align))
+            .ok_or(SysBusError::MmioExhausted { size, align })?;
+        self.mmio_ranges.insert(
+            base,
+            MmioRange {
+                size,
+                dev_type: None,
+            },
+        );
+        Ok(base)
+    }
+
+    /// Find the lowest free range of `size` aligned to `align` in `window`.
+    fn find_free_mmio(&self, window: (u64, u64), size: u64, align: u64) -> Option<u64> {
+        let align_up = |addr: u64| addr.checked_add(align - 1).map(|a| a & !(align - 1));
+        let mut base = align_up(window.0)?;
+        for (start, range) in self.mmio_ranges.range(..window.1) {
+            let end = base.checked_add(size)?;
+            if start + range.size <= base {
+                continue;
+            }
+            if end <= *start {
+                break;
+            }
+            base = align_up(start + range.size)?;
+        }
+
+        match base.checked_add(size) {
+            Some(end) if end <= window.1 => Some(base),
+            _ => None,
+        }
+    }
+
+    /// Reserve range `[base, base + size)` inside MMIO windows, e.g. to keep the range
+    /// of a detached device for the next device attached at the same address.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the range is out of MMIO windows or overlaps with others.
+    pub fn reserve_mmio(&mut self, base: u64, size: u64) -> SysBusResult<()> {
+        self.check_mmio_range(base, size, SysBusDevType::Others)?;
+        self.mmio_ranges.insert(
+            base,
+            MmioRange {
+                size,
+                dev_type: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Free the range at `base` allocated by `allocate_mmio` or reserved by `reserve_mmio`,
+    /// e.g. when the device it's allocated for fails to be realized. Ranges occupied by
+    /// attached devices are kept, return whether the range is freed.
+    pub fn release_mmio(&mut self, base: u64) -> bool {
+        match self.mmio_ranges.get(&base) {
+            Some(range) if range.dev_type.is_none() => {
+                self.mmio_ranges.remove(&base);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether range `[base, base + size)` is inside one of the MMIO windows.
+    pub fn in_mmio_window(&self, base: u64, size: u64) -> bool {
+        match base.checked_add(size) {
+            Some(end) => self
+                .mmio_windows
+                .iter()
+                .any(|(start, window_end)| *start <= base && end <= *window_end),
+            None => false,
+        }
+    }
+
+    /// Whether range `[base, base + size)` intersects with any MMIO window or any
+    /// occupied MMIO range, e.g. before plugging guest RAM into it.
+    pub fn mmio_overlaps(&self, base: u64, size: u64) -> bool {
+        let end = base.saturating_add(size);
+        self.mmio_windows
+            .iter()
+            .any(|(start, window_end)| *start < end && base < *window_end)
+            || self
+                .mmio_ranges
+                .range(..end)
+                .next_back()
+                .map_or(false, |(start, range)| start + range.size > base)
+    }
+
+    /// Check the range is available for device of `dev_type`. The range allocated by
+    /// `allocate_mmio` with the same base and size is available for any device.
+    fn check_mmio_range(
+        &self,
+        base: u64,
+        size: u64,
+        dev_type: SysBusDevType,
+    ) -> SysBusResult<()> {
+        let end = match base.checked_add(size) {
+            Some(end) if size != 0 => end,
+            _ => return Err(SysBusError::InvalidRegion { base, size }),
+        };
+        if !dev_type.is_platform() && !self.in_mmio_window(base, size) {
+            // Report the window nearest below the range, or the lowest one.
+            let (window_base, window_end) = self
+                .mmio_windows
+                .iter()
+                .rev()
+                .find(|(start, _)| *start <= base)
+                .or_else(|| self.mmio_windows.first())
+                .copied()
+                .unwrap_or_default();
+            return Err(SysBusError::AddressOutOfWindow {
+                base,
+                size,
+                window_base,
+                window_end,
+            });
+        }
+
+        if self.sys_mem.memory_overlaps(GuestAddress(base), size) {
+            return Err(SysBusError::RamOverlap {
+                base,
+                size,
+                dev_type: format!("{:?}", dev_type),
+            });
+        }
+
+        if let Some((start, range)) = self.mmio_ranges.range(..end).next_back() {
+            let reserved = *start == base && range.size == size && range.dev_type.is_none();
+            if start + range.size > base && !reserved {
+                return Err(SysBusError::AddressOverlap {
+                    base,
+                    size,
+                    dev_type: format!("{:?}", dev_type),
+                    other_base: *start,
+                    other_type: range
+                        .dev_type
+                        .map_or("reserved range".to_string(), |t| format!("{:?}", t)),
+                });
+            }
         }
+        Ok(())
+    }
+
+    fn device_index<T: 'static + SysBusDevOps>(&self, dev: &Arc<Mutex<T>>) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| Arc::as_ptr(d) as *const u8 == Arc::as_ptr(dev) as *const u8)
     }
 
-    pub fn build_region_ops<T: 'static + SysBusDevOps>(&self, dev: &Arc<Mutex<T>>) -> RegionOps {
+    /// Build operations of the region with index `region_index` of device. Writes to
+    /// read-only region are dropped without touching the device. Accesses are recorded
+    /// in the MMIO trace buffer while trace event of the device type is enabled.
+    pub fn build_region_ops<T: 'static + SysBusDevOps>(
+        &self,
+        dev: &Arc<Mutex<T>>,
+        region_index: usize,
+        read_only: bool,
+    ) -> RegionOps {
+        let const_regs = Arc::new(ArcSwap::from_pointee(ConstRegisterTable::default()));
+        self.build_region_ops_with(dev, region_index, read_only, const_regs)
+    }
+
+    /// Build region ops answering reads of the registers in `const_regs` without taking
+    /// the lock of device.
+    fn build_region_ops_with<T: 'static + SysBusDevOps>(
+        &self,
+        dev: &Arc<Mutex<T>>,
+        region_index: usize,
+        read_only: bool,
+        const_regs: Arc<ArcSwap<ConstRegisterTable>>,
+    ) -> RegionOps {
+        let dev_type = dev.lock().unwrap().get_type();
         let cloned_dev = dev.clone();
         let read_ops = move |data: &mut [u8], addr: GuestAddress, offset: u64| -> bool {
-            cloned_dev.lock().unwrap().read(data, addr, offset)
+            if let Some(value) = const_regs.load().get(offset, data.len()) {
+                data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+                return true;
+            }
+            let ret = cloned_dev
+                .lock()
+                .unwrap()
+                .read_region(region_index, data, addr, offset);
+            check_access(ret, dev_type, "read", offset, data.len())
         };
 
         let cloned_dev = dev.clone();
         let write_ops = move |data: &[u8], addr: GuestAddress, offset: u64| -> bool {
-            cloned_dev.lock().unwrap().write(data, addr, offset)
+            if read_only {
+                debug!(
+                    "Drop write to read-only region 0x{:x}, offset 0x{:x}, size {}",
+                    addr.raw_value(),
+                    offset,
+                    data.len()
+                );
+                return true;
+            }
+            let ret = cloned_dev
+                .lock()
+                .unwrap()
+                .write_region(region_index, data, addr, offset);
+            check_access(ret, dev_type, "write", offset, data.len())
+        };
+
+        self.trace_region_ops(
+            RegionOps {
+                read: Arc::new(read_ops),
+                write: Arc::new(write_ops),
+            },
+            dev_type,
+        )
+    }
+
+    fn trace_region_ops(&self, ops: RegionOps, dev_type: SysBusDevType) -> RegionOps {
+        let event = dev_type.mmio_trace_event();
+        let trace = self.mmio_trace.clone();
+        let read = ops.read;
+        let read_event = event.clone();
+        let read_trace = trace.clone();
+        let read_ops = move |data: &mut [u8], addr: GuestAddress, offset: u64| -> bool {
+            let ret = read(data, addr, offset);
+            if is_trace_event_enabled(&read_event) {
+                read_trace
+                    .lock()
+                    .unwrap()
+                    .record(dev_type, addr.raw_value(), offset, data, false);
+            }
+            ret
+        };
+
+        let write = ops.write;
+        let write_ops = move |data: &[u8], addr: GuestAddress, offset: u64| -> bool {
+            if is_trace_event_enabled(&event) {
+                trace
+                    .lock()
+                    .unwrap()
+                    .record(dev_type, addr.raw_value(), offset, data, true);
+            }
+            write(data, addr, offset)
         };
 
         RegionOps {
@@ -87,148 +582,921 @@ impl SysBus {
         }
     }
 
+    /// Attach a device to system bus and register its MMIO region.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - The device to attach.
+    /// * `region_base` - Base address of the region, allocated from MMIO windows if `None`.
+    /// * `region_size` - Size of the region.
     pub fn attach_device<T: 'static + SysBusDevOps>(
         &mut self,
         dev: &Arc<Mutex<T>>,
+        region_base: Option<u64>,
+        region_size: u64,
+    ) -> SysBusResult<()> {
+        self.attach_device_internal(dev, region_base, region_size, false)
+    }
+
+    /// Attach a ROM-like device to system bus, the guest can read its regions
+    /// but writes are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - The device to attach.
+    /// * `region_base` - Base address of the region, allocated from MMIO windows if `None`.
+    /// * `region_size` - Size of the region.
+    pub fn attach_device_ro<T: 'static + SysBusDevOps>(
+        &mut self,
+        dev: &Arc<Mutex<T>>,
+        region_base: Option<u64>,
+        region_size: u64,
+    ) -> SysBusResult<()> {
+        self.attach_device_internal(dev, region_base, region_size, true)
+    }
+
+    fn attach_device_internal<T: 'static + SysBusDevOps>(
+        &mut self,
+        dev: &Arc<Mutex<T>>,
+        region_base: Option<u64>,
+        region_size: u64,
+        read_only: bool,
+    ) -> SysBusResult<()> {
+        let dev_type = dev.lock().unwrap().get_type();
+        let (region_base, allocated) = match region_base {
+            Some(base) => {
+                self.check_mmio_range(base, region_size, dev_type)?;
+                (base, false)
+            }
+            None => {
+                let prefer_high = dev.lock().unwrap().prefer_high_mmio();
+                let align = region_size.next_power_of_two();
+                (
+                    self.allocate_mmio_hinted(region_size, align, prefer_high)?,
+                    true,
+                )
+            }
+        };
+        let const_regs = Arc::new(ArcSwap::from_pointee(ConstRegisterTable::new(
+            dev.lock().unwrap().const_registers(),
+        )));
+        let region = match self.register_region(
+            dev,
+            0,
+            region_base,
+            region_size,
+            read_only,
+            const_regs.clone(),
+        ) {
+            Ok(region) => region,
+            Err(e) => {
+                if allocated {
+                    self.mmio_ranges.remove(&region_base);
+                }
+                return Err(e);
+            }
+        };
+        if allocated {
+            if let Some(res) = dev.lock().unwrap().get_sys_resource() {
+                res.region_base = region_base;
+                res.region_size = region_size;
+            }
+        }
+        self.mmio_ranges.insert(
+            region_base,
+            MmioRange {
+                size: region_size,
+                dev_type: Some(dev_type),
+            },
+        );
+
+        let mut regions = vec![region];
+        let extra_regions = dev.lock().unwrap().regions();
+        for res in extra_regions.iter().filter(|res| res.region_index != 0) {
+            let registered = self
+                .check_mmio_range(res.region_base, res.region_size, dev_type)
+                .and_then(|_| {
+                    self.register_region(
+                        dev,
+                        res.region_index,
+                        res.region_base,
+                        res.region_size,
+                        read_only,
+                        Arc::new(ArcSwap::from_pointee(ConstRegisterTable::default())),
+                    )
+                });
+            match registered {
+                Ok(region) => {
+                    self.mmio_ranges.insert(
+                        res.region_base,
+                        MmioRange {
+                            size: res.region_size,
+                            dev_type: Some(dev_type),
+                        },
+                    );
+                    regions.push(region);
+                }
+                Err(e) => {
+                    self.unregister_regions(&regions);
+                    return Err(e);
+                }
+            }
+        }
+
+        let irq_line = self.create_irq_line(&mut *dev.lock().unwrap());
+        if let Some(line) = &irq_line {
+            dev.lock().unwrap().set_irq_line(line.clone());
+        }
+        self.devices.push(dev.clone());
+        self.regions.push(regions);
+        self.irq_lines.push(irq_line);
+        self.const_regs.push(const_regs);
+        Ok(())
+    }
+
+    /// Create IRQ line for device holding an IRQ. Its eventfd is used if it's registered
+    /// as irqfd, otherwise interrupt is injected through the router.
+    fn create_irq_line(&self, dev: &mut dyn SysBusDevOps) -> Option<SysBusIrqLine> {
+        let res = *dev.get_sys_resource()?;
+        if res.irq < 0 {
+            return None;
+        }
+        if res.irq_mode == IrqMode::Irqfd {
+            match dev.interrupt_evt().map(|evt| evt.try_clone()) {
+                Some(Ok(evt)) => {
+                    return Some(SysBusIrqLine::new_irqfd(res.irq, res.region_base, evt));
+                }
+                Some(Err(e)) => warn!("Failed to clone irqfd of irq {}: {:?}", res.irq, e),
+                None => (),
+            }
+        }
+        self.irq_router
+            .as_ref()
+            .map(|router| SysBusIrqLine::new_routed(res.irq, res.region_base, router.clone()))
+    }
+
+    /// Unmap the regions from memory space and free the ranges they occupied,
+    /// errors are logged since the regions are being dropped anyway.
+    fn unregister_regions(&mut self, regions: &[Region]) {
+        for region in regions.iter() {
+            let base = region.offset().raw_value();
+            if let Err(e) = self.sys_mem.root().delete_subregion(region) {
+                warn!("Failed to unregister region at 0x{:x}: {:?}", base, e);
+            }
+            self.mmio_ranges.remove(&base);
+        }
+    }
+
+    fn register_region<T: 'static + SysBusDevOps>(
+        &self,
+        dev: &Arc<Mutex<T>>,
+        region_index: usize,
         region_base: u64,
         region_size: u64,
-    ) -> Result<()> {
-        let region_ops = self.build_region_ops(dev);
-        let region = Region::init_io_region(region_size, region_ops);
+        read_only: bool,
+        const_regs: Arc<ArcSwap<ConstRegisterTable>>,
+    ) -> SysBusResult<Region> {
+        let region_ops = self.build_region_ops_with(dev, region_index, read_only, const_regs);
         let locked_dev = dev.lock().unwrap();
+        let region = match locked_dev.rom_mapping() {
+            Some(mapping) if region_index == 0 => {
+                if mapping.size() != region_size {
+                    return Err(SysBusError::InvalidRegion {
+                        base: region_base,
+                        size: region_size,
+                    });
+                }
+                Region::init_rom_device_region(mapping, region_ops)
+            }
+            _ => Region::init_io_region(region_size, region_ops),
+        };
 
-        region.set_ioeventfds(&locked_dev.ioeventfds());
+        // Ioeventfds are always located in the primary region.
+        if region_index == 0 {
+            region.set_ioeventfds(&self.resolve_ioeventfds(locked_dev.ioeventfds()));
+        }
+        let registered = region.clone();
         match locked_dev.get_type() {
             SysBusDevType::Serial if cfg!(target_arch = "x86_64") => {
                 #[cfg(target_arch = "x86_64")]
                 self.sys_io
                     .root()
                     .add_subregion(region, region_base)
-                    .with_context(|| {
-                        format!(
-                            "Failed to register region in I/O space: offset={},size={}",
-                            region_base, region_size
-                        )
+                    .map_err(|source| SysBusError::RegisterRegion {
+                        base: region_base,
+                        size: region_size,
+                        source,
                     })?;
             }
             _ => self
                 .sys_mem
                 .root()
                 .add_subregion(region, region_base)
-                .with_context(|| {
-                    format!(
-                        "Failed to register region in memory space: offset={},size={}",
-                        region_base, region_size
-                    )
+                .map_err(|source| SysBusError::RegisterRegion {
+                    base: region_base,
+                    size: region_size,
+                    source,
                 })?,
         }
 
-        self.devices.push(dev.clone());
-        Ok(())
+        Ok(registered)
     }
 
     pub fn attach_dynamic_device<T: 'static + SysBusDevOps>(
         &mut self,
         dev: &Arc<Mutex<T>>,
-    ) -> Result<()> {
+    ) -> SysBusResult<()> {
         self.devices.push(dev.clone());
+        self.regions.push(Vec::new());
+        self.irq_lines.push(None);
+        self.const_regs.push(Arc::new(ArcSwap::from_pointee(
+            ConstRegisterTable::default(),
+        )));
         Ok(())
     }
-}
 
-#[derive(Copy, Clone)]
-pub struct SysRes {
-    pub region_base: u64,
-    pub region_size: u64,
-    pub irq: i32,
-}
+    /// Iterate the attached devices in attach order, yielding device type and system
+    /// resource, which is `None` for devices without system resource.
+    pub fn iter_resources(&self) -> impl Iterator<Item = (SysBusDevType, Option<SysRes>)> + '_ {
+        self.devices.iter().map(|dev| {
+            let mut locked_dev = dev.lock().unwrap();
+            (locked_dev.get_type(), locked_dev.get_sys_resource().copied())
+        })
+    }
 
-impl Default for SysRes {
-    fn default() -> Self {
-        Self {
-            region_base: 0,
-            region_size: 0,
-            irq: -1,
+    /// Generate device tree nodes of the attached devices in attach order into the node of
+    /// system bus, which is opened by caller.
+    pub fn generate_fdt_nodes(&self, fdt: &mut FdtBuilder) -> SysBusResult<()> {
+        for dev in self.devices.iter() {
+            let mut locked_dev = dev.lock().unwrap();
+            let dev_type = locked_dev.get_type();
+            locked_dev
+                .fdt_node(fdt)
+                .map_err(|source| SysBusError::Fdt {
+                    dev_type: format!("{:?}", dev_type),
+                    source,
+                })?;
         }
+        Ok(())
     }
-}
-
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Eq, PartialEq)]
-pub enum SysBusDevType {
-    Serial,
-    Rtc,
-    VirtioMmio,
-    #[cfg(target_arch = "riscv64")]
-    Plic,
-    FwCfg,
-    Ramfb,
-    PcieMem,
-    Others,
-}
-
-/// Operations for sysbus devices.
-pub trait SysBusDevOps: Send {
-    /// Read function of device.
-    ///
-    /// # Arguments
-    ///
-    /// * `data` - A u8-type array.
-    /// * `base` - Base address of this device.
-    /// * `offset` - Offset from base address.
-    fn read(&mut self, data: &mut [u8], base: GuestAddress, offset: u64) -> bool;
 
-    /// Write function of device.
-    ///
-    /// # Arguments
+    /// Reset all of the attached devices in attach order, deassert the IRQ lines left
+    /// asserted by them and reload their constant registers.
     ///
-    /// * `data` - A u8-type array.
-    /// * `base` - Base address of this device.
-    /// * `offset` - Offset from base address.
-    fn write(&mut self, data: &[u8], base: GuestAddress, offset: u64) -> bool;
-
-    fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
-        Vec::new()
+    /// Failure of one device doesn't prevent the others from being reset, all of
+    /// the failures are reported together.
+    pub fn reset_all(&self) -> SysBusResult<()> {
+        let mut failures = Vec::new();
+        for (i, (dev, line)) in self.devices.iter().zip(self.irq_lines.iter()).enumerate() {
+            let mut locked_dev = dev.lock().unwrap();
+            if let Err(e) = locked_dev.reset() {
+                failures.push(format!("{:?}: {:?}", locked_dev.get_type(), e));
+            }
+            if !self.regions[i].is_empty() {
+                self.const_regs[i].store(Arc::new(ConstRegisterTable::new(
+                    locked_dev.const_registers(),
+                )));
+            }
+            if let Some(line) = line.as_ref().filter(|line| line.level()) {
+                if let Err(e) = line.set_level(false) {
+                    failures.push(format!("{:?}: {:?}", locked_dev.get_type(), e));
+                }
+            }
+        }
+        if !failures.is_empty() {
+            return Err(SysBusError::ResetFailed(failures.join("; ")));
+        }
+        Ok(())
     }
 
-    fn interrupt_evt(&self) -> Option<&EventFd> {
-        None
+    /// Pause all of the attached devices when vm is stopped.
+    pub fn pause_all(&self) -> SysBusResult<()> {
+        for dev in self.devices.iter() {
+            let mut locked_dev = dev.lock().unwrap();
+            locked_dev
+                .pause()
+                .map_err(|source| SysBusError::PauseFailed {
+                    dev_type: format!("{:?}", locked_dev.get_type()),
+                    source,
+                })?;
+        }
+        Ok(())
     }
 
-    fn set_irq(&mut self, sysbus: &mut SysBus) -> Result<i32> {
-        let irq = sysbus.min_free_irq;
-        if irq > sysbus.free_irqs.1 {
-            bail!("IRQ number exhausted.");
+    /// Resume all of the attached devices when vm continues.
+    pub fn resume_all(&self) -> SysBusResult<()> {
+        for dev in self.devices.iter() {
+            let mut locked_dev = dev.lock().unwrap();
+            locked_dev
+                .resume()
+                .map_err(|source| SysBusError::ResumeFailed {
+                    dev_type: format!("{:?}", locked_dev.get_type()),
+                    source,
+                })?;
         }
+        Ok(())
+    }
 
-        match self.interrupt_evt() {
-            None => Ok(-1_i32),
-            Some(evt) => {
-          //      KVM_FDS.load().register_irqfd(evt, irq as u32)?;
-                sysbus.min_free_irq = irq + 1;
-                Ok(irq)
+    /// Unrealize all of the attached devices when vm is torn down, in reverse of attach
+    /// order so that consumers are torn down before the providers they depend on.
+    ///
+    /// It's also called by panic hook, so devices locked by others are skipped instead
+    /// of waited for. Failure of one device doesn't prevent the others from being
+    /// unrealized, all of the failures are reported together.
+    pub fn unrealize_all(&self) -> SysBusResult<()> {
+        let mut failures = Vec::new();
+        for dev in self.devices.iter().rev() {
+            let mut locked_dev = match dev.try_lock() {
+                Ok(locked_dev) => locked_dev,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    failures.push("device is busy".to_string());
+                    continue;
+                }
+            };
+            if let Err(e) = locked_dev.unrealize() {
+                failures.push(format!("{:?}: {:?}", locked_dev.get_type(), e));
             }
         }
+        if !failures.is_empty() {
+            return Err(SysBusError::UnrealizeFailed(failures.join("; ")));
+        }
+        Ok(())
     }
 
-    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
-        None
+    /// Save state of the attached devices in attach order, each keyed by device type and
+    /// base address. Devices without state are skipped.
+    pub fn save_all(&self) -> SysBusResult<Vec<u8>> {
+        let mut entries = Vec::new();
+        let mut count: u32 = 0;
+        for dev in self.devices.iter() {
+            let mut locked_dev = dev.lock().unwrap();
+            let dev_type = locked_dev.get_type();
+            let data = locked_dev
+                .state_bytes()
+                .map_err(|source| SysBusError::SaveStateFailed {
+                    dev_type: format!("{:?}", dev_type),
+                    source,
+                })?;
+            if data.is_empty() {
+                continue;
+            }
+            let base = locked_dev
+                .get_sys_resource()
+                .map_or(0, |res| res.region_base);
+            let name = dev_type.name();
+            entries.push(name.len() as u8);
+            entries.extend_from_slice(name.as_bytes());
+            entries.extend_from_slice(&base.to_le_bytes());
+            entries.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            entries.extend_from_slice(&data);
+            count += 1;
+        }
+
+        let mut payload = count.to_le_bytes().to_vec();
+        payload.append(&mut entries);
+        Ok(encode_state(SYSBUS_STATE_VERSION, &payload))
     }
 
-    fn set_sys_resource(
-        &mut self,
-        sysbus: &mut SysBus,
-        region_base: u64,
-        region_size: u64,
-    ) -> Result<()> {
-        let irq = self.set_irq(sysbus)?;
-        if let Some(res) = self.get_sys_resource() {
-            res.region_base = region_base;
-            res.region_size = region_size;
-            res.irq = irq;
-            return Ok(());
+    /// Restore state saved by `save_all` to the attached devices, which are matched by
+    /// device type and base address. Every saved entry must have its device.
+    pub fn restore_all(&self, data: &[u8]) -> SysBusResult<()> {
+        let (_, payload) = decode_state(data, SYSBUS_STATE_VERSION)?;
+        let mut reader = state::StateReader::new(payload);
+        let count = reader.read_u32()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let name_len = reader.read_bytes(1)?[0] as usize;
+            let name = String::from_utf8_lossy(reader.read_bytes(name_len)?).to_string();
+            let base = reader.read_u64()?;
+            let len = reader.read_u32()? as usize;
+            entries.push(Some((name, base, reader.read_bytes(len)?)));
+        }
+        if !reader.is_empty() {
+            return Err(SysBusError::InvalidState("trailing bytes".to_string()));
+        }
+
+        for dev in self.devices.iter() {
+            let mut locked_dev = dev.lock().unwrap();
+            let dev_type = locked_dev.get_type();
+            let base = locked_dev
+                .get_sys_resource()
+                .map_or(0, |res| res.region_base);
+            let entry = entries.iter_mut().find(|entry| {
+                matches!(entry, Some((name, b, _)) if name == dev_type.name() && *b == base)
+            });
+            if let Some((_, _, state)) = entry.and_then(|entry| entry.take()) {
+                locked_dev.restore_state(state).map_err(|source| {
+                    SysBusError::RestoreStateFailed {
+                        dev_type: format!("{:?}", dev_type),
+                        source,
+                    }
+                })?;
+            }
+        }
+        if let Some((name, base, _)) = entries.into_iter().flatten().next() {
+            return Err(SysBusError::InvalidState(format!(
+                "no {} device at 0x{:x}",
+                name, base
+            )));
+        }
+        Ok(())
+    }
+
+    /// Detach a device from system bus, unmap its MMIO region and release its IRQ.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - The device attached by `attach_device` or `attach_dynamic_device`.
+    pub fn detach_device<T: 'static + SysBusDevOps>(
+        &mut self,
+        dev: &Arc<Mutex<T>>,
+    ) -> SysBusResult<()> {
+        let index = self
+            .device_index(dev)
+            .ok_or(SysBusError::DeviceNotAttached)?;
+
+        for region in self.regions[index].iter() {
+            self.sys_mem
+                .root()
+                .delete_subregion(region)
+                .map_err(|source| SysBusError::UnregisterRegion {
+                    base: region.offset().raw_value(),
+                    size: region.size(),
+                    source,
+                })?;
+            self.mmio_ranges.remove(&region.offset().raw_value());
+        }
+        self.regions.remove(index);
+        self.devices.remove(index);
+        self.const_regs.remove(index);
+        if let Some(line) = self.irq_lines.remove(index).filter(|line| line.level()) {
+            if let Err(e) = line.set_level(false) {
+                warn!(
+                    "Failed to deassert irq {} of detached device: {:?}",
+                    line.irq(),
+                    e
+                );
+            }
+        }
+
+        let mut locked_dev = dev.lock().unwrap();
+        let (irq, irq_mode) = match locked_dev.get_sys_resource() {
+            Some(res) => {
+                let old = (res.irq, res.irq_mode);
+                res.irq = -1;
+                res.irq_mode = IrqMode::None;
+                old
+            }
+            None => return Ok(()),
+        };
+        if irq < 0 {
+            return Ok(());
+        }
+        self.release_irq(irq);
+        if let (Some(router), Some(evt)) = (&self.irq_router, locked_dev.interrupt_evt()) {
+            if irq_mode != IrqMode::None {
+                router
+                    .unregister(evt, irq, irq_mode)
+                    .map_err(|source| SysBusError::UnrouteIrq { irq, source })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Register whose value never changes, read by vcpus without taking the lock of device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ConstRegister {
+    pub offset: u64,
+    /// Access size in bytes, up to 8.
+    pub size: usize,
+    pub value: u64,
+}
+
+/// Lookup table of the constant registers of device, keyed by offset and size.
+#[derive(Default)]
+struct ConstRegisterTable {
+    regs: HashMap<(u64, usize), u64>,
+}
+
+impl ConstRegisterTable {
+    fn new(regs: Vec<ConstRegister>) -> Self {
+        let regs = regs
+            .into_iter()
+            .filter(|reg| {
+                let valid = reg.size > 0 && reg.size <= 8;
+                if !valid {
+                    warn!(
+                        "Ignore constant register at 0x{:x} of size {}",
+                        reg.offset, reg.size
+                    );
+                }
+                valid
+            })
+            .map(|reg| ((reg.offset, reg.size), reg.value))
+            .collect();
+        ConstRegisterTable { regs }
+    }
+
+    fn get(&self, offset: u64, size: usize) -> Option<u64> {
+        self.regs.get(&(offset, size)).copied()
+    }
+}
+
+/// MMIO range occupied on system bus.
+#[derive(Copy, Clone, Debug)]
+pub struct MmioRange {
+    pub size: u64,
+    /// Type of the device owning this range, `None` if it's allocated but not attached yet.
+    pub dev_type: Option<SysBusDevType>,
+}
+
+/// The way interrupt of sysbus device is delivered to guest.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqMode {
+    /// Interrupt is not routed by system bus.
+    None,
+    /// Interrupt eventfd is registered to KVM as irqfd.
+    Irqfd,
+    /// Interrupt eventfd is polled in userspace and injected through interrupt controller.
+    Polling,
+}
+
+/// Router delivering interrupt eventfd of sysbus devices to guest.
+pub trait IrqRouter: Send + Sync {
+    /// Check whether irqfd is supported by hypervisor.
+    fn irqfd_supported(&self) -> bool;
+
+    /// Register `evt` to hypervisor as irqfd of `irq`.
+    fn register_irqfd(&self, evt: &EventFd, irq: i32) -> Result<()>;
+
+    /// Poll `evt` in userspace, and inject `irq` to guest when it's triggered.
+    fn register_polling(&self, evt: &EventFd, irq: i32) -> Result<()>;
+
+    /// Stop delivering `evt` registered in `mode`.
+    fn unregister(&self, evt: &EventFd, irq: i32, mode: IrqMode) -> Result<()>;
+
+    /// Inject an edge of `irq` to guest.
+    fn trigger_irq(&self, irq: i32) -> Result<()>;
+
+    /// Set level of `irq` driven by `source`, the line is asserted while any of its
+    /// sources asserts it.
+    fn set_irq_level(&self, irq: i32, source: u64, level: bool) -> Result<()>;
+}
+
+#[derive(Copy, Clone)]
+pub struct SysRes {
+    pub region_base: u64,
+    pub region_size: u64,
+    /// Index of the region among the regions of device, 0 for the primary one.
+    pub region_index: usize,
+    pub irq: i32,
+    pub irq_mode: IrqMode,
+}
+
+impl Default for SysRes {
+    fn default() -> Self {
+        Self {
+            region_base: 0,
+            region_size: 0,
+            region_index: 0,
+            irq: -1,
+            irq_mode: IrqMode::None,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SysBusDevType {
+    Serial,
+    Rtc,
+    VirtioMmio,
+    #[cfg(target_arch = "riscv64")]
+    Plic,
+    #[cfg(target_arch = "riscv64")]
+    Clint,
+    FwCfg,
+    Ramfb,
+    PcieMem,
+    Flash,
+    Watchdog,
+    SifiveTest,
+    PvPanic,
+    Gpio,
+    Others,
+}
+
+impl SysBusDevType {
+    /// Name of device type used by the MMIO trace event and QMP.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SysBusDevType::Serial => "serial",
+            SysBusDevType::Rtc => "rtc",
+            SysBusDevType::VirtioMmio => "virtio-mmio",
+            #[cfg(target_arch = "riscv64")]
+            SysBusDevType::Plic => "plic",
+            #[cfg(target_arch = "riscv64")]
+            SysBusDevType::Clint => "clint",
+            SysBusDevType::FwCfg => "fw-cfg",
+            SysBusDevType::Ramfb => "ramfb",
+            SysBusDevType::PcieMem => "pcie-mem",
+            SysBusDevType::Flash => "flash",
+            SysBusDevType::Watchdog => "watchdog",
+            SysBusDevType::SifiveTest => "sifive-test",
+            SysBusDevType::PvPanic => "pvpanic",
+            SysBusDevType::Gpio => "gpio",
+            SysBusDevType::Others => "others",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "serial" => Some(SysBusDevType::Serial),
+            "rtc" => Some(SysBusDevType::Rtc),
+            "virtio-mmio" => Some(SysBusDevType::VirtioMmio),
+            #[cfg(target_arch = "riscv64")]
+            "plic" => Some(SysBusDevType::Plic),
+            #[cfg(target_arch = "riscv64")]
+            "clint" => Some(SysBusDevType::Clint),
+            "fw-cfg" => Some(SysBusDevType::FwCfg),
+            "ramfb" => Some(SysBusDevType::Ramfb),
+            "pcie-mem" => Some(SysBusDevType::PcieMem),
+            "flash" => Some(SysBusDevType::Flash),
+            "watchdog" => Some(SysBusDevType::Watchdog),
+            "sifive-test" => Some(SysBusDevType::SifiveTest),
+            "pvpanic" => Some(SysBusDevType::PvPanic),
+            "gpio" => Some(SysBusDevType::Gpio),
+            "others" => Some(SysBusDevType::Others),
+            _ => None,
+        }
+    }
+
+    /// Trace event enabling MMIO tracing of devices of this type.
+    pub fn mmio_trace_event(&self) -> String {
+        format!("{}{}", MMIO_TRACE_EVENT_PREFIX, self.name())
+    }
+
+    /// Node name and compatible string of device tree node of devices of this type,
+    /// `None` if they aren't described in device tree.
+    pub fn fdt_binding(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            SysBusDevType::Serial => Some(("uart", "ns16550a")),
+            SysBusDevType::Rtc => Some(("rtc", "google,goldfish-rtc")),
+            SysBusDevType::VirtioMmio => Some(("virtio_mmio", "virtio,mmio")),
+            #[cfg(target_arch = "riscv64")]
+            SysBusDevType::Plic => Some(("interrupt-controller", "riscv,plic0")),
+            #[cfg(target_arch = "riscv64")]
+            SysBusDevType::Clint => Some(("clint", "riscv,clint0")),
+            SysBusDevType::Flash => Some(("flash", "mtd-ram")),
+            SysBusDevType::Watchdog => Some(("watchdog", "snps,dw-wdt")),
+            SysBusDevType::SifiveTest => Some(("test", "sifive,test0")),
+            SysBusDevType::PvPanic => Some(("pvpanic", "qemu,pvpanic-mmio")),
+            SysBusDevType::Gpio => Some(("gpio", "sifive,gpio0")),
+            SysBusDevType::FwCfg => Some(("fw-cfg", "qemu,fw-cfg-mmio")),
+            SysBusDevType::Ramfb | SysBusDevType::PcieMem | SysBusDevType::Others => None,
+        }
+    }
+
+    /// Platform devices are located at fixed addresses of the board memory layout,
+    /// others must be located in the MMIO window of system bus.
+    fn is_platform(&self) -> bool {
+        match self {
+            #[cfg(target_arch = "riscv64")]
+            SysBusDevType::Plic | SysBusDevType::Clint => true,
+            SysBusDevType::Serial
+            | SysBusDevType::Rtc
+            | SysBusDevType::FwCfg
+            | SysBusDevType::Ramfb
+            | SysBusDevType::PcieMem
+            | SysBusDevType::Flash
+            | SysBusDevType::Watchdog
+            | SysBusDevType::SifiveTest
+            | SysBusDevType::PvPanic
+            | SysBusDevType::Gpio => true,
+            SysBusDevType::VirtioMmio | SysBusDevType::Others => false,
+        }
+    }
+}
+
+/// Begin the device tree node of device, with `compatible`, `reg` and `interrupts` derived
+/// from its type and system resource. Caller may add device specific properties before
+/// ending the node with the returned depth.
+///
+/// Return `None` if devices of `dev_type` aren't described in device tree.
+pub fn begin_fdt_node(
+    fdt: &mut FdtBuilder,
+    dev_type: SysBusDevType,
+    res: &SysRes,
+) -> Result<Option<u32>> {
+    let (name, compatible) = match dev_type.fdt_binding() {
+        Some(binding) => binding,
+        None => return Ok(None),
+    };
+    let node_dep = fdt.begin_node(&format!("{}@{:x}", name, res.region_base))?;
+    fdt.set_property_string("compatible", compatible)?;
+    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
+    #[cfg(target_arch = "riscv64")]
+    if res.irq >= 0 {
+        fdt.set_property_u32("interrupt-parent", device_tree::PLIC_PHANDLE)?;
+        fdt.set_property_u32("interrupts", res.irq as u32)?;
+    }
+    Ok(Some(node_dep))
+}
+
+/// Result of guest access to the registers of sysbus device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessResult {
+    Ok,
+    /// Size of the access is not supported by the register.
+    UnsupportedSize,
+    /// No register is accessible at the offset.
+    BadOffset,
+    /// Access is valid but device fails to handle it.
+    Failed,
+}
+
+impl AccessResult {
+    pub fn is_ok(&self) -> bool {
+        *self == AccessResult::Ok
+    }
+}
+
+/// Log failed access of device at warn level, return whether the access succeeds.
+fn check_access(
+    ret: AccessResult,
+    dev_type: SysBusDevType,
+    op: &str,
+    offset: u64,
+    size: usize,
+) -> bool {
+    if !ret.is_ok() {
+        warn!(
+            "Failed to {} {:?} at offset 0x{:x}, size {}: {:?}",
+            op, dev_type, offset, size, ret
+        );
+    }
+    ret.is_ok()
+}
+
+/// Operations for sysbus devices.
+pub trait SysBusDevOps: Send {
+    /// Read function of device.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A u8-type array.
+    /// * `base` - Base address of this device.
+    /// * `offset` - Offset from base address.
+    fn read(&mut self, data: &mut [u8], base: GuestAddress, offset: u64) -> AccessResult;
+
+    /// Write function of device.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A u8-type array.
+    /// * `base` - Base address of this device.
+    /// * `offset` - Offset from base address.
+    fn write(&mut self, data: &[u8], base: GuestAddress, offset: u64) -> AccessResult;
+
+    /// Read function of the region with index `region_index`, dispatched to `read` by
+    /// default for devices with single region.
+    ///
+    /// # Arguments
+    ///
+    /// * `region_index` - Index of the region which is accessed.
+    /// * `data` - A u8-type array.
+    /// * `base` - Base address of the region.
+    /// * `offset` - Offset from base address.
+    fn read_region(
+        &mut self,
+        _region_index: usize,
+        data: &mut [u8],
+        base: GuestAddress,
+        offset: u64,
+    ) -> AccessResult {
+        self.read(data, base, offset)
+    }
+
+    /// Write function of the region with index `region_index`, dispatched to `write` by
+    /// default for devices with single region.
+    ///
+    /// # Arguments
+    ///
+    /// * `region_index` - Index of the region which is accessed.
+    /// * `data` - A u8-type array.
+    /// * `base` - Base address of the region.
+    /// * `offset` - Offset from base address.
+    fn write_region(
+        &mut self,
+        _region_index: usize,
+        data: &[u8],
+        base: GuestAddress,
+        offset: u64,
+    ) -> AccessResult {
+        self.write(data, base, offset)
+    }
+
+    /// Whether the primary region prefers high MMIO window if it's allocated by system
+    /// bus, e.g. for large regions which don't fit in the low window.
+    fn prefer_high_mmio(&self) -> bool {
+        false
+    }
+
+    /// Host memory backing the primary region of ROM-like device. The region is mapped
+    /// as ROM, which is read by guest without trapping, while writes still go through
+    /// `write` unless the device is attached by `attach_device_ro`.
+    fn rom_mapping(&self) -> Option<Arc<HostMemMapping>> {
+        None
+    }
+
+    /// MMIO regions of device. The primary region (index 0) is the one passed to
+    /// `attach_device`, other entries are registered at their own fixed addresses.
+    /// Devices with single region needn't override it.
+    fn regions(&self) -> Vec<SysRes> {
+        vec![SysRes::default()]
+    }
+
+    /// Ioeventfds registered in the primary region of device. Entries with `data_match`
+    /// are only triggered by writes of `data`, e.g. queue index written to the notify
+    /// register of multi-queue devices.
+    ///
+    /// If hypervisor lacks datamatch support, entries of the same address are merged
+    /// into a single catch-all eventfd, which is the first of them.
+    fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
+        Vec::new()
+    }
+
+    fn interrupt_evt(&self) -> Option<&EventFd> {
+        None
+    }
+
+    /// Registers of the primary region whose value never changes, e.g. magic and version
+    /// of virtio-mmio. Reads of them are answered without taking the lock of device. They
+    /// are queried when device is attached and reloaded after reset.
+    fn const_registers(&self) -> Vec<ConstRegister> {
+        Vec::new()
+    }
+
+    /// Whether device needs an IRQ without owning interrupt eventfd, e.g. devices
+    /// injecting interrupt from `write()` through `SysBusIrqLine`.
+    fn needs_irq_line(&self) -> bool {
+        false
+    }
+
+    /// Receive the handle to inject IRQ of device, called when device is attached with
+    /// an IRQ allocated.
+    fn set_irq_line(&mut self, _line: SysBusIrqLine) {}
+
+    /// Allocate IRQ for device and route its interrupt eventfd to guest.
+    ///
+    /// Return the IRQ number and the way interrupt is delivered.
+    fn set_irq(&mut self, sysbus: &mut SysBus) -> SysBusResult<(i32, IrqMode)> {
+        match self.interrupt_evt() {
+            None if self.needs_irq_line() => Ok((sysbus.alloc_irq()?, IrqMode::None)),
+            None => Ok((-1_i32, IrqMode::None)),
+            Some(evt) => {
+                let irq = sysbus.alloc_irq()?;
+                match sysbus.route_irq(evt, irq) {
+                    Ok(mode) => Ok((irq, mode)),
+                    Err(e) => {
+                        sysbus.release_irq(irq);
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        None
+    }
+
+    fn set_sys_resource(
+        &mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> SysBusResult<IrqMode> {
+        // Check before allocating IRQ, which would be leaked otherwise.
+        if self.get_sys_resource().is_none() {
+            return Err(SysBusError::MissingSysResource);
+        }
+        let (irq, irq_mode) = self.set_irq(sysbus)?;
+        match self.get_sys_resource() {
+            Some(res) => {
+                res.region_base = region_base;
+                res.region_size = region_size;
+                res.irq = irq;
+                res.irq_mode = irq_mode;
+                Ok(irq_mode)
+            }
+            None => {
+                sysbus.release_irq(irq);
+                Err(SysBusError::MissingSysResource)
+            }
         }
-        bail!("Failed to get sys resource.");
     }
 
     fn get_type(&self) -> SysBusDevType {
@@ -238,6 +1506,49 @@ pub trait SysBusDevOps: Send {
     fn reset(&mut self) -> Result<()> {
         Ok(())
     }
+
+    /// Fill the device tree node of device into `parent`, the node of system bus. The
+    /// default node is built by `begin_fdt_node`, devices without system resource are
+    /// skipped.
+    fn fdt_node(&mut self, parent: &mut FdtBuilder) -> Result<()> {
+        let dev_type = self.get_type();
+        let res = match self.get_sys_resource() {
+            Some(res) => *res,
+            None => return Ok(()),
+        };
+        if let Some(node_dep) = begin_fdt_node(parent, dev_type, &res)? {
+            parent.end_node(node_dep)?;
+        }
+        Ok(())
+    }
+
+    /// Serialize device state for snapshot, built by `encode_state` with the version of
+    /// device state. Devices without state return empty bytes.
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Restore device state serialized by `state_bytes`.
+    fn restore_state(&mut self, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Pause the device when vm is stopped, e.g. freeze internal timers.
+    fn pause(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Resume the device when vm continues.
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Release resources owned by the device when vm is torn down, e.g. file
+    /// descriptors, tap interfaces and background threads. It may be called more
+    /// than once, e.g. by panic hook after vm is destroyed.
+    fn unrealize(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 // impl AmlBuilder for SysBus {
@@ -250,3 +1561,1474 @@ pub trait SysBusDevOps: Send {
 //         scope.aml_bytes()
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::Once;
+
+    use address_space::AddressRange;
+    use util::device_tree::find_property;
+    use util::test_helper::set_test_enabled;
+    use util::trace::set_trace_event_enabled;
+
+    use super::*;
+
+    const TEST_MMIO_BASE: u64 = 0x1000_0000;
+    const TEST_MMIO_SIZE: u64 = 0x1000;
+
+    struct TestDevice {
+        res: SysRes,
+        interrupt_evt: EventFd,
+        reset_count: u32,
+        reset_fails: bool,
+        prefer_high: bool,
+        dev_type: SysBusDevType,
+    }
+
+    impl TestDevice {
+        fn new() -> Self {
+            TestDevice {
+                res: SysRes::default(),
+                interrupt_evt: EventFd::new(0).unwrap(),
+                reset_count: 0,
+                reset_fails: false,
+                prefer_high: false,
+                dev_type: SysBusDevType::Others,
+            }
+        }
+    }
+
+    impl SysBusDevOps for TestDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn interrupt_evt(&self) -> Option<&EventFd> {
+            Some(&self.interrupt_evt)
+        }
+
+        fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+            Some(&mut self.res)
+        }
+
+        fn reset(&mut self) -> Result<()> {
+            self.reset_count += 1;
+            if self.reset_fails {
+                bail!("device busy");
+            }
+            Ok(())
+        }
+
+        fn prefer_high_mmio(&self) -> bool {
+            self.prefer_high
+        }
+
+        fn get_type(&self) -> SysBusDevType {
+            self.dev_type
+        }
+    }
+
+    fn sysbus_init() -> SysBus {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        SysBus::new_single_window(
+            &sys_mem,
+            (1, 4),
+            (TEST_MMIO_BASE, TEST_MMIO_BASE + 0x10_0000),
+        )
+        .unwrap()
+    }
+
+    fn attach(sysbus: &mut SysBus, base: u64) -> Arc<Mutex<TestDevice>> {
+        let mut dev = TestDevice::new();
+        dev.set_sys_resource(sysbus, base, TEST_MMIO_SIZE).unwrap();
+        let dev = Arc::new(Mutex::new(dev));
+        sysbus.attach_device(&dev, Some(base), TEST_MMIO_SIZE).unwrap();
+        dev
+    }
+
+    fn mmio_mapped(sysbus: &SysBus, addr: u64) -> bool {
+        let mut buf = [0_u8; 4];
+        sysbus
+            .sys_mem
+            .read(&mut buf.as_mut(), GuestAddress(addr), 4)
+            .is_ok()
+    }
+
+    #[test]
+    fn test_new_validation() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let window = (TEST_MMIO_BASE, TEST_MMIO_BASE + 0x1000);
+        assert!(matches!(
+            SysBus::new_single_window(&sys_mem, (4, 1), window),
+            Err(SysBusError::InvalidIrqRange { start: 4, end: 1 })
+        ));
+        assert!(matches!(
+            SysBus::new_single_window(&sys_mem, (0, 4), window),
+            Err(SysBusError::IrqRangeOutOfBounds { .. })
+        ));
+        assert!(matches!(
+            SysBus::new_single_window(&sys_mem, (1, IRQ_MAX + 1), window),
+            Err(SysBusError::IrqRangeOutOfBounds { .. })
+        ));
+        assert!(matches!(
+            SysBus::new_single_window(&sys_mem, (1, 4), (TEST_MMIO_BASE, TEST_MMIO_BASE)),
+            Err(SysBusError::InvalidMmioWindow { .. })
+        ));
+        let windows = vec![window, (TEST_MMIO_BASE + 0x800, TEST_MMIO_BASE + 0x2000)];
+        assert!(matches!(
+            SysBus::new(&sys_mem, (1, 4), windows),
+            Err(SysBusError::MmioWindowOverlap { .. })
+        ));
+
+        // Window overlapping with RAM.
+        let base = GuestAddress(TEST_MMIO_BASE);
+        let ram =
+            Arc::new(HostMemMapping::new(base, None, 0x1000, None, false, false, false).unwrap());
+        sys_mem
+            .root()
+            .add_subregion(Region::init_ram_region(ram), TEST_MMIO_BASE)
+            .unwrap();
+        let err = SysBus::new_single_window(&sys_mem, (1, 4), window).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "MMIO window [0x10000000, 0x10001000) overlaps with guest RAM"
+        );
+
+        // Explicitly empty system bus has nothing to allocate.
+        let mut sysbus = SysBus::new(&sys_mem, EMPTY_IRQ_RANGE, Vec::new()).unwrap();
+        assert!(sysbus.alloc_irq().is_err());
+        assert!(sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_detach_device() {
+        let mut sysbus = sysbus_init();
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE);
+        let res = dev.lock().unwrap().res;
+        assert_eq!(res.region_base, TEST_MMIO_BASE);
+        assert_eq!(res.irq, 1);
+        assert_eq!(sysbus.devices.len(), 1);
+        assert!(mmio_mapped(&sysbus, TEST_MMIO_BASE));
+
+        sysbus.detach_device(&dev).unwrap();
+        assert!(sysbus.devices.is_empty());
+        assert!(!mmio_mapped(&sysbus, TEST_MMIO_BASE));
+        assert_eq!(dev.lock().unwrap().res.irq, -1);
+        // Detach the same device twice is not allowed.
+        assert!(sysbus.detach_device(&dev).is_err());
+
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE);
+        let res = dev.lock().unwrap().res;
+        assert_eq!(res.region_base, TEST_MMIO_BASE);
+        assert_eq!(res.irq, 1);
+        assert!(mmio_mapped(&sysbus, TEST_MMIO_BASE));
+        assert_eq!(sysbus.min_free_irq, 2);
+        assert!(sysbus.released_irqs.is_empty());
+    }
+
+    #[test]
+    fn test_reserve_mmio_after_detach() {
+        let mut sysbus = sysbus_init();
+        let base = sysbus
+            .allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE)
+            .unwrap();
+        let dev = attach(&mut sysbus, base);
+        sysbus.detach_device(&dev).unwrap();
+        assert!(!sysbus.mmio_ranges.contains_key(&base));
+
+        sysbus.reserve_mmio(base, TEST_MMIO_SIZE).unwrap();
+        // The reserved range is skipped by allocation but is available for attach.
+        let other = sysbus
+            .allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE)
+            .unwrap();
+        assert_ne!(other, base);
+        attach(&mut sysbus, base);
+        assert!(mmio_mapped(&sysbus, base));
+
+        // Occupied ranges and ranges out of windows can't be reserved.
+        assert!(sysbus.reserve_mmio(base, TEST_MMIO_SIZE).is_err());
+        assert!(sysbus.reserve_mmio(0, TEST_MMIO_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_irq_reuse_after_detach() {
+        let mut sysbus = sysbus_init();
+        let devs: Vec<Arc<Mutex<TestDevice>>> = (0..4)
+            .map(|i| attach(&mut sysbus, TEST_MMIO_BASE + i * TEST_MMIO_SIZE))
+            .collect();
+        // All of the IRQs in range (1, 4) are used.
+        assert!(sysbus.alloc_irq().is_err());
+
+        sysbus.detach_device(&devs[1]).unwrap();
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE + TEST_MMIO_SIZE);
+        assert_eq!(dev.lock().unwrap().res.irq, 2);
+        assert!(sysbus.alloc_irq().is_err());
+    }
+
+    #[test]
+    fn test_allocate_mmio_align() {
+        let mut sysbus = sysbus_init();
+        // Alignment larger than size.
+        let base = sysbus.allocate_mmio(0x100, 0x1000).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE);
+        let base = sysbus.allocate_mmio(0x100, 0x1000).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE + 0x1000);
+        // Small allocation fills the gap left by the previous one.
+        let base = sysbus.allocate_mmio(0x100, 0x100).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE + 0x100);
+        let base = sysbus.allocate_mmio(0x4000, 0x4000).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE + 0x4000);
+
+        assert!(sysbus.allocate_mmio(0, 0x1000).is_err());
+        assert!(sysbus.allocate_mmio(0x1000, 0x1001).is_err());
+    }
+
+    #[test]
+    fn test_allocate_mmio_exhausted() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let window = (TEST_MMIO_BASE, TEST_MMIO_BASE + 0x3000);
+        let mut sysbus = SysBus::new_single_window(&sys_mem, (1, 4), window).unwrap();
+        for i in 0..3 {
+            let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+            assert_eq!(base, TEST_MMIO_BASE + i * TEST_MMIO_SIZE);
+        }
+        assert!(sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).is_err());
+        assert!(sysbus.allocate_mmio(1, 1).is_err());
+
+        let window = (u64::max_value() - 0xfff, u64::max_value());
+        let mut sysbus = SysBus::new_single_window(&sys_mem, (1, 4), window).unwrap();
+        assert!(sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_allocate_mmio_no_overlap() {
+        let mut sysbus = sysbus_init();
+        attach(&mut sysbus, TEST_MMIO_BASE);
+        attach(&mut sysbus, TEST_MMIO_BASE + 2 * TEST_MMIO_SIZE);
+
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE + TEST_MMIO_SIZE);
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE + 3 * TEST_MMIO_SIZE);
+
+        // Base allocated by system bus itself.
+        let dev = Arc::new(Mutex::new(TestDevice::new()));
+        sysbus.attach_device(&dev, None, TEST_MMIO_SIZE).unwrap();
+        let res = dev.lock().unwrap().res;
+        assert_eq!(res.region_base, TEST_MMIO_BASE + 4 * TEST_MMIO_SIZE);
+        assert_eq!(res.region_size, TEST_MMIO_SIZE);
+        assert!(mmio_mapped(&sysbus, res.region_base));
+
+        // The range is free to allocate again after detached.
+        sysbus.detach_device(&dev).unwrap();
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE + 4 * TEST_MMIO_SIZE);
+    }
+
+    #[test]
+    fn test_release_mmio() {
+        let mut sysbus = sysbus_init();
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert!(sysbus.release_mmio(base));
+        assert!(!sysbus.release_mmio(base));
+        assert_eq!(
+            sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap(),
+            base
+        );
+
+        // Range of attached device is kept.
+        let dev = Arc::new(Mutex::new(TestDevice::new()));
+        sysbus.attach_device(&dev, Some(base), TEST_MMIO_SIZE).unwrap();
+        assert!(!sysbus.release_mmio(base));
+        assert!(mmio_mapped(&sysbus, base));
+        assert_ne!(
+            sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap(),
+            base
+        );
+    }
+
+    fn try_attach(sysbus: &mut SysBus, base: u64, size: u64) -> SysBusResult<()> {
+        let dev = Arc::new(Mutex::new(TestDevice::new()));
+        sysbus.attach_device(&dev, Some(base), size)
+    }
+
+    #[test]
+    fn test_attach_overlap() {
+        let mut sysbus = sysbus_init();
+        attach(&mut sysbus, TEST_MMIO_BASE + TEST_MMIO_SIZE);
+
+        // Exact overlap.
+        let err = try_attach(&mut sysbus, TEST_MMIO_BASE + TEST_MMIO_SIZE, TEST_MMIO_SIZE)
+            .unwrap_err();
+        match err {
+            SysBusError::AddressOverlap {
+                base,
+                other_base,
+                ref other_type,
+                ..
+            } => {
+                assert_eq!(base, TEST_MMIO_BASE + TEST_MMIO_SIZE);
+                assert_eq!(other_base, TEST_MMIO_BASE + TEST_MMIO_SIZE);
+                assert_eq!(other_type, "Others");
+            }
+            _ => panic!("Unexpected error: {:?}", err),
+        }
+
+        // Partial overlap at the head and the tail.
+        assert!(try_attach(&mut sysbus, TEST_MMIO_BASE + 0x800, TEST_MMIO_SIZE).is_err());
+        assert!(try_attach(&mut sysbus, TEST_MMIO_BASE + 0x1800, TEST_MMIO_SIZE).is_err());
+        // Range covering the whole attached one.
+        assert!(try_attach(&mut sysbus, TEST_MMIO_BASE, 3 * TEST_MMIO_SIZE).is_err());
+        assert!(try_attach(&mut sysbus, TEST_MMIO_BASE + 0x1100, 0x100).is_err());
+        assert_eq!(sysbus.devices.len(), 1);
+
+        // Adjacent ranges on both sides are allowed.
+        try_attach(&mut sysbus, TEST_MMIO_BASE, TEST_MMIO_SIZE).unwrap();
+        try_attach(&mut sysbus, TEST_MMIO_BASE + 2 * TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert_eq!(sysbus.devices.len(), 3);
+    }
+
+    #[test]
+    fn test_attach_reserved_range() {
+        let mut sysbus = sysbus_init();
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        // Range with different size is overlapped with the reserved one.
+        assert!(try_attach(&mut sysbus, base, 0x100).is_err());
+        try_attach(&mut sysbus, base, TEST_MMIO_SIZE).unwrap();
+        assert!(try_attach(&mut sysbus, base, TEST_MMIO_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_attach_out_of_window() {
+        let mut sysbus = sysbus_init();
+        let window_end = sysbus.mmio_windows[0].1;
+        for (base, size) in [
+            (TEST_MMIO_BASE - TEST_MMIO_SIZE, TEST_MMIO_SIZE),
+            (TEST_MMIO_BASE - 0x800, TEST_MMIO_SIZE),
+            (window_end - 0x800, TEST_MMIO_SIZE),
+            (window_end, TEST_MMIO_SIZE),
+        ] {
+            let err = try_attach(&mut sysbus, base, size).unwrap_err();
+            assert!(matches!(
+                err,
+                SysBusError::AddressOutOfWindow { .. }
+            ));
+        }
+        assert!(try_attach(&mut sysbus, u64::max_value() - 0xff, TEST_MMIO_SIZE).is_err());
+        assert!(sysbus.devices.is_empty());
+        try_attach(&mut sysbus, window_end - TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+    }
+
+    #[test]
+    fn test_multiple_mmio_windows() {
+        const HIGH_MMIO_BASE: u64 = 0x1_0000_0000;
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let mut sysbus = SysBus::new(
+            &sys_mem,
+            (1, 4),
+            vec![
+                (HIGH_MMIO_BASE, HIGH_MMIO_BASE + 0x100_0000),
+                (TEST_MMIO_BASE, TEST_MMIO_BASE + 0x2000),
+            ],
+        )
+        .unwrap();
+        assert_eq!(sysbus.mmio_windows[0].0, TEST_MMIO_BASE);
+
+        // Large range doesn't fit in the low window.
+        let base = sysbus.allocate_mmio(0x10_0000, 0x10_0000).unwrap();
+        assert_eq!(base, HIGH_MMIO_BASE);
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE);
+        let base = sysbus
+            .allocate_mmio_hinted(TEST_MMIO_SIZE, TEST_MMIO_SIZE, true)
+            .unwrap();
+        assert_eq!(base, HIGH_MMIO_BASE + 0x10_0000);
+
+        let mut dev = TestDevice::new();
+        dev.prefer_high = true;
+        let dev = Arc::new(Mutex::new(dev));
+        sysbus.attach_device(&dev, None, TEST_MMIO_SIZE).unwrap();
+        let base = dev.lock().unwrap().res.region_base;
+        assert_eq!(base, HIGH_MMIO_BASE + 0x10_0000 + TEST_MMIO_SIZE);
+
+        // Low window is exhausted, fall back to the high one.
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert_eq!(base, TEST_MMIO_BASE + TEST_MMIO_SIZE);
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        assert_eq!(base, HIGH_MMIO_BASE + 0x10_0000 + 2 * TEST_MMIO_SIZE);
+
+        // Ranges are validated against all windows.
+        let err = try_attach(&mut sysbus, HIGH_MMIO_BASE, TEST_MMIO_SIZE).unwrap_err();
+        assert!(matches!(err, SysBusError::AddressOverlap { .. }));
+        let err = try_attach(&mut sysbus, TEST_MMIO_BASE + 0x2000, TEST_MMIO_SIZE).unwrap_err();
+        match err {
+            SysBusError::AddressOutOfWindow { window_base, .. } => {
+                assert_eq!(window_base, TEST_MMIO_BASE)
+            }
+            _ => panic!("Unexpected error: {:?}", err),
+        }
+        try_attach(&mut sysbus, HIGH_MMIO_BASE + 0x20_0000, TEST_MMIO_SIZE).unwrap();
+        assert!(!sysbus.in_mmio_window(HIGH_MMIO_BASE - 0x800, TEST_MMIO_SIZE));
+
+        assert!(sysbus.mmio_overlaps(HIGH_MMIO_BASE - 0x800, TEST_MMIO_SIZE));
+        assert!(sysbus.mmio_overlaps(TEST_MMIO_BASE + 0x1800, 0x1000));
+        assert!(!sysbus.mmio_overlaps(TEST_MMIO_BASE + 0x2000, 0x1000));
+
+        // RAM plugged after the system bus is created.
+        let ram_base = HIGH_MMIO_BASE + 0x80_0000;
+        let addr = GuestAddress(ram_base);
+        let ram =
+            Arc::new(HostMemMapping::new(addr, None, 0x1000, None, false, false, false).unwrap());
+        sys_mem
+            .root()
+            .add_subregion(Region::init_ram_region(ram), ram_base)
+            .unwrap();
+        let err = try_attach(&mut sysbus, ram_base, TEST_MMIO_SIZE).unwrap_err();
+        assert!(matches!(err, SysBusError::RamOverlap { .. }));
+    }
+
+    #[derive(Default)]
+    struct TestRouter {
+        irqfd_supported: bool,
+        irqfd_fails: bool,
+        irqfd: Mutex<Vec<(RawFd, i32)>>,
+        polling: Mutex<Vec<(RawFd, i32)>>,
+        triggered: Mutex<Vec<i32>>,
+        levels: Mutex<Vec<(i32, u64, bool)>>,
+    }
+
+    impl IrqRouter for TestRouter {
+        fn irqfd_supported(&self) -> bool {
+            self.irqfd_supported
+        }
+
+        fn register_irqfd(&self, evt: &EventFd, irq: i32) -> Result<()> {
+            if self.irqfd_fails {
+                bail!("irqfd is not available");
+            }
+            self.irqfd.lock().unwrap().push((evt.as_raw_fd(), irq));
+            Ok(())
+        }
+
+        fn register_polling(&self, evt: &EventFd, irq: i32) -> Result<()> {
+            self.polling.lock().unwrap().push((evt.as_raw_fd(), irq));
+            Ok(())
+        }
+
+        fn unregister(&self, evt: &EventFd, irq: i32, mode: IrqMode) -> Result<()> {
+            let registered = match mode {
+                IrqMode::Irqfd => &self.irqfd,
+                IrqMode::Polling => &self.polling,
+                IrqMode::None => bail!("irq {} is not routed", irq),
+            };
+            registered
+                .lock()
+                .unwrap()
+                .retain(|entry| *entry != (evt.as_raw_fd(), irq));
+            Ok(())
+        }
+
+        fn trigger_irq(&self, irq: i32) -> Result<()> {
+            self.triggered.lock().unwrap().push(irq);
+            Ok(())
+        }
+
+        fn set_irq_level(&self, irq: i32, source: u64, level: bool) -> Result<()> {
+            self.levels.lock().unwrap().push((irq, source, level));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_set_irq_irqfd() {
+        let mut sysbus = sysbus_init();
+        let router = Arc::new(TestRouter {
+            irqfd_supported: true,
+            ..Default::default()
+        });
+        sysbus.set_irq_router(router.clone());
+
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE);
+        let locked_dev = dev.lock().unwrap();
+        let fd = locked_dev.interrupt_evt.as_raw_fd();
+        assert_eq!(locked_dev.res.irq_mode, IrqMode::Irqfd);
+        assert_eq!(*router.irqfd.lock().unwrap(), vec![(fd, 1)]);
+        assert!(router.polling.lock().unwrap().is_empty());
+        drop(locked_dev);
+
+        sysbus.detach_device(&dev).unwrap();
+        assert!(router.irqfd.lock().unwrap().is_empty());
+        assert_eq!(dev.lock().unwrap().res.irq_mode, IrqMode::None);
+    }
+
+    #[test]
+    fn test_set_irq_polling_fallback() {
+        let mut sysbus = sysbus_init();
+        let router = Arc::new(TestRouter {
+            irqfd_supported: true,
+            irqfd_fails: true,
+            ..Default::default()
+        });
+        sysbus.set_irq_router(router.clone());
+
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE);
+        let fd = dev.lock().unwrap().interrupt_evt.as_raw_fd();
+        assert_eq!(dev.lock().unwrap().res.irq_mode, IrqMode::Polling);
+        assert!(router.irqfd.lock().unwrap().is_empty());
+        assert_eq!(*router.polling.lock().unwrap(), vec![(fd, 1)]);
+
+        sysbus.detach_device(&dev).unwrap();
+        assert!(router.polling.lock().unwrap().is_empty());
+
+        // Irqfd is not supported by hypervisor.
+        let router = Arc::new(TestRouter::default());
+        sysbus.set_irq_router(router.clone());
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE);
+        assert_eq!(dev.lock().unwrap().res.irq_mode, IrqMode::Polling);
+        assert_eq!(router.polling.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_set_irq_without_router() {
+        let mut sysbus = sysbus_init();
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE);
+        let res = dev.lock().unwrap().res;
+        assert_eq!(res.irq, 1);
+        assert_eq!(res.irq_mode, IrqMode::None);
+    }
+
+    /// Device without interrupt eventfd, injecting interrupt when its register is written:
+    /// 0 for an edge, 1 and 2 for asserting and deasserting the level.
+    #[derive(Default)]
+    struct SyncIrqDevice {
+        res: SysRes,
+        irq_line: Option<SysBusIrqLine>,
+    }
+
+    impl SysBusDevOps for SyncIrqDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            let line = match &self.irq_line {
+                Some(line) => line,
+                None => return AccessResult::Failed,
+            };
+            let ret = match data[0] {
+                0 => line.pulse(),
+                1 => line.set_level(true),
+                2 => line.set_level(false),
+                _ => return AccessResult::BadOffset,
+            };
+            match ret {
+                Ok(()) => AccessResult::Ok,
+                Err(_) => AccessResult::Failed,
+            }
+        }
+
+        fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+            Some(&mut self.res)
+        }
+
+        fn needs_irq_line(&self) -> bool {
+            true
+        }
+
+        fn set_irq_line(&mut self, line: SysBusIrqLine) {
+            self.irq_line = Some(line);
+        }
+    }
+
+    #[test]
+    fn test_irq_line() {
+        let mut sysbus = sysbus_init();
+        let router = Arc::new(TestRouter::default());
+        sysbus.set_irq_router(router.clone());
+
+        let mut dev = SyncIrqDevice::default();
+        dev.set_sys_resource(&mut sysbus, TEST_MMIO_BASE, TEST_MMIO_SIZE)
+            .unwrap();
+        let dev = Arc::new(Mutex::new(dev));
+        sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+        let irq = dev.lock().unwrap().res.irq;
+        assert_eq!(irq, 1);
+        assert_eq!(dev.lock().unwrap().irq_line.as_ref().unwrap().irq(), irq);
+        assert!(router.polling.lock().unwrap().is_empty());
+
+        let write = |value: u8| {
+            sysbus
+                .sys_mem
+                .write_object(&value, GuestAddress(TEST_MMIO_BASE))
+                .unwrap()
+        };
+        write(0);
+        assert_eq!(*router.triggered.lock().unwrap(), vec![irq]);
+        write(1);
+        write(1);
+        assert_eq!(
+            router.levels.lock().unwrap().last(),
+            Some(&(irq, TEST_MMIO_BASE, true))
+        );
+
+        // Stale assert is cleared by reset.
+        sysbus.reset_all().unwrap();
+        assert_eq!(
+            router.levels.lock().unwrap().last(),
+            Some(&(irq, TEST_MMIO_BASE, false))
+        );
+        let count = router.levels.lock().unwrap().len();
+        sysbus.reset_all().unwrap();
+        assert_eq!(router.levels.lock().unwrap().len(), count);
+
+        // Device gets no line without router.
+        let mut sysbus = sysbus_init();
+        let mut dev = SyncIrqDevice::default();
+        dev.set_sys_resource(&mut sysbus, TEST_MMIO_BASE, TEST_MMIO_SIZE)
+            .unwrap();
+        let dev = Arc::new(Mutex::new(dev));
+        sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+        assert_eq!(dev.lock().unwrap().res.irq, 1);
+        assert!(dev.lock().unwrap().irq_line.is_none());
+    }
+
+    #[test]
+    fn test_reset_all() {
+        let mut sysbus = sysbus_init();
+        let devs: Vec<Arc<Mutex<TestDevice>>> = (0..3)
+            .map(|i| attach(&mut sysbus, TEST_MMIO_BASE + i * TEST_MMIO_SIZE))
+            .collect();
+        sysbus.reset_all().unwrap();
+        for dev in devs.iter() {
+            assert_eq!(dev.lock().unwrap().reset_count, 1);
+        }
+
+        devs[0].lock().unwrap().reset_fails = true;
+        devs[1].lock().unwrap().reset_fails = true;
+        let err = sysbus.reset_all().unwrap_err();
+        assert_eq!(err.to_string().matches("device busy").count(), 2);
+        // Failed devices don't prevent the rest from being reset.
+        for dev in devs.iter() {
+            assert_eq!(dev.lock().unwrap().reset_count, 2);
+        }
+    }
+
+    struct MultiRegionDevice {
+        res: SysRes,
+        doorbell: SysRes,
+        accessed: Vec<(usize, u64)>,
+    }
+
+    impl SysBusDevOps for MultiRegionDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::BadOffset
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::BadOffset
+        }
+
+        fn read_region(
+            &mut self,
+            region_index: usize,
+            _data: &mut [u8],
+            _base: GuestAddress,
+            offset: u64,
+        ) -> AccessResult {
+            self.accessed.push((region_index, offset));
+            AccessResult::Ok
+        }
+
+        fn regions(&self) -> Vec<SysRes> {
+            vec![self.res, self.doorbell]
+        }
+
+        fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+            Some(&mut self.res)
+        }
+    }
+
+    #[test]
+    fn test_attach_multiple_regions() {
+        let mut sysbus = sysbus_init();
+        let doorbell_base = TEST_MMIO_BASE + 0x8000;
+        let dev = Arc::new(Mutex::new(MultiRegionDevice {
+            res: SysRes::default(),
+            doorbell: SysRes {
+                region_base: doorbell_base,
+                region_size: 0x10,
+                region_index: 1,
+                ..Default::default()
+            },
+            accessed: Vec::new(),
+        }));
+        sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+        assert_eq!(sysbus.mmio_ranges.len(), 2);
+
+        assert!(mmio_mapped(&sysbus, TEST_MMIO_BASE + 0x8));
+        assert!(mmio_mapped(&sysbus, doorbell_base + 0x4));
+        assert_eq!(dev.lock().unwrap().accessed, vec![(0, 0x8), (1, 0x4)]);
+
+        // Doorbell region is released on detaching.
+        sysbus.detach_device(&dev).unwrap();
+        assert!(sysbus.mmio_ranges.is_empty());
+        assert!(!mmio_mapped(&sysbus, doorbell_base));
+    }
+
+    #[test]
+    fn test_attach_multiple_regions_overlap() {
+        let mut sysbus = sysbus_init();
+        try_attach(&mut sysbus, TEST_MMIO_BASE + 0x8000, TEST_MMIO_SIZE).unwrap();
+        let dev = Arc::new(Mutex::new(MultiRegionDevice {
+            res: SysRes::default(),
+            doorbell: SysRes {
+                region_base: TEST_MMIO_BASE + 0x8000,
+                region_size: 0x10,
+                region_index: 1,
+                ..Default::default()
+            },
+            accessed: Vec::new(),
+        }));
+        assert!(sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .is_err());
+        // The primary region is rolled back.
+        assert!(!mmio_mapped(&sysbus, TEST_MMIO_BASE));
+        assert_eq!(sysbus.mmio_ranges.len(), 1);
+        assert_eq!(sysbus.devices.len(), 1);
+    }
+
+    fn attach_shared(sysbus: &mut SysBus, base: u64, irq: i32) -> Arc<Mutex<TestDevice>> {
+        let mut dev = TestDevice::new();
+        let irq_mode = sysbus
+            .request_shared_irq(irq, &dev.interrupt_evt)
+            .unwrap();
+        dev.res = SysRes {
+            region_base: base,
+            region_size: TEST_MMIO_SIZE,
+            irq,
+            irq_mode,
+            ..Default::default()
+        };
+        let dev = Arc::new(Mutex::new(dev));
+        sysbus.attach_device(&dev, Some(base), TEST_MMIO_SIZE).unwrap();
+        dev
+    }
+
+    #[test]
+    fn test_request_shared_irq() {
+        let mut sysbus = sysbus_init();
+        let evt = EventFd::new(0).unwrap();
+        // IRQ not allocated yet can't be shared.
+        assert!(sysbus.request_shared_irq(1, &evt).is_err());
+
+        let dev = attach(&mut sysbus, TEST_MMIO_BASE);
+        let sharer = attach_shared(&mut sysbus, TEST_MMIO_BASE + TEST_MMIO_SIZE, 1);
+        assert_eq!(sharer.lock().unwrap().res.irq, 1);
+        assert_eq!(sysbus.alloc_irq().unwrap(), 2);
+
+        // The line is kept until the last sharer is detached.
+        sysbus.detach_device(&dev).unwrap();
+        assert!(sysbus.released_irqs.is_empty());
+        let another = attach_shared(&mut sysbus, TEST_MMIO_BASE, 1);
+        sysbus.detach_device(&sharer).unwrap();
+        assert!(sysbus.released_irqs.is_empty());
+        sysbus.detach_device(&another).unwrap();
+        assert!(sysbus.released_irqs.contains(&1));
+        assert!(sysbus.request_shared_irq(1, &evt).is_err());
+    }
+
+    #[test]
+    fn test_request_shared_irq_routed() {
+        let mut sysbus = sysbus_init();
+        let router = Arc::new(TestRouter {
+            irqfd_supported: true,
+            ..Default::default()
+        });
+        sysbus.set_irq_router(router.clone());
+
+        attach(&mut sysbus, TEST_MMIO_BASE);
+        let sharer = attach_shared(&mut sysbus, TEST_MMIO_BASE + TEST_MMIO_SIZE, 1);
+        assert_eq!(sharer.lock().unwrap().res.irq_mode, IrqMode::Irqfd);
+        assert_eq!(router.irqfd.lock().unwrap().len(), 2);
+
+        sysbus.detach_device(&sharer).unwrap();
+        assert_eq!(router.irqfd.lock().unwrap().len(), 1);
+    }
+
+    struct NoResDevice;
+
+    impl SysBusDevOps for NoResDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+    }
+
+    /// Device wanting an IRQ line but having no system resource.
+    struct NoResIrqDevice;
+
+    impl SysBusDevOps for NoResIrqDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn needs_irq_line(&self) -> bool {
+            true
+        }
+    }
+
+    struct NotifyDevice {
+        queue_evts: Vec<Arc<EventFd>>,
+        /// Queue indexes written to the notify register which reach the device.
+        trapped: Arc<Mutex<Vec<u32>>>,
+    }
+
+    const NOTIFY_OFFSET: u64 = 0x50;
+
+    impl SysBusDevOps for NotifyDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+            if offset == NOTIFY_OFFSET && data.len() == 4 {
+                let index = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                self.trapped.lock().unwrap().push(index);
+                if let Some(evt) = self.queue_evts.get(index as usize) {
+                    evt.write(1).unwrap();
+                }
+            }
+            AccessResult::Ok
+        }
+
+        fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
+            self.queue_evts
+                .iter()
+                .enumerate()
+                .map(|(index, evt)| RegionIoEventFd {
+                    fd: evt.clone(),
+                    addr_range: AddressRange::from((NOTIFY_OFFSET, 4)),
+                    data_match: true,
+                    data: index as u64,
+                })
+                .collect()
+        }
+    }
+
+    fn attach_notify_device(sysbus: &mut SysBus) -> (Vec<Arc<EventFd>>, Arc<Mutex<Vec<u32>>>) {
+        static TEST_ENABLED: Once = Once::new();
+        TEST_ENABLED.call_once(set_test_enabled);
+
+        let queue_evts: Vec<Arc<EventFd>> = (0..3)
+            .map(|_| Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()))
+            .collect();
+        let trapped = Arc::new(Mutex::new(Vec::new()));
+        let dev = Arc::new(Mutex::new(NotifyDevice {
+            queue_evts: queue_evts.clone(),
+            trapped: trapped.clone(),
+        }));
+        sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+        (queue_evts, trapped)
+    }
+
+    fn notify(sysbus: &SysBus, queue_index: u32) {
+        sysbus
+            .sys_mem
+            .write(
+                &mut queue_index.to_le_bytes().as_ref(),
+                GuestAddress(TEST_MMIO_BASE + NOTIFY_OFFSET),
+                4,
+            )
+            .unwrap();
+    }
+
+    fn fired(evts: &[Arc<EventFd>]) -> Vec<usize> {
+        evts.iter()
+            .enumerate()
+            .filter(|(_, evt)| evt.read().is_ok())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    #[test]
+    fn test_ioeventfd_datamatch() {
+        let mut sysbus = sysbus_init();
+        let (queue_evts, trapped) = attach_notify_device(&mut sysbus);
+
+        for index in [2, 0, 1] {
+            notify(&sysbus, index);
+            assert_eq!(fired(&queue_evts), vec![index as usize]);
+        }
+        // Unknown queue index is handled by device.
+        notify(&sysbus, 5);
+        assert!(fired(&queue_evts).is_empty());
+        assert_eq!(*trapped.lock().unwrap(), vec![5]);
+    }
+
+    #[test]
+    fn test_ioeventfd_unsupported() {
+        let mut sysbus = sysbus_init();
+        sysbus.set_ioeventfd_supported(false);
+        let (queue_evts, trapped) = attach_notify_device(&mut sysbus);
+
+        // All notifications reach the device, which wakes the queue written.
+        for index in [2, 0, 1] {
+            notify(&sysbus, index);
+            assert_eq!(fired(&queue_evts), vec![index as usize]);
+        }
+        notify(&sysbus, 5);
+        assert!(fired(&queue_evts).is_empty());
+        assert_eq!(*trapped.lock().unwrap(), vec![2, 0, 1, 5]);
+    }
+
+    #[test]
+    fn test_iter_resources() {
+        let mut sysbus = sysbus_init();
+        attach(&mut sysbus, TEST_MMIO_BASE);
+        sysbus
+            .attach_dynamic_device(&Arc::new(Mutex::new(NoResDevice)))
+            .unwrap();
+        let base = sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE).unwrap();
+        attach(&mut sysbus, base);
+
+        let resources: Vec<(SysBusDevType, Option<SysRes>)> = sysbus.iter_resources().collect();
+        assert_eq!(resources.len(), sysbus.devices.len());
+        let res = resources[0].1.unwrap();
+        assert_eq!(resources[0].0, SysBusDevType::Others);
+        assert_eq!((res.region_base, res.region_size, res.irq), (TEST_MMIO_BASE, TEST_MMIO_SIZE, 1));
+        assert!(resources[1].1.is_none());
+        let res = resources[2].1.unwrap();
+        assert_eq!((res.region_base, res.irq), (base, 2));
+    }
+
+    #[test]
+    fn test_generate_fdt_nodes() {
+        let mut sysbus = sysbus_init();
+        attach(&mut sysbus, TEST_MMIO_BASE).lock().unwrap().dev_type = SysBusDevType::VirtioMmio;
+        sysbus
+            .attach_dynamic_device(&Arc::new(Mutex::new(NoResDevice)))
+            .unwrap();
+        let base = sysbus
+            .allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE)
+            .unwrap();
+        attach(&mut sysbus, base).lock().unwrap().dev_type = SysBusDevType::Serial;
+        // Devices of type without device tree binding get no node.
+        let others_base = sysbus
+            .allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE)
+            .unwrap();
+        attach(&mut sysbus, others_base);
+
+        let mut fdt = FdtBuilder::new();
+        let root_node = fdt.begin_node("").unwrap();
+        let soc_node = fdt.begin_node("soc").unwrap();
+        sysbus.generate_fdt_nodes(&mut fdt).unwrap();
+        fdt.end_node(soc_node).unwrap();
+        fdt.end_node(root_node).unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let mut nodes = 0;
+        for (dev_type, res) in sysbus.iter_resources() {
+            let (res, (name, compatible)) = match (res, dev_type.fdt_binding()) {
+                (Some(res), Some(binding)) => (res, binding),
+                _ => continue,
+            };
+            let node = format!("/soc/{}@{:x}", name, res.region_base);
+            let reg = find_property(&dtb, &node, "reg").unwrap().unwrap();
+            let mut expected = res.region_base.to_be_bytes().to_vec();
+            expected.extend_from_slice(&res.region_size.to_be_bytes());
+            assert_eq!(reg, expected);
+            let mut expected = compatible.as_bytes().to_vec();
+            expected.push(0);
+            assert_eq!(
+                find_property(&dtb, &node, "compatible").unwrap().unwrap(),
+                expected
+            );
+            #[cfg(target_arch = "riscv64")]
+            assert_eq!(
+                find_property(&dtb, &node, "interrupts").unwrap().unwrap(),
+                (res.irq as u32).to_be_bytes().to_vec()
+            );
+            nodes += 1;
+        }
+        assert_eq!(nodes, 2);
+        assert!(
+            find_property(&dtb, &format!("/soc/others@{:x}", others_base), "reg")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    struct RomDevice {
+        content: [u8; 4],
+    }
+
+    impl SysBusDevOps for RomDevice {
+        fn read(&mut self, data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            data.copy_from_slice(&self.content[..data.len()]);
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            self.content[..data.len()].copy_from_slice(data);
+            AccessResult::Ok
+        }
+    }
+
+    #[test]
+    fn test_attach_device_ro() {
+        let mut sysbus = sysbus_init();
+        let dev = Arc::new(Mutex::new(RomDevice { content: [0x5a; 4] }));
+        sysbus
+            .attach_device_ro(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+
+        // Guest write is dropped without reaching the device.
+        sysbus
+            .sys_mem
+            .write(&mut [0_u8; 4].as_ref(), GuestAddress(TEST_MMIO_BASE), 4)
+            .unwrap();
+        assert_eq!(dev.lock().unwrap().content, [0x5a; 4]);
+
+        let mut buf = [0_u8; 4];
+        sysbus
+            .sys_mem
+            .read(&mut buf.as_mut(), GuestAddress(TEST_MMIO_BASE), 4)
+            .unwrap();
+        assert_eq!(buf, [0x5a; 4]);
+    }
+
+    struct FlashDevice {
+        rom: Arc<HostMemMapping>,
+        written: Vec<u8>,
+    }
+
+    impl SysBusDevOps for FlashDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Failed
+        }
+
+        fn write(&mut self, data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            self.written.extend_from_slice(data);
+            AccessResult::Ok
+        }
+
+        fn rom_mapping(&self) -> Option<Arc<HostMemMapping>> {
+            Some(self.rom.clone())
+        }
+    }
+
+    #[test]
+    fn test_attach_rom_mapping() {
+        let mut sysbus = sysbus_init();
+        let rom = HostMemMapping::new(
+            GuestAddress(TEST_MMIO_BASE),
+            None,
+            TEST_MMIO_SIZE,
+            None,
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        unsafe { *(rom.host_address() as *mut u8) = 0x5a };
+        let dev = Arc::new(Mutex::new(FlashDevice {
+            rom: Arc::new(rom),
+            written: Vec::new(),
+        }));
+        sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+
+        // Reads are served by the mapping, while writes still reach the device.
+        let mut buf = [0_u8; 1];
+        sysbus
+            .sys_mem
+            .read(&mut buf.as_mut(), GuestAddress(TEST_MMIO_BASE), 1)
+            .unwrap();
+        assert_eq!(buf, [0x5a]);
+        sysbus
+            .sys_mem
+            .write(&mut [0xa5_u8].as_ref(), GuestAddress(TEST_MMIO_BASE), 1)
+            .unwrap();
+        assert_eq!(dev.lock().unwrap().written, vec![0xa5]);
+    }
+
+    #[test]
+    fn test_mmio_trace() {
+        let mut sysbus = sysbus_init();
+        let dev = Arc::new(Mutex::new(RomDevice { content: [0x5a; 4] }));
+        sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+        let mut buf = [0_u8; 4];
+        sysbus
+            .sys_mem
+            .read(&mut buf.as_mut(), GuestAddress(TEST_MMIO_BASE), 4)
+            .unwrap();
+        // Accesses are not recorded before trace event is enabled.
+        assert_eq!(sysbus.mmio_trace().lock().unwrap().entries().count(), 0);
+
+        let event = SysBusDevType::Others.mmio_trace_event();
+        set_trace_event_enabled(&event, true);
+        sysbus
+            .sys_mem
+            .write(
+                &mut [0x12_u8, 0x34].as_ref(),
+                GuestAddress(TEST_MMIO_BASE + 0x8),
+                2,
+            )
+            .unwrap();
+        sysbus
+            .sys_mem
+            .read(&mut buf.as_mut(), GuestAddress(TEST_MMIO_BASE), 4)
+            .unwrap();
+        set_trace_event_enabled(&event, false);
+
+        let trace = sysbus.mmio_trace().lock().unwrap();
+        let entries: Vec<MmioTraceEntry> = trace.entries().copied().collect();
+        assert_eq!(entries.len(), 2);
+        let e = &entries[0];
+        assert_eq!(
+            (e.offset, e.size, e.value, e.is_write),
+            (0x8, 2, 0x3412, true)
+        );
+        let e = &entries[1];
+        assert_eq!(
+            (e.offset, e.size, e.value, e.is_write),
+            (0, 4, 0x5a5a_3412, false)
+        );
+        assert_eq!(e.region_base, TEST_MMIO_BASE);
+    }
+
+    /// Device with a single 4-byte register at offset 0.
+    struct RegDevice {
+        reg: u32,
+    }
+
+    impl RegDevice {
+        fn check(data_len: usize, offset: u64) -> AccessResult {
+            if data_len != 4 {
+                return AccessResult::UnsupportedSize;
+            }
+            if offset != 0 {
+                return AccessResult::BadOffset;
+            }
+            AccessResult::Ok
+        }
+    }
+
+    impl SysBusDevOps for RegDevice {
+        fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
+            let ret = Self::check(data.len(), offset);
+            if ret.is_ok() {
+                data.copy_from_slice(&self.reg.to_le_bytes());
+            }
+            ret
+        }
+
+        fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
+            let ret = Self::check(data.len(), offset);
+            if ret.is_ok() {
+                self.reg = u32::from_le_bytes(data.try_into().unwrap());
+            }
+            ret
+        }
+    }
+
+    #[test]
+    fn test_region_ops_access_violation() {
+        let sysbus = sysbus_init();
+        let dev = Arc::new(Mutex::new(RegDevice { reg: 0x1234_5678 }));
+        let ops = sysbus.build_region_ops(&dev, 0, false);
+        let base = GuestAddress(TEST_MMIO_BASE);
+
+        let mut data = [0_u8; 4];
+        assert!((ops.read)(&mut data, base, 0));
+        assert_eq!(u32::from_le_bytes(data), 0x1234_5678);
+
+        // Access of unsupported size or offset fails without touching the register.
+        let mut byte = [0_u8; 1];
+        assert!(!(ops.read)(&mut byte, base, 0));
+        assert!(!(ops.write)(&[0xff], base, 0));
+        assert!(!(ops.write)(&[0xff; 4], base, 2));
+        assert_eq!(dev.lock().unwrap().reg, 0x1234_5678);
+
+        assert!((ops.write)(&0xdead_beef_u32.to_le_bytes(), base, 0));
+        assert_eq!(dev.lock().unwrap().reg, 0xdead_beef);
+    }
+
+    struct TeardownDevice {
+        id: usize,
+        unrealized: Arc<Mutex<Vec<usize>>>,
+        unrealize_fails: bool,
+    }
+
+    impl SysBusDevOps for TeardownDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn unrealize(&mut self) -> Result<()> {
+            self.unrealized.lock().unwrap().push(self.id);
+            if self.unrealize_fails {
+                bail!("fd leaked");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_unrealize_all() {
+        let mut sysbus = sysbus_init();
+        let unrealized = Arc::new(Mutex::new(Vec::new()));
+        for id in 0..3 {
+            let dev = Arc::new(Mutex::new(TeardownDevice {
+                id,
+                unrealized: unrealized.clone(),
+                unrealize_fails: id == 1,
+            }));
+            sysbus.attach_dynamic_device(&dev).unwrap();
+        }
+
+        let err = sysbus.unrealize_all().unwrap_err();
+        assert!(matches!(err, SysBusError::UnrealizeFailed(_)));
+        assert_eq!(err.to_string().matches("fd leaked").count(), 1);
+        // Devices are torn down in reverse of attach order, failure doesn't stop the rest.
+        assert_eq!(*unrealized.lock().unwrap(), vec![2, 1, 0]);
+    }
+
+    /// Device with a 4-byte register whose value is saved as device state.
+    #[derive(Default)]
+    struct StateDevice {
+        res: SysRes,
+        reg: u32,
+    }
+
+    impl SysBusDevOps for StateDevice {
+        fn read(&mut self, data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            data.copy_from_slice(&self.reg.to_le_bytes()[..data.len()]);
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            self.reg = u32::from_le_bytes(data.try_into().unwrap());
+            AccessResult::Ok
+        }
+
+        fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+            Some(&mut self.res)
+        }
+
+        fn state_bytes(&self) -> Result<Vec<u8>> {
+            Ok(encode_state(1, &self.reg.to_le_bytes()))
+        }
+
+        fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+            let (_, payload) = decode_state(data, 1)?;
+            self.reg = u32::from_le_bytes(payload.try_into()?);
+            Ok(())
+        }
+    }
+
+    fn attach_state_devices(sysbus: &mut SysBus) {
+        for i in 0..2 {
+            let base = TEST_MMIO_BASE + i * TEST_MMIO_SIZE;
+            let dev = Arc::new(Mutex::new(StateDevice::default()));
+            sysbus
+                .attach_device(&dev, Some(base), TEST_MMIO_SIZE)
+                .unwrap();
+        }
+        // Device without state.
+        attach(sysbus, TEST_MMIO_BASE + 2 * TEST_MMIO_SIZE);
+    }
+
+    #[test]
+    fn test_save_restore_all() {
+        let mut sysbus = sysbus_init();
+        attach_state_devices(&mut sysbus);
+        for (i, value) in [0x1234_5678_u32, 0xdead_beef].iter().enumerate() {
+            let addr = GuestAddress(TEST_MMIO_BASE + i as u64 * TEST_MMIO_SIZE);
+            sysbus.sys_mem.write_object(value, addr).unwrap();
+        }
+        let state = sysbus.save_all().unwrap();
+
+        let mut restored = sysbus_init();
+        attach_state_devices(&mut restored);
+        restored.restore_all(&state).unwrap();
+        for (i, value) in [0x1234_5678_u32, 0xdead_beef].iter().enumerate() {
+            let addr = GuestAddress(TEST_MMIO_BASE + i as u64 * TEST_MMIO_SIZE);
+            assert_eq!(restored.sys_mem.read_object::<u32>(addr).unwrap(), *value);
+        }
+
+        // Saved device is missing, or the state is corrupted.
+        let mut missing = sysbus_init();
+        let dev = Arc::new(Mutex::new(StateDevice::default()));
+        missing
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+        assert!(matches!(
+            missing.restore_all(&state),
+            Err(SysBusError::InvalidState(_))
+        ));
+        assert!(restored.restore_all(&state[..state.len() - 1]).is_err());
+    }
+
+    /// Device with a constant register at offset 0, whose value changes after reset.
+    #[derive(Default)]
+    struct ConstRegDevice {
+        id: u32,
+        locked_reads: u32,
+    }
+
+    impl SysBusDevOps for ConstRegDevice {
+        fn read(&mut self, data: &mut [u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            self.locked_reads += 1;
+            data.fill(0xff);
+            AccessResult::Ok
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> AccessResult {
+            AccessResult::Ok
+        }
+
+        fn const_registers(&self) -> Vec<ConstRegister> {
+            vec![
+                ConstRegister {
+                    offset: 0,
+                    size: 4,
+                    value: u64::from(self.id),
+                },
+                // Invalid size is ignored.
+                ConstRegister {
+                    offset: 8,
+                    size: 16,
+                    value: 0,
+                },
+            ]
+        }
+
+        fn reset(&mut self) -> Result<()> {
+            self.id += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_const_registers() {
+        let mut sysbus = sysbus_init();
+        let dev = Arc::new(Mutex::new(ConstRegDevice {
+            id: 0x7472_6976,
+            locked_reads: 0,
+        }));
+        sysbus
+            .attach_device(&dev, Some(TEST_MMIO_BASE), TEST_MMIO_SIZE)
+            .unwrap();
+        let read = |offset: u64| -> u32 {
+            sysbus
+                .sys_mem
+                .read_object(GuestAddress(TEST_MMIO_BASE + offset))
+                .unwrap()
+        };
+
+        // Constant register is read while device is locked by others.
+        let locked_dev = dev.lock().unwrap();
+        assert_eq!(read(0), 0x7472_6976);
+        drop(locked_dev);
+        assert_eq!(dev.lock().unwrap().locked_reads, 0);
+
+        // Other registers and sizes go through the device.
+        assert_eq!(read(4), 0xffff_ffff);
+        assert_eq!(read(8), 0xffff_ffff);
+        let mut byte = [0_u8; 1];
+        assert!(sysbus
+            .sys_mem
+            .read(&mut byte.as_mut(), GuestAddress(TEST_MMIO_BASE), 1)
+            .is_ok());
+        assert_eq!(byte[0], 0xff);
+        assert_eq!(dev.lock().unwrap().locked_reads, 3);
+
+        // The table is reloaded after reset.
+        sysbus.reset_all().unwrap();
+        assert_eq!(read(0), 0x7472_6977);
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let window = (TEST_MMIO_BASE, TEST_MMIO_BASE + 0x1000);
+        let mut sysbus = SysBus::new_single_window(&sys_mem, (1, 1), window).unwrap();
+
+        attach(&mut sysbus, TEST_MMIO_BASE);
+        assert!(matches!(
+            sysbus.alloc_irq(),
+            Err(SysBusError::IrqExhausted { max: 1 })
+        ));
+        assert!(matches!(
+            sysbus.allocate_mmio(TEST_MMIO_SIZE, TEST_MMIO_SIZE),
+            Err(SysBusError::MmioExhausted { .. })
+        ));
+        assert!(matches!(
+            sysbus.allocate_mmio(TEST_MMIO_SIZE, 0x3),
+            Err(SysBusError::InvalidAllocation { .. })
+        ));
+        assert!(matches!(
+            try_attach(&mut sysbus, TEST_MMIO_BASE, 0),
+            Err(SysBusError::InvalidRegion { .. })
+        ));
+        assert!(matches!(
+            NoResDevice.set_sys_resource(&mut sysbus, TEST_MMIO_BASE, TEST_MMIO_SIZE),
+            Err(SysBusError::MissingSysResource)
+        ));
+        let dev = Arc::new(Mutex::new(TestDevice::new()));
+        assert!(matches!(
+            sysbus.detach_device(&dev),
+            Err(SysBusError::DeviceNotAttached)
+        ));
+        let evt = EventFd::new(0).unwrap();
+        assert!(matches!(
+            sysbus.request_shared_irq(2, &evt),
+            Err(SysBusError::IrqNotAllocated(2))
+        ));
+    }
+
+    #[test]
+    fn test_missing_sys_resource_keeps_irq() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let window = (TEST_MMIO_BASE, TEST_MMIO_BASE + 0x1000);
+        let mut sysbus = SysBus::new_single_window(&sys_mem, (1, 1), window).unwrap();
+
+        // Failed attempts take no IRQ from the pool of only one.
+        for _ in 0..2 {
+            assert!(matches!(
+                NoResIrqDevice.set_sys_resource(&mut sysbus, TEST_MMIO_BASE, TEST_MMIO_SIZE),
+                Err(SysBusError::MissingSysResource)
+            ));
+        }
+        assert_eq!(sysbus.alloc_irq().unwrap(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use util::trace::trace_events_with_prefix;
+
+use crate::{SysBusDevType, SysBusError, SysBusResult};
+
+/// Number of accesses kept in the MMIO trace buffer of system bus.
+pub const MMIO_TRACE_CAPACITY: usize = 4096;
+
+/// Prefix of the trace events which enable MMIO tracing of sysbus devices, followed
+/// by name of the device type, e.g. `sysbus:virtio-mmio`.
+pub const MMIO_TRACE_EVENT_PREFIX: &str = "sysbus:";
+
+/// One MMIO access of a traced device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioTraceEntry {
+    /// Nanoseconds since the trace buffer is created.
+    pub timestamp_ns: u64,
+    pub dev_type: SysBusDevType,
+    /// Base address of the accessed region.
+    pub region_base: u64,
+    pub offset: u64,
+    pub size: usize,
+    /// Accessed data in little endian, only the first 8 bytes are kept.
+    pub value: u64,
+    pub is_write: bool,
+}
+
+/// Bounded ring buffer of MMIO accesses. The oldest entry is dropped and counted
+/// as overflow once the buffer is full.
+pub struct MmioTrace {
+    start: Instant,
+    capacity: usize,
+    entries: VecDeque<MmioTraceEntry>,
+    overflow: u64,
+}
+
+impl MmioTrace {
+    pub fn new(capacity: usize) -> Self {
+        MmioTrace {
+            start: Instant::now(),
+            capacity,
+            entries: VecDeque::new(),
+            overflow: 0,
+        }
+    }
+
+    /// Record an access of `data` at `offset` of the region based at `region_base`.
+    pub fn record(
+        &mut self,
+        dev_type: SysBusDevType,
+        region_base: u64,
+        offset: u64,
+        data: &[u8],
+        is_write: bool,
+    ) {
+        if self.capacity == 0 {
+            self.overflow += 1;
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.overflow += 1;
+        }
+
+        let mut bytes = [0_u8; 8];
+        let len = data.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&data[..len]);
+        self.entries.push_back(MmioTraceEntry {
+            timestamp_ns: self.start.elapsed().as_nanos() as u64,
+            dev_type,
+            region_base,
+            offset,
+            size: data.len(),
+            value: u64::from_le_bytes(bytes),
+            is_write,
+        });
+    }
+
+    /// Recorded accesses from the oldest to the newest.
+    pub fn entries(&self) -> impl Iterator<Item = &MmioTraceEntry> {
+        self.entries.iter()
+    }
+
+    /// Number of entries dropped because the buffer is full.
+    pub fn overflow(&self) -> u64 {
+        self.overflow
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.overflow = 0;
+    }
+}
+
+/// Check every enabled MMIO trace event names a known device type, e.g. given
+/// by `-trace sysbus:<type>`.
+pub fn check_mmio_trace_events() -> SysBusResult<()> {
+    for event in trace_events_with_prefix(MMIO_TRACE_EVENT_PREFIX) {
+        let name = &event[MMIO_TRACE_EVENT_PREFIX.len()..];
+        if SysBusDevType::from_name(name).is_none() {
+            return Err(SysBusError::UnknownTraceDevType(name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mmio_trace_overflow() {
+        let mut trace = MmioTrace::new(2);
+        trace.record(SysBusDevType::Serial, 0x1000, 0, &[0x11], true);
+        trace.record(SysBusDevType::Serial, 0x1000, 4, &[0x22, 0x33], false);
+        assert_eq!(trace.overflow(), 0);
+
+        trace.record(
+            SysBusDevType::Serial,
+            0x1000,
+            8,
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+            true,
+        );
+        assert_eq!(trace.overflow(), 1);
+        let entries: Vec<MmioTraceEntry> = trace.entries().copied().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].offset, entries[0].value), (4, 0x3322));
+        assert_eq!(entries[1].size, 9);
+        assert_eq!(entries[1].value, 0x0807_0605_0403_0201);
+        assert!(entries[0].timestamp_ns <= entries[1].timestamp_ns);
+
+        trace.clear();
+        assert_eq!(trace.entries().count(), 0);
+        assert_eq!(trace.overflow(), 0);
+    }
+
+    #[test]
+    fn test_check_mmio_trace_events() {
+        use util::trace::set_trace_event_enabled;
+
+        set_trace_event_enabled("sysbus:virtio-mmio", true);
+        assert!(check_mmio_trace_events().is_ok());
+
+        set_trace_event_enabled("sysbus:bogus", true);
+        assert!(matches!(
+            check_mmio_trace_events(),
+            Err(SysBusError::UnknownTraceDevType(name)) if name == "bogus"
+        ));
+        set_trace_event_enabled("sysbus:bogus", false);
+        set_trace_event_enabled("sysbus:virtio-mmio", false);
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use crate::{SysBusError, SysBusResult};
+
+/// Magic number of the versioned state header, "SBST" in little endian.
+const STATE_MAGIC: u32 = 0x5453_4253;
+/// Header size: magic, version and payload length, all of them are u32 in little endian.
+const STATE_HEADER_SIZE: usize = 12;
+
+/// Prepend the versioned header to `payload`.
+pub fn encode_state(version: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(STATE_HEADER_SIZE + payload.len());
+    data.extend_from_slice(&STATE_MAGIC.to_le_bytes());
+    data.extend_from_slice(&version.to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Check the header built by `encode_state`, return the version and payload.
+///
+/// # Arguments
+///
+/// * `data` - State with header.
+/// * `max_version` - Newest version known by caller, newer states are rejected.
+pub fn decode_state(data: &[u8], max_version: u32) -> SysBusResult<(u32, &[u8])> {
+    let mut reader = StateReader::new(data);
+    if reader.read_u32()? != STATE_MAGIC {
+        return Err(SysBusError::InvalidState("bad magic".to_string()));
+    }
+    let version = reader.read_u32()?;
+    if version == 0 || version > max_version {
+        return Err(SysBusError::InvalidState(format!(
+            "unsupported version {}, max version is {}",
+            version, max_version
+        )));
+    }
+    let len = reader.read_u32()? as usize;
+    let payload = reader.read_bytes(len)?;
+    if !reader.is_empty() {
+        return Err(SysBusError::InvalidState("trailing bytes".to_string()));
+    }
+    Ok((version, payload))
+}
+
+/// Little endian reader of state bytes.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> SysBusResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(SysBusError::InvalidState("truncated state".to_string()));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_u32(&mut self) -> SysBusResult<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> SysBusResult<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_state_header() {
+        let data = encode_state(2, &[1, 2, 3]);
+        assert_eq!(data.len(), STATE_HEADER_SIZE + 3);
+        assert_eq!(decode_state(&data, 2).unwrap(), (2, &[1_u8, 2, 3][..]));
+        assert_eq!(decode_state(&data, 3).unwrap().0, 2);
+
+        // Newer version, bad magic, truncated payload and trailing bytes.
+        assert!(decode_state(&data, 1).is_err());
+        let mut bad = data.clone();
+        bad[0] ^= 0xff;
+        assert!(decode_state(&bad, 2).is_err());
+        assert!(decode_state(&data[..data.len() - 1], 2).is_err());
+        let mut long = data;
+        long.push(0);
+        assert!(decode_state(&long, 2).is_err());
+    }
+}
@@ -20,12 +20,10 @@ use crate::utils::{swap_u16, swap_u32, swap_u64};
 // use machine::standard_vm::aarch64::{LayoutEntryType, MEM_LAYOUT};
 use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
 
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 pub const FW_CFG_BASE: u64 = MEM_LAYOUT[LayoutEntryType::FwCfg as usize].0;
 #[cfg(target_arch = "x86_64")]
 pub const FW_CFG_BASE: u64 = 0x510;
-#[cfg(target_arch = "riscv64")]
-pub const FW_CFG_BASE: u64 = 0x510;
 
 const FW_CFG_FNAME_SIZE: usize = 56;
 
@@ -20,6 +20,7 @@ pub mod virtio;
 pub mod virtio_block;
 pub mod virtio_console;
 pub mod virtio_gpu;
+pub mod virtio_mmio;
 pub mod virtio_pci_modern;
 pub mod virtio_rng;
 pub mod vnc;
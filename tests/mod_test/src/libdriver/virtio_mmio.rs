@@ -0,0 +1,359 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::malloc::GuestAllocator;
+use super::virtio::{
+    TestVirtQueue, TestVirtioDev, VirtioDeviceOps, VIRTIO_CONFIG_S_DRIVER_OK,
+    VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_F_VERSION_1,
+};
+use crate::libtest::TestState;
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+
+const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x00;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x08;
+const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x10;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x14;
+const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x20;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x24;
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x30;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x34;
+const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x38;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x44;
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x50;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x60;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x64;
+const VIRTIO_MMIO_STATUS: u64 = 0x70;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x80;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x84;
+const VIRTIO_MMIO_QUEUE_AVAIL_LOW: u64 = 0x90;
+const VIRTIO_MMIO_QUEUE_AVAIL_HIGH: u64 = 0x94;
+const VIRTIO_MMIO_QUEUE_USED_LOW: u64 = 0xa0;
+const VIRTIO_MMIO_QUEUE_USED_HIGH: u64 = 0xa4;
+const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0xfc;
+const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+/// Interrupt status bit of used buffer notification.
+const VIRTIO_MMIO_INT_VRING: u32 = 0x1;
+/// Number of transports of the MMIO window which are scanned.
+const MMIO_SLOTS_SCANNED: u64 = 16;
+
+/// Driver of a virtio device behind a virtio-mmio transport of micro vm.
+pub struct TestVirtioMmioDev {
+    pub test_state: Rc<RefCell<TestState>>,
+    /// Base address of the transport.
+    pub base: u64,
+    pub virtio_dev: TestVirtioDev,
+    /// Queue number given by `init_device`, which the transport doesn't report.
+    queue_num: u16,
+    /// Queue selected last, the register is write-only.
+    queue_sel: Cell<u16>,
+}
+
+impl TestVirtioMmioDev {
+    pub fn new(test_state: Rc<RefCell<TestState>>, base: u64) -> Self {
+        Self {
+            test_state,
+            base,
+            virtio_dev: TestVirtioDev::new(),
+            queue_num: 0,
+            queue_sel: Cell::new(0),
+        }
+    }
+
+    /// Find the first transport of the MMIO window with a device of `device_type`.
+    pub fn find(test_state: Rc<RefCell<TestState>>, device_type: u32) -> Option<Self> {
+        let (mmio_base, mmio_size) = MEM_LAYOUT[LayoutEntryType::Mmio as usize];
+        let base = (0..MMIO_SLOTS_SCANNED)
+            .map(|slot| mmio_base + slot * mmio_size)
+            .find(|base| {
+                let ts = test_state.borrow();
+                ts.readl(base + VIRTIO_MMIO_MAGIC_VALUE) == VIRTIO_MMIO_MAGIC
+                    && ts.readl(base + VIRTIO_MMIO_DEVICE_ID) == device_type
+            })?;
+        let mut dev = Self::new(test_state, base);
+        dev.virtio_dev.device_type = device_type as u16;
+        Some(dev)
+    }
+
+    fn readl(&self, offset: u64) -> u32 {
+        self.test_state.borrow().readl(self.base + offset)
+    }
+
+    fn writel(&self, offset: u64, value: u32) {
+        self.test_state.borrow().writel(self.base + offset, value)
+    }
+}
+
+impl VirtioDeviceOps for TestVirtioMmioDev {
+    fn config_readb(&self, addr: u64) -> u8 {
+        self.test_state
+            .borrow()
+            .readb(self.base + VIRTIO_MMIO_CONFIG + addr)
+    }
+
+    fn config_readw(&self, addr: u64) -> u16 {
+        self.test_state
+            .borrow()
+            .readw(self.base + VIRTIO_MMIO_CONFIG + addr)
+    }
+
+    fn config_readl(&self, addr: u64) -> u32 {
+        self.readl(VIRTIO_MMIO_CONFIG + addr)
+    }
+
+    fn config_readq(&self, addr: u64) -> u64 {
+        self.test_state
+            .borrow()
+            .readq(self.base + VIRTIO_MMIO_CONFIG + addr)
+    }
+
+    fn config_writeb(&self, addr: u64, value: u8) {
+        self.test_state
+            .borrow()
+            .writeb(self.base + VIRTIO_MMIO_CONFIG + addr, value)
+    }
+
+    fn config_writew(&self, addr: u64, value: u16) {
+        self.test_state
+            .borrow()
+            .writew(self.base + VIRTIO_MMIO_CONFIG + addr, value)
+    }
+
+    fn config_writel(&self, addr: u64, value: u32) {
+        self.writel(VIRTIO_MMIO_CONFIG + addr, value)
+    }
+
+    fn config_writeq(&self, addr: u64, value: u64) {
+        self.test_state
+            .borrow()
+            .writeq(self.base + VIRTIO_MMIO_CONFIG + addr, value)
+    }
+
+    /// Interrupt of the transport is wired, the status register is polled instead.
+    fn enable_interrupt(&mut self) {}
+
+    fn disable_interrupt(&mut self) {}
+
+    fn get_device_features(&self) -> u64 {
+        self.writel(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 0);
+        let lo = self.readl(VIRTIO_MMIO_DEVICE_FEATURES) as u64;
+        self.writel(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1);
+        let hi = self.readl(VIRTIO_MMIO_DEVICE_FEATURES) as u64;
+        (hi << 32) | lo
+    }
+
+    fn set_guest_features(&self, features: u64) {
+        self.writel(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0);
+        self.writel(VIRTIO_MMIO_DRIVER_FEATURES, features as u32);
+        self.writel(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+        self.writel(VIRTIO_MMIO_DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    /// Driver features register is write-only, the negotiated ones are returned.
+    fn get_guest_features(&self) -> u64 {
+        self.virtio_dev.features
+    }
+
+    fn get_status(&self) -> u8 {
+        self.readl(VIRTIO_MMIO_STATUS) as u8
+    }
+
+    fn set_status(&self, status: u8) {
+        self.writel(VIRTIO_MMIO_STATUS, status as u32)
+    }
+
+    fn get_queue_nums(&self) -> u16 {
+        self.queue_num
+    }
+
+    fn get_generation(&self) -> u8 {
+        self.readl(VIRTIO_MMIO_CONFIG_GENERATION) as u8
+    }
+
+    fn queue_select(&self, index: u16) {
+        self.queue_sel.set(index);
+        self.writel(VIRTIO_MMIO_QUEUE_SEL, index as u32)
+    }
+
+    fn get_queue_select(&self) -> u16 {
+        self.queue_sel.get()
+    }
+
+    fn set_queue_size(&self, size: u16) {
+        self.writel(VIRTIO_MMIO_QUEUE_NUM, size as u32)
+    }
+
+    fn get_queue_size(&self) -> u16 {
+        self.readl(VIRTIO_MMIO_QUEUE_NUM_MAX) as u16
+    }
+
+    fn activate_queue(&self, desc: u64, avail: u64, used: u64) {
+        self.writel(VIRTIO_MMIO_QUEUE_DESC_LOW, desc as u32);
+        self.writel(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+        self.writel(VIRTIO_MMIO_QUEUE_AVAIL_LOW, avail as u32);
+        self.writel(VIRTIO_MMIO_QUEUE_AVAIL_HIGH, (avail >> 32) as u32);
+        self.writel(VIRTIO_MMIO_QUEUE_USED_LOW, used as u32);
+        self.writel(VIRTIO_MMIO_QUEUE_USED_HIGH, (used >> 32) as u32);
+    }
+
+    /// The interrupt is acked once it's seen.
+    fn queue_was_notified(&self, _virtqueue: Rc<RefCell<TestVirtQueue>>) -> bool {
+        let status = self.readl(VIRTIO_MMIO_INTERRUPT_STATUS);
+        if status & VIRTIO_MMIO_INT_VRING == 0 {
+            return false;
+        }
+        self.writel(VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_VRING);
+        true
+    }
+
+    fn setup_virtqueue(
+        &self,
+        test_state: Rc<RefCell<TestState>>,
+        alloc: Rc<RefCell<GuestAllocator>>,
+        index: u16,
+    ) -> Rc<RefCell<TestVirtQueue>> {
+        let virtqueue = Rc::new(RefCell::new(TestVirtQueue::new()));
+        virtqueue.borrow_mut().setup(self, alloc, index);
+        virtqueue.borrow().vring_init(test_state);
+
+        let size = virtqueue.borrow().size;
+        self.set_queue_size(size as u16);
+        let desc = virtqueue.borrow().desc;
+        let avail = virtqueue.borrow().avail;
+        let used = virtqueue.borrow().used;
+        self.activate_queue(desc, avail, used);
+        self.writel(VIRTIO_MMIO_QUEUE_READY, 1);
+
+        virtqueue
+    }
+
+    fn cleanup_virtqueue(&self, alloc: Rc<RefCell<GuestAllocator>>, desc_addr: u64) {
+        alloc.borrow_mut().free(desc_addr);
+    }
+
+    fn init_virtqueue(
+        &mut self,
+        test_state: Rc<RefCell<TestState>>,
+        alloc: Rc<RefCell<GuestAllocator>>,
+        num_queues: usize,
+    ) -> Vec<Rc<RefCell<TestVirtQueue>>> {
+        (0..num_queues)
+            .map(|i| self.setup_virtqueue(test_state.clone(), alloc.clone(), i as u16))
+            .collect()
+    }
+
+    fn virtqueue_notify(&self, virtqueue: Rc<RefCell<TestVirtQueue>>) {
+        self.writel(VIRTIO_MMIO_QUEUE_NOTIFY, virtqueue.borrow().index as u32);
+    }
+
+    fn kick_virtqueue(
+        &self,
+        test_state: Rc<RefCell<TestState>>,
+        virtqueue: Rc<RefCell<TestVirtQueue>>,
+    ) {
+        let vq = virtqueue.borrow();
+        let idx: u16 = test_state.borrow().readw(vq.avail + 2);
+
+        if (!vq.event) || (idx >= vq.get_avail_event(test_state.clone()) + 1) {
+            self.virtqueue_notify(virtqueue.clone());
+        }
+    }
+
+    fn poll_used_elem(
+        &self,
+        test_state: Rc<RefCell<TestState>>,
+        virtqueue: Rc<RefCell<TestVirtQueue>>,
+        desc_idx: u32,
+        timeout_us: u64,
+        len: &mut Option<u32>,
+        wait_notified: bool,
+    ) {
+        let start_time = Instant::now();
+        let timeout_us = Duration::from_micros(timeout_us);
+
+        loop {
+            if (!wait_notified || self.queue_was_notified(virtqueue.clone()))
+                && virtqueue.borrow_mut().get_buf(test_state.clone())
+            {
+                if let Some(got_len) = virtqueue.borrow().desc_len.get(&desc_idx) {
+                    if let Some(len) = len {
+                        *len = *got_len;
+                    }
+                    break;
+                }
+            }
+            assert!(Instant::now() - start_time < timeout_us);
+        }
+    }
+
+    fn init_device(
+        &mut self,
+        test_state: Rc<RefCell<TestState>>,
+        alloc: Rc<RefCell<GuestAllocator>>,
+        features: u64,
+        num_queues: usize,
+    ) -> Vec<Rc<RefCell<TestVirtQueue>>> {
+        self.reset();
+        self.set_acknowledge();
+        self.set_driver();
+        self.negotiate_features(features);
+        self.set_features_ok();
+        self.queue_num = num_queues as u16;
+        let vqs = self.init_virtqueue(test_state, alloc, num_queues);
+
+        self.set_driver_ok();
+        vqs
+    }
+
+    fn destroy_device(
+        &mut self,
+        alloc: Rc<RefCell<GuestAllocator>>,
+        vqs: Vec<Rc<RefCell<TestVirtQueue>>>,
+    ) {
+        self.reset();
+        for vq in vqs.iter() {
+            self.cleanup_virtqueue(alloc.clone(), vq.borrow().desc);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.set_status(0);
+        assert_eq!(self.get_status(), 0);
+        self.virtio_dev.feature_negotiated = false;
+    }
+
+    fn negotiate_features(&mut self, features: u64) {
+        self.virtio_dev.features = features;
+        self.set_guest_features(features);
+    }
+
+    fn set_features_ok(&mut self) {
+        if (self.get_guest_features() & (1 << VIRTIO_F_VERSION_1)) != 0 {
+            let status: u8 = self.get_status() | VIRTIO_CONFIG_S_FEATURES_OK;
+            self.set_status(status);
+            assert_eq!(self.get_status(), status);
+        }
+
+        self.virtio_dev.feature_negotiated = true;
+    }
+
+    fn set_driver_ok(&self) {
+        let status = self.get_status() | VIRTIO_CONFIG_S_DRIVER_OK;
+        self.set_status(status);
+        assert_eq!(self.get_status(), status);
+    }
+}
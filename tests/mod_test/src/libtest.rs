@@ -10,9 +10,11 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::cell::RefCell;
 use std::io;
 use std::io::{Read, Write, BufReader, BufRead};
+use std::os::unix::io::RawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
 use std::process::{Child, Command, Stdio};
@@ -22,16 +24,22 @@ use std::time::{Duration, Instant};
 use std::{env, fs};
 
 use hex;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 
 use crate::utils::get_tmp_dir;
 
 pub struct StreamHandler {
     stream: UnixStream,
+    /// Bytes read after the last returned line, e.g. a return following an event.
+    pending: RefCell<String>,
 }
 
 impl StreamHandler {
     fn new(stream: UnixStream) -> Self {
-        StreamHandler { stream }
+        StreamHandler {
+            stream,
+            pending: RefCell::new(String::new()),
+        }
     }
 
     fn write_line(&self, cmd: &str) {
@@ -42,9 +50,14 @@ impl StreamHandler {
             .unwrap();
     }
 
+    fn write_line_with_fd(&self, cmd: &str, fd: RawFd) {
+        let size = self.stream.send_with_fds(&[cmd.as_bytes()], &[fd]).unwrap();
+        assert_eq!(size, cmd.len());
+    }
+
     fn read_line(&self, timeout: Duration) -> String {
         let start = Instant::now();
-        let mut resp = String::new();
+        let mut resp = self.pending.take();
         let mut stream = self.stream.try_clone().unwrap();
         stream.set_nonblocking(true).unwrap();
 
@@ -59,7 +72,8 @@ impl StreamHandler {
             }
         };
 
-        let (line, _) = resp.split_at(pos.unwrap());
+        let (line, rest) = resp.split_at(pos.unwrap());
+        *self.pending.borrow_mut() = rest[1..].to_string();
         line.trim().to_string()
     }
 }
@@ -100,6 +114,10 @@ impl TestState {
             timeout: Duration::from_secs(360),
         };
         ts.check_qmp_greet();
+        // Negotiate capabilities so that events are sent to the test, as before
+        // negotiation was required. Tests must not negotiate again.
+        let ret = ts.qmp("{\"execute\": \"qmp_capabilities\"}");
+        assert_eq!(*ret.get("return").unwrap(), json!({}));
         ts
     }
 
@@ -108,6 +126,17 @@ impl TestState {
         self.process.wait().unwrap();
     }
 
+    /// Wait for the process to exit by itself, e.g. powered off by guest, and return
+    /// its exit code.
+    pub fn wait_exit(&mut self) -> Option<i32> {
+        self.process.wait().unwrap().code()
+    }
+
+    /// Pid of the vm process, e.g. to send signals to it.
+    pub fn pid(&self) -> u32 {
+        self.process.id()
+    }
+
     pub fn set_timeout(&mut self, duration: Duration) {
         self.timeout = duration;
     }
@@ -127,17 +156,39 @@ impl TestState {
         return resp;
     }
 
+    /// Wait for the next event, which must be `name` carrying `data`.
+    pub fn assert_qmp_event(&self, name: &str, data: Value) {
+        let event = self.wait_qmp_event();
+        assert_event(&event, name);
+        assert_eq!(*event.get("data").unwrap(), data);
+    }
+
     pub fn qmp(&self, cmd: &str) -> Value {
         // let timeout = Duration::from_secs(10);
         self.qmp_sock.write_line(cmd);
         serde_json::from_slice(self.qmp_sock.read_line(self.timeout).as_bytes()).unwrap()
     }
 
+    /// Send `cmd` along with `fd` by SCM rights, e.g. for `getfd` and `add-fd`.
+    pub fn qmp_with_fd(&self, cmd: &str, fd: RawFd) -> Value {
+        self.qmp_sock.write_line_with_fd(cmd, fd);
+        serde_json::from_slice(self.qmp_sock.read_line(self.timeout).as_bytes()).unwrap()
+    }
+
     pub fn qmp_read(&self) -> Value {
         // let timeout = Duration::from_secs(10);
         serde_json::from_slice(self.qmp_sock.read_line(self.timeout).as_bytes()).unwrap()
     }
 
+    /// Read the response of command, skipping events sent before it.
+    pub fn qmp_return(&self, cmd: &str) -> Value {
+        let mut ret = self.qmp(cmd);
+        while ret.get("event").is_some() {
+            ret = self.qmp_read();
+        }
+        ret
+    }
+
     fn send_test_cmd(&self, cmd: &str) -> String {
         // let timeout = Duration::from_secs(10);
         self.test_sock.write_line(cmd);
@@ -176,6 +227,22 @@ impl TestState {
         }
     }
 
+    /// Get `pc`, `a0`, `a1` and `a2` of vcpu `cpu` set for booting.
+    #[cfg(target_arch = "riscv64")]
+    pub fn boot_regs(&self, cpu: u64) -> Vec<u64> {
+        let cmd = format!("boot_regs {}", cpu);
+        let buf = self.send_test_cmd(&cmd);
+        let resp: Vec<&str> = buf.split(' ').collect();
+        assert_eq!(resp.len(), 5);
+        match resp[0] {
+            "OK" => resp[1..]
+                .iter()
+                .map(|reg| u64::from_str_radix(reg.replace("0x", "").as_str(), 16).unwrap())
+                .collect(),
+            _ => panic!("Failed to execute {}.", cmd),
+        }
+    }
+
     pub fn readb(&self, addr: u64) -> u8 {
         let cmd = format!("readb 0x{:x}", addr);
         self.send_read_cmd(&cmd) as u8
@@ -288,6 +355,42 @@ impl TestState {
             _ => panic!("Failed to execute {}.", cmd),
         }
     }
+
+    /// Add block backend `node_name` on `filename` by `blockdev-add`, with
+    /// `extra` arguments appended, e.g. `, "read-only": true`.
+    pub fn blockdev_add(&self, node_name: &str, filename: &str, extra: &str) -> Value {
+        self.qmp(&format!(
+            "{{\"execute\": \"blockdev-add\", \"arguments\": {{\"node-name\": \"{}\", \
+             \"file\": {{\"driver\": \"file\", \"filename\": \"{}\"}}, \
+             \"cache\": {{\"direct\": false}}{}}}}}",
+            node_name, filename, extra
+        ))
+    }
+
+    /// Get the return of `query-status`, e.g. `status` of the vm.
+    pub fn query_status(&self) -> Value {
+        let ret = self.qmp("{\"execute\": \"query-status\"}");
+        ret.get("return").unwrap().clone()
+    }
+
+    /// Get the run state of the vm, e.g. `running`.
+    pub fn run_status(&self) -> Value {
+        self.query_status()["status"].clone()
+    }
+
+    /// Get runtime statistics of each vcpu by `query-vcpu-stats`.
+    pub fn query_vcpu_stats(&self) -> Vec<Value> {
+        let ret = self.qmp("{\"execute\": \"query-vcpu-stats\"}");
+        ret.get("return").unwrap().as_array().unwrap().clone()
+    }
+}
+
+/// Check `msg` is the event `name` with a valid timestamp.
+pub fn assert_event(msg: &Value, name: &str) {
+    assert_eq!(*msg.get("event").unwrap(), json!(name));
+    let timestamp = msg.get("timestamp").unwrap();
+    assert!(timestamp["seconds"].as_u64().unwrap() > 0);
+    assert!(timestamp["microseconds"].as_u64().unwrap() < 1_000_000);
 }
 
 fn init_socket(path: &str) -> UnixListener {
@@ -379,6 +482,24 @@ fn wait_for_output(child_stdout: std::process::ChildStdout, expected_output: &st
 }
 
 pub fn test_init(extra_arg: Vec<&str>) -> TestState {
+    test_init_with_serial("stdio", extra_arg)
+}
+
+/// Same as `test_init`, with the serial backend given by `serial`, e.g. a socket which
+/// the test reads guest console from.
+pub fn test_init_with_serial(serial: &str, extra_arg: Vec<&str>) -> TestState {
+    init_vm(serial, extra_arg, Duration::from_secs(360))
+}
+
+/// Same as `test_init`, with the vm started by `-S`, so device state can be poked
+/// before guest executes the first instruction. Guest runs after `cont`.
+pub fn test_init_prelaunch(serial: &str, mut extra_arg: Vec<&str>) -> TestState {
+    extra_arg.push("-S");
+    init_vm(serial, extra_arg, Duration::ZERO)
+}
+
+/// Start the vm and wait `boot_wait` for guest to boot.
+fn init_vm(serial: &str, extra_arg: Vec<&str>, boot_wait: Duration) -> TestState {
     let binary_path = env::var("TELEVM_BINARY").unwrap();
     let tmp_dir = get_tmp_dir();
     let test_socket = format!("{}/test-televm.socket", tmp_dir);
@@ -394,7 +515,7 @@ pub fn test_init(extra_arg: Vec<&str>) -> TestState {
         .args(["-append", &format!("root=/dev/vda rw console=ttyS0")])
         .args(["-drive", &format!("id=rootfs,file={}/rootfs_guest.ext4", shared_path)])
         .args(["-device", &format!("virtio-blk-device,drive=rootfs,id=blk1")])
-        .args(["-serial", &format!("stdio")])
+        .args(["-serial", serial])
         // .args(["-netdev", &format!("tap,id=net0,ifname=tap0")])
         .args(["-qmp", &format!("unix:{},server,nowait", qmp_socket)])
         .args(["-mod-test", &test_socket])
@@ -407,7 +528,7 @@ pub fn test_init(extra_arg: Vec<&str>) -> TestState {
     let num_secs = 360;
 
     // 等待超时
-    thread::sleep(Duration::from_secs(num_secs)); 
+    thread::sleep(boot_wait);
     // 等待特定的输出 // let output = wait_for_output(&mut child, "Welcome to Ubuntu 22.04 LTS!", Duration::from_secs(num_secs));
     // let child_stdout = child.stdout.take();
     // let output = match child_stdout {
@@ -0,0 +1,154 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs;
+
+use util::device_tree::{Fdt, FdtBuilder, FdtNode};
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, test_init_prelaunch, TestState};
+use mod_test::utils::{get_rand_str, read_le_u64};
+
+const MEM_START: u64 = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+const KERNEL_START: u64 = MEM_START + 0x20_0000;
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942_534f;
+
+/// Firmware which spins at its entry with `j .`.
+const FIRMWARE: [u8; 4] = [0x6f, 0, 0, 0];
+
+fn check_fdt(ts: &TestState, fdt_addr: u64) {
+    let magic = ts.memread(fdt_addr, 4);
+    assert_eq!(u32::from_be_bytes(magic.try_into().unwrap()), FDT_MAGIC);
+}
+
+fn read_fdt(ts: &TestState, fdt_addr: u64) -> Fdt {
+    let header = ts.memread(fdt_addr, 8);
+    let total_size = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    Fdt::from_blob(&ts.memread(fdt_addr, total_size as u64)).unwrap()
+}
+
+/// Write a dtb describing a 16MiB memory and a firmware node, returns its path.
+fn write_user_dtb() -> String {
+    let mut fdt = FdtBuilder::new();
+    let root_node = fdt.begin_node("").unwrap();
+    fdt.set_property_u32("#address-cells", 2).unwrap();
+    fdt.set_property_u32("#size-cells", 2).unwrap();
+    let memory_node = fdt.begin_node("memory@80000000").unwrap();
+    fdt.set_property_string("device_type", "memory").unwrap();
+    fdt.set_property_array_u64("reg", &[MEM_START, 0x100_0000])
+        .unwrap();
+    fdt.end_node(memory_node).unwrap();
+    let firmware_node = fdt.begin_node("firmware").unwrap();
+    fdt.set_property_string("compatible", "televm,test")
+        .unwrap();
+    fdt.end_node(firmware_node).unwrap();
+    let chosen_node = fdt.begin_node("chosen").unwrap();
+    fdt.set_property_string("bootargs", "console=hvc0").unwrap();
+    fdt.end_node(chosen_node).unwrap();
+    fdt.end_node(root_node).unwrap();
+
+    let dtb_path = format!("/tmp/televm-dtb-{}.dtb", get_rand_str(8));
+    fs::write(&dtb_path, fdt.finish().unwrap()).unwrap();
+    dtb_path
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn check_kernel_entry_regs() {
+    let mut ts = test_init(Vec::new());
+
+    let regs = ts.boot_regs(0);
+    assert_eq!(regs[0], KERNEL_START);
+    assert_eq!(regs[1], 0);
+    check_fdt(&ts, regs[2]);
+    assert_eq!(regs[3], 0);
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn check_bios_entry_regs() {
+    let bios_path = format!("/tmp/televm-fw-{}.bin", get_rand_str(8));
+    fs::write(&bios_path, FIRMWARE).unwrap();
+    let mut ts = test_init(vec!["-smp", "2", "-bios", &bios_path]);
+
+    // Boot hart enters firmware with hartid, dtb and fw_dynamic info.
+    let regs = ts.boot_regs(0);
+    assert_eq!(regs[0], MEM_START);
+    assert_eq!(regs[1], 0);
+    check_fdt(&ts, regs[2]);
+    assert_eq!(ts.memread(MEM_START, 4), FIRMWARE.to_vec());
+
+    let info = ts.memread(regs[3], 6 * 8);
+    let mut info = info.as_slice();
+    assert_eq!(read_le_u64(&mut info), FW_DYNAMIC_INFO_MAGIC);
+    // Version.
+    assert_eq!(read_le_u64(&mut info), 2);
+    // Next stage is kernel in S-mode.
+    assert_eq!(read_le_u64(&mut info), KERNEL_START);
+    assert_eq!(read_le_u64(&mut info), 1);
+    // Options.
+    assert_eq!(read_le_u64(&mut info), 0);
+    // Boot hart.
+    assert_eq!(read_le_u64(&mut info), 0);
+    assert!(regs[3] >= MEM_START + FIRMWARE.len() as u64 && regs[3] < KERNEL_START);
+
+    // Secondary harts are not started by firmware entry.
+    let regs = ts.boot_regs(1);
+    assert_eq!(regs[1], 1);
+    assert_eq!(regs[3], 0);
+
+    ts.stop();
+    fs::remove_file(&bios_path).unwrap();
+}
+
+fn child<'a>(node: &'a FdtNode, name: &str) -> Option<&'a FdtNode> {
+    node.children.iter().find(|child| child.name == name)
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn check_user_dtb() {
+    let dtb_path = write_user_dtb();
+    let mut ts = test_init_prelaunch("stdio", vec!["-dtb", &dtb_path]);
+
+    // Nodes of user dtb are kept, /chosen and memory are patched.
+    let fdt = read_fdt(&ts, ts.boot_regs(0)[2]);
+    assert!(child(&fdt.root, "firmware").is_some());
+    assert!(child(&fdt.root, "cpus").is_none());
+    let bootargs = child(&fdt.root, "chosen")
+        .and_then(|chosen| chosen.property_str("bootargs"))
+        .unwrap();
+    assert!(bootargs.starts_with("root=/dev/vda rw console=ttyS0"));
+    assert!(child(&fdt.root, "memory@80000000").is_none());
+    let memory = child(&fdt.root, "memory").unwrap();
+    assert_eq!(memory.property_str("device_type"), Some("memory"));
+    assert_ne!(
+        memory.property("reg").unwrap()[8..16],
+        0x100_0000_u64.to_be_bytes()
+    );
+    ts.stop();
+
+    // Dtb is loaded as it is.
+    let mut ts = test_init_prelaunch("stdio", vec!["-dtb", &dtb_path, "-dtb-no-fixup"]);
+    let fdt = read_fdt(&ts, ts.boot_regs(0)[2]);
+    assert_eq!(
+        fdt.root,
+        Fdt::from_blob(&fs::read(&dtb_path).unwrap()).unwrap().root
+    );
+    ts.stop();
+
+    fs::remove_file(&dtb_path).unwrap();
+}
@@ -0,0 +1,239 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::{cleanup_img, create_img, TEST_IMAGE_SIZE};
+
+/// Arguments of a read-only raw backend for `blockdev_add`.
+const READ_ONLY_RAW: &str = ", \"driver\": \"raw\", \"read-only\": true";
+
+fn blockdev_del(ts: &TestState, node_name: &str) -> Value {
+    ts.qmp(&format!(
+        "{{\"execute\": \"blockdev-del\", \"arguments\": {{\"node-name\": \"{}\"}}}}",
+        node_name
+    ))
+}
+
+fn named_block_nodes(ts: &TestState) -> Vec<Value> {
+    let ret = ts.qmp("{\"execute\": \"query-named-block-nodes\"}");
+    ret.get("return").unwrap().as_array().unwrap().clone()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn blockdev_add_del() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let node_path = create_img(TEST_IMAGE_SIZE, 0);
+    let args = format!(
+        "-drive file={},id=drive0,direct=false -device virtio-blk-device,drive=drive0,id=blk0",
+        image_path
+    );
+    let mut ts = test_init(args.split(' ').map(String::from).collect());
+
+    let nodes = named_block_nodes(&ts);
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0]["node-name"], json!("drive0"));
+    assert_eq!(nodes[0]["ro"], json!(false));
+    assert_eq!(nodes[0]["image"]["filename"], json!(image_path));
+    assert_eq!(nodes[0]["image"]["virtual-size"], json!(TEST_IMAGE_SIZE));
+
+    // Backend used by a device can not be deleted.
+    assert!(blockdev_del(&ts, "drive0").get("error").is_some());
+
+    let ret = ts.blockdev_add("node1", &node_path, READ_ONLY_RAW);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert!(ts
+        .blockdev_add("node1", &node_path, READ_ONLY_RAW)
+        .get("error")
+        .is_some());
+    assert!(ts
+        .blockdev_add(
+            "node2",
+            &node_path,
+            ", \"driver\": \"qcow2\", \"read-only\": true"
+        )
+        .get("error")
+        .is_some());
+
+    let nodes = named_block_nodes(&ts);
+    assert_eq!(nodes.len(), 2);
+    assert_eq!(nodes[1]["node-name"], json!("node1"));
+    assert_eq!(nodes[1]["ro"], json!(true));
+    assert_eq!(nodes[1]["file"], json!(node_path));
+
+    let ret = ts.qmp(
+        "{\"execute\": \"device_add\", \"arguments\": {\"id\": \"blk1\", \
+         \"driver\": \"virtio-blk-device\", \"drive\": \"node1\"}}",
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert!(blockdev_del(&ts, "node1").get("error").is_some());
+
+    let event = ts.qmp("{\"execute\": \"device_del\", \"arguments\": {\"id\": \"blk1\"}}");
+    assert_eq!(*event.get("event").unwrap(), json!("DEVICE_DELETED"));
+    let ret = ts.qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    let ret = blockdev_del(&ts, "node1");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert!(blockdev_del(&ts, "node1").get("error").is_some());
+
+    let nodes = named_block_nodes(&ts);
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0]["node-name"], json!("drive0"));
+
+    ts.stop();
+    cleanup_img(image_path);
+    cleanup_img(node_path);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn query_block_and_blockstats() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let node_path = create_img(TEST_IMAGE_SIZE, 0);
+    let args = format!(
+        "-drive file={},id=drive0,direct=false -device virtio-blk-device,drive=drive0,id=blk0",
+        image_path
+    );
+    let mut ts = test_init(args.split(' ').map(String::from).collect());
+
+    let ret = ts.qmp("{\"execute\": \"query-block\"}");
+    let blocks = ret.get("return").unwrap().as_array().unwrap();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0]["device"], json!("blk0"));
+    assert_eq!(blocks[0]["inserted"]["node-name"], json!("drive0"));
+    assert_eq!(blocks[0]["inserted"]["file"], json!(image_path));
+    assert_eq!(blocks[0]["inserted"]["ro"], json!(false));
+    assert_eq!(
+        blocks[0]["inserted"]["image"]["virtual-size"],
+        json!(TEST_IMAGE_SIZE)
+    );
+
+    // Device plugged by device_add is listed too.
+    let ret = ts.blockdev_add("node1", &node_path, READ_ONLY_RAW);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let ret = ts.qmp(
+        "{\"execute\": \"device_add\", \"arguments\": {\"id\": \"blk1\", \
+         \"driver\": \"virtio-blk-device\", \"drive\": \"node1\"}}",
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let ret = ts.qmp("{\"execute\": \"query-block\"}");
+    let blocks = ret.get("return").unwrap().as_array().unwrap();
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[1]["device"], json!("blk1"));
+    assert_eq!(blocks[1]["inserted"]["ro"], json!(true));
+
+    // Counters are kept over stop/cont and reset along with the device.
+    for cmd in ["stop", "cont", "system_reset"] {
+        // Skip the STOP/RESUME/RESET events emitted along with the response.
+        let mut ret = ts.qmp(&format!("{{\"execute\": \"{}\"}}", cmd));
+        while ret.get("return").is_none() {
+            ret = ts.qmp_read();
+        }
+        let ret = ts.qmp("{\"execute\": \"query-blockstats\"}");
+        let stats = ret.get("return").unwrap().as_array().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0]["device"], json!("blk0"));
+        assert_eq!(stats[0]["node-name"], json!("drive0"));
+        assert_eq!(stats[1]["node-name"], json!("node1"));
+        for field in ["rd_bytes", "wr_operations", "failed_flush_operations"] {
+            assert!(stats[0]["stats"][field].is_u64());
+        }
+    }
+
+    ts.stop();
+    cleanup_img(image_path);
+    cleanup_img(node_path);
+}
+
+/// Base address of the virtio-blk transport whose capacity is `sectors`.
+fn virtio_blk_base(ts: &TestState, sectors: u64) -> u64 {
+    let ret = ts.qmp("{\"execute\": \"query-sysbus\"}");
+    let bases: Vec<u64> = ret
+        .get("return")
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|dev| dev["type"] == json!("VirtioMmio"))
+        .filter_map(|dev| dev["region_base"].as_u64())
+        .filter(|base| ts.readl(base + 0x8) == 2 && ts.readq(base + 0x100) == sectors)
+        .collect();
+    assert_eq!(bases.len(), 1);
+    bases[0]
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn block_resize() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let node_path = create_img(TEST_IMAGE_SIZE, 0);
+    let args = format!(
+        "-drive file={},id=drive0,direct=false -device virtio-blk-device,drive=drive0,id=blk0",
+        image_path
+    );
+    let mut ts = test_init(args.split(' ').map(String::from).collect());
+    let base = virtio_blk_base(&ts, TEST_IMAGE_SIZE >> 9);
+
+    // Grow the image, and the capacity in config space follows.
+    let ret = ts.qmp(&format!(
+        "{{\"execute\": \"block_resize\", \"arguments\": {{\"device\": \"blk0\", \"size\": {}}}}}",
+        TEST_IMAGE_SIZE * 2
+    ));
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(ts.readq(base + 0x100), (TEST_IMAGE_SIZE * 2) >> 9);
+    let len = std::fs::metadata(&image_path).unwrap().len();
+    assert_eq!(len, TEST_IMAGE_SIZE * 2);
+    let ret = ts.qmp("{\"execute\": \"query-block\"}");
+    assert_eq!(
+        ret["return"][0]["inserted"]["image"]["virtual-size"],
+        json!(TEST_IMAGE_SIZE * 2)
+    );
+
+    // Shrinking needs force.
+    let resize = |force: bool| {
+        ts.qmp(&format!(
+            "{{\"execute\": \"block_resize\", \"arguments\": {{\"node-name\": \"drive0\", \
+             \"size\": {}, \"force\": {}}}}}",
+            TEST_IMAGE_SIZE, force
+        ))
+    };
+    assert!(resize(false).get("error").is_some());
+    assert_eq!(ts.readq(base + 0x100), (TEST_IMAGE_SIZE * 2) >> 9);
+    assert_eq!(*resize(true).get("return").unwrap(), json!({}));
+    assert_eq!(ts.readq(base + 0x100), TEST_IMAGE_SIZE >> 9);
+
+    // Read-only backend can't be resized.
+    let ret = ts.blockdev_add("node1", &node_path, READ_ONLY_RAW);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let ret = ts.qmp(
+        "{\"execute\": \"device_add\", \"arguments\": {\"id\": \"blk1\", \
+         \"driver\": \"virtio-blk-device\", \"drive\": \"node1\"}}",
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let ret = ts.qmp(&format!(
+        "{{\"execute\": \"block_resize\", \"arguments\": {{\"device\": \"blk1\", \"size\": {}}}}}",
+        TEST_IMAGE_SIZE * 2
+    ));
+    assert!(ret.get("error").is_some());
+    assert_eq!(
+        std::fs::metadata(&node_path).unwrap().len(),
+        TEST_IMAGE_SIZE
+    );
+
+    ts.stop();
+    cleanup_img(image_path);
+    cleanup_img(node_path);
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::{json, Value};
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, TestState};
+
+const BOOT_DONE_ADDR: u64 = MEM_LAYOUT[LayoutEntryType::SifiveTest as usize].0 + 0x04;
+
+fn query_boot_time(ts: &TestState) -> Vec<(String, u64)> {
+    let ret = ts.qmp("{\"execute\": \"query-boot-time\"}");
+    ret.get("return")
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m: &Value| {
+            (
+                m.get("milestone").unwrap().as_str().unwrap().to_string(),
+                m.get("time-ns").unwrap().as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+fn assert_monotonic(milestones: &[(String, u64)]) {
+    assert!(milestones.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn boot_time_milestones() {
+    let mut ts = test_init(vec!["-boottime"]);
+    let milestones = query_boot_time(&ts);
+    let names: Vec<&str> = milestones.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "config-parsed",
+            "memory-mapped",
+            "devices-realized",
+            "vcpus-created",
+            "first-vcpu-entry",
+            "first-serial-output",
+        ]
+    );
+    assert_monotonic(&milestones);
+
+    // Guest rings the doorbell once it has booted, only the first ring counts.
+    ts.writel(BOOT_DONE_ADDR, 1);
+    let milestones = query_boot_time(&ts);
+    assert_eq!(milestones.len(), 7);
+    assert_eq!(milestones[6].0, "guest-boot-complete");
+    assert_monotonic(&milestones);
+    ts.writel(BOOT_DONE_ADDR, 1);
+    assert_eq!(query_boot_time(&ts), milestones);
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn boot_time_disabled() {
+    let mut ts = test_init(Vec::new());
+    ts.writel(BOOT_DONE_ADDR, 1);
+    let ret = ts.qmp("{\"execute\": \"query-boot-time\"}");
+    assert_eq!(
+        *ret.get("error").unwrap().get("class").unwrap(),
+        json!("GenericError")
+    );
+
+    ts.stop();
+}
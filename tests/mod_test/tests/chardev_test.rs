@@ -0,0 +1,155 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::{test_init_prelaunch, TestState};
+use mod_test::utils::get_rand_str;
+
+fn chardev_add(ts: &TestState, id: &str, backend: Value) -> Value {
+    let cmd = json!({
+        "execute": "chardev-add",
+        "arguments": {"id": id, "backend": backend}
+    });
+    ts.qmp(&cmd.to_string())
+}
+
+fn chardev_remove(ts: &TestState, id: &str) -> Value {
+    let cmd = json!({"execute": "chardev-remove", "arguments": {"id": id}});
+    ts.qmp(&cmd.to_string())
+}
+
+fn query_chardev(ts: &TestState, id: &str) -> Option<Value> {
+    let ret = ts.qmp("{\"execute\": \"query-chardev\"}");
+    ret["return"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|info| info["label"] == json!(id))
+        .cloned()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn chardev_add_remove() {
+    let mut ts = test_init_prelaunch("stdio", Vec::new());
+
+    // The chardev of serial is listed and can't be removed.
+    let info = query_chardev(&ts, "serial_chardev").unwrap();
+    assert_eq!(info["frontend-open"], json!(true));
+    assert_eq!(info["filename"], json!("stdio"));
+    assert!(chardev_remove(&ts, "serial_chardev").get("error").is_some());
+
+    // The allocated pts is returned for pty backend.
+    let ret = chardev_add(&ts, "chr-pty", json!({"type": "pty", "data": {}}));
+    let pty = ret["return"]["pty"].as_str().unwrap().to_string();
+    assert!(Path::new(&pty).exists());
+    let info = query_chardev(&ts, "chr-pty").unwrap();
+    assert_eq!(info["frontend-open"], json!(false));
+    assert_eq!(info["filename"], json!(format!("pty:{}", pty)));
+
+    // Id must be unique.
+    let ret = chardev_add(&ts, "chr-pty", json!({"type": "null"}));
+    assert!(ret.get("error").is_some());
+    let ret = chardev_add(&ts, "chr-null", json!({"type": "null"}));
+    assert_eq!(ret["return"], json!({}));
+    assert_eq!(
+        query_chardev(&ts, "chr-null").unwrap()["filename"],
+        json!("null")
+    );
+
+    // The server socket accepts client once added, and is gone once removed.
+    let sock_path = format!("/tmp/televm-chr-{}.sock", get_rand_str(8));
+    let ret = chardev_add(
+        &ts,
+        "chr-sock",
+        json!({"type": "socket", "data": {
+            "addr": {"type": "unix", "data": {"path": sock_path}},
+            "server": true, "wait": false}}),
+    );
+    assert_eq!(ret["return"], json!({}));
+    let mut client = UnixStream::connect(&sock_path).unwrap();
+    client.write_all(b"hello").unwrap();
+    assert_eq!(
+        query_chardev(&ts, "chr-sock").unwrap()["filename"],
+        json!(format!("unix:{},server=on", sock_path))
+    );
+    assert_eq!(chardev_remove(&ts, "chr-sock")["return"], json!({}));
+    assert!(!Path::new(&sock_path).exists());
+
+    // A hot-added server can't block waiting for client.
+    let ret = chardev_add(
+        &ts,
+        "chr-wait",
+        json!({"type": "socket", "data": {
+            "addr": {"type": "unix", "data": {"path": sock_path}},
+            "server": true, "wait": true}}),
+    );
+    assert!(ret.get("error").is_some());
+
+    assert_eq!(chardev_remove(&ts, "chr-pty")["return"], json!({}));
+    assert_eq!(chardev_remove(&ts, "chr-null")["return"], json!({}));
+    assert!(query_chardev(&ts, "chr-pty").is_none());
+    assert!(chardev_remove(&ts, "chr-pty").get("error").is_some());
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn chardev_serial_reference() {
+    let mut ts = test_init_prelaunch("chardev:ser0", vec!["-chardev", "null,id=ser0"]);
+
+    let info = query_chardev(&ts, "ser0").unwrap();
+    assert_eq!(info["frontend-open"], json!(true));
+    assert_eq!(info["filename"], json!("null"));
+    assert!(chardev_remove(&ts, "ser0").get("error").is_some());
+    // Chardev of command line keeps its id.
+    let ret = chardev_add(&ts, "ser0", json!({"type": "null"}));
+    assert!(ret.get("error").is_some());
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn chardev_serial_backend_uri() {
+    let log_path = format!("/tmp/televm-serial-{}.log", get_rand_str(8));
+    let mut ts = test_init_prelaunch(&format!("file:{}", log_path), Vec::new());
+    let info = query_chardev(&ts, "serial_chardev").unwrap();
+    assert_eq!(info["filename"], json!(format!("file:{}", log_path)));
+    assert!(Path::new(&log_path).exists());
+    ts.stop();
+    std::fs::remove_file(&log_path).unwrap();
+
+    // Server socket without client doesn't block the vm, and accepts client later.
+    let sock_path = format!("/tmp/televm-serial-{}.sock", get_rand_str(8));
+    let mut ts = test_init_prelaunch(&format!("unix:{},server,nowait", sock_path), Vec::new());
+    let info = query_chardev(&ts, "serial_chardev").unwrap();
+    assert_eq!(
+        info["filename"],
+        json!(format!("unix:{},server=on", sock_path))
+    );
+    let mut client = UnixStream::connect(&sock_path).unwrap();
+    client.write_all(b"\n").unwrap();
+    ts.stop();
+
+    let mut ts = test_init_prelaunch("null", Vec::new());
+    let info = query_chardev(&ts, "serial_chardev").unwrap();
+    assert_eq!(info["filename"], json!("null"));
+    ts.stop();
+}
@@ -0,0 +1,48 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::{test_init_prelaunch, TestState};
+
+fn expand_model(ts: &TestState, type_: &str, name: &str) -> Value {
+    ts.qmp(&format!(
+        "{{\"execute\": \"query-cpu-model-expansion\", \"arguments\": {{\"type\": \"{}\", \"model\": {{\"name\": \"{}\"}}}}}}",
+        type_, name
+    ))
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn cpu_model_expansion() {
+    // Vector and hypervisor extensions can always be turned off.
+    let mut ts = test_init_prelaunch("stdio", vec!["-cpu", "rv64,v=off,h=off"]);
+
+    let ret = expand_model(&ts, "full", "rv64");
+    let model = ret.get("return").unwrap().get("model").unwrap();
+    assert_eq!(*model.get("name").unwrap(), json!("rv64"));
+    let props = model.get("props").unwrap();
+    assert_eq!(*props.get("v").unwrap(), json!(false));
+    assert_eq!(*props.get("h").unwrap(), json!(false));
+    assert_eq!(*props.get("i").unwrap(), json!(true));
+    assert_eq!(*props.get("m").unwrap(), json!(true));
+
+    // Any model is expanded to the model of vcpus.
+    let ret = expand_model(&ts, "static", "host");
+    assert_eq!(ret.get("return").unwrap().get("model").unwrap(), model);
+
+    assert!(expand_model(&ts, "partial", "rv64").get("error").is_some());
+    assert!(expand_model(&ts, "full", "rv32").get("error").is_some());
+
+    ts.stop();
+}
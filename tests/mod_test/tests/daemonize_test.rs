@@ -0,0 +1,119 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::thread::sleep;
+use std::time::Duration;
+
+use mod_test::utils::get_tmp_dir;
+
+/// Launch a daemonized vm and return once the launching process exits. Its stdout
+/// and stderr are piped, so this hangs if the daemon keeps them open.
+fn launch_daemon(kernel: &str, pidfile: &str, log_file: &str) -> Output {
+    let shared_path = env::var("SHARED_PATH").unwrap();
+    Command::new(env::var("TELEVM_BINARY").unwrap())
+        .args(["-kernel", kernel])
+        .args(["-append", "root=/dev/vda rw console=ttyS0"])
+        .args([
+            "-drive",
+            &format!("id=rootfs,file={}/rootfs_guest.ext4", shared_path),
+        ])
+        .args(["-device", "virtio-blk-device,drive=rootfs,id=blk1"])
+        .args(["-serial", "null"])
+        .args(["-D", log_file])
+        .args(["-daemonize", "-pidfile", pidfile])
+        .output()
+        .unwrap()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn daemonize_waits_for_realize() {
+    let dir = get_tmp_dir();
+    let pidfile = format!("{}/televm.pid", dir);
+    let log_file = format!("{}/televm.log", dir);
+    let kernel = format!("{}/Image-6.9", env::var("SHARED_PATH").unwrap());
+
+    let output = launch_daemon(&kernel, &pidfile, &log_file);
+    assert_eq!(output.status.code(), Some(0));
+    // The vm is running when the launching process exits.
+    let pid: i32 = fs::read_to_string(&pidfile).unwrap().parse().unwrap();
+    assert_eq!(unsafe { libc::kill(pid, 0) }, 0);
+
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    for _ in 0..50 {
+        if !Path::new(&pidfile).exists() {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    assert!(!Path::new(&pidfile).exists());
+    fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn daemonize_reports_realize_error() {
+    let dir = get_tmp_dir();
+    let pidfile = format!("{}/televm.pid", dir);
+    let log_file = format!("{}/televm.log", dir);
+    // A truncated ELF passes the config check, and fails when it's loaded by realize.
+    let kernel = format!("{}/bad-kernel", dir);
+    fs::write(&kernel, b"\x7fELF\x02\x01\x01").unwrap();
+
+    let output = launch_daemon(&kernel, &pidfile, &log_file);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid ELF kernel image"),
+        "stderr: {}",
+        stderr
+    );
+    // The daemon cleans up before the launching process exits.
+    assert!(!Path::new(&pidfile).exists());
+    fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn daemonize_refuses_locked_pidfile() {
+    let dir = get_tmp_dir();
+    let pidfile = format!("{}/televm.pid", dir);
+    let log_file = format!("{}/televm.log", dir);
+    let kernel = format!("{}/Image-6.9", env::var("SHARED_PATH").unwrap());
+
+    let output = launch_daemon(&kernel, &pidfile, &log_file);
+    assert_eq!(output.status.code(), Some(0));
+    let pid = fs::read_to_string(&pidfile).unwrap();
+
+    // The second instance fails fast before forking, so the error is logged by
+    // itself, and it leaves the pidfile of the first one.
+    let output = launch_daemon(&kernel, &pidfile, &log_file);
+    assert_ne!(output.status.code(), Some(0));
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains(&format!("already running (pid {})", pid)));
+    assert_eq!(fs::read_to_string(&pidfile).unwrap(), pid);
+
+    unsafe { libc::kill(pid.parse().unwrap(), libc::SIGTERM) };
+    for _ in 0..50 {
+        if !Path::new(&pidfile).exists() {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    assert!(!Path::new(&pidfile).exists());
+    fs::remove_dir_all(dir).ok();
+}
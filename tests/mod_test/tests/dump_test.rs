@@ -0,0 +1,144 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::get_rand_str;
+
+const RAM_BASE: u64 = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+const ELF_HEADER_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+/// Size of each register note of vcpus.
+const NOTE_SIZE: usize = 396;
+
+fn dump_cmd(path: &str, extra: &str) -> String {
+    format!(
+        "{{\"execute\": \"dump-guest-memory\", \
+         \"arguments\": {{\"paging\": false, \"protocol\": \"file:{}\"{}}}}}",
+        path, extra
+    )
+}
+
+/// Run dump without detach, whose `DUMP_COMPLETED` is sent before the response.
+/// Returns data of the event and the response.
+fn dump_sync(ts: &TestState, cmd: &str) -> (Value, Value) {
+    let mut completed = Value::Null;
+    let mut ret = ts.qmp(cmd);
+    while ret.get("event").is_some() {
+        if ret["event"] == json!("DUMP_COMPLETED") {
+            completed = ret["data"].clone();
+        }
+        ret = ts.qmp_read();
+    }
+    (completed, ret)
+}
+
+fn wait_dump_completed(ts: &TestState) -> Value {
+    loop {
+        let ret = ts.qmp_read();
+        if ret["event"] == json!("DUMP_COMPLETED") {
+            return ret["data"].clone();
+        }
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn dump_guest_memory_window() {
+    let path = format!("/tmp/televm-dump-{}", get_rand_str(8));
+    let mut ts = test_init(vec!["-smp", "2"]);
+    let ret = ts.qmp_return("{\"execute\": \"stop\"}");
+    assert_eq!(ret["return"], json!({}));
+    let expected = ts.memread(RAM_BASE, 0x1000);
+
+    let extra = format!(", \"begin\": {}, \"length\": 4096", RAM_BASE);
+    let (data, ret) = dump_sync(&ts, &dump_cmd(&path, &extra));
+    assert_eq!(ret["return"], json!({}));
+    assert_eq!(data["result"]["status"], json!("completed"));
+    assert_eq!(data["result"]["total"], json!(0x1000));
+    // Vm paused before the dump stays paused.
+    assert_eq!(ts.run_status(), json!("paused"));
+
+    // One note of two vcpus, and one load of the window.
+    let core = fs::read(&path).unwrap();
+    assert_eq!(core[..4], [0x7f, b'E', b'L', b'F']);
+    assert_eq!(u16::from_le_bytes([core[56], core[57]]), 2);
+    let note = ELF_HEADER_SIZE;
+    let load = ELF_HEADER_SIZE + PHDR_SIZE;
+    assert_eq!(read_u64(&core, note + 32), 2 * NOTE_SIZE as u64);
+    assert_eq!(read_u64(&core, load + 24), RAM_BASE);
+    let offset = read_u64(&core, load + 8) as usize;
+    assert_eq!(core[offset..], expected[..]);
+    assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+    fs::remove_file(&path).unwrap();
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn dump_guest_memory_detach() {
+    let path = format!("/tmp/televm-dump-{}", get_rand_str(8));
+    let mut ts = test_init(vec![]);
+
+    let ret = ts.qmp_return(&dump_cmd(&path, ", \"detach\": true"));
+    assert_eq!(ret["return"], json!({}));
+    let data = wait_dump_completed(&ts);
+    assert_eq!(data["result"]["status"], json!("completed"));
+    let total = data["result"]["total"].as_u64().unwrap();
+    assert_eq!(data["result"]["completed"].as_u64().unwrap(), total);
+    // Running vm is resumed after the dump.
+    assert_eq!(ts.run_status(), json!("running"));
+
+    let headers = ELF_HEADER_SIZE + 2 * PHDR_SIZE + NOTE_SIZE;
+    assert_eq!(fs::metadata(&path).unwrap().len(), headers as u64 + total);
+    fs::remove_file(&path).unwrap();
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn dump_guest_memory_invalid() {
+    let path = format!("/tmp/televm-dump-{}", get_rand_str(8));
+    let mut ts = test_init(vec![]);
+
+    let ret = ts.qmp(
+        "{\"execute\": \"dump-guest-memory\", \
+         \"arguments\": {\"paging\": true, \"protocol\": \"file:/tmp/x\"}}",
+    );
+    assert!(ret.get("error").is_some());
+    let ret = ts.qmp(&dump_cmd(&path, ", \"begin\": 4096"));
+    assert!(ret.get("error").is_some());
+    let ret = ts.qmp(&dump_cmd(&path, ", \"begin\": 4096, \"length\": 4096"));
+    assert!(ret.get("error").is_some());
+    let ret = ts.qmp(&dump_cmd(&path, ", \"format\": \"kdump-zlib\""));
+    assert!(ret.get("error").is_some());
+    assert!(!Path::new(&path).exists());
+
+    // Failed dump removes its temporary file and resumes vm.
+    let path = format!("/tmp/televm-dump-{}/vmcore", get_rand_str(8));
+    let (data, ret) = dump_sync(&ts, &dump_cmd(&path, ""));
+    assert!(ret.get("error").is_some());
+    assert_eq!(data["result"]["status"], json!("failed"));
+    assert_eq!(ts.run_status(), json!("running"));
+    ts.stop();
+}
@@ -0,0 +1,148 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::ffi::CString;
+use std::io::{BufRead, BufReader};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::thread::sleep;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::get_rand_str;
+
+const MEMFD_SIZE: i64 = 1024 * 1024;
+
+fn create_memfd(name: &str) -> RawFd {
+    let name = CString::new(name).unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    assert!(fd >= 0);
+    assert_eq!(unsafe { libc::ftruncate(fd, MEMFD_SIZE) }, 0);
+    fd
+}
+
+fn query_fdsets(ts: &TestState) -> Vec<Value> {
+    let ret = ts.qmp("{\"execute\": \"query-fdsets\"}");
+    ret.get("return").unwrap().as_array().unwrap().clone()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn fdset_memfd_drive() {
+    let ts = test_init(Vec::new());
+    let memfd = create_memfd("televm-fdset");
+
+    // add-fd requires a fd passed along with it.
+    let ret = ts.qmp("{\"execute\": \"add-fd\"}");
+    assert!(ret.get("error").is_some());
+
+    let ret = ts.qmp_with_fd(
+        "{\"execute\": \"add-fd\", \"arguments\": {\"fdset-id\": 1, \"opaque\": \"memfd\"}}",
+        memfd,
+    );
+    let fd = ret["return"]["fd"].clone();
+    assert_eq!(ret["return"]["fdset-id"], json!(1));
+    unsafe { libc::close(memfd) };
+
+    let fdsets = query_fdsets(&ts);
+    assert_eq!(fdsets.len(), 1);
+    assert_eq!(fdsets[0]["fdset-id"], json!(1));
+    assert_eq!(fdsets[0]["fds"], json!([{"fd": fd, "opaque": "memfd"}]));
+
+    // The memfd is opened as a drive by the fd set path.
+    let ret = ts.blockdev_add("drive0", "/dev/fdset/1", "");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert!(ts
+        .blockdev_add("drive1", "/dev/fdset/2", "")
+        .get("error")
+        .is_some());
+    let ret = ts.qmp("{\"execute\": \"query-named-block-nodes\"}");
+    let nodes = ret.get("return").unwrap().as_array().unwrap();
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0]["file"], json!("/dev/fdset/1"));
+    assert_eq!(nodes[0]["image"]["virtual-size"], json!(MEMFD_SIZE));
+
+    // The drive owns a duplicated fd, so the fd set can be removed.
+    let ret = ts.qmp(&format!(
+        "{{\"execute\": \"remove-fd\", \"arguments\": {{\"fdset-id\": 1, \"fd\": {}}}}}",
+        fd
+    ));
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert!(query_fdsets(&ts).is_empty());
+    let ret = ts.qmp("{\"execute\": \"remove-fd\", \"arguments\": {\"fdset-id\": 1}}");
+    assert!(ret.get("error").is_some());
+
+    let ret = ts.qmp("{\"execute\": \"blockdev-del\", \"arguments\": {\"node-name\": \"drive0\"}}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    // Named fd by getfd can be closed by closefd.
+    let memfd = create_memfd("televm-getfd");
+    let ret = ts.qmp_with_fd(
+        "{\"execute\": \"getfd\", \"arguments\": {\"fdname\": \"fd0\"}}",
+        memfd,
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    unsafe { libc::close(memfd) };
+    let ret = ts.qmp("{\"execute\": \"closefd\", \"arguments\": {\"fdname\": \"fd0\"}}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let ret = ts.qmp("{\"execute\": \"closefd\", \"arguments\": {\"fdname\": \"fd0\"}}");
+    assert!(ret.get("error").is_some());
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn fdset_cleanup_on_disconnect() {
+    let mon_path = format!("/tmp/televm-mon-{}.sock", get_rand_str(8));
+    let chardev = format!("socket,id=mon0,path={},server,nowait", mon_path);
+    let ts = test_init(vec![
+        "-chardev",
+        &chardev,
+        "-mon",
+        "chardev=mon0,id=mon0,mode=control",
+    ]);
+
+    let stream = UnixStream::connect(&mon_path).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.contains("QMP"));
+
+    let memfd = create_memfd("televm-fdset");
+    let cmd = "{\"execute\": \"add-fd\", \"arguments\": {\"fdset-id\": 3}}";
+    stream.send_with_fds(&[cmd.as_bytes()], &[memfd]).unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.contains("\"fdset-id\":3"));
+    unsafe { libc::close(memfd) };
+    assert_eq!(query_fdsets(&ts).len(), 1);
+
+    // The fd left behind is closed when the client is gone.
+    drop(reader);
+    drop(stream);
+    let mut fdsets = query_fdsets(&ts);
+    for _ in 0..50 {
+        if fdsets.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+        fdsets = query_fdsets(&ts);
+    }
+    assert!(fdsets.is_empty());
+    assert!(ts
+        .blockdev_add("drive0", "/dev/fdset/3", "")
+        .get("error")
+        .is_some());
+}
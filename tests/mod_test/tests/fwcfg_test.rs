@@ -42,6 +42,41 @@ fn test_signature() {
     test_state.stop();
 }
 
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn test_boot_params() {
+    let mut test_state = test_init(Vec::new());
+    // Keep guest paused, so that its fw_cfg driver doesn't move the selector.
+    test_state.qmp("{\"execute\": \"stop\"}");
+    test_state.qmp_read();
+
+    let mut read_data: Vec<u8> = Vec::with_capacity(4);
+    test_state.fw_cfg_read_bytes(FwCfgEntryType::Signature as u16, &mut read_data, 4);
+    assert_eq!(read_data.as_slice(), b"QEMU");
+    // DMA interface is supported.
+    let read_data = test_state.fw_cfg_read_u32(FwCfgEntryType::Id as u16);
+    assert_eq!(read_data, 3);
+    let read_data = test_state.fw_cfg_read_u16(FwCfgEntryType::NbCpus as u16);
+    assert_eq!(read_data, 1);
+
+    let cmdline_size = test_state.fw_cfg_read_u32(FwCfgEntryType::CmdlineSize as u16);
+    let mut cmdline: Vec<u8> = Vec::new();
+    test_state.fw_cfg_read_bytes(
+        FwCfgEntryType::CmdlineData as u16,
+        &mut cmdline,
+        cmdline_size,
+    );
+    // Cmdline is a NUL-terminated string, with earlycon appended by serial.
+    assert_eq!(cmdline.pop(), Some(0));
+    let cmdline = String::from_utf8(cmdline).unwrap();
+    assert!(cmdline.starts_with("root=/dev/vda rw console=ttyS0"));
+    assert!(cmdline.contains("earlycon=uart,mmio,0x10000000"));
+
+    test_state.qmp("{\"execute\": \"cont\"}");
+    test_state.qmp_read();
+    test_state.stop();
+}
+
 #[test]
 fn test_id() {
     let mut args: Vec<&str> = Vec::new();
@@ -0,0 +1,137 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::test_init;
+
+const MEM_START: u64 = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+/// RAM below kernel, which is neither loaded nor touched by paused guest.
+const SPARE_ADDR: u64 = MEM_START + 0x10_0000;
+/// x0-x31 and pc.
+const NUM_REGS: usize = 33;
+const REG_A0: usize = 10;
+
+/// Minimal gdb remote protocol client.
+struct GdbClient {
+    stream: TcpStream,
+}
+
+impl GdbClient {
+    fn connect(addr: &str) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        GdbClient { stream }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0_u8; 1];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    /// Send `data` as a packet and return the reply packet.
+    fn request(&mut self, data: &str) -> String {
+        let sum = data.bytes().fold(0_u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, sum);
+        self.stream.write_all(packet.as_bytes()).unwrap();
+        assert_eq!(self.read_byte(), b'+');
+
+        while self.read_byte() != b'$' {}
+        let mut reply = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        let mut checksum = [0_u8; 2];
+        self.stream.read_exact(&mut checksum).unwrap();
+        let sum = reply.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b));
+        assert_eq!(
+            u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(),
+            sum
+        );
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+fn unused_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn gdbstub_regs_and_memory() {
+    let addr = format!("127.0.0.1:{}", unused_port());
+    let mut ts = test_init(vec!["-S", "-gdb", &format!("tcp:{}", addr)]);
+    let mut gdb = GdbClient::connect(&addr);
+
+    assert!(gdb.request("qSupported").contains("vContSupported+"));
+    assert_eq!(gdb.request("vCont?"), "vCont;c;C;s;S");
+    assert_eq!(gdb.request("?"), "T05thread:1;");
+
+    // Registers are little-endian hex, pc is the last one.
+    let regs = gdb.request("g");
+    assert_eq!(regs.len(), NUM_REGS * 16);
+    assert_eq!(&regs[..16], "0000000000000000");
+    let pc = u64::from_str_radix(&regs[32 * 16..], 16)
+        .unwrap()
+        .swap_bytes();
+    assert_eq!(pc, ts.boot_regs(0)[0]);
+
+    assert_eq!(
+        gdb.request(&format!("P{:x}=efcdab8967452301", REG_A0)),
+        "OK"
+    );
+    assert_eq!(gdb.request(&format!("p{:x}", REG_A0)), "efcdab8967452301");
+    // Writes to x0 are ignored.
+    assert_eq!(gdb.request("P0=0100000000000000"), "OK");
+    assert_eq!(gdb.request("p0"), "0000000000000000");
+    assert_eq!(gdb.request(&format!("G{}", regs)), "OK");
+    assert_eq!(gdb.request("g"), regs);
+    assert_eq!(gdb.request("p21"), "E16");
+
+    assert_eq!(gdb.request(&format!("M{:x},4:5aa55aa5", SPARE_ADDR)), "OK");
+    assert_eq!(gdb.request(&format!("m{:x},4", SPARE_ADDR)), "5aa55aa5");
+    assert_eq!(ts.memread(SPARE_ADDR, 4), vec![0x5a, 0xa5, 0x5a, 0xa5]);
+
+    // Breakpoints are ebreak written to guest memory, and removed with the original bytes.
+    assert_eq!(gdb.request(&format!("Z0,{:x},4", SPARE_ADDR)), "OK");
+    assert_eq!(ts.memread(SPARE_ADDR, 4), vec![0x73, 0x00, 0x10, 0x00]);
+    assert_eq!(gdb.request(&format!("z0,{:x},4", SPARE_ADDR)), "OK");
+    assert_eq!(ts.memread(SPARE_ADDR, 4), vec![0x5a, 0xa5, 0x5a, 0xa5]);
+    assert_eq!(gdb.request(&format!("Z0,{:x},2", SPARE_ADDR)), "OK");
+    assert_eq!(ts.memread(SPARE_ADDR, 2), vec![0x02, 0x90]);
+
+    // Detach removes the remaining breakpoint and resumes the vm.
+    assert_eq!(gdb.request("D"), "OK");
+    let event = ts.wait_qmp_event();
+    assert_eq!(*event.get("event").unwrap(), json!("RESUME"));
+    assert_eq!(ts.memread(SPARE_ADDR, 4), vec![0x5a, 0xa5, 0x5a, 0xa5]);
+    assert_eq!(ts.run_status(), json!("running"));
+
+    ts.stop();
+}
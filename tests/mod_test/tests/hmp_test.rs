@@ -0,0 +1,105 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use serde_json::json;
+
+use mod_test::libtest::test_init;
+use mod_test::utils::get_rand_str;
+
+const PROMPT: &str = "(televm) ";
+
+/// Read the output until the prompt, return it without the prompt.
+fn read_until_prompt(stream: &mut UnixStream) -> String {
+    let mut output = Vec::new();
+    let mut buf = [0_u8; 1024];
+    while !output.ends_with(PROMPT.as_bytes()) {
+        let len = stream.read(&mut buf).unwrap();
+        assert!(len > 0, "Monitor closed: {:?}", output);
+        output.extend_from_slice(&buf[..len]);
+    }
+    output.truncate(output.len() - PROMPT.len());
+    String::from_utf8(output).unwrap()
+}
+
+fn hmp(stream: &mut UnixStream, cmd: &[u8]) -> String {
+    // Send the line at once, or the monitor may take a partial line as a command.
+    stream.write_all(&[cmd, b"\n"].concat()).unwrap();
+    read_until_prompt(stream)
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn hmp_commands() {
+    let monitor_path = format!("/tmp/televm-hmp-{}.sock", get_rand_str(8));
+    let monitor = format!("unix:{},server,nowait", monitor_path);
+    let mut ts = test_init(vec!["-monitor", &monitor]);
+
+    let mut stream = UnixStream::connect(&monitor_path).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    assert!(read_until_prompt(&mut stream).contains("help"));
+
+    let help = hmp(&mut stream, b"help");
+    for cmd in ["info status", "info block", "info network", "balloon <MiB>"] {
+        assert!(help.contains(cmd));
+    }
+    assert_eq!(hmp(&mut stream, b""), "");
+    assert_eq!(hmp(&mut stream, b"info status"), "VM status: running\r\n");
+
+    // Stop and cont run by the qmp handlers, so qmp client gets the events.
+    assert_eq!(hmp(&mut stream, b"stop"), "");
+    assert_eq!(*ts.qmp_read().get("event").unwrap(), json!("STOP"));
+    assert_eq!(hmp(&mut stream, b"info status"), "VM status: paused\r\n");
+    assert_eq!(hmp(&mut stream, b"cont"), "");
+    assert_eq!(*ts.qmp_read().get("event").unwrap(), json!("RESUME"));
+    assert_eq!(
+        hmp(&mut stream, b"  info   status "),
+        "VM status: running\r\n"
+    );
+
+    assert_eq!(hmp(&mut stream, b"info network"), "No network backend\r\n");
+    assert_eq!(hmp(&mut stream, b"info block"), "No block backend\r\n");
+
+    // Errors of handlers and arguments are printed.
+    assert!(hmp(&mut stream, b"balloon 512").starts_with("Error: "));
+    assert_eq!(
+        hmp(&mut stream, b"balloon 1G"),
+        "Error: Invalid size: 1G, usage: balloon <MiB>\r\n"
+    );
+    assert_eq!(
+        hmp(&mut stream, b"stop now"),
+        "Error: Invalid arguments, usage: stop\r\n"
+    );
+
+    // Unknown commands list suggestions, arbitrary input does not break the monitor.
+    assert_eq!(
+        hmp(&mut stream, b"stpo"),
+        "Error: Unknown command: 'stpo', did you mean: stop\r\n"
+    );
+    assert_eq!(
+        hmp(&mut stream, b"info stat"),
+        "Error: Unknown command: 'info stat', did you mean: info status\r\n"
+    );
+    assert!(hmp(&mut stream, b"\xff\xfe\x00{\"execute\": \"quit\"}").contains("try 'help'"));
+    assert!(hmp(&mut stream, &[b'x'; 4096]).contains("..."));
+    assert_eq!(hmp(&mut stream, b"info status"), "VM status: running\r\n");
+
+    drop(stream);
+    std::fs::remove_file(&monitor_path).ok();
+    ts.stop();
+}
@@ -0,0 +1,95 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libdriver::malloc::GuestAllocator;
+use mod_test::libdriver::virtio::{VirtioDeviceOps, VIRTIO_F_VERSION_1};
+use mod_test::libdriver::virtio_block::{
+    add_blk_request, REQ_STATUS_OFFSET, TIMEOUT_US, VIRTIO_BLK_F_MQ, VIRTIO_BLK_S_OK,
+    VIRTIO_BLK_T_OUT,
+};
+use mod_test::libdriver::virtio_mmio::TestVirtioMmioDev;
+use mod_test::libtest::test_init_prelaunch;
+use mod_test::utils::{cleanup_img, create_img, TEST_IMAGE_SIZE};
+
+const VIRTIO_TYPE_BLOCK: u32 = 2;
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x50;
+const NUM_QUEUES: usize = 3;
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn virtio_mmio_queue_notify_datamatch() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let drive = format!("file={},id=drive0,direct=false", image_path);
+    let ts = test_init_prelaunch(
+        "stdio",
+        vec![
+            "-m",
+            "1G",
+            "-drive",
+            &drive,
+            "-device",
+            "virtio-blk-device,drive=drive0,id=blk0,num-queues=3",
+        ],
+    );
+    let test_state = Rc::new(RefCell::new(ts));
+    // RAM below kernel, which is not touched by paused guest.
+    let scratch_base = MEM_LAYOUT[LayoutEntryType::Mem as usize].0 + 0x10_0000;
+    let alloc = Rc::new(RefCell::new(GuestAllocator::new(
+        scratch_base,
+        0x10_0000,
+        0x1000,
+    )));
+
+    let mut dev = TestVirtioMmioDev::find(test_state.clone(), VIRTIO_TYPE_BLOCK).unwrap();
+    let features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_BLK_F_MQ);
+    let vqs = dev.init_device(test_state.clone(), alloc.clone(), features, NUM_QUEUES);
+
+    // Every queue is woken only by writing its own index, the others stay idle.
+    for (index, vq) in vqs.iter().enumerate().rev() {
+        let (free_head, req_addr) = add_blk_request(
+            test_state.clone(),
+            alloc.clone(),
+            vq.clone(),
+            VIRTIO_BLK_T_OUT,
+            index as u64,
+            false,
+        );
+        let other = ((index + 1) % NUM_QUEUES) as u32;
+        test_state
+            .borrow()
+            .writel(dev.base + VIRTIO_MMIO_QUEUE_NOTIFY, other);
+        sleep(Duration::from_millis(100));
+        assert!(!vq.borrow_mut().get_buf(test_state.clone()));
+
+        dev.virtqueue_notify(vq.clone());
+        dev.poll_used_elem(
+            test_state.clone(),
+            vq.clone(),
+            free_head,
+            TIMEOUT_US,
+            &mut None,
+            true,
+        );
+        let status = test_state.borrow().readb(req_addr + REQ_STATUS_OFFSET);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+    }
+
+    test_state.borrow_mut().stop();
+    cleanup_img(image_path);
+}
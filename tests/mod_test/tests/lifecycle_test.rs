@@ -0,0 +1,146 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init_prelaunch, test_init_with_serial};
+use mod_test::utils::get_rand_str;
+
+const CLINT_MTIME: u64 = MEM_LAYOUT[LayoutEntryType::Clint as usize].0 + 0xbff8;
+
+/// Collect guest console output for `duration`.
+fn serial_read(serial: &mut UnixStream, duration: Duration) -> Vec<u8> {
+    let start = Instant::now();
+    let mut output = Vec::new();
+    serial
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    while start.elapsed() < duration {
+        let mut buf = [0_u8; 1024];
+        match serial.read(&mut buf) {
+            Ok(size) => output.extend_from_slice(&buf[..size]),
+            Err(_) => continue,
+        }
+    }
+    output
+}
+
+/// Press enter on guest console, which makes the guest print a prompt if it runs.
+fn serial_poke(serial: &mut UnixStream) -> Vec<u8> {
+    serial.write_all(b"\n").unwrap();
+    serial_read(serial, Duration::from_secs(3))
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn stop_and_cont() {
+    let serial_path = format!("/tmp/televm-serial-{}.sock", get_rand_str(8));
+    let mut ts = test_init_with_serial(
+        &format!("socket,path={},server,nowait", serial_path),
+        Vec::new(),
+    );
+    let mut serial = UnixStream::connect(&serial_path).unwrap();
+
+    assert_eq!(
+        ts.query_status(),
+        json!({"running": true, "singlestep": false, "status": "running"})
+    );
+    assert!(!serial_poke(&mut serial).is_empty());
+
+    // Stop emits STOP event before the response.
+    let event = ts.qmp("{\"execute\": \"stop\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("STOP"));
+    let ret = ts.qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(
+        ts.query_status(),
+        json!({"running": false, "singlestep": false, "status": "paused"})
+    );
+
+    // Stop is idempotent.
+    let ret = ts.qmp("{\"execute\": \"stop\"}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    // Guest console goes quiet and CLINT timer doesn't move while paused.
+    serial_read(&mut serial, Duration::from_secs(1));
+    let mtime = ts.readq(CLINT_MTIME);
+    assert!(serial_poke(&mut serial).is_empty());
+    assert_eq!(ts.readq(CLINT_MTIME), mtime);
+
+    let event = ts.qmp("{\"execute\": \"cont\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("RESUME"));
+    let ret = ts.qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(
+        ts.query_status(),
+        json!({"running": true, "singlestep": false, "status": "running"})
+    );
+
+    // The enter pressed while paused is handled once resumed.
+    assert!(!serial_read(&mut serial, Duration::from_secs(3)).is_empty());
+    assert!(ts.readq(CLINT_MTIME) > mtime);
+
+    ts.stop();
+    std::fs::remove_file(&serial_path).ok();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn prelaunch_and_cont() {
+    let serial_path = format!("/tmp/televm-serial-{}.sock", get_rand_str(8));
+    let mut ts = test_init_prelaunch(
+        &format!("socket,path={},server,nowait", serial_path),
+        Vec::new(),
+    );
+    let mut serial = UnixStream::connect(&serial_path).unwrap();
+
+    assert_eq!(
+        ts.query_status(),
+        json!({"running": false, "singlestep": false, "status": "prelaunch"})
+    );
+    // Stop leaves the vm in prelaunch.
+    let ret = ts.qmp("{\"execute\": \"stop\"}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(
+        ts.query_status(),
+        json!({"running": false, "singlestep": false, "status": "prelaunch"})
+    );
+
+    // Guest doesn't run, neither does CLINT timer, while devices and memory are
+    // accessible.
+    let mtime = ts.readq(CLINT_MTIME);
+    assert!(serial_read(&mut serial, Duration::from_secs(1)).is_empty());
+    assert_eq!(ts.readq(CLINT_MTIME), mtime);
+    assert_ne!(ts.memread(ts.boot_regs(0)[0], 8), vec![0_u8; 8]);
+
+    let event = ts.qmp("{\"execute\": \"cont\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("RESUME"));
+    let ret = ts.qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(
+        ts.query_status(),
+        json!({"running": true, "singlestep": false, "status": "running"})
+    );
+
+    // Guest boots and prints to console.
+    assert!(!serial_read(&mut serial, Duration::from_secs(10)).is_empty());
+    assert!(ts.readq(CLINT_MTIME) > mtime);
+
+    ts.stop();
+    std::fs::remove_file(&serial_path).ok();
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::get_tmp_dir;
+
+/// Issue `count` qmp commands, each logged with a marker `<prefix>-<index>`.
+fn log_markers(ts: &TestState, prefix: &str, count: usize) {
+    for i in 0..count {
+        let ret = ts.qmp(&format!(
+            "{{\"execute\": \"query-status\", \"id\": \"{}-{}\"}}",
+            prefix, i
+        ));
+        assert!(ret.get("return").is_some());
+    }
+}
+
+/// Markers with `prefix` in the log file and its rotated files, sorted.
+fn read_markers(log_file: &str, prefix: &str) -> Vec<String> {
+    let mut files = vec![log_file.to_string()];
+    let mut index = 1;
+    while Path::new(&format!("{}.{}", log_file, index)).exists() {
+        files.push(format!("{}.{}", log_file, index));
+        index += 1;
+    }
+
+    let mut markers = Vec::new();
+    for file in files {
+        let content = fs::read_to_string(&file).unwrap();
+        // Both the command and its response carry the id, take the commands only.
+        for line in content.lines().filter(|line| line.contains("QMP: <--")) {
+            if let Some(pos) = line.find(&format!("\"{}-", prefix)) {
+                let marker = &line[pos + 1..];
+                markers.push(marker[..marker.find('"').unwrap()].to_string());
+            }
+        }
+    }
+    markers.sort();
+    markers
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn log_file_rotation() {
+    let dir = get_tmp_dir();
+    let log_file = format!("{}/televm.log", dir);
+    let log_arg = format!("{},rotate-size=4K,rotate-keep=100", log_file);
+    let mut ts = test_init(vec!["-D", &log_arg]);
+
+    log_markers(&ts, "rotate", 200);
+    ts.stop();
+
+    assert!(Path::new(&format!("{}.3", log_file)).exists());
+    assert!(fs::metadata(format!("{}.1", log_file)).unwrap().len() <= 4096);
+    let mut expected: Vec<String> = (0..200).map(|i| format!("rotate-{}", i)).collect();
+    expected.sort();
+    assert_eq!(read_markers(&log_file, "rotate"), expected);
+    fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn log_file_reopen_on_sighup() {
+    let dir = get_tmp_dir();
+    let log_file = format!("{}/televm.log", dir);
+    let moved = format!("{}/televm.log.old", dir);
+    let mut ts = test_init(vec!["-D", &log_file]);
+
+    log_markers(&ts, "before", 1);
+    fs::rename(&log_file, &moved).unwrap();
+    unsafe { libc::kill(ts.pid() as i32, libc::SIGHUP) };
+    sleep(Duration::from_millis(100));
+    // Still running after SIGHUP, and logging to the new file.
+    log_markers(&ts, "after", 1);
+    ts.stop();
+
+    assert_eq!(read_markers(&moved, "before"), vec!["before-0"]);
+    assert_eq!(read_markers(&log_file, "after"), vec!["after-0"]);
+    assert!(read_markers(&log_file, "before").is_empty());
+    fs::remove_dir_all(dir).ok();
+}
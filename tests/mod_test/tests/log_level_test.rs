@@ -0,0 +1,61 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs;
+
+use serde_json::json;
+
+use mod_test::libtest::test_init;
+use mod_test::utils::get_tmp_dir;
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn log_level_set_at_runtime() {
+    let log_file = format!("{}/televm.log", get_tmp_dir());
+    let mut ts = test_init(vec![
+        "-D",
+        &log_file,
+        "-log-level",
+        "machine_manager=warn,info",
+    ]);
+
+    // QMP commands are logged at info level, which is filtered out for machine_manager.
+    let ret = ts.qmp("{\"execute\": \"query-status\", \"id\": \"marker-before\"}");
+    assert!(ret.get("return").is_some());
+
+    let ret = ts.qmp(
+        "{\"execute\": \"set-log-level\", \"arguments\": {\"filter\": \"machine_manager=info,warn\"}}",
+    );
+    assert_eq!(ret["return"], json!({}));
+    let ret = ts.qmp("{\"execute\": \"query-status\", \"id\": \"marker-after\"}");
+    assert!(ret.get("return").is_some());
+
+    // An invalid filter is rejected and the current one is kept.
+    for filter in ["nonexist=debug", "virtio=loud", "verbose"] {
+        let ret = ts.qmp(&format!(
+            "{{\"execute\": \"set-log-level\", \"arguments\": {{\"filter\": \"{}\"}}}}",
+            filter
+        ));
+        assert_eq!(ret["error"]["class"], json!("GenericError"));
+    }
+    let ret = ts.qmp("{\"execute\": \"query-status\", \"id\": \"marker-kept\"}");
+    assert!(ret.get("return").is_some());
+
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(!log.contains("marker-before"));
+    assert!(log.contains("marker-after"));
+    assert!(log.contains("marker-kept"));
+
+    ts.stop();
+    fs::remove_file(log_file).ok();
+}
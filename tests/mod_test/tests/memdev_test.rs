@@ -0,0 +1,158 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::get_rand_str;
+
+/// Size of each backend, the alignment of hotplugged memory.
+const BACKEND_SIZE: u64 = 0x800_0000;
+
+fn object_add(ts: &TestState, args: Value) -> Value {
+    let cmd = json!({ "execute": "object-add", "arguments": args });
+    ts.qmp(&cmd.to_string())
+}
+
+fn plug_dimm(ts: &TestState, id: &str, memdev: &str) -> Value {
+    let cmd = json!({
+        "execute": "device_add",
+        "arguments": { "id": id, "driver": "pc-dimm", "memdev": memdev }
+    });
+    ts.qmp(&cmd.to_string())
+}
+
+fn memdev(ts: &TestState, id: &str) -> Value {
+    let ret = ts.qmp("{\"execute\": \"query-memdev\"}");
+    let memdevs = ret["return"].as_array().unwrap();
+    memdevs
+        .iter()
+        .find(|memdev| memdev["id"] == json!(id))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+fn plugged_memory(ts: &TestState) -> (Value, Value) {
+    let ret = ts.qmp("{\"execute\": \"query-memory-size-summary\"}");
+    (
+        ret["return"]["base-memory"].clone(),
+        ret["return"]["plugged-memory"].clone(),
+    )
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn memdev_file_and_anonymous() {
+    // The existing file is kept after the VM exits.
+    let path = format!("/tmp/televm-memdev-{}", get_rand_str(8));
+    std::fs::File::create(&path).unwrap();
+    let mut ts = test_init(vec![]);
+    let (base, plugged) = plugged_memory(&ts);
+    assert_eq!(plugged, json!(0));
+
+    // mem-path is required by and only by memory-backend-file.
+    let ret = object_add(
+        &ts,
+        json!({ "qom-type": "memory-backend-file", "id": "mem-file", "size": BACKEND_SIZE }),
+    );
+    assert!(ret.get("error").is_some());
+    let ret = object_add(
+        &ts,
+        json!({ "qom-type": "memory-backend-ram", "id": "mem-anon", "size": BACKEND_SIZE,
+                "mem-path": path }),
+    );
+    assert!(ret.get("error").is_some());
+
+    let ret = object_add(
+        &ts,
+        json!({ "qom-type": "memory-backend-file", "id": "mem-file", "size": BACKEND_SIZE,
+                "mem-path": path, "share": true, "prealloc": true }),
+    );
+    assert_eq!(ret["return"], json!({}));
+    let ret = object_add(
+        &ts,
+        json!({ "qom-type": "memory-backend-ram", "id": "mem-anon", "size": BACKEND_SIZE,
+                "merge": true, "dump": false }),
+    );
+    assert_eq!(ret["return"], json!({}));
+
+    assert_eq!(
+        memdev(&ts, "mem-file"),
+        json!({ "id": "mem-file", "type": "memory-backend-file", "size": BACKEND_SIZE,
+                "merge": false, "dump": true, "prealloc": true, "host-nodes": [],
+                "share": true, "plugged": false })
+    );
+    assert_eq!(
+        memdev(&ts, "mem-anon"),
+        json!({ "id": "mem-anon", "type": "memory-backend-ram", "size": BACKEND_SIZE,
+                "merge": true, "dump": false, "prealloc": false, "host-nodes": [],
+                "share": false, "plugged": false })
+    );
+    assert_eq!(plugged_memory(&ts), (base.clone(), json!(0)));
+
+    // Both are reflected once they are plugged one after another.
+    let ret = plug_dimm(&ts, "dimm-file", "mem-file");
+    assert_eq!(ret["return"], json!({}));
+    assert_eq!(memdev(&ts, "mem-file")["plugged"], json!(true));
+    assert_eq!(memdev(&ts, "mem-anon")["plugged"], json!(false));
+    assert_eq!(plugged_memory(&ts), (base.clone(), json!(BACKEND_SIZE)));
+    // The file is extended to the size of the backend.
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), BACKEND_SIZE);
+
+    let ret = plug_dimm(&ts, "dimm-anon", "mem-anon");
+    assert_eq!(ret["return"], json!({}));
+    assert_eq!(memdev(&ts, "mem-anon")["plugged"], json!(true));
+    assert_eq!(plugged_memory(&ts), (base, json!(2 * BACKEND_SIZE)));
+
+    ts.stop();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn memdev_plug_within_maxmem_and_slots() {
+    let backend = |ts: &TestState, id: &str| {
+        let ret = object_add(
+            ts,
+            json!({ "qom-type": "memory-backend-ram", "id": id, "size": BACKEND_SIZE }),
+        );
+        assert_eq!(ret["return"], json!({}));
+    };
+
+    // Room is left for one backend above the boot memory.
+    let mut ts = test_init(vec!["-m", "size=256M,maxmem=384M,slots=2"]);
+    assert_eq!(plugged_memory(&ts), (json!(256 << 20), json!(0)));
+    backend(&ts, "mem0");
+    backend(&ts, "mem1");
+    let ret = plug_dimm(&ts, "dimm0", "mem0");
+    assert_eq!(ret["return"], json!({}));
+    let ret = plug_dimm(&ts, "dimm1", "mem1");
+    assert!(ret.get("error").is_some());
+    assert_eq!(memdev(&ts, "mem1")["plugged"], json!(false));
+    assert_eq!(plugged_memory(&ts), (json!(256 << 20), json!(BACKEND_SIZE)));
+    ts.stop();
+
+    // The count of plugged memory is limited by slots.
+    let mut ts = test_init(vec!["-m", "size=256M,maxmem=1G,slots=1"]);
+    backend(&ts, "mem0");
+    backend(&ts, "mem1");
+    let ret = plug_dimm(&ts, "dimm0", "mem0");
+    assert_eq!(ret["return"], json!({}));
+    let ret = plug_dimm(&ts, "dimm1", "mem1");
+    assert!(ret["error"]["desc"]
+        .as_str()
+        .unwrap()
+        .contains("all 1 slots are used"));
+    assert_eq!(plugged_memory(&ts), (json!(256 << 20), json!(BACKEND_SIZE)));
+    ts.stop();
+}
@@ -0,0 +1,186 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, test_init_prelaunch, TestState};
+use mod_test::utils::get_rand_str;
+
+const PLIC_BASE: u64 = MEM_LAYOUT[LayoutEntryType::Plic as usize].0;
+/// Threshold register of context 0, which is M-mode one and not used by guest.
+const M_CONTEXT_THRESHOLD: u64 = PLIC_BASE + 0x0020_0000;
+
+fn migrate_cmd(path: &str) -> String {
+    format!(
+        "{{\"execute\": \"migrate\", \"arguments\": {{\"uri\": \"unix:{}\"}}}}",
+        path
+    )
+}
+
+fn set_compression(ts: &TestState, level: u8, cache_size: u64) {
+    let ret = ts.qmp(&format!(
+        "{{\"execute\": \"migrate-set-parameters\", \
+         \"arguments\": {{\"compress-level\": {}, \"xbzrle-cache-size\": {}}}}}",
+        level, cache_size
+    ));
+    assert_eq!(ret["return"], json!({}));
+}
+
+/// Poll `query-migrate` until migration leaves active state.
+fn wait_migration(ts: &TestState) -> Value {
+    let start = Instant::now();
+    loop {
+        let ret = ts.qmp_return("{\"execute\": \"query-migrate\"}");
+        if ret["return"]["status"] != json!("active") {
+            return ret["return"].clone();
+        }
+        assert!(start.elapsed() < Duration::from_secs(60));
+        sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn migrate_unix() {
+    let path = format!("/tmp/televm-migrate-{}.sock", get_rand_str(8));
+    let mut src = test_init(vec![]);
+    src.writel(M_CONTEXT_THRESHOLD, 5);
+
+    let incoming = format!("unix:{}", path);
+    let mut dst = test_init_prelaunch("stdio", vec!["-incoming", &incoming]);
+    assert_eq!(dst.run_status(), json!("prelaunch"));
+    // Vcpus are kept parked until the migrated vm is restored.
+    let ret = dst.qmp("{\"execute\": \"cont\"}");
+    assert!(ret.get("error").is_some());
+
+    let ret = src.qmp_return(&migrate_cmd(&path));
+    assert_eq!(ret["return"], json!({}));
+    let info = wait_migration(&src);
+    assert_eq!(info["status"], json!("completed"));
+    let total = info["ram"]["total"].as_u64().unwrap();
+    assert!(info["ram"]["transferred"].as_u64().unwrap() >= total);
+    assert!(info["downtime"].as_u64().is_some());
+    assert_eq!(src.run_status(), json!("paused"));
+
+    let info = wait_migration(&dst);
+    assert_eq!(info["status"], json!("completed"));
+    assert_eq!(dst.run_status(), json!("running"));
+    assert_eq!(dst.readl(M_CONTEXT_THRESHOLD), 5);
+    assert!(!std::path::Path::new(&path).exists());
+
+    src.stop();
+    dst.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn migrate_parameters() {
+    let mut ts = test_init(vec![]);
+    let ret = ts.qmp(
+        "{\"execute\": \"migrate-set-parameters\", \
+         \"arguments\": {\"max-bandwidth\": 1048576, \"downtime-limit\": 100}}",
+    );
+    assert_eq!(ret["return"], json!({}));
+    let ret = ts.qmp("{\"execute\": \"migrate-set-parameters\", \"arguments\": {}}");
+    assert_eq!(ret["return"], json!({}));
+    let ret = ts.qmp("{\"execute\": \"query-migrate-parameters\"}");
+    assert_eq!(
+        ret["return"],
+        json!({"max-bandwidth": 1048576, "downtime-limit": 100,
+               "compress-level": 0, "xbzrle-cache-size": 0})
+    );
+    let ret = ts
+        .qmp("{\"execute\": \"migrate-set-parameters\", \"arguments\": {\"compress-level\": 20}}");
+    assert!(ret.get("error").is_some());
+
+    // Nothing to cancel without migration in progress.
+    let ret = ts.qmp("{\"execute\": \"migrate_cancel\"}");
+    assert!(ret.get("error").is_some());
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn migrate_mismatch() {
+    let path = format!("/tmp/televm-migrate-{}.sock", get_rand_str(8));
+    let mut src = test_init(vec![]);
+    let incoming = format!("unix:{}", path);
+    let mut dst = test_init_prelaunch("stdio", vec!["-incoming", &incoming, "-m", "512M"]);
+
+    // Destination with other memory refuses the migration, and source keeps running.
+    let ret = src.qmp_return(&migrate_cmd(&path));
+    assert_eq!(ret["return"], json!({}));
+    assert_eq!(wait_migration(&src)["status"], json!("failed"));
+    assert_eq!(src.run_status(), json!("running"));
+    assert_eq!(wait_migration(&dst)["status"], json!("failed"));
+    assert_eq!(dst.run_status(), json!("prelaunch"));
+
+    src.stop();
+    dst.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn migrate_compressed() {
+    let path = format!("/tmp/televm-migrate-{}.sock", get_rand_str(8));
+    let mut src = test_init(vec![]);
+    src.writel(M_CONTEXT_THRESHOLD, 5);
+    set_compression(&src, 3, 0x100_0000);
+    let incoming = format!("unix:{}", path);
+    let mut dst = test_init_prelaunch("stdio", vec!["-incoming", &incoming]);
+    set_compression(&dst, 1, 0x1000);
+
+    let ret = src.qmp_return(&migrate_cmd(&path));
+    assert_eq!(ret["return"], json!({}));
+    let info = wait_migration(&src);
+    assert_eq!(info["status"], json!("completed"));
+    // Mostly zero RAM of guest is compressed well.
+    let total = info["ram"]["total"].as_u64().unwrap();
+    assert!(info["ram"]["transferred"].as_u64().unwrap() < total);
+    assert!(info["compression"]["compression-rate"].as_f64().unwrap() > 1.0);
+    assert_eq!(info["xbzrle-cache"]["cache-size"], json!(0x100_0000));
+
+    assert_eq!(wait_migration(&dst)["status"], json!("completed"));
+    assert_eq!(dst.run_status(), json!("running"));
+    assert_eq!(dst.readl(M_CONTEXT_THRESHOLD), 5);
+
+    src.stop();
+    dst.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn migrate_compression_mismatch() {
+    let path = format!("/tmp/televm-migrate-{}.sock", get_rand_str(8));
+    let mut src = test_init(vec![]);
+    set_compression(&src, 3, 0);
+    let incoming = format!("unix:{}", path);
+    let mut dst = test_init_prelaunch("stdio", vec!["-incoming", &incoming]);
+
+    // Destination not configured for zstd refuses the stream before any RAM is sent.
+    let ret = src.qmp_return(&migrate_cmd(&path));
+    assert_eq!(ret["return"], json!({}));
+    let info = wait_migration(&src);
+    assert_eq!(info["status"], json!("failed"));
+    assert_eq!(info["ram"]["transferred"], json!(0));
+    assert_eq!(src.run_status(), json!("running"));
+    assert_eq!(wait_migration(&dst)["status"], json!("failed"));
+    assert_eq!(dst.run_status(), json!("prelaunch"));
+
+    src.stop();
+    dst.stop();
+}
@@ -0,0 +1,67 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::{test_init_prelaunch, TestState};
+
+const MEM_SIZE: u64 = 1 << 30;
+
+fn balloon(ts: &TestState, value: u64) -> Value {
+    ts.qmp(&json!({"execute": "balloon", "arguments": {"value": value}}).to_string())
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn virtio_mmio_balloon_target() {
+    let mut ts = test_init_prelaunch(
+        "stdio",
+        vec!["-m", "1G", "-device", "virtio-balloon-device,id=balloon0"],
+    );
+
+    let ret = ts.qmp("{\"execute\": \"query-balloon\"}");
+    assert_eq!(
+        *ret.get("return").unwrap(),
+        json!({"actual": MEM_SIZE, "target": MEM_SIZE})
+    );
+
+    // Target below 64MiB or above the configured memory is refused.
+    for value in [(64 << 20) - 1, MEM_SIZE + 1] {
+        let ret = balloon(&ts, value);
+        assert_eq!(ret["error"]["class"], json!("GenericError"));
+    }
+
+    let ret = balloon(&ts, 512 << 20);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    // Guest driver is not running yet, so nothing is given up.
+    let ret = ts.qmp("{\"execute\": \"query-balloon\"}");
+    assert_eq!(
+        *ret.get("return").unwrap(),
+        json!({"actual": MEM_SIZE, "target": 512 << 20})
+    );
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn virtio_mmio_balloon_absent() {
+    let mut ts = test_init_prelaunch("stdio", Vec::new());
+
+    let ret = balloon(&ts, 512 << 20);
+    assert_eq!(ret["error"]["class"], json!("DeviceNotActive"));
+    let ret = ts.qmp("{\"execute\": \"query-balloon\"}");
+    assert_eq!(ret["error"]["class"], json!("DeviceNotActive"));
+
+    ts.stop();
+}
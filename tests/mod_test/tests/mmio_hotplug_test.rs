@@ -0,0 +1,106 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeSet;
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::{cleanup_img, create_img, TEST_IMAGE_SIZE};
+
+const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x00;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x08;
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_TYPE_BLOCK: u32 = 2;
+const MMIO_SLOTS_SCANNED: u64 = 16;
+
+/// Bases of the virtio-mmio transports mapped in the MMIO window.
+fn mapped_transports(ts: &TestState) -> BTreeSet<u64> {
+    let (base, size) = MEM_LAYOUT[LayoutEntryType::Mmio as usize];
+    (0..MMIO_SLOTS_SCANNED)
+        .map(|slot| base + slot * size)
+        .filter(|addr| ts.readl(addr + VIRTIO_MMIO_MAGIC_VALUE) == VIRTIO_MMIO_MAGIC)
+        .collect()
+}
+
+fn device_add_blk(ts: &TestState, id: &str, drive: &str) -> serde_json::Value {
+    ts.qmp(&format!(
+        "{{\"execute\": \"device_add\", \"arguments\": {{\"id\": \"{}\", \
+         \"driver\": \"virtio-blk-device\", \"drive\": \"{}\"}}}}",
+        id, drive
+    ))
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn virtio_mmio_hotplug_cycle() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let mut ts = test_init(Vec::new());
+    let boot_transports = mapped_transports(&ts);
+
+    let ret = ts.blockdev_add("drive0", &image_path, "");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    for _ in 0..2 {
+        let ret = device_add_blk(&ts, "blk0", "drive0");
+        assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+        // Transport of the plugged device appears in an empty slot.
+        let transports = mapped_transports(&ts);
+        let plugged: Vec<&u64> = transports.difference(&boot_transports).collect();
+        assert_eq!(plugged.len(), 1);
+        assert_eq!(
+            ts.readl(plugged[0] + VIRTIO_MMIO_DEVICE_ID),
+            VIRTIO_TYPE_BLOCK
+        );
+        assert!(boot_transports.is_subset(&transports));
+
+        let ret = device_add_blk(&ts, "blk0", "drive0");
+        assert!(ret.get("error").is_some());
+
+        let event = ts.qmp("{\"execute\": \"device_del\", \"arguments\": {\"id\": \"blk0\"}}");
+        assert_eq!(*event.get("event").unwrap(), json!("DEVICE_DELETED"));
+        assert_eq!(
+            *event.get("data").unwrap().get("device").unwrap(),
+            json!("blk0")
+        );
+        let ret = ts.qmp_read();
+        assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+        // Transport disappears, and the slot is reused by the next device.
+        assert_eq!(mapped_transports(&ts), boot_transports);
+    }
+
+    let ret = ts.qmp("{\"execute\": \"device_del\", \"arguments\": {\"id\": \"blk0\"}}");
+    assert!(ret.get("error").is_some());
+
+    // Backend is released by device_del and can be removed.
+    let ret = ts.qmp("{\"execute\": \"blockdev-del\", \"arguments\": {\"node-name\": \"drive0\"}}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    ts.stop();
+    cleanup_img(image_path);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn virtio_mmio_hotplug_missing_backend() {
+    let mut ts = test_init(Vec::new());
+    let boot_transports = mapped_transports(&ts);
+
+    let ret = device_add_blk(&ts, "blk0", "drive0");
+    assert!(ret.get("error").is_some());
+    assert_eq!(mapped_transports(&ts), boot_transports);
+
+    ts.stop();
+}
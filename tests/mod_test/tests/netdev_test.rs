@@ -0,0 +1,95 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::process::Command;
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::{test_init, TestState};
+
+const TAP_NAME: &str = "qtapnd0";
+
+/// Execute cmd used to create or delete tap.
+fn execute_cmd(args: &[&str]) {
+    let output = Command::new(args[0])
+        .args(&args[1..])
+        .output()
+        .unwrap_or_else(|_| panic!("Failed to execute {:?}", args));
+    assert!(output.status.success());
+}
+
+fn netdev_add(ts: &TestState, arguments: Value) -> Value {
+    ts.qmp(&json!({"execute": "netdev_add", "arguments": arguments}).to_string())
+}
+
+fn netdev_del(ts: &TestState, id: &str) -> Value {
+    ts.qmp(&json!({"execute": "netdev_del", "arguments": {"id": id}}).to_string())
+}
+
+fn query_netdev(ts: &TestState) -> Vec<Value> {
+    let ret = ts.qmp("{\"execute\": \"query-netdev\"}");
+    ret.get("return").unwrap().as_array().unwrap().clone()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn netdev_add_del() {
+    execute_cmd(&["ip", "tuntap", "add", TAP_NAME, "mode", "tap"]);
+    let mut ts = test_init(Vec::new());
+
+    let ret = netdev_add(
+        &ts,
+        json!({"id": "net-0", "type": "tap", "ifname": TAP_NAME, "script": "no", "vhost": "off"}),
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let netdevs = query_netdev(&ts);
+    assert_eq!(
+        netdevs,
+        vec![json!({"id": "net-0", "type": "tap", "ifname": TAP_NAME})]
+    );
+
+    // Invalid backends are refused without being registered.
+    let invalid = [
+        json!({"id": "net-0", "ifname": TAP_NAME}),
+        json!({"id": "net-1", "type": "tap"}),
+        json!({"id": "net-1", "ifname": TAP_NAME, "script": "/etc/qemu-ifup"}),
+        json!({"id": "net-1", "ifname": TAP_NAME, "vhost": "on"}),
+        json!({"id": "net-1", "type": "fd", "fds": "fd-missing"}),
+        json!({"id": "net-1", "type": "vhost-user", "chardev": "chr0"}),
+    ];
+    for arguments in invalid {
+        assert!(netdev_add(&ts, arguments).get("error").is_some());
+    }
+    assert_eq!(query_netdev(&ts).len(), 1);
+
+    let ret = ts.qmp(
+        "{\"execute\": \"device_add\", \"arguments\": {\"id\": \"net0\", \
+         \"driver\": \"virtio-net-device\", \"netdev\": \"net-0\"}}",
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(query_netdev(&ts)[0]["device"], json!("net0"));
+    assert!(netdev_del(&ts, "net-0").get("error").is_some());
+
+    let event = ts.qmp("{\"execute\": \"device_del\", \"arguments\": {\"id\": \"net0\"}}");
+    assert_eq!(*event.get("event").unwrap(), json!("DEVICE_DELETED"));
+    let ret = ts.qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    let ret = netdev_del(&ts, "net-0");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert!(netdev_del(&ts, "net-0").get("error").is_some());
+    assert!(query_netdev(&ts).is_empty());
+
+    ts.stop();
+    execute_cmd(&["ip", "tuntap", "del", TAP_NAME, "mode", "tap"]);
+}
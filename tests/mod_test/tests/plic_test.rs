@@ -0,0 +1,72 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, TestState};
+
+const PLIC_BASE: u64 = MEM_LAYOUT[LayoutEntryType::Plic as usize].0;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_PER_HART: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x0020_0000;
+const CONTEXT_PER_HART: u64 = 0x1000;
+const CONTEXT_THRESHOLD: u64 = 0;
+const CONTEXT_CLAIM: u64 = 4;
+
+const NR_HARTS: u64 = 4;
+
+fn context_reg(context: u64, reg: u64) -> u64 {
+    PLIC_BASE + CONTEXT_BASE + context * CONTEXT_PER_HART + reg
+}
+
+fn set_up() -> TestState {
+    let extra_args: Vec<&str> = "-machine microvm -smp 4".split(' ').collect();
+    test_init(extra_args)
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn check_smp_contexts() {
+    let mut ts = set_up();
+
+    let ret = ts.qmp("{\"execute\": \"query-cpus\"}");
+    let cpus = ret.get("return").unwrap().as_array().unwrap();
+    assert_eq!(cpus.len(), NR_HARTS as usize);
+    for (index, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.get("CPU").unwrap().as_u64().unwrap(), index as u64);
+        assert_eq!(cpu.get("current").unwrap().as_bool().unwrap(), index == 0);
+    }
+
+    // Two contexts per hart, M-mode ones are not used by guest.
+    for context in 0..NR_HARTS * 2 {
+        if context % 2 == 0 {
+            ts.writel(context_reg(context, CONTEXT_THRESHOLD), 0x5);
+            assert_eq!(ts.readl(context_reg(context, CONTEXT_THRESHOLD)), 0x5);
+            ts.writel(context_reg(context, CONTEXT_THRESHOLD), 0);
+            assert_eq!(ts.readl(context_reg(context, CONTEXT_CLAIM)), 0);
+        } else {
+            // Guest owns S-mode contexts, only check the claim register responds.
+            assert!(ts.readl(context_reg(context, CONTEXT_CLAIM)) < 0x100);
+        }
+    }
+
+    // Enable registers are accessed as 32-bit words.
+    let enable = PLIC_BASE + ENABLE_BASE + ENABLE_PER_HART * 2 * (NR_HARTS - 1);
+    ts.writel(enable, 0x8000_0100);
+    assert_eq!(ts.readl(enable), 0x8000_0100);
+    ts.writel(enable, 0);
+
+    // Contexts beyond the last hart don't exist.
+    assert_eq!(ts.readl(context_reg(NR_HARTS * 2, CONTEXT_THRESHOLD)), 0);
+
+    ts.stop();
+}
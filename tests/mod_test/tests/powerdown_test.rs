@@ -0,0 +1,91 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, TestState};
+
+const GPIO_ADDR: u64 = MEM_LAYOUT[LayoutEntryType::Gpio as usize].0;
+const GPIO_INPUT_VAL: u64 = 0x00;
+const GPIO_INPUT_EN: u64 = 0x04;
+const GPIO_RISE_IE: u64 = 0x18;
+const GPIO_RISE_IP: u64 = 0x1c;
+const GPIO_FALL_IP: u64 = 0x24;
+
+/// Press power button, whose POWERDOWN event comes before the return.
+fn powerdown(ts: &TestState) {
+    let event = ts.qmp("{\"execute\": \"system_powerdown\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("POWERDOWN"));
+    assert_eq!(*ts.qmp_read().get("return").unwrap(), json!({}));
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn powerdown_button() {
+    let mut ts = test_init(Vec::new());
+    // Gpio-keys requests the line of power button as input.
+    ts.writel(GPIO_ADDR + GPIO_INPUT_EN, 1);
+    ts.writel(GPIO_ADDR + GPIO_RISE_IE, 1);
+    assert_eq!(ts.readl(GPIO_ADDR + GPIO_INPUT_VAL), 0);
+
+    powerdown(&ts);
+    assert_eq!(ts.readl(GPIO_ADDR + GPIO_INPUT_VAL), 1);
+    assert_eq!(ts.readl(GPIO_ADDR + GPIO_RISE_IP), 1);
+    // Button is released by itself, guest is left running.
+    sleep(Duration::from_millis(300));
+    assert_eq!(ts.readl(GPIO_ADDR + GPIO_INPUT_VAL), 0);
+    assert_eq!(ts.readl(GPIO_ADDR + GPIO_FALL_IP), 1);
+    let ret = ts.qmp("{\"execute\": \"query-status\"}");
+    assert_eq!(
+        *ret.get("return").unwrap().get("status").unwrap(),
+        json!("running")
+    );
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn powerdown_force_off() {
+    let mut ts = test_init(vec!["-action", "powerdown=force-off,powerdown-timeout=1"]);
+    powerdown(&ts);
+    let event = ts.wait_qmp_event();
+    assert_eq!(*event.get("event").unwrap(), json!("SHUTDOWN"));
+    assert_eq!(
+        *event.get("data").unwrap(),
+        json!({"guest": false, "reason": "host-qmp-system-powerdown"})
+    );
+    assert_eq!(ts.wait_exit(), Some(0));
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn powerdown_reset_drops_force_off() {
+    let mut ts = test_init(vec!["-action", "powerdown=force-off,powerdown-timeout=1"]);
+    powerdown(&ts);
+    let event = ts.qmp("{\"execute\": \"system_reset\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("RESET"));
+    ts.qmp_read();
+    sleep(Duration::from_millis(1500));
+    let ret = ts.qmp("{\"execute\": \"query-status\"}");
+    assert_eq!(
+        *ret.get("return").unwrap().get("status").unwrap(),
+        json!("running")
+    );
+
+    ts.stop();
+}
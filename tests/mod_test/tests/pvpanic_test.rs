@@ -0,0 +1,103 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::test_init;
+
+const PVPANIC_ADDR: u64 = MEM_LAYOUT[LayoutEntryType::PvPanic as usize].0;
+const PVPANIC_PANICKED: u8 = 1;
+const PVPANIC_CRASH_LOADED: u8 = 2;
+/// Exit code of process when guest fails.
+const VM_EXIT_GUEST_FAILURE: i32 = 2;
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn pvpanic_pause_action() {
+    let mut ts = test_init(vec!["-device", "pvpanic", "-action", "panic=pause"]);
+    assert_eq!(
+        ts.readb(PVPANIC_ADDR),
+        PVPANIC_PANICKED | PVPANIC_CRASH_LOADED
+    );
+
+    ts.writeb(PVPANIC_ADDR, PVPANIC_PANICKED);
+    ts.assert_qmp_event(
+        "GUEST_PANICKED",
+        json!({"action": "pause", "reason": "pvpanic"}),
+    );
+    ts.assert_qmp_event("STOP", json!({}));
+    assert_eq!(ts.run_status(), json!("guest-panicked"));
+
+    // Guest goes on after cont.
+    let event = ts.qmp("{\"execute\": \"cont\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("RESUME"));
+    ts.qmp_read();
+    assert_eq!(ts.run_status(), json!("running"));
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn pvpanic_default_action() {
+    let mut ts = test_init(vec!["-device", "pvpanic"]);
+
+    // Crash kernel takes over the panic, which isn't reported.
+    ts.writeb(PVPANIC_ADDR, PVPANIC_CRASH_LOADED);
+    ts.writeb(PVPANIC_ADDR, PVPANIC_PANICKED);
+    ts.assert_qmp_event(
+        "GUEST_PANICKED",
+        json!({"action": "none", "reason": "pvpanic"}),
+    );
+    assert_eq!(ts.run_status(), json!("running"));
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn pvpanic_shutdown_action() {
+    let mut ts = test_init(vec!["-device", "pvpanic", "-action", "panic=shutdown"]);
+
+    ts.writeb(PVPANIC_ADDR, PVPANIC_PANICKED);
+    ts.assert_qmp_event(
+        "GUEST_PANICKED",
+        json!({"action": "shutdown", "reason": "pvpanic"}),
+    );
+    ts.assert_qmp_event(
+        "SHUTDOWN",
+        json!({"guest": true, "reason": "guest-shutdown"}),
+    );
+    assert_eq!(ts.wait_exit(), Some(VM_EXIT_GUEST_FAILURE));
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn pvpanic_exit_failure_action() {
+    // Guest panic exits even though guest shutdown pauses.
+    let mut ts = test_init(vec![
+        "-device",
+        "pvpanic",
+        "-action",
+        "panic=exit-failure,shutdown=pause",
+    ]);
+
+    ts.writeb(PVPANIC_ADDR, PVPANIC_PANICKED);
+    ts.assert_qmp_event(
+        "GUEST_PANICKED",
+        json!({"action": "exit-failure", "reason": "pvpanic"}),
+    );
+    ts.assert_qmp_event("SHUTDOWN", json!({"guest": true, "reason": "guest-panic"}));
+    assert_eq!(ts.wait_exit(), Some(VM_EXIT_GUEST_FAILURE));
+}
@@ -0,0 +1,301 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::{assert_event, test_init};
+use mod_test::utils::get_rand_str;
+
+fn read_msg(reader: &mut BufReader<UnixStream>) -> Value {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    serde_json::from_str(line.trim()).unwrap()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn qmp_event_broadcast() {
+    let mon_path = format!("/tmp/televm-mon-{}.sock", get_rand_str(8));
+    let chardev = format!("socket,id=mon0,path={},server,nowait", mon_path);
+    let mut ts = test_init(vec![
+        "-chardev",
+        &chardev,
+        "-mon",
+        "chardev=mon0,id=mon0,mode=control",
+    ]);
+
+    let stream = UnixStream::connect(&mon_path).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    assert!(read_msg(&mut reader).get("QMP").is_some());
+
+    // The negotiated client gets STOP before the return of stop.
+    let event = ts.qmp("{\"execute\": \"stop\"}");
+    assert_event(&event, "STOP");
+    assert_eq!(*ts.qmp_read().get("return").unwrap(), json!({}));
+
+    // The client which has not negotiated gets nothing.
+    let mut line = String::new();
+    let err = reader.read_line(&mut line).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ));
+
+    writer
+        .write_all(b"{\"execute\": \"qmp_capabilities\"}")
+        .unwrap();
+    assert_eq!(*read_msg(&mut reader).get("return").unwrap(), json!({}));
+
+    // Both negotiated clients get RESUME.
+    let event = ts.qmp("{\"execute\": \"cont\"}");
+    assert_event(&event, "RESUME");
+    assert_eq!(*ts.qmp_read().get("return").unwrap(), json!({}));
+    assert_event(&read_msg(&mut reader), "RESUME");
+
+    drop(writer);
+    drop(reader);
+    std::fs::remove_file(&mon_path).ok();
+    ts.stop();
+}
+
+struct MonClient {
+    writer: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl MonClient {
+    fn connect(path: &str) -> Self {
+        let stream = UnixStream::connect(path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut client = MonClient {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+        };
+        assert!(client.read().get("QMP").is_some());
+        client
+    }
+
+    fn send(&mut self, msg: &str) {
+        self.writer.write_all(msg.as_bytes()).unwrap();
+    }
+
+    fn read(&mut self) -> Value {
+        read_msg(&mut self.reader)
+    }
+
+    fn assert_nothing(&mut self) {
+        let mut line = String::new();
+        let err = self.reader.read_line(&mut line).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+    }
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn qmp_multiple_clients() {
+    let mon_path = format!("/tmp/televm-mon-{}.sock", get_rand_str(8));
+    let chardev = format!("socket,id=mon0,path={},server,nowait", mon_path);
+    let mut ts = test_init(vec![
+        "-chardev",
+        &chardev,
+        "-mon",
+        "chardev=mon0,id=mon0,mode=control",
+    ]);
+
+    // Both clients are connected to the same socket and greeted.
+    let mut first = MonClient::connect(&mon_path);
+    let mut second = MonClient::connect(&mon_path);
+
+    // Only the first client negotiates, and only once.
+    first.send("{\"execute\": \"qmp_capabilities\"}");
+    assert_eq!(first.read()["return"], json!({}));
+    first.send("{\"execute\": \"qmp_capabilities\", \"id\": \"again\"}");
+    let ret = first.read();
+    assert_eq!(ret["error"]["class"], json!("CommandNotFound"));
+    assert_eq!(ret["id"], json!("again"));
+    second.assert_nothing();
+
+    // Interleaved commands are answered on the socket they come from, even if
+    // a message arrives in pieces.
+    second.send("{\"execute\": \"query-status\", ");
+    first.send("{\"execute\": \"query-version\", \"id\": \"first-1\"}");
+    second.send("\"id\": \"second-1\"}");
+    first.send("{\"execute\": \"query-status\", \"id\": \"first-2\"}");
+    let ret = first.read();
+    assert_eq!(ret["id"], json!("first-1"));
+    assert!(ret["return"].get("qemu").is_some());
+    let ret = second.read();
+    assert_eq!(ret["id"], json!("second-1"));
+    assert_eq!(ret["return"]["status"], json!("running"));
+    assert_eq!(first.read()["id"], json!("first-2"));
+
+    // Events only go to the negotiated clients.
+    let event = ts.qmp("{\"execute\": \"stop\"}");
+    assert_event(&event, "STOP");
+    assert_eq!(ts.qmp_read()["return"], json!({}));
+    assert_event(&first.read(), "STOP");
+    second.assert_nothing();
+
+    second.send("{\"execute\": \"qmp_capabilities\"}");
+    assert_eq!(second.read()["return"], json!({}));
+    first.send("{\"execute\": \"cont\", \"id\": \"first-3\"}");
+    assert_event(&first.read(), "RESUME");
+    assert_eq!(first.read()["id"], json!("first-3"));
+    assert_event(&second.read(), "RESUME");
+    second.assert_nothing();
+
+    // The other client keeps working after one is gone.
+    drop(first);
+    second.send("{\"execute\": \"query-status\", \"id\": \"second-2\"}");
+    assert_eq!(second.read()["id"], json!("second-2"));
+
+    drop(second);
+    std::fs::remove_file(&mon_path).ok();
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn qmp_oob_execution() {
+    let mon_path = format!("/tmp/televm-mon-{}.sock", get_rand_str(8));
+    let chardev = format!("socket,id=mon0,path={},server,nowait", mon_path);
+    let mut ts = test_init(vec![
+        "-chardev",
+        &chardev,
+        "-mon",
+        "chardev=mon0,id=mon0,mode=control",
+    ]);
+
+    let mut oob = MonClient::connect(&mon_path);
+    let mut plain = MonClient::connect(&mon_path);
+
+    // Unknown capabilities are refused, and the client stays unnegotiated.
+    oob.send("{\"execute\": \"qmp_capabilities\", \"arguments\": {\"enable\": [\"foo\"]}}");
+    assert_eq!(oob.read()["error"]["class"], json!("GenericError"));
+    oob.send("{\"execute\": \"qmp_capabilities\", \"arguments\": {\"enable\": [\"oob\"]}}");
+    assert_eq!(oob.read()["return"], json!({}));
+    plain.send("{\"execute\": \"qmp_capabilities\"}");
+    assert_eq!(plain.read()["return"], json!({}));
+
+    // The out-of-band command overtakes the in-band one sent before it.
+    oob.send(concat!(
+        "{\"execute\": \"query-status\", \"id\": \"in-1\"}",
+        "{\"exec-oob\": \"query-status\", \"id\": \"oob-1\"}"
+    ));
+    let ret = oob.read();
+    assert_eq!(ret["id"], json!("oob-1"));
+    assert_eq!(ret["return"]["status"], json!("running"));
+    let ret = oob.read();
+    assert_eq!(ret["id"], json!("in-1"));
+    assert_eq!(ret["return"]["status"], json!("running"));
+
+    // Only the allowed commands can be executed out-of-band.
+    oob.send("{\"exec-oob\": \"stop\", \"id\": \"oob-2\"}");
+    let ret = oob.read();
+    assert_eq!(ret["id"], json!("oob-2"));
+    assert_eq!(ret["error"]["class"], json!("GenericError"));
+    oob.send("{\"execute\": \"query-status\", \"exec-oob\": \"query-status\"}");
+    assert_eq!(oob.read()["error"]["class"], json!("GenericError"));
+
+    // The client which has not enabled oob can't use it.
+    plain.send("{\"exec-oob\": \"query-status\", \"id\": \"plain-1\"}");
+    let ret = plain.read();
+    assert_eq!(ret["id"], json!("plain-1"));
+    assert_eq!(ret["error"]["class"], json!("GenericError"));
+
+    // In-band commands beyond the queue limit are refused at once.
+    let batch: String = (0..10)
+        .map(|i| format!("{{\"execute\": \"query-status\", \"id\": \"q-{}\"}}", i))
+        .collect();
+    oob.send(&batch);
+    for i in 8..10 {
+        let ret = oob.read();
+        assert_eq!(ret["id"], json!(format!("q-{}", i)));
+        assert_eq!(ret["error"]["class"], json!("GenericError"));
+    }
+    for i in 0..8 {
+        let ret = oob.read();
+        assert_eq!(ret["id"], json!(format!("q-{}", i)));
+        assert_eq!(ret["return"]["status"], json!("running"));
+    }
+    oob.assert_nothing();
+    plain.assert_nothing();
+
+    drop(oob);
+    drop(plain);
+    std::fs::remove_file(&mon_path).ok();
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn qmp_input_limits() {
+    let mon_path = format!("/tmp/televm-mon-{}.sock", get_rand_str(8));
+    let chardev = format!("socket,id=mon0,path={},server,nowait", mon_path);
+    let mut ts = test_init(vec![
+        "-chardev",
+        &chardev,
+        "-mon",
+        "chardev=mon0,id=mon0,mode=control",
+    ]);
+
+    let mut flood = MonClient::connect(&mon_path);
+    let mut huge = MonClient::connect(&mon_path);
+    flood.send("{\"execute\": \"qmp_capabilities\", \"arguments\": {\"enable\": [\"oob\"]}}");
+    assert_eq!(flood.read()["return"], json!({}));
+
+    // Commands beyond the rate are throttled, without breaking the client.
+    let batch: String = (0..150)
+        .map(|i| format!("{{\"exec-oob\": \"query-status\", \"id\": \"r-{}\"}}", i))
+        .collect();
+    flood.send(&batch);
+    let rets: Vec<Value> = (0..150).map(|_| flood.read()).collect();
+    assert_eq!(rets[0]["return"]["status"], json!("running"));
+    assert!(rets
+        .iter()
+        .any(|ret| ret["error"]["class"] == json!("OperationThrottled")));
+
+    // An unterminated command over 1MiB gets a parse error, then the client is reset.
+    let mut command = String::from("{\"execute\": \"query-status\", \"id\": \"");
+    command.push_str(&"a".repeat(2 << 20));
+    let _ = huge.writer.write_all(command.as_bytes());
+    let ret = huge.read();
+    assert_eq!(ret["error"]["class"], json!("GenericError"));
+    let mut line = String::new();
+    assert_eq!(huge.reader.read_line(&mut line).unwrap(), 0);
+
+    // Other clients are still served.
+    std::thread::sleep(Duration::from_secs(1));
+    flood.send("{\"execute\": \"query-status\", \"id\": \"after\"}");
+    let ret = flood.read();
+    assert_eq!(ret["id"], json!("after"));
+    assert_eq!(ret["return"]["status"], json!("running"));
+
+    drop(flood);
+    drop(huge);
+    std::fs::remove_file(&mon_path).ok();
+    ts.stop();
+}
@@ -0,0 +1,49 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use machine_manager::qmp::qmp_schema::MachineInfo;
+use machine_manager::qmp::Version;
+use mod_test::libtest::test_init;
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn query_version_reports_televm() {
+    let mut ts = test_init(Vec::new());
+    let ret = ts.qmp_return("{\"execute\": \"query-version\"}");
+    let version: Version = serde_json::from_value(ret["return"].clone()).unwrap();
+    assert_eq!(version, Version::current());
+    let package = ret["return"]["package"].clone();
+    assert!(package.as_str().unwrap().starts_with("TeleVM-"));
+    assert!(package.as_str().unwrap().ends_with("(riscv64)"));
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn query_machines_lists_microvm_default() {
+    let mut ts = test_init(Vec::new());
+    let ret = ts.qmp_return("{\"execute\": \"query-machines\"}");
+    let machines: Vec<MachineInfo> = serde_json::from_value(ret["return"].clone()).unwrap();
+    let names: Vec<&str> = machines.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, ["microvm", "none"]);
+    assert!(machines[0].is_default);
+    assert!(!machines[1].is_default);
+    for machine in machines.iter() {
+        assert!((1..=254).contains(&machine.cpu_max));
+        assert!(!machine.hotplug);
+        assert!(!machine.deprecated);
+    }
+
+    ts.stop();
+}
@@ -0,0 +1,120 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+use serde_json::{json, Value};
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use machine_manager::signal_handler::VM_EXIT_GENE_ERR;
+use mod_test::libtest::{test_init, TestState};
+
+const FINISHER_ADDR: u64 = MEM_LAYOUT[LayoutEntryType::SifiveTest as usize].0;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+fn assert_shutdown(ts: &TestState, data: Value) {
+    let event = ts.wait_qmp_event();
+    assert_eq!(*event.get("event").unwrap(), json!("SHUTDOWN"));
+    assert_eq!(*event.get("data").unwrap(), data);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn quit_exits_with_success() {
+    let mut ts = test_init(Vec::new());
+    let ret = ts.qmp("{\"execute\": \"quit\"}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_shutdown(&ts, json!({"guest": false, "reason": "host-qmp-quit"}));
+    assert_eq!(ts.wait_exit(), Some(0));
+    // Temporary files are cleaned on exit.
+    assert!(!Path::new(&format!("{}/qmp.socket", ts.resource_path)).exists());
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn guest_shutdown_exits_with_success() {
+    let mut ts = test_init(Vec::new());
+    ts.writel(FINISHER_ADDR, FINISHER_PASS);
+    assert_shutdown(&ts, json!({"guest": true, "reason": "guest-shutdown"}));
+    assert_eq!(ts.wait_exit(), Some(0));
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn no_shutdown_pauses_guest() {
+    shutdown_pauses_guest(vec!["-no-shutdown"]);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn shutdown_pause_action_pauses_guest() {
+    shutdown_pauses_guest(vec!["-action", "shutdown=pause"]);
+}
+
+fn shutdown_pauses_guest(args: Vec<&str>) {
+    let mut ts = test_init(args);
+    ts.writel(FINISHER_ADDR, FINISHER_PASS);
+    assert_shutdown(
+        &ts,
+        json!({"guest": true, "reason": "guest-shutdown", "resumable": true}),
+    );
+    assert_eq!(*ts.wait_qmp_event().get("event").unwrap(), json!("STOP"));
+    let ret = ts.qmp("{\"execute\": \"query-status\"}");
+    assert_eq!(ret["return"]["status"], json!("paused"));
+
+    // The paused machine can still be quit.
+    let ret = ts.qmp("{\"execute\": \"quit\"}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_shutdown(&ts, json!({"guest": false, "reason": "host-qmp-quit"}));
+    assert_eq!(ts.wait_exit(), Some(0));
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn no_shutdown_guest_can_be_reset() {
+    let mut ts = test_init(vec!["-no-shutdown"]);
+    ts.writel(FINISHER_ADDR, FINISHER_PASS);
+    assert_shutdown(
+        &ts,
+        json!({"guest": true, "reason": "guest-shutdown", "resumable": true}),
+    );
+    assert_eq!(*ts.wait_qmp_event().get("event").unwrap(), json!("STOP"));
+
+    // The paused machine is reset by host, then resumed from its boot state.
+    let ret = ts.qmp("{\"execute\": \"system_reset\"}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(*ts.wait_qmp_event().get("event").unwrap(), json!("RESET"));
+    let ret = ts.qmp("{\"execute\": \"cont\"}");
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(*ts.wait_qmp_event().get("event").unwrap(), json!("RESUME"));
+    let ret = ts.qmp("{\"execute\": \"query-status\"}");
+    assert_eq!(ret["return"]["status"], json!("running"));
+
+    // Guest reboot is still a reset with `-no-shutdown`.
+    ts.writel(FINISHER_ADDR, FINISHER_RESET);
+    assert_eq!(*ts.wait_qmp_event().get("event").unwrap(), json!("RESET"));
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn config_error_exits_with_error() {
+    let status = Command::new(env::var("TELEVM_BINARY").unwrap())
+        .args(["-machine", "no-such-machine"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(VM_EXIT_GENE_ERR));
+}
@@ -125,6 +125,80 @@ fn test_basic() {
     test_state.borrow_mut().stop();
 }
 
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn test_screendump() {
+    use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+    use mod_test::utils::get_rand_str;
+    use serde_json::json;
+
+    // RAM below kernel, which is not touched by paused guest.
+    let scratch_base = MEM_LAYOUT[LayoutEntryType::Mem as usize].0 + 0x10_0000;
+    let width = 32_u32;
+    let height = 32_u32;
+
+    let mut test_state = test_init(vec!["-device", "ramfb,id=ramfb1"]);
+    test_state.qmp("{\"execute\": \"stop\"}");
+    test_state.qmp_read();
+    let mut allocator = GuestAllocator::new(scratch_base, 0x10_0000, 0x1000);
+
+    let path = format!("/tmp/televm-screendump-{}.ppm", get_rand_str(8));
+    let screendump = format!(
+        "{{\"execute\": \"screendump\", \"arguments\": {{\"filename\": \"{}\"}}}}",
+        path
+    );
+    // No framebuffer before guest configures it.
+    let ret = test_state.qmp(&screendump);
+    assert!(ret.get("error").is_some());
+
+    // Pixel (x, y) is R=x, G=y, B=0xa5 in XRGB8888.
+    let framebuffer_base = allocator.alloc((width * height * RAMFB_BPP) as u64);
+    for y in 0..height {
+        let line: Vec<u8> = (0..width)
+            .flat_map(|x| [0xa5, y as u8, x as u8, 0])
+            .collect();
+        test_state.memwrite(framebuffer_base + (y * width * RAMFB_BPP) as u64, &line);
+    }
+    let mut ramfb_config = RamfbConfig {
+        address: framebuffer_base,
+        fourcc: RAMFB_FORMAT,
+        flags: 0,
+        width,
+        height,
+        stride: 0,
+    };
+    ramfb_config.write_to_file(&mut allocator, &test_state, "etc/ramfb");
+
+    let ret = test_state.qmp(&screendump);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let image = fs::read(&path).unwrap();
+    let header = format!("P6\n{} {}\n255\n", width, height);
+    assert_eq!(&image[..header.len()], header.as_bytes());
+    let pixels = &image[header.len()..];
+    assert_eq!(pixels.len(), (width * height * 3) as usize);
+    assert_eq!(pixels[..3], [0, 0, 0xa5]);
+    let last = pixels.len() - 3;
+    assert_eq!(pixels[last..], [width as u8 - 1, height as u8 - 1, 0xa5]);
+
+    // Framebuffer out of RAM is rejected, and the previous one is kept.
+    ramfb_config.address = ABNORMAL_FB_BASE;
+    ramfb_config.write_to_file(&mut allocator, &test_state, "etc/ramfb");
+    let ret = test_state.qmp(&screendump);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(fs::read(&path).unwrap(), image);
+
+    let ret = test_state.qmp(&format!(
+        "{{\"execute\": \"screendump\", \"arguments\": {{\"filename\": \"{}\", \"format\": \"png\"}}}}",
+        path
+    ));
+    assert!(ret.get("error").is_some());
+
+    fs::remove_file(&path).ok();
+    test_state.qmp("{\"execute\": \"cont\"}");
+    test_state.qmp_read();
+    test_state.stop();
+}
+
 #[test]
 fn test_abnormal_param() {
     let mut args: Vec<&str> = Vec::new();
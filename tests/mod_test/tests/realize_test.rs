@@ -0,0 +1,52 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::test_init;
+use mod_test::utils::{cleanup_img, create_img, TEST_IMAGE_SIZE};
+
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x08;
+const VIRTIO_MMIO_SLOTS: u64 = 4;
+
+/// Boot with virtio devices realized on `threads` threads, returns device ids of
+/// the first virtio-mmio slots.
+fn boot_with_virtio_devices(image_path: &str, threads: &str) -> Vec<u32> {
+    let args = format!(
+        "-realize-threads {} \
+         -drive file={},id=drive0,direct=false \
+         -device virtio-blk-device,drive=drive0,id=blk0 \
+         -device virtio-serial-device,id=serial0",
+        threads, image_path
+    );
+    let mut ts = test_init(args.split(' ').collect());
+    let (base, size) = MEM_LAYOUT[LayoutEntryType::Mmio as usize];
+    let ids = (0..VIRTIO_MMIO_SLOTS)
+        .map(|slot| ts.readl(base + slot * size + VIRTIO_MMIO_DEVICE_ID))
+        .collect();
+    ts.stop();
+    ids
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn realize_threads_keep_layout() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+
+    let serial_ids = boot_with_virtio_devices(&image_path, "1");
+    let parallel_ids = boot_with_virtio_devices(&image_path, "4");
+    // Devices are attached to the same slots whether realized in parallel or not.
+    assert_eq!(serial_ids, parallel_ids);
+    assert!(serial_ids.iter().any(|id| *id != 0));
+
+    cleanup_img(image_path);
+}
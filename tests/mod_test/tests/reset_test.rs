@@ -0,0 +1,82 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, TestState};
+
+const MEM_START: u64 = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+const KERNEL_START: u64 = MEM_START + 0x20_0000;
+/// RAM below kernel, which is neither loaded nor touched by paused guest.
+const SPARE_ADDR: u64 = MEM_START + 0x10_0000;
+const SENTINEL: [u8; 8] = [0x5a; 8];
+const HEADER_LEN: usize = 64;
+
+fn kernel_header() -> Vec<u8> {
+    let shared_path = env::var("SHARED_PATH").unwrap();
+    let mut kernel = File::open(format!("{}/Image-6.9", shared_path)).unwrap();
+    let mut header = vec![0_u8; HEADER_LEN];
+    kernel.read_exact(&mut header).unwrap();
+    header
+}
+
+fn system_reset(ts: &TestState, cmd: &str) {
+    // Reset emits RESET event before the response.
+    let event = ts.qmp(cmd);
+    assert_eq!(*event.get("event").unwrap(), json!("RESET"));
+    assert_eq!(*event.get("data").unwrap(), json!({"guest": false}));
+    let ret = ts.qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn system_reset_reloads_kernel() {
+    let mut ts = test_init(Vec::new());
+    let header = kernel_header();
+
+    // Keep guest paused, so that it doesn't touch memory checked below.
+    ts.qmp("{\"execute\": \"stop\"}");
+    ts.qmp_read();
+
+    ts.memwrite(KERNEL_START, &SENTINEL);
+    ts.memwrite(SPARE_ADDR, &SENTINEL);
+    system_reset(&ts, "{\"execute\": \"system_reset\"}");
+    assert_eq!(ts.memread(KERNEL_START, HEADER_LEN as u64), header);
+    assert_eq!(ts.memread(SPARE_ADDR, SENTINEL.len() as u64), SENTINEL);
+    assert_eq!(ts.boot_regs(0)[0], KERNEL_START);
+
+    // Vm stays paused after reset.
+    let ret = ts.qmp("{\"execute\": \"query-status\"}");
+    assert_eq!(
+        *ret.get("return").unwrap().get("status").unwrap(),
+        json!("paused")
+    );
+
+    ts.memwrite(KERNEL_START, &SENTINEL);
+    system_reset(
+        &ts,
+        "{\"execute\": \"system_reset\", \"arguments\": {\"clear-memory\": true}}",
+    );
+    assert_eq!(ts.memread(KERNEL_START, HEADER_LEN as u64), header);
+    assert_eq!(ts.memread(SPARE_ADDR, SENTINEL.len() as u64), vec![0_u8; 8]);
+
+    ts.qmp("{\"execute\": \"cont\"}");
+    ts.qmp_read();
+    ts.stop();
+}
@@ -0,0 +1,63 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use mod_test::libtest::{test_init_prelaunch, TestState};
+
+fn query_rtc(ts: &TestState) -> Value {
+    ts.qmp("{\"execute\": \"query-rtc\"}")["return"].clone()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn rtc_fixed_base_and_vm_clock() {
+    let mut ts = test_init_prelaunch("null", vec!["-rtc", "base=2006-06-17T16:01:21,clock=vm"]);
+
+    // Rtc starts at the given time, and doesn't tick before the first `cont`.
+    let rtc = query_rtc(&ts);
+    assert_eq!(rtc["date-time"], "2006-06-17T16:01:21Z");
+    assert_eq!(rtc["clock"], "vm");
+    sleep(Duration::from_millis(1500));
+    assert_eq!(query_rtc(&ts)["time"], rtc["time"]);
+
+    ts.qmp("{\"execute\": \"cont\"}");
+    sleep(Duration::from_millis(1500));
+    let running = query_rtc(&ts)["time"].as_u64().unwrap();
+    assert!(running >= rtc["time"].as_u64().unwrap() + 1_000_000_000);
+
+    // Stopped guest doesn't observe time jumps.
+    ts.qmp("{\"execute\": \"stop\"}");
+    let stopped = query_rtc(&ts)["time"].as_u64().unwrap();
+    sleep(Duration::from_millis(1500));
+    assert_eq!(query_rtc(&ts)["time"].as_u64().unwrap(), stopped);
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn rtc_host_clock() {
+    let mut ts = test_init_prelaunch("null", vec!["-rtc", "base=utc,clock=host"]);
+
+    let rtc = query_rtc(&ts);
+    assert_eq!(rtc["clock"], "host");
+    sleep(Duration::from_millis(1500));
+    let time = query_rtc(&ts)["time"].as_u64().unwrap();
+    assert!(time >= rtc["time"].as_u64().unwrap() + 1_000_000_000);
+
+    ts.stop();
+}
@@ -0,0 +1,47 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::path::Path;
+
+use serde_json::json;
+
+use mod_test::libtest::test_init_prelaunch;
+use mod_test::utils::get_rand_str;
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn sandbox_runtime_features() {
+    for sandbox in ["on", "on,obsolete=deny", "on,debug=on"] {
+        let mut ts = test_init_prelaunch("null", vec!["-sandbox", sandbox]);
+
+        // Vcpus start running under their own filter.
+        assert_eq!(ts.qmp("{\"execute\": \"cont\"}")["return"], json!({}));
+        let ret = ts.qmp("{\"execute\": \"query-status\"}");
+        assert_eq!(ret["return"]["status"], json!("running"));
+
+        // Chardev hotplug binds a socket in main thread, whose syscalls are allowed.
+        let sock_path = format!("/tmp/televm-sandbox-{}.sock", get_rand_str(8));
+        let cmd = json!({
+            "execute": "chardev-add",
+            "arguments": {"id": "chr-sock", "backend": {"type": "socket", "data": {
+                "addr": {"type": "unix", "data": {"path": sock_path}},
+                "server": true, "wait": false}}}
+        });
+        assert_eq!(ts.qmp(&cmd.to_string())["return"], json!({}));
+        assert!(Path::new(&sock_path).exists());
+        let cmd = json!({"execute": "chardev-remove", "arguments": {"id": "chr-sock"}});
+        assert_eq!(ts.qmp(&cmd.to_string())["return"], json!({}));
+
+        ts.stop();
+    }
+}
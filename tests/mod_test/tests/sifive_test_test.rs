@@ -0,0 +1,87 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::path::Path;
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use machine_manager::signal_handler::VM_EXIT_GUEST_REBOOT;
+use mod_test::libtest::{test_init, TestState};
+
+const FINISHER_ADDR: u64 = MEM_LAYOUT[LayoutEntryType::SifiveTest as usize].0;
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Guest writes the finisher, whose write is answered on test socket before the
+/// process exits with `code`.
+fn finish(mut ts: TestState, value: u32, code: i32) {
+    ts.writel(FINISHER_ADDR, value);
+    ts.assert_qmp_event(
+        "SHUTDOWN",
+        json!({"guest": true, "reason": "guest-shutdown"}),
+    );
+    assert_eq!(ts.wait_exit(), Some(code));
+    // Temporary files are cleaned on exit.
+    assert!(!Path::new(&format!("{}/qmp.socket", ts.resource_path)).exists());
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn sifive_test_pass() {
+    let ts = test_init(Vec::new());
+    assert_eq!(ts.readl(FINISHER_ADDR), 0);
+    finish(ts, FINISHER_PASS, 0);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn sifive_test_fail() {
+    let ts = test_init(Vec::new());
+    finish(ts, 7 << 16 | FINISHER_FAIL, 7);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn sifive_test_reset() {
+    let mut ts = test_init(Vec::new());
+    ts.writel(FINISHER_ADDR, FINISHER_RESET);
+    ts.assert_qmp_event("RESET", json!({"guest": true}));
+    assert_eq!(ts.run_status(), json!("running"));
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn sifive_test_reset_shutdown_action() {
+    reset_exits(vec!["-action", "reboot=shutdown"]);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn sifive_test_reset_no_reboot() {
+    reset_exits(vec!["-no-reboot"]);
+}
+
+/// Guest reboot exits with its own code, while guest shutdown is unchanged.
+fn reset_exits(args: Vec<&str>) {
+    let mut ts = test_init(args.clone());
+    ts.writel(FINISHER_ADDR, FINISHER_RESET);
+    ts.assert_qmp_event("SHUTDOWN", json!({"guest": true, "reason": "guest-reset"}));
+    assert_eq!(ts.wait_exit(), Some(VM_EXIT_GUEST_REBOOT));
+
+    finish(test_init(args.clone()), FINISHER_PASS, 0);
+    finish(test_init(args), 7 << 16 | FINISHER_FAIL, 7);
+}
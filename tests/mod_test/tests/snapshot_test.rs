@@ -0,0 +1,96 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, test_init_prelaunch};
+use mod_test::utils::get_rand_str;
+
+const PLIC_BASE: u64 = MEM_LAYOUT[LayoutEntryType::Plic as usize].0;
+/// Threshold register of context 0, which is M-mode one and not used by guest.
+const M_CONTEXT_THRESHOLD: u64 = PLIC_BASE + 0x0020_0000;
+
+fn snapshot_cmd(cmd: &str, path: &str) -> String {
+    format!(
+        "{{\"execute\": \"{}\", \"arguments\": {{\"filename\": \"{}\"}}}}",
+        cmd, path
+    )
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn snapshot_save_and_load() {
+    let path = format!("/tmp/televm-snap-{}", get_rand_str(8));
+    let mut ts = test_init(vec![]);
+    ts.writel(M_CONTEXT_THRESHOLD, 5);
+
+    // Running vm is paused while saving and then resumed.
+    let ret = ts.qmp_return(&snapshot_cmd("snapshot-save", &path));
+    assert_eq!(ret["return"], json!({}));
+    assert_eq!(ts.run_status(), json!("running"));
+    // Only vm waiting with `-incoming defer` can be restored.
+    let ret = ts.qmp_return(&snapshot_cmd("snapshot-load", &path));
+    assert!(ret.get("error").is_some());
+    ts.stop();
+
+    let mut ts = test_init_prelaunch("stdio", vec!["-incoming", "defer"]);
+    assert_eq!(ts.run_status(), json!("prelaunch"));
+    assert_eq!(ts.readl(M_CONTEXT_THRESHOLD), 0);
+    // Vcpus are kept parked until snapshot is loaded.
+    let ret = ts.qmp("{\"execute\": \"cont\"}");
+    assert!(ret.get("error").is_some());
+    let ret = ts.qmp(&snapshot_cmd("snapshot-save", &path));
+    assert!(ret.get("error").is_some());
+
+    let ret = ts.qmp_return(&snapshot_cmd("snapshot-load", &path));
+    assert_eq!(ret["return"], json!({}));
+    assert_eq!(ts.run_status(), json!("running"));
+    assert_eq!(ts.readl(M_CONTEXT_THRESHOLD), 5);
+    ts.stop();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn snapshot_load_mismatch() {
+    let path = format!("/tmp/televm-snap-{}", get_rand_str(8));
+    let mut ts = test_init(vec![]);
+    let ret = ts.qmp_return(&snapshot_cmd("snapshot-save", &path));
+    assert_eq!(ret["return"], json!({}));
+    ts.stop();
+
+    // Machine with other memory or vcpus refuses the snapshot and keeps waiting.
+    for args in [vec!["-m", "512M"], vec!["-smp", "2"]] {
+        let mut extra_args = vec!["-incoming", "defer"];
+        extra_args.extend(args);
+        let mut ts = test_init_prelaunch("stdio", extra_args);
+        let ret = ts.qmp(&snapshot_cmd("snapshot-load", &path));
+        let desc = ret["error"]["desc"].as_str().unwrap();
+        assert!(desc.contains("doesn't match") || desc.contains("vcpus"));
+        assert_eq!(ts.run_status(), json!("prelaunch"));
+        ts.stop();
+    }
+
+    // Truncated snapshot is refused as well.
+    let data = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+    let mut ts = test_init_prelaunch("stdio", vec!["-incoming", "defer"]);
+    let ret = ts.qmp(&snapshot_cmd("snapshot-load", &path));
+    assert!(ret.get("error").is_some());
+    assert_eq!(ts.run_status(), json!("prelaunch"));
+    ts.stop();
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::json;
+
+use mod_test::libtest::{test_init, TestState};
+
+fn plug_cpu(ts: &TestState, id: &str, core_id: u8) -> serde_json::Value {
+    ts.qmp(&format!(
+        "{{\"execute\": \"device_add\", \"arguments\": {{\"id\": \"{}\", \"driver\": \"host-riscv64-cpu\", \"core-id\": {}}}}}",
+        id, core_id
+    ))
+}
+
+fn cpu_indexes(ts: &TestState) -> Vec<u64> {
+    let ret = ts.qmp("{\"execute\": \"query-cpus-fast\"}");
+    ret.get("return")
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|cpu| cpu.get("cpu-index").unwrap().as_u64().unwrap())
+        .collect()
+}
+
+fn hotpluggable_cores(ts: &TestState) -> Vec<u64> {
+    let ret = ts.qmp("{\"execute\": \"query-hotpluggable-cpus\"}");
+    ret.get("return")
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|cpu| {
+            cpu.get("props")
+                .unwrap()
+                .get("core-id")
+                .unwrap()
+                .as_u64()
+                .unwrap()
+        })
+        .collect()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn query_cpus_fast_topology() {
+    let mut ts = test_init(vec!["-smp", "cpus=8,sockets=2,cores=2,threads=2"]);
+
+    let ret = ts.qmp("{\"execute\": \"query-cpus-fast\"}");
+    let cpus = ret.get("return").unwrap().as_array().unwrap();
+    assert_eq!(cpus.len(), 8);
+    for (index, cpu) in cpus.iter().enumerate() {
+        assert_eq!(*cpu.get("cpu-index").unwrap(), json!(index));
+        assert_eq!(*cpu.get("target").unwrap(), json!("riscv64"));
+        assert_eq!(
+            *cpu.get("props").unwrap(),
+            json!({
+                "socket-id": index / 4,
+                "core-id": index / 2 % 2,
+                "thread-id": index % 2,
+            })
+        );
+    }
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn cpu_hotplug() {
+    let mut ts = test_init(vec!["-smp", "cpus=2,maxcpus=4"]);
+    assert_eq!(cpu_indexes(&ts), vec![0, 1]);
+    assert_eq!(hotpluggable_cores(&ts), vec![2, 3]);
+
+    // Plugging out of order keeps cpus sorted by index.
+    let ret = plug_cpu(&ts, "cpu3", 3);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let ret = plug_cpu(&ts, "cpu2", 2);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(cpu_indexes(&ts), vec![0, 1, 2, 3]);
+    assert!(hotpluggable_cores(&ts).is_empty());
+
+    let ret = ts.qmp("{\"execute\": \"query-cpus\"}");
+    let cpus = ret.get("return").unwrap().as_array().unwrap();
+    assert_eq!(cpus.len(), 4);
+    assert_eq!(*cpus[2].get("CPU").unwrap(), json!(2));
+    // Vcpu thread of hot-added hart is running before device_add returns.
+    assert!(cpus[3].get("thread_id").unwrap().as_u64().unwrap() > 0);
+
+    // Id in use, hart already present and beyond maxcpus.
+    assert!(plug_cpu(&ts, "cpu2", 1).get("error").is_some());
+    assert!(plug_cpu(&ts, "cpu4", 1).get("error").is_some());
+    assert!(plug_cpu(&ts, "cpu4", 4).get("error").is_some());
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn vcpu_affinity() {
+    let mut ts = test_init(vec!["-smp", "cpus=2", "-cpu-affinity", "vcpu0=0"]);
+
+    let ret = ts.qmp("{\"execute\": \"query-cpus-fast\"}");
+    let cpus = ret.get("return").unwrap().as_array().unwrap();
+    assert_eq!(*cpus[0].get("host-cpus").unwrap(), json!([0]));
+    // Vcpu not named is not pinned.
+    assert!(!cpus[1]
+        .get("host-cpus")
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    ts.stop();
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde_json::{json, Value};
+
+use mod_test::libtest::test_init;
+
+const COUNTERS: [&str; 6] = [
+    "guest-time-ns",
+    "exit-time-ns",
+    "mmio-exits",
+    "sbi-exits",
+    "interrupt-exits",
+    "other-exits",
+];
+
+fn counter(stats: &Value, name: &str) -> u64 {
+    stats.get(name).unwrap().as_u64().unwrap()
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn vcpu_stats_query() {
+    let mut ts = test_init(vec!["-smp", "2"]);
+
+    let stats = ts.query_vcpu_stats();
+    assert_eq!(stats.len(), 2);
+    for (index, cpu) in stats.iter().enumerate() {
+        assert_eq!(*cpu.get("cpu-index").unwrap(), json!(index));
+        assert!(counter(cpu, "thread-id") > 0);
+        assert!(counter(cpu, "guest-time-ns") > 0);
+    }
+    // Booted guest has accessed devices and called SBI.
+    assert!(
+        stats
+            .iter()
+            .map(|cpu| counter(cpu, "mmio-exits"))
+            .sum::<u64>()
+            > 0
+    );
+    assert!(
+        stats
+            .iter()
+            .map(|cpu| counter(cpu, "sbi-exits"))
+            .sum::<u64>()
+            > 0
+    );
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn vcpu_stats_stop_cont_reset() {
+    let mut ts = test_init(Vec::new());
+
+    // Counters are kept across stop and cont.
+    let before = ts.query_vcpu_stats();
+    let event = ts.qmp("{\"execute\": \"stop\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("STOP"));
+    ts.qmp_read();
+    let paused = ts.query_vcpu_stats();
+    for name in COUNTERS {
+        assert!(counter(&paused[0], name) >= counter(&before[0], name));
+    }
+    let event = ts.qmp("{\"execute\": \"cont\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("RESUME"));
+    ts.qmp_read();
+    let resumed = ts.query_vcpu_stats();
+    for name in COUNTERS {
+        assert!(counter(&resumed[0], name) >= counter(&paused[0], name));
+    }
+
+    // System reset zeroes counters.
+    let event = ts.qmp("{\"execute\": \"stop\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("STOP"));
+    ts.qmp_read();
+    let event = ts.qmp("{\"execute\": \"system_reset\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("RESET"));
+    ts.qmp_read();
+    let reset = ts.query_vcpu_stats();
+    for name in COUNTERS {
+        assert_eq!(counter(&reset[0], name), 0);
+    }
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn vcpu_stats_steal_time() {
+    // Vcpus sharing one host cpu steal time from each other.
+    let mut ts = test_init(vec![
+        "-smp",
+        "2",
+        "-cpu",
+        "host,steal-time=on",
+        "-cpu-affinity",
+        "0,0",
+    ]);
+
+    let before = ts.query_vcpu_stats();
+    let after = ts.query_vcpu_stats();
+    for (cpu_before, cpu_after) in before.iter().zip(after.iter()) {
+        assert!(counter(cpu_after, "steal-time-ns") >= counter(cpu_before, "steal-time-ns"));
+    }
+
+    ts.stop();
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::rc::Rc;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libdriver::malloc::GuestAllocator;
+use mod_test::libdriver::virtio::{
+    TestVirtQueue, TestVringDescEntry, VirtioDeviceOps, VIRTIO_F_VERSION_1,
+};
+use mod_test::libdriver::virtio_mmio::TestVirtioMmioDev;
+use mod_test::libtest::{test_init_prelaunch, TestState};
+use mod_test::utils::get_rand_str;
+
+const VIRTIO_TYPE_9P: u32 = 9;
+const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+const TIMEOUT_US: u64 = 15 * 1000 * 1000;
+const MSIZE: u32 = 8192;
+const P9_RLERROR: u8 = 7;
+const P9_TLOPEN: u8 = 12;
+const P9_TLCREATE: u8 = 14;
+const P9_TVERSION: u8 = 100;
+const P9_TATTACH: u8 = 104;
+const P9_TWALK: u8 = 110;
+const P9_TREAD: u8 = 116;
+const P9_TWRITE: u8 = 118;
+const P9_NOFID: u32 = !0;
+const ROOT_FID: u32 = 0;
+const ELOOP: u32 = 40;
+
+/// Builder of a T-message.
+struct Msg(Vec<u8>);
+
+impl Msg {
+    fn new() -> Self {
+        Msg(Vec::new())
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    fn string(self, string: &str) -> Self {
+        self.u16(string.len() as u16).bytes(string.as_bytes())
+    }
+}
+
+struct P9Driver {
+    test_state: Rc<RefCell<TestState>>,
+    dev: TestVirtioMmioDev,
+    vq: Rc<RefCell<TestVirtQueue>>,
+    alloc: Rc<RefCell<GuestAllocator>>,
+}
+
+impl P9Driver {
+    /// Send a request through the queue, return type and payload of the reply.
+    fn call(&self, msg_type: u8, msg: Msg) -> (u8, Vec<u8>) {
+        let mut request = ((msg.0.len() + 7) as u32).to_le_bytes().to_vec();
+        request.push(msg_type);
+        request.extend_from_slice(&1_u16.to_le_bytes());
+        request.extend_from_slice(&msg.0);
+
+        let req_addr = self.alloc.borrow_mut().alloc(request.len() as u64);
+        self.test_state.borrow().memwrite(req_addr, &request);
+        let resp_addr = self.alloc.borrow_mut().alloc(MSIZE as u64);
+        let free_head = self.vq.borrow_mut().add_chained(
+            self.test_state.clone(),
+            vec![
+                TestVringDescEntry {
+                    data: req_addr,
+                    len: request.len() as u32,
+                    write: false,
+                },
+                TestVringDescEntry {
+                    data: resp_addr,
+                    len: MSIZE,
+                    write: true,
+                },
+            ],
+        );
+        self.dev
+            .kick_virtqueue(self.test_state.clone(), self.vq.clone());
+        let mut len = Some(0);
+        self.dev.poll_used_elem(
+            self.test_state.clone(),
+            self.vq.clone(),
+            free_head,
+            TIMEOUT_US,
+            &mut len,
+            true,
+        );
+
+        let reply = self
+            .test_state
+            .borrow()
+            .memread(resp_addr, len.unwrap() as u64);
+        assert_eq!(
+            u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]),
+            reply.len() as u32
+        );
+        (reply[4], reply[7..].to_vec())
+    }
+
+    /// Send a request which must succeed, return payload of the reply.
+    fn call_ok(&self, msg_type: u8, msg: Msg) -> Vec<u8> {
+        let (reply_type, payload) = self.call(msg_type, msg);
+        assert_eq!(reply_type, msg_type + 1, "request {} failed", msg_type);
+        payload
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn virtio_9p_read_write() {
+    let base = format!("/tmp/televm-9p-{}", get_rand_str(8));
+    let share = format!("{}/share", base);
+    fs::create_dir_all(&share).unwrap();
+    fs::write(format!("{}/hello", share), b"hello from host").unwrap();
+    fs::write(format!("{}/secret", base), b"host only").unwrap();
+    symlink("../secret", format!("{}/escape", share)).unwrap();
+
+    let fsdev = format!("local,id=fs0,path={},security_model=none", share);
+    let ts = test_init_prelaunch(
+        "stdio",
+        vec![
+            "-m",
+            "1G",
+            "-fsdev",
+            &fsdev,
+            "-device",
+            "virtio-9p-device,id=p9,fsdev=fs0,mount_tag=share",
+        ],
+    );
+    let test_state = Rc::new(RefCell::new(ts));
+    // RAM below kernel, which is not touched by paused guest.
+    let scratch_base = MEM_LAYOUT[LayoutEntryType::Mem as usize].0 + 0x10_0000;
+    let alloc = Rc::new(RefCell::new(GuestAllocator::new(
+        scratch_base,
+        0x10_0000,
+        0x1000,
+    )));
+
+    let mut dev = TestVirtioMmioDev::find(test_state.clone(), VIRTIO_TYPE_9P).unwrap();
+    let features = dev.get_device_features();
+    assert_ne!(features & (1 << VIRTIO_9P_MOUNT_TAG), 0);
+    assert_eq!(dev.config_readw(0), 5);
+    let tag: Vec<u8> = (0..5).map(|i| dev.config_readb(2 + i)).collect();
+    assert_eq!(tag, b"share");
+
+    let features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_9P_MOUNT_TAG);
+    let mut vqs = dev.init_device(test_state.clone(), alloc.clone(), features, 1);
+    let p9 = P9Driver {
+        test_state: test_state.clone(),
+        dev,
+        vq: vqs.remove(0),
+        alloc,
+    };
+
+    let reply = p9.call_ok(P9_TVERSION, Msg::new().u32(MSIZE).string("9P2000.L"));
+    assert_eq!(u32_at(&reply, 0), MSIZE);
+    assert_eq!(&reply[6..], b"9P2000.L");
+    p9.call_ok(
+        P9_TATTACH,
+        Msg::new()
+            .u32(ROOT_FID)
+            .u32(P9_NOFID)
+            .string("root")
+            .string("")
+            .u32(0),
+    );
+
+    // Overwrite the head of a host file, and read it back.
+    let reply = p9.call_ok(
+        P9_TWALK,
+        Msg::new().u32(ROOT_FID).u32(1).u16(1).string("hello"),
+    );
+    assert_eq!(reply[0..2], [1, 0]);
+    p9.call_ok(P9_TLOPEN, Msg::new().u32(1).u32(libc::O_RDWR as u32));
+    let data = b"HELLO";
+    let reply = p9.call_ok(
+        P9_TWRITE,
+        Msg::new().u32(1).u64(0).u32(data.len() as u32).bytes(data),
+    );
+    assert_eq!(u32_at(&reply, 0), data.len() as u32);
+    let reply = p9.call_ok(P9_TREAD, Msg::new().u32(1).u64(0).u32(100));
+    assert_eq!(u32_at(&reply, 0), 15);
+    assert_eq!(&reply[4..], b"HELLO from host");
+    assert_eq!(
+        fs::read(format!("{}/hello", share)).unwrap(),
+        b"HELLO from host"
+    );
+
+    // Create a file from guest.
+    p9.call_ok(P9_TWALK, Msg::new().u32(ROOT_FID).u32(2).u16(0));
+    p9.call_ok(
+        P9_TLCREATE,
+        Msg::new()
+            .u32(2)
+            .string("new")
+            .u32(libc::O_WRONLY as u32)
+            .u32(0o644)
+            .u32(0),
+    );
+    p9.call_ok(P9_TWRITE, Msg::new().u32(2).u64(0).u32(3).bytes(b"abc"));
+    assert_eq!(fs::read(format!("{}/new", share)).unwrap(), b"abc");
+
+    // Symlink pointing out of the shared directory can't be opened.
+    p9.call_ok(
+        P9_TWALK,
+        Msg::new().u32(ROOT_FID).u32(3).u16(1).string("escape"),
+    );
+    let (reply_type, reply) = p9.call(P9_TLOPEN, Msg::new().u32(3).u32(0));
+    assert_eq!(reply_type, P9_RLERROR);
+    assert_eq!(u32_at(&reply, 0), ELOOP);
+
+    test_state.borrow_mut().stop();
+    fs::remove_dir_all(base).unwrap();
+}
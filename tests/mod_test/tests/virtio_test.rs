@@ -99,10 +99,7 @@ fn send_one_request(
 }
 
 fn check_stratovirt_status(test_state: Rc<RefCell<TestState>>) {
-    let ret = test_state
-        .borrow()
-        .qmp("{\"execute\": \"qmp_capabilities\"}");
-    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    assert_eq!(test_state.borrow().run_status(), json!("running"));
 }
 
 fn init_device_step(
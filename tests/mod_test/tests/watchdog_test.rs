@@ -0,0 +1,84 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use serde_json::json;
+
+use machine::micro_vm::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+use mod_test::libtest::{test_init, TestState};
+
+const WDT_ADDR: u64 = MEM_LAYOUT[LayoutEntryType::Watchdog as usize].0;
+const WDT_CR: u64 = 0x00;
+const WDT_TORR: u64 = 0x04;
+const WDT_CCVR: u64 = 0x08;
+const WDT_CRR: u64 = 0x0c;
+const WDT_COMP_TYPE: u64 = 0xfc;
+
+/// Enable watchdog with the shortest timeout, which is 65ms.
+fn enable_watchdog(ts: &TestState) {
+    ts.writel(WDT_ADDR + WDT_TORR, 0);
+    ts.writel(WDT_ADDR + WDT_CR, 1);
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn watchdog_kick() {
+    let mut ts = test_init(Vec::new());
+    assert_eq!(ts.readl(WDT_ADDR + WDT_COMP_TYPE), 0x4457_0120);
+    assert_eq!(ts.readl(WDT_ADDR + WDT_CCVR), 0);
+
+    // Timeout of TOP 5 is 2^21 cycles of 1MHz clock.
+    ts.writel(WDT_ADDR + WDT_TORR, 0x55);
+    ts.writel(WDT_ADDR + WDT_CR, 1);
+    let count = ts.readl(WDT_ADDR + WDT_CCVR);
+    assert!(count > 0 && count <= 1 << 21);
+    sleep(Duration::from_millis(100));
+    let left = ts.readl(WDT_ADDR + WDT_CCVR);
+    assert!(left < count);
+    ts.writel(WDT_ADDR + WDT_CRR, 0x76);
+    assert!(ts.readl(WDT_ADDR + WDT_CCVR) > left);
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn watchdog_reset_action() {
+    let mut ts = test_init(Vec::new());
+    enable_watchdog(&ts);
+    ts.assert_qmp_event("WATCHDOG", json!({"action": "reset"}));
+    ts.assert_qmp_event("RESET", json!({"guest": true}));
+
+    ts.stop();
+}
+
+#[test]
+#[cfg(target_arch = "riscv64")]
+fn watchdog_pause_action() {
+    let mut ts = test_init(vec!["-watchdog-action", "pause"]);
+    enable_watchdog(&ts);
+    ts.assert_qmp_event("WATCHDOG", json!({"action": "pause"}));
+    ts.assert_qmp_event("STOP", json!({}));
+    assert_eq!(ts.run_status(), json!("paused"));
+
+    // System reset disarms the watchdog.
+    let event = ts.qmp("{\"execute\": \"system_reset\"}");
+    assert_eq!(*event.get("event").unwrap(), json!("RESET"));
+    ts.qmp_read();
+    assert_eq!(ts.readl(WDT_ADDR + WDT_CR), 0);
+    assert_eq!(ts.readl(WDT_ADDR + WDT_CCVR), 0);
+
+    ts.stop();
+}
@@ -80,6 +80,8 @@ pub struct ArgParser<'a> {
 pub struct ArgMatches<'a> {
     pub args: BTreeMap<&'a str, Arg<'a>>,
     pub extra_args: Vec<String>,
+    /// Values supplied by `supply_value`, as (argument name, value, origin).
+    supplied: Vec<(String, String, String)>,
 }
 
 /// The structure of a command line argument. Used to set all the options that
@@ -187,9 +189,15 @@ impl<'a> ArgParser<'a> {
 
     /// Starts the parsing process.This method gets all user provided arguments
     /// from [`env::args_os`] in order to allow for invalid UTF-8 code points.
-    pub fn get_matches(mut self) -> Result<ArgMatches<'a>> {
+    pub fn get_matches(self) -> Result<ArgMatches<'a>> {
         let cmd_args: Vec<String> = env::args().collect();
-        let (arg_hash, multi_vec, sub_str) = parse_cmdline(&cmd_args, &self.allow_list)?;
+        self.get_matches_from(&cmd_args)
+    }
+
+    /// Starts the parsing process from the given arguments, the first one is
+    /// the name of the application.
+    pub fn get_matches_from(mut self, cmd_args: &[String]) -> Result<ArgMatches<'a>> {
+        let (arg_hash, multi_vec, sub_str) = parse_cmdline(cmd_args, &self.allow_list)?;
 
         if arg_hash.contains_key(HELP_SHORT) || arg_hash.contains_key(HELP_LONG) {
             self.output_help(&mut std::io::stdout());
@@ -541,7 +549,103 @@ impl<'a> Arg<'a> {
 
 impl<'a> ArgMatches<'a> {
     fn new(args: BTreeMap<&'a str, Arg<'a>>, extra_args: Vec<String>) -> Self {
-        ArgMatches { args, extra_args }
+        ArgMatches {
+            args,
+            extra_args,
+            supplied: Vec::new(),
+        }
+    }
+
+    /// Supply a value for `arg` from outside of the command line, such as a
+    /// config file. The command line takes precedence: a single value `arg`
+    /// given on the command line keeps its value, and the values of a multiple
+    /// `arg` given on the command line come after the supplied ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `arg_name` - Name of `arg`.
+    /// * `value` - Value of `arg`, `None` for a flag.
+    /// * `origin` - Where the value comes from, reported by `origin_of`.
+    pub fn supply_value(
+        &mut self,
+        arg_name: &str,
+        value: Option<String>,
+        origin: &str,
+    ) -> Result<()> {
+        let supplied = self
+            .supplied
+            .iter()
+            .filter(|(name, _, _)| name == arg_name)
+            .count();
+        let arg = match self.args.get_mut(arg_name) {
+            Some(arg) => arg,
+            None => {
+                return Err(anyhow!(UtilError::UnexpectedArguments(
+                    arg_name.to_string()
+                )))
+            }
+        };
+        if arg.value.is_none() && arg.values.is_none() {
+            if let Some(value) = value {
+                return Err(anyhow!(UtilError::IllegelValue(
+                    value,
+                    arg_name.to_string()
+                )));
+            }
+            arg.presented = true;
+            return Ok(());
+        }
+
+        let value = match value {
+            Some(value) => value,
+            None if arg.can_no_value => String::new(),
+            None => return Err(anyhow!(UtilError::MissingValue(arg_name.to_string()))),
+        };
+        if let Some(possible_values) = &arg.possible_values {
+            if !possible_values.iter().any(|possible| *possible == value) {
+                return Err(anyhow!(UtilError::ValueOutOfPossible(
+                    arg_name.to_string(),
+                    format!("{:?}", possible_values),
+                )));
+            }
+        }
+        let multiple_values = if arg.multiple {
+            arg.values.as_mut()
+        } else {
+            None
+        };
+        if let Some(values) = multiple_values {
+            if !arg.presented {
+                values.clear();
+            }
+            values.insert(supplied, value.clone());
+        } else if supplied != 0 {
+            return Err(anyhow!(UtilError::DuplicateArgument(arg_name.to_string())));
+        } else if arg.presented {
+            return Ok(());
+        } else if arg.values.is_some() {
+            arg.values = Some(vec![value.clone()]);
+        } else {
+            arg.value = Some(value.clone());
+        }
+        arg.presented = true;
+        self.supplied
+            .push((arg_name.to_string(), value, origin.to_string()));
+        Ok(())
+    }
+
+    /// Get where the `value` of `arg` was supplied from, `None` if it was given
+    /// on the command line.
+    ///
+    /// # Arguments
+    ///
+    /// * `arg_name` - Name of `arg`.
+    /// * `value` - Value of `arg`.
+    pub fn origin_of(&self, arg_name: &str, value: &str) -> Option<&str> {
+        self.supplied
+            .iter()
+            .find(|(name, supplied, _)| name == arg_name && supplied == value)
+            .map(|(_, _, origin)| origin.as_str())
     }
 
     /// Get the single value for `arg`.
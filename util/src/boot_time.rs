@@ -0,0 +1,174 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+
+/// Milestones of vm boot recorded by `-boottime`, in the order they are reached.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BootMilestone {
+    /// Command line is parsed into vm config.
+    ConfigParsed,
+    /// Guest RAM is mapped.
+    MemoryMapped,
+    /// Devices given by command line are realized.
+    DevicesRealized,
+    /// Vcpus are created and set to their boot state.
+    VcpusCreated,
+    /// The first vcpu enters guest.
+    FirstVcpuEntry,
+    /// Guest prints the first byte on serial or SBI debug console.
+    FirstSerialOutput,
+    /// Guest rings the boot complete doorbell of sifive test device.
+    GuestBootComplete,
+}
+
+pub const BOOT_MILESTONES: [BootMilestone; 7] = [
+    BootMilestone::ConfigParsed,
+    BootMilestone::MemoryMapped,
+    BootMilestone::DevicesRealized,
+    BootMilestone::VcpusCreated,
+    BootMilestone::FirstVcpuEntry,
+    BootMilestone::FirstSerialOutput,
+    BootMilestone::GuestBootComplete,
+];
+
+impl BootMilestone {
+    /// Name of the milestone used by the summary and QMP `query-boot-time`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootMilestone::ConfigParsed => "config-parsed",
+            BootMilestone::MemoryMapped => "memory-mapped",
+            BootMilestone::DevicesRealized => "devices-realized",
+            BootMilestone::VcpusCreated => "vcpus-created",
+            BootMilestone::FirstVcpuEntry => "first-vcpu-entry",
+            BootMilestone::FirstSerialOutput => "first-serial-output",
+            BootMilestone::GuestBootComplete => "guest-boot-complete",
+        }
+    }
+}
+
+/// Time of each milestone since process start, only the first time it's reached
+/// is kept.
+struct BootTime {
+    start: Instant,
+    reached: [OnceCell<Duration>; BOOT_MILESTONES.len()],
+}
+
+impl BootTime {
+    fn new(start: Instant) -> Self {
+        BootTime {
+            start,
+            reached: Default::default(),
+        }
+    }
+
+    fn record(&self, milestone: BootMilestone) {
+        let cell = &self.reached[milestone as usize];
+        if cell.get().is_none() {
+            let _ = cell.set(self.start.elapsed());
+        }
+    }
+
+    fn milestones(&self) -> Vec<(BootMilestone, Duration)> {
+        BOOT_MILESTONES
+            .iter()
+            .filter_map(|m| self.reached[*m as usize].get().map(|time| (*m, *time)))
+            .collect()
+    }
+}
+
+static BOOT_TIME: OnceCell<BootTime> = OnceCell::new();
+
+/// Start recording boot milestones by `-boottime`.
+///
+/// # Arguments
+///
+/// * `start` - When process starts, milestones are timed from it.
+pub fn enable_boot_time(start: Instant) {
+    let _ = BOOT_TIME.set(BootTime::new(start));
+}
+
+pub fn is_boot_time_enabled() -> bool {
+    BOOT_TIME.get().is_some()
+}
+
+/// Record that `milestone` is reached, which is only a check of the flag without
+/// `-boottime`, so it's cheap enough for hot paths, e.g. vcpu entry.
+#[inline]
+pub fn record_boot_milestone(milestone: BootMilestone) {
+    if let Some(boot_time) = BOOT_TIME.get() {
+        boot_time.record(milestone);
+    }
+}
+
+/// Milestones reached so far and their time since process start, none without
+/// `-boottime`.
+pub fn boot_milestones() -> Option<Vec<(BootMilestone, Duration)>> {
+    BOOT_TIME.get().map(|boot_time| boot_time.milestones())
+}
+
+/// Table of all milestones printed at exit, unreached ones are marked by `-`.
+pub fn boot_time_summary() -> Option<String> {
+    let reached = boot_milestones()?;
+    let mut summary = String::from("Boot time (ms since process start):\n");
+    for milestone in BOOT_MILESTONES.iter() {
+        let time = match reached.iter().find(|(m, _)| m == milestone) {
+            Some((_, time)) => format!("{:.3}", time.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        let _ = writeln!(summary, "  {:<24}{:>12}", milestone.name(), time);
+    }
+    Some(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_boot_time() {
+        let boot_time = BootTime::new(Instant::now());
+        for milestone in BOOT_MILESTONES.iter().take(4) {
+            boot_time.record(*milestone);
+        }
+        boot_time.record(BootMilestone::GuestBootComplete);
+        // Only the first time is kept.
+        let first = boot_time.milestones()[0].1;
+        boot_time.record(BootMilestone::ConfigParsed);
+
+        let milestones = boot_time.milestones();
+        assert_eq!(milestones.len(), 5);
+        assert_eq!(milestones[0], (BootMilestone::ConfigParsed, first));
+        assert_eq!(milestones[4].0, BootMilestone::GuestBootComplete);
+        assert!(milestones.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(
+            BOOT_MILESTONES[BootMilestone::FirstVcpuEntry as usize].name(),
+            "first-vcpu-entry"
+        );
+
+        assert!(!is_boot_time_enabled());
+        assert!(boot_time_summary().is_none());
+        record_boot_milestone(BootMilestone::ConfigParsed);
+        enable_boot_time(Instant::now());
+        record_boot_milestone(BootMilestone::ConfigParsed);
+        let summary = boot_time_summary().unwrap();
+        assert_eq!(summary.lines().count(), BOOT_MILESTONES.len() + 1);
+        assert!(summary.contains("config-parsed"));
+        assert!(summary
+            .lines()
+            .any(|line| line.contains("guest-boot-complete") && line.ends_with('-')));
+    }
+}
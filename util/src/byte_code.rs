@@ -71,6 +71,53 @@ impl ByteCode for i32 {}
 impl ByteCode for i64 {}
 impl ByteCode for i128 {}
 
+/// Byte order of the data in guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// Whether it's the byte order of host, data needn't be swapped.
+    pub fn is_native(&self) -> bool {
+        match self {
+            Endian::Little => cfg!(target_endian = "little"),
+            Endian::Big => cfg!(target_endian = "big"),
+        }
+    }
+}
+
+/// A trait for objects accessed with explicit byte order, the packed structs
+/// implement it by swapping each of their fields.
+pub trait SwapBytes: ByteCode {
+    /// Return the object with byte order of all fields reversed.
+    fn swap_bytes(self) -> Self;
+
+    /// Convert the object between host byte order and `endian`.
+    fn to_endian(self, endian: Endian) -> Self {
+        if endian.is_native() {
+            self
+        } else {
+            self.swap_bytes()
+        }
+    }
+}
+
+macro_rules! impl_swap_bytes {
+    ($($t:ty),*) => {
+        $(
+            impl SwapBytes for $t {
+                fn swap_bytes(self) -> Self {
+                    <$t>::swap_bytes(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_swap_bytes!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -126,4 +173,21 @@ mod test {
         let res_num = u32::from_mut_bytes(res_bytes).unwrap();
         assert_eq!(*res_num, 0x9934_5678);
     }
+
+    #[test]
+    fn test_swap_bytes() {
+        let num = 0x1234_5678_u32;
+        assert_eq!(SwapBytes::swap_bytes(num), 0x7856_3412);
+        assert_eq!(SwapBytes::swap_bytes(0x12_u8), 0x12);
+
+        let (native, foreign) = if cfg!(target_endian = "little") {
+            (Endian::Little, Endian::Big)
+        } else {
+            (Endian::Big, Endian::Little)
+        };
+        assert!(native.is_native());
+        assert!(!foreign.is_native());
+        assert_eq!(num.to_endian(native), num);
+        assert_eq!(num.to_endian(foreign), 0x7856_3412);
+    }
 }
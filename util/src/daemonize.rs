@@ -30,28 +30,79 @@
 //! 7. Disassociate from its process group, to insulate itself from signals
 //! sent to the process group.
 //! 8. Handle any `SIGCLD` signals.
+//!
+//! The launching process doesn't exit until the daemon reports its startup result
+//! through a pipe, by `daemon_ready` once the vm is running or `daemon_failed`
+//! with the error. It then exits with 0, or 1 with the error on stderr, so that
+//! scripts starting the daemon don't race against realize failures.
+//!
+//! The pid file is locked by `flock` for the lifetime of the daemon, so another
+//! instance using the same pid file fails to start instead of fighting over the
+//! sockets and images of the running one.
 
 use std::cmp::Ordering;
 use std::fs::{File, OpenOptions};
-use std::io::prelude::*;
+use std::io::{prelude::*, SeekFrom};
 use std::os::unix::fs::OpenOptionsExt;
-use std::os::unix::io::RawFd;
-use std::path::Path;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::process::exit;
+use std::sync::Mutex;
 
 use crate::UtilError;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 
-/// Write process id to pid file.
-fn create_pid_file(path: &str) -> Result<()> {
-    let pid: u32 = std::process::id();
+/// Message of the daemon reporting it's ready.
+const STARTUP_READY: u8 = 0;
+/// Message of the daemon reporting it failed, followed by the error.
+const STARTUP_FAILED: u8 = 1;
+/// Exit code of the launching process if the daemon fails to start.
+const STARTUP_FAILURE_EXIT: i32 = 1;
+
+/// Write end of the pipe which the daemon reports its startup result by.
+static STARTUP_PIPE: Mutex<Option<File>> = Mutex::new(None);
 
+/// Open pid file and take an exclusive lock on it. A stale pid file left by an
+/// instance which is gone isn't locked, and it's taken over.
+///
+/// # Errors
+///
+/// `PidFileLocked` Error, the pid file is locked by a running instance.
+fn lock_pid_file(path: &str) -> Result<File> {
     let mut pid_file: File = OpenOptions::new()
+        .read(true)
         .write(true)
         .create(true)
+        // Pid of the running instance is kept until the lock is taken.
+        .truncate(false)
         .mode(0o600)
-        .open(path)?;
+        .open(path)
+        .with_context(|| format!("Failed to open pid file {}", path))?;
+    // SAFETY: the file has a valid raw fd.
+    if unsafe { libc::flock(pid_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(err).with_context(|| format!("Failed to lock pid file {}", path));
+        }
+        let mut pid = String::new();
+        let _ = pid_file.read_to_string(&mut pid);
+        let pid = match pid.trim() {
+            "" => "unknown".to_string(),
+            pid => pid.to_string(),
+        };
+        return Err(anyhow!(UtilError::PidFileLocked(path.to_string(), pid)));
+    }
+
+    Ok(pid_file)
+}
+
+/// Write process id to the locked pid file, and sync it to disk.
+fn write_pid_file(pid_file: &mut File) -> Result<()> {
+    let pid: u32 = std::process::id();
+
+    pid_file.set_len(0)?;
+    pid_file.seek(SeekFrom::Start(0))?;
     write!(pid_file, "{}", pid)?;
+    pid_file.sync_all()?;
 
     Ok(())
 }
@@ -61,20 +112,20 @@ fn create_pid_file(path: &str) -> Result<()> {
 /// process is referred to as the child process. The calling process is referred
 /// to as the parent process.
 /// **libc::fork()** may have three kinds ret:
-/// if ret > 0 : current process is parent process, it's not expected, so exit
+/// if ret > 0 : current process is parent process, return the pid of child
 /// if ret < 0 : error occurred in fork()
-/// if ret = 0 : current process is child process, it's expected
+/// if ret = 0 : current process is child process, return `None`
 ///
 /// # Errors
 ///
 /// `DaemonFork` Error, the ret of `libc::fork()` is less than zero.
-fn fork() -> Result<()> {
+fn fork() -> Result<Option<libc::pid_t>> {
     let ret = unsafe { libc::fork() };
 
     match ret.cmp(&0) {
         Ordering::Less => Err(anyhow!(UtilError::DaemonFork)),
-        Ordering::Greater => exit(0),
-        Ordering::Equal => Ok(()),
+        Ordering::Greater => Ok(Some(ret)),
+        Ordering::Equal => Ok(None),
     }
 }
 
@@ -99,36 +150,90 @@ fn set_sid() -> Result<()> {
     }
 }
 
-/// Redirect stdio to `/dev/null`.
+/// Redirect stdio `fd` to `file`.
 ///
 /// Use [dup(2)](https://man7.org/linux/man-pages/man2/dup.2.html)
 /// dup2(oldfd, newfd) creates a copy of the file descriptor `oldfd`, uses the
 /// file descriptor number specified in `newfd`. If the file descriptor `newfd`
-/// was previously open, it is silently closed before being reused. This
-/// function use `dup2` to redirect file descriptor use to `/dev/null`.
+/// was previously open, it is silently closed before being reused.
 ///
 /// # Errors
 ///
-/// `DaemonRedirectStdio` Error, the ret of `libc::open()`, `libc::dup2()`,
-/// `libc::close()`is -1
-fn redirect_stdio(fd: RawFd) -> Result<()> {
-    unsafe {
-        let devnull_fd = libc::open(b"/dev/null\0" as *const [u8; 10] as _, libc::O_RDWR);
-
-        if devnull_fd == -1 {
-            return Err(anyhow!(UtilError::DaemonRedirectStdio));
-        }
+/// `DaemonRedirectStdio` Error, the ret of `libc::dup2()` is -1
+fn redirect_stdio(fd: RawFd, file: &File) -> Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+        return Err(anyhow!(UtilError::DaemonRedirectStdio));
+    }
 
-        if libc::dup2(devnull_fd, fd) == -1 {
-            return Err(anyhow!(UtilError::DaemonRedirectStdio));
-        }
+    Ok(())
+}
+
+/// Redirect stdin to `/dev/null`, and stdout and stderr to `log_file`, or
+/// `/dev/null` without it.
+fn redirect_all_stdio(log_file: Option<&str>) -> Result<()> {
+    let devnull = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|_| anyhow!(UtilError::DaemonRedirectStdio))?;
+    let output = match log_file {
+        Some(path) => OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o640)
+            .open(path)
+            .map_err(|_| anyhow!(UtilError::DaemonRedirectStdio))?,
+        None => devnull.try_clone()?,
+    };
+
+    redirect_stdio(libc::STDIN_FILENO, &devnull)?;
+    redirect_stdio(libc::STDOUT_FILENO, &output)?;
+    redirect_stdio(libc::STDERR_FILENO, &output)
+}
+
+/// Create a pipe, both ends are closed on exec.
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(anyhow!(UtilError::DaemonFork));
+    }
+    // SAFETY: the fds are just created and owned by nothing else.
+    unsafe { Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))) }
+}
+
+/// Startup result reported by the daemon through the pipe.
+fn startup_result(msg: &[u8]) -> std::result::Result<(), String> {
+    match msg.split_first() {
+        Some((&STARTUP_READY, _)) => Ok(()),
+        Some((&STARTUP_FAILED, err)) => Err(String::from_utf8_lossy(err).into_owned()),
+        _ => Err("Daemon exited unexpectedly during startup".to_string()),
+    }
+}
 
-        if libc::close(devnull_fd) == -1 {
-            return Err(anyhow!(UtilError::DaemonRedirectStdio));
+/// Wait for the startup result of the daemon in the launching process, and exit
+/// with it.
+fn wait_startup(mut pipe: File, child: libc::pid_t) -> ! {
+    let mut msg = Vec::new();
+    // Reading ends once the daemon reports, or exits without reporting.
+    let _ = pipe.read_to_end(&mut msg);
+    // Reap the intermediate child, which exits once the daemon is forked.
+    unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+
+    match startup_result(&msg) {
+        Ok(()) => exit(0),
+        Err(e) => {
+            let _ = writeln!(std::io::stderr(), "{}", e);
+            exit(STARTUP_FAILURE_EXIT);
         }
     }
+}
 
-    Ok(())
+fn report_startup(result: u8, msg: &str) {
+    if let Some(mut pipe) = STARTUP_PIPE.lock().unwrap().take() {
+        let mut buf = vec![result];
+        buf.extend_from_slice(msg.as_bytes());
+        let _ = pipe.write_all(&buf);
+    }
 }
 
 /// Daemonize a process.
@@ -136,42 +241,111 @@ fn redirect_stdio(fd: RawFd) -> Result<()> {
 /// # Arguments
 ///
 /// * `pid_file` - Path where will create pid file.
+/// * `log_file` - Path of log file which stdout and stderr are redirected to.
+///
+/// Returns the locked pid file, the lock is held as long as it's kept open.
 ///
 /// # Notes
-/// This function do five things to daemonize a process:
-/// 1. Reset its umask value.
-/// 2. Run in the background use fork.
-/// 3. Ignore all terminal I/O signals.
-/// 4. Disassociate from the control terminal.
-/// 5. Write pid to pidfile.
-pub fn daemonize(pid_file: Option<String>) -> Result<()> {
-    if let Some(path) = pid_file.as_ref() {
-        if Path::new(path).exists() {
-            return Err(anyhow!(UtilError::PidFileExist));
-        }
+/// This function do seven things to daemonize a process:
+/// 1. Lock pidfile, so it fails fast if another instance is running.
+/// 2. Run in the background use fork, the launching process waits for the
+///    startup result reported by `daemon_ready` or `daemon_failed`.
+/// 3. Disassociate from the control terminal.
+/// 4. Fork again, so that it never gets a control terminal.
+/// 5. Redirect stdio to `/dev/null` or `log_file`.
+/// 6. Write pid to pidfile.
+/// 7. Change working directory to `/` once it's ready.
+pub fn daemonize(pid_file: Option<String>, log_file: Option<&str>) -> Result<Option<File>> {
+    // The lock is shared with the forked processes, and kept by the daemon after
+    // the others exit.
+    let mut pid_file = pid_file.as_deref().map(lock_pid_file).transpose()?;
+
+    // The first fork make parent process wait for the startup result, child
+    // process inherit parent's session ID and have a new process ID. It can
+    // guarantee child process will not be the first process in a session.
+    let (read_end, write_end) = pipe()?;
+    if let Some(child) = fork()? {
+        drop(write_end);
+        wait_startup(read_end, child);
     }
+    drop(read_end);
+    *STARTUP_PIPE.lock().unwrap() = Some(write_end);
 
-    // The first fork make parent process quit, child process inherit parent's
-    // session ID and have a new process ID. It can guarantee child
-    // process will not be the first process in a session.
-    fork()?;
     // Create a new session for process. Now parent process quit will not
     // influence stratovirt process. But stratovirt becomes the first process in
     // new section.
     set_sid()?;
     // The second fork make stratovirt run as daemonize process. It won't be the
     // first process in this session and never get terminal control.
-    fork()?;
-    // Redirect stdio to `/dev/null`.
-    redirect_stdio(libc::STDIN_FILENO)?;
-    redirect_stdio(libc::STDOUT_FILENO)?;
-    redirect_stdio(libc::STDERR_FILENO)?;
+    if fork()?.is_some() {
+        exit(0);
+    }
+    redirect_all_stdio(log_file)?;
 
     // Now can record PID to file. It won't be changed again in stratovirt's
     // lifetime.
-    if let Some(path) = pid_file {
-        create_pid_file(&path)?;
+    if let Some(file) = pid_file.as_mut() {
+        write_pid_file(file)?;
     }
 
+    Ok(pid_file)
+}
+
+/// Report the daemon is ready, so the launching process exits with 0. The working
+/// directory is changed to `/` from now on, relative paths are resolved when vm
+/// is realized. Nothing is done unless the process is daemonized.
+pub fn daemon_ready() -> Result<()> {
+    if STARTUP_PIPE.lock().unwrap().is_none() {
+        return Ok(());
+    }
+    std::env::set_current_dir("/").with_context(|| "Failed to change directory to /")?;
+    report_startup(STARTUP_READY, "");
     Ok(())
 }
+
+/// Report the daemon fails to start with error `err`, so the launching process
+/// prints it and exits with nonzero. Nothing is done unless the process is
+/// daemonized and not ready yet.
+pub fn daemon_failed(err: &str) {
+    report_startup(STARTUP_FAILED, err);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_startup_result() {
+        assert_eq!(startup_result(&[STARTUP_READY]), Ok(()));
+        let mut msg = vec![STARTUP_FAILED];
+        msg.extend_from_slice(b"Failed to realize micro VM.");
+        assert_eq!(
+            startup_result(&msg),
+            Err("Failed to realize micro VM.".to_string())
+        );
+        assert!(startup_result(&[]).is_err());
+        assert!(startup_result(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn test_lock_pid_file() {
+        let path = format!("/tmp/test_lock_pid_file_{}.pid", std::process::id());
+        std::fs::write(&path, "1").unwrap();
+        // A stale pid file is taken over.
+        let mut pid_file = lock_pid_file(&path).unwrap();
+        write_pid_file(&mut pid_file).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+
+        let err = lock_pid_file(&path).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("already running (pid {})", std::process::id())));
+
+        drop(pid_file);
+        assert!(lock_pid_file(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -24,6 +24,12 @@ pub const GIC_ITS_PHANDLE: u32 = 3;
 pub const PPI_CLUSTER_PHANDLE: u32 = 4;
 pub const FIRST_VCPU_PHANDLE: u32 = 6;
 pub const CPU_PHANDLE_START: u32 = 10;
+/// Fixed clock of watchdog, above phandles of harts and their interrupt controllers.
+pub const WDT_CLK_PHANDLE: u32 = 0x200;
+/// Syscon of sifive test finisher, referred by its poweroff and reboot nodes.
+pub const SIFIVE_TEST_PHANDLE: u32 = 0x201;
+/// Gpio controller, referred by gpio-keys of power button.
+pub const GPIO_PHANDLE: u32 = 0x202;
 
 pub const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
 pub const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
@@ -42,6 +48,7 @@ const FDT_LAST_COMP_VERSION: u32 = 16;
 const FDT_BEGIN_NODE: u32 = 0x00000001;
 const FDT_END_NODE: u32 = 0x00000002;
 const FDT_PROP: u32 = 0x00000003;
+const FDT_NOP: u32 = 0x00000004;
 const FDT_END: u32 = 0x00000009;
 // Memory reservation block alignment.
 const MEM_RESERVE_ALIGNMENT: usize = 8;
@@ -75,7 +82,7 @@ pub struct FdtBuilder {
 }
 
 /// FdtReserveEntry structure.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FdtReserveEntry {
     /// The address of reserved memory.
     /// On 32-bit CPUs the upper 32-bits of the value are ignored.
@@ -84,6 +91,12 @@ pub struct FdtReserveEntry {
     size: u64,
 }
 
+impl FdtReserveEntry {
+    pub fn new(address: u64, size: u64) -> Self {
+        FdtReserveEntry { address, size }
+    }
+}
+
 fn check_mem_reserve_overlap(mem_reservations: &[FdtReserveEntry]) -> bool {
     if mem_reservations.len() <= 1 {
         return true;
@@ -302,6 +315,309 @@ pub fn dump_dtb(fdt: &Vec<u8>, file_path: &str) {
     }
 }
 
+fn malformed(reason: &str) -> anyhow::Error {
+    anyhow!(UtilError::MalformedFdt(reason.to_string()))
+}
+
+fn read_be_u32(fdt: &[u8], offset: usize) -> Result<u32> {
+    fdt.get(offset..offset + 4)
+        .map(BigEndian::read_u32)
+        .ok_or_else(|| malformed("offset out of blob"))
+}
+
+fn read_cstr(fdt: &[u8], offset: usize) -> Result<&str> {
+    let tail = fdt
+        .get(offset..)
+        .ok_or_else(|| malformed("offset out of blob"))?;
+    let len = tail
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| malformed("unterminated string"))?;
+    std::str::from_utf8(&tail[..len]).map_err(|_| malformed("non utf-8 string"))
+}
+
+/// Look up property of the node in flattened device tree blob.
+///
+/// Return `None` if the node or the property doesn't exist.
+///
+/// # Arguments
+///
+/// * `fdt` - The blob produced by `FdtBuilder::finish`.
+/// * `node_path` - Full path of node, e.g. "/soc/uart@10000000", "/" for the root node.
+/// * `prop` - Name of the property.
+pub fn find_property(fdt: &[u8], node_path: &str, prop: &str) -> Result<Option<Vec<u8>>> {
+    if read_be_u32(fdt, 0)? != FDT_MAGIC {
+        return Err(malformed("bad magic"));
+    }
+    let off_dt_struct = read_be_u32(fdt, 8)? as usize;
+    let off_dt_strings = read_be_u32(fdt, 12)? as usize;
+    let align = |offset: usize| {
+        let remainder = offset % STRUCTURE_BLOCK_ALIGNMENT;
+        if remainder == 0 {
+            offset
+        } else {
+            offset + STRUCTURE_BLOCK_ALIGNMENT - remainder
+        }
+    };
+
+    let mut path: Vec<&str> = Vec::new();
+    let mut offset = off_dt_struct;
+    loop {
+        let token = read_be_u32(fdt, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = read_cstr(fdt, offset)?;
+                offset = align(offset + name.len() + 1);
+                path.push(name);
+            }
+            FDT_END_NODE => {
+                path.pop()
+                    .ok_or_else(|| malformed("unbalanced end of node"))?;
+            }
+            FDT_PROP => {
+                let len = read_be_u32(fdt, offset)? as usize;
+                let name_off = read_be_u32(fdt, offset + 4)? as usize;
+                let value = fdt
+                    .get(offset + 8..offset + 8 + len)
+                    .ok_or_else(|| malformed("property out of blob"))?;
+                offset = align(offset + 8 + len);
+                let current = if path.len() <= 1 {
+                    "/".to_string()
+                } else {
+                    path.join("/")
+                };
+                if current == node_path && read_cstr(fdt, off_dt_strings + name_off)? == prop {
+                    return Ok(Some(value.to_vec()));
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return Ok(None),
+            _ => return Err(malformed("unknown token")),
+        }
+    }
+}
+
+/// Node of flattened device tree, which is parsed from a blob by `Fdt::from_blob`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FdtNode {
+    /// Name of the node with unit address, e.g. "uart@10000000", "" for the root node.
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    pub fn new(name: &str) -> Self {
+        FdtNode {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Value of u32 property `name`, `None` if it doesn't exist or is not a u32.
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        self.property(name)
+            .filter(|value| value.len() == size_of::<u32>())
+            .map(BigEndian::read_u32)
+    }
+
+    /// First string of string list property `name`.
+    pub fn property_str(&self, name: &str) -> Option<&str> {
+        let value = self.property(name)?;
+        let len = value.iter().position(|b| *b == 0).unwrap_or(value.len());
+        std::str::from_utf8(&value[..len]).ok()
+    }
+
+    /// Replace the value of property `name`, or add it if it doesn't exist.
+    pub fn set_property(&mut self, name: &str, value: &[u8]) {
+        match self.properties.iter_mut().find(|(prop, _)| prop == name) {
+            Some((_, old)) => *old = value.to_vec(),
+            None => self.properties.push((name.to_string(), value.to_vec())),
+        }
+    }
+
+    pub fn remove_property(&mut self, name: &str) {
+        self.properties.retain(|(prop, _)| prop != name);
+    }
+
+    /// Child named `name`, which is added if it doesn't exist.
+    pub fn child_or_insert(&mut self, name: &str) -> &mut FdtNode {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(FdtNode::new(name));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+
+    /// Call `f` on the node and its descendants in depth-first order, with
+    /// their full paths.
+    pub fn walk<'a>(&'a self, path: &str, f: &mut dyn FnMut(&str, &'a FdtNode)) {
+        f(path, self);
+        for child in self.children.iter() {
+            let child_path = if path == "/" {
+                format!("/{}", child.name)
+            } else {
+                format!("{}/{}", path, child.name)
+            };
+            child.walk(&child_path, f);
+        }
+    }
+
+    fn build(&self, builder: &mut FdtBuilder) -> Result<()> {
+        let node_dep = builder.begin_node(&self.name)?;
+        // Properties go before subnodes, as the builder requires.
+        for (name, value) in self.properties.iter() {
+            builder.set_property(name, value)?;
+        }
+        for child in self.children.iter() {
+            child.build(builder)?;
+        }
+        builder.end_node(node_dep)
+    }
+}
+
+/// Flattened device tree parsed from a blob, which is modified and serialized
+/// again, e.g. the one given by `-dtb`.
+#[derive(Clone, Debug, Default)]
+pub struct Fdt {
+    pub root: FdtNode,
+    pub mem_reserve: Vec<FdtReserveEntry>,
+    pub boot_cpuid_phys: u32,
+}
+
+impl Fdt {
+    /// Parse the blob, whose header is checked against its length first so that
+    /// a truncated blob is refused.
+    pub fn from_blob(blob: &[u8]) -> Result<Self> {
+        if blob.len() < FDT_HEADER_SIZE {
+            return Err(malformed("truncated header"));
+        }
+        if read_be_u32(blob, 0)? != FDT_MAGIC {
+            return Err(malformed("bad magic"));
+        }
+        let total_size = read_be_u32(blob, 4)? as usize;
+        if total_size < FDT_HEADER_SIZE {
+            return Err(malformed("total size smaller than header"));
+        }
+        if total_size > blob.len() {
+            return Err(malformed(&format!(
+                "truncated blob, total size is {} but {} bytes are given",
+                total_size,
+                blob.len()
+            )));
+        }
+        let blob = &blob[..total_size];
+        let last_comp_version = read_be_u32(blob, 24)?;
+        if last_comp_version > FDT_VERSION {
+            return Err(malformed(&format!(
+                "unsupported last compatible version {}",
+                last_comp_version
+            )));
+        }
+
+        let mut mem_reserve = Vec::new();
+        let mut offset = read_be_u32(blob, 16)? as usize;
+        loop {
+            let entry = blob
+                .get(offset..offset + 16)
+                .ok_or_else(|| malformed("memory reservation out of blob"))?;
+            let address = BigEndian::read_u64(&entry[0..8]);
+            let size = BigEndian::read_u64(&entry[8..16]);
+            if address == 0 && size == 0 {
+                break;
+            }
+            mem_reserve.push(FdtReserveEntry::new(address, size));
+            offset += 16;
+        }
+
+        Ok(Fdt {
+            root: parse_structure(blob)?,
+            mem_reserve,
+            boot_cpuid_phys: read_be_u32(blob, 28)?,
+        })
+    }
+
+    pub fn finish(&self) -> Result<Vec<u8>> {
+        let mut builder = FdtBuilder::new();
+        builder.add_mem_reserve(&self.mem_reserve)?;
+        builder.set_boot_cpuid_phys(self.boot_cpuid_phys);
+        self.root.build(&mut builder)?;
+        builder.finish()
+    }
+}
+
+fn parse_structure(fdt: &[u8]) -> Result<FdtNode> {
+    let off_dt_struct = read_be_u32(fdt, 8)? as usize;
+    let off_dt_strings = read_be_u32(fdt, 12)? as usize;
+    let align = |offset: usize| {
+        let remainder = offset % STRUCTURE_BLOCK_ALIGNMENT;
+        if remainder == 0 {
+            offset
+        } else {
+            offset + STRUCTURE_BLOCK_ALIGNMENT - remainder
+        }
+    };
+
+    let mut nodes: Vec<FdtNode> = Vec::new();
+    let mut root = None;
+    let mut offset = off_dt_struct;
+    loop {
+        let token = read_be_u32(fdt, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                if root.is_some() {
+                    return Err(malformed("node out of root node"));
+                }
+                let name = read_cstr(fdt, offset)?;
+                offset = align(offset + name.len() + 1);
+                nodes.push(FdtNode::new(name));
+            }
+            FDT_END_NODE => {
+                let node = nodes
+                    .pop()
+                    .ok_or_else(|| malformed("unbalanced end of node"))?;
+                match nodes.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => root = Some(node),
+                }
+            }
+            FDT_PROP => {
+                let len = read_be_u32(fdt, offset)? as usize;
+                let name_off = read_be_u32(fdt, offset + 4)? as usize;
+                let value = fdt
+                    .get(offset + 8..offset + 8 + len)
+                    .ok_or_else(|| malformed("property out of blob"))?;
+                offset = align(offset + 8 + len);
+                let name = read_cstr(fdt, off_dt_strings + name_off)?;
+                nodes
+                    .last_mut()
+                    .ok_or_else(|| malformed("property out of node"))?
+                    .properties
+                    .push((name.to_string(), value.to_vec()));
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => return Err(malformed("unknown token")),
+        }
+    }
+    if !nodes.is_empty() {
+        return Err(malformed("unclosed node"));
+    }
+    root.ok_or_else(|| malformed("no root node"))
+}
+
 /// Trait for devices to be added to the Flattened Device Tree.
 #[allow(clippy::upper_case_acronyms)]
 pub trait CompileFDT {
@@ -491,6 +807,37 @@ mod tests {
         assert!(fdt_builder.finish().is_err());
     }
 
+    #[test]
+    fn test_find_property() {
+        let mut fdt_builder = FdtBuilder::new();
+        let root_node = fdt_builder.begin_node("").unwrap();
+        fdt_builder.set_property_u32("#size-cells", 2).unwrap();
+        let soc_node = fdt_builder.begin_node("soc").unwrap();
+        let uart_node = fdt_builder.begin_node("uart@1000").unwrap();
+        fdt_builder
+            .set_property_array_u64("reg", &[0x1000, 0x100])
+            .unwrap();
+        fdt_builder.end_node(uart_node).unwrap();
+        fdt_builder.end_node(soc_node).unwrap();
+        fdt_builder.end_node(root_node).unwrap();
+        let fdt = fdt_builder.finish().unwrap();
+
+        assert_eq!(
+            find_property(&fdt, "/", "#size-cells").unwrap(),
+            Some(vec![0, 0, 0, 2])
+        );
+        let reg = find_property(&fdt, "/soc/uart@1000", "reg")
+            .unwrap()
+            .unwrap();
+        assert_eq!(BigEndian::read_u64(&reg[0..8]), 0x1000);
+        assert_eq!(BigEndian::read_u64(&reg[8..16]), 0x100);
+        assert!(find_property(&fdt, "/soc", "reg").unwrap().is_none());
+        assert!(find_property(&fdt, "/soc/uart@1000", "interrupts")
+            .unwrap()
+            .is_none());
+        assert!(find_property(&fdt[4..], "/", "#size-cells").is_err());
+    }
+
     #[test]
     fn test_mem_reserve_overlap() {
         let mut fdt_builder = FdtBuilder::new();
@@ -506,4 +853,95 @@ mod tests {
         ];
         assert!(fdt_builder.add_mem_reserve(&mem_reservations).is_err());
     }
+
+    fn sample_blob() -> Vec<u8> {
+        let mut fdt_builder = FdtBuilder::new();
+        fdt_builder
+            .add_mem_reserve(&[FdtReserveEntry::new(0x8000_0000, 0x1000)])
+            .unwrap();
+        fdt_builder.set_boot_cpuid_phys(1);
+        let root_node = fdt_builder.begin_node("").unwrap();
+        fdt_builder.set_property_u32("#address-cells", 2).unwrap();
+        let chosen_node = fdt_builder.begin_node("chosen").unwrap();
+        fdt_builder
+            .set_property_string("bootargs", "console=ttyS0")
+            .unwrap();
+        fdt_builder.end_node(chosen_node).unwrap();
+        let soc_node = fdt_builder.begin_node("soc").unwrap();
+        let uart_node = fdt_builder.begin_node("uart@1000").unwrap();
+        fdt_builder
+            .set_property_string("compatible", "ns16550a")
+            .unwrap();
+        fdt_builder.end_node(uart_node).unwrap();
+        fdt_builder.end_node(soc_node).unwrap();
+        fdt_builder.end_node(root_node).unwrap();
+        fdt_builder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_parse_blob() {
+        let blob = sample_blob();
+        let fdt = Fdt::from_blob(&blob).unwrap();
+        assert_eq!(fdt.boot_cpuid_phys, 1);
+        assert_eq!(
+            fdt.mem_reserve,
+            vec![FdtReserveEntry::new(0x8000_0000, 0x1000)]
+        );
+        assert_eq!(fdt.root.property_u32("#address-cells"), Some(2));
+        assert_eq!(
+            fdt.root.children[0].property_str("bootargs"),
+            Some("console=ttyS0")
+        );
+
+        let mut paths = Vec::new();
+        fdt.root
+            .walk("/", &mut |path, _| paths.push(path.to_string()));
+        assert_eq!(paths, vec!["/", "/chosen", "/soc", "/soc/uart@1000"]);
+
+        // Serializing the parsed tree gives the same blob.
+        assert_eq!(fdt.finish().unwrap(), blob);
+
+        // Trailing bytes after total size are ignored.
+        let mut padded = blob.clone();
+        padded.extend_from_slice(&[0; 16]);
+        assert_eq!(Fdt::from_blob(&padded).unwrap().root, fdt.root);
+    }
+
+    #[test]
+    fn test_parse_malformed_blob() {
+        let blob = sample_blob();
+        assert!(Fdt::from_blob(&blob[..FDT_HEADER_SIZE - 1]).is_err());
+        assert!(Fdt::from_blob(&blob[..blob.len() - 1]).is_err());
+        let mut bad_magic = blob.clone();
+        bad_magic[0] = 0;
+        assert!(Fdt::from_blob(&bad_magic).is_err());
+        let mut bad_struct = blob.clone();
+        BigEndian::write_u32(&mut bad_struct[8..12], blob.len() as u32);
+        assert!(Fdt::from_blob(&bad_struct).is_err());
+    }
+
+    #[test]
+    fn test_modify_node() {
+        let mut fdt = Fdt::from_blob(&sample_blob()).unwrap();
+        let chosen = fdt.root.child_or_insert("chosen");
+        chosen.set_property("bootargs", b"console=hvc0\0");
+        chosen.set_property("linux,initrd-start", &0x8800_0000_u64.to_be_bytes());
+        fdt.root.child_or_insert("memory@80000000");
+        fdt.root.remove_property("#address-cells");
+
+        let blob = fdt.finish().unwrap();
+        assert_eq!(
+            find_property(&blob, "/chosen", "bootargs").unwrap(),
+            Some(b"console=hvc0\0".to_vec())
+        );
+        assert!(find_property(&blob, "/chosen", "linux,initrd-start")
+            .unwrap()
+            .is_some());
+        assert!(find_property(&blob, "/", "#address-cells")
+            .unwrap()
+            .is_none());
+        let fdt = Fdt::from_blob(&blob).unwrap();
+        assert_eq!(fdt.root.children.len(), 3);
+        assert_eq!(fdt.root.children[0].properties.len(), 2);
+    }
 }
@@ -55,8 +55,8 @@ pub enum UtilError {
     DaemonSetsid,
     #[error("Unable to redirect standard streams to /dev/null.")]
     DaemonRedirectStdio,
-    #[error("Pidfile path is existed yet.")]
-    PidFileExist,
+    #[error("Pidfile {0} is locked, TeleVM is already running (pid {1}).")]
+    PidFileLocked(String, String),
     // epoll_context error
     #[error("Found bad syscall, error is {0} .")]
     BadSyscall(std::io::Error),
@@ -86,4 +86,6 @@ pub enum UtilError {
     MemReserveOverlap,
     #[error("Failed to set {0} property")]
     SetPropertyErr(String),
+    #[error("Malformed fdt blob: {0}")]
+    MalformedFdt(String),
 }
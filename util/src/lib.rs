@@ -20,6 +20,7 @@ extern crate kvm_ioctls;
 pub mod aio;
 pub mod arg_parser;
 pub mod bitmap;
+pub mod boot_time;
 pub mod byte_code;
 pub mod checksum;
 pub mod daemonize;
@@ -33,8 +34,11 @@ pub mod logger;
 pub mod loop_context;
 pub mod num_ops;
 pub mod offsetof;
+pub mod parallel;
 #[cfg(not(target_env = "musl"))]
 pub mod pixman;
+pub mod privilege;
+pub mod seccomp;
 pub mod syscall;
 pub mod tap;
 pub mod test_helper;
@@ -10,11 +10,182 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::sync::Mutex;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
 
 use crate::unix::gettid;
-use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// Environment variable of the log filter, such as "virtio=debug,sysbus=trace,info".
+pub const LOG_FILTER_ENV: &str = "TELEVM_LOG";
+/// Legacy environment variable which only gives the global log level.
+const LOG_LEVEL_ENV: &str = "STRATOVIRT_LOG_LEVEL";
+
+/// Modules whose log level can be set separately. The first component of the log
+/// target, which is the crate name for the `log` macros, is matched against them.
+const LOG_MODULES: [&str; 18] = [
+    "TeleVM",
+    "acpi",
+    "address_space",
+    "boot_loader",
+    "cpu",
+    "devices",
+    "hypervisor",
+    "machine",
+    "machine_manager",
+    "migration",
+    "pci",
+    "sysbus",
+    "usb",
+    "util",
+    "vfio",
+    "vhost_user_fs",
+    "virtio",
+    "vnc",
+];
+
+/// Socket of the local syslog daemon, journald listens on it too.
+const SYSLOG_PATH: &str = "/dev/log";
+/// Syslog facility of the records, LOG_DAEMON.
+const SYSLOG_FACILITY: u8 = 3 << 3;
+
+/// Records written between two checks of the log file size on disk.
+const LOG_SIZE_CHECK_INTERVAL: u32 = 64;
+/// Bumped by `SIGHUP` to reopen log files on their next record.
+static LOG_REOPEN_GEN: AtomicUsize = AtomicUsize::new(0);
+
+static LOG_LEVELS: Lazy<Arc<LogLevels>> =
+    Lazy::new(|| Arc::new(LogLevels::new(&LogFilter::default())));
+
+/// Log filter, a comma separated list of `module=level` and an optional bare
+/// `level` for the modules not listed, e.g. "virtio=debug,sysbus=trace,info".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(&'static str, LevelFilter)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// Level of the module named `module`.
+    pub fn level(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == module)
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level).with_context(|| {
+        format!(
+            "Invalid log level \"{}\", should be one of off, error, warn, info, debug and trace",
+            level
+        )
+    })
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    let name = match LOG_MODULES.iter().find(|name| **name == module) {
+                        Some(name) => *name,
+                        None => bail!(
+                            "Unknown log module \"{}\", should be one of {}",
+                            module,
+                            LOG_MODULES.join(", ")
+                        ),
+                    };
+                    let level = parse_level(level.trim())?;
+                    filter.modules.retain(|(n, _)| *n != name);
+                    filter.modules.push((name, level));
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (module, level) in &self.modules {
+            write!(f, "{}={},", module, level.as_str().to_lowercase())?;
+        }
+        write!(f, "{}", self.default.as_str().to_lowercase())
+    }
+}
+
+fn level_from_usize(level: usize) -> LevelFilter {
+    LevelFilter::iter()
+        .find(|l| *l as usize == level)
+        .unwrap_or(LevelFilter::Off)
+}
+
+/// Effective log level of every module, kept in atomics so that checking whether a
+/// record is enabled doesn't take any lock.
+struct LogLevels {
+    default: AtomicUsize,
+    modules: [AtomicUsize; LOG_MODULES.len()],
+}
+
+impl LogLevels {
+    fn new(filter: &LogFilter) -> Self {
+        let levels = LogLevels {
+            default: AtomicUsize::new(0),
+            modules: Default::default(),
+        };
+        levels.apply(filter);
+        levels
+    }
+
+    /// Set levels of all modules by `filter`, and return the most verbose one.
+    fn apply(&self, filter: &LogFilter) -> LevelFilter {
+        let mut max = filter.default;
+        for (module, level) in LOG_MODULES.iter().zip(self.modules.iter()) {
+            let module_level = filter.level(module);
+            level.store(module_level as usize, Ordering::Relaxed);
+            max = max.max(module_level);
+        }
+        self.default
+            .store(filter.default as usize, Ordering::Relaxed);
+        max
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        let module = target.split("::").next().unwrap_or(target);
+        let level = match LOG_MODULES.iter().position(|name| *name == module) {
+            Some(idx) => &self.modules[idx],
+            None => &self.default,
+        };
+        level_from_usize(level.load(Ordering::Relaxed))
+    }
+}
 
 fn format_now() -> String {
     let mut ts = libc::timespec {
@@ -40,24 +211,286 @@ fn format_now() -> String {
     )
 }
 
+/// Log file which is reopened on request, and optionally rotated once it reaches a
+/// size. Rotated files are named "<path>.1" to "<path>.<keep>", from the newest.
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// Size of the file, counted by the records written to it.
+    size: u64,
+    /// Rotate size and number of rotated files kept.
+    rotate: Option<(u64, usize)>,
+    records: u32,
+    /// Value of `LOG_REOPEN_GEN` when the file was opened.
+    reopen_gen: usize,
+}
+
+fn open_log_file(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)
+}
+
+impl LogFile {
+    /// Open log file `path` for appending. It's rotated before it goes beyond
+    /// `rotate_size` bytes, with `rotate_keep` (at least 1) rotated files kept.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        rotate_size: Option<u64>,
+        rotate_keep: usize,
+    ) -> Result<Self> {
+        // The working directory may be changed later, e.g. by daemonize.
+        let path = match std::env::current_dir() {
+            Ok(dir) if path.as_ref().is_relative() => dir.join(path),
+            _ => path.as_ref().to_path_buf(),
+        };
+        let file = open_log_file(&path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            rotate: rotate_size.map(|size| (size, rotate_keep)),
+            records: 0,
+            reopen_gen: LOG_REOPEN_GEN.load(Ordering::Relaxed),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        self.file = open_log_file(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    fn rotate(&mut self, keep: usize) -> std::io::Result<()> {
+        for index in (1..keep).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.reopen()?;
+
+        // Make the renames durable before the old file is dropped.
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+}
+
+impl Write for LogFile {
+    /// Write a whole record, rotating the file beforehand if it has reached the
+    /// rotate size.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let reopen_gen = LOG_REOPEN_GEN.load(Ordering::Relaxed);
+        if reopen_gen != self.reopen_gen {
+            self.reopen_gen = reopen_gen;
+            // Keep writing to the old file if the new one can't be opened.
+            let _ = self.reopen();
+        }
+        if let Some((rotate_size, keep)) = self.rotate {
+            self.records += 1;
+            if self.records >= LOG_SIZE_CHECK_INTERVAL {
+                self.records = 0;
+                // The file may be truncated or removed by others, e.g. logrotate.
+                self.size = self.file.metadata()?.len();
+            }
+            if self.size > 0 && self.size + buf.len() as u64 > rotate_size {
+                // Keep writing to the current file if rotation fails, the record
+                // matters more than the size limit.
+                let _ = self.rotate(keep);
+            }
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Reopen the log file on the next record, used by `SIGHUP` handler after the file is
+/// moved away by logrotate(8). It's async-signal-safe.
+pub fn request_log_reopen() {
+    LOG_REOPEN_GEN.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Severity of syslog for log `level`.
+fn syslog_severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Timestamp of RFC 3164 like "Oct  8 09:05:02", in local time.
+fn format_syslog_time() -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut ti: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut ti) };
+
+    format!(
+        "{} {:>2} {:02}:{:02}:{:02}",
+        MONTHS[ti.tm_mon.clamp(0, 11) as usize],
+        ti.tm_mday,
+        ti.tm_hour,
+        ti.tm_min,
+        ti.tm_sec
+    )
+}
+
+/// Syslog client sending records to the local daemon in RFC 3164 framing without
+/// hostname, as glibc does: "<PRI>Mmm dd hh:mm:ss IDENT[PID]: MSG". The message
+/// starts with the vm id and module, "vm=<id> module=<module> <file>:<line>: ...".
+///
+/// The socket is non-blocking, records are dropped and counted rather than blocking
+/// the logging thread when the daemon is slow.
+pub struct Syslog {
+    socket: UnixDatagram,
+    path: PathBuf,
+    ident: String,
+    vm_id: String,
+    /// Records dropped since the last report.
+    dropped: AtomicU64,
+}
+
+impl Syslog {
+    /// Connect to the local syslog daemon. Records are tagged by `ident`, and carry
+    /// `vm_id` in the message.
+    pub fn connect(ident: &str, vm_id: &str) -> Result<Self> {
+        Self::connect_to(SYSLOG_PATH, ident, vm_id)
+    }
+
+    fn connect_to<P: AsRef<Path>>(path: P, ident: &str, vm_id: &str) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(&path)
+            .with_context(|| format!("Failed to connect to syslog {}", path.display()))?;
+        socket.set_nonblocking(true)?;
+        Ok(Syslog {
+            socket,
+            path,
+            ident: ident.to_string(),
+            vm_id: if vm_id.is_empty() {
+                "-".to_string()
+            } else {
+                vm_id.to_string()
+            },
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    fn format(&self, level: Level, module: &str, location: &str, args: &fmt::Arguments) -> String {
+        format!(
+            "<{}>{} {}[{}]: vm={} module={} {}: {}",
+            SYSLOG_FACILITY | syslog_severity(level),
+            format_syslog_time(),
+            self.ident,
+            std::process::id(),
+            self.vm_id,
+            module,
+            location,
+            args
+        )
+    }
+
+    /// Send a message, return false if it's dropped as the daemon is busy. It's
+    /// written to stderr if the daemon is gone.
+    fn send(&self, msg: &str) -> bool {
+        match self.socket.send(msg.as_bytes()) {
+            Ok(_) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => false,
+            Err(_) => {
+                // The daemon may be restarted with a new socket.
+                if self.socket.send_to(msg.as_bytes(), &self.path).is_err() {
+                    let _ = writeln!(std::io::stderr(), "{}", msg);
+                }
+                true
+            }
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        let module = record.target().split("::").next().unwrap_or("");
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let report = format_args!("{} log records dropped as syslog is busy", dropped);
+            if !self.send(&self.format(Level::Warn, "util", "logger", &report)) {
+                // Report them next time.
+                self.dropped.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+
+        let location = format!(
+            "{}:{}",
+            record.file().unwrap_or(""),
+            record.line().unwrap_or(0)
+        );
+        if !self.send(&self.format(record.level(), module, &location, record.args())) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Where log records go.
+pub enum LogBackend {
+    Stdout,
+    File(LogFile),
+    /// Local syslog daemon, with the ident and the vm id of records.
+    Syslog {
+        ident: String,
+        vm_id: String,
+    },
+}
+
+enum LogHandler {
+    Writer(Mutex<Box<dyn Write + Send>>),
+    Syslog(Syslog),
+}
+
 /// Format like "%year-%mon-%dayT%hour:%min:%sec.%nsec
 struct VmLogger {
-    handler: Option<Mutex<Box<dyn Write + Send>>>,
-    level: Level,
+    handler: Option<LogHandler>,
+    levels: Arc<LogLevels>,
 }
 
 impl Log for VmLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.handler.is_some() && metadata.level() <= self.level
+        self.handler.is_some() && metadata.level() <= self.levels.level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let pid = unsafe { libc::getpid() };
-            let tid = gettid();
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match &self.handler {
+            Some(LogHandler::Writer(writer)) => {
+                let pid = unsafe { libc::getpid() };
+                let tid = gettid();
 
-            self.handler.as_ref().map(|writer| {
-                writer.lock().unwrap().write_fmt(format_args!(
+                // Write the whole line at once, so that a rotating log file never splits it.
+                let line = format!(
                     "{:<5}: [{}][{}][{}: {}]:{}: {}\n",
                     format_now(),
                     pid,
@@ -66,41 +499,336 @@ impl Log for VmLogger {
                     record.line().unwrap_or(0),
                     record.level(),
                     record.args()
-                ))
-            });
+                );
+                let _ = writer.lock().unwrap().write_all(line.as_bytes());
+            }
+            Some(LogHandler::Syslog(syslog)) => syslog.log(record),
+            None => {}
         }
     }
 
     fn flush(&self) {}
 }
 
-fn init_vm_logger(
-    level: Option<Level>,
-    logfile: Option<Box<dyn Write + Send>>,
-) -> Result<(), log::SetLoggerError> {
-    let buffer = logfile.map(Mutex::new);
+fn init_vm_logger(filter: &LogFilter, handler: Option<LogHandler>) -> Result<()> {
+    let max_level = LOG_LEVELS.apply(filter);
     let logger = VmLogger {
-        level: level.unwrap_or(Level::Info),
-        handler: buffer,
+        levels: LOG_LEVELS.clone(),
+        handler,
     };
 
-    log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(LevelFilter::Trace))
+    log::set_boxed_logger(Box::new(logger))
+        .map(|()| log::set_max_level(max_level))
+        .with_context(|| "Logger has been initialized")
+}
+
+/// Filter given by the legacy `STRATOVIRT_LOG_LEVEL`, an invalid level is taken as info.
+fn legacy_env_filter() -> LogFilter {
+    let default = std::env::var(LOG_LEVEL_ENV)
+        .ok()
+        .and_then(|l| match l.to_lowercase().as_str() {
+            "error" => Some(LevelFilter::Error),
+            "warn" => Some(LevelFilter::Warn),
+            "info" => Some(LevelFilter::Info),
+            "debug" => Some(LevelFilter::Debug),
+            "trace" => Some(LevelFilter::Trace),
+            _ => None,
+        })
+        .unwrap_or(LevelFilter::Info);
+    LogFilter {
+        default,
+        modules: Vec::new(),
+    }
+}
+
+fn parse_filter(filter: Option<&str>) -> Result<LogFilter> {
+    match filter {
+        Some(f) => f
+            .parse::<LogFilter>()
+            .with_context(|| format!("Invalid log filter \"{}\"", f)),
+        None => Ok(legacy_env_filter()),
+    }
 }
 
-pub fn init_logger_with_env(logfile: Option<Box<dyn Write + Send>>) -> Result<(), SetLoggerError> {
-    let level = match std::env::var("STRATOVIRT_LOG_LEVEL") {
-        Ok(l) => match l.to_lowercase().as_str() {
-            "error" => Level::Error,
-            "warn" => Level::Warn,
-            "info" => Level::Info,
-            "debug" => Level::Debug,
-            "trace" => Level::Trace,
-            _ => Level::Info,
+/// Init the logger with log filter string `filter`, such as "virtio=debug,info".
+/// Without `filter`, the global level is taken from `STRATOVIRT_LOG_LEVEL`.
+pub fn init_logger(filter: Option<&str>, logfile: Option<Box<dyn Write + Send>>) -> Result<()> {
+    let filter = parse_filter(filter)?;
+    init_vm_logger(&filter, logfile.map(|w| LogHandler::Writer(Mutex::new(w))))
+}
+
+/// Init the logger sending records to `backend`, with log filter as `init_logger`.
+/// Log goes to stderr if syslog daemon is unavailable.
+pub fn init_logger_with_backend(filter: Option<&str>, backend: LogBackend) -> Result<()> {
+    let filter = parse_filter(filter)?;
+    let handler = match backend {
+        LogBackend::Stdout => LogHandler::Writer(Mutex::new(Box::new(std::io::stdout()))),
+        LogBackend::File(file) => LogHandler::Writer(Mutex::new(Box::new(file))),
+        LogBackend::Syslog { ident, vm_id } => match Syslog::connect(&ident, &vm_id) {
+            Ok(syslog) => LogHandler::Syslog(syslog),
+            Err(e) => {
+                let _ = writeln!(std::io::stderr(), "{:?}, log goes to stderr", e);
+                LogHandler::Writer(Mutex::new(Box::new(std::io::stderr())))
+            }
         },
-        _ => Level::Info,
     };
+    init_vm_logger(&filter, Some(handler))
+}
 
-    init_vm_logger(Some(level), logfile)?;
+/// Init the logger with log filter given by `TELEVM_LOG`, or `STRATOVIRT_LOG_LEVEL`.
+pub fn init_logger_with_env(logfile: Option<Box<dyn Write + Send>>) -> Result<()> {
+    let filter = std::env::var(LOG_FILTER_ENV)
+        .ok()
+        .filter(|f| !f.trim().is_empty());
+    init_logger(filter.as_deref(), logfile)
+}
 
+/// Replace the log filter at runtime.
+pub fn set_log_filter(filter: &str) -> Result<()> {
+    let filter = filter
+        .parse::<LogFilter>()
+        .with_context(|| format!("Invalid log filter \"{}\"", filter))?;
+    log::set_max_level(LOG_LEVELS.apply(&filter));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log_to(logger: &VmLogger, target: &str, level: Level, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .target(target)
+                .level(level)
+                .args(format_args!("{}", msg))
+                .build(),
+        );
+    }
+
+    fn read_lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_log_file_rotation() {
+        let dir = std::env::temp_dir().join(format!("televm-log-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("televm.log");
+        let logger = Arc::new(VmLogger {
+            handler: Some(LogHandler::Writer(Mutex::new(Box::new(
+                LogFile::open(&path, Some(1024), 100).unwrap(),
+            )))),
+            levels: Arc::new(LogLevels::new(&LogFilter::default())),
+        });
+
+        let mut threads = Vec::new();
+        for t in 0..4 {
+            let logger = logger.clone();
+            threads.push(std::thread::spawn(move || {
+                for i in 0..100 {
+                    log_to(
+                        &logger,
+                        "virtio",
+                        Level::Info,
+                        &format!("record-{}-{}", t, i),
+                    );
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut records = read_lines(&path);
+        let mut index = 1;
+        loop {
+            let rotated = dir.join(format!("televm.log.{}", index));
+            if !rotated.exists() {
+                break;
+            }
+            assert!(std::fs::metadata(&rotated).unwrap().len() <= 1024);
+            records.extend(read_lines(&rotated));
+            index += 1;
+        }
+        assert!(index > 5);
+        let mut records: Vec<String> = records
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().to_string())
+            .collect();
+        records.sort();
+        // Every record is written exactly once across the rotated files.
+        let mut expected: Vec<String> = (0..4)
+            .flat_map(|t| (0..100).map(move |i| format!("record-{}-{}", t, i)))
+            .collect();
+        expected.sort();
+        assert_eq!(records.len(), 400);
+        assert_eq!(records, expected);
+
+        // Only the newest rotated files are kept.
+        let mut file = LogFile::open(&path, Some(1024), 2).unwrap();
+        for i in 0..100 {
+            file.write_all(format!("keep-{:064}\n", i).as_bytes())
+                .unwrap();
+        }
+        assert!(dir.join("televm.log.2").exists());
+        assert!(read_lines(&dir.join("televm.log.2"))[0].starts_with("keep-"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_file_reopen() {
+        let dir = std::env::temp_dir().join(format!("televm-log-reopen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("televm.log");
+        let moved = dir.join("televm.log.old");
+
+        let mut file = LogFile::open(&path, None, 0).unwrap();
+        file.write_all(b"before\n").unwrap();
+        std::fs::rename(&path, &moved).unwrap();
+        file.write_all(b"moved\n").unwrap();
+        request_log_reopen();
+        file.write_all(b"after\n").unwrap();
+
+        assert_eq!(read_lines(&moved), vec!["before", "moved"]);
+        assert_eq!(read_lines(&path), vec!["after"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_syslog() {
+        let dir = std::env::temp_dir().join(format!("televm-syslog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.sock");
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        daemon.set_nonblocking(true).unwrap();
+        let recv = |daemon: &UnixDatagram| {
+            let mut buf = vec![0_u8; 4096];
+            daemon
+                .recv(&mut buf)
+                .ok()
+                .map(|len| String::from_utf8(buf[..len].to_vec()).unwrap())
+        };
+
+        assert!(Syslog::connect_to(dir.join("nonexist.sock"), "televm", "vm1").is_err());
+        let logger = VmLogger {
+            handler: Some(LogHandler::Syslog(
+                Syslog::connect_to(&path, "televm-vm1", "vm1").unwrap(),
+            )),
+            levels: Arc::new(LogLevels::new(&"virtio=debug,info".parse().unwrap())),
+        };
+        let prefix = format!("televm-vm1[{}]: vm=vm1", std::process::id());
+
+        log_to(&logger, "virtio::block", Level::Error, "broken");
+        let msg = recv(&daemon).unwrap();
+        assert!(msg.starts_with("<27>"));
+        assert!(msg.contains(&format!("{} module=virtio ", prefix)));
+        assert!(msg.ends_with(": broken"));
+        log_to(&logger, "virtio::block", Level::Debug, "details");
+        assert!(recv(&daemon).unwrap().starts_with("<31>"));
+        log_to(&logger, "pci", Level::Info, "info");
+        assert!(recv(&daemon).unwrap().starts_with("<30>"));
+        log_to(&logger, "pci", Level::Debug, "filtered");
+        assert!(recv(&daemon).is_none());
+
+        // Records are dropped rather than blocking while the daemon doesn't receive.
+        let mut sent = 0;
+        for i in 0..100_000 {
+            log_to(&logger, "pci", Level::Warn, &format!("flood-{}", i));
+            sent += 1;
+            if let Some(LogHandler::Syslog(syslog)) = &logger.handler {
+                if syslog.dropped.load(Ordering::Relaxed) >= 10 {
+                    break;
+                }
+            }
+        }
+        assert!(sent < 100_000);
+        let mut received = 0;
+        while recv(&daemon).is_some() {
+            received += 1;
+        }
+        assert_eq!(received + 10, sent);
+        log_to(&logger, "pci", Level::Warn, "recovered");
+        let report = recv(&daemon).unwrap();
+        assert!(report.starts_with("<28>"));
+        assert!(report.contains("module=util logger: 10 log records dropped"));
+        assert!(recv(&daemon).unwrap().ends_with(": recovered"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_filter_parse() {
+        let filter = "virtio=debug, sysbus=trace,warn"
+            .parse::<LogFilter>()
+            .unwrap();
+        assert_eq!(filter.level("virtio"), LevelFilter::Debug);
+        assert_eq!(filter.level("sysbus"), LevelFilter::Trace);
+        assert_eq!(filter.level("pci"), LevelFilter::Warn);
+        assert_eq!(filter.to_string(), "virtio=debug,sysbus=trace,warn");
+
+        let filter = "virtio=debug,virtio=off".parse::<LogFilter>().unwrap();
+        assert_eq!(filter.level("virtio"), LevelFilter::Off);
+        assert_eq!(filter.level("pci"), LevelFilter::Info);
+        assert_eq!(filter.to_string(), "virtio=off,info");
+        assert_eq!("".parse::<LogFilter>().unwrap(), LogFilter::default());
+
+        assert!("virtio=verbose".parse::<LogFilter>().is_err());
+        assert!("nonexist=debug".parse::<LogFilter>().is_err());
+        assert!("loud".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn test_log_levels_filtering() {
+        let output = Capture::default();
+        let levels = Arc::new(LogLevels::new(
+            &"virtio=debug,sysbus=off,warn".parse().unwrap(),
+        ));
+        let logger = VmLogger {
+            handler: Some(LogHandler::Writer(Mutex::new(Box::new(output.clone())))),
+            levels: levels.clone(),
+        };
+
+        log_to(&logger, "virtio::block", Level::Debug, "virtio-debug");
+        log_to(&logger, "virtio::block", Level::Trace, "virtio-trace");
+        log_to(&logger, "sysbus", Level::Error, "sysbus-error");
+        log_to(&logger, "pci::bus", Level::Warn, "pci-warn");
+        log_to(&logger, "pci::bus", Level::Info, "pci-info");
+        log_to(&logger, "kvm_ioctls", Level::Warn, "other-warn");
+        let text = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(text.contains("virtio-debug"));
+        assert!(!text.contains("virtio-trace"));
+        assert!(!text.contains("sysbus-error"));
+        assert!(text.contains("pci-warn"));
+        assert!(!text.contains("pci-info"));
+        assert!(text.contains("other-warn"));
+
+        // Changing the filter takes effect on the running logger.
+        output.0.lock().unwrap().clear();
+        let max = levels.apply(&"sysbus=trace,error".parse().unwrap());
+        assert_eq!(max, LevelFilter::Trace);
+        log_to(&logger, "virtio::block", Level::Debug, "virtio-debug");
+        log_to(&logger, "sysbus::mmio", Level::Trace, "sysbus-trace");
+        log_to(&logger, "pci::bus", Level::Error, "pci-error");
+        let text = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(!text.contains("virtio-debug"));
+        assert!(text.contains("sysbus-trace"));
+        assert!(text.contains("pci-error"));
+    }
+}
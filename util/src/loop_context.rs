@@ -13,7 +13,7 @@
 use std::collections::BTreeMap;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
@@ -21,6 +21,7 @@ use libc::{c_void, read, EFD_NONBLOCK};
 use log::warn;
 use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 use crate::UtilError;
 use anyhow::{anyhow, Context, Result};
@@ -154,28 +155,220 @@ pub trait EventLoopManager: Send + Sync {
 }
 
 /// Timer structure is used for delay function execution.
-struct Timer {
+struct Timer<F: ?Sized> {
+    /// Identify the timer to be cancelled or re-armed by `TimerHandle`.
+    id: u64,
     /// Given the function that will be called.
-    func: Box<dyn Fn()>,
+    func: Arc<F>,
     /// Given the real time when the `func` will be called.
     expire_time: Instant,
 }
 
-impl Timer {
+impl<F: ?Sized> Timer<F> {
     /// Construct function
     ///
     /// # Arguments
     ///
+    /// * `id` - the id of timer.
     /// * `func` - the function will be called later.
-    /// * `nsec` - delay time in nanosecond.
-    pub fn new(func: Box<dyn Fn()>, nsec: u64) -> Self {
+    /// * `delay` - delay time from now.
+    fn new(id: u64, func: Arc<F>, delay: Duration) -> Self {
         Timer {
+            id,
             func,
-            expire_time: Instant::now() + Duration::new(0, nsec as u32),
+            expire_time: Instant::now() + delay,
         }
     }
 }
 
+/// Wakes up the loop when the soonest timer changes, so the loop waits for it in
+/// time even if the timers are changed by other threads.
+enum TimerWaker {
+    /// Armed to the soonest timer and registered in the loop.
+    Timerfd(Mutex<TimerFd>),
+    /// Kick event of the loop, used if timerfd can't be created. The loop then
+    /// re-evaluates the epoll timeout after it's woken up.
+    Kick(Arc<EventFd>),
+}
+
+impl TimerWaker {
+    fn new(kick_event: &Arc<EventFd>) -> Self {
+        match Self::create_timerfd() {
+            Ok(timerfd) => TimerWaker::Timerfd(Mutex::new(timerfd)),
+            Err(e) => {
+                warn!("{:?}, timers of eventloop fall back to epoll timeout", e);
+                TimerWaker::Kick(kick_event.clone())
+            }
+        }
+    }
+
+    fn create_timerfd() -> Result<TimerFd> {
+        let timerfd = TimerFd::new().with_context(|| "Failed to create timerfd")?;
+        // The timer may be re-armed after epoll reports it, reading it mustn't block.
+        // SAFETY: the timerfd is valid.
+        let ret = unsafe { libc::fcntl(timerfd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| "Failed to set timerfd nonblocking");
+        }
+        Ok(timerfd)
+    }
+
+    fn arm(&self, expire_time: Instant) {
+        match self {
+            TimerWaker::Timerfd(timerfd) => {
+                // Zero disarms timerfd, so an expired timer is armed with the minimum delay.
+                let delay = expire_time
+                    .saturating_duration_since(Instant::now())
+                    .max(Duration::from_nanos(1));
+                if let Err(e) = timerfd.lock().unwrap().reset(delay, None) {
+                    warn!("Failed to arm timerfd of eventloop, {:?}", e);
+                }
+            }
+            TimerWaker::Kick(kick_event) => {
+                if let Err(e) = kick_event.write(1) {
+                    warn!("Failed to kick eventloop for timers, {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Timers of a loop sorted by expire time, the soonest one is armed on `waker`.
+struct TimerQueue<F: ?Sized> {
+    /// Timer list
+    timers: Mutex<Vec<Timer<F>>>,
+    /// Only armed with `timers` locked.
+    waker: Arc<TimerWaker>,
+    next_id: AtomicU64,
+}
+
+impl<F: ?Sized> TimerQueue<F> {
+    fn new(waker: Arc<TimerWaker>) -> Self {
+        TimerQueue {
+            timers: Mutex::new(Vec::new()),
+            waker,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn alloc_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Insert `timer` in order of expire_time, after removing the timer with the
+    /// same id if it's pending.
+    fn insert(&self, timer: Timer<F>) {
+        let mut timers = self.timers.lock().unwrap();
+        let soonest = timers.first().map(|t| t.expire_time);
+        timers.retain(|t| t.id != timer.id);
+        let index = timers
+            .iter()
+            .position(|t| timer.expire_time < t.expire_time)
+            .unwrap_or(timers.len());
+        timers.insert(index, timer);
+        if soonest != Some(timers[0].expire_time) {
+            self.waker.arm(timers[0].expire_time);
+        }
+    }
+
+    /// Remove the pending timer `id`, and arm the next one if it's the soonest.
+    fn remove(&self, id: u64) -> bool {
+        let mut timers = self.timers.lock().unwrap();
+        let index = match timers.iter().position(|t| t.id == id) {
+            Some(index) => index,
+            None => return false,
+        };
+        timers.remove(index);
+        if index == 0 && !timers.is_empty() {
+            self.waker.arm(timers[0].expire_time);
+        }
+        true
+    }
+
+    fn is_pending(&self, id: u64) -> bool {
+        self.timers.lock().unwrap().iter().any(|t| t.id == id)
+    }
+
+    /// Take out the timers which have already expired, and arm the next one.
+    fn take_expired(&self) -> Vec<Timer<F>> {
+        let now = Instant::now();
+        let mut timers = self.timers.lock().unwrap();
+        let expired_nr = timers
+            .iter()
+            .position(|t| t.expire_time > now)
+            .unwrap_or(timers.len());
+        let expired_timers: Vec<Timer<F>> = timers.drain(0..expired_nr).collect();
+        if expired_nr != 0 && !timers.is_empty() {
+            self.waker.arm(timers[0].expire_time);
+        }
+        expired_timers
+    }
+
+    /// Get the time from now to the soonest expire_time, zero if it's expired.
+    fn min_timeout(&self) -> Option<Duration> {
+        let timers = self.timers.lock().unwrap();
+        timers
+            .first()
+            .map(|t| t.expire_time.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Handle of a timer added by `EventLoopContext::add_timer`, which cancels or re-arms
+/// the timer from any thread. It never waits for the timer function, so it's safe
+/// to be used with the lock taken by the function held, e.g. the device mutex.
+#[derive(Clone)]
+pub struct TimerHandle {
+    id: u64,
+    func: Arc<dyn Fn() + Send + Sync>,
+    /// Bumped every time the timer is re-armed or cancelled.
+    gen: Arc<AtomicU64>,
+    /// Generation of the timer whose function is called last.
+    called_gen: Arc<AtomicU64>,
+    queue: Arc<TimerQueue<dyn Fn() + Send + Sync>>,
+}
+
+impl TimerHandle {
+    /// Cancel the timer if it's pending, and return whether it was pending. The
+    /// function being called now isn't waited for.
+    pub fn cancel(&self) -> bool {
+        self.gen.fetch_add(1, Ordering::SeqCst);
+        self.queue.remove(self.id)
+    }
+
+    /// Re-arm the timer to call the function after `delay` from now, whether it's
+    /// pending, called or cancelled. The pending one is replaced.
+    pub fn rearm(&self, delay: Duration) {
+        let gen = self.gen.fetch_add(1, Ordering::SeqCst) + 1;
+        let func = self.func.clone();
+        let called_gen = self.called_gen.clone();
+        let func: Arc<dyn Fn() + Send + Sync> = Arc::new(move || {
+            called_gen.store(gen, Ordering::SeqCst);
+            func();
+        });
+        self.queue.insert(Timer::new(self.id, func, delay));
+    }
+
+    /// Whether the timer is waiting to be called.
+    pub fn is_pending(&self) -> bool {
+        self.queue.is_pending(self.id)
+    }
+
+    /// Whether the function being called is for the latest arming, that is the
+    /// timer isn't cancelled or re-armed since it expired. The function which
+    /// takes the lock held by callers of `cancel` and `rearm` checks it after
+    /// taking the lock, to drop the expiry raced with them.
+    pub fn is_current(&self) -> bool {
+        self.called_gen.load(Ordering::SeqCst) == self.gen.load(Ordering::SeqCst)
+    }
+}
+
+impl Debug for TimerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimerHandle").field("id", &self.id).finish()
+    }
+}
+
 /// Epoll Loop Context
 #[allow(clippy::vec_box)]
 pub struct EventLoopContext {
@@ -184,7 +377,7 @@ pub struct EventLoopContext {
     /// Control epoll loop running.
     manager: Option<Arc<Mutex<dyn EventLoopManager>>>,
     /// Used to wakeup epoll to re-evaluate events or timers.
-    kick_event: EventFd,
+    kick_event: Arc<EventFd>,
     /// Used to avoid unnecessary kick operation when the
     /// next re-evaluation is performed before next epoll.
     kick_me: AtomicBool,
@@ -196,8 +389,12 @@ pub struct EventLoopContext {
     gc: Arc<RwLock<Vec<Box<EventNotifier>>>>,
     /// Temp events vector, store wait returned events.
     ready_events: Vec<EpollEvent>,
-    /// Timer list
-    timers: Arc<Mutex<Vec<Timer>>>,
+    /// Timers added by `delay_call`.
+    timers: TimerQueue<dyn Fn()>,
+    /// Timers added by `add_timer`, which may be changed by other threads.
+    shared_timers: Arc<TimerQueue<dyn Fn() + Send + Sync>>,
+    /// Wakes up the loop for both kinds of timers.
+    timer_waker: Arc<TimerWaker>,
 }
 
 // SAFETY: The closure in EventNotifier and Timer doesn't impl Send, they're
@@ -207,21 +404,49 @@ unsafe impl Send for EventLoopContext {}
 impl EventLoopContext {
     /// Constructs a new `EventLoopContext`.
     pub fn new() -> Self {
+        let kick_event = Arc::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let timer_waker = Arc::new(TimerWaker::new(&kick_event));
         let mut ctx = EventLoopContext {
             epoll: Epoll::new().unwrap(),
             manager: None,
-            kick_event: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kick_event,
             kick_me: AtomicBool::new(false),
             kicked: AtomicBool::new(false),
             events: Arc::new(RwLock::new(BTreeMap::new())),
             gc: Arc::new(RwLock::new(Vec::new())),
             ready_events: vec![EpollEvent::default(); READY_EVENT_MAX],
-            timers: Arc::new(Mutex::new(Vec::new())),
+            timers: TimerQueue::new(timer_waker.clone()),
+            shared_timers: Arc::new(TimerQueue::new(timer_waker.clone())),
+            timer_waker,
         };
         ctx.init_kick();
+        ctx.init_timerfd();
         ctx
     }
 
+    fn init_timerfd(&mut self) {
+        let timerfd = match self.timer_waker.as_ref() {
+            TimerWaker::Timerfd(timerfd) => timerfd.lock().unwrap().as_raw_fd(),
+            TimerWaker::Kick(_) => return,
+        };
+        let timer_waker = self.timer_waker.clone();
+        // Expired timers are called after events are handled.
+        let timer_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            if let TimerWaker::Timerfd(timerfd) = timer_waker.as_ref() {
+                let _ = timerfd.lock().unwrap().wait();
+            }
+            None
+        });
+        self.add_event(EventNotifier::new(
+            NotifierOperation::AddExclusion,
+            timerfd,
+            None,
+            EventSet::IN,
+            vec![timer_handler],
+        ))
+        .unwrap();
+    }
+
     fn init_kick(&mut self) {
         let kick_handler: Rc<NotifierCallback> = Rc::new(|_, fd| {
             read_fd(fd);
@@ -498,37 +723,42 @@ impl EventLoopContext {
     /// * `func` - the function will be called later.
     /// * `nsec` - delay time in nanoseconds.
     pub fn delay_call(&mut self, func: Box<dyn Fn()>, nsec: u64) {
-        let timer = Timer::new(func, nsec);
+        let id = self.timers.alloc_id();
+        self.timers
+            .insert(Timer::new(id, Arc::from(func), Duration::from_nanos(nsec)));
+    }
 
-        // insert in order of expire_time
-        let mut timers = self.timers.lock().unwrap();
-        let mut index = timers.len();
-        for (i, t) in timers.iter().enumerate() {
-            if timer.expire_time < t.expire_time {
-                index = i;
-                break;
-            }
-        }
-        timers.insert(index, timer);
-        drop(timers);
-        self.kick();
+    /// Call the function given by `func` after `delay` in this loop, and return
+    /// the handle to cancel or re-arm it. The timer is kept until the function is
+    /// called or it's cancelled, and it can be re-armed any times.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - delay time from now.
+    /// * `func` - the function will be called later.
+    pub fn add_timer(&self, delay: Duration, func: Box<dyn Fn() + Send + Sync>) -> TimerHandle {
+        let handle = TimerHandle {
+            id: self.shared_timers.alloc_id(),
+            func: Arc::from(func),
+            gen: Arc::new(AtomicU64::new(0)),
+            called_gen: Arc::new(AtomicU64::new(0)),
+            queue: self.shared_timers.clone(),
+        };
+        handle.rearm(delay);
+        handle
     }
 
     /// Get the expire_time of the soonest Timer, and then translate it to timeout.
     fn timers_min_timeout(&self) -> i32 {
         // The kick event happens before re-evaluate can be ignored.
         self.kicked.store(false, Ordering::SeqCst);
-        let timers = self.timers.lock().unwrap();
-        if timers.is_empty() {
-            return -1;
-        }
-
-        let now = Instant::now();
-        if timers[0].expire_time <= now {
-            return 0;
-        }
+        let timeout = match self.min_timeout() {
+            Some(timeout) => timeout,
+            None => return -1,
+        };
 
-        let timeout = (timers[0].expire_time - now).as_millis();
+        // Round up, so that epoll doesn't wake up before the timer expires.
+        let timeout = (timeout + Duration::from_nanos(999_999)).as_millis();
         if timeout >= i32::MAX as u128 {
             i32::MAX - 1
         } else {
@@ -539,17 +769,11 @@ impl EventLoopContext {
     pub fn timers_min_timeout_ns(&self) -> i64 {
         // The kick event happens before re-evaluate can be ignored.
         self.kicked.store(false, Ordering::SeqCst);
-        let timers = self.timers.lock().unwrap();
-        if timers.is_empty() {
-            return -1;
-        }
-
-        let now = Instant::now();
-        if timers[0].expire_time <= now {
-            return 0;
-        }
+        let timeout = match self.min_timeout() {
+            Some(timeout) => timeout.as_nanos(),
+            None => return -1,
+        };
 
-        let timeout = (timers[0].expire_time - now).as_nanos();
         if timeout >= i64::MAX as u128 {
             i64::MAX - 1
         } else {
@@ -557,25 +781,30 @@ impl EventLoopContext {
         }
     }
 
+    /// Get the time from now to the soonest timer of both queues.
+    fn min_timeout(&self) -> Option<Duration> {
+        match (self.timers.min_timeout(), self.shared_timers.min_timeout()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
 
     /// Call function of the timers which have already expired.
     pub fn run_timers(&mut self) {
-        let now = Instant::now();
-        let mut expired_nr = 0;
-
-        let mut timers = self.timers.lock().unwrap();
-        for timer in timers.iter() {
-            if timer.expire_time > now {
-                break;
-            }
-
-            expired_nr += 1;
+        let mut expired: Vec<(Instant, Arc<dyn Fn()>)> = self
+            .timers
+            .take_expired()
+            .into_iter()
+            .map(|t| (t.expire_time, t.func))
+            .collect();
+        for timer in self.shared_timers.take_expired() {
+            expired.push((timer.expire_time, timer.func));
         }
-
-        let expired_timers: Vec<Timer> = timers.drain(0..expired_nr).collect();
-        drop(timers);
-        for timer in expired_timers {
-            (timer.func)();
+        expired.sort_by_key(|(expire_time, _)| *expire_time);
+        // The functions are called without the timers locked, so they can add,
+        // cancel or re-arm timers.
+        for (_, func) in expired {
+            func();
         }
     }
 
@@ -901,4 +1130,128 @@ mod test {
 
         assert!(mainloop.update_events(vec![event]).is_ok());
     }
+
+    /// Run the loop until `done` returns true, or it takes longer than `limit`.
+    fn run_until<F: Fn() -> bool>(ctx: &mut EventLoopContext, limit: Duration, done: F) {
+        let start = Instant::now();
+        // Wake up the loop at the limit, even if it has nothing else to do.
+        ctx.delay_call(Box::new(|| {}), limit.as_nanos() as u64);
+        while !done() && start.elapsed() < limit {
+            ctx.run().unwrap();
+        }
+    }
+
+    #[test]
+    fn timer_accuracy_test() {
+        let mut mainloop = EventLoopContext::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let start = Instant::now();
+        let delays = [30_u64, 10, 20];
+        let mut handles = Vec::new();
+        for delay in delays {
+            let fired = fired.clone();
+            handles.push(mainloop.add_timer(
+                Duration::from_millis(delay),
+                Box::new(move || fired.lock().unwrap().push((delay, start.elapsed()))),
+            ));
+        }
+        assert!(handles.iter().all(|handle| handle.is_pending()));
+
+        run_until(&mut mainloop, Duration::from_secs(1), || {
+            fired.lock().unwrap().len() == delays.len()
+        });
+        let fired = fired.lock().unwrap();
+        assert_eq!(
+            fired.iter().map(|(delay, _)| *delay).collect::<Vec<u64>>(),
+            vec![10, 20, 30]
+        );
+        // Never early, and the upper bound leaves room for a loaded host.
+        for (delay, elapsed) in fired.iter() {
+            let delay = Duration::from_millis(*delay);
+            assert!(*elapsed >= delay, "{:?} fired at {:?}", delay, elapsed);
+            assert!(
+                *elapsed < delay + Duration::from_millis(50),
+                "{:?} fired at {:?}",
+                delay,
+                elapsed
+            );
+        }
+        assert!(handles.iter().all(|handle| !handle.is_pending()));
+    }
+
+    #[test]
+    fn timer_cancel_rearm_test() {
+        let mut mainloop = EventLoopContext::new();
+        let count = Arc::new(AtomicU64::new(0));
+        let counter = count.clone();
+        let handle = mainloop.add_timer(
+            Duration::from_millis(10),
+            Box::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+
+        assert!(handle.cancel());
+        assert!(!handle.cancel());
+        run_until(&mut mainloop, Duration::from_millis(30), || false);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        // Re-arming a pending timer replaces it.
+        handle.rearm(Duration::from_millis(20));
+        handle.rearm(Duration::from_millis(5));
+        run_until(&mut mainloop, Duration::from_millis(40), || false);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // The loop waiting for nothing is woken up by the timer re-armed by another
+        // thread, which holds the lock taken by the timer function meanwhile.
+        let lock = Arc::new(Mutex::new(()));
+        let locked = lock.clone();
+        let counter = count.clone();
+        let handle = mainloop.add_timer(
+            Duration::from_secs(3600),
+            Box::new(move || {
+                let _locked = locked.lock().unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+        handle.cancel();
+        let thread_handle = handle.clone();
+        let thread = std::thread::spawn(move || {
+            let _locked = lock.lock().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            thread_handle.rearm(Duration::from_millis(1));
+        });
+        let start = Instant::now();
+        run_until(&mut mainloop, Duration::from_secs(1), || {
+            count.load(Ordering::SeqCst) == 2
+        });
+        assert!(start.elapsed() < Duration::from_millis(500));
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn timer_is_current_test() {
+        let mut mainloop = EventLoopContext::new();
+        let handle: Arc<Mutex<Option<TimerHandle>>> = Arc::new(Mutex::new(None));
+        let current = Arc::new(Mutex::new(Vec::new()));
+        let (timer, record) = (handle.clone(), current.clone());
+        *handle.lock().unwrap() = Some(mainloop.add_timer(
+            Duration::from_millis(1),
+            Box::new(move || {
+                let timer = timer.lock().unwrap();
+                let timer = timer.as_ref().unwrap();
+                let mut record = record.lock().unwrap();
+                record.push(timer.is_current());
+                // The expiry being handled is stale once the timer is re-armed.
+                if record.len() == 1 {
+                    timer.rearm(Duration::from_millis(1));
+                    record.push(timer.is_current());
+                }
+            }),
+        ));
+        run_until(&mut mainloop, Duration::from_secs(1), || {
+            current.lock().unwrap().len() == 3
+        });
+        assert_eq!(*current.lock().unwrap(), vec![true, false, true]);
+    }
 }
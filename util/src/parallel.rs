@@ -0,0 +1,94 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::Mutex;
+use std::thread;
+
+/// Task run by `run_parallel`, which may borrow from the caller.
+pub type ParallelTask<'a, T> = Box<dyn FnOnce() -> T + Send + 'a>;
+
+/// Run independent `tasks` on at most `threads` scoped threads, and return their
+/// results in the order of `tasks` whichever finishes first. Tasks run one by one on
+/// the caller thread if `threads` is 1, which keeps the order of side effects.
+///
+/// # Arguments
+///
+/// * `threads` - The maximum number of threads.
+/// * `tasks` - Tasks to run.
+pub fn run_parallel<T: Send>(threads: usize, tasks: Vec<ParallelTask<'_, T>>) -> Vec<T> {
+    let workers = threads.min(tasks.len());
+    if workers <= 1 {
+        return tasks.into_iter().map(|task| task()).collect();
+    }
+
+    let nr_tasks = tasks.len();
+    let queue = Mutex::new(tasks.into_iter().enumerate());
+    let results: Mutex<Vec<Option<T>>> = Mutex::new((0..nr_tasks).map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((idx, task)) => {
+                        let result = task();
+                        results.lock().unwrap()[idx] = Some(result);
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_run_parallel() {
+        for threads in [1, 2, 8] {
+            let running = AtomicUsize::new(0);
+            let max_running = AtomicUsize::new(0);
+            let tasks: Vec<ParallelTask<'_, usize>> = (0..6)
+                .map(|idx| {
+                    let running = &running;
+                    let max_running = &max_running;
+                    Box::new(move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(now, Ordering::SeqCst);
+                        // Later tasks finish first.
+                        thread::sleep(Duration::from_millis(10 * (6 - idx) as u64));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        idx * 2
+                    }) as ParallelTask<'_, usize>
+                })
+                .collect();
+
+            assert_eq!(run_parallel(threads, tasks), vec![0, 2, 4, 6, 8, 10]);
+            assert!(max_running.load(Ordering::SeqCst) <= threads);
+            if threads == 1 {
+                assert_eq!(max_running.load(Ordering::SeqCst), 1);
+            }
+        }
+        assert!(run_parallel::<()>(4, Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Privilege drop of the VMM process, given by `-chroot` and `-runas`.
+//!
+//! TeleVM may start as root to open tap devices, hugepages and other host
+//! resources while devices are realized. Before vcpus start, it changes its root
+//! directory and switches to an unprivileged user, so the files opened later,
+//! such as the images of `blockdev-add`, are resolved inside the new root and
+//! checked against the permissions of that user.
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+/// Size of the buffer for the strings of passwd and group entries.
+const ENTRY_BUF_LEN: usize = 16384;
+/// Most supplementary groups of a user that are kept.
+const MAX_GROUPS: usize = 256;
+
+/// Securebits which let a process keep its capabilities across setuid.
+const SECBIT_NO_SETUID_FIXUP: libc::c_int = 1 << 2;
+const SECBIT_KEEP_CAPS: libc::c_int = 1 << 4;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Root directory given by `-chroot`, once the process changed to it.
+static CHROOT_DIR: OnceCell<String> = OnceCell::new();
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// User and groups given by `-runas user[:group]`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunAs {
+    /// Name of the user, or its uid if it has no passwd entry.
+    pub user: String,
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups of the user, including `gid`.
+    pub groups: Vec<u32>,
+}
+
+impl RunAs {
+    /// Resolve `user[:group]`, each one is a name or a numeric id. The primary
+    /// group of the user is used if group is not given.
+    pub fn parse(runas: &str) -> Result<Self> {
+        let (user, group) = match runas.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (runas, None),
+        };
+        if user.is_empty() || group == Some("") {
+            bail!("Invalid runas {}, must be user[:group]", runas);
+        }
+
+        let (name, uid, user_gid) = match find_user(user)? {
+            Some((name, uid, gid)) => (Some(name), uid, Some(gid)),
+            None => match user.parse::<u32>() {
+                Ok(uid) => (None, uid, None),
+                Err(_) => bail!("User {} not found", user),
+            },
+        };
+        if uid == 0 {
+            bail!("Running as root {} doesn't drop privileges", user);
+        }
+        let gid = match group {
+            Some(group) => match find_group(group)? {
+                Some(gid) => gid,
+                None => group
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("Group {} not found", group))?,
+            },
+            None => user_gid.with_context(|| {
+                format!("User {} has no passwd entry, its group must be given", user)
+            })?,
+        };
+        if gid == 0 {
+            bail!("Running as group root doesn't drop privileges");
+        }
+        let groups = match name.as_deref() {
+            Some(name) => user_groups(name, gid)?,
+            None => vec![gid],
+        };
+
+        Ok(RunAs {
+            user: name.unwrap_or_else(|| uid.to_string()),
+            uid,
+            gid,
+            groups,
+        })
+    }
+
+    /// Whether `path` would be writable by the user, judged by its mode bits.
+    pub fn can_write(&self, path: &Path) -> Result<bool> {
+        let meta = std::fs::metadata(path)
+            .with_context(|| format!("Failed to get metadata of {}", path.display()))?;
+        let mode = meta.mode();
+        let writable = if meta.uid() == self.uid {
+            mode & 0o200 != 0
+        } else if self.groups.contains(&meta.gid()) {
+            mode & 0o020 != 0
+        } else {
+            mode & 0o002 != 0
+        };
+        Ok(writable)
+    }
+}
+
+impl fmt::Display for RunAs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({}:{})", self.user, self.uid, self.gid)
+    }
+}
+
+/// Look up the passwd entry of `user`, return its name, uid and gid.
+fn find_user(user: &str) -> Result<Option<(String, u32, u32)>> {
+    // SAFETY: passwd is plain old data, filled by getpwnam_r/getpwuid_r.
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUF_LEN];
+    let mut result = std::ptr::null_mut();
+    let ret = match user.parse::<u32>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let name = CString::new(user).with_context(|| format!("Invalid user {}", user))?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+    if ret != 0 {
+        bail!(
+            "Failed to look up user {}: {}",
+            user,
+            Error::from_raw_os_error(ret)
+        );
+    }
+    if result.is_null() {
+        return Ok(None);
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) }
+        .to_string_lossy()
+        .into_owned();
+    Ok(Some((name, pwd.pw_uid, pwd.pw_gid)))
+}
+
+/// Look up the gid of group named `group`.
+fn find_group(group: &str) -> Result<Option<u32>> {
+    let name = CString::new(group).with_context(|| format!("Invalid group {}", group))?;
+    // SAFETY: group is plain old data, filled by getgrnam_r.
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUF_LEN];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        bail!(
+            "Failed to look up group {}: {}",
+            group,
+            Error::from_raw_os_error(ret)
+        );
+    }
+    if result.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(grp.gr_gid))
+}
+
+/// Supplementary groups of user `name`, whose primary group is replaced by `gid`.
+fn user_groups(name: &str, gid: u32) -> Result<Vec<u32>> {
+    let cname = CString::new(name).with_context(|| format!("Invalid user {}", name))?;
+    let mut groups = vec![0 as libc::gid_t; MAX_GROUPS];
+    let mut count = MAX_GROUPS as libc::c_int;
+    let ret = unsafe { libc::getgrouplist(cname.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+    if ret < 0 {
+        bail!("User {} is in more than {} groups", name, MAX_GROUPS);
+    }
+    groups.truncate(count as usize);
+    Ok(groups)
+}
+
+/// Root directory of the process given by `-chroot`, paths opened after the
+/// privilege drop are resolved inside it.
+pub fn chroot_dir() -> Option<&'static str> {
+    CHROOT_DIR.get().map(String::as_str)
+}
+
+/// Change root directory to `dir` if given, then switch to the user `runas` if
+/// given, and verify that no capability remains.
+pub fn drop_privileges(dir: Option<&str>, runas: Option<&RunAs>) -> Result<()> {
+    if let Some(dir) = dir {
+        change_root(dir)?;
+    }
+    if let Some(runas) = runas {
+        switch_user(runas)?;
+        check_no_capability()
+            .with_context(|| format!("Privileges remain after switching to {}", runas))?;
+    }
+    Ok(())
+}
+
+fn change_root(dir: &str) -> Result<()> {
+    let cdir = CString::new(dir).with_context(|| format!("Invalid chroot {}", dir))?;
+    if unsafe { libc::chroot(cdir.as_ptr()) } != 0 {
+        return Err(Error::last_os_error()).with_context(|| format!("Failed to chroot to {}", dir));
+    }
+    std::env::set_current_dir("/")
+        .with_context(|| format!("Failed to change directory to the root of {}", dir))?;
+    CHROOT_DIR
+        .set(dir.to_string())
+        .map_err(|_| anyhow!("Root directory is changed already"))
+}
+
+fn switch_user(runas: &RunAs) -> Result<()> {
+    // Groups are dropped first, as setting them needs the privileges of root.
+    if unsafe { libc::setgroups(runas.groups.len(), runas.groups.as_ptr()) } != 0 {
+        return Err(Error::last_os_error())
+            .with_context(|| format!("Failed to set groups of {}", runas));
+    }
+    if unsafe { libc::setresgid(runas.gid, runas.gid, runas.gid) } != 0 {
+        return Err(Error::last_os_error())
+            .with_context(|| format!("Failed to set gid {}", runas.gid));
+    }
+    if unsafe { libc::setresuid(runas.uid, runas.uid, runas.uid) } != 0 {
+        return Err(Error::last_os_error())
+            .with_context(|| format!("Failed to set uid {}", runas.uid));
+    }
+    Ok(())
+}
+
+/// Verify that setuid cleared the capabilities and they can't be regained.
+fn check_no_capability() -> Result<()> {
+    let securebits = unsafe { libc::prctl(libc::PR_GET_SECUREBITS) };
+    if securebits < 0 {
+        return Err(Error::last_os_error()).with_context(|| "Failed to get securebits");
+    }
+    if securebits & (SECBIT_KEEP_CAPS | SECBIT_NO_SETUID_FIXUP) != 0 {
+        bail!(
+            "Securebits {:#x} keep capabilities across setuid",
+            securebits
+        );
+    }
+
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data: [CapUserData; 2] = Default::default();
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error()).with_context(|| "Failed to get capabilities");
+    }
+    if data
+        .iter()
+        .any(|set| set.effective != 0 || set.permitted != 0)
+    {
+        bail!("Capabilities are still effective or permitted");
+    }
+
+    if unsafe { libc::setuid(0) } == 0 {
+        bail!("Root is regained by setuid");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_runas() {
+        let runas = RunAs::parse("4242:4343").unwrap();
+        assert_eq!((runas.uid, runas.gid), (4242, 4343));
+        assert_eq!(runas.groups, vec![4343]);
+        assert_eq!(runas.to_string(), "4242(4242:4343)");
+
+        assert!(RunAs::parse("").is_err());
+        assert!(RunAs::parse("4242:").is_err());
+        assert!(RunAs::parse(":4343").is_err());
+        // No passwd entry to take the group from.
+        assert!(RunAs::parse("4242").is_err());
+        assert!(RunAs::parse("root").is_err());
+        assert!(RunAs::parse("0:4343").is_err());
+        assert!(RunAs::parse("4242:0").is_err());
+        assert!(RunAs::parse("televm-no-such-user").is_err());
+        assert!(RunAs::parse("4242:televm-no-such-group").is_err());
+    }
+
+    #[test]
+    fn test_can_write() {
+        let runas = RunAs::parse("4242:4343").unwrap();
+        let path = std::env::temp_dir().join(format!("televm-runas-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        let owner = RunAs {
+            user: "owner".to_string(),
+            uid: meta.uid(),
+            gid: 4343,
+            groups: vec![4343],
+        };
+        let group = RunAs {
+            user: "group".to_string(),
+            uid: 4242,
+            gid: meta.gid(),
+            groups: vec![meta.gid()],
+        };
+
+        for (mode, owner_write, group_write, other_write) in [
+            (0o600, true, false, false),
+            (0o460, false, true, false),
+            (0o402, false, false, true),
+            (0o444, false, false, false),
+        ] {
+            std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(mode))
+                .unwrap();
+            assert_eq!(owner.can_write(&path).unwrap(), owner_write);
+            assert_eq!(group.can_write(&path).unwrap(), group_write);
+            assert_eq!(runas.can_write(&path).unwrap(), other_write);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(runas.can_write(&path).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Seccomp sandbox of the VMM process, enabled by `-sandbox on`.
+//!
+//! Once devices are realized, every thread installs the BPF allowlist of its type,
+//! other syscalls kill the process, or are only logged in debug mode, which helps
+//! building the allowlists. Threads created afterwards inherit the filter of their
+//! creator, so the list of main thread covers the vcpus and workers it creates at
+//! runtime. Block I/O is submitted by the main loop, iothreads and vcpus through
+//! native aio or io_uring, their lists include these syscalls.
+
+use anyhow::{anyhow, bail, Result};
+use libc::{c_long, sock_filter, sock_fprog};
+use once_cell::sync::OnceCell;
+
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: u32 = 0xc000_00f3;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+
+/// Offsets of syscall number and arch in `struct seccomp_data`.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Syscalls of all threads: I/O on fds opened already, memory, signals, time
+/// and the event loop, whose timers may be re-armed by any thread.
+const COMMON_SYSCALLS: &[c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_fallocate,
+    libc::SYS_io_submit,
+    libc::SYS_io_getevents,
+    libc::SYS_io_uring_enter,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_ctl,
+    libc::SYS_recvfrom,
+    libc::SYS_sendto,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmsg,
+    libc::SYS_futex,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_mremap,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_tgkill,
+    libc::SYS_prctl,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_timerfd_settime,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+];
+
+/// Syscalls of activating and resetting devices, which vcpus do on guest accesses.
+const DEVICE_SYSCALLS: &[c_long] = &[
+    libc::SYS_eventfd2,
+    libc::SYS_timerfd_create,
+    libc::SYS_io_setup,
+    libc::SYS_io_destroy,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_register,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+];
+
+/// Syscalls of serving the clients of qmp and chardev sockets.
+const CLIENT_SYSCALLS: &[c_long] = &[
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_ppoll,
+];
+
+/// Syscalls of main thread, where qmp commands are executed. Hotplug and other
+/// runtime features need them deliberately: `openat` for drives, chardevs and
+/// dump, `flock` for drive locks taken on resume and released on pause, sockets
+/// for chardevs, netdevs and migration, `memfd_create` for memory
+/// backends and `clone` for vcpus and workers. `seccomp` lets vcpus created later
+/// add their own filter, which can only be more restrictive.
+const MAIN_SYSCALLS: &[c_long] = &[
+    libc::SYS_openat,
+    libc::SYS_unlinkat,
+    libc::SYS_flock,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_fstatfs,
+    libc::SYS_ftruncate,
+    libc::SYS_memfd_create,
+    libc::SYS_pipe2,
+    libc::SYS_epoll_create1,
+    libc::SYS_socket,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_connect,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_sched_setaffinity,
+    libc::SYS_seccomp,
+    libc::SYS_kill,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    libc::SYS_getcwd,
+];
+
+/// Obsolete syscalls, which are allowed unless `obsolete=deny`.
+const OBSOLETE_SYSCALLS: &[c_long] = &[
+    libc::SYS_remap_file_pages,
+    libc::SYS_lookup_dcookie,
+    #[cfg(not(target_arch = "x86_64"))]
+    libc::SYS_nfsservctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_uselib,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ustat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_sysfs,
+];
+
+/// Action taken on syscalls out of the allowlist.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeccompAction {
+    /// Kill the process.
+    Kill,
+    /// Log the syscall to audit log and allow it.
+    Log,
+}
+
+impl SeccompAction {
+    fn ret(&self) -> u32 {
+        match self {
+            SeccompAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+            SeccompAction::Log => libc::SECCOMP_RET_LOG,
+        }
+    }
+}
+
+/// Syscalls of rotating and reopening the log file, which any thread may do when
+/// it logs.
+const LOG_FILE_SYSCALLS: &[c_long] = &[
+    libc::SYS_openat,
+    libc::SYS_renameat2,
+    #[cfg(target_arch = "aarch64")]
+    libc::SYS_renameat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+];
+
+/// Types of threads, each with its own allowlist.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeccompThread {
+    Main,
+    Vcpu,
+    /// Iothreads and the monitor thread.
+    IoThread,
+}
+
+/// Sandbox of the process.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Sandbox {
+    pub action: SeccompAction,
+    /// Kill obsolete syscalls too.
+    pub deny_obsolete: bool,
+    /// Log goes to a file which may be rotated or reopened.
+    pub log_file: bool,
+}
+
+static SANDBOX: OnceCell<Sandbox> = OnceCell::new();
+
+/// Enable `sandbox`, threads install their filters by `install_thread_filter` from now on.
+pub fn enable_sandbox(sandbox: Sandbox) -> Result<()> {
+    SANDBOX
+        .set(sandbox)
+        .map_err(|_| anyhow!("Sandbox is enabled already"))
+}
+
+/// Whether sandbox is enabled.
+pub fn sandbox_enabled() -> bool {
+    SANDBOX.get().is_some()
+}
+
+/// Syscalls allowed for `thread`, sorted.
+pub fn allowlist(thread: SeccompThread, deny_obsolete: bool) -> Vec<c_long> {
+    let mut syscalls = COMMON_SYSCALLS.to_vec();
+    match thread {
+        // Vcpus and workers created by main thread are restricted to the intersection
+        // of their own list and this one, so it includes the others.
+        SeccompThread::Main => {
+            syscalls.extend(DEVICE_SYSCALLS);
+            syscalls.extend(CLIENT_SYSCALLS);
+            syscalls.extend(MAIN_SYSCALLS);
+        }
+        SeccompThread::Vcpu => syscalls.extend(DEVICE_SYSCALLS),
+        SeccompThread::IoThread => syscalls.extend(CLIENT_SYSCALLS),
+    }
+    if !deny_obsolete {
+        syscalls.extend(OBSOLETE_SYSCALLS);
+    }
+    syscalls.sort_unstable();
+    syscalls.dedup();
+    syscalls
+}
+
+/// Install the filter of `thread` to the calling thread, nothing is done unless
+/// sandbox is enabled.
+pub fn install_thread_filter(thread: SeccompThread) -> Result<()> {
+    if let Some(sandbox) = SANDBOX.get() {
+        let mut filter =
+            SyscallFilter::new(sandbox.action).allow(&allowlist(thread, sandbox.deny_obsolete));
+        if sandbox.log_file {
+            filter = filter.allow(LOG_FILE_SYSCALLS);
+        }
+        filter.install()?;
+        info!("Seccomp filter of {:?} thread is installed", thread);
+    }
+    Ok(())
+}
+
+/// BPF filter allowing the syscalls in the list, others are handled by `action`.
+pub struct SyscallFilter {
+    action: SeccompAction,
+    syscalls: Vec<c_long>,
+}
+
+impl SyscallFilter {
+    pub fn new(action: SeccompAction) -> Self {
+        SyscallFilter {
+            action,
+            syscalls: Vec::new(),
+        }
+    }
+
+    pub fn allow(mut self, syscalls: &[c_long]) -> Self {
+        self.syscalls.extend(syscalls);
+        self
+    }
+
+    /// Build the BPF program. Syscalls of other archs always kill the process, as
+    /// their numbers mean different syscalls.
+    fn build(&self) -> Vec<sock_filter> {
+        let mut program = vec![
+            bpf_stmt(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                SECCOMP_DATA_ARCH,
+            ),
+            bpf_jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        ];
+        for syscall in &self.syscalls {
+            program.push(bpf_jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *syscall as u32,
+                0,
+                1,
+            ));
+            program.push(bpf_stmt(
+                libc::BPF_RET | libc::BPF_K,
+                libc::SECCOMP_RET_ALLOW,
+            ));
+        }
+        program.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, self.action.ret()));
+        program
+    }
+
+    /// Install the filter to the calling thread, which can't be removed.
+    pub fn install(&self) -> Result<()> {
+        load_program(&self.build())
+    }
+}
+
+fn bpf_stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Load BPF `program` as seccomp filter of the calling thread. It doesn't allocate,
+/// so that it's safe in the child of fork.
+fn load_program(program: &[sock_filter]) -> Result<()> {
+    if program.len() > u16::MAX as usize {
+        bail!(
+            "Seccomp filter of {} instructions is too long",
+            program.len()
+        );
+    }
+    let prog = sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut sock_filter,
+    };
+    // Safe because it only sets the no_new_privs attribute of the calling thread,
+    // which is required by seccomp filter of unprivileged process.
+    let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    if ret != 0 {
+        bail!(
+            "Failed to set no_new_privs: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // Safe because `prog` points to `program` which outlives the syscall, and the
+    // kernel copies the filter.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            0,
+            &prog as *const sock_fprog,
+        )
+    };
+    if ret != 0 {
+        bail!(
+            "Failed to install seccomp filter: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let main = allowlist(SeccompThread::Main, false);
+        let vcpu = allowlist(SeccompThread::Vcpu, false);
+        let iothread = allowlist(SeccompThread::IoThread, false);
+        // Vcpus inherit the filter of main thread.
+        assert!(vcpu.iter().all(|syscall| main.contains(syscall)));
+        assert!(iothread.iter().all(|syscall| main.contains(syscall)));
+        assert!(main.contains(&libc::SYS_openat));
+        assert!(main.contains(&libc::SYS_clone));
+        assert!(!vcpu.contains(&libc::SYS_openat));
+        assert!(!iothread.contains(&libc::SYS_clone));
+        assert!(vcpu.contains(&libc::SYS_ioctl));
+        assert!(iothread.contains(&libc::SYS_accept4));
+        assert!(iothread.contains(&libc::SYS_timerfd_settime));
+
+        let deny_obsolete = allowlist(SeccompThread::Main, true);
+        assert!(main.contains(&libc::SYS_remap_file_pages));
+        assert!(!deny_obsolete.contains(&libc::SYS_remap_file_pages));
+        assert_eq!(deny_obsolete.len(), main.len() - OBSOLETE_SYSCALLS.len());
+        assert!(main.contains(&libc::SYS_seccomp));
+        assert!(!vcpu.contains(&libc::SYS_seccomp));
+        // Never allowed.
+        for list in [&main, &vcpu, &iothread] {
+            assert!(!list.contains(&libc::SYS_execve));
+            assert!(!list.contains(&libc::SYS_ptrace));
+        }
+    }
+
+    #[test]
+    fn test_build_filter() {
+        let filter =
+            SyscallFilter::new(SeccompAction::Log).allow(&[libc::SYS_read, libc::SYS_write]);
+        let program = filter.build();
+        assert_eq!(program.len(), 4 + 2 * 2 + 1);
+        assert_eq!(program[1].k, AUDIT_ARCH);
+        assert_eq!(program[2].k, libc::SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(program[4].k, libc::SYS_read as u32);
+        assert_eq!((program[4].jt, program[4].jf), (0, 1));
+        assert_eq!(program[5].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(program[6].k, libc::SYS_write as u32);
+        assert_eq!(program[8].k, libc::SECCOMP_RET_LOG);
+    }
+
+    /// Run a worker process, which installs the filter without `getppid` and calls it,
+    /// return the signal it's killed by, or 0 if it exits normally.
+    fn run_filtered_worker(action: SeccompAction) -> i32 {
+        // Program is built before fork, as the child of a multi-threaded process
+        // mustn't allocate.
+        let program = SyscallFilter::new(action)
+            .allow(&[libc::SYS_exit, libc::SYS_exit_group, libc::SYS_rt_sigreturn])
+            .build();
+        // Safe because the child only makes syscalls before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            if load_program(&program).is_err() {
+                // Safe because it only exits the child.
+                unsafe { libc::_exit(2) };
+            }
+            // Safe because it's a syscall without arguments.
+            unsafe { libc::syscall(libc::SYS_getppid) };
+            // Safe because it only exits the child.
+            unsafe { libc::_exit(0) };
+        }
+
+        let mut status = 0;
+        // Safe because it only waits for the child.
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        if libc::WIFSIGNALED(status) {
+            return libc::WTERMSIG(status);
+        }
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+        0
+    }
+
+    #[test]
+    fn test_disallowed_syscall() {
+        // Enforce mode kills the worker.
+        assert_eq!(run_filtered_worker(SeccompAction::Kill), libc::SIGSYS);
+        // Debug mode only logs it.
+        assert_eq!(run_filtered_worker(SeccompAction::Log), 0);
+    }
+}
@@ -53,3 +53,88 @@ pub fn mbind(
 
     Ok(())
 }
+
+/// Whether host cpu `cpu` exists, no matter it's online or not.
+pub fn host_cpu_exists(cpu: usize) -> bool {
+    std::path::Path::new(&format!("/sys/devices/system/cpu/cpu{}", cpu)).exists()
+}
+
+/// Pin `thread` to host cpu `cpu`.
+///
+/// * Arguments
+///
+/// * `thread` - The pthread to be pinned.
+/// * `cpu` - Index of host cpu.
+pub fn set_thread_affinity(thread: libc::pthread_t, cpu: usize) -> Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize || !host_cpu_exists(cpu) {
+        bail!("Host cpu {} doesn't exist", cpu);
+    }
+
+    // SAFETY: cpu_set_t is a plain bitmap.
+    let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `cpu` is checked to be in range of `cpu_set`.
+    unsafe { libc::CPU_SET(cpu, &mut cpu_set) };
+    // SAFETY: The size passed is the one of `cpu_set`, which is only read.
+    let ret = unsafe {
+        libc::pthread_setaffinity_np(thread, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set)
+    };
+    if ret != 0 {
+        bail!(
+            "Failed to pin thread to host cpu {}, error is {}",
+            cpu,
+            std::io::Error::from_raw_os_error(ret)
+        );
+    }
+
+    Ok(())
+}
+
+/// Get host cpus which thread `tid` is allowed to run on.
+pub fn get_thread_affinity(tid: libc::pid_t) -> Result<Vec<usize>> {
+    // SAFETY: cpu_set_t is a plain bitmap.
+    let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: The size passed is the one of `cpu_set`, which is written by kernel.
+    let ret = unsafe {
+        libc::sched_getaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &mut cpu_set)
+    };
+    if ret < 0 {
+        bail!(
+            "Failed to get affinity of thread {}, error is {}",
+            tid,
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok((0..libc::CPU_SETSIZE as usize)
+        // SAFETY: `cpu` is in range of `cpu_set`.
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &cpu_set) })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_thread_affinity() {
+        let allowed = get_thread_affinity(0).unwrap();
+        assert!(!allowed.is_empty());
+        assert!(host_cpu_exists(allowed[0]));
+
+        let cpu = *allowed.last().unwrap();
+        let handle = std::thread::spawn(move || {
+            // SAFETY: gettid has no side effect.
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+            // SAFETY: pthread_self has no side effect.
+            set_thread_affinity(unsafe { libc::pthread_self() }, cpu).unwrap();
+            get_thread_affinity(tid).unwrap()
+        });
+        assert_eq!(handle.join().unwrap(), vec![cpu]);
+
+        // SAFETY: pthread_self has no side effect.
+        assert!(
+            set_thread_affinity(unsafe { libc::pthread_self() }, libc::CPU_SETSIZE as usize)
+                .is_err()
+        );
+    }
+}
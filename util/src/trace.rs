@@ -126,6 +126,26 @@ pub fn enable_trace_events(file: &str) -> Result<()> {
     }
 }
 
+pub fn set_trace_event_enabled(event: &str, enabled: bool) {
+    let mut trace_events = TRACE_EVENTS.load().deref().deref().clone();
+    if enabled {
+        trace_events.insert(event.to_string());
+    } else {
+        trace_events.remove(event);
+    }
+    TRACE_EVENTS.store(Arc::new(trace_events));
+}
+
+/// Get the enabled trace events starting with `prefix`.
+pub fn trace_events_with_prefix(prefix: &str) -> Vec<String> {
+    TRACE_EVENTS
+        .load()
+        .iter()
+        .filter(|event| event.starts_with(prefix))
+        .cloned()
+        .collect()
+}
+
 pub fn is_trace_event_enabled(event: &str) -> bool {
     if TRACE_EVENTS.load().is_empty() {
         return false;
@@ -64,6 +64,9 @@ pub fn parse_unix_uri(uri: &str) -> Result<String> {
     }
 }
 
+/// Magic number of hugetlbfs in `statfs`.
+pub const HUGETLBFS_MAGIC: i64 = 0x9584_58f6;
+
 /// Call libc::mmap to allocate memory or map disk file.
 ///
 /// # Arguments
@@ -74,6 +77,7 @@ pub fn parse_unix_uri(uri: &str) -> Result<String> {
 /// * `read_only` - Allow to write or not.
 /// * `is_share` - Share the mapping or not.
 /// * `dump_guest_core` - Exclude from a core dump or not.
+/// * `reserve` - Reserve swap space for the mapping or not, `MAP_NORESERVE` is used if not.
 ///
 /// # Errors
 ///
@@ -85,6 +89,7 @@ pub fn do_mmap(
     read_only: bool,
     is_share: bool,
     dump_guest_core: bool,
+    reserve: bool,
 ) -> Result<u64> {
     let mut flags: i32 = 0;
     let mut fd: i32 = -1;
@@ -99,6 +104,9 @@ pub fn do_mmap(
     } else {
         flags |= libc::MAP_PRIVATE;
     }
+    if !reserve {
+        flags |= libc::MAP_NORESERVE;
+    }
 
     let mut prot = libc::PROT_READ;
     if !read_only {
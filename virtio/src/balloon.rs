@@ -0,0 +1,485 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::{cmp, mem};
+
+use super::{
+    iov_to_buf, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioTrace,
+    VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BALLOON,
+};
+use crate::VirtioError;
+use address_space::{AddressSpace, GuestAddress};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, warn};
+use machine_manager::{
+    config::{BalloonConfig, DEFAULT_VIRTQUEUE_SIZE},
+    event,
+    event_loop::{register_event_helper, unregister_event_helper},
+    qmp::{qmp_schema::BalloonInfo, QmpChannel},
+};
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
+use migration_derive::{ByteCode, Desc};
+use util::byte_code::ByteCode;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::num_ops::read_u32;
+use util::offset_of;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Number of virtqueues, the inflate queue and the deflate queue.
+const QUEUE_NUM_BALLOON: usize = 2;
+/// Balloon pages are always 4KiB, whatever the page size of guest is.
+const BALLOON_PFN_SHIFT: u32 = 12;
+const BALLOON_PAGE_SIZE: u64 = 1 << BALLOON_PFN_SHIFT;
+/// Memory size guest keeps at least when the balloon is inflated.
+pub const BALLOON_MIN_GUEST_MEM: u64 = 64 << 20;
+/// `BALLOON_CHANGE` is reported when the actual size changes by more than it.
+const BALLOON_EVENT_THRESHOLD: u64 = 1 << 20;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioBalloonConfig {
+    /// Number of pages host wants guest to give up.
+    num_pages: u32,
+    /// Number of pages guest has given up.
+    actual: u32,
+}
+
+impl ByteCode for VirtioBalloonConfig {}
+
+struct BalloonIoHandler {
+    inflate_queue: Arc<Mutex<Queue>>,
+    inflate_evt: Arc<EventFd>,
+    deflate_queue: Arc<Mutex<Queue>>,
+    deflate_evt: Arc<EventFd>,
+    mem_space: Arc<AddressSpace>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    driver_features: u64,
+}
+
+impl BalloonIoHandler {
+    /// Handle the page frame numbers guest puts in the inflate or deflate queue.
+    /// Inflated pages are released to host, deflated ones are faulted in again
+    /// when guest touches them, so nothing is done for them.
+    fn process_queue(&mut self, inflate: bool) -> Result<()> {
+        self.trace_request(
+            "Balloon".to_string(),
+            if inflate { "inflate" } else { "deflate" }.to_string(),
+        );
+        let queue = if inflate {
+            &self.inflate_queue
+        } else {
+            &self.deflate_queue
+        };
+        let mut locked_queue = queue.lock().unwrap();
+        loop {
+            let elem = locked_queue
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)
+                .with_context(|| "Failed to pop avail ring for balloon")?;
+            if elem.desc_num == 0 {
+                break;
+            }
+            if inflate {
+                let len = elem.out_iovec.iter().map(|iov| iov.len as usize).sum();
+                let mut pfns = vec![0_u8; len];
+                iov_to_buf(&self.mem_space, &elem.out_iovec, &mut pfns)?;
+                for pfn in pfns.chunks_exact(mem::size_of::<u32>()) {
+                    let pfn = u32::from_le_bytes([pfn[0], pfn[1], pfn[2], pfn[3]]) as u64;
+                    let addr = GuestAddress(pfn << BALLOON_PFN_SHIFT);
+                    // Page stays backed if it can't be discarded, e.g. on huge pages.
+                    if let Err(ref e) = self.mem_space.discard_range(addr, BALLOON_PAGE_SIZE) {
+                        warn!("Failed to release balloon page {:X}: {:?}", addr.0, e);
+                    }
+                }
+            }
+            locked_queue
+                .vring
+                .add_used(&self.mem_space, elem.index, 0)
+                .with_context(|| {
+                    format!("Failed to add used ring for balloon, index {}", elem.index)
+                })?;
+        }
+
+        (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue), false).with_context(
+            || {
+                anyhow!(VirtioError::InterruptTrigger(
+                    "balloon",
+                    VirtioInterruptType::Vring
+                ))
+            },
+        )?;
+        self.trace_send_interrupt("Balloon".to_string());
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for BalloonIoHandler {
+    fn internal_notifiers(balloon_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_handler = balloon_handler.lock().unwrap();
+        let queues = [
+            (locked_handler.inflate_evt.as_raw_fd(), true),
+            (locked_handler.deflate_evt.as_raw_fd(), false),
+        ];
+        for (queue_fd, inflate) in queues {
+            let cloned_handler = balloon_handler.clone();
+            let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                if let Err(ref e) = cloned_handler.lock().unwrap().process_queue(inflate) {
+                    error!("Failed to process balloon queue: {:?}", e);
+                }
+                None
+            });
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::AddShared,
+                queue_fd,
+                None,
+                EventSet::IN,
+                vec![handler],
+            ));
+        }
+        notifiers
+    }
+}
+
+/// Status of balloon device.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct VirtioBalloonState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Virtio Balloon config space.
+    config_space: VirtioBalloonConfig,
+}
+
+/// Virtio balloon device structure.
+pub struct Balloon {
+    /// Configuration of the balloon device.
+    balloon_cfg: BalloonConfig,
+    /// Status of balloon device.
+    state: VirtioBalloonState,
+    /// Memory size of guest configured by `-m`.
+    ram_size: u64,
+    /// Actual memory size reported last by `BALLOON_CHANGE`.
+    reported_actual: u64,
+    /// Callback to notify guest of new target, set when the device is activated.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// EventFd for device deactivate.
+    deactivate_evts: Vec<RawFd>,
+}
+
+impl Balloon {
+    /// Create a virtio-balloon device.
+    ///
+    /// # Arguments
+    ///
+    /// * `balloon_cfg` - Device configuration set by user.
+    /// * `ram_size` - Memory size of guest.
+    pub fn new(balloon_cfg: BalloonConfig, ram_size: u64) -> Self {
+        Balloon {
+            balloon_cfg,
+            state: VirtioBalloonState {
+                device_features: 0_u64,
+                driver_features: 0_u64,
+                config_space: VirtioBalloonConfig::default(),
+            },
+            ram_size,
+            reported_actual: ram_size,
+            interrupt_cb: None,
+            deactivate_evts: Vec::new(),
+        }
+    }
+
+    /// Memory size guest is asked to keep.
+    pub fn target_size(&self) -> u64 {
+        self.ram_size - ((self.state.config_space.num_pages as u64) << BALLOON_PFN_SHIFT)
+    }
+
+    /// Memory size guest keeps after giving pages to the balloon.
+    pub fn actual_size(&self) -> u64 {
+        self.ram_size
+            .saturating_sub((self.state.config_space.actual as u64) << BALLOON_PFN_SHIFT)
+    }
+
+    /// Ask guest to inflate or deflate the balloon to keep `size` bytes of memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Target memory size of guest.
+    pub fn set_target_size(&mut self, size: u64) -> Result<()> {
+        if size < BALLOON_MIN_GUEST_MEM {
+            bail!(
+                "Balloon target {} is below the minimum guest memory {}",
+                size,
+                BALLOON_MIN_GUEST_MEM
+            );
+        }
+        if size > self.ram_size {
+            bail!(
+                "Balloon target {} exceeds the guest memory {}",
+                size,
+                self.ram_size
+            );
+        }
+        self.state.config_space.num_pages = ((self.ram_size - size) >> BALLOON_PFN_SHIFT) as u32;
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb(&VirtioInterruptType::Config, None, false).with_context(|| {
+                anyhow!(VirtioError::InterruptTrigger(
+                    "balloon",
+                    VirtioInterruptType::Config
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn report_actual_size(&mut self) {
+        let actual = self.actual_size();
+        let diff = cmp::max(actual, self.reported_actual) - cmp::min(actual, self.reported_actual);
+        if diff > BALLOON_EVENT_THRESHOLD {
+            self.reported_actual = actual;
+            let balloon_info = BalloonInfo {
+                actual,
+                target: None,
+            };
+            event!(BalloonChanged; balloon_info);
+        }
+    }
+}
+
+impl VirtioDevice for Balloon {
+    /// Realize virtio balloon device.
+    fn realize(&mut self) -> Result<()> {
+        self.state.device_features = 1_u64 << VIRTIO_F_VERSION_1;
+        if self.balloon_cfg.deflate_on_oom {
+            self.state.device_features |= 1_u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_BALLOON
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_BALLOON
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        DEFAULT_VIRTQUEUE_SIZE
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.state.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        self.state.driver_features = self.checked_driver_features(page, value);
+    }
+
+    /// Get driver features by guest.
+    fn get_driver_features(&self, features_select: u32) -> u32 {
+        read_u32(self.state.driver_features, features_select)
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
+        let config_slice = self.state.config_space.as_bytes();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            return Err(anyhow!(VirtioError::DevConfigOverflow(offset, config_len)));
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let config_len = mem::size_of::<VirtioBalloonConfig>() as u64;
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= config_len)
+            .ok_or_else(|| anyhow!(VirtioError::DevConfigOverflow(offset, config_len)))?;
+        // The only writable field is "actual".
+        if offset < offset_of!(VirtioBalloonConfig, actual) as u64 {
+            bail!("Field num_pages of balloon config is read-only");
+        }
+
+        let mut config_space = self.state.config_space;
+        config_space.as_mut_bytes()[offset as usize..end as usize].copy_from_slice(data);
+        self.state.config_space.actual = config_space.actual;
+        self.report_actual_size();
+        Ok(())
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: Vec<Arc<EventFd>>,
+    ) -> Result<()> {
+        if queues.len() != QUEUE_NUM_BALLOON || queue_evts.len() != QUEUE_NUM_BALLOON {
+            return Err(anyhow!(VirtioError::IncorrectQueueNum(
+                QUEUE_NUM_BALLOON,
+                queues.len()
+            )));
+        }
+        let handler = BalloonIoHandler {
+            inflate_queue: queues[0].clone(),
+            inflate_evt: queue_evts[0].clone(),
+            deflate_queue: queues[1].clone(),
+            deflate_evt: queue_evts[1].clone(),
+            mem_space,
+            interrupt_cb: interrupt_cb.clone(),
+            driver_features: self.state.driver_features,
+        };
+
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
+        self.interrupt_cb = Some(interrupt_cb);
+        Ok(())
+    }
+
+    fn deactivate(&mut self) -> Result<()> {
+        self.interrupt_cb = None;
+        unregister_event_helper(None, &mut self.deactivate_evts)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // Guest gets all of its memory back after reset.
+        self.state.config_space.actual = 0;
+        self.report_actual_size();
+        Ok(())
+    }
+}
+
+impl StateTransfer for Balloon {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        Ok(self.state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::Result<()> {
+        self.state = *VirtioBalloonState::from_bytes(state)
+            .ok_or_else(|| anyhow!(migration::error::MigrationError::FromBytesError("BALLOON")))?;
+        self.reported_actual = self.actual_size();
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) =
+            MigrationManager::get_desc_alias(&VirtioBalloonState::descriptor().name)
+        {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for Balloon {}
+
+impl VirtioTrace for BalloonIoHandler {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM_SIZE: u64 = 1 << 30;
+
+    #[test]
+    fn test_balloon_target() {
+        let mut balloon = Balloon::new(BalloonConfig::default(), RAM_SIZE);
+        assert_eq!(balloon.target_size(), RAM_SIZE);
+        assert_eq!(balloon.actual_size(), RAM_SIZE);
+
+        assert!(balloon.set_target_size(BALLOON_MIN_GUEST_MEM - 1).is_err());
+        assert!(balloon.set_target_size(RAM_SIZE + 1).is_err());
+        assert_eq!(balloon.target_size(), RAM_SIZE);
+
+        balloon.set_target_size(RAM_SIZE / 2).unwrap();
+        assert_eq!(balloon.target_size(), RAM_SIZE / 2);
+        let mut num_pages = [0_u8; 4];
+        balloon.read_config(0, &mut num_pages).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(num_pages),
+            (RAM_SIZE / 2 / BALLOON_PAGE_SIZE) as u32
+        );
+        // Guest can't change the target.
+        assert!(balloon.write_config(0, &[0_u8; 4]).is_err());
+    }
+
+    #[test]
+    fn test_balloon_actual() {
+        QmpChannel::object_init();
+        let mut balloon = Balloon::new(BalloonConfig::default(), RAM_SIZE);
+        balloon.realize().unwrap();
+        assert_eq!(
+            balloon.get_device_features(1),
+            (1_u64 << (VIRTIO_F_VERSION_1 - 32)) as u32
+        );
+
+        // Changes within the threshold are not reported.
+        let pages = (BALLOON_EVENT_THRESHOLD / BALLOON_PAGE_SIZE) as u32;
+        balloon.write_config(4, &pages.to_le_bytes()).unwrap();
+        assert_eq!(balloon.actual_size(), RAM_SIZE - BALLOON_EVENT_THRESHOLD);
+        assert_eq!(balloon.reported_actual, RAM_SIZE);
+
+        balloon.write_config(4, &(pages * 2).to_le_bytes()).unwrap();
+        assert_eq!(
+            balloon.reported_actual,
+            RAM_SIZE - 2 * BALLOON_EVENT_THRESHOLD
+        );
+
+        balloon.reset().unwrap();
+        assert_eq!(balloon.actual_size(), RAM_SIZE);
+        assert_eq!(balloon.reported_actual, RAM_SIZE);
+        assert!(balloon.write_config(6, &[0_u8; 4]).is_err());
+    }
+
+    #[test]
+    fn test_balloon_deflate_on_oom() {
+        let balloon_cfg = BalloonConfig {
+            id: "balloon0".to_string(),
+            deflate_on_oom: true,
+        };
+        let mut balloon = Balloon::new(balloon_cfg, RAM_SIZE);
+        balloon.realize().unwrap();
+        assert_ne!(
+            balloon.get_device_features(0) & (1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM),
+            0
+        );
+    }
+}
@@ -23,7 +23,7 @@ use std::io::{Seek, SeekFrom, Write};
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
@@ -43,6 +43,7 @@ use byteorder::{ByteOrder, LittleEndian};
 use log::{error, warn};
 use machine_manager::config::{BlkDevConfig, ConfigCheck, DriveFile, VmConfig};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper, EventLoop};
+use machine_manager::qmp::qmp_schema::BlockDeviceStats;
 use migration::{
     migration::Migratable, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
     StateTransfer,
@@ -84,6 +85,95 @@ type SenderConfig = (
     AioEngine,
 );
 
+/// Statistics of requests completed by a block device, shared by its queues.
+/// The counters are independent of each other, so relaxed ordering is enough.
+#[derive(Default)]
+pub struct BlockStats {
+    rd_bytes: AtomicU64,
+    wr_bytes: AtomicU64,
+    rd_operations: AtomicU64,
+    wr_operations: AtomicU64,
+    flush_operations: AtomicU64,
+    rd_total_time_ns: AtomicU64,
+    wr_total_time_ns: AtomicU64,
+    flush_total_time_ns: AtomicU64,
+    failed_rd_operations: AtomicU64,
+    failed_wr_operations: AtomicU64,
+    failed_flush_operations: AtomicU64,
+}
+
+impl BlockStats {
+    /// Account a completed request, `latency_ns` is counted since it's popped
+    /// from the virtqueue. Requests other than read, write and flush are ignored.
+    fn account(&self, request_type: u32, bytes: u64, latency_ns: u64, status: u8) {
+        let (ops, data, time, failed) = match request_type {
+            VIRTIO_BLK_T_IN => (
+                &self.rd_operations,
+                Some(&self.rd_bytes),
+                &self.rd_total_time_ns,
+                &self.failed_rd_operations,
+            ),
+            VIRTIO_BLK_T_OUT => (
+                &self.wr_operations,
+                Some(&self.wr_bytes),
+                &self.wr_total_time_ns,
+                &self.failed_wr_operations,
+            ),
+            VIRTIO_BLK_T_FLUSH => (
+                &self.flush_operations,
+                None,
+                &self.flush_total_time_ns,
+                &self.failed_flush_operations,
+            ),
+            _ => return,
+        };
+        if status != VIRTIO_BLK_S_OK {
+            failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        ops.fetch_add(1, Ordering::Relaxed);
+        if let Some(data) = data {
+            data.fetch_add(bytes, Ordering::Relaxed);
+        }
+        time.fetch_add(latency_ns, Ordering::Relaxed);
+    }
+
+    /// Get the current value of all counters.
+    pub fn snapshot(&self) -> BlockDeviceStats {
+        BlockDeviceStats {
+            rd_bytes: self.rd_bytes.load(Ordering::Relaxed),
+            wr_bytes: self.wr_bytes.load(Ordering::Relaxed),
+            rd_operations: self.rd_operations.load(Ordering::Relaxed),
+            wr_operations: self.wr_operations.load(Ordering::Relaxed),
+            flush_operations: self.flush_operations.load(Ordering::Relaxed),
+            rd_total_time_ns: self.rd_total_time_ns.load(Ordering::Relaxed),
+            wr_total_time_ns: self.wr_total_time_ns.load(Ordering::Relaxed),
+            flush_total_time_ns: self.flush_total_time_ns.load(Ordering::Relaxed),
+            failed_rd_operations: self.failed_rd_operations.load(Ordering::Relaxed),
+            failed_wr_operations: self.failed_wr_operations.load(Ordering::Relaxed),
+            failed_flush_operations: self.failed_flush_operations.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in [
+            &self.rd_bytes,
+            &self.wr_bytes,
+            &self.rd_operations,
+            &self.wr_operations,
+            &self.flush_operations,
+            &self.rd_total_time_ns,
+            &self.wr_total_time_ns,
+            &self.flush_total_time_ns,
+            &self.failed_rd_operations,
+            &self.failed_wr_operations,
+            &self.failed_flush_operations,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
     let mut id_bytes = vec![0; VIRTIO_BLK_ID_BYTES as usize];
     let bytes_to_copy = cmp::min(serial_num.len(), VIRTIO_BLK_ID_BYTES as usize);
@@ -111,28 +201,31 @@ pub struct AioCompleteCb {
     req: Rc<Request>,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
+    /// Statistics of the block device.
+    stats: Arc<BlockStats>,
 }
 
 impl AioCompleteCb {
-    fn new(
-        queue: Arc<Mutex<Queue>>,
-        mem_space: Arc<AddressSpace>,
-        req: Rc<Request>,
-        interrupt_cb: Arc<VirtioInterrupt>,
-        driver_features: u64,
-    ) -> Self {
+    fn new(handler: &BlockIoHandler, req: Rc<Request>) -> Self {
         AioCompleteCb {
-            queue,
-            mem_space,
+            queue: handler.queue.clone(),
+            mem_space: handler.mem_space.clone(),
             req,
-            interrupt_cb,
-            driver_features,
+            interrupt_cb: handler.interrupt_cb.clone(),
+            driver_features: handler.driver_features,
+            stats: handler.stats.clone(),
         }
     }
 
     fn complete_request(&self, status: u8) -> Result<()> {
         let mut req = Some(self.req.as_ref());
         while let Some(req_raw) = req {
+            self.stats.account(
+                req_raw.out_header.request_type,
+                req_raw.data_len,
+                req_raw.start.elapsed().as_nanos() as u64,
+                status,
+            );
             self.complete_one_request(req_raw, status)?;
             req = req_raw.next.as_ref().as_ref();
         }
@@ -180,6 +273,8 @@ struct Request {
     data_len: u64,
     in_len: u32,
     in_header: GuestAddress,
+    /// When the request is popped from the virtqueue.
+    start: Instant,
     /// Point to the next merged Request.
     next: Box<Option<Request>>,
 }
@@ -227,6 +322,7 @@ impl Request {
             data_len: 0,
             in_len: 0,
             in_header,
+            start: Instant::now(),
             next: Box::new(None),
         };
 
@@ -251,17 +347,13 @@ impl Request {
                     bail!("Empty data for block request");
                 }
                 for elem_iov in data_iovec.unwrap() {
-                    if let Some(hva) = handler.mem_space.get_host_address(elem_iov.addr) {
-                        let iov = Iovec {
-                            iov_base: hva,
-                            iov_len: u64::from(elem_iov.len),
-                        };
-                        request.iovec.push(iov);
-                        // Note: elem_iov total len is no more than 1<<32.
-                        request.data_len += u64::from(elem_iov.len);
-                    } else {
-                        bail!("Map desc base {:?} failed", elem_iov.addr);
-                    }
+                    let iovec = handler
+                        .mem_space
+                        .get_host_iovec(elem_iov.addr, u64::from(elem_iov.len))
+                        .with_context(|| format!("Map desc base {:?} failed", elem_iov.addr))?;
+                    request.iovec.extend(iovec);
+                    // Note: elem_iov total len is no more than 1<<32.
+                    request.data_len += u64::from(elem_iov.len);
                 }
             }
             VIRTIO_BLK_T_FLUSH => (),
@@ -411,6 +503,8 @@ struct BlockIoHandler {
     iothread: Option<String>,
     /// Using the leak bucket to implement IO limits
     leak_bucket: Option<LeakBucket>,
+    /// Statistics of the block device.
+    stats: Arc<BlockStats>,
 }
 
 impl BlockIoHandler {
@@ -487,13 +581,7 @@ impl BlockIoHandler {
             let mut status = VIRTIO_BLK_S_OK;
             let req = Request::new(self, &mut elem, &mut status)?;
             if status != VIRTIO_BLK_S_OK {
-                let aiocompletecb = AioCompleteCb::new(
-                    self.queue.clone(),
-                    self.mem_space.clone(),
-                    Rc::new(req),
-                    self.interrupt_cb.clone(),
-                    self.driver_features,
-                );
+                let aiocompletecb = AioCompleteCb::new(self, Rc::new(req));
                 // unlock queue, because it will be hold below.
                 drop(queue);
                 aiocompletecb.complete_request(status)?;
@@ -514,13 +602,7 @@ impl BlockIoHandler {
         let merge_req_queue = self.merge_req_queue(req_queue);
         for req in merge_req_queue.into_iter() {
             let req_rc = Rc::new(req);
-            let aiocompletecb = AioCompleteCb::new(
-                self.queue.clone(),
-                self.mem_space.clone(),
-                req_rc.clone(),
-                self.interrupt_cb.clone(),
-                self.driver_features,
-            );
+            let aiocompletecb = AioCompleteCb::new(self, req_rc.clone());
             if let Some(disk_img) = self.disk_image.as_ref() {
                 let aiocb = AioCb {
                     direct: self.direct,
@@ -941,6 +1023,8 @@ pub struct Block {
     broken: Arc<AtomicBool>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// Statistics of requests, kept over stop/cont and cleared by reset.
+    stats: Arc<BlockStats>,
 }
 
 impl Block {
@@ -961,7 +1045,76 @@ impl Block {
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             drive_files,
+            stats: Arc::new(BlockStats::default()),
+        }
+    }
+
+    /// Get the configuration of the block device.
+    pub fn config(&self) -> &BlkDevConfig {
+        &self.blk_cfg
+    }
+
+    /// Get the size of the image file in bytes.
+    pub fn disk_size(&self) -> u64 {
+        self.disk_sectors << SECTOR_SHIFT
+    }
+
+    /// Get the statistics of requests.
+    pub fn stats(&self) -> Arc<BlockStats> {
+        self.stats.clone()
+    }
+
+    /// Resize the image file to `size` bytes, and let the driver re-read the capacity.
+    /// Shrinking is refused unless `force` is set.
+    pub fn resize(&mut self, size: u64, force: bool) -> Result<()> {
+        let image = match self.disk_image.as_ref() {
+            Some(image) => image.clone(),
+            None => bail!("Block device {} has no image file", self.blk_cfg.id),
+        };
+        if self.blk_cfg.read_only {
+            bail!("Can't resize read-only block device {}", self.blk_cfg.id);
+        }
+        if size == 0 || size % SECTOR_SIZE != 0 {
+            bail!("Size {} is not aligned to {}", size, SECTOR_SIZE);
         }
+        if size < self.disk_size() && !force {
+            bail!(
+                "Shrinking block device {} from {} to {} bytes needs force",
+                self.blk_cfg.id,
+                self.disk_size(),
+                size
+            );
+        }
+
+        image
+            .set_len(size)
+            .with_context(|| format!("Failed to resize {}", self.blk_cfg.path_on_host))?;
+        self.disk_sectors = size >> SECTOR_SHIFT;
+        self.state.config_space.capacity = self.disk_sectors;
+        self.update_handlers()
+    }
+
+    /// Send the image and config to io handlers, which trigger a config change interrupt.
+    fn update_handlers(&self) -> Result<()> {
+        for sender in &self.senders {
+            sender
+                .send((
+                    self.disk_image.clone(),
+                    self.req_align,
+                    self.buf_align,
+                    self.disk_sectors,
+                    self.blk_cfg.serial_num.clone(),
+                    self.blk_cfg.direct,
+                    self.blk_cfg.aio,
+                ))
+                .with_context(|| anyhow!(VirtioError::ChannelSend("image fd".to_string())))?;
+        }
+        for update_evt in &self.update_evts {
+            update_evt
+                .write(1)
+                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
+        }
+        Ok(())
     }
 
     fn build_device_config_space(&mut self) {
@@ -1136,6 +1289,7 @@ impl VirtioDevice for Block {
                     Some(iops) => Some(LeakBucket::new(iops)?),
                     None => None,
                 },
+                stats: self.stats.clone(),
             };
 
             let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
@@ -1159,7 +1313,14 @@ impl VirtioDevice for Block {
         Ok(())
     }
 
+    fn reset(&mut self) -> Result<()> {
+        self.stats.reset();
+        Ok(())
+    }
+
     fn update_config(&mut self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
+        // Another device is filled in, it doesn't inherit the statistics.
+        self.stats.reset();
         if let Some(conf) = dev_config {
             self.blk_cfg = conf
                 .as_any()
@@ -1173,27 +1334,7 @@ impl VirtioDevice for Block {
         }
 
         self.realize()?;
-
-        for sender in &self.senders {
-            sender
-                .send((
-                    self.disk_image.clone(),
-                    self.req_align,
-                    self.buf_align,
-                    self.disk_sectors,
-                    self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
-                    self.blk_cfg.aio,
-                ))
-                .with_context(|| anyhow!(VirtioError::ChannelSend("image fd".to_string())))?;
-        }
-        for update_evt in &self.update_evts {
-            update_evt
-                .write(1)
-                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
-        }
-
-        Ok(())
+        self.update_handlers()
     }
 }
 
@@ -1261,6 +1402,7 @@ mod tests {
                 deactivate_evts: Vec::new(),
                 broken: Arc::new(AtomicBool::new(false)),
                 drive_files: Arc::new(Mutex::new(HashMap::new())),
+                stats: Arc::new(BlockStats::default()),
             }
         }
     }
@@ -1314,6 +1456,7 @@ mod tests {
             &block.blk_cfg.path_on_host,
             block.blk_cfg.read_only,
             block.blk_cfg.direct,
+            false,
         )
         .unwrap();
         assert!(block.realize().is_ok());
@@ -1426,6 +1569,92 @@ mod tests {
         assert_eq!(id_bytes_temp.len(), 20);
     }
 
+    #[test]
+    fn test_block_stats() {
+        let mut block = Block::default();
+        let stats = block.stats();
+
+        // Queues account requests concurrently.
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let stats = stats.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        stats.account(VIRTIO_BLK_T_IN, 4096, 10, VIRTIO_BLK_S_OK);
+                        stats.account(VIRTIO_BLK_T_OUT, 512, 20, VIRTIO_BLK_S_OK);
+                        stats.account(VIRTIO_BLK_T_FLUSH, 0, 30, VIRTIO_BLK_S_OK);
+                        stats.account(VIRTIO_BLK_T_OUT, 512, 20, VIRTIO_BLK_S_IOERR);
+                        stats.account(VIRTIO_BLK_T_GET_ID, 20, 5, VIRTIO_BLK_S_OK);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let snapshot = block.stats().snapshot();
+        assert_eq!(snapshot.rd_operations, 400);
+        assert_eq!(snapshot.rd_bytes, 400 * 4096);
+        assert_eq!(snapshot.rd_total_time_ns, 400 * 10);
+        assert_eq!(snapshot.wr_operations, 400);
+        assert_eq!(snapshot.wr_bytes, 400 * 512);
+        assert_eq!(snapshot.wr_total_time_ns, 400 * 20);
+        assert_eq!(snapshot.flush_operations, 400);
+        assert_eq!(snapshot.flush_total_time_ns, 400 * 30);
+        assert_eq!(snapshot.failed_wr_operations, 400);
+        assert_eq!(snapshot.failed_rd_operations, 0);
+
+        // Device reset clears the statistics.
+        block.reset().unwrap();
+        assert_eq!(block.stats().snapshot(), BlockDeviceStats::default());
+    }
+
+    #[test]
+    fn test_block_resize() {
+        let mut block = Block::default();
+        assert!(block.resize(1 << 20, false).is_err());
+
+        block.blk_cfg.direct = false;
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(1 << 20).unwrap();
+        block.blk_cfg.path_on_host = f.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            block.blk_cfg.read_only,
+            block.blk_cfg.direct,
+            false,
+        )
+        .unwrap();
+        block.realize().unwrap();
+        let (sender, receiver) = channel();
+        let update_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        block.senders.push(sender);
+        block.update_evts.push(update_evt.clone());
+
+        // Grow the image, the io handler gets the new size and raises config interrupt.
+        block.resize(2 << 20, false).unwrap();
+        assert_eq!(f.as_file().metadata().unwrap().len(), 2 << 20);
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity).unwrap();
+        assert_eq!(u64::from_le_bytes(capacity), (2 << 20) >> SECTOR_SHIFT);
+        assert_eq!(receiver.try_recv().unwrap().3, (2 << 20) >> SECTOR_SHIFT);
+        assert_eq!(update_evt.read().unwrap(), 1);
+
+        // Unaligned size and shrinking without force are refused.
+        assert!(block.resize((2 << 20) + 1, false).is_err());
+        assert!(block.resize(1 << 20, false).is_err());
+        assert_eq!(block.disk_size(), 2 << 20);
+        block.resize(1 << 20, true).unwrap();
+        assert_eq!(f.as_file().metadata().unwrap().len(), 1 << 20);
+
+        // Read-only device can't be resized.
+        block.blk_cfg.read_only = true;
+        assert!(block.resize(4 << 20, false).is_err());
+        assert_eq!(block.disk_size(), 1 << 20);
+    }
+
     // Test iothread and qos capability. The function will spawn a thread called 'iothread', then
     // io request will be handled by this thread.
     #[test]
@@ -1435,6 +1664,7 @@ mod tests {
         // spawn io thread
         let io_conf = IothreadConfig {
             id: thread_name.clone(),
+            cpu_affinity: None,
         };
         EventLoop::object_init(&Some(vec![io_conf])).unwrap();
 
@@ -1452,6 +1682,7 @@ mod tests {
             &block.blk_cfg.path_on_host,
             block.blk_cfg.read_only,
             block.blk_cfg.direct,
+            false,
         )
         .unwrap();
 
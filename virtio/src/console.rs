@@ -283,6 +283,11 @@ impl Console {
             chardev: Arc::new(Mutex::new(Chardev::new(console_cfg.chardev))),
         }
     }
+
+    /// Character device for redirection.
+    pub fn chardev(&self) -> Arc<Mutex<Chardev>> {
+        self.chardev.clone()
+    }
 }
 
 impl VirtioDevice for Console {
@@ -27,20 +27,24 @@
 //! - `riscv64`
 
 
+mod balloon;
 pub mod block;
 mod console;
 pub mod error;
 mod net;
+mod p9;
 pub mod vhost;
 mod virtio_mmio;
 mod virtqueue;
 pub use anyhow::Result;
+pub use balloon::{Balloon, VirtioBalloonState};
 pub use block::{Block, BlockState};
 pub use console::{Console, VirtioConsoleState};
 pub use error::VirtioError;
 pub use error::*;
 use log::{error, warn};
 pub use net::*;
+pub use p9::Virtio9p;
 pub use virtqueue::*;
 
 pub use vhost::kernel as VhostKern;
@@ -52,10 +56,10 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
-use anyhow::anyhow;
 use anyhow::bail;
+use anyhow::Context;
 use machine_manager::config::ConfigCheck;
-use util::aio::mem_to_buf;
+use util::aio::{iov_from_buf_direct, iov_to_buf_direct, mem_to_buf};
 use util::num_ops::write_u32;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -71,6 +75,7 @@ pub const VIRTIO_TYPE_CONSOLE: u32 = 3;
 pub const VIRTIO_TYPE_RNG: u32 = 4;
 pub const VIRTIO_TYPE_BALLOON: u32 = 5;
 pub const VIRTIO_TYPE_SCSI: u32 = 8;
+pub const VIRTIO_TYPE_9P: u32 = 9;
 pub const VIRTIO_TYPE_GPU: u32 = 16;
 pub const VIRTIO_TYPE_VSOCK: u32 = 19;
 pub const VIRTIO_TYPE_FS: u32 = 26;
@@ -133,6 +138,10 @@ pub const VIRTIO_NET_F_MQ: u32 = 22;
 pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u32 = 23;
 /// Configuration cols and rows are valid.
 pub const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+/// Guest deflates the balloon when it runs out of memory.
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
+/// Mount tag of the shared directory is in the config space.
+pub const VIRTIO_9P_MOUNT_TAG: u32 = 0;
 /// Maximum size of any single segment is in size_max.
 pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1;
 /// Maximum number of segments in a request is in seg_max.
@@ -454,14 +463,32 @@ pub fn report_virtio_error(
 pub fn iov_to_buf(mem_space: &AddressSpace, iovec: &[ElemIovec], buf: &mut [u8]) -> Result<usize> {
     let mut start: usize = 0;
     let mut end: usize = 0;
-    let mut hva;
 
     for iov in iovec {
         end = cmp::min(start + iov.len as usize, buf.len());
-        hva = mem_space
-            .get_host_address(iov.addr)
-            .ok_or_else(|| anyhow!("Map iov base failed"))?;
-        mem_to_buf(&mut buf[start..end], hva)?;
+        let host_iovec = mem_space
+            .get_host_iovec(iov.addr, (end - start) as u64)
+            .with_context(|| "Map iov base failed")?;
+        iov_to_buf_direct(&host_iovec, &mut buf[start..end])?;
+        if end >= buf.len() {
+            break;
+        }
+        start = end;
+    }
+    Ok(end)
+}
+
+/// Write buf to iovec and return the written number of bytes.
+pub fn iov_from_buf(mem_space: &AddressSpace, iovec: &[ElemIovec], buf: &[u8]) -> Result<usize> {
+    let mut start: usize = 0;
+    let mut end: usize = 0;
+
+    for iov in iovec {
+        end = cmp::min(start + iov.len as usize, buf.len());
+        let host_iovec = mem_space
+            .get_host_iovec(iov.addr, (end - start) as u64)
+            .with_context(|| "Map iov base failed")?;
+        iov_from_buf_direct(&host_iovec, &buf[start..end])?;
         if end >= buf.len() {
             break;
         }
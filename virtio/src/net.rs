@@ -46,7 +46,7 @@ use crate::{
     iov_discard_front, iov_to_buf, mem_to_buf, report_virtio_error, virtio_has_feature, ElemIovec,
     Element, VirtioError,
 };
-use address_space::{AddressSpace, RegionCache};
+use address_space::{AddressSpace, RegionCache, RegionType};
 use anyhow::{anyhow, bail, Context, Result};
 use log::{error, warn};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
@@ -723,17 +723,28 @@ impl NetIoHandler {
     ) -> Result<Vec<libc::iovec>> {
         let mut iovecs = Vec::new();
         for elem_iov in elem_iovecs.iter() {
-            let host_addr = mem_space
-                .get_host_address_from_cache(elem_iov.addr, cache)
-                .unwrap_or(0);
-            if host_addr != 0 {
-                let iovec = libc::iovec {
-                    iov_base: host_addr as *mut libc::c_void,
-                    iov_len: elem_iov.len as libc::size_t,
-                };
-                iovecs.push(iovec);
-            } else {
-                bail!("Failed to get host address for {}", elem_iov.addr.0);
+            let start = elem_iov.addr.raw_value();
+            let len = u64::from(elem_iov.len);
+            let cached = cache.filter(|c| {
+                c.reg_type == RegionType::Ram && start >= c.start && start + len <= c.end
+            });
+            match cached {
+                Some(c) => {
+                    iovecs.push(libc::iovec {
+                        iov_base: (c.host_base + start - c.start) as *mut libc::c_void,
+                        iov_len: len as libc::size_t,
+                    });
+                }
+                // The buffer may straddle multiple regions out of the cached one.
+                None => {
+                    let host_iovec = mem_space
+                        .get_host_iovec(elem_iov.addr, len)
+                        .with_context(|| format!("Failed to get host address for {}", start))?;
+                    iovecs.extend(host_iovec.iter().map(|iov| libc::iovec {
+                        iov_base: iov.iov_base as *mut libc::c_void,
+                        iov_len: iov.iov_len as libc::size_t,
+                    }));
+                }
             }
         }
         Ok(iovecs)
@@ -0,0 +1,330 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Virtio-9p shares a host directory with guest by 9P2000.L over a single
+//! virtqueue, each request and its reply in one descriptor chain.
+
+mod protocol;
+mod server;
+
+use std::cmp;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use super::{
+    iov_from_buf, iov_to_buf, Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
+    VirtioTrace, VIRTIO_9P_MOUNT_TAG, VIRTIO_F_VERSION_1, VIRTIO_TYPE_9P,
+};
+use crate::VirtioError;
+use address_space::AddressSpace;
+use anyhow::{anyhow, bail, Context, Result};
+use log::error;
+use machine_manager::{
+    config::{Virtio9pConfig, DEFAULT_VIRTQUEUE_SIZE},
+    event_loop::{register_event_helper, unregister_event_helper},
+};
+use server::{P9Server, P9_MAX_MSIZE};
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::num_ops::read_u32;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Number of virtqueues, requests and replies share one queue.
+const QUEUE_NUM_9P: usize = 1;
+
+struct P9IoHandler {
+    queue: Arc<Mutex<Queue>>,
+    queue_evt: Arc<EventFd>,
+    mem_space: Arc<AddressSpace>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    driver_features: u64,
+    server: P9Server,
+}
+
+impl P9IoHandler {
+    /// Handle the request of an element and write the reply to it, return
+    /// the length of the reply.
+    fn handle_request(&mut self, elem: &Element) -> Result<u32> {
+        let len = Element::iovec_size(&elem.out_iovec) as usize;
+        if len > P9_MAX_MSIZE as usize {
+            bail!(
+                "9p request size {} exceeds the maximum {}",
+                len,
+                P9_MAX_MSIZE
+            );
+        }
+        let mut request = vec![0_u8; len];
+        iov_to_buf(&self.mem_space, &elem.out_iovec, &mut request)?;
+        let reply = self.server.handle(&request);
+        let reply_space = Element::iovec_size(&elem.in_iovec) as usize;
+        if reply.len() > reply_space {
+            bail!(
+                "9p reply size {} exceeds the buffer size {}",
+                reply.len(),
+                reply_space
+            );
+        }
+        Ok(iov_from_buf(&self.mem_space, &elem.in_iovec, &reply)? as u32)
+    }
+
+    fn process_queue(&mut self) -> Result<()> {
+        self.trace_request("9p".to_string(), "request".to_string());
+        let queue = self.queue.clone();
+        let mut locked_queue = queue.lock().unwrap();
+        loop {
+            let elem = locked_queue
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)
+                .with_context(|| "Failed to pop avail ring for 9p")?;
+            if elem.desc_num == 0 {
+                break;
+            }
+            let len = self.handle_request(&elem).unwrap_or_else(|e| {
+                error!("Failed to handle 9p request: {:?}", e);
+                0
+            });
+            locked_queue
+                .vring
+                .add_used(&self.mem_space, elem.index, len)
+                .with_context(|| format!("Failed to add used ring for 9p, index {}", elem.index))?;
+        }
+
+        (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue), false).with_context(
+            || {
+                anyhow!(VirtioError::InterruptTrigger(
+                    "9p",
+                    VirtioInterruptType::Vring
+                ))
+            },
+        )?;
+        self.trace_send_interrupt("9p".to_string());
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for P9IoHandler {
+    fn internal_notifiers(p9_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let queue_fd = p9_handler.lock().unwrap().queue_evt.as_raw_fd();
+        let cloned_handler = p9_handler.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(ref e) = cloned_handler.lock().unwrap().process_queue() {
+                error!("Failed to process 9p queue: {:?}", e);
+            }
+            None
+        });
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            queue_fd,
+            None,
+            EventSet::IN,
+            vec![handler],
+        )]
+    }
+}
+
+/// Virtio 9p device structure. Fids opened by guest live in the handler, so
+/// the device doesn't support migration of its state.
+pub struct Virtio9p {
+    /// Configuration of the 9p device.
+    p9_cfg: Virtio9pConfig,
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Config space: length of mount tag in u16 and the tag.
+    config_space: Vec<u8>,
+    /// The shared directory, opened on realize as it may be unreachable by
+    /// path after TeleVM chroots.
+    root: Option<File>,
+    /// EventFd for device deactivate.
+    deactivate_evts: Vec<RawFd>,
+}
+
+impl Virtio9p {
+    /// Create a virtio-9p device.
+    ///
+    /// # Arguments
+    ///
+    /// * `p9_cfg` - Device configuration set by user.
+    pub fn new(p9_cfg: Virtio9pConfig) -> Self {
+        Virtio9p {
+            p9_cfg,
+            device_features: 0_u64,
+            driver_features: 0_u64,
+            config_space: Vec::new(),
+            root: None,
+            deactivate_evts: Vec::new(),
+        }
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    /// Realize virtio 9p device.
+    fn realize(&mut self) -> Result<()> {
+        let path = &self.p9_cfg.fsdev.path;
+        self.root = Some(
+            P9Server::open_root(Path::new(path))
+                .with_context(|| format!("Failed to open shared directory {}", path))?,
+        );
+        let tag = self.p9_cfg.mount_tag.as_bytes();
+        self.config_space = (tag.len() as u16).to_le_bytes().to_vec();
+        self.config_space.extend_from_slice(tag);
+        self.device_features = (1_u64 << VIRTIO_F_VERSION_1) | (1_u64 << VIRTIO_9P_MOUNT_TAG);
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_9P
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_9P
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        DEFAULT_VIRTQUEUE_SIZE
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        self.driver_features = self.checked_driver_features(page, value);
+    }
+
+    /// Get driver features by guest.
+    fn get_driver_features(&self, features_select: u32) -> u32 {
+        read_u32(self.driver_features, features_select)
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            return Err(anyhow!(VirtioError::DevConfigOverflow(offset, config_len)));
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            data.write_all(
+                &self.config_space[offset as usize..cmp::min(end, config_len) as usize],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        bail!("Config space of 9p is read-only");
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        mut queue_evts: Vec<Arc<EventFd>>,
+    ) -> Result<()> {
+        if queues.len() != QUEUE_NUM_9P || queue_evts.len() != QUEUE_NUM_9P {
+            return Err(anyhow!(VirtioError::IncorrectQueueNum(
+                QUEUE_NUM_9P,
+                queues.len()
+            )));
+        }
+        let root = self
+            .root
+            .as_ref()
+            .with_context(|| "9p device is not realized")?
+            .try_clone()?;
+        let handler = P9IoHandler {
+            queue: queues[0].clone(),
+            queue_evt: queue_evts.remove(0),
+            mem_space,
+            interrupt_cb,
+            driver_features: self.driver_features,
+            server: P9Server::new(root, self.p9_cfg.fsdev.readonly),
+        };
+
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
+        Ok(())
+    }
+
+    /// Fids of guest are dropped with the handler.
+    fn deactivate(&mut self) -> Result<()> {
+        unregister_event_helper(None, &mut self.deactivate_evts)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.driver_features = 0;
+        Ok(())
+    }
+}
+
+impl VirtioTrace for P9IoHandler {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine_manager::config::FsDevConfig;
+
+    fn p9_config(path: &str) -> Virtio9pConfig {
+        Virtio9pConfig {
+            id: "p9".to_string(),
+            mount_tag: "share".to_string(),
+            fsdev: FsDevConfig {
+                id: "fs0".to_string(),
+                path: path.to_string(),
+                security_model: "none".to_string(),
+                readonly: false,
+            },
+        }
+    }
+
+    #[test]
+    fn test_p9_realize() {
+        let dir = std::env::temp_dir();
+        let mut p9 = Virtio9p::new(p9_config(dir.to_str().unwrap()));
+        p9.realize().unwrap();
+        assert_eq!(p9.device_type(), VIRTIO_TYPE_9P);
+        assert_eq!(p9.queue_num(), QUEUE_NUM_9P);
+        assert_ne!(p9.get_device_features(0) & (1 << VIRTIO_9P_MOUNT_TAG), 0);
+
+        let mut config = [0_u8; 7];
+        p9.read_config(0, &mut config).unwrap();
+        assert_eq!(&config, b"\x05\x00share");
+        let mut tag = [0_u8; 5];
+        p9.read_config(2, &mut tag).unwrap();
+        assert_eq!(&tag, b"share");
+        assert!(p9.read_config(7, &mut tag).is_err());
+        assert!(p9.write_config(0, &[0]).is_err());
+
+        let mut p9 = Virtio9p::new(p9_config("/no/such/dir"));
+        assert!(p9.realize().is_err());
+    }
+}
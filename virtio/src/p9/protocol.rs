@@ -0,0 +1,242 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Messages of 9P2000.L, the dialect of 9P the Linux client speaks over virtio.
+//! All integers are little endian, strings are prefixed by a u16 length.
+
+use std::io;
+
+use libc::{EINVAL, EPROTO};
+
+/// Size of the header all messages start with: size[4] type[1] tag[2].
+pub const P9_HEADER_SIZE: usize = 7;
+/// Size of a qid on the wire: type[1] version[4] path[8].
+pub const P9_QID_SIZE: usize = 13;
+/// Tag of Tversion, which is sent when no other message is in flight.
+pub const P9_NOTAG: u16 = !0;
+/// Fid which stands for no fid, used as afid when there's no authentication.
+pub const P9_NOFID: u32 = !0;
+/// The only protocol version supported.
+pub const P9_PROTO_2000L: &[u8] = b"9P2000.L";
+
+pub const P9_RLERROR: u8 = 7;
+pub const P9_TSTATFS: u8 = 8;
+pub const P9_TLOPEN: u8 = 12;
+pub const P9_TLCREATE: u8 = 14;
+pub const P9_TREADLINK: u8 = 22;
+pub const P9_TGETATTR: u8 = 24;
+pub const P9_TSETATTR: u8 = 26;
+pub const P9_TREADDIR: u8 = 40;
+pub const P9_TFSYNC: u8 = 50;
+pub const P9_TMKDIR: u8 = 72;
+pub const P9_TRENAMEAT: u8 = 74;
+pub const P9_TUNLINKAT: u8 = 76;
+pub const P9_TVERSION: u8 = 100;
+pub const P9_TATTACH: u8 = 104;
+pub const P9_TFLUSH: u8 = 108;
+pub const P9_TWALK: u8 = 110;
+pub const P9_TREAD: u8 = 116;
+pub const P9_TWRITE: u8 = 118;
+pub const P9_TCLUNK: u8 = 120;
+
+/// Qid types.
+pub const P9_QTDIR: u8 = 0x80;
+pub const P9_QTSYMLINK: u8 = 0x02;
+pub const P9_QTFILE: u8 = 0x00;
+
+/// Most names walked by one Twalk.
+pub const P9_MAXWELEM: u16 = 16;
+
+/// Fields of Tgetattr which are always valid in Rgetattr, from mode to blocks.
+pub const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+/// Fields to change in Tsetattr.
+pub const P9_SETATTR_MODE: u32 = 0x1;
+pub const P9_SETATTR_UID: u32 = 0x2;
+pub const P9_SETATTR_GID: u32 = 0x4;
+pub const P9_SETATTR_SIZE: u32 = 0x8;
+pub const P9_SETATTR_ATIME: u32 = 0x10;
+pub const P9_SETATTR_MTIME: u32 = 0x20;
+pub const P9_SETATTR_ATIME_SET: u32 = 0x80;
+pub const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Open flags of Tlopen and Tlcreate, which don't depend on the host architecture.
+pub const P9_DOTL_ACCMODE: u32 = 0o3;
+pub const P9_DOTL_TRUNC: u32 = 0o1000;
+pub const P9_DOTL_APPEND: u32 = 0o2000;
+pub const P9_DOTL_DIRECTORY: u32 = 0o200000;
+
+/// Flag of Tunlinkat to remove a directory.
+pub const P9_DOTL_AT_REMOVEDIR: u32 = 0x200;
+
+/// Identity of a file on the server.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Qid {
+    pub ty: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+fn malformed() -> io::Error {
+    io::Error::from_raw_os_error(EPROTO)
+}
+
+/// Decoder of the fields of a T-message.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(malformed());
+        }
+        let (field, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(field)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let field = self.take(2)?;
+        Ok(u16::from_le_bytes([field[0], field[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let mut field = [0_u8; 4];
+        field.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(field))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let mut field = [0_u8; 8];
+        field.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(field))
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        self.take(len)
+    }
+
+    pub fn read_string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.read_u16()? as usize;
+        let string = self.take(len)?;
+        if string.contains(&0) {
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        }
+        Ok(string)
+    }
+}
+
+/// Encoder of an R-message, the header is filled by `finish`.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer {
+            buf: vec![0_u8; P9_HEADER_SIZE],
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn write_u16(&mut self, value: u16) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn write_u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    /// Strings longer than u16::MAX are truncated, host names never are.
+    pub fn write_string(&mut self, string: &[u8]) -> &mut Self {
+        let len = string.len().min(u16::MAX as usize);
+        self.write_u16(len as u16).write_bytes(&string[..len])
+    }
+
+    pub fn write_qid(&mut self, qid: &Qid) -> &mut Self {
+        self.write_u8(qid.ty)
+            .write_u32(qid.version)
+            .write_u64(qid.path)
+    }
+
+    /// Fields written so far.
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[P9_HEADER_SIZE..]
+    }
+
+    pub fn finish(mut self, msg_type: u8, tag: u16) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&size.to_le_bytes());
+        self.buf[4] = msg_type;
+        self.buf[5..7].copy_from_slice(&tag.to_le_bytes());
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec() {
+        let mut writer = Writer::new();
+        writer
+            .write_u32(0x1234_5678)
+            .write_string(b"9P2000.L")
+            .write_qid(&Qid {
+                ty: P9_QTDIR,
+                version: 1,
+                path: 2,
+            });
+        let msg = writer.finish(P9_TVERSION + 1, P9_NOTAG);
+        assert_eq!(msg.len(), P9_HEADER_SIZE + 4 + 2 + 8 + P9_QID_SIZE);
+        assert_eq!(&msg[0..7], &[34, 0, 0, 0, 101, 0xff, 0xff]);
+
+        let mut reader = Reader::new(&msg[P9_HEADER_SIZE..]);
+        assert_eq!(reader.read_u32().unwrap(), 0x1234_5678);
+        assert_eq!(reader.read_string().unwrap(), b"9P2000.L");
+        assert_eq!(reader.read_u8().unwrap(), P9_QTDIR);
+        assert_eq!(reader.read_u32().unwrap(), 1);
+        assert_eq!(reader.read_u64().unwrap(), 2);
+        assert!(reader.read_u8().is_err());
+
+        // String longer than the message and string with NUL are refused.
+        assert!(Reader::new(&[4, 0, b'a']).read_string().is_err());
+        assert!(Reader::new(&[2, 0, b'a', 0]).read_string().is_err());
+    }
+}
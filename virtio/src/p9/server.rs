@@ -0,0 +1,1027 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! File server of virtio-9p.
+//!
+//! A fid only keeps the names leading to its file from the shared directory.
+//! Every request opens the file again from the root fd, one name at a time
+//! and without following symlinks, and ".." never goes above the root, so
+//! guest can't reach anything outside of the shared directory, even if
+//! symlinks in it point elsewhere or directories are moved under it.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+use libc::{c_int, EBADF, EINVAL, EISDIR, ELOOP, ENXIO, EOPNOTSUPP, EROFS};
+
+use super::protocol::*;
+
+/// Largest message size agreed with guest.
+pub const P9_MAX_MSIZE: u32 = 512 << 10;
+/// Size of Rread and Rreaddir without data: header and count[4].
+const P9_IOHDR_SIZE: u32 = P9_HEADER_SIZE as u32 + 4;
+
+/// A directory stream of an opened fid, for Treaddir.
+struct DirStream(*mut libc::DIR);
+
+// SAFETY: the stream is only used by the fid which owns it.
+unsafe impl Send for DirStream {}
+
+impl Drop for DirStream {
+    fn drop(&mut self) {
+        // SAFETY: the stream is opened by fdopendir and closed only here.
+        unsafe { libc::closedir(self.0) };
+    }
+}
+
+#[derive(Default)]
+struct Fid {
+    /// Names from the shared directory to the file, without "." or "..".
+    names: Vec<CString>,
+    /// File opened by Tlopen or Tlcreate.
+    file: Option<File>,
+    /// Directory stream, created by the first Treaddir.
+    dir: Option<DirStream>,
+}
+
+fn errno(code: c_int) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+fn check_ret(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// Name of a directory entry, which must not be empty or contain '/'.
+fn entry_name(name: &[u8]) -> io::Result<CString> {
+    if name.is_empty() || name.contains(&b'/') || name == b"." || name == b".." {
+        return Err(errno(EINVAL));
+    }
+    CString::new(name).map_err(|_| errno(EINVAL))
+}
+
+fn openat(dir: &File, name: &CStr, flags: c_int, mode: u32) -> io::Result<File> {
+    // SAFETY: name is a valid C string and the returned fd is owned by File.
+    let fd = check_ret(unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            mode,
+        )
+    })?;
+    // SAFETY: fd is just opened.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Path which refers to the file of `file` itself, to change or reopen a file
+/// opened by O_PATH.
+fn proc_fd_path(file: &File) -> CString {
+    CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap()
+}
+
+/// Open the file of an O_PATH fd for real. O_NOFOLLOW is not added, as the
+/// proc path is a symlink itself.
+fn reopen(file: &File, flags: c_int) -> io::Result<File> {
+    let path = proc_fd_path(file);
+    // SAFETY: path is a valid C string and the returned fd is owned by File.
+    let fd = check_ret(unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) })?;
+    // SAFETY: fd is just opened.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn fstat(fd: RawFd) -> io::Result<libc::stat> {
+    // SAFETY: stat is plain data filled by fstat.
+    let mut st: libc::stat = unsafe { mem::zeroed() };
+    // SAFETY: st is a valid buffer.
+    check_ret(unsafe { libc::fstat(fd, &mut st) })?;
+    Ok(st)
+}
+
+fn qid_of(st: &libc::stat) -> Qid {
+    let ty = match st.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => P9_QTDIR,
+        libc::S_IFLNK => P9_QTSYMLINK,
+        _ => P9_QTFILE,
+    };
+    Qid {
+        ty,
+        version: 0,
+        path: st.st_ino,
+    }
+}
+
+/// Host open flags of 9P2000.L open flags, the file is never created by them.
+fn open_flags(flags: u32) -> c_int {
+    let mut host_flags = (flags & P9_DOTL_ACCMODE) as c_int;
+    if flags & P9_DOTL_TRUNC != 0 {
+        host_flags |= libc::O_TRUNC;
+    }
+    if flags & P9_DOTL_APPEND != 0 {
+        host_flags |= libc::O_APPEND;
+    }
+    if flags & P9_DOTL_DIRECTORY != 0 {
+        host_flags |= libc::O_DIRECTORY;
+    }
+    host_flags
+}
+
+pub struct P9Server {
+    /// Shared directory.
+    root: File,
+    readonly: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl P9Server {
+    /// Create the server of a shared directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Directory opened when the device is realized.
+    /// * `readonly` - Refuse requests which modify the directory.
+    pub fn new(root: File, readonly: bool) -> Self {
+        P9Server {
+            root,
+            readonly,
+            msize: P9_MAX_MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    /// Open the shared directory, guest sees nothing else of host.
+    pub fn open_root(path: &Path) -> io::Result<File> {
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| errno(EINVAL))?;
+        // SAFETY: path is a valid C string and the returned fd is owned by File.
+        let fd = check_ret(unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        })?;
+        // SAFETY: fd is just opened.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Handle a T-message and return the R-message for it.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader::new(request);
+        let header = (|| -> io::Result<(u8, u16)> {
+            reader.read_u32()?;
+            Ok((reader.read_u8()?, reader.read_u16()?))
+        })();
+        let (msg_type, tag) = match header {
+            Ok(header) => header,
+            Err(e) => return Self::error_reply(P9_NOTAG, &e),
+        };
+        let mut writer = Writer::new();
+        match self.dispatch(msg_type, &mut reader, &mut writer) {
+            Ok(()) => writer.finish(msg_type + 1, tag),
+            Err(e) => Self::error_reply(tag, &e),
+        }
+    }
+
+    fn error_reply(tag: u16, e: &io::Error) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_u32(e.raw_os_error().unwrap_or(libc::EIO) as u32);
+        writer.finish(P9_RLERROR, tag)
+    }
+
+    fn dispatch(&mut self, msg_type: u8, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        match msg_type {
+            P9_TVERSION => self.version(req, resp),
+            P9_TATTACH => self.attach(req, resp),
+            P9_TWALK => self.walk(req, resp),
+            P9_TGETATTR => self.getattr(req, resp),
+            P9_TSETATTR => self.setattr(req),
+            P9_TLOPEN => self.lopen(req, resp),
+            P9_TLCREATE => self.lcreate(req, resp),
+            P9_TREAD => self.read(req, resp),
+            P9_TWRITE => self.write(req, resp),
+            P9_TREADDIR => self.readdir(req, resp),
+            P9_TMKDIR => self.mkdir(req, resp),
+            P9_TUNLINKAT => self.unlinkat(req),
+            P9_TRENAMEAT => self.renameat(req),
+            P9_TREADLINK => self.readlink(req, resp),
+            P9_TSTATFS => self.statfs(req, resp),
+            P9_TFSYNC => self.fsync(req),
+            P9_TCLUNK => {
+                let fid = req.read_u32()?;
+                self.fids
+                    .remove(&fid)
+                    .map(|_| ())
+                    .ok_or_else(|| errno(EBADF))
+            }
+            // Requests are handled one by one, there's nothing to flush.
+            P9_TFLUSH => req.read_u16().map(|_| ()),
+            _ => Err(errno(EOPNOTSUPP)),
+        }
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(|| errno(EBADF))
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.readonly {
+            return Err(errno(EROFS));
+        }
+        Ok(())
+    }
+
+    /// Open the directory `names` lead to, without following symlinks.
+    fn open_dir(&self, names: &[CString]) -> io::Result<File> {
+        let mut dir = self.root.try_clone()?;
+        for name in names {
+            dir = openat(&dir, name, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        }
+        Ok(dir)
+    }
+
+    /// Open the file `names` lead to, a symlink is opened as itself by O_PATH
+    /// and refused otherwise.
+    fn open_names(&self, names: &[CString], flags: c_int) -> io::Result<File> {
+        match names.split_last() {
+            Some((name, parent)) => openat(&self.open_dir(parent)?, name, flags, 0),
+            None => openat(
+                &self.root,
+                CStr::from_bytes_with_nul(b".\0").unwrap(),
+                flags,
+                0,
+            ),
+        }
+    }
+
+    /// Parent directory and name of the file of a fid, the root has none.
+    fn open_parent(&self, names: &[CString]) -> io::Result<(File, CString)> {
+        match names.split_last() {
+            Some((name, parent)) => Ok((self.open_dir(parent)?, name.clone())),
+            None => Err(errno(EINVAL)),
+        }
+    }
+
+    fn version(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let msize = req.read_u32()?;
+        let version = req.read_string()?;
+        if msize < P9_IOHDR_SIZE + P9_QID_SIZE as u32 {
+            return Err(errno(EINVAL));
+        }
+        // A new session starts, all fids of the old one are clunked.
+        self.fids.clear();
+        self.msize = msize.min(P9_MAX_MSIZE);
+        resp.write_u32(self.msize);
+        if version == P9_PROTO_2000L {
+            resp.write_string(P9_PROTO_2000L);
+        } else {
+            resp.write_string(b"unknown");
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let afid = req.read_u32()?;
+        if afid != P9_NOFID {
+            return Err(errno(EOPNOTSUPP));
+        }
+        if self.fids.contains_key(&fid) {
+            return Err(errno(EBADF));
+        }
+        let st = fstat(self.root.as_raw_fd())?;
+        self.fids.insert(fid, Fid::default());
+        resp.write_qid(&qid_of(&st));
+        Ok(())
+    }
+
+    fn walk(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let newfid = req.read_u32()?;
+        let nwname = req.read_u16()?;
+        if nwname > P9_MAXWELEM {
+            return Err(errno(EINVAL));
+        }
+        let mut names = self.fid(fid)?.names.clone();
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(errno(EBADF));
+        }
+
+        let mut qids = Vec::new();
+        for index in 0..nwname {
+            let name = req.read_string()?;
+            let stat = match name {
+                b".." => {
+                    names.pop();
+                    Ok(())
+                }
+                b"." => Ok(()),
+                _ => entry_name(name).map(|name| names.push(name)),
+            }
+            .and_then(|_| {
+                let file = self.open_names(&names, libc::O_PATH)?;
+                fstat(file.as_raw_fd())
+            });
+            match stat {
+                Ok(st) => qids.push(qid_of(&st)),
+                // Walk stops at the first name which can't be walked, it's
+                // an error only if no name is walked.
+                Err(e) if index == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == nwname as usize {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    names,
+                    ..Default::default()
+                },
+            );
+        }
+        resp.write_u16(qids.len() as u16);
+        for qid in qids.iter() {
+            resp.write_qid(qid);
+        }
+        Ok(())
+    }
+
+    fn stat_fid(&self, fid: &Fid) -> io::Result<libc::stat> {
+        match &fid.file {
+            Some(file) => fstat(file.as_raw_fd()),
+            None => fstat(self.open_names(&fid.names, libc::O_PATH)?.as_raw_fd()),
+        }
+    }
+
+    fn getattr(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let _request_mask = req.read_u64()?;
+        let st = self.stat_fid(self.fid(fid)?)?;
+        resp.write_u64(P9_GETATTR_BASIC)
+            .write_qid(&qid_of(&st))
+            .write_u32(st.st_mode)
+            .write_u32(st.st_uid)
+            .write_u32(st.st_gid)
+            .write_u64(u64::from(st.st_nlink))
+            .write_u64(st.st_rdev)
+            .write_u64(st.st_size as u64)
+            .write_u64(st.st_blksize as u64)
+            .write_u64(st.st_blocks as u64)
+            .write_u64(st.st_atime as u64)
+            .write_u64(st.st_atime_nsec as u64)
+            .write_u64(st.st_mtime as u64)
+            .write_u64(st.st_mtime_nsec as u64)
+            .write_u64(st.st_ctime as u64)
+            .write_u64(st.st_ctime_nsec as u64);
+        // btime, gen and data_version are not reported.
+        for _ in 0..4 {
+            resp.write_u64(0);
+        }
+        Ok(())
+    }
+
+    fn setattr(&mut self, req: &mut Reader) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let valid = req.read_u32()?;
+        let mode = req.read_u32()?;
+        let uid = req.read_u32()?;
+        let gid = req.read_u32()?;
+        let size = req.read_u64()?;
+        let times = [
+            (req.read_u64()?, req.read_u64()?),
+            (req.read_u64()?, req.read_u64()?),
+        ];
+        self.check_writable()?;
+
+        // Changes are made through the proc path of an O_PATH fd, which
+        // neither blocks on a FIFO nor needs permission to read the file. A
+        // symlink can't be changed without following it.
+        let file = self.open_names(&self.fid(fid)?.names, libc::O_PATH)?;
+        let st = fstat(file.as_raw_fd())?;
+        if st.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            return Err(errno(ELOOP));
+        }
+        let path = proc_fd_path(&file);
+        if valid & P9_SETATTR_MODE != 0 {
+            // SAFETY: path is a valid C string.
+            check_ret(unsafe { libc::fchmodat(libc::AT_FDCWD, path.as_ptr(), mode & 0o7777, 0) })?;
+        }
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = if valid & P9_SETATTR_UID != 0 { uid } else { !0 };
+            let gid = if valid & P9_SETATTR_GID != 0 { gid } else { !0 };
+            // SAFETY: path is a valid C string.
+            check_ret(unsafe { libc::fchownat(libc::AT_FDCWD, path.as_ptr(), uid, gid, 0) })?;
+        }
+        if valid & P9_SETATTR_SIZE != 0 {
+            match st.st_mode & libc::S_IFMT {
+                libc::S_IFREG => {}
+                libc::S_IFDIR => return Err(errno(EISDIR)),
+                _ => return Err(errno(EINVAL)),
+            }
+            reopen(&file, libc::O_WRONLY | libc::O_NONBLOCK)?.set_len(size)?;
+        }
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let mut ts = [libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            }; 2];
+            let bits = [
+                (P9_SETATTR_ATIME, P9_SETATTR_ATIME_SET),
+                (P9_SETATTR_MTIME, P9_SETATTR_MTIME_SET),
+            ];
+            for (index, (change, set)) in bits.iter().enumerate() {
+                if valid & change == 0 {
+                    continue;
+                }
+                if valid & set != 0 {
+                    ts[index].tv_sec = times[index].0 as libc::time_t;
+                    ts[index].tv_nsec = times[index].1 as _;
+                } else {
+                    ts[index].tv_nsec = libc::UTIME_NOW;
+                }
+            }
+            // SAFETY: path is a valid C string and ts is valid.
+            check_ret(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), ts.as_ptr(), 0) })?;
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let flags = open_flags(req.read_u32()?);
+        if flags & (libc::O_ACCMODE | libc::O_TRUNC) != libc::O_RDONLY {
+            self.check_writable()?;
+        }
+        // Only regular files and directories are opened, so that opening a
+        // FIFO or a device never blocks or touches the host device.
+        let file = self.open_names(&self.fid(fid)?.names, libc::O_PATH)?;
+        let st = fstat(file.as_raw_fd())?;
+        match st.st_mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFDIR => {}
+            libc::S_IFLNK => return Err(errno(ELOOP)),
+            _ => return Err(errno(ENXIO)),
+        }
+        let file = reopen(&file, flags | libc::O_NONBLOCK)?;
+        let fid = self.fids.get_mut(&fid).ok_or_else(|| errno(EBADF))?;
+        if fid.file.is_some() {
+            return Err(errno(EINVAL));
+        }
+        fid.file = Some(file);
+        resp.write_qid(&qid_of(&st)).write_u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let name = entry_name(req.read_string()?)?;
+        let flags = open_flags(req.read_u32()?) & !libc::O_DIRECTORY;
+        let mode = req.read_u32()?;
+        let _gid = req.read_u32()?;
+        self.check_writable()?;
+
+        let dir = self.open_dir(&self.fid(fid)?.names)?;
+        let file = openat(&dir, &name, flags | libc::O_CREAT, mode & 0o7777)?;
+        let st = fstat(file.as_raw_fd())?;
+        // The fid stands for the new file from now on.
+        let fid = self.fids.get_mut(&fid).ok_or_else(|| errno(EBADF))?;
+        fid.names.push(name);
+        fid.file = Some(file);
+        fid.dir = None;
+        resp.write_qid(&qid_of(&st)).write_u32(0);
+        Ok(())
+    }
+
+    fn opened(&self, fid: u32) -> io::Result<&File> {
+        self.fid(fid)?.file.as_ref().ok_or_else(|| errno(EBADF))
+    }
+
+    fn read(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let offset = req.read_u64()?;
+        let count = req.read_u32()?.min(self.msize - P9_IOHDR_SIZE);
+        let mut data = vec![0_u8; count as usize];
+        let len = self.opened(fid)?.read_at(&mut data, offset)?;
+        resp.write_u32(len as u32).write_bytes(&data[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let offset = req.read_u64()?;
+        let count = req.read_u32()?;
+        let data = req.read_bytes(count as usize)?;
+        // Data is appended whatever the offset is if the file is opened with O_APPEND.
+        let len = self.opened(fid)?.write_at(data, offset)?;
+        resp.write_u32(len as u32);
+        Ok(())
+    }
+
+    fn readdir(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let offset = req.read_u64()?;
+        let count = req.read_u32()?.min(self.msize - P9_IOHDR_SIZE) as usize;
+        let fid = self.fids.get_mut(&fid).ok_or_else(|| errno(EBADF))?;
+        if fid.dir.is_none() {
+            let file = fid.file.as_ref().ok_or_else(|| errno(EBADF))?;
+            // The stream owns a dup of the fd, so the fd of the fid is kept.
+            let file_fd = file.as_raw_fd();
+            // SAFETY: fd of the file is valid.
+            let fd = check_ret(unsafe { libc::fcntl(file_fd, libc::F_DUPFD_CLOEXEC, 0) })?;
+            // SAFETY: fd is valid and owned by the stream from now on.
+            let dir = unsafe { libc::fdopendir(fd) };
+            if dir.is_null() {
+                let e = io::Error::last_os_error();
+                // SAFETY: fd is not taken by the stream.
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+            fid.dir = Some(DirStream(dir));
+        }
+        let dir = fid.dir.as_ref().unwrap().0;
+
+        // SAFETY: dir is a valid stream, and offset is 0 or got from d_off.
+        unsafe {
+            if offset == 0 {
+                libc::rewinddir(dir);
+            } else {
+                libc::seekdir(dir, offset as libc::c_long);
+            }
+        }
+        let mut entries = Writer::new();
+        loop {
+            // SAFETY: dir is a valid stream, the entry is used before the next call.
+            let entry = unsafe { libc::readdir(dir) };
+            if entry.is_null() {
+                break;
+            }
+            // SAFETY: entry is not null.
+            let entry = unsafe { &*entry };
+            // SAFETY: d_name is NUL terminated.
+            let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) }.to_bytes();
+            // Entry which doesn't fit is returned by the next Treaddir, which
+            // starts from d_off of the last returned one.
+            let entry_len = P9_QID_SIZE + 8 + 1 + 2 + name.len();
+            if entries.payload().len() + entry_len > count {
+                break;
+            }
+            let ty = match entry.d_type {
+                libc::DT_DIR => P9_QTDIR,
+                libc::DT_LNK => P9_QTSYMLINK,
+                _ => P9_QTFILE,
+            };
+            entries
+                .write_qid(&Qid {
+                    ty,
+                    version: 0,
+                    path: entry.d_ino,
+                })
+                .write_u64(entry.d_off as u64)
+                .write_u8(entry.d_type)
+                .write_string(name);
+        }
+        let entries = entries.payload();
+        resp.write_u32(entries.len() as u32).write_bytes(entries);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let dfid = req.read_u32()?;
+        let name = entry_name(req.read_string()?)?;
+        let mode = req.read_u32()?;
+        let _gid = req.read_u32()?;
+        self.check_writable()?;
+
+        let dir = self.open_dir(&self.fid(dfid)?.names)?;
+        // SAFETY: name is a valid C string.
+        check_ret(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode & 0o7777) })?;
+        let st = fstat(openat(&dir, &name, libc::O_PATH, 0)?.as_raw_fd())?;
+        resp.write_qid(&qid_of(&st));
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, req: &mut Reader) -> io::Result<()> {
+        let dfid = req.read_u32()?;
+        let name = entry_name(req.read_string()?)?;
+        let flags = req.read_u32()?;
+        self.check_writable()?;
+
+        let flags = if flags & P9_DOTL_AT_REMOVEDIR != 0 {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        let dir = self.open_dir(&self.fid(dfid)?.names)?;
+        // SAFETY: name is a valid C string.
+        check_ret(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    fn renameat(&mut self, req: &mut Reader) -> io::Result<()> {
+        let old_dfid = req.read_u32()?;
+        let old_name = entry_name(req.read_string()?)?;
+        let new_dfid = req.read_u32()?;
+        let new_name = entry_name(req.read_string()?)?;
+        self.check_writable()?;
+
+        let old_dir = self.open_dir(&self.fid(old_dfid)?.names)?;
+        let new_dir = self.open_dir(&self.fid(new_dfid)?.names)?;
+        // SAFETY: names are valid C strings.
+        check_ret(unsafe {
+            libc::renameat(
+                old_dir.as_raw_fd(),
+                old_name.as_ptr(),
+                new_dir.as_raw_fd(),
+                new_name.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn readlink(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let (dir, name) = self.open_parent(&self.fid(fid)?.names)?;
+        let mut target = vec![0_u8; libc::PATH_MAX as usize];
+        // SAFETY: name is a valid C string and target is big enough.
+        let len = unsafe {
+            libc::readlinkat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                target.as_mut_ptr() as *mut libc::c_char,
+                target.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The target is only text for guest, it's never followed by the server.
+        resp.write_string(&target[..len as usize]);
+        Ok(())
+    }
+
+    fn statfs(&mut self, req: &mut Reader, resp: &mut Writer) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let file = self.open_names(&self.fid(fid)?.names, libc::O_PATH)?;
+        // SAFETY: statfs is plain data filled by fstatfs.
+        let mut st: libc::statfs = unsafe { mem::zeroed() };
+        // SAFETY: fd and st are valid.
+        check_ret(unsafe { libc::fstatfs(file.as_raw_fd(), &mut st) })?;
+        // SAFETY: fsid is two 32-bit integers.
+        let fsid: [u32; 2] = unsafe { mem::transmute(st.f_fsid) };
+        resp.write_u32(st.f_type as u32)
+            .write_u32(st.f_bsize as u32)
+            .write_u64(st.f_blocks)
+            .write_u64(st.f_bfree)
+            .write_u64(st.f_bavail)
+            .write_u64(st.f_files)
+            .write_u64(st.f_ffree)
+            .write_u64(fsid[0] as u64 | (fsid[1] as u64) << 32)
+            .write_u32(st.f_namelen as u32);
+        Ok(())
+    }
+
+    fn fsync(&mut self, req: &mut Reader) -> io::Result<()> {
+        let fid = req.read_u32()?;
+        let datasync = req.read_u32()?;
+        let file = self.opened(fid)?;
+        if datasync != 0 {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::PathBuf;
+
+    const ROOT_FID: u32 = 1;
+
+    fn request(msg_type: u8, fields: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut writer = Writer::new();
+        fields(&mut writer);
+        writer.finish(msg_type, 1)
+    }
+
+    /// Send a request and return the payload of the reply, or the error code.
+    fn call(server: &mut P9Server, msg: Vec<u8>) -> Result<Vec<u8>, u32> {
+        let reply = server.handle(&msg);
+        let size = u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize;
+        assert_eq!(size, reply.len());
+        let payload = reply[P9_HEADER_SIZE..].to_vec();
+        if reply[4] == P9_RLERROR {
+            return Err(Reader::new(&payload).read_u32().unwrap());
+        }
+        assert_eq!(reply[4], msg[4] + 1);
+        Ok(payload)
+    }
+
+    fn walk(server: &mut P9Server, fid: u32, newfid: u32, names: &[&str]) -> Result<usize, u32> {
+        let payload = call(
+            server,
+            request(P9_TWALK, |w| {
+                w.write_u32(fid)
+                    .write_u32(newfid)
+                    .write_u16(names.len() as u16);
+                for name in names {
+                    w.write_string(name.as_bytes());
+                }
+            }),
+        )?;
+        Ok(Reader::new(&payload).read_u16().unwrap() as usize)
+    }
+
+    fn lopen(server: &mut P9Server, fid: u32, flags: u32) -> Result<Vec<u8>, u32> {
+        call(
+            server,
+            request(P9_TLOPEN, |w| {
+                w.write_u32(fid).write_u32(flags);
+            }),
+        )
+    }
+
+    fn setattr(
+        server: &mut P9Server,
+        fid: u32,
+        valid: u32,
+        mode: u32,
+        size: u64,
+    ) -> Result<Vec<u8>, u32> {
+        call(
+            server,
+            request(P9_TSETATTR, |w| {
+                w.write_u32(fid)
+                    .write_u32(valid)
+                    .write_u32(mode)
+                    .write_u32(0)
+                    .write_u32(0)
+                    .write_u64(size);
+                for _ in 0..4 {
+                    w.write_u64(0);
+                }
+            }),
+        )
+    }
+
+    fn setup(name: &str, readonly: bool) -> (PathBuf, P9Server) {
+        let base = std::env::temp_dir().join(format!("p9_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let share = base.join("share");
+        fs::create_dir_all(share.join("dir")).unwrap();
+        fs::write(base.join("secret"), b"host only").unwrap();
+        fs::write(share.join("file"), b"hello").unwrap();
+        symlink("../secret", share.join("escape")).unwrap();
+        symlink(&base, share.join("dir").join("up")).unwrap();
+
+        let mut server = P9Server::new(P9Server::open_root(&share).unwrap(), readonly);
+        let payload = call(
+            &mut server,
+            request(P9_TVERSION, |w| {
+                w.write_u32(8192).write_string(P9_PROTO_2000L);
+            }),
+        )
+        .unwrap();
+        let mut reader = Reader::new(&payload);
+        assert_eq!(reader.read_u32().unwrap(), 8192);
+        assert_eq!(reader.read_string().unwrap(), P9_PROTO_2000L);
+        call(
+            &mut server,
+            request(P9_TATTACH, |w| {
+                w.write_u32(ROOT_FID)
+                    .write_u32(P9_NOFID)
+                    .write_string(b"root")
+                    .write_string(b"")
+                    .write_u32(0);
+            }),
+        )
+        .unwrap();
+        (base, server)
+    }
+
+    #[test]
+    fn test_p9_read_write() {
+        let (base, mut server) = setup("rw", false);
+
+        assert_eq!(walk(&mut server, ROOT_FID, 2, &["file"]), Ok(1));
+        lopen(&mut server, 2, libc::O_RDWR as u32).unwrap();
+        let payload = call(
+            &mut server,
+            request(P9_TWRITE, |w| {
+                w.write_u32(2)
+                    .write_u64(5)
+                    .write_u32(6)
+                    .write_bytes(b" world");
+            }),
+        )
+        .unwrap();
+        assert_eq!(Reader::new(&payload).read_u32().unwrap(), 6);
+        let payload = call(
+            &mut server,
+            request(P9_TREAD, |w| {
+                w.write_u32(2).write_u64(0).write_u32(100);
+            }),
+        )
+        .unwrap();
+        let mut reader = Reader::new(&payload);
+        let count = reader.read_u32().unwrap();
+        assert_eq!(reader.read_bytes(count as usize).unwrap(), b"hello world");
+
+        // Create a file in a sub directory, and find it by readdir.
+        assert_eq!(walk(&mut server, ROOT_FID, 3, &["dir"]), Ok(1));
+        call(
+            &mut server,
+            request(P9_TLCREATE, |w| {
+                w.write_u32(3)
+                    .write_string(b"new")
+                    .write_u32(libc::O_WRONLY as u32)
+                    .write_u32(0o644)
+                    .write_u32(0);
+            }),
+        )
+        .unwrap();
+        assert!(base.join("share/dir/new").exists());
+        assert_eq!(walk(&mut server, ROOT_FID, 4, &["dir"]), Ok(1));
+        lopen(&mut server, 4, P9_DOTL_DIRECTORY).unwrap();
+        let payload = call(
+            &mut server,
+            request(P9_TREADDIR, |w| {
+                w.write_u32(4).write_u64(0).write_u32(4096);
+            }),
+        )
+        .unwrap();
+        let mut reader = Reader::new(&payload);
+        let count = reader.read_u32().unwrap() as usize;
+        let mut entries = Reader::new(reader.read_bytes(count).unwrap());
+        let mut names = Vec::new();
+        while let Ok(_qid_type) = entries.read_u8() {
+            entries.read_bytes(P9_QID_SIZE - 1 + 8 + 1).unwrap();
+            names.push(entries.read_string().unwrap().to_vec());
+        }
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                b".".to_vec(),
+                b"..".to_vec(),
+                b"new".to_vec(),
+                b"up".to_vec()
+            ]
+        );
+
+        call(
+            &mut server,
+            request(P9_TCLUNK, |w| {
+                w.write_u32(4);
+            }),
+        )
+        .unwrap();
+        assert_eq!(lopen(&mut server, 4, 0), Err(EBADF as u32));
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_p9_confinement() {
+        let (base, mut server) = setup("confine", false);
+
+        // ".." of the root is the root itself.
+        assert_eq!(walk(&mut server, ROOT_FID, 2, &["..", "..", "file"]), Ok(3));
+        // Symlinks are walked to, but never through or opened.
+        assert_eq!(walk(&mut server, ROOT_FID, 3, &["escape"]), Ok(1));
+        assert_eq!(lopen(&mut server, 3, 0), Err(libc::ELOOP as u32));
+        assert_eq!(
+            walk(&mut server, ROOT_FID, 4, &["dir", "up", "secret"]),
+            Ok(2)
+        );
+        assert_eq!(lopen(&mut server, 4, 0), Err(EBADF as u32));
+        let payload = call(
+            &mut server,
+            request(P9_TREADLINK, |w| {
+                w.write_u32(3);
+            }),
+        )
+        .unwrap();
+        assert_eq!(Reader::new(&payload).read_string().unwrap(), b"../secret");
+
+        // Names with '/' or standing for other directories are refused.
+        for name in ["../secret", "..", "", "dir/up"] {
+            let ret = call(
+                &mut server,
+                request(P9_TLCREATE, |w| {
+                    w.write_u32(ROOT_FID)
+                        .write_string(name.as_bytes())
+                        .write_u32(0)
+                        .write_u32(0o644)
+                        .write_u32(0);
+                }),
+            );
+            assert_eq!(ret, Err(EINVAL as u32));
+        }
+        assert_eq!(
+            walk(&mut server, ROOT_FID, 5, &["dir/up"]),
+            Err(EINVAL as u32)
+        );
+        // A symlink is not replaced by a created file.
+        let ret = call(
+            &mut server,
+            request(P9_TLCREATE, |w| {
+                w.write_u32(ROOT_FID)
+                    .write_string(b"escape")
+                    .write_u32(libc::O_WRONLY as u32)
+                    .write_u32(0o644)
+                    .write_u32(0);
+            }),
+        );
+        assert!(ret.is_err());
+        assert_eq!(fs::read(base.join("secret")).unwrap(), b"host only");
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_p9_special_files() {
+        let (base, mut server) = setup("special", false);
+        let fifo = CString::new(base.join("share/fifo").as_os_str().as_bytes()).unwrap();
+        // SAFETY: fifo is a valid C string.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        // A FIFO is never opened, so neither request blocks without a peer.
+        assert_eq!(walk(&mut server, ROOT_FID, 2, &["fifo"]), Ok(1));
+        assert_eq!(lopen(&mut server, 2, 0), Err(libc::ENXIO as u32));
+        setattr(&mut server, 2, P9_SETATTR_MODE, 0o640, 0).unwrap();
+        let st = fs::metadata(base.join("share/fifo")).unwrap();
+        assert_eq!(st.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(
+            setattr(&mut server, 2, P9_SETATTR_SIZE, 0, 0),
+            Err(EINVAL as u32)
+        );
+
+        assert_eq!(walk(&mut server, ROOT_FID, 3, &["file"]), Ok(1));
+        setattr(&mut server, 3, P9_SETATTR_MODE | P9_SETATTR_SIZE, 0o600, 2).unwrap();
+        assert_eq!(fs::read(base.join("share/file")).unwrap(), b"he");
+        assert_eq!(walk(&mut server, ROOT_FID, 4, &["dir"]), Ok(1));
+        assert_eq!(
+            setattr(&mut server, 4, P9_SETATTR_SIZE, 0, 0),
+            Err(libc::EISDIR as u32)
+        );
+
+        // A walk has at most P9_MAXWELEM names.
+        let names = vec!["."; P9_MAXWELEM as usize + 1];
+        assert_eq!(walk(&mut server, ROOT_FID, 5, &names), Err(EINVAL as u32));
+        assert_eq!(
+            walk(&mut server, ROOT_FID, 5, &names[1..]),
+            Ok(P9_MAXWELEM as usize)
+        );
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_p9_readonly() {
+        let (base, mut server) = setup("ro", true);
+
+        assert_eq!(walk(&mut server, ROOT_FID, 2, &["file"]), Ok(1));
+        assert_eq!(
+            lopen(&mut server, 2, libc::O_RDWR as u32),
+            Err(EROFS as u32)
+        );
+        lopen(&mut server, 2, 0).unwrap();
+        let ret = call(
+            &mut server,
+            request(P9_TMKDIR, |w| {
+                w.write_u32(ROOT_FID)
+                    .write_string(b"sub")
+                    .write_u32(0o755)
+                    .write_u32(0);
+            }),
+        );
+        assert_eq!(ret, Err(EROFS as u32));
+        // Unknown request and short message get an error.
+        assert_eq!(
+            call(&mut server, request(30, |_| {})),
+            Err(EOPNOTSUPP as u32)
+        );
+        assert!(call(&mut server, request(P9_TREAD, |_| {})).is_err());
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -432,6 +432,7 @@ impl VhostUserClient {
                     true,
                     true,
                     false,
+                    true,
                 )?;
                 let inflight = VhostInflight {
                     file,
@@ -23,12 +23,15 @@ use crate::error::VirtioError;
 use address_space::{AddressRange, AddressSpace, GuestAddress, RegionIoEventFd};
 use byteorder::{ByteOrder, LittleEndian};
 use devices::InterruptController;
-use log::{error, warn};
+use log::{error, info};
 #[cfg(target_arch = "x86_64")]
 use machine_manager::config::{BootSource, Param};
 use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use migration_derive::{ByteCode, Desc};
-use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use sysbus::{
+    decode_state, encode_state, AccessResult, ConstRegister, IrqMode, StateReader, SysBus,
+    SysBusDevOps, SysBusDevType, SysRes,
+};
 use util::byte_code::ByteCode;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -93,6 +96,8 @@ const MMIO_VERSION: u32 = 2;
 
 /// The maximum of virtio queue within a virtio device.
 const MAXIMUM_NR_QUEUES: usize = 8;
+/// Version of virtio mmio state saved by `state_bytes`.
+const VIRTIO_MMIO_STATE_VERSION: u32 = 1;
 
 /// HostNotifyInfo includes the info needed for notifying backend from guest.
 pub struct HostNotifyInfo {
@@ -388,13 +393,17 @@ impl VirtioMmioDevice {
             .realize()
             .with_context(|| "Failed to realize virtio.")?;
 
-        if region_base >= sysbus.mmio_region.1 {
+        if !sysbus.in_mmio_window(region_base, region_size) {
             bail!("Mmio region space exhausted.");
         }
-        self.set_sys_resource(sysbus, region_base, region_size)?;
+        let irq_mode = self.set_sys_resource(sysbus, region_base, region_size)?;
+        info!(
+            "Virtio mmio device at 0x{:x}: irq {} is delivered by {:?}",
+            region_base, self.res.irq, irq_mode
+        );
         self.assign_interrupt_cb();
         let dev = Arc::new(Mutex::new(self));
-        sysbus.attach_device(&dev, region_base, region_size)?;
+        sysbus.attach_device(&dev, Some(region_base), region_size)?;
 
         #[cfg(target_arch = "x86_64")]
         bs.lock().unwrap().kernel_cmdline.push(Param {
@@ -409,6 +418,46 @@ impl VirtioMmioDevice {
         Ok(dev)
     }
 
+    /// Realize the device in a hotplug slot declared to guest in advance. The slot
+    /// keeps its MMIO range and IRQ line, which the device is bound to until detached.
+    ///
+    /// # Arguments
+    ///
+    /// * `sysbus` - System bus to attach the device to.
+    /// * `region_base` - Base address of the range reserved by the slot.
+    /// * `region_size` - Size of the range reserved by the slot.
+    /// * `irq` - IRQ number allocated for the slot.
+    pub fn realize_in_slot(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+        irq: i32,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.device
+            .lock()
+            .unwrap()
+            .realize()
+            .with_context(|| "Failed to realize virtio.")?;
+
+        let irq_mode = sysbus.request_shared_irq(irq, &self.interrupt_evt)?;
+        self.res = SysRes {
+            region_base,
+            region_size,
+            irq,
+            irq_mode,
+            ..Default::default()
+        };
+        info!(
+            "Virtio mmio device plugged at 0x{:x}: irq {} is delivered by {:?}",
+            region_base, irq, irq_mode
+        );
+        self.assign_interrupt_cb();
+        let dev = Arc::new(Mutex::new(self));
+        sysbus.attach_device(&dev, Some(region_base), region_size)?;
+        Ok(dev)
+    }
+
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     fn activate(&mut self) -> Result<()> {
@@ -466,7 +515,10 @@ impl VirtioMmioDevice {
         let interrupt_evt = self.interrupt_evt.clone();
         let cloned_state = self.state.clone();
         let irq_chip = self.irq_chip.clone();
-        let irq = self.get_sys_resource().unwrap().irq as u8;
+        let res = *self.get_sys_resource().unwrap();
+        let irq = res.irq as u8;
+        // Interrupt eventfd is delivered by system bus unless it's not routed.
+        let inject = res.irq_mode == IrqMode::None;
         let cb = Arc::new(Box::new(
             move |int_type: &VirtioInterruptType, _queue: Option<&Queue>, needs_reset: bool| {
                 let status = match int_type {
@@ -491,7 +543,9 @@ impl VirtioMmioDevice {
                 interrupt_evt
                     .write(1)
                     .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
-                irq_chip.lock().unwrap().kvm_irq_trigger(irq);
+                if inject {
+                    irq_chip.lock().unwrap().kvm_irq_trigger(irq)?;
+                }
                 Ok(())
             },
         ) as VirtioInterrupt);
@@ -501,8 +555,25 @@ impl VirtioMmioDevice {
 }
 
 impl SysBusDevOps for VirtioMmioDevice {
+    fn const_registers(&self) -> Vec<ConstRegister> {
+        let device_id = self.device.lock().unwrap().device_type();
+        [
+            (MAGIC_VALUE_REG, MMIO_MAGIC_VALUE),
+            (VERSION_REG, MMIO_VERSION),
+            (DEVICE_ID_REG, device_id),
+            (VENDOR_ID_REG, VENDOR_ID),
+        ]
+        .iter()
+        .map(|(offset, value)| ConstRegister {
+            offset: *offset,
+            size: 4,
+            value: u64::from(*value),
+        })
+        .collect()
+    }
+
     /// Read data by virtio driver from VM.
-    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> AccessResult {
         match offset {
             0x00..=0xff if data.len() == 4 => {
                 let value = match self.state.lock().unwrap().config_space.read_common_config(
@@ -518,11 +589,12 @@ impl SysBusDevOps for VirtioMmioDevice {
                             self.device.lock().unwrap().device_type(),
                             e,
                         );
-                        return false;
+                        return AccessResult::Failed;
                     }
                 };
                 LittleEndian::write_u32(data, value);
             }
+            0x00..=0xff => return AccessResult::UnsupportedSize,
             0x100..=0xfff => {
                 if let Err(ref e) = self
                     .device
@@ -536,24 +608,34 @@ impl SysBusDevOps for VirtioMmioDevice {
                         self.device.lock().unwrap().device_type(),
                         e,
                     );
-                    return false;
+                    return AccessResult::Failed;
                 }
             }
-            _ => {
-                warn!(
-                    "Failed to read mmio register: overflows, offset is 0x{:x}, type: {}",
-                    offset,
-                    self.device.lock().unwrap().device_type(),
-                );
-            }
+            _ => return AccessResult::BadOffset,
         };
-        true
+        AccessResult::Ok
     }
 
     /// Write data by virtio driver from VM.
-    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> AccessResult {
         let mut locked_state = self.state.lock().unwrap();
         match offset {
+            // Reached only if ioeventfds aren't registered, wake the queue written.
+            _ if offset == u64::from(NOTIFY_REG_OFFSET) && data.len() == 4 => {
+                let index = LittleEndian::read_u32(data) as usize;
+                match self.host_notify_info.events.get(index) {
+                    Some(evt) => {
+                        if let Err(ref e) = evt.write(1) {
+                            error!("Failed to notify queue {}, {:?}", index, e);
+                            return AccessResult::Failed;
+                        }
+                    }
+                    None => {
+                        error!("Invalid queue {} notified", index);
+                        return AccessResult::Failed;
+                    }
+                }
+            }
             0x00..=0xff if data.len() == 4 => {
                 let value = LittleEndian::read_u32(data);
                 if let Err(ref e) = locked_state.config_space.write_common_config(
@@ -568,7 +650,7 @@ impl SysBusDevOps for VirtioMmioDevice {
                         self.device.lock().unwrap().device_type(),
                         e,
                     );
-                    return false;
+                    return AccessResult::Failed;
                 }
 
                 if locked_state.config_space.check_device_status(
@@ -586,11 +668,12 @@ impl SysBusDevOps for VirtioMmioDevice {
                             self.device.lock().unwrap().device_type(),
                             e,
                         );
-                        return false;
+                        return AccessResult::Failed;
                     }
                     self.state.lock().unwrap().activated = true;
                 }
             }
+            0x00..=0xff => return AccessResult::UnsupportedSize,
             0x100..=0xfff => {
                 if locked_state
                     .config_space
@@ -608,26 +691,19 @@ impl SysBusDevOps for VirtioMmioDevice {
                             self.device.lock().unwrap().device_type(),
                             e,
                         );
-                        return false;
+                        return AccessResult::Failed;
                     }
                 } else {
                     error!("Failed to write virtio-dev config space: driver is not ready 0x{:X}, type: {}",
                         locked_state.config_space.get_device_status(),
                         self.device.lock().unwrap().device_type(),
                     );
-                    return false;
+                    return AccessResult::Failed;
                 }
             }
-            _ => {
-                warn!(
-                    "Failed to write mmio register: overflows, offset is 0x{:x} type: {}",
-                    offset,
-                    self.device.lock().unwrap().device_type(),
-                );
-                return false;
-            }
+            _ => return AccessResult::BadOffset,
         }
-        true
+        AccessResult::Ok
     }
 
     fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
@@ -655,6 +731,73 @@ impl SysBusDevOps for VirtioMmioDevice {
     fn get_type(&self) -> SysBusDevType {
         SysBusDevType::VirtioMmio
     }
+
+    fn reset(&mut self) -> Result<()> {
+        let mut locked_state = self.state.lock().unwrap();
+        if locked_state.activated {
+            self.device
+                .lock()
+                .unwrap()
+                .deactivate()
+                .with_context(|| "Failed to deactivate virtio device")?;
+            locked_state.activated = false;
+        }
+        self.device
+            .lock()
+            .unwrap()
+            .reset()
+            .with_context(|| "Failed to reset virtio device")?;
+        locked_state.config_space = VirtioMmioCommonConfig::new(&self.device);
+        self.interrupt_status.store(0, Ordering::SeqCst);
+        self.queues.clear();
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> Result<()> {
+        self.device
+            .lock()
+            .unwrap()
+            .unrealize()
+            .with_context(|| "Failed to unrealize virtio device")
+    }
+
+    /// Transport state with queues, followed by driver features acked by the device.
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        let locked_dev = self.device.lock().unwrap();
+        let mut payload = locked_dev.device_type().to_le_bytes().to_vec();
+        for page in 0..2 {
+            payload.extend_from_slice(&locked_dev.get_driver_features(page).to_le_bytes());
+        }
+        drop(locked_dev);
+        payload.append(&mut self.get_state_vec()?);
+        Ok(encode_state(VIRTIO_MMIO_STATE_VERSION, &payload))
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let (_, payload) = decode_state(data, VIRTIO_MMIO_STATE_VERSION)?;
+        let mut reader = StateReader::new(payload);
+        let device_type = reader.read_u32()?;
+        let features = [reader.read_u32()?, reader.read_u32()?];
+        let state = reader.read_bytes(std::mem::size_of::<VirtioMmioState>())?;
+        if !reader.is_empty() {
+            bail!("Invalid virtio mmio state length {}", payload.len());
+        }
+        if device_type != self.device.lock().unwrap().device_type() {
+            bail!("Virtio device type {} doesn't match", device_type);
+        }
+        if self.state.lock().unwrap().activated {
+            bail!("Can't restore state of activated virtio mmio device");
+        }
+
+        self.set_state_mut(state)?;
+        let mut locked_dev = self.device.lock().unwrap();
+        for (page, value) in features.iter().enumerate() {
+            locked_dev.set_driver_features(page as u32, *value);
+        }
+        drop(locked_dev);
+        // Queues of the activated device are handed to the device again.
+        MigrationHook::resume(self)
+    }
 }
 
 
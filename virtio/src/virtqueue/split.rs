@@ -17,7 +17,7 @@ use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{fence, Ordering};
 use std::sync::Arc;
 
-use address_space::{AddressSpace, GuestAddress, RegionCache, RegionType};
+use address_space::{AddressSpace, GuestAddress, RegionCache, RegionType, TranslationCache};
 use anyhow::{anyhow, bail, Context, Result};
 use log::{error, warn};
 use util::byte_code::ByteCode;
@@ -175,12 +175,13 @@ impl SplitVringDesc {
     /// * `desc_table` - Guest address of virtqueue descriptor table.
     /// * `queue_size` - Size of virtqueue.
     /// * `index` - Index of descriptor in the virqueue descriptor table.
+    /// * `cache` - Translation cache of the virtqueue.
     fn new(
         sys_mem: &Arc<AddressSpace>,
         desc_table_host: u64,
         queue_size: u16,
         index: u16,
-        cache: &mut Option<RegionCache>,
+        cache: &mut TranslationCache,
     ) -> Result<Self> {
         if index >= queue_size {
             return Err(anyhow!(VirtioError::QueueIndex(index, queue_size)));
@@ -211,33 +212,16 @@ impl SplitVringDesc {
         &self,
         sys_mem: &Arc<AddressSpace>,
         queue_size: u16,
-        cache: &mut Option<RegionCache>,
+        cache: &mut TranslationCache,
     ) -> bool {
         if self.len == 0 {
             error!("Zero sized buffers are not allowed");
             return false;
         }
-        let mut miss_cached = true;
-        if let Some(reg_cache) = cache {
-            let base = self.addr.0;
-            let offset = self.len as u64;
-            if base > reg_cache.start && base + offset < reg_cache.end {
-                if base.checked_add(offset).is_none() {
-                    error!("The memory of descriptor is invalid, range overflows");
-                    return false;
-                }
-                miss_cached = false;
-            }
-        } else {
-            let gotten_cache = sys_mem.get_region_cache(self.addr);
-            if let Some(obtained_cache) = gotten_cache {
-                if obtained_cache.reg_type == RegionType::Ram {
-                    *cache = gotten_cache;
-                }
-            }
-        }
-
-        if miss_cached {
+        if cache
+            .translate(sys_mem, self.addr, u64::from(self.len))
+            .is_none()
+        {
             if let Err(ref e) = checked_offset_mem(sys_mem, self.addr, u64::from(self.len)) {
                 error!("The memory of descriptor is invalid, {:?} ", e);
                 return false;
@@ -266,7 +250,7 @@ impl SplitVringDesc {
         desc_table_host: u64,
         queue_size: u16,
         index: u16,
-        cache: &mut Option<RegionCache>,
+        cache: &mut TranslationCache,
     ) -> Result<SplitVringDesc> {
         SplitVringDesc::new(sys_mem, desc_table_host, queue_size, index, cache)
             .with_context(|| format!("Failed to find next descriptor {}", index))
@@ -306,7 +290,7 @@ impl SplitVringDesc {
     fn get_element(
         sys_mem: &Arc<AddressSpace>,
         desc_info: &DescInfo,
-        cache: &mut Option<RegionCache>,
+        cache: &mut TranslationCache,
         elem: &mut Element,
     ) -> Result<()> {
         let mut desc_table_host = desc_info.table_host;
@@ -332,8 +316,9 @@ impl SplitVringDesc {
                 } else {
                     bail!("Found two indirect descriptor elem in one request");
                 }
-                desc_table_host = sys_mem
-                    .get_host_address_from_cache(desc.addr, cache)
+                desc_table_host = cache
+                    .translate(sys_mem, desc.addr, u64::from(desc.len))
+                    .or_else(|| sys_mem.get_host_address(desc.addr))
                     .unwrap_or(0);
                 if desc_table_host == 0 {
                     bail!("Failed to get descriptor table entry host address");
@@ -385,6 +370,8 @@ impl ByteCode for SplitVringDesc {}
 pub struct SplitVring {
     /// Region cache information.
     cache: Option<RegionCache>,
+    /// Translation cache of descriptors, see `TranslationCache` for invalidation rules.
+    trans_cache: TranslationCache,
     /// The configuration of virtqueue.
     queue_config: QueueConfig,
 }
@@ -411,6 +398,7 @@ impl SplitVring {
     pub fn new(queue_config: QueueConfig) -> Self {
         SplitVring {
             cache: None,
+            trans_cache: TranslationCache::default(),
             queue_config,
         }
     }
@@ -703,13 +691,22 @@ impl SplitVring {
                 ))
             })?;
 
+        // Region cache is stale as well once the topology changed.
+        if self.trans_cache.sync_generation(sys_mem) {
+            self.cache = None;
+        }
         let desc = SplitVringDesc::new(
             sys_mem,
             self.addr_cache.desc_table_host,
             self.actual_size(),
             desc_index,
-            &mut self.cache,
+            &mut self.trans_cache,
         )?;
+        if self.cache.is_none() {
+            self.cache = sys_mem
+                .get_region_cache(desc.addr)
+                .filter(|cache| cache.reg_type == RegionType::Ram);
+        }
 
         // Suppress queue notification related to current processing desc chain.
         if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
@@ -723,14 +720,13 @@ impl SplitVring {
             index: desc_index,
             desc,
         };
-        SplitVringDesc::get_element(sys_mem, &desc_info, &mut self.cache, elem).with_context(
-            || {
+        SplitVringDesc::get_element(sys_mem, &desc_info, &mut self.trans_cache, elem)
+            .with_context(|| {
                 format!(
                     "Failed to get element from descriptor chain {}, table addr: 0x{:X}, size: {}",
                     desc_info.index, desc_info.table_host, desc_info.size,
                 )
-            },
-        )?;
+            })?;
         self.next_avail += Wrapping(1);
 
         Ok(())
@@ -1904,4 +1900,66 @@ mod tests {
         assert!(vring.set_used_event_idx(&sys_space, 4).is_ok()); //event_idx
         assert_eq!(vring.should_notify(&sys_space, features), false);
     }
+
+    #[test]
+    fn test_translation_cache_matches_flat_view() {
+        const CHAIN_LEN: u16 = 64;
+        let sys_space = address_space_init();
+
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.addr_cache.desc_table_host =
+            sys_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.ready = true;
+        queue_config.size = QUEUE_SIZE;
+        let vring = SplitVring::new(queue_config);
+        for i in 0..CHAIN_LEN {
+            let flags = if i == CHAIN_LEN - 1 {
+                0
+            } else {
+                VIRTQ_DESC_F_NEXT
+            };
+            let addr = GuestAddress(0x10000 + u64::from(i) * 0x1000);
+            vring
+                .set_desc(&sys_space, i, addr, 0x800, flags, i + 1)
+                .unwrap();
+        }
+
+        let mut cache = TranslationCache::default();
+        let desc_info = DescInfo {
+            table_host: vring.addr_cache.desc_table_host,
+            size: QUEUE_SIZE,
+            index: 0,
+            desc: SplitVringDesc::new(
+                &sys_space,
+                vring.addr_cache.desc_table_host,
+                QUEUE_SIZE,
+                0,
+                &mut cache,
+            )
+            .unwrap(),
+        };
+        let mut elem = Element::new(0);
+        SplitVringDesc::get_element(&sys_space, &desc_info, &mut cache, &mut elem).unwrap();
+        assert_eq!(elem.desc_num, CHAIN_LEN);
+
+        // Cached translation gives the same host address as walking the flat view,
+        // whether it misses or hits.
+        let mut uncached = Vec::new();
+        for iov in elem.out_iovec.iter() {
+            assert!(sys_space.address_in_memory(iov.addr, u64::from(iov.len)));
+            uncached.push(sys_space.get_host_address(iov.addr).unwrap());
+        }
+        for _ in 0..2 {
+            let mut cached = Vec::new();
+            for iov in elem.out_iovec.iter() {
+                cached.push(
+                    cache
+                        .translate(&sys_space, iov.addr, u64::from(iov.len))
+                        .unwrap(),
+                );
+            }
+            assert_eq!(cached, uncached);
+        }
+    }
 }